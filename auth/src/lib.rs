@@ -102,6 +102,15 @@ pub mod password_reset {
         pub user_id: String,
         pub token: String,
     }
+
+    /// Sets the password in clear, for the clients that don't implement OPAQUE. The token is the
+    /// one sent by email, and is consumed.
+    #[derive(Serialize, Deserialize, Clone)]
+    pub struct ClientPasswordResetCompleteRequest {
+        pub token: String,
+        #[serde(rename = "newPassword")]
+        pub new_password: String,
+    }
 }
 
 #[derive(Clone, Serialize, Deserialize)]
@@ -110,4 +119,7 @@ pub struct JWTClaims {
     pub iat: DateTime<Utc>,
     pub user: String,
     pub groups: HashSet<String>,
+    /// The session of the refresh token that issued the JWT, if any: the JWT is revoked with it.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub session: Option<String>,
 }
@@ -25,6 +25,11 @@ impl ArgonHasher {
     /// computationally intensive, it doesn't serve any security purpose.
     const SALT: &'static [u8] = b"lldap_opaque_salt";
     /// Config for the argon hasher. Security enthusiasts may want to tweak this for their system.
+    ///
+    /// Changing it invalidates all the existing password files: the server never stores an
+    /// Argon2 hash (or a PHC string) that it could check and re-hash with stronger parameters,
+    /// the hash is an input of the OPAQUE envelope, computed by the client. Raising the cost
+    /// requires a new `CipherSuite`, and every user has to set their password again.
     const CONFIG: &'static argon2::Config<'static> = &argon2::Config {
         ad: &[],
         hash_length: 128,
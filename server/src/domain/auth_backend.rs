@@ -0,0 +1,145 @@
+use crate::domain::types::UserId;
+use async_trait::async_trait;
+use std::{collections::HashSet, sync::Arc};
+
+/// The answer of an authentication backend to a password check.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AuthOutcome {
+    Accepted,
+    Rejected,
+    /// The backend doesn't know the user: the local password is checked instead.
+    NotHandled,
+}
+
+/// Checks the passwords of some users against another system, e.g. an upstream LDAP server. The
+/// users and their groups are still managed locally, and only the password check is delegated:
+/// the account lockout and any second factor still apply after it.
+#[async_trait]
+pub trait AuthBackend: Send + Sync {
+    async fn check_password(&self, user_id: &UserId, password: &str)
+        -> anyhow::Result<AuthOutcome>;
+}
+
+pub type SharedAuthBackend = Arc<dyn AuthBackend>;
+
+#[derive(Clone)]
+struct Route {
+    name: String,
+    backend: SharedAuthBackend,
+    users: HashSet<UserId>,
+    /// Lowercase.
+    groups: HashSet<String>,
+}
+
+/// Which backend checks the password of each user. The users that aren't routed anywhere, the
+/// default, are checked against their local password.
+#[derive(Clone, Default)]
+pub struct AuthBackendRouter {
+    routes: Arc<Vec<Route>>,
+}
+
+impl AuthBackendRouter {
+    /// Routes the users, and the members of the groups, to the backend. The earlier backends
+    /// take precedence.
+    pub fn with_backend(
+        mut self,
+        name: &str,
+        backend: SharedAuthBackend,
+        users: impl IntoIterator<Item = UserId>,
+        groups: impl IntoIterator<Item = String>,
+    ) -> Self {
+        Arc::make_mut(&mut self.routes).push(Route {
+            name: name.to_owned(),
+            backend,
+            users: users.into_iter().collect(),
+            groups: groups
+                .into_iter()
+                .map(|group| group.to_lowercase())
+                .collect(),
+        });
+        self
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.routes.is_empty()
+    }
+
+    /// Whether the groups of the user are needed to route it.
+    pub fn has_group_routes(&self) -> bool {
+        self.routes.iter().any(|route| !route.groups.is_empty())
+    }
+
+    /// The name and the backend for the user. A route by user ID takes precedence over a route
+    /// by group.
+    pub fn route(
+        &self,
+        user_id: &UserId,
+        group_names: &[String],
+    ) -> Option<(&str, &SharedAuthBackend)> {
+        self.routes
+            .iter()
+            .find(|route| route.users.contains(user_id))
+            .or_else(|| {
+                self.routes.iter().find(|route| {
+                    group_names
+                        .iter()
+                        .any(|group| route.groups.contains(&group.to_lowercase()))
+                })
+            })
+            .map(|route| (route.name.as_str(), &route.backend))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    struct FixedBackend(AuthOutcome);
+
+    #[async_trait]
+    impl AuthBackend for FixedBackend {
+        async fn check_password(&self, _: &UserId, _: &str) -> anyhow::Result<AuthOutcome> {
+            Ok(self.0)
+        }
+    }
+
+    #[test]
+    fn test_route() {
+        let router = AuthBackendRouter::default();
+        assert!(router.is_empty());
+        assert!(router
+            .route(&UserId::new("bob"), &["staff".to_owned()])
+            .is_none());
+        let router = router
+            .with_backend(
+                "corp",
+                Arc::new(FixedBackend(AuthOutcome::Accepted)),
+                [UserId::new("bob")],
+                ["Staff".to_owned()],
+            )
+            .with_backend(
+                "partners",
+                Arc::new(FixedBackend(AuthOutcome::Rejected)),
+                [UserId::new("john")],
+                ["partners".to_owned()],
+            );
+        assert!(router.has_group_routes());
+        let route = |user: &str, groups: &[&str]| {
+            router
+                .route(
+                    &UserId::new(user),
+                    &groups.iter().map(|g| g.to_string()).collect::<Vec<_>>(),
+                )
+                .map(|(name, _)| name.to_owned())
+        };
+        assert_eq!(route("bob", &[]), Some("corp".to_owned()));
+        assert_eq!(route("patrick", &["staff"]), Some("corp".to_owned()));
+        // The user route wins over the group route.
+        assert_eq!(route("john", &["staff"]), Some("partners".to_owned()));
+        assert_eq!(
+            route("patrick", &["partners", "STAFF"]),
+            Some("corp".to_owned())
+        );
+        assert_eq!(route("patrick", &["admins"]), None);
+    }
+}
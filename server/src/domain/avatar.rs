@@ -0,0 +1,165 @@
+use super::types::JpegPhoto;
+use image::{imageops::FilterType, DynamicImage, ImageFormat, ImageOutputFormat};
+use thiserror::Error;
+
+/// Quality used when the avatar has to be re-encoded.
+const JPEG_QUALITY: u8 = 85;
+
+#[derive(Error, Debug)]
+pub enum AvatarError {
+    #[error("Not a valid image: {0}")]
+    InvalidImage(String),
+    #[error("Unsupported image format {0:?}, expected a JPEG or a PNG")]
+    UnsupportedFormat(ImageFormat),
+    #[error("The avatar is {size} bytes, more than the maximum of {max_size} bytes")]
+    TooLarge { size: usize, max_size: usize },
+}
+
+fn invalid_image(e: impl std::fmt::Display) -> AvatarError {
+    AvatarError::InvalidImage(e.to_string())
+}
+
+fn encode_jpeg(image: &DynamicImage) -> Result<Vec<u8>, AvatarError> {
+    let mut bytes = Vec::new();
+    // The JPEG encoder doesn't support an alpha channel.
+    DynamicImage::ImageRgb8(image.to_rgb8())
+        .write_to(
+            &mut std::io::Cursor::new(&mut bytes),
+            ImageOutputFormat::Jpeg(JPEG_QUALITY),
+        )
+        .map_err(invalid_image)?;
+    Ok(bytes)
+}
+
+/// Parses an uploaded avatar. JPEGs are kept as they are, PNGs are converted to JPEG. An empty
+/// upload clears the avatar.
+pub fn decode_avatar(bytes: &[u8]) -> Result<JpegPhoto, AvatarError> {
+    if bytes.is_empty() {
+        return Ok(JpegPhoto::null());
+    }
+    match image::guess_format(bytes).map_err(invalid_image)? {
+        ImageFormat::Jpeg => JpegPhoto::try_from(bytes).map_err(invalid_image),
+        ImageFormat::Png => JpegPhoto::try_from(encode_jpeg(
+            &image::load_from_memory_with_format(bytes, ImageFormat::Png).map_err(invalid_image)?,
+        )?)
+        .map_err(invalid_image),
+        format => Err(AvatarError::UnsupportedFormat(format)),
+    }
+}
+
+/// Downscales the avatar to fit in a `max_dimension` square, keeping the aspect ratio, then
+/// checks that it fits in `max_size` bytes. Avatars that are small enough are left untouched.
+pub fn limit_avatar(
+    photo: JpegPhoto,
+    max_dimension: u32,
+    max_size: usize,
+) -> Result<JpegPhoto, AvatarError> {
+    let bytes = photo.into_bytes();
+    if bytes.is_empty() {
+        return Ok(JpegPhoto::null());
+    }
+    let image =
+        image::load_from_memory_with_format(&bytes, ImageFormat::Jpeg).map_err(invalid_image)?;
+    let bytes = if image.width() > max_dimension || image.height() > max_dimension {
+        encode_jpeg(&image.resize(max_dimension, max_dimension, FilterType::Lanczos3))?
+    } else {
+        bytes
+    };
+    if bytes.len() > max_size {
+        return Err(AvatarError::TooLarge {
+            size: bytes.len(),
+            max_size,
+        });
+    }
+    JpegPhoto::try_from(bytes).map_err(invalid_image)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use image::{Rgba, RgbaImage};
+
+    fn image_bytes(width: u32, height: u32, format: ImageOutputFormat) -> Vec<u8> {
+        let image = RgbaImage::from_fn(width, height, |x, y| {
+            Rgba([(x % 256) as u8, (y % 256) as u8, 128, 200])
+        });
+        let image = match format {
+            ImageOutputFormat::Jpeg(_) => {
+                DynamicImage::ImageRgb8(DynamicImage::ImageRgba8(image).to_rgb8())
+            }
+            _ => DynamicImage::ImageRgba8(image),
+        };
+        let mut bytes = Vec::new();
+        image
+            .write_to(&mut std::io::Cursor::new(&mut bytes), format)
+            .unwrap();
+        bytes
+    }
+
+    fn dimensions(photo: JpegPhoto) -> (u32, u32) {
+        let image =
+            image::load_from_memory_with_format(&photo.into_bytes(), ImageFormat::Jpeg).unwrap();
+        (image.width(), image.height())
+    }
+
+    #[test]
+    fn test_valid_jpeg_is_kept() {
+        let bytes = image_bytes(64, 32, ImageOutputFormat::Jpeg(90));
+        let photo = decode_avatar(&bytes).unwrap();
+        let photo = limit_avatar(photo, 128, 1 << 20).unwrap();
+        assert_eq!(photo.into_bytes(), bytes);
+    }
+
+    #[test]
+    fn test_large_jpeg_is_downscaled() {
+        let bytes = image_bytes(400, 200, ImageOutputFormat::Jpeg(90));
+        let photo = limit_avatar(decode_avatar(&bytes).unwrap(), 100, 1 << 20).unwrap();
+        assert_eq!(dimensions(photo), (100, 50));
+    }
+
+    #[test]
+    fn test_png_is_converted() {
+        let bytes = image_bytes(64, 64, ImageOutputFormat::Png);
+        let photo = decode_avatar(&bytes).unwrap();
+        assert_eq!(
+            image::guess_format(&photo.clone().into_bytes()).unwrap(),
+            ImageFormat::Jpeg
+        );
+        assert_eq!(dimensions(photo), (64, 64));
+    }
+
+    #[test]
+    fn test_random_blob_is_rejected() {
+        let blob: Vec<u8> = (0..1024u32).map(|i| (i * 7919 % 251) as u8).collect();
+        assert!(matches!(
+            decode_avatar(&blob),
+            Err(AvatarError::InvalidImage(_))
+        ));
+        // Looks like a JPEG, but isn't.
+        let mut truncated = image_bytes(64, 64, ImageOutputFormat::Jpeg(90));
+        truncated.truncate(20);
+        assert!(matches!(
+            decode_avatar(&truncated),
+            Err(AvatarError::InvalidImage(_))
+        ));
+    }
+
+    #[test]
+    fn test_oversized_avatar_is_rejected() {
+        let bytes = image_bytes(64, 64, ImageOutputFormat::Jpeg(100));
+        let size = bytes.len();
+        assert!(matches!(
+            limit_avatar(decode_avatar(&bytes).unwrap(), 128, 100),
+            Err(AvatarError::TooLarge { size: s, max_size: 100 }) if s == size
+        ));
+    }
+
+    #[test]
+    fn test_empty_avatar() {
+        assert_eq!(decode_avatar(&[]).unwrap(), JpegPhoto::null());
+        assert_eq!(
+            limit_avatar(JpegPhoto::null(), 128, 100).unwrap(),
+            JpegPhoto::null()
+        );
+    }
+}
@@ -0,0 +1,158 @@
+use crate::{
+    domain::{
+        handler::{CreateUserRequest, GroupBackendHandler, GroupRequestFilter, UserBackendHandler},
+        model,
+        sql_backend_handler::SqlBackendHandler,
+        sql_opaque_handler::register_password,
+        types::{ADMIN_GROUP_NAME, PASSWORD_MANAGER_GROUP_NAME, READONLY_GROUP_NAME},
+    },
+    infra::configuration::Configuration,
+};
+use anyhow::{Context, Result};
+use sea_orm::{EntityTrait, PaginatorTrait};
+use tracing::{info, instrument, warn};
+
+async fn ensure_group_exists(handler: &SqlBackendHandler, group_name: &str) -> Result<()> {
+    if handler
+        .list_groups(Some(GroupRequestFilter::DisplayName(group_name.to_owned())))
+        .await?
+        .is_empty()
+    {
+        warn!("Could not find {} group, trying to create it", group_name);
+        handler
+            .create_group(group_name)
+            .await
+            .context(format!("while creating {} group", group_name))?;
+    }
+    Ok(())
+}
+
+async fn create_admin_user(handler: &SqlBackendHandler, config: &Configuration) -> Result<()> {
+    let pass_length = config.ldap_user_pass.unsecure().len();
+    anyhow::ensure!(
+        pass_length >= 8,
+        "Minimum password length is 8 characters, got {} characters",
+        pass_length
+    );
+    handler
+        .create_user(CreateUserRequest {
+            user_id: config.ldap_user_dn.clone(),
+            email: config.ldap_user_email.clone(),
+            display_name: Some("Administrator".to_string()),
+            ..Default::default()
+        })
+        .await
+        .context("Error creating admin user")?;
+    register_password(handler, &config.ldap_user_dn, &config.ldap_user_pass)
+        .await
+        .context("Error setting the admin password")?;
+    let groups = handler
+        .list_groups(Some(GroupRequestFilter::DisplayName(
+            ADMIN_GROUP_NAME.to_owned(),
+        )))
+        .await?;
+    anyhow::ensure!(
+        groups.len() == 1,
+        "Could not find the {} group",
+        ADMIN_GROUP_NAME
+    );
+    handler
+        .add_user_to_group(&config.ldap_user_dn, groups[0].id)
+        .await
+        .context("Error adding admin user to group")
+}
+
+/// Creates the well-known groups, and the admin user from the configuration if there is no user
+/// at all. Running it again changes nothing: in particular, an existing admin keeps their
+/// password.
+#[instrument(skip_all, level = "debug", err)]
+pub async fn bootstrap(handler: &SqlBackendHandler, config: &Configuration) -> Result<()> {
+    for group_name in [
+        ADMIN_GROUP_NAME,
+        PASSWORD_MANAGER_GROUP_NAME,
+        READONLY_GROUP_NAME,
+    ] {
+        ensure_group_exists(handler, group_name).await?;
+    }
+    // Soft-deleted users count: their IDs are still taken.
+    if model::User::find().count(&handler.sql_pool).await? == 0 {
+        info!(
+            "No user found, creating the admin user {}",
+            config.ldap_user_dn
+        );
+        create_admin_user(handler, config)
+            .await
+            .context("while creating the admin user")?;
+    } else if handler
+        .get_user_details(&config.ldap_user_dn)
+        .await
+        .is_err()
+    {
+        warn!(
+            "The admin user {} doesn't exist, make sure another user is in the {} group",
+            config.ldap_user_dn, ADMIN_GROUP_NAME
+        );
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::domain::{
+        handler::{BindRequest, LoginHandler},
+        sql_backend_handler::tests::*,
+        types::UserId,
+    };
+    use secstr::SecUtf8;
+
+    #[tokio::test]
+    async fn test_bootstrap_is_idempotent() {
+        let mut config = get_default_config();
+        config.ldap_user_pass = SecUtf8::from("admin_password");
+        let handler = SqlBackendHandler::new(config.clone(), get_initialized_db().await);
+        bootstrap(&handler, &config).await.unwrap();
+        register_password(
+            &handler,
+            &UserId::new("admin"),
+            &SecUtf8::from("new_password"),
+        )
+        .await
+        .unwrap();
+
+        bootstrap(&handler, &config).await.unwrap();
+        assert_eq!(get_user_names(&handler, None).await, vec!["admin"]);
+        let groups = handler.list_groups(None).await.unwrap();
+        assert_eq!(
+            groups
+                .iter()
+                .map(|g| (g.display_name.as_str(), g.users.clone()))
+                .collect::<Vec<_>>(),
+            vec![
+                (ADMIN_GROUP_NAME, vec![UserId::new("admin")]),
+                (PASSWORD_MANAGER_GROUP_NAME, vec![]),
+                (READONLY_GROUP_NAME, vec![]),
+            ]
+        );
+        // The password wasn't reset.
+        handler
+            .bind(BindRequest {
+                name: UserId::new("admin"),
+                password: "new_password".to_owned(),
+            })
+            .await
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_bootstrap_with_existing_users() {
+        let fixture = TestFixture::new().await;
+        bootstrap(&fixture.handler, &get_default_config())
+            .await
+            .unwrap();
+        assert_eq!(
+            get_user_names(&fixture.handler, None).await,
+            vec!["bob", "john", "nogroup", "patrick"]
+        );
+    }
+}
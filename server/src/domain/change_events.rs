@@ -0,0 +1,126 @@
+//! The committed changes to the users and groups, broadcast to the GraphQL subscribers. These are
+//! the events sent to the webhooks.
+use super::{
+    sql_webhook_queue::WebhookEvent,
+    types::{DateTime, UserId},
+};
+use futures::Stream;
+use tokio::sync::broadcast::{self, error::RecvError};
+use tracing::warn;
+
+/// How many events a subscriber can fall behind before it misses the oldest ones.
+const CHANGE_EVENT_BUFFER_SIZE: usize = 1024;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ChangeEvent {
+    pub event: WebhookEvent,
+    /// `None` for the changes made by the server itself.
+    pub actor: Option<UserId>,
+    pub timestamp: DateTime,
+}
+
+/// Sending never waits for the subscribers: the changes don't slow down because of a slow
+/// subscriber, which skips the oldest events instead.
+#[derive(Clone)]
+pub struct ChangeEventSender(broadcast::Sender<ChangeEvent>);
+
+impl Default for ChangeEventSender {
+    fn default() -> Self {
+        Self(broadcast::channel(CHANGE_EVENT_BUFFER_SIZE).0)
+    }
+}
+
+impl ChangeEventSender {
+    /// The events committed from now on.
+    pub fn subscribe(&self) -> impl Stream<Item = ChangeEvent> + Send + 'static {
+        futures::stream::unfold(self.0.subscribe(), |mut receiver| async move {
+            loop {
+                match receiver.recv().await {
+                    Ok(event) => return Some((event, receiver)),
+                    Err(RecvError::Lagged(skipped)) => {
+                        warn!(
+                            skipped,
+                            "A subscriber fell behind, skipping the oldest changes"
+                        )
+                    }
+                    Err(RecvError::Closed) => return None,
+                }
+            }
+        })
+    }
+
+    pub(crate) fn pending(&self, events: Vec<ChangeEvent>) -> PendingChangeEvents {
+        PendingChangeEvents {
+            sender: Some(self.clone()),
+            events,
+        }
+    }
+}
+
+/// The events of a transaction, to publish once it is committed.
+#[derive(Default)]
+#[must_use = "the events are only sent by `publish`, once the transaction is committed"]
+pub(crate) struct PendingChangeEvents {
+    sender: Option<ChangeEventSender>,
+    events: Vec<ChangeEvent>,
+}
+
+impl PendingChangeEvents {
+    pub(crate) fn publish(self) {
+        if let Some(sender) = self.sender {
+            for event in self.events {
+                // Fails only when there is no subscriber.
+                let _ = sender.0.send(event);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeZone;
+    use futures::StreamExt;
+
+    fn user_created(user_id: &str) -> ChangeEvent {
+        ChangeEvent {
+            event: WebhookEvent::UserCreated {
+                user_id: UserId::new(user_id),
+            },
+            actor: None,
+            timestamp: chrono::Utc.timestamp_millis_opt(42).unwrap(),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_publish_after_subscribe() {
+        let sender = ChangeEventSender::default();
+        // No subscriber yet: the event is dropped.
+        sender.pending(vec![user_created("early")]).publish();
+        let events = sender.subscribe();
+        let pending = sender.pending(vec![user_created("bob"), user_created("john")]);
+        drop(sender);
+        pending.publish();
+        assert_eq!(
+            events.collect::<Vec<_>>().await,
+            vec![user_created("bob"), user_created("john")]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_slow_subscriber_skips_the_oldest_events() {
+        let sender = ChangeEventSender::default();
+        let events = sender.subscribe();
+        sender
+            .pending(
+                (0..CHANGE_EVENT_BUFFER_SIZE + 2)
+                    .map(|i| user_created(&i.to_string()))
+                    .collect(),
+            )
+            .publish();
+        drop(sender);
+        let received = events.collect::<Vec<_>>().await;
+        assert_eq!(received.len(), CHANGE_EVENT_BUFFER_SIZE);
+        assert_eq!(received[0], user_created("2"));
+    }
+}
@@ -0,0 +1,46 @@
+use crate::domain::types::DateTime;
+use once_cell::sync::Lazy;
+use std::sync::Mutex;
+
+static LAST_CREATION_DATE: Lazy<Mutex<Option<DateTime>>> = Lazy::new(|| Mutex::new(None));
+
+fn next_creation_date(last: Option<DateTime>, now: DateTime) -> DateTime {
+    match last {
+        Some(last) if now <= last => last + chrono::Duration::microseconds(1),
+        _ => now,
+    }
+}
+
+/// The creation date of the new users and groups, from which their UUIDs are derived (see
+/// `Uuid::from_name_and_date`).
+///
+/// It's the system clock, but it never goes back: if the clock is set back, e.g. by NTP, the
+/// dates keep increasing from the last one given, by one microsecond each. The instances that
+/// share a database still each have their own clock: keep them synchronized.
+pub fn creation_date() -> DateTime {
+    let mut last = LAST_CREATION_DATE.lock().unwrap();
+    let date = next_creation_date(*last, chrono::Utc::now());
+    *last = Some(date);
+    date
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_creation_date() {
+        let dates = (0..1000).map(|_| creation_date()).collect::<Vec<_>>();
+        assert!(dates.windows(2).all(|w| w[0] < w[1]));
+        let now = chrono::Utc::now();
+        assert_eq!(next_creation_date(None, now), now);
+        let earlier = now - chrono::Duration::seconds(1);
+        assert_eq!(next_creation_date(Some(earlier), now), now);
+        // The clock was set back.
+        let later = now + chrono::Duration::hours(1);
+        assert_eq!(
+            next_creation_date(Some(later), now),
+            later + chrono::Duration::microseconds(1)
+        );
+    }
+}
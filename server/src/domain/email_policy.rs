@@ -0,0 +1,130 @@
+use crate::infra::configuration::{Configuration, EmailPolicyOptions};
+use thiserror::Error;
+
+#[derive(Error, Debug, Clone, PartialEq, Eq)]
+pub enum EmailError {
+    #[error("Invalid email '{email}': {reason}")]
+    Malformed { email: String, reason: String },
+    #[error("The emails of the domain '{0}' are not allowed")]
+    DomainNotAllowed(String),
+    #[error("The emails of the domain '{0}' are blocked")]
+    DomainBlocked(String),
+}
+
+/// Trims the email and lowercases its domain, which is case-insensitive. The local part is kept
+/// as is.
+pub fn normalize_domain(email: &str) -> String {
+    let email = email.trim();
+    match email.rsplit_once('@') {
+        Some((local_part, domain)) => format!("{}@{}", local_part, domain.to_lowercase()),
+        None => email.to_owned(),
+    }
+}
+
+/// The form in which the emails are stored and compared for uniqueness: with
+/// `email_policy.normalize`, "User@Example.COM" is stored as "User@example.com", and with
+/// `case_insensitive_emails` as "user@example.com".
+pub fn normalize_email(config: &Configuration, email: String) -> String {
+    let email = if config.email_policy.normalize {
+        normalize_domain(&email)
+    } else {
+        email
+    };
+    if config.case_insensitive_emails {
+        email.to_lowercase()
+    } else {
+        email
+    }
+}
+
+/// The domain or one of its parents is in the list.
+fn is_in_domains(domain: &str, domains: &[String]) -> bool {
+    domains.iter().any(|d| {
+        let d = d.to_lowercase();
+        domain == d || domain.ends_with(&format!(".{}", d))
+    })
+}
+
+/// Checks the email of a new or updated user against the policy. The empty email, of the users
+/// without one, is always accepted.
+pub fn check_email(options: &EmailPolicyOptions, email: &str) -> Result<(), EmailError> {
+    if email.is_empty() {
+        return Ok(());
+    }
+    if options.validate {
+        email
+            .parse::<lettre::Address>()
+            .map_err(|e| EmailError::Malformed {
+                email: email.to_owned(),
+                reason: e.to_string(),
+            })?;
+    }
+    let domain = email
+        .rsplit_once('@')
+        .map(|(_, domain)| domain.to_lowercase())
+        .unwrap_or_default();
+    if is_in_domains(&domain, &options.blocked_domains) {
+        return Err(EmailError::DomainBlocked(domain));
+    }
+    if !options.allowed_domains.is_empty() && !is_in_domains(&domain, &options.allowed_domains) {
+        return Err(EmailError::DomainNotAllowed(domain));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::infra::configuration::ConfigurationBuilder;
+
+    #[test]
+    fn test_normalize_email() {
+        assert_eq!(
+            normalize_domain("  User@Example.COM "),
+            "User@example.com".to_owned()
+        );
+        assert_eq!(normalize_domain("not an email"), "not an email".to_owned());
+        let mut config = ConfigurationBuilder::for_tests();
+        assert_eq!(
+            normalize_email(&config, " User@Ex.com".to_owned()),
+            " User@Ex.com"
+        );
+        config.email_policy.normalize = true;
+        assert_eq!(
+            normalize_email(&config, " User@Ex.com".to_owned()),
+            "User@ex.com"
+        );
+        config.case_insensitive_emails = true;
+        assert_eq!(
+            normalize_email(&config, " User@Ex.com".to_owned()),
+            "user@ex.com"
+        );
+    }
+
+    #[test]
+    fn test_check_email() {
+        let mut options = EmailPolicyOptions::default();
+        assert_eq!(check_email(&options, "bob"), Ok(()));
+        options.validate = true;
+        assert_eq!(check_email(&options, "bob@example.com"), Ok(()));
+        assert_eq!(check_email(&options, ""), Ok(()));
+        for email in ["bob", "bob@", "@example.com", "bob smith@example.com"] {
+            assert!(matches!(
+                check_email(&options, email),
+                Err(EmailError::Malformed { .. })
+            ));
+        }
+        options.allowed_domains = vec!["example.com".to_owned()];
+        options.blocked_domains = vec!["Spam.Example.com".to_owned()];
+        assert_eq!(check_email(&options, "bob@Example.com"), Ok(()));
+        assert_eq!(check_email(&options, "bob@eu.example.com"), Ok(()));
+        assert_eq!(
+            check_email(&options, "bob@notexample.com"),
+            Err(EmailError::DomainNotAllowed("notexample.com".to_owned()))
+        );
+        assert_eq!(
+            check_email(&options, "bob@spam.example.com"),
+            Err(EmailError::DomainBlocked("spam.example.com".to_owned()))
+        );
+    }
+}
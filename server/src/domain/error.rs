@@ -6,7 +6,14 @@ pub enum DomainError {
     #[error("Authentication error: `{0}`")]
     AuthenticationError(String),
     #[error("Database error: `{0}`")]
-    DatabaseError(#[from] sea_orm::DbErr),
+    DatabaseError(sea_orm::DbErr),
+    /// No database connection was free before the acquire timeout.
+    #[error("The server is busy, try again later")]
+    ServerBusy,
+    #[error("Password change required for `{0}`")]
+    PasswordChangeRequired(String),
+    #[error("Account locked for `{0}`")]
+    AccountLocked(String),
     #[error("Authentication protocol error for `{0}`")]
     AuthenticationProtocolError(#[from] lldap_auth::opaque::AuthenticationError),
     #[error("Unknown crypto error: `{0}`")]
@@ -15,10 +22,37 @@ pub enum DomainError {
     BinarySerializationError(#[from] bincode::Error),
     #[error("Invalid base64: `{0}`")]
     Base64DecodeError(#[from] base64::DecodeError),
+    #[error("WebAuthn error: `{0}`")]
+    WebauthnError(#[from] webauthn_rs::prelude::WebauthnError),
+    #[error("Invalid avatar: `{0}`")]
+    InvalidAvatar(#[from] super::avatar::AvatarError),
+    #[error("Invalid email: `{0}`")]
+    InvalidEmail(#[from] super::email_policy::EmailError),
     #[error("Entity not found: `{0}`")]
     EntityNotFound(String),
+    #[error("Invalid value: `{0}`")]
+    ValidationError(String),
     #[error("Internal error: `{0}`")]
     InternalError(String),
 }
 
+impl From<sea_orm::DbErr> for DomainError {
+    fn from(error: sea_orm::DbErr) -> Self {
+        match error {
+            sea_orm::DbErr::ConnectionAcquire => {
+                crate::infra::metrics::record_db_pool_timeout();
+                DomainError::ServerBusy
+            }
+            error => DomainError::DatabaseError(error),
+        }
+    }
+}
+
+/// The counts of the database are `u64`.
+impl From<std::num::TryFromIntError> for DomainError {
+    fn from(error: std::num::TryFromIntError) -> Self {
+        DomainError::InternalError(error.to_string())
+    }
+}
+
 pub type Result<T> = std::result::Result<T, DomainError>;
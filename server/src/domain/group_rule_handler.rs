@@ -0,0 +1,163 @@
+use crate::domain::{
+    error::Result,
+    types::{DateTime, GroupId},
+};
+use async_trait::async_trait;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+
+/// How a condition compares the values of the attribute. The comparisons are case-insensitive.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub enum GroupRuleOperator {
+    Equals,
+    StartsWith,
+    EndsWith,
+}
+
+impl GroupRuleOperator {
+    pub const ALL: [GroupRuleOperator; 3] = [
+        GroupRuleOperator::Equals,
+        GroupRuleOperator::StartsWith,
+        GroupRuleOperator::EndsWith,
+    ];
+
+    /// The name stored in the database.
+    pub fn as_str(self) -> &'static str {
+        match self {
+            GroupRuleOperator::Equals => "equals",
+            GroupRuleOperator::StartsWith => "starts_with",
+            GroupRuleOperator::EndsWith => "ends_with",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Self> {
+        Self::ALL
+            .into_iter()
+            .find(|operator| operator.as_str() == name)
+    }
+
+    /// `pattern` is already lowercase.
+    fn matches(self, value: &str, pattern: &str) -> bool {
+        let value = value.to_lowercase();
+        match self {
+            GroupRuleOperator::Equals => value == pattern,
+            GroupRuleOperator::StartsWith => value.starts_with(pattern),
+            GroupRuleOperator::EndsWith => value.ends_with(pattern),
+        }
+    }
+}
+
+/// A condition on an attribute of the users: a built-in one (`user_id`, `email`, `display_name`,
+/// `first_name` or `last_name`) or a custom one of type string. An attribute with several values
+/// matches if one of them does; a missing attribute doesn't match.
+#[derive(PartialEq, Eq, Debug, Serialize, Deserialize, Clone)]
+pub struct GroupRuleCondition {
+    pub attribute: String,
+    pub operator: GroupRuleOperator,
+    pub value: String,
+}
+
+impl GroupRuleCondition {
+    fn matches(&self, attributes: &HashMap<String, Vec<String>>) -> bool {
+        let pattern = self.value.to_lowercase();
+        attributes
+            .get(&self.attribute)
+            .map(|values| {
+                values
+                    .iter()
+                    .any(|value| self.operator.matches(value, &pattern))
+            })
+            .unwrap_or(false)
+    }
+}
+
+/// Keeps the users matching all the conditions in the group. The memberships added by the rules
+/// are removed when the users don't match anymore, unlike the ones added by hand.
+#[derive(PartialEq, Eq, Debug, Serialize, Deserialize, Clone)]
+pub struct GroupRule {
+    pub rule_id: i32,
+    pub group_id: GroupId,
+    pub conditions: Vec<GroupRuleCondition>,
+    pub creation_date: DateTime,
+}
+
+impl GroupRule {
+    /// The attributes of the user, by name, as used in the conditions.
+    pub fn matches(&self, attributes: &HashMap<String, Vec<String>>) -> bool {
+        self.conditions
+            .iter()
+            .all(|condition| condition.matches(attributes))
+    }
+}
+
+#[derive(PartialEq, Eq, Debug, Serialize, Deserialize, Clone)]
+pub struct CreateGroupRuleRequest {
+    pub group_id: GroupId,
+    /// At least one.
+    pub conditions: Vec<GroupRuleCondition>,
+}
+
+#[async_trait]
+pub trait GroupRuleBackendHandler {
+    /// Sorted by ID.
+    async fn list_group_rules(&self) -> Result<Vec<GroupRule>>;
+    /// The rule applies to the users created or changed from now on. Reevaluate the rules to apply
+    /// it to the existing users.
+    async fn create_group_rule(&self, request: CreateGroupRuleRequest) -> Result<i32>;
+    /// The memberships added by the rule stay until the rules are reevaluated.
+    async fn delete_group_rule(&self, rule_id: i32) -> Result<()>;
+    /// Applies the rules to all the users, and returns the number of memberships added or
+    /// removed.
+    async fn reevaluate_group_rules(&self) -> Result<usize>;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn condition(attribute: &str, operator: GroupRuleOperator, value: &str) -> GroupRuleCondition {
+        GroupRuleCondition {
+            attribute: attribute.to_owned(),
+            operator,
+            value: value.to_owned(),
+        }
+    }
+
+    #[test]
+    fn test_rule_matches_all_the_conditions() {
+        let rule = GroupRule {
+            rule_id: 1,
+            group_id: GroupId(2),
+            conditions: vec![
+                condition("email", GroupRuleOperator::EndsWith, "@ENG.example.com"),
+                condition("department", GroupRuleOperator::StartsWith, "infra"),
+            ],
+            creation_date: chrono::Utc::now(),
+        };
+        let attributes = |email: &str, departments: &[&str]| {
+            HashMap::from([
+                ("email".to_owned(), vec![email.to_owned()]),
+                (
+                    "department".to_owned(),
+                    departments.iter().map(|d| d.to_string()).collect(),
+                ),
+            ])
+        };
+        assert!(rule.matches(&attributes("bob@eng.example.com", &["Infrastructure"])));
+        assert!(rule.matches(&attributes("bob@eng.example.com", &["sales", "infra"])));
+        assert!(!rule.matches(&attributes("bob@example.com", &["infra"])));
+        assert!(!rule.matches(&attributes("bob@eng.example.com", &[])));
+        assert!(!rule.matches(&HashMap::new()));
+    }
+
+    #[test]
+    fn test_operator_names() {
+        for operator in GroupRuleOperator::ALL {
+            assert_eq!(
+                GroupRuleOperator::from_name(operator.as_str()),
+                Some(operator)
+            );
+        }
+        assert_eq!(GroupRuleOperator::from_name("contains"), None);
+    }
+}
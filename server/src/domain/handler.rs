@@ -1,12 +1,19 @@
 use super::{
     error::Result,
+    group_rule_handler::GroupRuleBackendHandler,
+    maintenance_handler::MaintenanceBackendHandler,
+    session_handler::SessionBackendHandler,
+    sudo_role_handler::SudoRoleBackendHandler,
     types::{
-        Group, GroupDetails, GroupId, JpegPhoto, User, UserAndGroups, UserColumn, UserId, Uuid,
+        Attribute, AttributeName, AttributeSchema, AttributeValue, AuditSource, Comparison,
+        DateTime, Group, GroupDetails, GroupId, JpegPhoto, OrderedValue, User, UserAndGroups,
+        UserColumn, UserId, Uuid,
     },
 };
 use async_trait::async_trait;
+use secstr::SecUtf8;
 use serde::{Deserialize, Serialize};
-use std::collections::HashSet;
+use std::collections::{HashMap, HashSet};
 
 #[derive(PartialEq, Eq, Debug, Serialize, Deserialize, Clone)]
 pub struct BindRequest {
@@ -21,10 +28,27 @@ pub enum UserRequestFilter {
     Not(Box<UserRequestFilter>),
     UserId(UserId),
     Equality(UserColumn, String),
+    // Same, but ignoring the case of the string columns.
+    EqualityIgnoreCase(UserColumn, String),
+    // The column is not null.
+    Present(UserColumn),
+    // Compares a date or integer column.
+    Ordering(UserColumn, Comparison, OrderedValue),
+    // Same, with one of the values of a custom attribute. The bound is parsed according to the
+    // type of the attribute.
+    AttributeOrdering(AttributeName, Comparison, String),
+    // One of the values of a custom attribute is equal to the value, parsed according to the type
+    // of the attribute.
+    AttributeEquality(AttributeName, String),
+    // The user ID is in the list.
+    UserIds(Vec<UserId>),
     // Check if a user belongs to a group identified by name.
     MemberOf(String),
     // Same, by id.
     MemberOfId(GroupId),
+    // Always true, but also returns the soft-deleted users when it's part of the top-level
+    // conjunction (they are excluded by default).
+    IncludeDeleted,
 }
 
 #[derive(PartialEq, Eq, Debug, Serialize, Deserialize, Clone)]
@@ -35,19 +59,92 @@ pub enum GroupRequestFilter {
     DisplayName(String),
     Uuid(Uuid),
     GroupId(GroupId),
+    GidNumber(i32),
     // Check if the group contains a user identified by uid.
     Member(UserId),
+    // The group has a POSIX group number.
+    HasGidNumber,
+}
+
+/// Keyset pagination: the items are sorted by ID, and each page starts after the last item of the
+/// previous one, so that concurrent inserts and deletes don't shift the pages.
+#[derive(PartialEq, Eq, Debug, Serialize, Deserialize, Clone)]
+pub struct Pagination {
+    /// The `next_cursor` of the previous page, `None` for the first page.
+    pub after: Option<String>,
+    pub page_size: usize,
+}
+
+#[derive(PartialEq, Eq, Debug, Serialize, Deserialize, Clone)]
+pub struct Page<T> {
+    pub items: Vec<T>,
+    /// `None` on the last page.
+    pub next_cursor: Option<String>,
+}
+
+#[derive(PartialEq, Eq, Debug, Serialize, Deserialize, Clone, Copy)]
+pub struct UserSortKey {
+    pub column: UserColumn,
+    pub descending: bool,
 }
 
 #[derive(PartialEq, Eq, Debug, Serialize, Deserialize, Clone, Default)]
 pub struct CreateUserRequest {
-    // Same fields as User, but no creation_date, and with password.
+    // Same fields as User.
     pub user_id: UserId,
     pub email: String,
     pub display_name: Option<String>,
     pub first_name: Option<String>,
     pub last_name: Option<String>,
     pub avatar: Option<JpegPhoto>,
+    /// Only for the imports, to keep the original date, from which the UUID is derived. Defaults
+    /// to `clock::creation_date()`.
+    #[serde(default)]
+    pub creation_date: Option<DateTime>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct ImportUserRequest {
+    pub user_id: UserId,
+    pub email: String,
+    pub display_name: Option<String>,
+    pub first_name: Option<String>,
+    pub last_name: Option<String>,
+    pub password: Option<SecUtf8>,
+    /// A bcrypt, Argon2 or scrypt hash from another system, instead of the password.
+    #[serde(default)]
+    pub password_hash: Option<String>,
+}
+
+/// An invalid row of an import, with the reason it was rejected.
+#[derive(PartialEq, Eq, Debug, Serialize, Deserialize, Clone)]
+pub struct ImportFailure {
+    /// Index of the user in the request.
+    pub row: usize,
+    pub user_id: UserId,
+    pub reason: String,
+}
+
+#[derive(PartialEq, Eq, Debug, Serialize, Deserialize, Clone, Default)]
+pub struct ImportReport {
+    pub imported: usize,
+    /// If not empty, no user was imported.
+    pub failures: Vec<ImportFailure>,
+}
+
+/// What a bulk membership change did for one user.
+#[derive(PartialEq, Eq, Debug, Serialize, Deserialize, Clone, Copy)]
+pub enum MembershipChangeStatus {
+    Changed,
+    /// Already a member when adding, or not a member when removing.
+    Unchanged,
+    NoSuchUser,
+}
+
+#[derive(PartialEq, Eq, Debug, Serialize, Deserialize, Clone)]
+pub struct MembershipChange {
+    pub user_id: UserId,
+    pub status: MembershipChangeStatus,
 }
 
 #[derive(PartialEq, Eq, Debug, Serialize, Deserialize, Clone, Default)]
@@ -59,6 +156,8 @@ pub struct UpdateUserRequest {
     pub first_name: Option<String>,
     pub last_name: Option<String>,
     pub avatar: Option<JpegPhoto>,
+    /// Forces the user to reset their password before they can log in again.
+    pub must_change_password: Option<bool>,
 }
 
 #[derive(PartialEq, Eq, Debug, Serialize, Deserialize, Clone)]
@@ -67,17 +166,89 @@ pub struct UpdateGroupRequest {
     pub display_name: Option<String>,
 }
 
+/// Who makes the changes, to attribute them in the audit log.
+#[derive(PartialEq, Eq, Debug, Serialize, Deserialize, Clone)]
+pub struct AuditActor {
+    pub user_id: UserId,
+    pub source: AuditSource,
+}
+
+#[derive(PartialEq, Eq, Debug, Serialize, Deserialize, Clone)]
+pub enum AuditTarget {
+    User(UserId),
+    Group(GroupId),
+}
+
+/// A change to a user or a group. The values are only stored as keyed hashes, enough to tell
+/// whether two values are the same without revealing them.
+#[derive(PartialEq, Eq, Debug, Serialize, Deserialize, Clone)]
+pub struct AuditLogEntry {
+    /// `None` for the changes made by the server itself.
+    pub actor: Option<UserId>,
+    /// Both are set for the changes to a membership.
+    pub target_user_id: Option<UserId>,
+    pub target_group_id: Option<GroupId>,
+    pub attribute_name: String,
+    pub old_value_hash: Option<String>,
+    pub new_value_hash: Option<String>,
+    pub timestamp: DateTime,
+    pub source: AuditSource,
+    /// The ID of the request that made the change, shared by all its changes.
+    pub correlation_id: Option<String>,
+}
+
+/// A deleted user or group, kept for the incremental sync.
+#[derive(PartialEq, Eq, Debug, Serialize, Deserialize, Clone)]
+pub struct Tombstone {
+    /// Exactly one of the two is set.
+    pub user_id: Option<UserId>,
+    pub group_id: Option<GroupId>,
+    pub uuid: Uuid,
+    pub deleted_at: DateTime,
+}
+
+/// The changes to the users and groups since a point in time.
+#[derive(PartialEq, Eq, Debug)]
+pub struct ChangeSet {
+    /// The users and groups created or modified since then, in their current state.
+    pub users: Vec<User>,
+    pub groups: Vec<Group>,
+    pub deletions: Vec<Tombstone>,
+    /// Where the next sync should start from.
+    pub until: DateTime,
+    /// Some deletions may have been forgotten since then: the client should resync from scratch.
+    pub resync_required: bool,
+}
+
 #[async_trait]
 pub trait LoginHandler: Clone + Send {
     async fn bind(&self, request: BindRequest) -> Result<()>;
+    /// The SCRAM-SHA-256 verifier of the password of the user, if known.
+    async fn get_scram_verifier(&self, user_id: &UserId) -> Result<Option<String>>;
+    /// Ends a SCRAM bind, once the proof of the client is checked: fails like `bind` for a wrong
+    /// proof, a locked account or a password that must be changed.
+    async fn finish_scram_bind(&self, user_id: &UserId, valid_proof: bool) -> Result<()>;
 }
 
 #[async_trait]
 pub trait GroupBackendHandler {
     async fn list_groups(&self, filters: Option<GroupRequestFilter>) -> Result<Vec<Group>>;
+    /// Same as `list_groups`, but sorted by group ID, one page at a time.
+    async fn list_groups_page(
+        &self,
+        filters: Option<GroupRequestFilter>,
+        page: Pagination,
+    ) -> Result<Page<Group>>;
+    /// The number of groups matching the filters, e.g. to tell how many pages there are.
+    async fn count_groups(&self, filters: Option<GroupRequestFilter>) -> Result<usize>;
+    /// All the groups, sorted by display name, with their number of direct members. The deleted
+    /// users aren't counted, and the members themselves aren't fetched.
+    async fn list_groups_with_member_counts(&self) -> Result<Vec<(GroupDetails, usize)>>;
     async fn get_group_details(&self, group_id: GroupId) -> Result<GroupDetails>;
     async fn update_group(&self, request: UpdateGroupRequest) -> Result<()>;
     async fn create_group(&self, group_name: &str) -> Result<GroupId>;
+    /// Only for the imports, to keep the original creation date, from which the UUID is derived.
+    async fn create_group_at(&self, group_name: &str, creation_date: DateTime) -> Result<GroupId>;
     async fn delete_group(&self, group_id: GroupId) -> Result<()>;
 }
 
@@ -88,17 +259,177 @@ pub trait UserBackendHandler {
         filters: Option<UserRequestFilter>,
         get_groups: bool,
     ) -> Result<Vec<UserAndGroups>>;
+    /// Same as `list_users`, one page at a time.
+    async fn list_users_page(
+        &self,
+        filters: Option<UserRequestFilter>,
+        get_groups: bool,
+        page: Pagination,
+    ) -> Result<Page<UserAndGroups>>;
+    /// Same as `list_users`, sorted by the keys then by ID: `limit` users after the first
+    /// `offset` ones.
+    async fn list_users_sorted(
+        &self,
+        filters: Option<UserRequestFilter>,
+        get_groups: bool,
+        sort: Vec<UserSortKey>,
+        offset: usize,
+        limit: Option<usize>,
+    ) -> Result<Vec<UserAndGroups>>;
+    async fn count_users(&self, filters: Option<UserRequestFilter>) -> Result<usize>;
     async fn get_user_details(&self, user_id: &UserId) -> Result<User>;
     async fn create_user(&self, request: CreateUserRequest) -> Result<()>;
     async fn update_user(&self, request: UpdateUserRequest) -> Result<()>;
     async fn delete_user(&self, user_id: &UserId) -> Result<()>;
+    /// Changes the ID of the user. The memberships, attributes and credentials follow it, the
+    /// audit log keeps the old ID in the past entries.
+    async fn rename_user(&self, user_id: &UserId, new_user_id: &UserId) -> Result<()>;
     async fn add_user_to_group(&self, user_id: &UserId, group_id: GroupId) -> Result<()>;
     async fn remove_user_from_group(&self, user_id: &UserId, group_id: GroupId) -> Result<()>;
+    /// Adds the users to the group in a single transaction, once each, and returns what happened
+    /// to each of them. The existing memberships are left alone. The unknown users are reported,
+    /// or fail the whole change with `all_or_nothing`.
+    async fn add_users_to_group(
+        &self,
+        group_id: GroupId,
+        user_ids: &[UserId],
+        all_or_nothing: bool,
+    ) -> Result<Vec<MembershipChange>>;
+    /// Same as `add_users_to_group`, for the removals.
+    async fn remove_users_from_group(
+        &self,
+        group_id: GroupId,
+        user_ids: &[UserId],
+        all_or_nothing: bool,
+    ) -> Result<Vec<MembershipChange>>;
     async fn get_user_groups(&self, user_id: &UserId) -> Result<HashSet<GroupDetails>>;
+    /// Same as `get_user_groups` for several users, in a single query. The unknown and deleted
+    /// users are left out.
+    async fn get_users_groups(
+        &self,
+        user_ids: &[UserId],
+    ) -> Result<HashMap<UserId, HashSet<GroupDetails>>>;
+    /// Clears the failed login attempts of the user, lifting any lockout.
+    async fn unlock_user(&self, user_id: &UserId) -> Result<()>;
+    /// Case-insensitive substring search in the given columns (all the display fields if empty),
+    /// best matches first.
+    async fn search_users(
+        &self,
+        query: &str,
+        fields: &[UserColumn],
+        limit: usize,
+    ) -> Result<Vec<User>>;
+}
+
+#[async_trait]
+pub trait UserAttributeBackendHandler {
+    async fn create_user_attribute(&self, schema: AttributeSchema) -> Result<()>;
+    /// Indexes the values of the attribute, for the searches on it, or stops indexing them.
+    async fn set_user_attribute_indexed(
+        &self,
+        name: &AttributeName,
+        is_indexed: bool,
+    ) -> Result<()>;
+    async fn list_user_attributes_schema(&self) -> Result<Vec<AttributeSchema>>;
+    async fn get_user_attributes(&self, user_id: &UserId) -> Result<Vec<Attribute>>;
+    /// Replaces all the values of the attribute for the user. An empty list removes the attribute.
+    async fn set_user_attribute(
+        &self,
+        user_id: &UserId,
+        name: &AttributeName,
+        values: Vec<AttributeValue>,
+    ) -> Result<()>;
+}
+
+#[async_trait]
+pub trait GroupAttributeBackendHandler {
+    async fn create_group_attribute(&self, schema: AttributeSchema) -> Result<()>;
+    /// Indexes the values of the attribute, for the searches on it, or stops indexing them.
+    async fn set_group_attribute_indexed(
+        &self,
+        name: &AttributeName,
+        is_indexed: bool,
+    ) -> Result<()>;
+    async fn list_group_attributes_schema(&self) -> Result<Vec<AttributeSchema>>;
+    async fn get_group_attributes(&self, group_id: GroupId) -> Result<Vec<Attribute>>;
+    /// Replaces all the values of the attribute for the group. An empty list removes the
+    /// attribute.
+    async fn set_group_attribute(
+        &self,
+        group_id: GroupId,
+        name: &AttributeName,
+        values: Vec<AttributeValue>,
+    ) -> Result<()>;
 }
 
 #[async_trait]
-pub trait BackendHandler: Clone + Send + GroupBackendHandler + UserBackendHandler {}
+pub trait UserImportBackendHandler {
+    /// Validates all the users, then imports them in a single transaction. If any user is
+    /// invalid, nothing is imported and the report lists the failures.
+    async fn import_users(&self, users: Vec<ImportUserRequest>) -> Result<ImportReport>;
+}
+
+/// Groups can be members of other groups. The LDAP `member` and `memberOf` attributes reflect the
+/// transitive members, up to the configured maximum nesting depth.
+#[async_trait]
+pub trait NestedGroupBackendHandler {
+    /// Fails if `child` is already (transitively) a parent of `parent`.
+    async fn add_group_to_group(&self, parent: GroupId, child: GroupId) -> Result<()>;
+    async fn remove_group_from_group(&self, parent: GroupId, child: GroupId) -> Result<()>;
+    /// The direct members of the group and of all its (transitive) subgroups.
+    async fn effective_members(&self, group_id: GroupId) -> Result<HashSet<UserId>>;
+}
+
+/// Every change to a user or a group is recorded in the audit log, in the same transaction.
+#[async_trait]
+pub trait AuditLogBackendHandler {
+    /// The entries about `target` between `from` and `to` (inclusive), oldest first.
+    async fn get_audit_log(
+        &self,
+        target: AuditTarget,
+        from: DateTime,
+        to: DateTime,
+    ) -> Result<Vec<AuditLogEntry>>;
+    /// All the entries of a single request, in the order they were written.
+    async fn get_audit_log_by_correlation_id(
+        &self,
+        correlation_id: &str,
+    ) -> Result<Vec<AuditLogEntry>>;
+}
+
+#[async_trait]
+pub trait BackendHandler:
+    Clone
+    + Send
+    + GroupBackendHandler
+    + UserBackendHandler
+    + SudoRoleBackendHandler
+    + GroupRuleBackendHandler
+    + SessionBackendHandler
+    + MaintenanceBackendHandler
+{
+    /// Attributes the following changes made through this handler to `actor`. Without an actor,
+    /// the changes are attributed to the server itself.
+    fn set_audit_actor(&mut self, _actor: AuditActor) {}
+    /// Tags the following changes made through this handler with the ID of the request, in the
+    /// audit log and the webhook payloads.
+    fn set_correlation_id(&mut self, _correlation_id: String) {}
+    /// The changes since `since`, or everything if `None`. A change may be returned again by the
+    /// next sync, and a user or group deleted then recreated shows up in both the deletions and
+    /// the changes: apply the deletions first.
+    async fn changed_since(&self, since: Option<DateTime>) -> Result<ChangeSet>;
+}
+
+#[cfg(test)]
+use super::group_rule_handler::{CreateGroupRuleRequest, GroupRule};
+#[cfg(test)]
+use super::maintenance_handler::MaintenanceStatus;
+#[cfg(test)]
+use super::oidc_handler::{CreateOidcClientRequest, OidcAuthorization, OidcClient, OidcHandler};
+#[cfg(test)]
+use super::session_handler::Session;
+#[cfg(test)]
+use super::sudo_role_handler::{SudoRole, SudoRoleRequest, SudoRoleRequestFilter};
 
 #[cfg(test)]
 mockall::mock! {
@@ -109,27 +440,79 @@ mockall::mock! {
     #[async_trait]
     impl GroupBackendHandler for TestBackendHandler {
         async fn list_groups(&self, filters: Option<GroupRequestFilter>) -> Result<Vec<Group>>;
+        async fn list_groups_page(&self, filters: Option<GroupRequestFilter>, page: Pagination) -> Result<Page<Group>>;
+        async fn count_groups(&self, filters: Option<GroupRequestFilter>) -> Result<usize>;
+        async fn list_groups_with_member_counts(&self) -> Result<Vec<(GroupDetails, usize)>>;
         async fn get_group_details(&self, group_id: GroupId) -> Result<GroupDetails>;
         async fn update_group(&self, request: UpdateGroupRequest) -> Result<()>;
         async fn create_group(&self, group_name: &str) -> Result<GroupId>;
+        async fn create_group_at(&self, group_name: &str, creation_date: DateTime) -> Result<GroupId>;
         async fn delete_group(&self, group_id: GroupId) -> Result<()>;
     }
     #[async_trait]
     impl UserBackendHandler for TestBackendHandler {
         async fn list_users(&self, filters: Option<UserRequestFilter>, get_groups: bool) -> Result<Vec<UserAndGroups>>;
+        async fn list_users_page(&self, filters: Option<UserRequestFilter>, get_groups: bool, page: Pagination) -> Result<Page<UserAndGroups>>;
+        async fn list_users_sorted(&self, filters: Option<UserRequestFilter>, get_groups: bool, sort: Vec<UserSortKey>, offset: usize, limit: Option<usize>) -> Result<Vec<UserAndGroups>>;
+        async fn count_users(&self, filters: Option<UserRequestFilter>) -> Result<usize>;
         async fn get_user_details(&self, user_id: &UserId) -> Result<User>;
         async fn create_user(&self, request: CreateUserRequest) -> Result<()>;
         async fn update_user(&self, request: UpdateUserRequest) -> Result<()>;
         async fn delete_user(&self, user_id: &UserId) -> Result<()>;
+        async fn rename_user(&self, user_id: &UserId, new_user_id: &UserId) -> Result<()>;
         async fn get_user_groups(&self, user_id: &UserId) -> Result<HashSet<GroupDetails>>;
+        async fn get_users_groups(&self, user_ids: &[UserId]) -> Result<HashMap<UserId, HashSet<GroupDetails>>>;
         async fn add_user_to_group(&self, user_id: &UserId, group_id: GroupId) -> Result<()>;
         async fn remove_user_from_group(&self, user_id: &UserId, group_id: GroupId) -> Result<()>;
+        async fn add_users_to_group(&self, group_id: GroupId, user_ids: &[UserId], all_or_nothing: bool) -> Result<Vec<MembershipChange>>;
+        async fn remove_users_from_group(&self, group_id: GroupId, user_ids: &[UserId], all_or_nothing: bool) -> Result<Vec<MembershipChange>>;
+        async fn unlock_user(&self, user_id: &UserId) -> Result<()>;
+        async fn search_users(&self, query: &str, fields: &[UserColumn], limit: usize) -> Result<Vec<User>>;
     }
     #[async_trait]
-    impl BackendHandler for TestBackendHandler {}
+    impl SudoRoleBackendHandler for TestBackendHandler {
+        async fn list_sudo_roles(&self, filters: Option<SudoRoleRequestFilter>) -> Result<Vec<SudoRole>>;
+        async fn create_sudo_role(&self, request: SudoRoleRequest) -> Result<()>;
+        async fn update_sudo_role(&self, request: SudoRoleRequest) -> Result<()>;
+        async fn delete_sudo_role(&self, name: &str) -> Result<()>;
+    }
+    #[async_trait]
+    impl GroupRuleBackendHandler for TestBackendHandler {
+        async fn list_group_rules(&self) -> Result<Vec<GroupRule>>;
+        async fn create_group_rule(&self, request: CreateGroupRuleRequest) -> Result<i32>;
+        async fn delete_group_rule(&self, rule_id: i32) -> Result<()>;
+        async fn reevaluate_group_rules(&self) -> Result<usize>;
+    }
+    #[async_trait]
+    impl SessionBackendHandler for TestBackendHandler {
+        async fn list_sessions(&self, user_id: &UserId) -> Result<Vec<Session>>;
+        async fn get_session(&self, session_id: &str) -> Result<Session>;
+        async fn revoke_session(&self, session_id: &str) -> Result<()>;
+    }
+    #[async_trait]
+    impl MaintenanceBackendHandler for TestBackendHandler {
+        async fn start_maintenance(&self, dry_run: bool) -> Result<()>;
+        async fn get_maintenance_status(&self) -> Result<Option<MaintenanceStatus>>;
+    }
+    #[async_trait]
+    impl BackendHandler for TestBackendHandler {
+        async fn changed_since(&self, since: Option<DateTime>) -> Result<ChangeSet>;
+    }
     #[async_trait]
     impl LoginHandler for TestBackendHandler {
         async fn bind(&self, request: BindRequest) -> Result<()>;
+        async fn get_scram_verifier(&self, user_id: &UserId) -> Result<Option<String>>;
+        async fn finish_scram_bind(&self, user_id: &UserId, valid_proof: bool) -> Result<()>;
+    }
+    #[async_trait]
+    impl OidcHandler for TestBackendHandler {
+        async fn create_oidc_client(&self, request: CreateOidcClientRequest) -> Result<String>;
+        async fn list_oidc_clients(&self) -> Result<Vec<OidcClient>>;
+        async fn get_oidc_client(&self, client_id: &str) -> Result<OidcClient>;
+        async fn delete_oidc_client(&self, client_id: &str) -> Result<()>;
+        async fn authenticate_oidc_client(&self, client_id: &str, client_secret: &str) -> Result<OidcClient>;
+        async fn create_oidc_authorization_code(&self, authorization: OidcAuthorization) -> Result<String>;
+        async fn consume_oidc_authorization_code(&self, client_id: &str, code: &str) -> Result<OidcAuthorization>;
     }
 }
 
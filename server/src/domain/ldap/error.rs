@@ -1,3 +1,4 @@
+use crate::domain::error::DomainError;
 use ldap3_proto::LdapResultCode;
 
 #[derive(Debug, PartialEq)]
@@ -15,3 +16,11 @@ impl std::fmt::Display for LdapError {
 impl std::error::Error for LdapError {}
 
 pub type LdapResult<T> = std::result::Result<T, LdapError>;
+
+/// The result code of a failed backend call: `Busy` tells the client to retry later.
+pub fn get_error_code(error: &DomainError) -> LdapResultCode {
+    match error {
+        DomainError::ServerBusy => LdapResultCode::Busy,
+        _ => LdapResultCode::Other,
+    }
+}
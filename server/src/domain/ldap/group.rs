@@ -3,38 +3,73 @@ use ldap3_proto::{
 };
 use tracing::{debug, info, instrument, warn};
 
-use crate::domain::{
-    handler::{BackendHandler, GroupRequestFilter},
-    ldap::error::LdapError,
-    types::{Group, GroupColumn, UserId, Uuid},
+use std::collections::HashMap;
+
+use crate::{
+    domain::{
+        handler::{BackendHandler, GroupRequestFilter, Pagination, UserRequestFilter},
+        ldap::error::LdapError,
+        types::{Group, GroupColumn, UserColumn, UserId, Uuid},
+    },
+    infra::configuration::UserRdnAttribute,
 };
 
 use super::{
-    error::LdapResult,
+    error::{get_error_code, LdapResult},
     utils::{
-        expand_attribute_wildcards, get_user_id_from_distinguished_name, map_group_field, LdapInfo,
+        escape_dn_value, expand_attribute_wildcards, get_user_rdn_from_distinguished_name,
+        make_user_distinguished_name, map_group_field, split_comparison, split_matching_rule,
+        LdapInfo, UserRdn,
     },
 };
 
 fn get_group_attribute(
     group: &Group,
-    base_dn_str: &str,
+    ldap_info: &LdapInfo,
     attribute: &str,
     user_filter: &Option<&UserId>,
-    ignored_group_attributes: &[String],
+    member_emails: &HashMap<UserId, String>,
 ) -> Option<Vec<Vec<u8>>> {
     let attribute = attribute.to_ascii_lowercase();
     let attribute_values = match attribute.as_str() {
-        "objectclass" => vec![b"groupOfUniqueNames".to_vec()],
+        "objectclass" => {
+            let object_classes = ldap_info
+                .object_classes
+                .group
+                .iter()
+                // A posixGroup must have a gidNumber.
+                .filter(|object_class| {
+                    group.gid_number.is_some() || !object_class.eq_ignore_ascii_case("posixGroup")
+                })
+                .map(|object_class| object_class.clone().into_bytes())
+                .collect::<Vec<_>>();
+            if object_classes.is_empty() {
+                return None;
+            }
+            object_classes
+        }
+        "gidnumber" => vec![group.gid_number?.to_string().into_bytes()],
+        // The members of the posixGroup, by user ID whatever the RDN of their DN.
+        "memberuid" => group
+            .users
+            .iter()
+            .filter(|u| user_filter.map(|f| *u == f).unwrap_or(true))
+            .map(|u| u.to_string().into_bytes())
+            .collect(),
         // Always returned as part of the base response.
         "dn" | "distinguishedname" => return None,
         "cn" | "uid" => vec![group.display_name.clone().into_bytes()],
         "entryuuid" => vec![group.uuid.to_string().into_bytes()],
+        "createtimestamp" => vec![group.creation_date.to_rfc3339().into_bytes()],
+        "modifytimestamp" => vec![group.modified_at.to_rfc3339().into_bytes()],
         "member" | "uniquemember" => group
             .users
             .iter()
             .filter(|u| user_filter.map(|f| *u == f).unwrap_or(true))
-            .map(|u| format!("uid={},ou=people,{}", u, base_dn_str).into_bytes())
+            .map(|u| {
+                let email = member_emails.get(u).map(String::as_str).unwrap_or_default();
+                make_user_distinguished_name(u, email, ldap_info).into_bytes()
+            })
             .collect(),
         "1.1" => return None,
         // We ignore the operational attribute wildcard
@@ -46,7 +81,7 @@ fn get_group_attribute(
             )
         }
         _ => {
-            if !ignored_group_attributes.contains(&attribute) {
+            if !ldap_info.ignored_group_attributes.contains(&attribute) {
                 warn!(
                     r#"Ignoring unrecognized group attribute: {}\n\
                       To disable this warning, add it to "ignored_group_attributes" in the config."#,
@@ -63,7 +98,21 @@ fn get_group_attribute(
     }
 }
 
-const ALL_GROUP_ATTRIBUTE_KEYS: &[&str] = &[
+/// Whether the groups have this objectClass. `groupOfNames` matches as well as
+/// `groupOfUniqueNames`: the groups have both `member` and `uniqueMember`.
+fn is_group_object_class(ldap_info: &LdapInfo, value: &str) -> bool {
+    let has_object_class = |name: &str| {
+        ldap_info
+            .object_classes
+            .group
+            .iter()
+            .any(|object_class| object_class.eq_ignore_ascii_case(name))
+    };
+    has_object_class(value)
+        || (value.eq_ignore_ascii_case("groupOfNames") && has_object_class("groupOfUniqueNames"))
+}
+
+pub const ALL_GROUP_ATTRIBUTE_KEYS: &[&str] = &[
     "objectclass",
     "uid",
     "cn",
@@ -74,25 +123,21 @@ const ALL_GROUP_ATTRIBUTE_KEYS: &[&str] = &[
 
 fn make_ldap_search_group_result_entry(
     group: Group,
-    base_dn_str: &str,
-    attributes: &[String],
+    ldap_info: &LdapInfo,
+    expanded_attributes: &[&str],
     user_filter: &Option<&UserId>,
-    ignored_group_attributes: &[String],
+    member_emails: &HashMap<UserId, String>,
 ) -> LdapSearchResultEntry {
-    let expanded_attributes = expand_attribute_wildcards(attributes, ALL_GROUP_ATTRIBUTE_KEYS);
-
     LdapSearchResultEntry {
-        dn: format!("cn={},ou=groups,{}", group.display_name, base_dn_str),
+        dn: format!(
+            "cn={},{}",
+            escape_dn_value(&group.display_name),
+            ldap_info.groups_dn_str()
+        ),
         attributes: expanded_attributes
             .iter()
             .filter_map(|a| {
-                let values = get_group_attribute(
-                    &group,
-                    base_dn_str,
-                    a,
-                    user_filter,
-                    ignored_group_attributes,
-                )?;
+                let values = get_group_attribute(&group, ldap_info, a, user_filter, member_emails)?;
                 Some(LdapPartialAttribute {
                     atype: a.to_string(),
                     vals: values,
@@ -102,30 +147,79 @@ fn make_ldap_search_group_result_entry(
     }
 }
 
+/// The emails of the `mail` member DNs in the filter, to be resolved to user IDs.
+fn get_filter_member_emails(ldap_info: &LdapInfo, filter: &LdapFilter) -> Vec<String> {
+    match filter {
+        LdapFilter::And(filters) | LdapFilter::Or(filters) => filters
+            .iter()
+            .flat_map(|f| get_filter_member_emails(ldap_info, f))
+            .collect(),
+        LdapFilter::Not(filter) => get_filter_member_emails(ldap_info, filter),
+        LdapFilter::Equality(field, value)
+            if field.eq_ignore_ascii_case("member")
+                || field.eq_ignore_ascii_case("uniquemember") =>
+        {
+            match get_user_rdn_from_distinguished_name(&value.to_ascii_lowercase(), ldap_info) {
+                Ok(UserRdn::Email(email)) => vec![email],
+                _ => Vec::new(),
+            }
+        }
+        _ => Vec::new(),
+    }
+}
+
+/// Looks up the users with these emails, or with these IDs, and maps their emails to their IDs.
+async fn get_user_emails<Backend: BackendHandler>(
+    backend: &Backend,
+    filters: Vec<UserRequestFilter>,
+) -> LdapResult<Vec<(UserId, String)>> {
+    if filters.is_empty() {
+        return Ok(Vec::new());
+    }
+    Ok(backend
+        .list_users(Some(UserRequestFilter::Or(filters)), false)
+        .await
+        .map_err(|e| LdapError {
+            code: LdapResultCode::Other,
+            message: format!("Error while looking up the members: {:#}", e),
+        })?
+        .into_iter()
+        .map(|u| (u.user.user_id, u.user.email))
+        .collect())
+}
+
 fn convert_group_filter(
     ldap_info: &LdapInfo,
     filter: &LdapFilter,
+    member_ids: &HashMap<String, UserId>,
 ) -> LdapResult<GroupRequestFilter> {
-    let rec = |f| convert_group_filter(ldap_info, f);
+    let rec = |f| convert_group_filter(ldap_info, f, member_ids);
     match filter {
-        LdapFilter::Equality(field, value) => {
+        // The ordering filters are not supported on groups.
+        LdapFilter::Equality(field, value) if split_comparison(field).1.is_none() => {
+            // The group names are case-insensitive whatever the matching rule.
+            let (field, _) = split_matching_rule(field)?;
             let field = &field.to_ascii_lowercase();
             let value = &value.to_ascii_lowercase();
             match field.as_str() {
                 "member" | "uniquemember" => {
-                    let user_name = get_user_id_from_distinguished_name(
-                        value,
-                        &ldap_info.base_dn,
-                        &ldap_info.base_dn_str,
-                    )?;
-                    Ok(GroupRequestFilter::Member(user_name))
+                    match get_user_rdn_from_distinguished_name(value, ldap_info)? {
+                        UserRdn::UserId(user_name) => Ok(GroupRequestFilter::Member(user_name)),
+                        UserRdn::Email(email) => Ok(match member_ids.get(&email) {
+                            Some(user_id) => GroupRequestFilter::Member(user_id.clone()),
+                            // No such user, no such member.
+                            None => {
+                                GroupRequestFilter::Not(Box::new(GroupRequestFilter::And(vec![])))
+                            }
+                        }),
+                    }
                 }
-                "objectclass" => match value.as_str() {
-                    "groupofuniquenames" | "groupofnames" => Ok(GroupRequestFilter::And(vec![])),
-                    _ => Ok(GroupRequestFilter::Not(Box::new(GroupRequestFilter::And(
-                        vec![],
-                    )))),
-                },
+                "memberuid" => Ok(GroupRequestFilter::Member(UserId::new(value))),
+                "objectclass" => Ok(if is_group_object_class(ldap_info, value) {
+                    GroupRequestFilter::And(vec![])
+                } else {
+                    GroupRequestFilter::Not(Box::new(GroupRequestFilter::And(vec![])))
+                }),
                 _ => match map_group_field(field) {
                     Some(GroupColumn::DisplayName) => {
                         Ok(GroupRequestFilter::DisplayName(value.to_string()))
@@ -136,6 +230,12 @@ fn convert_group_filter(
                             message: format!("Invalid UUID: {:#}", e),
                         })?,
                     )),
+                    Some(GroupColumn::GidNumber) => Ok(match value.parse() {
+                        Ok(gid_number) => GroupRequestFilter::GidNumber(gid_number),
+                        Err(_) => {
+                            GroupRequestFilter::Not(Box::new(GroupRequestFilter::And(vec![])))
+                        }
+                    }),
                     _ => {
                         if !ldap_info.ignored_group_attributes.contains(field) {
                             warn!(
@@ -160,7 +260,9 @@ fn convert_group_filter(
         LdapFilter::Not(filter) => Ok(GroupRequestFilter::Not(Box::new(rec(filter)?))),
         LdapFilter::Present(field) => {
             let field = &field.to_ascii_lowercase();
-            if field == "objectclass"
+            if matches!(map_group_field(field), Some(GroupColumn::GidNumber)) {
+                Ok(GroupRequestFilter::HasGidNumber)
+            } else if field == "objectclass"
                 || field == "dn"
                 || field == "distinguishedname"
                 || map_group_field(field).is_some()
@@ -186,10 +288,22 @@ pub async fn get_groups_list<Backend: BackendHandler>(
     attributes: &[String],
     base: &str,
     user_filter: &Option<&UserId>,
+    page: Option<Pagination>,
     backend: &mut Backend,
-) -> LdapResult<Vec<LdapOp>> {
+) -> LdapResult<(Vec<LdapOp>, Option<String>)> {
     debug!(?ldap_filter);
-    let filter = convert_group_filter(ldap_info, ldap_filter)?;
+    let member_ids = get_user_emails(
+        backend,
+        get_filter_member_emails(ldap_info, ldap_filter)
+            .into_iter()
+            .map(|email| UserRequestFilter::Equality(UserColumn::Email, email))
+            .collect(),
+    )
+    .await?
+    .into_iter()
+    .map(|(user_id, email)| (email, user_id))
+    .collect();
+    let filter = convert_group_filter(ldap_info, ldap_filter, &member_ids)?;
     let parsed_filters = match user_filter {
         None => filter,
         Some(u) => {
@@ -198,24 +312,53 @@ pub async fn get_groups_list<Backend: BackendHandler>(
         }
     };
     debug!(?parsed_filters);
-    let groups = backend
-        .list_groups(Some(parsed_filters))
-        .await
-        .map_err(|e| LdapError {
-            code: LdapResultCode::Other,
-            message: format!(r#"Error while listing groups "{}": {:#}"#, base, e),
-        })?;
+    let (groups, next_cursor) = match page {
+        None => backend
+            .list_groups(Some(parsed_filters))
+            .await
+            .map(|groups| (groups, None)),
+        Some(page) => backend
+            .list_groups_page(Some(parsed_filters), page)
+            .await
+            .map(|page| (page.items, page.next_cursor)),
+    }
+    .map_err(|e| LdapError {
+        code: get_error_code(&e),
+        message: format!(r#"Error while listing groups "{}": {:#}"#, base, e),
+    })?;
 
-    Ok(groups
+    let expanded_attributes = expand_attribute_wildcards(attributes, ALL_GROUP_ATTRIBUTE_KEYS);
+    // The member DNs are named after their emails.
+    let need_member_emails = ldap_info.user_rdn_attribute == UserRdnAttribute::Mail
+        && expanded_attributes
+            .iter()
+            .any(|a| a.eq_ignore_ascii_case("member") || a.eq_ignore_ascii_case("uniquemember"));
+    let member_emails = if need_member_emails {
+        let members = groups
+            .iter()
+            .flat_map(|g| g.users.iter().cloned())
+            .collect::<std::collections::HashSet<_>>();
+        get_user_emails(
+            backend,
+            members.into_iter().map(UserRequestFilter::UserId).collect(),
+        )
+        .await?
+        .into_iter()
+        .collect()
+    } else {
+        HashMap::new()
+    };
+    let entries = groups
         .into_iter()
         .map(|u| {
             LdapOp::SearchResultEntry(make_ldap_search_group_result_entry(
                 u,
-                &ldap_info.base_dn_str,
-                attributes,
+                ldap_info,
+                &expanded_attributes,
                 user_filter,
-                &ldap_info.ignored_group_attributes,
+                &member_emails,
             ))
         })
-        .collect::<Vec<_>>())
+        .collect::<Vec<_>>();
+    Ok((entries, next_cursor))
 }
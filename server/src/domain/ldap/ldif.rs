@@ -0,0 +1,545 @@
+use crate::domain::{
+    error::DomainError,
+    handler::{BackendHandler, CreateUserRequest, GroupRequestFilter},
+    ldap::utils::first_rdn_value,
+    types::{parse_generalized_time, DateTime, GroupId, UserId},
+};
+use std::collections::{HashMap, HashSet};
+use tracing::{debug, info, instrument};
+
+#[derive(thiserror::Error, Debug)]
+pub enum LdifError {
+    #[error("Line {line}: {message}")]
+    Parse { line: usize, message: String },
+    #[error(transparent)]
+    Domain(#[from] DomainError),
+}
+
+fn parse_error<T>(line: usize, message: impl Into<String>) -> Result<T, LdifError> {
+    Err(LdifError::Parse {
+        line,
+        message: message.into(),
+    })
+}
+
+#[derive(Debug, PartialEq, Eq)]
+struct LdifAttribute {
+    /// Lowercase.
+    name: String,
+    value: Vec<u8>,
+    line: usize,
+}
+
+#[derive(Debug, PartialEq, Eq)]
+struct LdifEntry {
+    dn: String,
+    line: usize,
+    attributes: Vec<LdifAttribute>,
+}
+
+impl LdifEntry {
+    fn values<'a>(&'a self, name: &'a str) -> impl Iterator<Item = &'a LdifAttribute> + 'a {
+        self.attributes.iter().filter(move |a| a.name == name)
+    }
+
+    fn string_values(&self, name: &str) -> Result<Vec<String>, LdifError> {
+        self.values(name)
+            .map(|a| match std::str::from_utf8(&a.value) {
+                Ok(s) => Ok(s.to_owned()),
+                Err(_) => parse_error(a.line, format!("Invalid UTF-8 in {}", name)),
+            })
+            .collect()
+    }
+
+    fn first_string(&self, name: &str) -> Result<Option<String>, LdifError> {
+        Ok(self.string_values(name)?.into_iter().next())
+    }
+}
+
+/// Joins the folded lines (continuations start with a space), and returns each logical line with
+/// the number of its first physical line. Blank lines are kept as entry separators.
+fn unfold_lines(ldif: &str) -> Vec<(usize, String)> {
+    let mut lines: Vec<(usize, String)> = Vec::new();
+    for (index, line) in ldif.lines().enumerate() {
+        let line = line.strip_suffix('\r').unwrap_or(line);
+        match (line.strip_prefix(' '), lines.last_mut()) {
+            (Some(continuation), Some((_, previous))) if !previous.is_empty() => {
+                previous.push_str(continuation)
+            }
+            _ => lines.push((index + 1, line.to_owned())),
+        }
+    }
+    lines
+}
+
+fn parse_attribute(line_number: usize, line: &str) -> Result<LdifAttribute, LdifError> {
+    let (name, value) = match line.split_once(':') {
+        Some(split) => split,
+        None => return parse_error(line_number, format!("Expected \"name: value\": {}", line)),
+    };
+    let value = if let Some(encoded) = value.strip_prefix(':') {
+        match base64::decode(encoded.trim()) {
+            Ok(bytes) => bytes,
+            Err(e) => {
+                return parse_error(
+                    line_number,
+                    format!("Invalid base64 value for {}: {}", name, e),
+                )
+            }
+        }
+    } else if value.starts_with('<') {
+        return parse_error(line_number, "URL values are not supported");
+    } else {
+        value.trim_start().as_bytes().to_vec()
+    };
+    Ok(LdifAttribute {
+        name: name.trim().to_ascii_lowercase(),
+        value,
+        line: line_number,
+    })
+}
+
+fn parse_ldif(ldif: &str) -> Result<Vec<LdifEntry>, LdifError> {
+    let mut entries = Vec::new();
+    let mut current: Option<LdifEntry> = None;
+    for (line_number, line) in unfold_lines(ldif) {
+        if line.trim().is_empty() {
+            entries.extend(current.take());
+            continue;
+        }
+        if line.starts_with('#') {
+            continue;
+        }
+        let attribute = parse_attribute(line_number, &line)?;
+        match &mut current {
+            None if attribute.name == "version" => {}
+            None if attribute.name == "dn" => {
+                current = Some(LdifEntry {
+                    dn: String::from_utf8(attribute.value)
+                        .or_else(|_| parse_error(line_number, "Invalid UTF-8 in the DN"))?,
+                    line: line_number,
+                    attributes: Vec::new(),
+                })
+            }
+            None => return parse_error(line_number, "Expected the entry to start with a dn"),
+            Some(entry) => entry.attributes.push(attribute),
+        }
+    }
+    entries.extend(current);
+    Ok(entries)
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum LdifEntryStatus {
+    /// Created, or would be created in a dry run.
+    Created,
+    /// Already exists.
+    Skipped,
+    /// Doesn't match the schema.
+    Rejected(String),
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LdifEntryReport {
+    pub line: usize,
+    pub dn: String,
+    pub status: LdifEntryStatus,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub struct LdifImportReport {
+    pub entries: Vec<LdifEntryReport>,
+    /// (user, group name) pairs, added or that would be added in a dry run.
+    pub memberships: Vec<(UserId, String)>,
+    /// Memberships that reference a user or group that doesn't exist.
+    pub unresolved_memberships: Vec<(UserId, String)>,
+}
+
+enum ParsedEntry {
+    User(CreateUserRequest),
+    Group {
+        name: String,
+        creation_date: Option<DateTime>,
+    },
+}
+
+fn has_object_class(object_classes: &[String], candidates: &[&str]) -> bool {
+    object_classes
+        .iter()
+        .any(|c| candidates.contains(&c.to_ascii_lowercase().as_str()))
+}
+
+/// Maps the entry to a user or a group, and collects its memberships.
+fn map_entry(
+    entry: &LdifEntry,
+    memberships: &mut Vec<(UserId, String)>,
+) -> Result<Result<ParsedEntry, String>, LdifError> {
+    if let Some(change_type) = entry.first_string("changetype")? {
+        if !change_type.eq_ignore_ascii_case("add") {
+            return Ok(Err(format!("Unsupported changetype: {}", change_type)));
+        }
+    }
+    // Kept from the original directory, as the UUIDs are derived from it.
+    let creation_date = match entry.first_string("createtimestamp")? {
+        Some(value) => match parse_generalized_time(&value) {
+            Some(date) => Some(date),
+            None => return Ok(Err(format!("Invalid createTimestamp: {}", value))),
+        },
+        None => None,
+    };
+    let object_classes = entry.string_values("objectclass")?;
+    if has_object_class(
+        &object_classes,
+        &["inetorgperson", "posixaccount", "person"],
+    ) {
+        let user_id = match entry.first_string("uid")? {
+            Some(uid) => UserId::new(&uid),
+            None => return Ok(Err("Missing uid".to_owned())),
+        };
+        let email = match entry.first_string("mail")? {
+            Some(mail) => mail,
+            None => return Ok(Err("Missing mail".to_owned())),
+        };
+        for group_dn in entry.string_values("memberof")? {
+            if let Some(group) = first_rdn_value(&group_dn) {
+                memberships.push((user_id.clone(), group));
+            }
+        }
+        Ok(Ok(ParsedEntry::User(CreateUserRequest {
+            user_id,
+            email,
+            display_name: match entry.first_string("displayname")? {
+                Some(name) => Some(name),
+                None => entry.first_string("cn")?,
+            },
+            first_name: entry.first_string("givenname")?,
+            last_name: entry.first_string("sn")?,
+            creation_date,
+            ..Default::default()
+        })))
+    } else if has_object_class(
+        &object_classes,
+        &["groupofnames", "groupofuniquenames", "posixgroup"],
+    ) {
+        let name = match entry.first_string("cn")? {
+            Some(cn) => cn,
+            None => return Ok(Err("Missing cn".to_owned())),
+        };
+        for member_dn in entry
+            .string_values("member")?
+            .into_iter()
+            .chain(entry.string_values("uniquemember")?)
+        {
+            if let Some(user) = first_rdn_value(&member_dn) {
+                memberships.push((UserId::new(&user), name.clone()));
+            }
+        }
+        for user in entry.string_values("memberuid")? {
+            memberships.push((UserId::new(&user), name.clone()));
+        }
+        Ok(Ok(ParsedEntry::Group {
+            name,
+            creation_date,
+        }))
+    } else {
+        Ok(Err(format!(
+            "Unsupported object classes: {}",
+            object_classes.join(", ")
+        )))
+    }
+}
+
+/// Imports the users and groups of an LDIF file, then their memberships: groups can be defined
+/// after the users that reference them. In a dry run, nothing is written.
+#[instrument(skip_all, level = "debug", err)]
+pub async fn import_ldif<Backend: BackendHandler>(
+    backend: &Backend,
+    ldif: &str,
+    dry_run: bool,
+) -> Result<LdifImportReport, LdifError> {
+    debug!(?dry_run);
+    let mut report = LdifImportReport::default();
+    // First pass: parse everything, so that errors are reported before any write.
+    let mut memberships = Vec::new();
+    let mut parsed_entries = Vec::new();
+    for entry in parse_ldif(ldif)? {
+        match map_entry(&entry, &mut memberships)? {
+            Ok(parsed) => parsed_entries.push((entry, parsed)),
+            Err(reason) => report.entries.push(LdifEntryReport {
+                line: entry.line,
+                dn: entry.dn,
+                status: LdifEntryStatus::Rejected(reason),
+            }),
+        }
+    }
+    let mut groups: HashMap<String, Option<GroupId>> = backend
+        .list_groups(None)
+        .await?
+        .into_iter()
+        .map(|g| (g.display_name, Some(g.id)))
+        .collect();
+    let mut users = HashSet::new();
+    for (entry, parsed) in parsed_entries {
+        let status = match parsed {
+            ParsedEntry::User(request) => {
+                let user_id = request.user_id.clone();
+                let exists = match backend.get_user_details(&user_id).await {
+                    Ok(_) => true,
+                    Err(DomainError::EntityNotFound(_)) => false,
+                    Err(e) => return Err(e.into()),
+                };
+                users.insert(user_id);
+                if exists {
+                    LdifEntryStatus::Skipped
+                } else {
+                    if !dry_run {
+                        backend.create_user(request).await?;
+                    }
+                    LdifEntryStatus::Created
+                }
+            }
+            ParsedEntry::Group {
+                name,
+                creation_date,
+            } => {
+                if groups.contains_key(&name) {
+                    LdifEntryStatus::Skipped
+                } else {
+                    let group_id = match (dry_run, creation_date) {
+                        (true, _) => None,
+                        (false, Some(creation_date)) => {
+                            Some(backend.create_group_at(&name, creation_date).await?)
+                        }
+                        (false, None) => Some(backend.create_group(&name).await?),
+                    };
+                    groups.insert(name, group_id);
+                    LdifEntryStatus::Created
+                }
+            }
+        };
+        report.entries.push(LdifEntryReport {
+            line: entry.line,
+            dn: entry.dn,
+            status,
+        });
+    }
+    // Second pass: all the groups are known now.
+    report.entries.sort_by_key(|e| e.line);
+    memberships.sort();
+    memberships.dedup();
+    for (user_id, group_name) in memberships {
+        let user_exists = users.contains(&user_id)
+            || match backend.get_user_details(&user_id).await {
+                Ok(_) => true,
+                Err(DomainError::EntityNotFound(_)) => false,
+                Err(e) => return Err(e.into()),
+            };
+        match (user_exists, groups.get(&group_name)) {
+            (true, Some(group_id)) => {
+                if let Some(group_id) = group_id {
+                    if !dry_run && !is_member(backend, &user_id, *group_id).await? {
+                        backend.add_user_to_group(&user_id, *group_id).await?;
+                    }
+                }
+                report.memberships.push((user_id, group_name));
+            }
+            _ => report.unresolved_memberships.push((user_id, group_name)),
+        }
+    }
+    info!(
+        "LDIF import{}: {} entries, {} memberships",
+        if dry_run { " (dry run)" } else { "" },
+        report.entries.len(),
+        report.memberships.len()
+    );
+    Ok(report)
+}
+
+async fn is_member<Backend: BackendHandler>(
+    backend: &Backend,
+    user_id: &UserId,
+    group_id: GroupId,
+) -> Result<bool, LdifError> {
+    Ok(!backend
+        .list_groups(Some(GroupRequestFilter::And(vec![
+            GroupRequestFilter::GroupId(group_id),
+            GroupRequestFilter::Member(user_id.clone()),
+        ])))
+        .await?
+        .is_empty())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::domain::{
+        handler::{GroupBackendHandler, UserBackendHandler},
+        sql_backend_handler::tests::*,
+    };
+
+    const LDIF: &str = r#"version: 1
+
+# A user that references a group defined later.
+dn: uid=alice,ou=people,dc=example,dc=com
+objectClass: inetOrgPerson
+uid: alice
+mail: alice@example.com
+cn: Alice
+givenName: Alice
+sn:: TGlkZGVsbA==
+memberOf: cn=admins,ou=groups,dc=example,dc=com
+
+dn: uid=bob,ou=people,dc=example,dc=com
+objectClass: inetOrgPerson
+uid: bob
+mail: bob@bob.bob
+
+dn: cn=admins,ou=groups,dc=example,dc=com
+objectClass: groupOfUniqueNames
+cn: admins
+uniqueMember: uid=bob,ou=people,dc=example,dc=com
+uniqueMember: uid=nobody,ou=people,dc=example,dc=com
+
+dn: ou=people,dc=example,dc=com
+objectClass: organizationalUnit
+ou: people
+"#;
+
+    #[test]
+    fn test_parse_ldif() {
+        let entries =
+            parse_ldif("dn: uid=bob,ou=people,\n dc=example\n# comment\ncn:: Ym9i\n").unwrap();
+        assert_eq!(
+            entries,
+            vec![LdifEntry {
+                dn: "uid=bob,ou=people,dc=example".to_owned(),
+                line: 1,
+                attributes: vec![LdifAttribute {
+                    name: "cn".to_owned(),
+                    value: b"bob".to_vec(),
+                    line: 4,
+                }],
+            }]
+        );
+    }
+
+    #[test]
+    fn test_parse_ldif_invalid_base64() {
+        let error = parse_ldif("dn: uid=bob\nobjectClass: person\ncn:: not*base64\n").unwrap_err();
+        assert!(
+            matches!(error, LdifError::Parse { line: 3, .. }),
+            "{:?}",
+            error
+        );
+    }
+
+    #[tokio::test]
+    async fn test_import_ldif_dry_run() {
+        let fixture = TestFixture::new().await;
+        let report = import_ldif(&fixture.handler, LDIF, true).await.unwrap();
+        assert_eq!(
+            report
+                .entries
+                .iter()
+                .map(|e| (e.line, &e.status))
+                .collect::<Vec<_>>(),
+            vec![
+                (4, &LdifEntryStatus::Created),
+                (13, &LdifEntryStatus::Skipped),
+                (18, &LdifEntryStatus::Created),
+                (
+                    24,
+                    &LdifEntryStatus::Rejected(
+                        "Unsupported object classes: organizationalUnit".to_owned()
+                    )
+                ),
+            ]
+        );
+        assert_eq!(
+            report.memberships,
+            vec![
+                (UserId::new("alice"), "admins".to_owned()),
+                (UserId::new("bob"), "admins".to_owned())
+            ]
+        );
+        assert_eq!(
+            report.unresolved_memberships,
+            vec![(UserId::new("nobody"), "admins".to_owned())]
+        );
+        // Nothing was written.
+        assert_eq!(
+            get_user_names(&fixture.handler, None).await,
+            vec!["bob", "john", "nogroup", "patrick"]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_import_ldif() {
+        let fixture = TestFixture::new().await;
+        import_ldif(&fixture.handler, LDIF, false).await.unwrap();
+        let alice = fixture
+            .handler
+            .get_user_details(&UserId::new("alice"))
+            .await
+            .unwrap();
+        assert_eq!(alice.last_name.as_deref(), Some("Liddell"));
+        let admins = fixture
+            .handler
+            .list_groups(Some(GroupRequestFilter::DisplayName("admins".to_owned())))
+            .await
+            .unwrap();
+        assert_eq!(
+            admins[0].users,
+            vec![UserId::new("alice"), UserId::new("bob")]
+        );
+        // Importing again changes nothing.
+        let report = import_ldif(&fixture.handler, LDIF, false).await.unwrap();
+        assert!(report
+            .entries
+            .iter()
+            .all(|e| e.status != LdifEntryStatus::Created));
+    }
+
+    #[tokio::test]
+    async fn test_import_ldif_creation_dates() {
+        let fixture = TestFixture::new().await;
+        let ldif = r#"dn: uid=alice,ou=people,dc=example,dc=com
+objectClass: inetOrgPerson
+uid: alice
+mail: alice@example.com
+createTimestamp: 20150621093000Z
+
+dn: cn=admins,ou=groups,dc=example,dc=com
+objectClass: groupOfNames
+cn: admins
+createTimestamp: 20160101000000Z
+
+dn: cn=staff,ou=groups,dc=example,dc=com
+objectClass: groupOfNames
+cn: staff
+createTimestamp: yesterday
+"#;
+        let report = import_ldif(&fixture.handler, ldif, false).await.unwrap();
+        assert_eq!(
+            report.entries[2].status,
+            LdifEntryStatus::Rejected("Invalid createTimestamp: yesterday".to_owned())
+        );
+        let date = |value| parse_generalized_time(value).unwrap();
+        let alice = fixture
+            .handler
+            .get_user_details(&UserId::new("alice"))
+            .await
+            .unwrap();
+        assert_eq!(alice.creation_date, date("20150621093000Z"));
+        assert_eq!(
+            alice.uuid,
+            crate::domain::types::Uuid::from_name_and_date("alice", &date("20150621093000Z"))
+        );
+        let admins = fixture
+            .handler
+            .list_groups(Some(GroupRequestFilter::DisplayName("admins".to_owned())))
+            .await
+            .unwrap();
+        assert_eq!(admins[0].creation_date, date("20160101000000Z"));
+    }
+}
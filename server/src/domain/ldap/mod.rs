@@ -1,4 +1,7 @@
 pub mod error;
 pub mod group;
+pub mod ldif;
+pub mod sort;
+pub mod sudo;
 pub mod user;
 pub mod utils;
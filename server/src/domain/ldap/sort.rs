@@ -0,0 +1,203 @@
+use std::cmp::Ordering;
+
+use ldap3_proto::LdapSearchResultEntry;
+
+use crate::domain::{handler::UserSortKey, types::UserColumn};
+
+use super::{
+    group::ALL_GROUP_ATTRIBUTE_KEYS, sudo::ALL_SUDO_ROLE_ATTRIBUTE_KEYS,
+    user::ALL_USER_ATTRIBUTE_KEYS, utils::map_user_field,
+};
+
+/// A key of the server-side sort control (RFC 2891). The ordering rule is ignored: the values
+/// are compared case-insensitively.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SortKey {
+    pub attribute: String,
+    pub reverse: bool,
+}
+
+/// The columns with an index, that the database can sort by.
+const SORTABLE_USER_COLUMNS: &[UserColumn] = &[
+    UserColumn::UserId,
+    UserColumn::Email,
+    UserColumn::DisplayName,
+    UserColumn::CreationDate,
+];
+
+/// The sort keys for the database, if it can sort the users by all of them.
+pub fn get_user_sort_keys(keys: &[SortKey]) -> Option<Vec<UserSortKey>> {
+    keys.iter()
+        .map(|key| {
+            map_user_field(&key.attribute.to_ascii_lowercase())
+                .filter(|column| SORTABLE_USER_COLUMNS.contains(column))
+                .map(|column| UserSortKey {
+                    column,
+                    descending: key.reverse,
+                })
+        })
+        .collect()
+}
+
+/// The sort attributes that the entries don't return for these requested attributes: they have
+/// to be requested too to sort the entries, then removed with `remove_attributes`.
+pub fn get_missing_sort_attributes(attributes: &[String], keys: &[SortKey]) -> Vec<String> {
+    let all_attributes = attributes.is_empty() || attributes.iter().any(|a| a == "*");
+    keys.iter()
+        .map(|key| key.attribute.to_ascii_lowercase())
+        .filter(|attribute| {
+            !attributes.iter().any(|a| a.eq_ignore_ascii_case(attribute))
+                && !(all_attributes
+                    && ALL_USER_ATTRIBUTE_KEYS
+                        .iter()
+                        .chain(ALL_GROUP_ATTRIBUTE_KEYS)
+                        .chain(ALL_SUDO_ROLE_ATTRIBUTE_KEYS)
+                        .any(|a| a.eq_ignore_ascii_case(attribute)))
+        })
+        .collect()
+}
+
+pub fn remove_attributes(entry: &mut LdapSearchResultEntry, attributes: &[String]) {
+    entry
+        .attributes
+        .retain(|a| !attributes.iter().any(|r| r.eq_ignore_ascii_case(&a.atype)));
+}
+
+fn get_sort_value(entry: &LdapSearchResultEntry, attribute: &str) -> Option<String> {
+    entry
+        .attributes
+        .iter()
+        .find(|a| a.atype.eq_ignore_ascii_case(attribute))
+        .and_then(|a| a.vals.first())
+        .map(|v| String::from_utf8_lossy(v).to_lowercase())
+}
+
+/// Sorts the entries in memory. Like in RFC 2891, a missing attribute sorts after all the values:
+/// last, or first in reverse order.
+pub fn sort_entries(entries: &mut [LdapSearchResultEntry], keys: &[SortKey]) {
+    entries.sort_by_cached_key(|entry| {
+        keys.iter()
+            .map(|key| SortValue {
+                value: get_sort_value(entry, &key.attribute),
+                reverse: key.reverse,
+            })
+            .collect::<Vec<_>>()
+    });
+}
+
+#[derive(PartialEq, Eq)]
+struct SortValue {
+    value: Option<String>,
+    reverse: bool,
+}
+
+impl Ord for SortValue {
+    fn cmp(&self, other: &Self) -> Ordering {
+        let ordering = match (&self.value, &other.value) {
+            (Some(a), Some(b)) => a.cmp(b),
+            (Some(_), None) => Ordering::Less,
+            (None, Some(_)) => Ordering::Greater,
+            (None, None) => Ordering::Equal,
+        };
+        if self.reverse {
+            ordering.reverse()
+        } else {
+            ordering
+        }
+    }
+}
+
+impl PartialOrd for SortValue {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ldap3_proto::LdapPartialAttribute;
+
+    fn make_entry(uid: &str, sn: Option<&str>) -> LdapSearchResultEntry {
+        let mut attributes = vec![LdapPartialAttribute {
+            atype: "uid".to_string(),
+            vals: vec![uid.as_bytes().to_vec()],
+        }];
+        attributes.extend(sn.map(|sn| LdapPartialAttribute {
+            atype: "sn".to_string(),
+            vals: vec![sn.as_bytes().to_vec()],
+        }));
+        LdapSearchResultEntry {
+            dn: format!("uid={},ou=people,dc=example,dc=com", uid),
+            attributes,
+        }
+    }
+
+    fn key(attribute: &str, reverse: bool) -> SortKey {
+        SortKey {
+            attribute: attribute.to_string(),
+            reverse,
+        }
+    }
+
+    #[test]
+    fn test_get_user_sort_keys() {
+        assert_eq!(
+            get_user_sort_keys(&[key("mail", true), key("createTimestamp", false)]),
+            Some(vec![
+                UserSortKey {
+                    column: UserColumn::Email,
+                    descending: true,
+                },
+                UserSortKey {
+                    column: UserColumn::CreationDate,
+                    descending: false,
+                },
+            ])
+        );
+        assert_eq!(
+            get_user_sort_keys(&[key("uid", false), key("sn", false)]),
+            None
+        );
+    }
+
+    #[test]
+    fn test_sort_entries() {
+        let mut entries = vec![
+            make_entry("bob", Some("Smith")),
+            make_entry("alice", None),
+            make_entry("carol", Some("smith")),
+            make_entry("dave", Some("Doe")),
+        ];
+        let uids = |entries: &[LdapSearchResultEntry]| {
+            entries
+                .iter()
+                .map(|e| get_sort_value(e, "uid").unwrap())
+                .collect::<Vec<_>>()
+        };
+        sort_entries(&mut entries, &[key("sn", false), key("uid", true)]);
+        assert_eq!(uids(&entries), vec!["dave", "carol", "bob", "alice"]);
+        sort_entries(&mut entries, &[key("SN", true)]);
+        assert_eq!(uids(&entries), vec!["alice", "carol", "bob", "dave"]);
+    }
+
+    #[test]
+    fn test_missing_sort_attributes() {
+        let keys = [key("sn", false), key("modifyTimestamp", false)];
+        assert_eq!(
+            get_missing_sort_attributes(&["uid".to_string()], &keys),
+            vec!["sn", "modifytimestamp"]
+        );
+        assert_eq!(
+            get_missing_sort_attributes(&["*".to_string(), "modifyTimestamp".to_string()], &keys),
+            Vec::<String>::new()
+        );
+        assert_eq!(
+            get_missing_sort_attributes(&[], &keys),
+            vec!["modifytimestamp"]
+        );
+        let mut entry = make_entry("bob", Some("Smith"));
+        remove_attributes(&mut entry, &["SN".to_string()]);
+        assert_eq!(entry, make_entry("bob", None));
+    }
+}
@@ -0,0 +1,238 @@
+use ldap3_proto::{
+    proto::LdapOp, LdapFilter, LdapPartialAttribute, LdapResultCode, LdapSearchResultEntry,
+};
+use tracing::{debug, info, instrument};
+
+use crate::domain::{
+    handler::BackendHandler,
+    ldap::error::LdapError,
+    sudo_role_handler::{SubstringPattern, SudoAttribute, SudoRole, SudoRoleRequestFilter},
+    types::UserId,
+};
+
+use super::{
+    error::{get_error_code, LdapResult},
+    utils::{
+        escape_dn_value, expand_attribute_wildcards, split_comparison, split_matching_rule,
+        unsupported_matching_rule, LdapInfo, MatchingRule,
+    },
+};
+
+fn get_sudo_role_attribute(role: &SudoRole, attribute: &str) -> Option<Vec<Vec<u8>>> {
+    let attribute = attribute.to_ascii_lowercase();
+    let attribute_values = match attribute.as_str() {
+        "objectclass" => vec![b"top".to_vec(), b"sudoRole".to_vec()],
+        // Always returned as part of the base response.
+        "dn" | "distinguishedname" => return None,
+        "cn" => vec![role.name.clone().into_bytes()],
+        "createtimestamp" => vec![role.creation_date.to_rfc3339().into_bytes()],
+        "modifytimestamp" => vec![role.modified_at.to_rfc3339().into_bytes()],
+        "1.1" => return None,
+        // We ignore the operational attribute wildcard
+        "+" => return None,
+        "*" => {
+            panic!(
+                "Matched {}, * should have been expanded into attribute list and * removed",
+                attribute
+            )
+        }
+        _ => match SudoAttribute::from_ldap_name(&attribute) {
+            Some(sudo_attribute) => role
+                .values(sudo_attribute)
+                .iter()
+                .map(|v| v.clone().into_bytes())
+                .collect(),
+            // e.g. sudoRunAsUser or sudoNotBefore, which are not supported.
+            None => {
+                debug!(?attribute, "Ignoring unsupported sudoRole attribute");
+                return None;
+            }
+        },
+    };
+    if attribute_values.is_empty() {
+        None
+    } else {
+        Some(attribute_values)
+    }
+}
+
+pub const ALL_SUDO_ROLE_ATTRIBUTE_KEYS: &[&str] = &[
+    "objectclass",
+    "cn",
+    "sudoUser",
+    "sudoHost",
+    "sudoCommand",
+    "sudoOption",
+];
+
+fn make_ldap_search_sudo_role_result_entry(
+    role: SudoRole,
+    ldap_info: &LdapInfo,
+    expanded_attributes: &[&str],
+) -> LdapSearchResultEntry {
+    LdapSearchResultEntry {
+        dn: format!(
+            "cn={},{}",
+            escape_dn_value(&role.name),
+            ldap_info.sudoers_base_dn_str
+        ),
+        attributes: expanded_attributes
+            .iter()
+            .filter_map(|a| {
+                let values = get_sudo_role_attribute(&role, a)?;
+                Some(LdapPartialAttribute {
+                    atype: a.to_string(),
+                    vals: values,
+                })
+            })
+            .collect::<Vec<LdapPartialAttribute>>(),
+    }
+}
+
+/// The values of the sudo attributes are matched exactly, like with the `caseExactIA5Match` of
+/// the sudo schema. The `cn` is case-insensitive.
+fn convert_sudo_filter(filter: &LdapFilter) -> LdapResult<SudoRoleRequestFilter> {
+    let match_nothing = || SudoRoleRequestFilter::Not(Box::new(SudoRoleRequestFilter::And(vec![])));
+    match filter {
+        // The sudo attributes have no ordering.
+        LdapFilter::Equality(attribute, value) if split_comparison(attribute).1.is_none() => {
+            let (field, rule) = split_matching_rule(attribute)?;
+            let field = &field.to_ascii_lowercase();
+            match field.as_str() {
+                "objectclass" => Ok(
+                    if value.eq_ignore_ascii_case("sudorole") || value.eq_ignore_ascii_case("top") {
+                        SudoRoleRequestFilter::And(vec![])
+                    } else {
+                        match_nothing()
+                    },
+                ),
+                "cn" => Ok(SudoRoleRequestFilter::Name(value.clone())),
+                _ => match SudoAttribute::from_ldap_name(field) {
+                    Some(_) if rule == MatchingRule::CaseIgnore => {
+                        Err(unsupported_matching_rule(attribute))
+                    }
+                    Some(attribute) => {
+                        Ok(SudoRoleRequestFilter::Equality(attribute, value.clone()))
+                    }
+                    None => Ok(match_nothing()),
+                },
+            }
+        }
+        LdapFilter::Substring(field, substring)
+            if SudoAttribute::from_ldap_name(field).is_some() =>
+        {
+            Ok(SudoRoleRequestFilter::Substring(
+                SudoAttribute::from_ldap_name(field).unwrap(),
+                SubstringPattern {
+                    initial: substring.initial.clone(),
+                    any: substring.any.clone(),
+                    final_: substring.final_.clone(),
+                },
+            ))
+        }
+        LdapFilter::And(filters) => Ok(SudoRoleRequestFilter::And(
+            filters
+                .iter()
+                .map(convert_sudo_filter)
+                .collect::<LdapResult<_>>()?,
+        )),
+        LdapFilter::Or(filters) => Ok(SudoRoleRequestFilter::Or(
+            filters
+                .iter()
+                .map(convert_sudo_filter)
+                .collect::<LdapResult<_>>()?,
+        )),
+        LdapFilter::Not(filter) => Ok(SudoRoleRequestFilter::Not(Box::new(convert_sudo_filter(
+            filter,
+        )?))),
+        LdapFilter::Present(field) => {
+            let field = &field.to_ascii_lowercase();
+            Ok(match SudoAttribute::from_ldap_name(field) {
+                Some(attribute) => SudoRoleRequestFilter::Present(attribute),
+                None if field == "objectclass"
+                    || field == "cn"
+                    || field == "dn"
+                    || field == "distinguishedname" =>
+                {
+                    SudoRoleRequestFilter::And(vec![])
+                }
+                None => match_nothing(),
+            })
+        }
+        _ => Err(LdapError {
+            code: LdapResultCode::UnwillingToPerform,
+            message: format!("Unsupported sudoRole filter: {:?}", filter),
+        }),
+    }
+}
+
+/// The roles that apply to the user, the way sudo looks them up: by name, by group with `%`, by
+/// uidNumber with `#`, or for everyone with `ALL`. The roles without users, like `defaults`,
+/// apply to everyone.
+async fn get_user_roles_filter<Backend: BackendHandler>(
+    backend: &Backend,
+    user_id: &UserId,
+) -> LdapResult<SudoRoleRequestFilter> {
+    let to_ldap_error = |e| LdapError {
+        code: LdapResultCode::Other,
+        message: format!(r#"Error while looking up the user "{}": {:#}"#, user_id, e),
+    };
+    let user = backend
+        .get_user_details(user_id)
+        .await
+        .map_err(to_ldap_error)?;
+    let groups = backend
+        .get_user_groups(user_id)
+        .await
+        .map_err(to_ldap_error)?;
+    let mut values = vec![user_id.to_string(), "ALL".to_owned()];
+    values.extend(groups.into_iter().map(|g| format!("%{}", g.display_name)));
+    values.extend(user.uid_number.map(|uid| format!("#{}", uid)));
+    let mut filters = values
+        .into_iter()
+        .map(|v| SudoRoleRequestFilter::Equality(SudoAttribute::User, v))
+        .collect::<Vec<_>>();
+    filters.push(SudoRoleRequestFilter::Not(Box::new(
+        SudoRoleRequestFilter::Present(SudoAttribute::User),
+    )));
+    Ok(SudoRoleRequestFilter::Or(filters))
+}
+
+#[instrument(skip_all, level = "debug")]
+pub async fn get_sudo_roles_list<Backend: BackendHandler>(
+    ldap_info: &LdapInfo,
+    ldap_filter: &LdapFilter,
+    attributes: &[String],
+    base: &str,
+    user_filter: &Option<&UserId>,
+    backend: &mut Backend,
+) -> LdapResult<Vec<LdapOp>> {
+    debug!(?ldap_filter);
+    let filter = convert_sudo_filter(ldap_filter)?;
+    let parsed_filters = match user_filter {
+        None => filter,
+        Some(u) => {
+            info!("Unprivileged search, limiting results");
+            SudoRoleRequestFilter::And(vec![filter, get_user_roles_filter(backend, u).await?])
+        }
+    };
+    debug!(?parsed_filters);
+    let roles = backend
+        .list_sudo_roles(Some(parsed_filters))
+        .await
+        .map_err(|e| LdapError {
+            code: get_error_code(&e),
+            message: format!(r#"Error while listing sudo roles "{}": {:#}"#, base, e),
+        })?;
+    let expanded_attributes = expand_attribute_wildcards(attributes, ALL_SUDO_ROLE_ATTRIBUTE_KEYS);
+    Ok(roles
+        .into_iter()
+        .map(|r| {
+            LdapOp::SearchResultEntry(make_ldap_search_sudo_role_result_entry(
+                r,
+                ldap_info,
+                &expanded_attributes,
+            ))
+        })
+        .collect())
+}
@@ -3,35 +3,44 @@ use ldap3_proto::{
 };
 use tracing::{debug, info, instrument, warn};
 
-use crate::domain::{
-    handler::{BackendHandler, UserRequestFilter},
-    ldap::{error::LdapError, utils::expand_attribute_wildcards},
-    types::{GroupDetails, User, UserColumn, UserId},
+use crate::{
+    domain::{
+        handler::{BackendHandler, Pagination, UserRequestFilter, UserSortKey},
+        ldap::{error::LdapError, utils::expand_attribute_wildcards},
+        types::{
+            AttributeName, AttributeType, Comparison, GroupDetails, OrderedValue, User, UserColumn,
+            UserId,
+        },
+    },
+    infra::{access_control::UserAttributeAccess, configuration::PosixOptions},
 };
 
 use super::{
-    error::LdapResult,
-    utils::{get_group_id_from_distinguished_name, map_user_field, LdapInfo},
+    error::{get_error_code, LdapResult},
+    utils::{
+        escape_dn_value, get_group_id_from_distinguished_name, make_user_distinguished_name,
+        map_user_field, split_comparison, split_matching_rule, LdapInfo, MatchingRule,
+    },
 };
 
 fn get_user_attribute(
     user: &User,
     attribute: &str,
-    base_dn_str: &str,
+    groups_dn_str: &str,
     groups: Option<&[GroupDetails]>,
     ignored_user_attributes: &[String],
+    posix_options: &PosixOptions,
+    object_classes: &[String],
 ) -> Option<Vec<Vec<u8>>> {
     let attribute = attribute.to_ascii_lowercase();
     let attribute_values = match attribute.as_str() {
-        "objectclass" => vec![
-            b"inetOrgPerson".to_vec(),
-            b"posixAccount".to_vec(),
-            b"mailAccount".to_vec(),
-            b"person".to_vec(),
-        ],
+        "objectclass" => object_classes
+            .iter()
+            .map(|object_class| object_class.clone().into_bytes())
+            .collect(),
         // dn is always returned as part of the base response.
         "dn" | "distinguishedname" => return None,
-        "uid" => vec![user.user_id.to_string().into_bytes()],
+        "uid" => vec![user.display_user_id().as_bytes().to_vec()],
         "entryuuid" => vec![user.uuid.to_string().into_bytes()],
         "mail" => vec![user.email.clone().into_bytes()],
         "givenname" => vec![user.first_name.clone()?.into_bytes()],
@@ -42,14 +51,40 @@ fn get_user_attribute(
             .flatten()
             .map(|id_and_name| {
                 format!(
-                    "uid={},ou=groups,{}",
-                    &id_and_name.display_name, base_dn_str
+                    "uid={},{}",
+                    escape_dn_value(&id_and_name.display_name),
+                    groups_dn_str
                 )
                 .into_bytes()
             })
             .collect(),
         "cn" | "displayname" => vec![user.display_name.clone()?.into_bytes()],
-        "createtimestamp" | "modifytimestamp" => vec![user.creation_date.to_rfc3339().into_bytes()],
+        "createtimestamp" => vec![user.creation_date.to_rfc3339().into_bytes()],
+        "modifytimestamp" => vec![user.modified_at.to_rfc3339().into_bytes()],
+        "uidnumber" => vec![user.uid_number?.to_string().into_bytes()],
+        "gidnumber" => vec![user
+            .gid_number
+            .or(posix_options.default_gid_number)
+            .or(user.uid_number)?
+            .to_string()
+            .into_bytes()],
+        "homedirectory" => vec![user
+            .home_directory
+            .clone()
+            .unwrap_or_else(|| {
+                format!(
+                    "{}/{}",
+                    posix_options.home_directory_prefix.trim_end_matches('/'),
+                    user.user_id
+                )
+            })
+            .into_bytes()],
+        "loginshell" => vec![user
+            .login_shell
+            .clone()
+            .unwrap_or_else(|| posix_options.default_login_shell.clone())
+            .into_bytes()],
+        "gecos" => vec![user.display_name.clone()?.into_bytes()],
         "1.1" => return None,
         // We ignore the operational attribute wildcard.
         "+" => return None,
@@ -77,7 +112,7 @@ fn get_user_attribute(
     }
 }
 
-const ALL_USER_ATTRIBUTE_KEYS: &[&str] = &[
+pub const ALL_USER_ATTRIBUTE_KEYS: &[&str] = &[
     "objectclass",
     "uid",
     "mail",
@@ -91,20 +126,34 @@ const ALL_USER_ATTRIBUTE_KEYS: &[&str] = &[
 
 fn make_ldap_search_user_result_entry(
     user: User,
-    base_dn_str: &str,
+    ldap_info: &LdapInfo,
     attributes: &[&str],
     groups: Option<&[GroupDetails]>,
-    ignored_user_attributes: &[String],
+    access: Option<&UserAttributeAccess>,
 ) -> LdapSearchResultEntry {
-    let dn = format!("uid={},ou=people,{}", user.user_id.as_str(), base_dn_str);
+    let dn = make_user_distinguished_name(&user.user_id, &user.email, ldap_info);
+    let groups_dn_str = ldap_info.groups_dn_str();
 
     LdapSearchResultEntry {
         dn,
         attributes: attributes
             .iter()
+            // The denied attributes are left out.
+            .filter(|a| {
+                access.map_or(true, |access| {
+                    access.can_read_ldap_attribute(&user.user_id, a)
+                })
+            })
             .filter_map(|a| {
-                let values =
-                    get_user_attribute(&user, a, base_dn_str, groups, ignored_user_attributes)?;
+                let values = get_user_attribute(
+                    &user,
+                    a,
+                    &groups_dn_str,
+                    groups,
+                    &ldap_info.ignored_user_attributes,
+                    &ldap_info.posix_options,
+                    &ldap_info.object_classes.user,
+                )?;
                 Some(LdapPartialAttribute {
                     atype: a.to_string(),
                     vals: values,
@@ -114,6 +163,42 @@ fn make_ldap_search_user_result_entry(
     }
 }
 
+/// An ordering filter, `(field>=value)` or `(field<=value)`: only on the integer and date
+/// attributes. The unknown attributes are assumed to be custom attributes.
+fn convert_user_comparison(
+    field: &str,
+    comparison: Comparison,
+    value: &str,
+) -> LdapResult<UserRequestFilter> {
+    let field = &field.to_ascii_lowercase();
+    let (column, attribute_type) = match map_user_field(field) {
+        Some(column @ (UserColumn::CreationDate | UserColumn::ModifiedAt)) => {
+            (column, AttributeType::DateTime)
+        }
+        Some(column @ UserColumn::UidNumber) => (column, AttributeType::Integer),
+        None if !["objectclass", "memberof", "dn", "distinguishedname"]
+            .contains(&field.as_str()) =>
+        {
+            return Ok(UserRequestFilter::AttributeOrdering(
+                AttributeName::new(field),
+                comparison,
+                value.to_owned(),
+            ))
+        }
+        _ => {
+            return Err(LdapError {
+                code: LdapResultCode::InappropriateMatching,
+                message: format!(r#"The attribute "{}" has no ordering"#, field),
+            })
+        }
+    };
+    let bound = OrderedValue::parse(attribute_type, value).map_err(|e| LdapError {
+        code: LdapResultCode::InvalidAttributeSyntax,
+        message: format!(r#"Invalid filter on "{}": {}"#, field, e),
+    })?;
+    Ok(UserRequestFilter::Ordering(column, comparison, bound))
+}
+
 fn convert_user_filter(ldap_info: &LdapInfo, filter: &LdapFilter) -> LdapResult<UserRequestFilter> {
     let rec = |f| convert_user_filter(ldap_info, f);
     match filter {
@@ -125,55 +210,68 @@ fn convert_user_filter(ldap_info: &LdapInfo, filter: &LdapFilter) -> LdapResult<
         )),
         LdapFilter::Not(filter) => Ok(UserRequestFilter::Not(Box::new(rec(filter)?))),
         LdapFilter::Equality(field, value) => {
+            if let (field, Some(comparison)) = split_comparison(field) {
+                return convert_user_comparison(field, comparison, value);
+            }
+            // The user IDs, group names and object classes are case-insensitive whatever the
+            // matching rule.
+            let (field, rule) = split_matching_rule(field)?;
             let field = &field.to_ascii_lowercase();
             match field.as_str() {
                 "memberof" => {
                     let group_name = get_group_id_from_distinguished_name(
                         &value.to_ascii_lowercase(),
-                        &ldap_info.base_dn,
-                        &ldap_info.base_dn_str,
+                        ldap_info,
                     )?;
                     Ok(UserRequestFilter::MemberOf(group_name))
                 }
-                "objectclass" => match value.to_ascii_lowercase().as_str() {
-                    "person" | "inetorgperson" | "posixaccount" | "mailaccount" => {
-                        Ok(UserRequestFilter::And(vec![]))
-                    }
-                    _ => Ok(UserRequestFilter::Not(Box::new(UserRequestFilter::And(
-                        vec![],
-                    )))),
-                },
+                "objectclass" => Ok(
+                    if ldap_info
+                        .object_classes
+                        .user
+                        .iter()
+                        .any(|object_class| object_class.eq_ignore_ascii_case(value))
+                    {
+                        UserRequestFilter::And(vec![])
+                    } else {
+                        UserRequestFilter::Not(Box::new(UserRequestFilter::And(vec![])))
+                    },
+                ),
                 _ => match map_user_field(field) {
                     Some(UserColumn::UserId) => Ok(UserRequestFilter::UserId(UserId::new(value))),
-                    Some(field) => Ok(UserRequestFilter::Equality(field, value.clone())),
-                    None => {
-                        if !ldap_info.ignored_user_attributes.contains(field) {
-                            warn!(
-                                r#"Ignoring unknown user attribute "{}" in filter.\n\
-                                      To disable this warning, add it to "ignored_user_attributes" in the config"#,
-                                field
-                            );
-                        }
-                        Ok(UserRequestFilter::Not(Box::new(UserRequestFilter::And(
-                            vec![],
-                        ))))
+                    Some(field) if rule == MatchingRule::CaseIgnore => {
+                        Ok(UserRequestFilter::EqualityIgnoreCase(field, value.clone()))
                     }
+                    Some(field) => Ok(UserRequestFilter::Equality(field, value.clone())),
+                    None if ldap_info.ignored_user_attributes.contains(field) => Ok(
+                        UserRequestFilter::Not(Box::new(UserRequestFilter::And(vec![]))),
+                    ),
+                    // The unknown attributes are assumed to be custom attributes.
+                    None => Ok(UserRequestFilter::AttributeEquality(
+                        AttributeName::new(field),
+                        value.clone(),
+                    )),
                 },
             }
         }
         LdapFilter::Present(field) => {
             let field = &field.to_ascii_lowercase();
-            // Check that it's a field we support.
-            if field == "objectclass"
-                || field == "dn"
-                || field == "distinguishedname"
-                || map_user_field(field).is_some()
-            {
-                Ok(UserRequestFilter::And(vec![]))
-            } else {
-                Ok(UserRequestFilter::Not(Box::new(UserRequestFilter::And(
+            // Check that it's a field we support, and that the user has a value.
+            match map_user_field(field) {
+                Some(
+                    column @ (UserColumn::DisplayName
+                    | UserColumn::FirstName
+                    | UserColumn::LastName
+                    | UserColumn::Avatar
+                    | UserColumn::UidNumber),
+                ) => Ok(UserRequestFilter::Present(column)),
+                Some(_) => Ok(UserRequestFilter::And(vec![])),
+                None if field == "objectclass" || field == "dn" || field == "distinguishedname" => {
+                    Ok(UserRequestFilter::And(vec![]))
+                }
+                None => Ok(UserRequestFilter::Not(Box::new(UserRequestFilter::And(
                     vec![],
-                ))))
+                )))),
             }
         }
         _ => Err(LdapError {
@@ -183,6 +281,21 @@ fn convert_user_filter(ldap_info: &LdapInfo, filter: &LdapFilter) -> LdapResult<
     }
 }
 
+/// Which users to fetch from the backend.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum UserListing {
+    All,
+    /// Sorted by ID, one page at a time.
+    Page(Pagination),
+    /// Sorted by the keys: `limit` users after the first `offset` ones.
+    Sorted {
+        keys: Vec<UserSortKey>,
+        offset: usize,
+        limit: Option<usize>,
+    },
+}
+
+/// Also returns the cursor of the next page, for `UserListing::Page`.
 #[instrument(skip_all, level = "debug")]
 pub async fn get_user_list<Backend: BackendHandler>(
     ldap_info: &LdapInfo,
@@ -190,12 +303,22 @@ pub async fn get_user_list<Backend: BackendHandler>(
     attributes: &[String],
     base: &str,
     user_filter: &Option<&UserId>,
+    access: Option<&UserAttributeAccess<'_>>,
+    listing: UserListing,
     backend: &mut Backend,
-) -> LdapResult<Vec<LdapOp>> {
+) -> LdapResult<(Vec<LdapOp>, Option<String>)> {
     debug!(?ldap_filter);
     let filters = convert_user_filter(ldap_info, ldap_filter)?;
     let parsed_filters = match user_filter {
-        None => filters,
+        None => {
+            if !access.map_or(true, |access| access.can_filter(&filters)) {
+                return Err(LdapError {
+                    code: LdapResultCode::InsufficentAccessRights,
+                    message: "The filter looks at attributes that cannot be read".to_string(),
+                });
+            }
+            filters
+        }
         Some(u) => {
             info!("Unprivileged search, limiting results");
             UserRequestFilter::And(vec![filters, UserRequestFilter::UserId((*u).clone())])
@@ -206,24 +329,40 @@ pub async fn get_user_list<Backend: BackendHandler>(
     let need_groups = expanded_attributes
         .iter()
         .any(|s| s.to_ascii_lowercase() == "memberof");
-    let users = backend
-        .list_users(Some(parsed_filters), need_groups)
-        .await
-        .map_err(|e| LdapError {
-            code: LdapResultCode::Other,
-            message: format!(r#"Error while searching user "{}": {:#}"#, base, e),
-        })?;
+    let (users, next_cursor) = match listing {
+        UserListing::All => backend
+            .list_users(Some(parsed_filters), need_groups)
+            .await
+            .map(|users| (users, None)),
+        UserListing::Page(page) => backend
+            .list_users_page(Some(parsed_filters), need_groups, page)
+            .await
+            .map(|page| (page.items, page.next_cursor)),
+        UserListing::Sorted {
+            keys,
+            offset,
+            limit,
+        } => backend
+            .list_users_sorted(Some(parsed_filters), need_groups, keys, offset, limit)
+            .await
+            .map(|users| (users, None)),
+    }
+    .map_err(|e| LdapError {
+        code: get_error_code(&e),
+        message: format!(r#"Error while searching user "{}": {:#}"#, base, e),
+    })?;
 
-    Ok(users
+    let entries = users
         .into_iter()
         .map(|u| {
             LdapOp::SearchResultEntry(make_ldap_search_user_result_entry(
                 u.user,
-                &ldap_info.base_dn_str,
+                ldap_info,
                 &expanded_attributes,
                 u.groups.as_deref(),
-                &ldap_info.ignored_user_attributes,
+                access,
             ))
         })
-        .collect::<Vec<_>>())
+        .collect::<Vec<_>>();
+    Ok((entries, next_cursor))
 }
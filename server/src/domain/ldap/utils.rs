@@ -2,9 +2,13 @@ use itertools::Itertools;
 use ldap3_proto::LdapResultCode;
 use tracing::{debug, instrument, warn};
 
-use crate::domain::{
-    ldap::error::{LdapError, LdapResult},
-    types::{GroupColumn, UserColumn, UserId},
+use crate::{
+    domain::{
+        handler::{BackendHandler, UserRequestFilter},
+        ldap::error::{LdapError, LdapResult},
+        types::{Comparison, GroupColumn, UserColumn, UserId},
+    },
+    infra::configuration::{LdapObjectClasses, PosixOptions, UserRdnAttribute},
 };
 
 fn make_dn_pair<I>(mut iter: I) -> LdapResult<(String, String)>
@@ -31,38 +35,148 @@ where
     })
 }
 
+/// Escapes a value to put in a DN, e.g. `Doe\, John`, per RFC 4514. The control characters
+/// are hex-escaped; the other non-ASCII characters are kept as is, the DN being UTF-8.
+pub fn escape_dn_value(value: &str) -> String {
+    let last = value.chars().count().saturating_sub(1);
+    let mut escaped = String::with_capacity(value.len());
+    for (i, c) in value.chars().enumerate() {
+        match c {
+            ',' | '+' | '=' | '"' | '\\' | '<' | '>' | ';' => {
+                escaped.push('\\');
+                escaped.push(c);
+            }
+            ' ' | '#' if i == 0 => {
+                escaped.push('\\');
+                escaped.push(c);
+            }
+            ' ' if i == last => escaped.push_str("\\ "),
+            c if c.is_control() => {
+                let mut buffer = [0; 4];
+                for byte in c.encode_utf8(&mut buffer).bytes() {
+                    escaped.push_str(&format!("\\{:02x}", byte));
+                }
+            }
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+/// Reverses `escape_dn_value`, accepting any RFC 4514 escape. The spaces around the value are
+/// dropped, unless escaped.
+pub fn unescape_dn_value(value: &str) -> Result<String, String> {
+    let invalid = || format!("Invalid escape sequence in {:?}", value);
+    let mut bytes = Vec::with_capacity(value.len());
+    // Length of the value up to the last escaped or non-space character.
+    let mut significant_length = 0;
+    let mut chars = value.trim_start().chars();
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            let mut buffer = [0; 4];
+            bytes.extend_from_slice(c.encode_utf8(&mut buffer).as_bytes());
+            if c != ' ' {
+                significant_length = bytes.len();
+            }
+            continue;
+        }
+        match chars.next().ok_or_else(invalid)? {
+            high if high.is_ascii_hexdigit() => {
+                let low = chars
+                    .next()
+                    .and_then(|c| c.to_digit(16))
+                    .ok_or_else(invalid)?;
+                bytes.push((high.to_digit(16).unwrap() * 16 + low) as u8);
+            }
+            c @ (',' | '+' | '=' | '"' | '\\' | '<' | '>' | ';' | ' ' | '#') => bytes.push(c as u8),
+            _ => return Err(invalid()),
+        }
+        significant_length = bytes.len();
+    }
+    bytes.truncate(significant_length);
+    String::from_utf8(bytes).map_err(|_| format!("Invalid UTF-8 in {:?}", value))
+}
+
+/// Splits on the separators that are not escaped with a backslash.
+fn split_unescaped(value: &str, separator: char) -> Vec<&str> {
+    let mut parts = Vec::new();
+    let mut start = 0;
+    let mut escaped = false;
+    for (i, c) in value.char_indices() {
+        if escaped {
+            escaped = false;
+        } else if c == '\\' {
+            escaped = true;
+        } else if c == separator {
+            parts.push(&value[start..i]);
+            start = i + c.len_utf8();
+        }
+    }
+    parts.push(&value[start..]);
+    parts
+}
+
+/// The unescaped value of the first RDN of the DN, e.g. "Doe, John" for
+/// `cn=Doe\, John,ou=groups,dc=example,dc=com`.
+pub fn first_rdn_value(dn: &str) -> Option<String> {
+    let rdn = split_unescaped(dn, ',').into_iter().next()?;
+    match split_unescaped(rdn, '=').as_slice() {
+        [_, value] => unescape_dn_value(value).ok(),
+        _ => None,
+    }
+}
+
 pub fn parse_distinguished_name(dn: &str) -> LdapResult<Vec<(String, String)>> {
     assert!(dn == dn.to_ascii_lowercase());
-    dn.split(',')
-        .map(|s| make_dn_pair(s.split('=').map(str::trim).map(String::from)))
+    split_unescaped(dn, ',')
+        .into_iter()
+        .map(|rdn| {
+            let parts = split_unescaped(rdn, '=')
+                .into_iter()
+                // The hex escapes can hide uppercase characters.
+                .map(|part| unescape_dn_value(part).map(|part| part.to_ascii_lowercase()))
+                .collect::<Result<Vec<_>, _>>()
+                .map_err(|message| LdapError {
+                    code: LdapResultCode::InvalidDNSyntax,
+                    message,
+                })?;
+            make_dn_pair(parts.into_iter())
+        })
         .collect()
 }
 
-fn get_id_from_distinguished_name(
+fn get_rdn_from_distinguished_name(
     dn: &str,
-    base_tree: &[(String, String)],
-    base_dn_str: &str,
+    ldap_info: &LdapInfo,
     is_group: bool,
-) -> LdapResult<String> {
+    rdn_attributes: &[&str],
+) -> LdapResult<(String, String)> {
     let parts = parse_distinguished_name(dn)?;
+    let base_tree = &ldap_info.base_dn;
     {
-        let ou = if is_group { "groups" } else { "people" };
+        let (ou, container) = if is_group {
+            (&ldap_info.groups_ou, ldap_info.groups_dn_str())
+        } else {
+            (&ldap_info.users_ou, ldap_info.users_dn_str())
+        };
         if !is_subtree(&parts, base_tree) {
             Err("Not a subtree of the base tree".to_string())
         } else if parts.len() == base_tree.len() + 2 {
-            if parts[1].0 != "ou" || parts[1].1 != ou || (parts[0].0 != "cn" && parts[0].0 != "uid")
+            if parts[1].0 != "ou"
+                || &parts[1].1 != ou
+                || !rdn_attributes.contains(&parts[0].0.as_str())
             {
                 Err(format!(
-                    r#"Unexpected DN format. Got "{}", expected: "uid=id,ou={},{}""#,
-                    dn, ou, base_dn_str
+                    r#"Unexpected DN format. Got "{}", expected: "uid=id,{}""#,
+                    dn, container
                 ))
             } else {
-                Ok(parts[0].1.to_string())
+                Ok(parts[0].clone())
             }
         } else {
             Err(format!(
-                r#"Unexpected DN format. Got "{}", expected: "uid=id,ou={},{}""#,
-                dn, ou, base_dn_str
+                r#"Unexpected DN format. Got "{}", expected: "uid=id,{}""#,
+                dn, container
             ))
         }
     }
@@ -72,20 +186,91 @@ fn get_id_from_distinguished_name(
     })
 }
 
-pub fn get_user_id_from_distinguished_name(
+fn get_id_from_distinguished_name(
     dn: &str,
-    base_tree: &[(String, String)],
-    base_dn_str: &str,
-) -> LdapResult<UserId> {
-    get_id_from_distinguished_name(dn, base_tree, base_dn_str, false).map(UserId::from)
+    ldap_info: &LdapInfo,
+    is_group: bool,
+) -> LdapResult<String> {
+    get_rdn_from_distinguished_name(dn, ldap_info, is_group, &["cn", "uid"]).map(|(_, id)| id)
 }
 
-pub fn get_group_id_from_distinguished_name(
+pub fn get_user_id_from_distinguished_name(dn: &str, ldap_info: &LdapInfo) -> LdapResult<UserId> {
+    get_id_from_distinguished_name(dn, ldap_info, false).map(UserId::from)
+}
+
+/// How a user DN names the user.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum UserRdn {
+    UserId(UserId),
+    /// Only with the `mail` RDN attribute.
+    Email(String),
+}
+
+/// Like `get_user_id_from_distinguished_name`, but also accepts the `mail` RDN if configured.
+/// The `uid` RDN is always accepted, e.g. for the users without an email.
+pub fn get_user_rdn_from_distinguished_name(dn: &str, ldap_info: &LdapInfo) -> LdapResult<UserRdn> {
+    let rdn_attributes: &[&str] = match ldap_info.user_rdn_attribute {
+        UserRdnAttribute::Uid => &["cn", "uid"],
+        UserRdnAttribute::Mail => &["cn", "uid", "mail"],
+    };
+    let (attribute, value) = get_rdn_from_distinguished_name(dn, ldap_info, false, rdn_attributes)?;
+    Ok(match attribute.as_str() {
+        "mail" => UserRdn::Email(value),
+        _ => UserRdn::UserId(UserId::from(value)),
+    })
+}
+
+/// Finds the user named by the DN.
+pub async fn resolve_user_distinguished_name<Backend: BackendHandler>(
     dn: &str,
-    base_tree: &[(String, String)],
-    base_dn_str: &str,
-) -> LdapResult<String> {
-    get_id_from_distinguished_name(dn, base_tree, base_dn_str, true)
+    ldap_info: &LdapInfo,
+    backend: &Backend,
+) -> LdapResult<UserId> {
+    match get_user_rdn_from_distinguished_name(dn, ldap_info)? {
+        UserRdn::UserId(user_id) => Ok(user_id),
+        UserRdn::Email(email) => {
+            let mut users = backend
+                .list_users(
+                    Some(UserRequestFilter::Equality(UserColumn::Email, email)),
+                    false,
+                )
+                .await
+                .map_err(|e| LdapError {
+                    code: LdapResultCode::OperationsError,
+                    message: format!("Error while looking up {}: {:#}", dn, e),
+                })?;
+            match (users.pop(), users.is_empty()) {
+                (Some(user), true) => Ok(user.user.user_id),
+                _ => Err(LdapError {
+                    code: LdapResultCode::NoSuchObject,
+                    message: format!("No such user: {}", dn),
+                }),
+            }
+        }
+    }
+}
+
+/// The DN of the user, named after the configured RDN attribute.
+pub fn make_user_distinguished_name(user_id: &UserId, email: &str, ldap_info: &LdapInfo) -> String {
+    match ldap_info.user_rdn_attribute {
+        // Several users can have no email.
+        UserRdnAttribute::Mail if !email.is_empty() => {
+            format!(
+                "mail={},{}",
+                escape_dn_value(email),
+                ldap_info.users_dn_str()
+            )
+        }
+        _ => format!(
+            "uid={},{}",
+            escape_dn_value(user_id.as_str()),
+            ldap_info.users_dn_str()
+        ),
+    }
+}
+
+pub fn get_group_id_from_distinguished_name(dn: &str, ldap_info: &LdapInfo) -> LdapResult<String> {
+    get_id_from_distinguished_name(dn, ldap_info, true)
 }
 
 #[instrument(skip_all, level = "debug")]
@@ -135,6 +320,62 @@ pub fn is_subtree(subtree: &[(String, String)], base_tree: &[(String, String)])
     true
 }
 
+/// The matching rule of an extensible match filter. `ldap3_proto` can't decode these filters: the
+/// `LdapControlsCodec` rewrites `(cn:caseExactMatch:=Bob)` into an equality filter on the
+/// attribute `cn:caseExactMatch`, and the approximate matches into plain equality filters.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MatchingRule {
+    /// The equality rule of the attribute.
+    Equality,
+    CaseIgnore,
+    CaseExact,
+}
+
+/// Splits `cn:caseExactMatch` into `cn` and the matching rule. Only the case rules are supported,
+/// and not with the `dn` flag of `(cn:dn:caseExactMatch:=Bob)`.
+pub fn split_matching_rule(attribute: &str) -> LdapResult<(&str, MatchingRule)> {
+    let (name, rule) = match attribute.split_once(':') {
+        None => return Ok((attribute, MatchingRule::Equality)),
+        Some(split) => split,
+    };
+    let rule = match rule.to_ascii_lowercase().as_str() {
+        "caseignorematch" | "2.5.13.2" | "caseignoreia5match" | "1.3.6.1.4.1.1466.109.114.2" => {
+            MatchingRule::CaseIgnore
+        }
+        "caseexactmatch" | "2.5.13.5" | "caseexactia5match" | "1.3.6.1.4.1.1466.109.114.1" => {
+            MatchingRule::CaseExact
+        }
+        _ => return Err(unsupported_matching_rule(attribute)),
+    };
+    if name.is_empty() {
+        return Err(unsupported_matching_rule(attribute));
+    }
+    Ok((name, rule))
+}
+
+/// Splits `uidNumber>=` into `uidNumber` and the comparison. `ldap3_proto` can't decode the
+/// ordering filters either: the `LdapControlsCodec` rewrites `(uidNumber>=1000)` into an equality
+/// filter on the attribute `uidNumber>=`.
+pub fn split_comparison(attribute: &str) -> (&str, Option<Comparison>) {
+    if let Some(name) = attribute.strip_suffix(">=") {
+        (name, Some(Comparison::GreaterOrEqual))
+    } else if let Some(name) = attribute.strip_suffix("<=") {
+        (name, Some(Comparison::LessOrEqual))
+    } else {
+        (attribute, None)
+    }
+}
+
+pub fn unsupported_matching_rule(attribute: &str) -> LdapError {
+    LdapError {
+        code: LdapResultCode::UnwillingToPerform,
+        message: format!(
+            r#"Unsupported matching rule in the filter on "{}""#,
+            attribute
+        ),
+    }
+}
+
 pub fn map_user_field(field: &str) -> Option<UserColumn> {
     assert!(field == field.to_ascii_lowercase());
     Some(match field {
@@ -144,10 +385,10 @@ pub fn map_user_field(field: &str) -> Option<UserColumn> {
         "givenname" | "first_name" => UserColumn::FirstName,
         "sn" | "last_name" => UserColumn::LastName,
         "avatar" => UserColumn::Avatar,
-        "creationdate" | "createtimestamp" | "modifytimestamp" | "creation_date" => {
-            UserColumn::CreationDate
-        }
+        "creationdate" | "createtimestamp" | "creation_date" => UserColumn::CreationDate,
+        "modifytimestamp" | "modified_at" => UserColumn::ModifiedAt,
         "entryuuid" | "uuid" => UserColumn::Uuid,
+        "uidnumber" => UserColumn::UidNumber,
         _ => return None,
     })
 }
@@ -156,10 +397,10 @@ pub fn map_group_field(field: &str) -> Option<GroupColumn> {
     assert!(field == field.to_ascii_lowercase());
     Some(match field {
         "cn" | "displayname" | "uid" | "display_name" => GroupColumn::DisplayName,
-        "creationdate" | "createtimestamp" | "modifytimestamp" | "creation_date" => {
-            GroupColumn::CreationDate
-        }
+        "creationdate" | "createtimestamp" | "creation_date" => GroupColumn::CreationDate,
+        "modifytimestamp" | "modified_at" => GroupColumn::ModifiedAt,
         "entryuuid" | "uuid" => GroupColumn::Uuid,
+        "gidnumber" => GroupColumn::GidNumber,
         _ => return None,
     })
 }
@@ -167,6 +408,123 @@ pub fn map_group_field(field: &str) -> Option<GroupColumn> {
 pub struct LdapInfo {
     pub base_dn: Vec<(String, String)>,
     pub base_dn_str: String,
+    pub user_rdn_attribute: UserRdnAttribute,
+    pub posix_options: PosixOptions,
+    pub sudoers_base_dn: Vec<(String, String)>,
+    pub sudoers_base_dn_str: String,
+    /// The `ou` values of the users and of the groups, lowercase.
+    pub users_ou: String,
+    pub groups_ou: String,
     pub ignored_user_attributes: Vec<String>,
     pub ignored_group_attributes: Vec<String>,
+    pub object_classes: LdapObjectClasses,
+}
+
+impl LdapInfo {
+    /// `ou=people,<base DN>` by default.
+    pub fn users_dn_str(&self) -> String {
+        format!(
+            "ou={},{}",
+            escape_dn_value(&self.users_ou),
+            self.base_dn_str
+        )
+    }
+
+    /// `ou=groups,<base DN>` by default.
+    pub fn groups_dn_str(&self) -> String {
+        format!(
+            "ou={},{}",
+            escape_dn_value(&self.groups_ou),
+            self.base_dn_str
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_escape_dn_value() {
+        let cases = [
+            ("bob", "bob"),
+            ("Doe, John", r"Doe\, John"),
+            ("a+b", r"a\+b"),
+            ("a=b", r"a\=b"),
+            (r#"say "hi""#, r#"say \"hi\""#),
+            (r"back\slash", r"back\\slash"),
+            ("<tag>", r"\<tag\>"),
+            ("a;b", r"a\;b"),
+            ("#hash", r"\#hash"),
+            ("not#first", "not#first"),
+            (" leading", r"\ leading"),
+            ("trailing ", r"trailing\ "),
+            ("inner space", "inner space"),
+            (" ", r"\ "),
+            ("nul\0", r"nul\00"),
+            ("tab\there", r"tab\09here"),
+            ("line\nfeed", r"line\0afeed"),
+            ("del\u{7f}", r"del\7f"),
+            ("c1\u{85}", r"c1\c2\85"),
+            ("Bôb Böbberson", "Bôb Böbberson"),
+            ("日本", "日本"),
+            ("", ""),
+        ];
+        for (value, escaped) in cases {
+            assert_eq!(escape_dn_value(value), escaped, "escaping {:?}", value);
+            assert_eq!(
+                unescape_dn_value(escaped).as_deref(),
+                Ok(value),
+                "unescaping {:?}",
+                escaped
+            );
+        }
+    }
+
+    #[test]
+    fn test_unescape_dn_value() {
+        assert_eq!(unescape_dn_value(r"Doe\2c John").unwrap(), "Doe, John");
+        assert_eq!(unescape_dn_value(r"Doe\2C John").unwrap(), "Doe, John");
+        assert_eq!(unescape_dn_value(r"B\c3\b4b").unwrap(), "Bôb");
+        assert_eq!(unescape_dn_value("  bob  ").unwrap(), "bob");
+        assert_eq!(unescape_dn_value(r" bob\  ").unwrap(), "bob ");
+        assert!(unescape_dn_value(r"bob\").is_err());
+        assert!(unescape_dn_value(r"bob\q").is_err());
+        assert!(unescape_dn_value(r"bob\2").is_err());
+        assert!(unescape_dn_value(r"\ff").is_err());
+    }
+
+    #[test]
+    fn test_parse_escaped_distinguished_name() {
+        assert_eq!(
+            parse_distinguished_name(r"cn=doe\, john,ou=groups,dc=example,dc=com").unwrap(),
+            vec![
+                ("cn".to_owned(), "doe, john".to_owned()),
+                ("ou".to_owned(), "groups".to_owned()),
+                ("dc".to_owned(), "example".to_owned()),
+                ("dc".to_owned(), "com".to_owned()),
+            ]
+        );
+        assert_eq!(
+            parse_distinguished_name(r"cn=a\3db\41").unwrap(),
+            vec![("cn".to_owned(), "a=ba".to_owned())]
+        );
+        assert_eq!(
+            parse_distinguished_name(r"cn=a\,b\").unwrap_err().code,
+            LdapResultCode::InvalidDNSyntax
+        );
+    }
+
+    #[test]
+    fn test_first_rdn_value() {
+        assert_eq!(
+            first_rdn_value(r"cn=Doe\, John,ou=groups,dc=example,dc=com").as_deref(),
+            Some("Doe, John")
+        );
+        assert_eq!(
+            first_rdn_value("uid=bob,ou=people,dc=example,dc=com").as_deref(),
+            Some("bob")
+        );
+        assert_eq!(first_rdn_value("bob"), None);
+    }
 }
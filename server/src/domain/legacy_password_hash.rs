@@ -0,0 +1,186 @@
+//! Password hashes imported from other systems. LLDAP itself only stores OPAQUE password files,
+//! whose slow hash is Argon2id: the imported hashes are checked on the simple binds, and replaced
+//! with a password file once the password is known, or re-hashed with the default KDF if they are
+//! kept.
+
+use crate::infra::configuration::PasswordKdf;
+use anyhow::Result;
+use rand::RngCore;
+use scrypt::password_hash::{PasswordHash, PasswordHasher, PasswordVerifier, SaltString};
+
+/// The schemes recognized from the prefix of the hash, in the modular crypt format or the PHC
+/// string format. The OPAQUE password files are binary, they never start with a `$`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LegacyPasswordScheme {
+    Bcrypt,
+    Argon2,
+    Scrypt,
+}
+
+impl From<PasswordKdf> for LegacyPasswordScheme {
+    fn from(kdf: PasswordKdf) -> Self {
+        match kdf {
+            PasswordKdf::Argon2id => LegacyPasswordScheme::Argon2,
+            PasswordKdf::Bcrypt => LegacyPasswordScheme::Bcrypt,
+            PasswordKdf::Scrypt => LegacyPasswordScheme::Scrypt,
+        }
+    }
+}
+
+fn random_salt() -> [u8; 16] {
+    let mut salt = [0; 16];
+    rand::rngs::OsRng.fill_bytes(&mut salt);
+    salt
+}
+
+impl LegacyPasswordScheme {
+    pub fn detect(password_hash: &[u8]) -> Option<Self> {
+        const PREFIXES: &[(&[u8], LegacyPasswordScheme)] = &[
+            (b"$2a$", LegacyPasswordScheme::Bcrypt),
+            (b"$2b$", LegacyPasswordScheme::Bcrypt),
+            (b"$2y$", LegacyPasswordScheme::Bcrypt),
+            (b"$argon2id$", LegacyPasswordScheme::Argon2),
+            (b"$argon2i$", LegacyPasswordScheme::Argon2),
+            (b"$argon2d$", LegacyPasswordScheme::Argon2),
+            (b"$scrypt$", LegacyPasswordScheme::Scrypt),
+        ];
+        PREFIXES
+            .iter()
+            .find(|(prefix, _)| password_hash.starts_with(prefix))
+            .map(|(_, scheme)| *scheme)
+    }
+
+    /// Whether the password matches the hash. A malformed hash matches nothing.
+    pub fn verify(&self, password_hash: &[u8], clear_password: &str) -> bool {
+        let password_hash = match std::str::from_utf8(password_hash) {
+            Ok(password_hash) => password_hash,
+            Err(_) => return false,
+        };
+        match self {
+            LegacyPasswordScheme::Bcrypt => {
+                bcrypt::verify(clear_password, password_hash).unwrap_or(false)
+            }
+            LegacyPasswordScheme::Argon2 => {
+                argon2::verify_encoded(password_hash, clear_password.as_bytes()).unwrap_or(false)
+            }
+            LegacyPasswordScheme::Scrypt => PasswordHash::new(password_hash)
+                .and_then(|hash| scrypt::Scrypt.verify_password(clear_password.as_bytes(), &hash))
+                .is_ok(),
+        }
+    }
+
+    /// A new hash of the password, with a random salt and the recommended parameters.
+    pub fn hash(&self, clear_password: &str) -> Result<Vec<u8>> {
+        let hash = match self {
+            LegacyPasswordScheme::Bcrypt => bcrypt::hash(clear_password, bcrypt::DEFAULT_COST)?,
+            LegacyPasswordScheme::Argon2 => argon2::hash_encoded(
+                clear_password.as_bytes(),
+                &random_salt(),
+                &argon2::Config {
+                    variant: argon2::Variant::Argon2id,
+                    ..Default::default()
+                },
+            )?,
+            LegacyPasswordScheme::Scrypt => scrypt::Scrypt
+                .hash_password(
+                    clear_password.as_bytes(),
+                    &SaltString::b64_encode(&random_salt())?,
+                )?
+                .to_string(),
+        };
+        Ok(hash.into_bytes())
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+
+    pub fn bcrypt_hash(password: &str) -> Vec<u8> {
+        // The minimum cost, to keep the tests fast.
+        bcrypt::hash(password, 4).unwrap().into_bytes()
+    }
+
+    pub fn argon2_hash(password: &str) -> Vec<u8> {
+        argon2::hash_encoded(
+            password.as_bytes(),
+            b"some_salt_value",
+            &argon2::Config {
+                variant: argon2::Variant::Argon2id,
+                ..Default::default()
+            },
+        )
+        .unwrap()
+        .into_bytes()
+    }
+
+    pub fn scrypt_hash(password: &str) -> Vec<u8> {
+        // Low parameters, to keep the tests fast.
+        scrypt::Scrypt
+            .hash_password_customized(
+                password.as_bytes(),
+                None,
+                None,
+                scrypt::Params::new(4, 8, 1).unwrap(),
+                &SaltString::b64_encode(b"some_salt_value").unwrap(),
+            )
+            .unwrap()
+            .to_string()
+            .into_bytes()
+    }
+
+    #[test]
+    fn test_detect() {
+        assert_eq!(
+            LegacyPasswordScheme::detect(&bcrypt_hash("bob00")),
+            Some(LegacyPasswordScheme::Bcrypt)
+        );
+        assert_eq!(
+            LegacyPasswordScheme::detect(b"$2y$10$abcdefghijklmnopqrstuv"),
+            Some(LegacyPasswordScheme::Bcrypt)
+        );
+        assert_eq!(
+            LegacyPasswordScheme::detect(&argon2_hash("bob00")),
+            Some(LegacyPasswordScheme::Argon2)
+        );
+        assert_eq!(
+            LegacyPasswordScheme::detect(&scrypt_hash("bob00")),
+            Some(LegacyPasswordScheme::Scrypt)
+        );
+        assert_eq!(LegacyPasswordScheme::detect(b"$1$md5crypt"), None);
+        assert_eq!(LegacyPasswordScheme::detect(&[0, 1, 2, 3]), None);
+    }
+
+    #[test]
+    fn test_verify() {
+        for hash in [
+            bcrypt_hash("bob00"),
+            argon2_hash("bob00"),
+            scrypt_hash("bob00"),
+        ] {
+            let scheme = LegacyPasswordScheme::detect(&hash).unwrap();
+            assert!(scheme.verify(&hash, "bob00"));
+            assert!(!scheme.verify(&hash, "wrong_password"));
+        }
+        // A hash is only checked with its own scheme.
+        assert!(!LegacyPasswordScheme::Argon2.verify(&bcrypt_hash("bob00"), "bob00"));
+        assert!(!LegacyPasswordScheme::Bcrypt.verify(&argon2_hash("bob00"), "bob00"));
+        assert!(!LegacyPasswordScheme::Scrypt.verify(&argon2_hash("bob00"), "bob00"));
+        assert!(!LegacyPasswordScheme::Bcrypt.verify(b"$2b$garbage", "bob00"));
+        assert!(!LegacyPasswordScheme::Scrypt.verify(b"$scrypt$garbage", "bob00"));
+    }
+
+    #[test]
+    fn test_hash() {
+        for scheme in [
+            LegacyPasswordScheme::Bcrypt,
+            LegacyPasswordScheme::Argon2,
+            LegacyPasswordScheme::Scrypt,
+        ] {
+            let hash = scheme.hash("bob00").unwrap();
+            assert_eq!(LegacyPasswordScheme::detect(&hash), Some(scheme));
+            assert!(scheme.verify(&hash, "bob00"));
+            assert!(!scheme.verify(&hash, "wrong_password"));
+        }
+    }
+}
@@ -0,0 +1,165 @@
+use crate::domain::{
+    error::{DomainError, Result},
+    types::DateTime,
+};
+use async_trait::async_trait;
+use serde::{Deserialize, Serialize};
+use std::sync::{Arc, Mutex};
+
+/// The steps of a rebuild of the derived data, in order. Each one runs in its own transaction.
+///
+/// The transitive memberships of the nested groups aren't stored: they are computed from the
+/// nestings on every query, so removing the nestings of missing groups is all they need. There is
+/// no other denormalized count or index to rebuild.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum MaintenanceStep {
+    /// The memberships of missing users or groups, left by manual edits on the databases that
+    /// don't enforce the foreign keys.
+    OrphanMemberships,
+    /// Same for the nestings of missing groups.
+    OrphanGroupNestings,
+    /// The memberships added or removed by the group rules.
+    GroupRules,
+    /// The uidNumbers and gidNumbers of the users and groups without one.
+    PosixNumbers,
+    /// The group ID sequence, moved past the highest group ID, e.g. after an import with
+    /// explicit IDs.
+    GroupIdSequence,
+}
+
+impl MaintenanceStep {
+    pub const ALL: [MaintenanceStep; 5] = [
+        MaintenanceStep::OrphanMemberships,
+        MaintenanceStep::OrphanGroupNestings,
+        MaintenanceStep::GroupRules,
+        MaintenanceStep::PosixNumbers,
+        MaintenanceStep::GroupIdSequence,
+    ];
+
+    pub fn as_str(self) -> &'static str {
+        match self {
+            MaintenanceStep::OrphanMemberships => "orphan_memberships",
+            MaintenanceStep::OrphanGroupNestings => "orphan_group_nestings",
+            MaintenanceStep::GroupRules => "group_rules",
+            MaintenanceStep::PosixNumbers => "posix_numbers",
+            MaintenanceStep::GroupIdSequence => "group_id_sequence",
+        }
+    }
+}
+
+/// What a rebuild changed, or would change for a dry run.
+#[derive(PartialEq, Eq, Debug, Default, Serialize, Deserialize, Clone)]
+pub struct MaintenanceReport {
+    pub orphan_memberships_removed: usize,
+    pub orphan_group_nestings_removed: usize,
+    pub rule_memberships_added: usize,
+    pub rule_memberships_removed: usize,
+    pub posix_numbers_assigned: usize,
+    pub group_id_sequence_advanced: bool,
+}
+
+impl MaintenanceReport {
+    pub fn is_empty(&self) -> bool {
+        self == &Self::default()
+    }
+}
+
+/// The progress of a rebuild running in the background, or the result of the last one.
+#[derive(PartialEq, Eq, Debug, Serialize, Deserialize, Clone)]
+pub struct MaintenanceStatus {
+    pub dry_run: bool,
+    pub started_at: DateTime,
+    /// Unset while running.
+    pub finished_at: Option<DateTime>,
+    /// The number of steps done, out of `MaintenanceStep::ALL`.
+    pub steps_done: usize,
+    /// The changes of the steps done so far.
+    pub report: MaintenanceReport,
+    /// Why the rebuild stopped. The steps done before were committed, unless it is a dry run.
+    pub error: Option<String>,
+}
+
+/// The status of the last rebuild, shared by all the clones of a handler so that any session can
+/// follow it.
+#[derive(Clone, Default)]
+pub struct MaintenanceProgress(Arc<Mutex<Option<MaintenanceStatus>>>);
+
+impl MaintenanceProgress {
+    /// Fails if a rebuild is already running.
+    pub fn start(&self, dry_run: bool) -> Result<()> {
+        let mut status = self.0.lock().unwrap();
+        if matches!(&*status, Some(status) if status.finished_at.is_none()) {
+            return Err(DomainError::ValidationError(
+                "A maintenance is already running".to_owned(),
+            ));
+        }
+        *status = Some(MaintenanceStatus {
+            dry_run,
+            started_at: chrono::Utc::now(),
+            finished_at: None,
+            steps_done: 0,
+            report: MaintenanceReport::default(),
+            error: None,
+        });
+        Ok(())
+    }
+
+    pub fn step_done(&self, report: &MaintenanceReport) {
+        if let Some(status) = self.0.lock().unwrap().as_mut() {
+            status.steps_done += 1;
+            status.report = report.clone();
+        }
+    }
+
+    pub fn finish(&self, result: &Result<MaintenanceReport>) {
+        if let Some(status) = self.0.lock().unwrap().as_mut() {
+            status.finished_at = Some(chrono::Utc::now());
+            match result {
+                Ok(report) => status.report = report.clone(),
+                Err(e) => status.error = Some(e.to_string()),
+            }
+        }
+    }
+
+    pub fn status(&self) -> Option<MaintenanceStatus> {
+        self.0.lock().unwrap().clone()
+    }
+}
+
+#[async_trait]
+pub trait MaintenanceBackendHandler {
+    /// Starts rebuilding the data derived from the users and groups in the background, e.g. after
+    /// a bulk import or manual edits of the database. It can run on a live server. With `dry_run`,
+    /// the changes are reported but rolled back. Fails if a rebuild is already running.
+    async fn start_maintenance(&self, dry_run: bool) -> Result<()>;
+    /// The status of the last rebuild started since the server started, if any.
+    async fn get_maintenance_status(&self) -> Result<Option<MaintenanceStatus>>;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_progress() {
+        let progress = MaintenanceProgress::default();
+        assert_eq!(progress.status(), None);
+        progress.start(true).unwrap();
+        assert!(progress.start(false).is_err());
+        let report = MaintenanceReport {
+            orphan_memberships_removed: 2,
+            ..Default::default()
+        };
+        progress.step_done(&report);
+        let status = progress.status().unwrap();
+        assert_eq!(status.steps_done, 1);
+        assert_eq!(status.report, report);
+        assert_eq!(status.finished_at, None);
+        progress.finish(&Err(DomainError::InternalError("down".to_owned())));
+        let status = progress.status().unwrap();
+        assert!(status.finished_at.is_some());
+        assert_eq!(status.error.as_deref(), Some("Internal error: `down`"));
+        // Another one can start once finished.
+        progress.start(false).unwrap();
+    }
+}
@@ -0,0 +1,14 @@
+use crate::domain::{error::Result, types::UserId};
+use async_trait::async_trait;
+
+/// One-time recovery codes, for the users who lost their second factor. Only their hashes are
+/// stored.
+#[async_trait]
+pub trait MfaBackupCodesHandler: Clone + Send {
+    /// Replaces the codes of the user with a new set, returned in clear: it can't be shown again.
+    async fn generate_backup_codes(&self, user_id: &UserId) -> Result<Vec<String>>;
+    /// Checks the code and consumes it, so that it can't be used again. Accepted in place of a
+    /// TOTP code.
+    async fn consume_backup_code(&self, user_id: &UserId, code: &str) -> Result<()>;
+    async fn count_backup_codes(&self, user_id: &UserId) -> Result<usize>;
+}
@@ -1,12 +1,48 @@
+pub mod auth_backend;
+pub mod avatar;
+pub mod bootstrap;
+pub mod change_events;
+pub mod clock;
+pub mod email_policy;
 pub mod error;
+pub mod group_rule_handler;
 pub mod handler;
 pub mod ldap;
+pub mod legacy_password_hash;
+pub mod maintenance_handler;
+pub mod mfa_backup_codes_handler;
 pub mod model;
+pub mod oidc_handler;
 pub mod opaque_handler;
+pub mod password_policy;
+pub mod scram;
+pub mod session_handler;
+pub mod sql_attribute_backend_handler;
+pub mod sql_audit_log_handler;
 pub mod sql_backend_handler;
+pub mod sql_backup;
+pub mod sql_change_sync;
 pub mod sql_group_backend_handler;
+pub mod sql_group_rule_backend_handler;
+pub mod sql_maintenance_backend_handler;
+pub mod sql_mfa_backup_codes_handler;
 pub mod sql_migrations;
+pub mod sql_nested_group_backend_handler;
+pub mod sql_oidc_handler;
 pub mod sql_opaque_handler;
+pub mod sql_posix_numbers;
+pub mod sql_session_backend_handler;
+pub mod sql_sudo_role_backend_handler;
 pub mod sql_tables;
+pub mod sql_totp_handler;
 pub mod sql_user_backend_handler;
+pub mod sql_user_import_handler;
+pub mod sql_webauthn_handler;
+pub mod sql_webhook_queue;
+pub mod sudo_role_handler;
+pub mod totp;
+pub mod totp_handler;
+pub mod totp_secret;
 pub mod types;
+pub mod user_id_policy;
+pub mod webauthn_handler;
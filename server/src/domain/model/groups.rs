@@ -12,7 +12,12 @@ pub struct Model {
     pub group_id: GroupId,
     pub display_name: String,
     pub creation_date: chrono::DateTime<chrono::Utc>,
+    #[sea_orm(
+        column_type = "Enum { name: crate::domain::types::uuid_type_name(), variants: Vec::new() }"
+    )]
     pub uuid: Uuid,
+    pub modified_at: chrono::DateTime<chrono::Utc>,
+    pub gid_number: Option<i32>,
 }
 
 #[derive(Copy, Clone, Debug, EnumIter, DeriveRelation)]
@@ -35,7 +40,9 @@ impl From<Model> for crate::domain::types::Group {
             id: group.group_id,
             display_name: group.display_name,
             creation_date: group.creation_date,
+            modified_at: group.modified_at,
             uuid: group.uuid,
+            gid_number: group.gid_number,
             users: vec![],
         }
     }
@@ -12,6 +12,8 @@ pub struct Model {
     pub user_id: UserId,
     #[sea_orm(primary_key)]
     pub group_id: GroupId,
+    /// Added by a group rule, and removed when the user doesn't match it anymore.
+    pub assigned_by_rule: bool,
 }
 
 #[derive(Copy, Clone, Debug, EnumIter, DeriveRelation)]
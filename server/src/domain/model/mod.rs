@@ -2,11 +2,31 @@
 
 pub mod prelude;
 
+pub mod audit_log;
+pub mod deletion_tombstones;
+pub mod email_verification_tokens;
+pub mod failed_login_attempts;
+pub mod group_attribute_schema;
+pub mod group_attributes;
+pub mod group_memberships;
+pub mod group_rule_conditions;
+pub mod group_rules;
 pub mod groups;
-pub mod jwt_refresh_storage;
 pub mod jwt_storage;
 pub mod memberships;
+pub mod mfa_backup_codes;
+pub mod oidc_authorization_codes;
+pub mod oidc_clients;
 pub mod password_reset_tokens;
+pub mod refresh_tokens;
+pub mod sequences;
+pub mod sessions;
+pub mod sudo_role_values;
+pub mod sudo_roles;
+pub mod user_attribute_schema;
+pub mod user_attributes;
 pub mod users;
+pub mod webauthn_credentials;
+pub mod webhook_deliveries;
 
 pub use prelude::*;
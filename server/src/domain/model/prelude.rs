@@ -1,14 +1,54 @@
 //! `SeaORM` Entity. Generated by sea-orm-codegen 0.10.3
 
+pub use super::audit_log::Column as AuditLogColumn;
+pub use super::audit_log::Entity as AuditLog;
+pub use super::deletion_tombstones::Column as DeletionTombstonesColumn;
+pub use super::deletion_tombstones::Entity as DeletionTombstones;
+pub use super::email_verification_tokens::Column as EmailVerificationTokensColumn;
+pub use super::email_verification_tokens::Entity as EmailVerificationTokens;
+pub use super::failed_login_attempts::Column as FailedLoginAttemptsColumn;
+pub use super::failed_login_attempts::Entity as FailedLoginAttempts;
+pub use super::group_attribute_schema::Column as GroupAttributeSchemaColumn;
+pub use super::group_attribute_schema::Entity as GroupAttributeSchema;
+pub use super::group_attributes::Column as GroupAttributesColumn;
+pub use super::group_attributes::Entity as GroupAttributes;
+pub use super::group_memberships::Column as GroupMembershipColumn;
+pub use super::group_memberships::Entity as GroupMembership;
+pub use super::group_rule_conditions::Column as GroupRuleConditionsColumn;
+pub use super::group_rule_conditions::Entity as GroupRuleConditions;
+pub use super::group_rules::Column as GroupRulesColumn;
+pub use super::group_rules::Entity as GroupRules;
 pub use super::groups::Column as GroupColumn;
 pub use super::groups::Entity as Group;
-pub use super::jwt_refresh_storage::Column as JwtRefreshStorageColumn;
-pub use super::jwt_refresh_storage::Entity as JwtRefreshStorage;
 pub use super::jwt_storage::Column as JwtStorageColumn;
 pub use super::jwt_storage::Entity as JwtStorage;
 pub use super::memberships::Column as MembershipColumn;
 pub use super::memberships::Entity as Membership;
+pub use super::mfa_backup_codes::Column as MfaBackupCodesColumn;
+pub use super::mfa_backup_codes::Entity as MfaBackupCodes;
+pub use super::oidc_authorization_codes::Column as OidcAuthorizationCodesColumn;
+pub use super::oidc_authorization_codes::Entity as OidcAuthorizationCodes;
+pub use super::oidc_clients::Column as OidcClientsColumn;
+pub use super::oidc_clients::Entity as OidcClients;
 pub use super::password_reset_tokens::Column as PasswordResetTokensColumn;
 pub use super::password_reset_tokens::Entity as PasswordResetTokens;
+pub use super::refresh_tokens::Column as RefreshTokensColumn;
+pub use super::refresh_tokens::Entity as RefreshTokens;
+pub use super::sequences::Column as SequencesColumn;
+pub use super::sequences::Entity as Sequences;
+pub use super::sessions::Column as SessionsColumn;
+pub use super::sessions::Entity as Sessions;
+pub use super::sudo_role_values::Column as SudoRoleValuesColumn;
+pub use super::sudo_role_values::Entity as SudoRoleValues;
+pub use super::sudo_roles::Column as SudoRolesColumn;
+pub use super::sudo_roles::Entity as SudoRoles;
+pub use super::user_attribute_schema::Column as UserAttributeSchemaColumn;
+pub use super::user_attribute_schema::Entity as UserAttributeSchema;
+pub use super::user_attributes::Column as UserAttributesColumn;
+pub use super::user_attributes::Entity as UserAttributes;
 pub use super::users::Column as UserColumn;
 pub use super::users::Entity as User;
+pub use super::webauthn_credentials::Column as WebauthnCredentialsColumn;
+pub use super::webauthn_credentials::Entity as WebauthnCredentials;
+pub use super::webhook_deliveries::Column as WebhookDeliveriesColumn;
+pub use super::webhook_deliveries::Entity as WebhookDeliveries;
@@ -6,12 +6,15 @@ use serde::{Deserialize, Serialize};
 use crate::domain::types::UserId;
 
 #[derive(Clone, Debug, PartialEq, DeriveEntityModel, Eq, Serialize, Deserialize)]
-#[sea_orm(table_name = "jwt_refresh_storage")]
+#[sea_orm(table_name = "refresh_tokens")]
 pub struct Model {
     #[sea_orm(primary_key, auto_increment = false)]
-    pub refresh_token_hash: i64,
+    pub token_hash: String,
+    pub family_id: String,
     pub user_id: UserId,
+    pub issued_at: chrono::DateTime<chrono::Utc>,
     pub expiry_date: chrono::DateTime<chrono::Utc>,
+    pub revoked: bool,
 }
 
 #[derive(Copy, Clone, Debug, EnumIter, DeriveRelation)]
@@ -3,7 +3,7 @@
 use sea_orm::entity::prelude::*;
 use serde::{Deserialize, Serialize};
 
-use crate::domain::types::{JpegPhoto, UserId, Uuid};
+use crate::domain::types::{uuid_column_type, JpegPhoto, MfaType, UserId, Uuid};
 
 #[derive(Copy, Clone, Default, Debug, DeriveEntity)]
 pub struct Entity;
@@ -21,8 +21,24 @@ pub struct Model {
     pub creation_date: chrono::DateTime<chrono::Utc>,
     pub password_hash: Option<Vec<u8>>,
     pub totp_secret: Option<String>,
-    pub mfa_type: Option<String>,
+    pub mfa_type: Option<MfaType>,
     pub uuid: Uuid,
+    pub deleted_at: Option<chrono::DateTime<chrono::Utc>>,
+    pub password_changed_at: Option<chrono::DateTime<chrono::Utc>>,
+    pub must_change_password: bool,
+    pub modified_at: chrono::DateTime<chrono::Utc>,
+    pub uid_number: Option<i32>,
+    pub gid_number: Option<i32>,
+    pub home_directory: Option<String>,
+    pub login_shell: Option<String>,
+    pub email_verified: bool,
+    pub scram_sha256_verifier: Option<String>,
+    /// See `TotpParameters::to_stored`, NULL for the defaults.
+    pub totp_parameters: Option<String>,
+    /// The time step of the last TOTP code accepted, that can't be used again.
+    pub totp_last_counter: Option<i64>,
+    /// The casing of the user ID, see `User::user_id_display`.
+    pub user_id_display: Option<String>,
 }
 
 impl EntityName for Entity {
@@ -44,6 +60,19 @@ pub enum Column {
     TotpSecret,
     MfaType,
     Uuid,
+    DeletedAt,
+    PasswordChangedAt,
+    MustChangePassword,
+    ModifiedAt,
+    UidNumber,
+    GidNumber,
+    HomeDirectory,
+    LoginShell,
+    EmailVerified,
+    ScramSha256Verifier,
+    TotpParameters,
+    TotpLastCounter,
+    UserIdDisplay,
 }
 
 impl ColumnTrait for Column {
@@ -59,9 +88,22 @@ impl ColumnTrait for Column {
             Column::Avatar => ColumnType::Binary,
             Column::CreationDate => ColumnType::DateTime,
             Column::PasswordHash => ColumnType::Binary,
-            Column::TotpSecret => ColumnType::String(Some(64)),
+            Column::TotpSecret => ColumnType::String(Some(255)),
             Column::MfaType => ColumnType::String(Some(64)),
-            Column::Uuid => ColumnType::String(Some(36)),
+            Column::Uuid => uuid_column_type(),
+            Column::DeletedAt => ColumnType::DateTime,
+            Column::PasswordChangedAt => ColumnType::DateTime,
+            Column::MustChangePassword => ColumnType::Boolean,
+            Column::ModifiedAt => ColumnType::DateTime,
+            Column::UidNumber => ColumnType::Integer,
+            Column::GidNumber => ColumnType::Integer,
+            Column::HomeDirectory => ColumnType::String(Some(255)),
+            Column::LoginShell => ColumnType::String(Some(255)),
+            Column::EmailVerified => ColumnType::Boolean,
+            Column::ScramSha256Verifier => ColumnType::String(Some(255)),
+            Column::TotpParameters => ColumnType::String(Some(64)),
+            Column::TotpLastCounter => ColumnType::BigInteger,
+            Column::UserIdDisplay => ColumnType::String(Some(255)),
         }
         .def()
     }
@@ -71,8 +113,8 @@ impl ColumnTrait for Column {
 pub enum Relation {
     #[sea_orm(has_many = "super::memberships::Entity")]
     Memberships,
-    #[sea_orm(has_many = "super::jwt_refresh_storage::Entity")]
-    JwtRefreshStorage,
+    #[sea_orm(has_many = "super::refresh_tokens::Entity")]
+    RefreshTokens,
     #[sea_orm(has_many = "super::jwt_storage::Entity")]
     JwtStorage,
     #[sea_orm(has_many = "super::password_reset_tokens::Entity")]
@@ -98,9 +140,9 @@ impl Related<super::memberships::Entity> for Entity {
     }
 }
 
-impl Related<super::jwt_refresh_storage::Entity> for Entity {
+impl Related<super::refresh_tokens::Entity> for Entity {
     fn to() -> RelationDef {
-        Relation::JwtRefreshStorage.def()
+        Relation::RefreshTokens.def()
     }
 }
 
@@ -127,8 +169,15 @@ impl From<Model> for crate::domain::types::User {
             first_name: user.first_name,
             last_name: user.last_name,
             creation_date: user.creation_date,
+            modified_at: user.modified_at,
             uuid: user.uuid,
             avatar: user.avatar,
+            uid_number: user.uid_number,
+            gid_number: user.gid_number,
+            home_directory: user.home_directory,
+            login_shell: user.login_shell,
+            email_verified: user.email_verified,
+            user_id_display: user.user_id_display,
         }
     }
 }
@@ -0,0 +1,59 @@
+use crate::domain::{
+    error::Result,
+    types::{DateTime, UserId},
+};
+use async_trait::async_trait;
+use serde::{Deserialize, Serialize};
+
+/// An application that delegates its logins to LLDAP through OpenID Connect.
+#[derive(PartialEq, Eq, Debug, Serialize, Deserialize, Clone)]
+pub struct OidcClient {
+    pub client_id: String,
+    /// The redirect URI of an authorization request must be one of these, exactly.
+    pub redirect_uris: Vec<String>,
+    pub allowed_scopes: Vec<String>,
+    pub creation_date: DateTime,
+}
+
+#[derive(PartialEq, Eq, Debug, Serialize, Deserialize, Clone)]
+pub struct CreateOidcClientRequest {
+    pub client_id: String,
+    pub redirect_uris: Vec<String>,
+    pub allowed_scopes: Vec<String>,
+}
+
+/// What a user agreed to give a client, exchanged for an authorization code.
+#[derive(PartialEq, Eq, Debug, Serialize, Deserialize, Clone)]
+pub struct OidcAuthorization {
+    pub client_id: String,
+    pub user_id: UserId,
+    pub redirect_uri: String,
+    pub scopes: Vec<String>,
+    pub nonce: Option<String>,
+}
+
+#[async_trait]
+pub trait OidcHandler: Clone + Send {
+    /// Returns the client secret. Only its hash is stored, so it cannot be retrieved later.
+    async fn create_oidc_client(&self, request: CreateOidcClientRequest) -> Result<String>;
+    async fn list_oidc_clients(&self) -> Result<Vec<OidcClient>>;
+    async fn get_oidc_client(&self, client_id: &str) -> Result<OidcClient>;
+    async fn delete_oidc_client(&self, client_id: &str) -> Result<()>;
+    /// Fails if the client doesn't exist or the secret is wrong.
+    async fn authenticate_oidc_client(
+        &self,
+        client_id: &str,
+        client_secret: &str,
+    ) -> Result<OidcClient>;
+    /// Returns the code to send back to the client, valid for a few minutes.
+    async fn create_oidc_authorization_code(
+        &self,
+        authorization: OidcAuthorization,
+    ) -> Result<String>;
+    /// A code can only be used once, by the client it was issued to.
+    async fn consume_oidc_authorization_code(
+        &self,
+        client_id: &str,
+        code: &str,
+    ) -> Result<OidcAuthorization>;
+}
@@ -0,0 +1,294 @@
+use crate::infra::{
+    configuration::PasswordPolicyOptions,
+    i18n::{MessageCatalog, MessageId},
+};
+use anyhow::{Context, Result};
+use sha1::{Digest, Sha1};
+use std::{collections::HashSet, time::Duration};
+use thiserror::Error;
+use tracing::{instrument, warn};
+
+const BREACH_CHECK_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// A rule of the password policy that a password breaks.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum PasswordPolicyViolation {
+    TooShort { min_length: usize },
+    TooLong { max_length: usize },
+    MissingLowercase,
+    MissingUppercase,
+    MissingDigit,
+    MissingSymbol,
+    Banned,
+    Breached { count: u64 },
+}
+
+impl PasswordPolicyViolation {
+    /// The message in the first of the locales that has it, or in English.
+    pub fn localize(&self, catalog: &MessageCatalog, locales: &[String]) -> String {
+        let (id, variables) = match self {
+            Self::TooShort { min_length } => (
+                MessageId::PasswordTooShort,
+                vec![("min_length", min_length.to_string())],
+            ),
+            Self::TooLong { max_length } => (
+                MessageId::PasswordTooLong,
+                vec![("max_length", max_length.to_string())],
+            ),
+            Self::MissingLowercase => (MessageId::PasswordMissingLowercase, vec![]),
+            Self::MissingUppercase => (MessageId::PasswordMissingUppercase, vec![]),
+            Self::MissingDigit => (MessageId::PasswordMissingDigit, vec![]),
+            Self::MissingSymbol => (MessageId::PasswordMissingSymbol, vec![]),
+            Self::Banned => (MessageId::PasswordBanned, vec![]),
+            Self::Breached { count } => (
+                MessageId::PasswordBreached,
+                vec![("count", count.to_string())],
+            ),
+        };
+        let variables = variables
+            .iter()
+            .map(|(name, value)| (*name, value.as_str()))
+            .collect::<Vec<_>>();
+        catalog.message(locales, id, &variables)
+    }
+}
+
+impl std::fmt::Display for PasswordPolicyViolation {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.localize(&MessageCatalog::default(), &[]))
+    }
+}
+
+/// All the rules that a password breaks.
+#[derive(Debug, Clone, PartialEq, Eq, Error)]
+#[error("{}", .0.iter().map(ToString::to_string).collect::<Vec<_>>().join("; "))]
+pub struct PasswordPolicyError(pub Vec<PasswordPolicyViolation>);
+
+impl PasswordPolicyError {
+    pub fn localize(&self, catalog: &MessageCatalog, locales: &[String]) -> String {
+        self.0
+            .iter()
+            .map(|violation| violation.localize(catalog, locales))
+            .collect::<Vec<_>>()
+            .join("; ")
+    }
+}
+
+/// Checks the passwords set in clear. The OPAQUE registrations of the web UI never send the
+/// password to the server, so they can't be checked here.
+#[derive(Debug, Default)]
+pub struct PasswordPolicy {
+    options: PasswordPolicyOptions,
+    banned_passwords: HashSet<String>,
+    breach_check_client: Option<reqwest::Client>,
+}
+
+impl PasswordPolicy {
+    pub fn new(options: &PasswordPolicyOptions) -> Result<Self> {
+        let banned_passwords = match &options.banned_passwords_file {
+            Some(file) => std::fs::read_to_string(file)
+                .with_context(|| format!("Could not read the banned passwords file `{}`", file))?
+                .lines()
+                .map(str::trim)
+                .filter(|line| !line.is_empty())
+                .map(str::to_lowercase)
+                .collect(),
+            None => HashSet::new(),
+        };
+        let breach_check_client = if options.breach_check_enabled {
+            Some(
+                reqwest::Client::builder()
+                    .timeout(BREACH_CHECK_TIMEOUT)
+                    .build()?,
+            )
+        } else {
+            None
+        };
+        Ok(Self {
+            options: options.clone(),
+            banned_passwords,
+            breach_check_client,
+        })
+    }
+
+    pub fn options(&self) -> &PasswordPolicyOptions {
+        &self.options
+    }
+
+    /// The rules that can be checked locally.
+    pub fn check_rules(&self, password: &str) -> Vec<PasswordPolicyViolation> {
+        use PasswordPolicyViolation::*;
+        let options = &self.options;
+        let length = password.chars().count();
+        let mut violations = Vec::new();
+        if length < options.min_length {
+            violations.push(TooShort {
+                min_length: options.min_length,
+            });
+        }
+        if length > options.max_length {
+            violations.push(TooLong {
+                max_length: options.max_length,
+            });
+        }
+        let classes: [(bool, fn(char) -> bool, PasswordPolicyViolation); 4] = [
+            (
+                options.require_lowercase,
+                char::is_lowercase,
+                MissingLowercase,
+            ),
+            (
+                options.require_uppercase,
+                char::is_uppercase,
+                MissingUppercase,
+            ),
+            (options.require_digit, |c| c.is_ascii_digit(), MissingDigit),
+            (
+                options.require_symbol,
+                |c| !c.is_alphanumeric() && !c.is_whitespace(),
+                MissingSymbol,
+            ),
+        ];
+        for (required, matches, violation) in classes {
+            if required && !password.chars().any(matches) {
+                violations.push(violation);
+            }
+        }
+        if self
+            .banned_passwords
+            .contains(&password.trim().to_lowercase())
+        {
+            violations.push(Banned);
+        }
+        violations
+    }
+
+    #[instrument(skip_all, level = "debug")]
+    pub async fn check(&self, password: &str) -> std::result::Result<(), PasswordPolicyError> {
+        let mut violations = self.check_rules(password);
+        if let Some(client) = &self.breach_check_client {
+            match self.breach_count(client, password).await {
+                Ok(0) => (),
+                Ok(count) => violations.push(PasswordPolicyViolation::Breached { count }),
+                // The password changes shouldn't depend on a third-party service.
+                Err(e) => warn!("Could not check the password against the breaches: {:#}", e),
+            }
+        }
+        if violations.is_empty() {
+            Ok(())
+        } else {
+            Err(PasswordPolicyError(violations))
+        }
+    }
+
+    /// The k-anonymity range query of Have I Been Pwned: only the first 5 characters of the hash
+    /// are sent, and the matching suffixes are returned with their counts.
+    async fn breach_count(&self, client: &reqwest::Client, password: &str) -> Result<u64> {
+        let hash = sha1_hex(password);
+        let (prefix, suffix) = hash.split_at(5);
+        let body = client
+            .get(format!(
+                "{}/range/{}",
+                self.options.breach_check_url.trim_end_matches('/'),
+                prefix
+            ))
+            .send()
+            .await?
+            .error_for_status()?
+            .text()
+            .await?;
+        Ok(find_breach_count(&body, suffix))
+    }
+}
+
+fn sha1_hex(password: &str) -> String {
+    Sha1::digest(password.as_bytes())
+        .iter()
+        .map(|b| format!("{:02X}", b))
+        .collect()
+}
+
+/// Parses the `SUFFIX:COUNT` lines of a range response.
+fn find_breach_count(body: &str, suffix: &str) -> u64 {
+    body.lines()
+        .filter_map(|line| line.trim().split_once(':'))
+        .find(|(line_suffix, _)| line_suffix.eq_ignore_ascii_case(suffix))
+        .and_then(|(_, count)| count.trim().parse().ok())
+        .unwrap_or(0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::infra::configuration::PasswordPolicyOptionsBuilder;
+    use PasswordPolicyViolation::*;
+
+    #[test]
+    fn test_character_classes() {
+        let policy = PasswordPolicy::new(
+            &PasswordPolicyOptionsBuilder::default()
+                .min_length(10)
+                .max_length(20)
+                .require_lowercase(true)
+                .require_uppercase(true)
+                .require_digit(true)
+                .require_symbol(true)
+                .build()
+                .unwrap(),
+        )
+        .unwrap();
+        assert_eq!(
+            policy.check_rules("abc"),
+            vec![
+                TooShort { min_length: 10 },
+                MissingUppercase,
+                MissingDigit,
+                MissingSymbol
+            ]
+        );
+        assert_eq!(
+            policy.check_rules("ABCDEFGHIJKLMNOPQRSTUVWXYZ"),
+            vec![
+                TooLong { max_length: 20 },
+                MissingLowercase,
+                MissingDigit,
+                MissingSymbol
+            ]
+        );
+        assert_eq!(policy.check_rules("Correct-Horse-1"), vec![]);
+        assert_eq!(
+            PasswordPolicyError(policy.check_rules("correct horse 1")).to_string(),
+            "The password must contain an uppercase letter; The password must contain a symbol"
+        );
+    }
+
+    #[test]
+    fn test_banned_passwords() {
+        let file = std::env::temp_dir().join("lldap_test_banned_passwords.txt");
+        std::fs::write(&file, "password\n\n  Letmein123 \n").unwrap();
+        let policy = PasswordPolicy::new(
+            &PasswordPolicyOptionsBuilder::default()
+                .banned_passwords_file(Some(file.to_str().unwrap().to_owned()))
+                .build()
+                .unwrap(),
+        )
+        .unwrap();
+        std::fs::remove_file(&file).unwrap();
+        assert_eq!(policy.check_rules("PASSWORD"), vec![Banned]);
+        assert_eq!(policy.check_rules("letmein123"), vec![Banned]);
+        assert_eq!(policy.check_rules("password1"), vec![]);
+    }
+
+    #[test]
+    fn test_breach_count() {
+        // SHA-1 of "password".
+        let hash = sha1_hex("password");
+        assert_eq!(hash, "5BAA61E4C9B93F3F0682250B6CF8331B7EE68FD8");
+        let body = "1E4C9B93F3F0682250B6CF8331B7EE68FD7:3\r\n1E4C9B93F3F0682250B6CF8331B7EE68FD8:9659365\r\n";
+        assert_eq!(find_breach_count(body, &hash[5..]), 9659365);
+        assert_eq!(
+            find_breach_count(body, "0000000000000000000000000000000000"),
+            0
+        );
+    }
+}
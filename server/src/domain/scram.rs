@@ -0,0 +1,367 @@
+//! SCRAM-SHA-256 (RFC 5802 and RFC 7677), for the SASL binds over LDAP: the client proves that it
+//! knows the password without sending it. The server only stores a verifier, derived from the
+//! salted password, that can check the proof but not be used to log in.
+//!
+//! The passwords are used as is, without SASLprep: the clients leave the ASCII passwords
+//! unchanged too.
+use hmac::{Hmac, Mac, NewMac};
+use sha2::{Digest, Sha256};
+
+pub const MECHANISM: &str = "SCRAM-SHA-256";
+const ITERATIONS: u32 = 4096;
+const SALT_LENGTH: usize = 16;
+const NONCE_LENGTH: usize = 24;
+
+#[derive(Debug, Clone, PartialEq, Eq, thiserror::Error)]
+pub enum ScramError {
+    #[error("Malformed SCRAM message: {0}")]
+    Malformed(&'static str),
+    #[error("Channel binding is not supported")]
+    ChannelBindingNotSupported,
+    #[error("Invalid SCRAM proof")]
+    InvalidProof,
+}
+
+fn hmac(key: &[u8], data: &[u8]) -> Vec<u8> {
+    let mut mac = Hmac::<Sha256>::new_varkey(key).expect("HMAC accepts keys of any size");
+    mac.update(data);
+    mac.finalize().into_bytes().to_vec()
+}
+
+/// `Hi()`: PBKDF2 with HMAC-SHA-256, for a single block.
+fn salted_password(password: &str, salt: &[u8], iterations: u32) -> Vec<u8> {
+    let mut first_block = salt.to_vec();
+    first_block.extend_from_slice(&1u32.to_be_bytes());
+    let mut block = hmac(password.as_bytes(), &first_block);
+    let mut result = block.clone();
+    for _ in 1..iterations {
+        block = hmac(password.as_bytes(), &block);
+        result.iter_mut().zip(&block).for_each(|(r, b)| *r ^= b);
+    }
+    result
+}
+
+/// Without shortcut on the first difference, not to reveal how much of a proof is right.
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+/// What the server stores to check the proofs of a password, formatted as in RFC 5803:
+/// `SCRAM-SHA-256$<iterations>:<salt>$<stored key>:<server key>`, in base64.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ScramVerifier {
+    iterations: u32,
+    salt: Vec<u8>,
+    stored_key: Vec<u8>,
+    server_key: Vec<u8>,
+}
+
+impl ScramVerifier {
+    /// The verifier of the password, with a random salt.
+    pub fn new(password: &str) -> Self {
+        use rand::RngCore;
+        let mut salt = vec![0; SALT_LENGTH];
+        rand::rngs::OsRng.fill_bytes(&mut salt);
+        Self::with_salt(password, salt, ITERATIONS)
+    }
+
+    fn with_salt(password: &str, salt: Vec<u8>, iterations: u32) -> Self {
+        let salted_password = salted_password(password, &salt, iterations);
+        let client_key = hmac(&salted_password, b"Client Key");
+        Self {
+            iterations,
+            salt,
+            stored_key: Sha256::digest(&client_key).to_vec(),
+            server_key: hmac(&salted_password, b"Server Key"),
+        }
+    }
+
+    /// A verifier that matches no password, for the users without one. The salt is derived from
+    /// the user name: the exchange looks the same as for the other users, and fails at the proof.
+    pub fn unknown_user(username: &str) -> Self {
+        use rand::RngCore;
+        let mut server_key = vec![0; 32];
+        rand::rngs::OsRng.fill_bytes(&mut server_key);
+        Self {
+            iterations: ITERATIONS,
+            salt: Sha256::digest(format!("lldap-scram-salt:{}", username).as_bytes())
+                [..SALT_LENGTH]
+                .to_vec(),
+            stored_key: Sha256::digest(&server_key).to_vec(),
+            server_key,
+        }
+    }
+
+    pub fn parse(verifier: &str) -> Option<Self> {
+        let (parameters, keys) = verifier
+            .strip_prefix(MECHANISM)?
+            .strip_prefix('$')?
+            .split_once('$')?;
+        let (iterations, salt) = parameters.split_once(':')?;
+        let (stored_key, server_key) = keys.split_once(':')?;
+        Some(Self {
+            iterations: iterations.parse().ok()?,
+            salt: base64::decode(salt).ok()?,
+            stored_key: base64::decode(stored_key).ok()?,
+            server_key: base64::decode(server_key).ok()?,
+        })
+    }
+}
+
+impl std::fmt::Display for ScramVerifier {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{}${}:{}${}:{}",
+            MECHANISM,
+            self.iterations,
+            base64::encode(&self.salt),
+            base64::encode(&self.stored_key),
+            base64::encode(&self.server_key)
+        )
+    }
+}
+
+/// A `saslname`, where `,` and `=` are escaped as `=2C` and `=3D`.
+fn parse_sasl_name(name: &str) -> Result<String, ScramError> {
+    let mut result = String::with_capacity(name.len());
+    let mut rest = name;
+    while let Some(index) = rest.find('=') {
+        result.push_str(&rest[..index]);
+        rest = &rest[index..];
+        if let Some(r) = rest.strip_prefix("=2C") {
+            result.push(',');
+            rest = r;
+        } else if let Some(r) = rest.strip_prefix("=3D") {
+            result.push('=');
+            rest = r;
+        } else {
+            return Err(ScramError::Malformed("invalid escape in a name"));
+        }
+    }
+    result.push_str(rest);
+    Ok(result)
+}
+
+/// The first message of the client: `<gs2 header><client first message bare>`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ClientFirstMessage {
+    /// The authentication identity.
+    pub username: String,
+    /// The identity to act as, if the client asked for one.
+    pub authzid: Option<String>,
+    gs2_header: String,
+    client_nonce: String,
+    bare: String,
+}
+
+impl ClientFirstMessage {
+    pub fn parse(message: &[u8]) -> Result<Self, ScramError> {
+        let message =
+            std::str::from_utf8(message).map_err(|_| ScramError::Malformed("not UTF-8"))?;
+        let mut parts = message.splitn(3, ',');
+        let (cbind_flag, authzid, bare) = match (parts.next(), parts.next(), parts.next()) {
+            (Some(cbind_flag), Some(authzid), Some(bare)) => (cbind_flag, authzid, bare),
+            _ => return Err(ScramError::Malformed("missing GS2 header")),
+        };
+        match cbind_flag {
+            "n" | "y" => (),
+            flag if flag.starts_with("p=") => return Err(ScramError::ChannelBindingNotSupported),
+            _ => return Err(ScramError::Malformed("invalid channel binding flag")),
+        }
+        let authzid = match authzid {
+            "" => None,
+            authzid => {
+                Some(parse_sasl_name(authzid.strip_prefix("a=").ok_or(
+                    ScramError::Malformed("invalid authorization identity"),
+                )?)?)
+            }
+        };
+        let mut attributes = bare.split(',');
+        let username = attributes
+            .next()
+            .and_then(|a| a.strip_prefix("n="))
+            .ok_or(ScramError::Malformed("missing user name"))?;
+        let client_nonce = attributes
+            .next()
+            .and_then(|a| a.strip_prefix("r="))
+            .filter(|nonce| !nonce.is_empty())
+            .ok_or(ScramError::Malformed("missing nonce"))?;
+        Ok(Self {
+            username: parse_sasl_name(username)?,
+            authzid,
+            gs2_header: message[..message.len() - bare.len()].to_owned(),
+            client_nonce: client_nonce.to_owned(),
+            bare: bare.to_owned(),
+        })
+    }
+}
+
+/// An exchange between the first message of the server and the final message of the client.
+#[derive(Debug, Clone)]
+pub struct ScramExchange {
+    verifier: ScramVerifier,
+    client_first: ClientFirstMessage,
+    server_first: String,
+    nonce: String,
+}
+
+impl ScramExchange {
+    /// Answers the first message of the client with the salt and the iterations of the verifier.
+    pub fn start(client_first: ClientFirstMessage, verifier: ScramVerifier) -> (Self, String) {
+        let nonce = format!(
+            "{}{}",
+            client_first.client_nonce,
+            crate::domain::sql_oidc_handler::gen_random_token(NONCE_LENGTH)
+        );
+        let server_first = format!(
+            "r={},s={},i={}",
+            nonce,
+            base64::encode(&verifier.salt),
+            verifier.iterations
+        );
+        (
+            Self {
+                verifier,
+                client_first,
+                server_first: server_first.clone(),
+                nonce,
+            },
+            server_first,
+        )
+    }
+
+    /// Checks the proof in the final message of the client, and returns the final message of
+    /// the server, that proves to the client that the server knows the verifier.
+    pub fn finish(self, client_final: &[u8]) -> Result<String, ScramError> {
+        let client_final =
+            std::str::from_utf8(client_final).map_err(|_| ScramError::Malformed("not UTF-8"))?;
+        let (without_proof, proof) = client_final
+            .rsplit_once(",p=")
+            .ok_or(ScramError::Malformed("missing proof"))?;
+        let mut attributes = without_proof.split(',');
+        let channel_binding = attributes
+            .next()
+            .and_then(|a| a.strip_prefix("c="))
+            .and_then(|c| base64::decode(c).ok())
+            .ok_or(ScramError::Malformed("missing channel binding"))?;
+        if channel_binding != self.client_first.gs2_header.as_bytes() {
+            return Err(ScramError::Malformed("the channel binding doesn't match"));
+        }
+        if attributes.next().and_then(|a| a.strip_prefix("r=")) != Some(self.nonce.as_str()) {
+            return Err(ScramError::Malformed("the nonce doesn't match"));
+        }
+        let proof = base64::decode(proof).map_err(|_| ScramError::Malformed("invalid proof"))?;
+        let auth_message = format!(
+            "{},{},{}",
+            self.client_first.bare, self.server_first, without_proof
+        );
+        let client_signature = hmac(&self.verifier.stored_key, auth_message.as_bytes());
+        if proof.len() != client_signature.len() {
+            return Err(ScramError::InvalidProof);
+        }
+        let client_key: Vec<u8> = proof
+            .iter()
+            .zip(&client_signature)
+            .map(|(p, s)| p ^ s)
+            .collect();
+        if !constant_time_eq(&Sha256::digest(&client_key), &self.verifier.stored_key) {
+            return Err(ScramError::InvalidProof);
+        }
+        let server_signature = hmac(&self.verifier.server_key, auth_message.as_bytes());
+        Ok(format!("v={}", base64::encode(server_signature)))
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+
+    /// The client side of the exchange.
+    pub fn client_final(password: &str, client_first_bare: &str, server_first: &str) -> String {
+        let mut fields = server_first.split(',');
+        let nonce = fields.next().unwrap().strip_prefix("r=").unwrap();
+        let salt = base64::decode(fields.next().unwrap().strip_prefix("s=").unwrap()).unwrap();
+        let iterations = fields.next().unwrap().strip_prefix("i=").unwrap();
+        let salted_password = salted_password(password, &salt, iterations.parse().unwrap());
+        let client_key = hmac(&salted_password, b"Client Key");
+        let without_proof = format!("c=biws,r={}", nonce);
+        let auth_message = format!("{},{},{}", client_first_bare, server_first, without_proof);
+        let client_signature = hmac(&Sha256::digest(&client_key), auth_message.as_bytes());
+        let proof: Vec<u8> = client_key
+            .iter()
+            .zip(&client_signature)
+            .map(|(k, s)| k ^ s)
+            .collect();
+        format!("{},p={}", without_proof, base64::encode(proof))
+    }
+
+    #[test]
+    fn test_rfc_7677_example() {
+        // The example exchange of RFC 7677, with the password "pencil".
+        let verifier = ScramVerifier::with_salt(
+            "pencil",
+            base64::decode("W22ZaJ0SNY7soEsUEjb6gQ==").unwrap(),
+            4096,
+        );
+        let client_first = ClientFirstMessage::parse(b"n,,n=user,r=rOprNGfwEbeRWgbNEkqO").unwrap();
+        assert_eq!(client_first.username, "user");
+        let (mut exchange, _) = ScramExchange::start(client_first, verifier);
+        exchange.nonce = "rOprNGfwEbeRWgbNEkqO%hvYDpWUa2RaTCAfuxFIlj)hNlF$k0".to_owned();
+        exchange.server_first =
+            "r=rOprNGfwEbeRWgbNEkqO%hvYDpWUa2RaTCAfuxFIlj)hNlF$k0,s=W22ZaJ0SNY7soEsUEjb6gQ==,i=4096"
+                .to_owned();
+        assert_eq!(
+            exchange
+                .finish(
+                    b"c=biws,r=rOprNGfwEbeRWgbNEkqO%hvYDpWUa2RaTCAfuxFIlj)hNlF$k0,\
+                      p=dHzbZapWIk4jUhN+Ute9ytag9zjfMHgsqmmiz7AndVQ="
+                )
+                .unwrap(),
+            "v=6rriTRBi23WpRR/wtup+mMhUZUn/dB5nLTJRsjl95G4="
+        );
+    }
+
+    #[test]
+    fn test_exchange() {
+        let verifier = ScramVerifier::parse(&ScramVerifier::new("bob00").to_string()).unwrap();
+        let client_first_bare = "n=bob,r=abcdef";
+        let start = |verifier: &ScramVerifier| {
+            let client_first =
+                ClientFirstMessage::parse(format!("n,,{}", client_first_bare).as_bytes()).unwrap();
+            ScramExchange::start(client_first, verifier.clone())
+        };
+        let (exchange, server_first) = start(&verifier);
+        assert!(server_first.starts_with("r=abcdef"));
+        let message = client_final("bob00", client_first_bare, &server_first);
+        assert!(exchange.finish(message.as_bytes()).is_ok());
+
+        let (exchange, server_first) = start(&verifier);
+        let message = client_final("wrong", client_first_bare, &server_first);
+        assert_eq!(
+            exchange.finish(message.as_bytes()),
+            Err(ScramError::InvalidProof)
+        );
+
+        let verifier = ScramVerifier::unknown_user("bob");
+        let (exchange, server_first) = start(&verifier);
+        let message = client_final("bob00", client_first_bare, &server_first);
+        assert_eq!(
+            exchange.finish(message.as_bytes()),
+            Err(ScramError::InvalidProof)
+        );
+    }
+
+    #[test]
+    fn test_parse_client_first() {
+        let message = ClientFirstMessage::parse(b"y,a=bob,n=b=2Cob=3D,r=abc").unwrap();
+        assert_eq!(message.username, "b,ob=");
+        assert_eq!(message.authzid.as_deref(), Some("bob"));
+        assert_eq!(
+            ClientFirstMessage::parse(b"p=tls-unique,,n=bob,r=abc"),
+            Err(ScramError::ChannelBindingNotSupported)
+        );
+        assert!(ClientFirstMessage::parse(b"n,,n=bob").is_err());
+        assert!(ClientFirstMessage::parse(b"n,,n=b=ob,r=abc").is_err());
+    }
+}
@@ -0,0 +1,33 @@
+use crate::domain::{
+    error::Result,
+    types::{DateTime, UserId},
+};
+use async_trait::async_trait;
+use serde::{Deserialize, Serialize};
+
+/// Where a session is used from, as of its last refresh.
+#[derive(PartialEq, Eq, Debug, Default, Serialize, Deserialize, Clone)]
+pub struct SessionClient {
+    pub ip_address: Option<String>,
+    pub user_agent: Option<String>,
+}
+
+/// A login, and the refresh tokens issued from it.
+#[derive(PartialEq, Eq, Debug, Serialize, Deserialize, Clone)]
+pub struct Session {
+    pub session_id: String,
+    pub user_id: UserId,
+    pub creation_date: DateTime,
+    /// The last refresh of the JWT.
+    pub last_seen: DateTime,
+    pub client: SessionClient,
+}
+
+#[async_trait]
+pub trait SessionBackendHandler {
+    /// The sessions of the user that are neither revoked nor expired, most recently seen first.
+    async fn list_sessions(&self, user_id: &UserId) -> Result<Vec<Session>>;
+    async fn get_session(&self, session_id: &str) -> Result<Session>;
+    /// Revokes all the refresh tokens of the session.
+    async fn revoke_session(&self, session_id: &str) -> Result<()>;
+}
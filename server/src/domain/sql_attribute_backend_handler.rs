@@ -0,0 +1,643 @@
+use super::{
+    error::{DomainError, Result},
+    handler::{GroupAttributeBackendHandler, UserAttributeBackendHandler},
+    model::{
+        self, GroupAttributeSchemaColumn, GroupAttributesColumn, UserAttributeSchemaColumn,
+        UserAttributesColumn, UserColumn,
+    },
+    sql_audit_log_handler::AuditChange,
+    sql_backend_handler::SqlBackendHandler,
+    sql_migrations::{has_indexed_attributes, update_attribute_values_index, AttributeTable},
+    types::{Attribute, AttributeName, AttributeSchema, AttributeValue, GroupId, UserId},
+};
+use async_trait::async_trait;
+use sea_orm::{
+    ActiveModelTrait, ActiveValue, ColumnTrait, EntityTrait, QueryFilter, QueryOrder,
+    TransactionTrait,
+};
+use tracing::{debug, instrument};
+
+/// Checks that the values can be stored in the attribute described by `schema`.
+pub(crate) fn validate_attribute_values(
+    schema: &AttributeSchema,
+    values: &[AttributeValue],
+) -> Result<()> {
+    if !schema.is_list && values.len() > 1 {
+        return Err(DomainError::ValidationError(format!(
+            "Attribute '{}' is single-valued, got {} values",
+            schema.name,
+            values.len()
+        )));
+    }
+    if let Some(value) = values
+        .iter()
+        .find(|v| v.attribute_type() != schema.attribute_type)
+    {
+        return Err(DomainError::ValidationError(format!(
+            "Attribute '{}' is of type {}, got a value of type {}",
+            schema.name,
+            schema.attribute_type.as_str(),
+            value.attribute_type().as_str()
+        )));
+    }
+    Ok(())
+}
+
+/// Groups the raw (name, value) rows, sorted by name, into typed attributes.
+pub(crate) fn parse_attribute_rows(
+    rows: impl IntoIterator<Item = (AttributeSchema, Vec<u8>)>,
+) -> Result<Vec<Attribute>> {
+    let mut attributes: Vec<Attribute> = Vec::new();
+    for (schema, bytes) in rows {
+        let value = AttributeValue::from_bytes(schema.attribute_type, bytes).map_err(|e| {
+            DomainError::InternalError(format!(
+                "Invalid value stored for attribute '{}': {:#}",
+                schema.name, e
+            ))
+        })?;
+        match attributes.last_mut() {
+            Some(attribute) if attribute.name == schema.name => attribute.values.push(value),
+            _ => attributes.push(Attribute {
+                name: schema.name,
+                values: vec![value],
+            }),
+        }
+    }
+    Ok(attributes)
+}
+
+/// The audit log entry for setting a custom attribute. The values are length-prefixed and
+/// concatenated, so that the hash covers the whole list.
+fn get_attribute_change(
+    change: AuditChange,
+    old_values: Vec<Vec<u8>>,
+    new_values: &[AttributeValue],
+) -> Option<AuditChange> {
+    fn concat(values: impl IntoIterator<Item = Vec<u8>>) -> Option<Vec<u8>> {
+        let mut bytes = Vec::new();
+        for value in values {
+            bytes.extend((value.len() as u64).to_be_bytes());
+            bytes.extend(value);
+        }
+        (!bytes.is_empty()).then_some(bytes)
+    }
+    let old_value = concat(old_values);
+    let new_value = concat(new_values.iter().map(AttributeValue::to_bytes));
+    (old_value != new_value).then(|| change.values(old_value, new_value))
+}
+
+/// Custom attributes are prefixed so they can't be mistaken for the built-in ones.
+fn get_audit_attribute_name(name: &AttributeName) -> String {
+    format!("attributes.{}", name)
+}
+
+#[async_trait]
+impl UserAttributeBackendHandler for SqlBackendHandler {
+    #[instrument(skip_all, level = "debug", err)]
+    async fn create_user_attribute(&self, schema: AttributeSchema) -> Result<()> {
+        debug!(?schema);
+        let transaction = self.sql_pool.begin().await?;
+        let was_indexed = has_indexed_attributes(&transaction, AttributeTable::Users).await?;
+        let new_attribute = model::user_attribute_schema::ActiveModel {
+            attribute_name: ActiveValue::Set(schema.name),
+            attribute_type: ActiveValue::Set(schema.attribute_type),
+            is_list: ActiveValue::Set(schema.is_list),
+            is_indexed: ActiveValue::Set(schema.is_indexed),
+        };
+        new_attribute.insert(&transaction).await?;
+        update_attribute_values_index(&transaction, AttributeTable::Users, was_indexed).await?;
+        transaction.commit().await?;
+        Ok(())
+    }
+
+    #[instrument(skip_all, level = "debug", err)]
+    async fn set_user_attribute_indexed(
+        &self,
+        name: &AttributeName,
+        is_indexed: bool,
+    ) -> Result<()> {
+        debug!(?name, ?is_indexed);
+        let transaction = self.sql_pool.begin().await?;
+        let schema = model::UserAttributeSchema::find_by_id(name.clone())
+            .one(&transaction)
+            .await?
+            .ok_or_else(|| DomainError::EntityNotFound(format!("No such attribute: '{}'", name)))?;
+        if schema.is_indexed == is_indexed {
+            return Ok(());
+        }
+        let was_indexed = has_indexed_attributes(&transaction, AttributeTable::Users).await?;
+        model::user_attribute_schema::ActiveModel {
+            attribute_name: ActiveValue::Set(schema.attribute_name),
+            is_indexed: ActiveValue::Set(is_indexed),
+            ..Default::default()
+        }
+        .update(&transaction)
+        .await?;
+        update_attribute_values_index(&transaction, AttributeTable::Users, was_indexed).await?;
+        transaction.commit().await?;
+        Ok(())
+    }
+
+    #[instrument(skip_all, level = "debug", ret, err)]
+    async fn list_user_attributes_schema(&self) -> Result<Vec<AttributeSchema>> {
+        Ok(model::UserAttributeSchema::find()
+            .order_by_asc(UserAttributeSchemaColumn::AttributeName)
+            .all(&self.sql_pool)
+            .await?
+            .into_iter()
+            .map(AttributeSchema::from)
+            .collect())
+    }
+
+    #[instrument(skip_all, level = "debug", ret, err)]
+    async fn get_user_attributes(&self, user_id: &UserId) -> Result<Vec<Attribute>> {
+        debug!(?user_id);
+        let rows = model::UserAttributes::find()
+            .filter(UserAttributesColumn::UserId.eq(user_id))
+            .order_by_asc(UserAttributesColumn::AttributeName)
+            .find_also_related(model::UserAttributeSchema)
+            .all(&self.sql_pool)
+            .await?;
+        parse_attribute_rows(rows.into_iter().filter_map(|(row, schema)| {
+            schema.map(|schema| (AttributeSchema::from(schema), row.value))
+        }))
+    }
+
+    #[instrument(skip_all, level = "debug", err)]
+    async fn set_user_attribute(
+        &self,
+        user_id: &UserId,
+        name: &AttributeName,
+        values: Vec<AttributeValue>,
+    ) -> Result<()> {
+        debug!(?user_id, ?name);
+        let schema: AttributeSchema = model::UserAttributeSchema::find_by_id(name.clone())
+            .one(&self.sql_pool)
+            .await?
+            .ok_or_else(|| DomainError::EntityNotFound(format!("No such attribute: '{}'", name)))?
+            .into();
+        validate_attribute_values(&schema, &values)?;
+        if model::User::find_by_id(user_id.clone())
+            .filter(UserColumn::DeletedAt.is_null())
+            .one(&self.sql_pool)
+            .await?
+            .is_none()
+        {
+            return Err(DomainError::EntityNotFound(format!(
+                "No such user: '{}'",
+                user_id
+            )));
+        }
+        let transaction = self.sql_pool.begin().await?;
+        let old_values = model::UserAttributes::find()
+            .filter(UserAttributesColumn::UserId.eq(user_id))
+            .filter(UserAttributesColumn::AttributeName.eq(name))
+            .all(&transaction)
+            .await?
+            .into_iter()
+            .map(|row| row.value)
+            .collect();
+        let change = get_attribute_change(
+            AuditChange::user(user_id, &get_audit_attribute_name(name)),
+            old_values,
+            &values,
+        );
+        model::UserAttributes::delete_many()
+            .filter(UserAttributesColumn::UserId.eq(user_id))
+            .filter(UserAttributesColumn::AttributeName.eq(name))
+            .exec(&transaction)
+            .await?;
+        if !values.is_empty() {
+            model::UserAttributes::insert_many(values.iter().map(|value| {
+                model::user_attributes::ActiveModel {
+                    user_id: ActiveValue::Set(user_id.clone()),
+                    attribute_name: ActiveValue::Set(name.clone()),
+                    value: ActiveValue::Set(value.to_bytes()),
+                }
+            }))
+            .exec(&transaction)
+            .await?;
+        }
+        let mut changes = change.into_iter().collect::<Vec<_>>();
+        changes.extend(
+            self.apply_group_rules(&transaction, Some(std::slice::from_ref(user_id)))
+                .await?,
+        );
+        let events = self.write_audit_log(&transaction, changes).await?;
+        transaction.commit().await?;
+        events.publish();
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl GroupAttributeBackendHandler for SqlBackendHandler {
+    #[instrument(skip_all, level = "debug", err)]
+    async fn create_group_attribute(&self, schema: AttributeSchema) -> Result<()> {
+        debug!(?schema);
+        let transaction = self.sql_pool.begin().await?;
+        let was_indexed = has_indexed_attributes(&transaction, AttributeTable::Groups).await?;
+        let new_attribute = model::group_attribute_schema::ActiveModel {
+            attribute_name: ActiveValue::Set(schema.name),
+            attribute_type: ActiveValue::Set(schema.attribute_type),
+            is_list: ActiveValue::Set(schema.is_list),
+            is_indexed: ActiveValue::Set(schema.is_indexed),
+        };
+        new_attribute.insert(&transaction).await?;
+        update_attribute_values_index(&transaction, AttributeTable::Groups, was_indexed).await?;
+        transaction.commit().await?;
+        Ok(())
+    }
+
+    #[instrument(skip_all, level = "debug", err)]
+    async fn set_group_attribute_indexed(
+        &self,
+        name: &AttributeName,
+        is_indexed: bool,
+    ) -> Result<()> {
+        debug!(?name, ?is_indexed);
+        let transaction = self.sql_pool.begin().await?;
+        let schema = model::GroupAttributeSchema::find_by_id(name.clone())
+            .one(&transaction)
+            .await?
+            .ok_or_else(|| DomainError::EntityNotFound(format!("No such attribute: '{}'", name)))?;
+        if schema.is_indexed == is_indexed {
+            return Ok(());
+        }
+        let was_indexed = has_indexed_attributes(&transaction, AttributeTable::Groups).await?;
+        model::group_attribute_schema::ActiveModel {
+            attribute_name: ActiveValue::Set(schema.attribute_name),
+            is_indexed: ActiveValue::Set(is_indexed),
+            ..Default::default()
+        }
+        .update(&transaction)
+        .await?;
+        update_attribute_values_index(&transaction, AttributeTable::Groups, was_indexed).await?;
+        transaction.commit().await?;
+        Ok(())
+    }
+
+    #[instrument(skip_all, level = "debug", ret, err)]
+    async fn list_group_attributes_schema(&self) -> Result<Vec<AttributeSchema>> {
+        Ok(model::GroupAttributeSchema::find()
+            .order_by_asc(GroupAttributeSchemaColumn::AttributeName)
+            .all(&self.sql_pool)
+            .await?
+            .into_iter()
+            .map(AttributeSchema::from)
+            .collect())
+    }
+
+    #[instrument(skip_all, level = "debug", ret, err)]
+    async fn get_group_attributes(&self, group_id: GroupId) -> Result<Vec<Attribute>> {
+        debug!(?group_id);
+        let rows = model::GroupAttributes::find()
+            .filter(GroupAttributesColumn::GroupId.eq(group_id))
+            .order_by_asc(GroupAttributesColumn::AttributeName)
+            .find_also_related(model::GroupAttributeSchema)
+            .all(&self.sql_pool)
+            .await?;
+        parse_attribute_rows(rows.into_iter().filter_map(|(row, schema)| {
+            schema.map(|schema| (AttributeSchema::from(schema), row.value))
+        }))
+    }
+
+    #[instrument(skip_all, level = "debug", err)]
+    async fn set_group_attribute(
+        &self,
+        group_id: GroupId,
+        name: &AttributeName,
+        values: Vec<AttributeValue>,
+    ) -> Result<()> {
+        debug!(?group_id, ?name);
+        let schema: AttributeSchema = model::GroupAttributeSchema::find_by_id(name.clone())
+            .one(&self.sql_pool)
+            .await?
+            .ok_or_else(|| DomainError::EntityNotFound(format!("No such attribute: '{}'", name)))?
+            .into();
+        validate_attribute_values(&schema, &values)?;
+        if model::Group::find_by_id(group_id)
+            .one(&self.sql_pool)
+            .await?
+            .is_none()
+        {
+            return Err(DomainError::EntityNotFound(format!(
+                "No such group: '{:?}'",
+                group_id
+            )));
+        }
+        let transaction = self.sql_pool.begin().await?;
+        let old_values = model::GroupAttributes::find()
+            .filter(GroupAttributesColumn::GroupId.eq(group_id))
+            .filter(GroupAttributesColumn::AttributeName.eq(name))
+            .all(&transaction)
+            .await?
+            .into_iter()
+            .map(|row| row.value)
+            .collect();
+        let change = get_attribute_change(
+            AuditChange::group(group_id, &get_audit_attribute_name(name)),
+            old_values,
+            &values,
+        );
+        model::GroupAttributes::delete_many()
+            .filter(GroupAttributesColumn::GroupId.eq(group_id))
+            .filter(GroupAttributesColumn::AttributeName.eq(name))
+            .exec(&transaction)
+            .await?;
+        if !values.is_empty() {
+            model::GroupAttributes::insert_many(values.iter().map(|value| {
+                model::group_attributes::ActiveModel {
+                    group_id: ActiveValue::Set(group_id),
+                    attribute_name: ActiveValue::Set(name.clone()),
+                    value: ActiveValue::Set(value.to_bytes()),
+                }
+            }))
+            .exec(&transaction)
+            .await?;
+        }
+        let events = self
+            .write_audit_log(&transaction, change.into_iter().collect())
+            .await?;
+        transaction.commit().await?;
+        events.publish();
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::domain::{
+        handler::GroupBackendHandler, sql_backend_handler::tests::*, types::AttributeType,
+    };
+
+    fn department_schema() -> AttributeSchema {
+        AttributeSchema {
+            name: AttributeName::new("Department"),
+            attribute_type: AttributeType::String,
+            is_list: false,
+            is_indexed: false,
+        }
+    }
+
+    #[tokio::test]
+    async fn test_create_and_list_user_attributes() {
+        let fixture = TestFixture::new().await;
+        fixture
+            .handler
+            .create_user_attribute(department_schema())
+            .await
+            .unwrap();
+        assert_eq!(
+            fixture.handler.list_user_attributes_schema().await.unwrap(),
+            vec![AttributeSchema {
+                name: AttributeName::new("department"),
+                ..department_schema()
+            }]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_create_user_attribute_case_insensitive() {
+        let fixture = TestFixture::new().await;
+        fixture
+            .handler
+            .create_user_attribute(department_schema())
+            .await
+            .unwrap();
+        fixture
+            .handler
+            .create_user_attribute(AttributeSchema {
+                name: AttributeName::new("DEPARTMENT"),
+                ..department_schema()
+            })
+            .await
+            .unwrap_err();
+    }
+
+    #[tokio::test]
+    async fn test_set_and_get_user_attribute() {
+        let fixture = TestFixture::new().await;
+        fixture
+            .handler
+            .create_user_attribute(department_schema())
+            .await
+            .unwrap();
+        fixture
+            .handler
+            .set_user_attribute(
+                &UserId::new("bob"),
+                &AttributeName::new("department"),
+                vec![AttributeValue::String("Engineering".to_owned())],
+            )
+            .await
+            .unwrap();
+        assert_eq!(
+            fixture
+                .handler
+                .get_user_attributes(&UserId::new("bob"))
+                .await
+                .unwrap(),
+            vec![Attribute {
+                name: AttributeName::new("department"),
+                values: vec![AttributeValue::String("Engineering".to_owned())],
+            }]
+        );
+        assert_eq!(
+            fixture
+                .handler
+                .get_user_attributes(&UserId::new("patrick"))
+                .await
+                .unwrap(),
+            vec![]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_set_user_attribute_list() {
+        let fixture = TestFixture::new().await;
+        fixture
+            .handler
+            .create_user_attribute(AttributeSchema {
+                name: AttributeName::new("roomNumber"),
+                attribute_type: AttributeType::Integer,
+                is_list: true,
+                is_indexed: false,
+            })
+            .await
+            .unwrap();
+        let values = vec![AttributeValue::Integer(12), AttributeValue::Integer(-3)];
+        fixture
+            .handler
+            .set_user_attribute(
+                &UserId::new("bob"),
+                &AttributeName::new("roomnumber"),
+                values.clone(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(
+            fixture
+                .handler
+                .get_user_attributes(&UserId::new("bob"))
+                .await
+                .unwrap(),
+            vec![Attribute {
+                name: AttributeName::new("roomnumber"),
+                values,
+            }]
+        );
+        // Setting an empty list removes the attribute.
+        fixture
+            .handler
+            .set_user_attribute(
+                &UserId::new("bob"),
+                &AttributeName::new("roomnumber"),
+                vec![],
+            )
+            .await
+            .unwrap();
+        assert_eq!(
+            fixture
+                .handler
+                .get_user_attributes(&UserId::new("bob"))
+                .await
+                .unwrap(),
+            vec![]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_set_user_attribute_wrong_type() {
+        let fixture = TestFixture::new().await;
+        fixture
+            .handler
+            .create_user_attribute(department_schema())
+            .await
+            .unwrap();
+        assert!(matches!(
+            fixture
+                .handler
+                .set_user_attribute(
+                    &UserId::new("bob"),
+                    &AttributeName::new("department"),
+                    vec![AttributeValue::Integer(3)],
+                )
+                .await,
+            Err(DomainError::ValidationError(_))
+        ));
+        assert!(matches!(
+            fixture
+                .handler
+                .set_user_attribute(
+                    &UserId::new("bob"),
+                    &AttributeName::new("department"),
+                    vec![
+                        AttributeValue::String("a".to_owned()),
+                        AttributeValue::String("b".to_owned())
+                    ],
+                )
+                .await,
+            Err(DomainError::ValidationError(_))
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_set_unknown_user_attribute() {
+        let fixture = TestFixture::new().await;
+        assert!(matches!(
+            fixture
+                .handler
+                .set_user_attribute(
+                    &UserId::new("bob"),
+                    &AttributeName::new("department"),
+                    vec![AttributeValue::String("Engineering".to_owned())],
+                )
+                .await,
+            Err(DomainError::EntityNotFound(_))
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_set_and_get_group_attribute() {
+        let fixture = TestFixture::new().await;
+        fixture
+            .handler
+            .create_group_attribute(AttributeSchema {
+                name: AttributeName::new("costCenter"),
+                attribute_type: AttributeType::Integer,
+                is_list: false,
+                is_indexed: false,
+            })
+            .await
+            .unwrap();
+        fixture
+            .handler
+            .set_group_attribute(
+                fixture.groups[0],
+                &AttributeName::new("costcenter"),
+                vec![AttributeValue::Integer(1234)],
+            )
+            .await
+            .unwrap();
+        assert_eq!(
+            fixture
+                .handler
+                .get_group_attributes(fixture.groups[0])
+                .await
+                .unwrap(),
+            vec![Attribute {
+                name: AttributeName::new("costcenter"),
+                values: vec![AttributeValue::Integer(1234)],
+            }]
+        );
+        assert!(matches!(
+            fixture
+                .handler
+                .set_group_attribute(
+                    fixture.groups[0],
+                    &AttributeName::new("costcenter"),
+                    vec![AttributeValue::String("1234".to_owned())],
+                )
+                .await,
+            Err(DomainError::ValidationError(_))
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_group_attributes_deleted_with_group() {
+        let fixture = TestFixture::new().await;
+        fixture
+            .handler
+            .create_group_attribute(AttributeSchema {
+                name: AttributeName::new("description"),
+                attribute_type: AttributeType::String,
+                is_list: false,
+                is_indexed: false,
+            })
+            .await
+            .unwrap();
+        fixture
+            .handler
+            .set_group_attribute(
+                fixture.groups[0],
+                &AttributeName::new("description"),
+                vec![AttributeValue::String("The best".to_owned())],
+            )
+            .await
+            .unwrap();
+        fixture
+            .handler
+            .delete_group(fixture.groups[0])
+            .await
+            .unwrap();
+        assert_eq!(
+            model::GroupAttributes::find()
+                .all(&fixture.handler.sql_pool)
+                .await
+                .unwrap(),
+            vec![]
+        );
+    }
+}
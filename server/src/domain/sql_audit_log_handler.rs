@@ -0,0 +1,505 @@
+use super::{
+    change_events::{ChangeEvent, PendingChangeEvents},
+    error::Result,
+    handler::{AuditLogBackendHandler, AuditLogEntry, AuditTarget},
+    model::{self, AuditLogColumn, GroupColumn, UserColumn},
+    sql_backend_handler::SqlBackendHandler,
+    sql_webhook_queue::WebhookEvent,
+    types::{AuditSource, DateTime, GroupId, UserId},
+};
+use async_trait::async_trait;
+use hmac::{Hmac, Mac, NewMac};
+use sea_orm::{
+    sea_query::Expr, ActiveValue, ColumnTrait, ConnectionTrait, EntityTrait, QueryFilter,
+    QueryOrder,
+};
+use sha2::Sha256;
+use std::collections::HashSet;
+use tracing::{debug, instrument};
+
+/// The names of the entries that are not about a single attribute.
+pub(crate) const CREATED: &str = "created";
+pub(crate) const DELETED: &str = "deleted";
+pub(crate) const PASSWORD: &str = "password";
+pub(crate) const MEMBERSHIP: &str = "membership";
+pub(crate) const SUBGROUP: &str = "subgroup";
+pub(crate) const UNLOCKED: &str = "unlocked";
+pub(crate) const WEBAUTHN_CREDENTIAL: &str = "webauthn_credential";
+pub(crate) const MFA_BACKUP_CODES: &str = "mfa_backup_codes";
+pub(crate) const TOTP_SECRET: &str = "totp_secret";
+
+/// A change to be written to the audit log. The values are hashed when written.
+#[derive(Debug, Clone, Default)]
+pub(crate) struct AuditChange {
+    target_user_id: Option<UserId>,
+    target_group_id: Option<GroupId>,
+    attribute_name: String,
+    old_value: Option<Vec<u8>>,
+    new_value: Option<Vec<u8>>,
+}
+
+impl AuditChange {
+    pub(crate) fn user(user_id: &UserId, attribute_name: &str) -> Self {
+        AuditChange {
+            target_user_id: Some(user_id.clone()),
+            attribute_name: attribute_name.to_owned(),
+            ..Default::default()
+        }
+    }
+
+    pub(crate) fn group(group_id: GroupId, attribute_name: &str) -> Self {
+        AuditChange {
+            target_group_id: Some(group_id),
+            attribute_name: attribute_name.to_owned(),
+            ..Default::default()
+        }
+    }
+
+    pub(crate) fn membership(user_id: &UserId, group_id: GroupId) -> Self {
+        AuditChange {
+            target_group_id: Some(group_id),
+            ..Self::user(user_id, MEMBERSHIP)
+        }
+    }
+
+    pub(crate) fn values(mut self, old_value: Option<Vec<u8>>, new_value: Option<Vec<u8>>) -> Self {
+        self.old_value = old_value;
+        self.new_value = new_value;
+        self
+    }
+
+    /// Whether the change sets a value that wasn't there before, e.g. adds a membership.
+    pub(crate) fn is_addition(&self) -> bool {
+        self.old_value.is_none()
+    }
+
+    /// The lifecycle changes are also sent to the webhooks.
+    fn get_webhook_event(&self) -> Option<WebhookEvent> {
+        let user_id = self.target_user_id.clone();
+        Some(
+            match (user_id, self.target_group_id, self.attribute_name.as_str()) {
+                (Some(user_id), None, CREATED) => WebhookEvent::UserCreated { user_id },
+                (Some(user_id), None, DELETED) => WebhookEvent::UserDeleted { user_id },
+                (Some(user_id), None, PASSWORD) => WebhookEvent::UserPasswordChanged { user_id },
+                (None, Some(group_id), CREATED) => WebhookEvent::GroupCreated { group_id },
+                (Some(user_id), Some(group_id), MEMBERSHIP) if self.new_value.is_some() => {
+                    WebhookEvent::MembershipAdded { user_id, group_id }
+                }
+                (Some(user_id), Some(group_id), MEMBERSHIP) => {
+                    WebhookEvent::MembershipRemoved { user_id, group_id }
+                }
+                _ => return None,
+            },
+        )
+    }
+}
+
+impl SqlBackendHandler {
+    /// HMAC keyed with the server key: low-entropy values like emails can't be recovered from
+    /// the hashes by trying candidates.
+    fn hash_audit_value(&self, value: &[u8]) -> String {
+        let mut mac = Hmac::<Sha256>::new_varkey(self.config.get_server_keys().private())
+            .expect("HMAC accepts keys of any size");
+        mac.update(value);
+        mac.finalize()
+            .into_bytes()
+            .iter()
+            .map(|b| format!("{:02x}", b))
+            .collect()
+    }
+
+    /// Writes the changes to the audit log, queues the matching webhook events, and updates the
+    /// modification date of the changed users and groups. Pass the transaction of the changes, so
+    /// that they are either all committed or all rolled back. The returned events are published to
+    /// the subscribers once the transaction is committed.
+    #[instrument(skip_all, level = "debug", fields(correlation_id = ?self.correlation_id))]
+    pub(crate) async fn write_audit_log(
+        &self,
+        connection: &impl ConnectionTrait,
+        changes: Vec<AuditChange>,
+    ) -> Result<PendingChangeEvents> {
+        // Every change to the users and groups goes through here.
+        self.last_write.mark();
+        if changes.is_empty() {
+            return Ok(PendingChangeEvents::default());
+        }
+        let events: Vec<WebhookEvent> = changes
+            .iter()
+            .filter_map(AuditChange::get_webhook_event)
+            .collect();
+        let modified_users = changes
+            .iter()
+            .filter_map(|change| change.target_user_id.clone())
+            .collect::<HashSet<_>>();
+        let modified_groups = changes
+            .iter()
+            .filter_map(|change| change.target_group_id)
+            .collect::<HashSet<_>>();
+        let now = chrono::Utc::now();
+        let (actor, source) = match &self.audit_actor {
+            Some(actor) => (Some(actor.user_id.clone()), actor.source),
+            None => (None, AuditSource::Internal),
+        };
+        model::AuditLog::insert_many(changes.into_iter().map(|change| {
+            model::audit_log::ActiveModel {
+                actor_user_id: ActiveValue::Set(actor.clone()),
+                target_user_id: ActiveValue::Set(change.target_user_id),
+                target_group_id: ActiveValue::Set(change.target_group_id),
+                attribute_name: ActiveValue::Set(change.attribute_name),
+                old_value_hash: ActiveValue::Set(
+                    change.old_value.map(|v| self.hash_audit_value(&v)),
+                ),
+                new_value_hash: ActiveValue::Set(
+                    change.new_value.map(|v| self.hash_audit_value(&v)),
+                ),
+                timestamp: ActiveValue::Set(now),
+                source: ActiveValue::Set(source),
+                correlation_id: ActiveValue::Set(self.correlation_id.clone()),
+                ..Default::default()
+            }
+        }))
+        .exec(connection)
+        .await?;
+        if !modified_users.is_empty() {
+            model::User::update_many()
+                .col_expr(UserColumn::ModifiedAt, Expr::value(now))
+                .filter(UserColumn::UserId.is_in(modified_users))
+                .exec(connection)
+                .await?;
+        }
+        if !modified_groups.is_empty() {
+            model::Group::update_many()
+                .col_expr(GroupColumn::ModifiedAt, Expr::value(now))
+                .filter(GroupColumn::GroupId.is_in(modified_groups))
+                .exec(connection)
+                .await?;
+        }
+        self.enqueue_webhook_events(connection, &events).await?;
+        Ok(self.change_events.pending(
+            events
+                .into_iter()
+                .map(|event| ChangeEvent {
+                    event,
+                    actor: actor.clone(),
+                    timestamp: now,
+                })
+                .collect(),
+        ))
+    }
+}
+
+#[async_trait]
+impl AuditLogBackendHandler for SqlBackendHandler {
+    #[instrument(skip_all, level = "debug", err)]
+    async fn get_audit_log(
+        &self,
+        target: AuditTarget,
+        from: DateTime,
+        to: DateTime,
+    ) -> Result<Vec<AuditLogEntry>> {
+        debug!(?target, ?from, ?to);
+        let query = match target {
+            AuditTarget::User(user_id) => {
+                model::AuditLog::find().filter(AuditLogColumn::TargetUserId.eq(user_id))
+            }
+            AuditTarget::Group(group_id) => {
+                model::AuditLog::find().filter(AuditLogColumn::TargetGroupId.eq(group_id))
+            }
+        };
+        Ok(query
+            .filter(AuditLogColumn::Timestamp.between(from, to))
+            .order_by_asc(AuditLogColumn::Timestamp)
+            .order_by_asc(AuditLogColumn::Id)
+            .all(&self.sql_pool)
+            .await?
+            .into_iter()
+            .map(AuditLogEntry::from)
+            .collect())
+    }
+
+    #[instrument(skip_all, level = "debug", err)]
+    async fn get_audit_log_by_correlation_id(
+        &self,
+        correlation_id: &str,
+    ) -> Result<Vec<AuditLogEntry>> {
+        debug!(?correlation_id);
+        Ok(model::AuditLog::find()
+            .filter(AuditLogColumn::CorrelationId.eq(correlation_id))
+            .order_by_asc(AuditLogColumn::Id)
+            .all(&self.sql_pool)
+            .await?
+            .into_iter()
+            .map(AuditLogEntry::from)
+            .collect())
+    }
+}
+
+impl From<model::audit_log::Model> for AuditLogEntry {
+    fn from(entry: model::audit_log::Model) -> Self {
+        AuditLogEntry {
+            actor: entry.actor_user_id,
+            target_user_id: entry.target_user_id,
+            target_group_id: entry.target_group_id,
+            attribute_name: entry.attribute_name,
+            old_value_hash: entry.old_value_hash,
+            new_value_hash: entry.new_value_hash,
+            timestamp: entry.timestamp,
+            source: entry.source,
+            correlation_id: entry.correlation_id,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::domain::{
+        handler::{
+            AuditActor, BackendHandler, GroupBackendHandler, UpdateUserRequest, UserBackendHandler,
+        },
+        sql_backend_handler::tests::*,
+    };
+
+    async fn get_user_log(handler: &SqlBackendHandler, user_id: &str) -> Vec<AuditLogEntry> {
+        let now = chrono::Utc::now();
+        handler
+            .get_audit_log(
+                AuditTarget::User(UserId::new(user_id)),
+                now - chrono::Duration::minutes(1),
+                now + chrono::Duration::minutes(1),
+            )
+            .await
+            .unwrap()
+    }
+
+    fn get_attribute_names(entries: &[AuditLogEntry]) -> Vec<&str> {
+        entries
+            .iter()
+            .map(|e| e.attribute_name.as_str())
+            .collect::<Vec<_>>()
+    }
+
+    #[tokio::test]
+    async fn test_audit_log_user_changes() {
+        let fixture = TestFixture::new().await;
+        let entries = get_user_log(&fixture.handler, "bob").await;
+        assert_eq!(
+            get_attribute_names(&entries),
+            vec![
+                CREATED,
+                "email",
+                "display_name",
+                "first_name",
+                "last_name",
+                MEMBERSHIP
+            ]
+        );
+        assert!(entries
+            .iter()
+            .all(|e| e.actor.is_none() && e.source == AuditSource::Internal));
+        let membership = entries.last().unwrap();
+        assert_eq!(membership.target_group_id, Some(fixture.groups[0]));
+        assert!(membership.old_value_hash.is_none());
+        assert!(membership.new_value_hash.is_some());
+
+        let mut handler = fixture.handler.clone();
+        handler.set_audit_actor(AuditActor {
+            user_id: UserId::new("admin"),
+            source: AuditSource::Graphql,
+        });
+        // Only the changed attributes are logged.
+        handler
+            .update_user(UpdateUserRequest {
+                user_id: UserId::new("bob"),
+                display_name: Some("Bobby".to_owned()),
+                first_name: Some("first bob".to_owned()),
+                ..Default::default()
+            })
+            .await
+            .unwrap();
+        handler
+            .update_user(UpdateUserRequest {
+                user_id: UserId::new("bob"),
+                display_name: Some("display bob".to_owned()),
+                ..Default::default()
+            })
+            .await
+            .unwrap();
+        let entries = get_user_log(&fixture.handler, "bob").await;
+        let changes = &entries[entries.len() - 2..];
+        assert_eq!(
+            get_attribute_names(changes),
+            vec!["display_name", "display_name"]
+        );
+        assert!(changes
+            .iter()
+            .all(|e| e.actor == Some(UserId::new("admin")) && e.source == AuditSource::Graphql));
+        // The hashes of the same values match.
+        assert_eq!(changes[0].old_value_hash, entries[2].new_value_hash);
+        assert_eq!(changes[1].new_value_hash, entries[2].new_value_hash);
+        assert_eq!(changes[0].new_value_hash, changes[1].old_value_hash);
+        assert_ne!(changes[0].old_value_hash, changes[0].new_value_hash);
+    }
+
+    #[tokio::test]
+    async fn test_audit_log_password_change() {
+        let fixture = TestFixture::new().await;
+        insert_user(&fixture.handler, "alice", "password").await;
+        let entries = get_user_log(&fixture.handler, "alice").await;
+        let password = entries.last().unwrap();
+        assert_eq!(password.attribute_name, PASSWORD);
+        assert_eq!(password.old_value_hash, None);
+        assert_eq!(password.new_value_hash, None);
+    }
+
+    #[tokio::test]
+    async fn test_audit_log_failed_change() {
+        let fixture = TestFixture::new().await;
+        let before = get_user_log(&fixture.handler, "bob").await;
+        fixture
+            .handler
+            .remove_user_from_group(&UserId::new("bob"), fixture.groups[1])
+            .await
+            .unwrap_err();
+        fixture
+            .handler
+            .delete_user(&UserId::new("nobody"))
+            .await
+            .unwrap_err();
+        assert_eq!(get_user_log(&fixture.handler, "bob").await, before);
+        assert!(get_user_log(&fixture.handler, "nobody").await.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_audit_log_time_range() {
+        let fixture = TestFixture::new().await;
+        let now = chrono::Utc::now();
+        assert!(fixture
+            .handler
+            .get_audit_log(
+                AuditTarget::Group(fixture.groups[0]),
+                now - chrono::Duration::days(2),
+                now - chrono::Duration::days(1),
+            )
+            .await
+            .unwrap()
+            .is_empty());
+        assert_eq!(
+            get_attribute_names(
+                &fixture
+                    .handler
+                    .get_audit_log(
+                        AuditTarget::Group(fixture.groups[0]),
+                        now - chrono::Duration::minutes(1),
+                        now + chrono::Duration::minutes(1),
+                    )
+                    .await
+                    .unwrap()
+            ),
+            vec![CREATED, "display_name", MEMBERSHIP, MEMBERSHIP]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_audit_log_by_correlation_id() {
+        let fixture = TestFixture::new().await;
+        let mut handler = fixture.handler.clone();
+        handler.set_correlation_id("request-1".to_owned());
+        handler
+            .add_user_to_group(&UserId::new("bob"), fixture.groups[2])
+            .await
+            .unwrap();
+        handler
+            .update_user(UpdateUserRequest {
+                user_id: UserId::new("john"),
+                display_name: Some("Johnny".to_owned()),
+                ..Default::default()
+            })
+            .await
+            .unwrap();
+        let entries = fixture
+            .handler
+            .get_audit_log_by_correlation_id("request-1")
+            .await
+            .unwrap();
+        assert_eq!(
+            get_attribute_names(&entries),
+            vec![MEMBERSHIP, "display_name"]
+        );
+        assert_eq!(entries[0].target_user_id, Some(UserId::new("bob")));
+        assert_eq!(entries[1].target_user_id, Some(UserId::new("john")));
+        // The changes made without an ID are not tagged.
+        assert!(get_user_log(&fixture.handler, "bob").await[0]
+            .correlation_id
+            .is_none());
+        assert!(fixture
+            .handler
+            .get_audit_log_by_correlation_id("request-2")
+            .await
+            .unwrap()
+            .is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_modified_at() {
+        let fixture = TestFixture::new().await;
+        let get_user = |user_id: &'static str| {
+            let handler = fixture.handler.clone();
+            async move {
+                handler
+                    .get_user_details(&UserId::new(user_id))
+                    .await
+                    .unwrap()
+            }
+        };
+        let get_group = |group_id: GroupId| {
+            let handler = fixture.handler.clone();
+            async move {
+                handler
+                    .list_groups(None)
+                    .await
+                    .unwrap()
+                    .into_iter()
+                    .find(|g| g.id == group_id)
+                    .unwrap()
+            }
+        };
+        let bob = get_user("bob").await;
+        let john = get_user("john").await;
+        let group = get_group(fixture.groups[2]).await;
+        fixture
+            .handler
+            .update_user(UpdateUserRequest {
+                user_id: UserId::new("john"),
+                display_name: Some("Johnny".to_owned()),
+                ..Default::default()
+            })
+            .await
+            .unwrap();
+        assert_ne!(get_user("john").await.modified_at, john.modified_at);
+        assert_eq!(get_user("bob").await.modified_at, bob.modified_at);
+        // Both sides of a membership change.
+        fixture
+            .handler
+            .add_user_to_group(&UserId::new("bob"), fixture.groups[2])
+            .await
+            .unwrap();
+        assert_ne!(get_user("bob").await.modified_at, bob.modified_at);
+        assert_ne!(
+            get_group(fixture.groups[2]).await.modified_at,
+            group.modified_at
+        );
+        // Nothing changed, nothing is updated.
+        let john = get_user("john").await;
+        fixture
+            .handler
+            .update_user(UpdateUserRequest {
+                user_id: UserId::new("john"),
+                display_name: Some("Johnny".to_owned()),
+                ..Default::default()
+            })
+            .await
+            .unwrap();
+        assert_eq!(get_user("john").await.modified_at, john.modified_at);
+    }
+}
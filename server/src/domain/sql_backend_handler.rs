@@ -1,21 +1,153 @@
-use super::{handler::BackendHandler, sql_tables::DbConnection};
+use super::{
+    auth_backend::AuthBackendRouter,
+    change_events::ChangeEventSender,
+    error::{DomainError, Result},
+    handler::{AuditActor, BackendHandler, ChangeSet, Pagination},
+    maintenance_handler::MaintenanceProgress,
+    sql_tables::DbConnection,
+    totp_secret::TotpSecretKeys,
+    types::DateTime,
+};
 use crate::infra::configuration::Configuration;
 use async_trait::async_trait;
+use std::{
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+/// When the handler last wrote to the DB. A clone starts with the history of the original, but
+/// they don't share their later writes: each session keeps track of its own.
+#[derive(Default)]
+pub(crate) struct LastWrite(Mutex<Option<Instant>>);
+
+impl LastWrite {
+    pub(crate) fn mark(&self) {
+        *self.0.lock().unwrap() = Some(Instant::now());
+    }
+
+    fn is_within(&self, duration: Duration) -> bool {
+        self.0
+            .lock()
+            .unwrap()
+            .map(|last_write| last_write.elapsed() < duration)
+            .unwrap_or(false)
+    }
+}
+
+impl Clone for LastWrite {
+    fn clone(&self) -> Self {
+        Self(Mutex::new(*self.0.lock().unwrap()))
+    }
+}
 
 #[derive(Clone)]
 pub struct SqlBackendHandler {
     pub(crate) config: Configuration,
     pub(crate) sql_pool: DbConnection,
+    /// Replica of `sql_pool` for the listings and searches. The writes always go to `sql_pool`.
+    pub(crate) read_sql_pool: Option<DbConnection>,
+    pub(crate) last_write: LastWrite,
+    pub(crate) audit_actor: Option<AuditActor>,
+    pub(crate) correlation_id: Option<String>,
+    /// Shared by all the clones, to publish the changes of every session.
+    pub(crate) change_events: ChangeEventSender,
+    /// Where the passwords of the binds are checked, if not locally.
+    pub(crate) auth_backends: AuthBackendRouter,
+    /// Shared by all the clones, like `change_events`.
+    pub(crate) maintenance: MaintenanceProgress,
+    /// Encrypt the new TOTP secrets, and decrypt the stored ones.
+    pub(crate) totp_keys: Option<Arc<TotpSecretKeys>>,
 }
 
 impl SqlBackendHandler {
     pub fn new(config: Configuration, sql_pool: DbConnection) -> Self {
-        SqlBackendHandler { config, sql_pool }
+        SqlBackendHandler {
+            config,
+            sql_pool,
+            read_sql_pool: None,
+            last_write: LastWrite::default(),
+            audit_actor: None,
+            correlation_id: None,
+            change_events: ChangeEventSender::default(),
+            auth_backends: AuthBackendRouter::default(),
+            maintenance: MaintenanceProgress::default(),
+            totp_keys: None,
+        }
+    }
+
+    pub fn change_events(&self) -> ChangeEventSender {
+        self.change_events.clone()
+    }
+
+    pub fn with_read_replica(mut self, read_sql_pool: DbConnection) -> Self {
+        self.read_sql_pool = Some(read_sql_pool);
+        self
+    }
+
+    pub fn with_auth_backends(mut self, auth_backends: AuthBackendRouter) -> Self {
+        self.auth_backends = auth_backends;
+        self
+    }
+
+    pub fn with_totp_keys(mut self, totp_keys: TotpSecretKeys) -> Self {
+        self.totp_keys = Some(Arc::new(totp_keys));
+        self
+    }
+
+    /// The replica, if any, unless this handler wrote recently: the replica may not have caught
+    /// up with the write yet.
+    pub(crate) fn read_pool(&self) -> &DbConnection {
+        match &self.read_sql_pool {
+            Some(read_sql_pool)
+                if !self.last_write.is_within(Duration::from_secs(
+                    self.config.database_replica_max_lag_seconds,
+                )) =>
+            {
+                read_sql_pool
+            }
+            _ => &self.sql_pool,
+        }
+    }
+}
+
+/// The cursors are the ID of the last item of the page, encoded to keep them opaque to the
+/// clients.
+pub(crate) fn encode_cursor(last_id: &str) -> String {
+    base64::encode(last_id)
+}
+
+/// Returns the ID after which the page starts, if any.
+pub(crate) fn get_page_start(page: &Pagination) -> Result<Option<String>> {
+    if page.page_size == 0 {
+        return Err(DomainError::ValidationError(
+            "The page size must be positive".to_owned(),
+        ));
     }
+    page.after
+        .as_ref()
+        .map(|cursor| {
+            base64::decode(cursor)
+                .ok()
+                .and_then(|id| String::from_utf8(id).ok())
+                .ok_or_else(|| DomainError::ValidationError(format!("Invalid cursor: {}", cursor)))
+        })
+        .transpose()
 }
 
 #[async_trait]
-impl BackendHandler for SqlBackendHandler {}
+impl BackendHandler for SqlBackendHandler {
+    fn set_audit_actor(&mut self, actor: AuditActor) {
+        self.audit_actor = Some(actor);
+    }
+
+    fn set_correlation_id(&mut self, correlation_id: String) {
+        self.correlation_id = Some(correlation_id);
+    }
+
+    async fn changed_since(&self, since: Option<DateTime>) -> Result<ChangeSet> {
+        self.get_changes_since(since).await
+    }
+}
 
 #[cfg(test)]
 pub mod tests {
@@ -28,7 +160,7 @@ pub mod tests {
             sql_tables::init_table,
             types::{GroupId, UserId},
         },
-        infra::configuration::ConfigurationBuilder,
+        infra::configuration::{ConfigurationBuilder, UuidBackfill},
     };
     use lldap_auth::{opaque, registration};
     use sea_orm::Database;
@@ -49,7 +181,9 @@ pub mod tests {
 
     pub async fn get_initialized_db() -> DbConnection {
         let sql_pool = get_in_memory_db().await;
-        init_table(&sql_pool).await.unwrap();
+        init_table(&sql_pool, UuidBackfill::default())
+            .await
+            .unwrap();
         sql_pool
     }
 
@@ -85,7 +219,7 @@ pub mod tests {
         handler
             .create_user(CreateUserRequest {
                 user_id: UserId::new(name),
-                email: "bob@bob.bob".to_string(),
+                email: format!("{}@bob.bob", name),
                 display_name: Some("display ".to_string() + name),
                 first_name: Some("first ".to_string() + name),
                 last_name: Some("last ".to_string() + name),
@@ -126,8 +260,11 @@ pub mod tests {
 
     impl TestFixture {
         pub async fn new() -> Self {
+            Self::with_config(get_default_config()).await
+        }
+
+        pub async fn with_config(config: Configuration) -> Self {
             let sql_pool = get_initialized_db().await;
-            let config = get_default_config();
             let handler = SqlBackendHandler::new(config, sql_pool);
             insert_user_no_password(&handler, "bob").await;
             insert_user_no_password(&handler, "patrick").await;
@@ -145,6 +282,25 @@ pub mod tests {
         }
     }
 
+    #[tokio::test]
+    async fn test_read_replica() {
+        let replica = get_initialized_db().await;
+        let handler = SqlBackendHandler::new(get_default_config(), get_initialized_db().await)
+            .with_read_replica(replica.clone());
+        insert_user_no_password(&handler, "bob").await;
+        // Right after the write, the session reads from the primary.
+        assert_eq!(get_user_names(&handler, None).await, vec!["bob"]);
+        // Another session reads from the replica, which doesn't replicate anything here.
+        let other_session = SqlBackendHandler::new(get_default_config(), handler.sql_pool.clone())
+            .with_read_replica(replica);
+        assert!(get_user_names(&other_session, None).await.is_empty());
+        // The other reads still go to the primary.
+        other_session
+            .get_user_details(&UserId::new("bob"))
+            .await
+            .unwrap();
+    }
+
     #[tokio::test]
     async fn test_sql_injection() {
         let sql_pool = get_initialized_db().await;
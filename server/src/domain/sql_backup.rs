@@ -0,0 +1,459 @@
+//! A snapshot of the DB that doesn't depend on the engine: the rows of the tables, with the schema
+//! version that they follow. The sessions, the pending tokens and the webhook queue are not
+//! included: they are short-lived, and would be stale by the time of a restore.
+use super::{
+    model,
+    sql_migrations::{
+        get_schema_version, has_indexed_attributes, update_attribute_values_index, AttributeTable,
+    },
+    sql_tables::{DbConnection, LAST_SCHEMA_VERSION},
+};
+use anyhow::{bail, Context, Result};
+use sea_orm::{
+    ActiveModelTrait, ConnectionTrait, DatabaseTransaction, DbBackend, DbErr, EntityTrait,
+    IntoActiveModel, PaginatorTrait, Statement, TransactionTrait,
+};
+use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value};
+use tracing::{info, instrument};
+
+/// Changes when the layout of `Backup` does, independently of the schema version.
+pub const BACKUP_FORMAT_VERSION: u32 = 1;
+
+/// The schema version of the first backups.
+const FIRST_BACKUP_SCHEMA_VERSION: u8 = 26;
+
+/// Upgrades the tables of a backup to the next schema version, the way the migration of the same
+/// version changes the DB.
+type BackupUpgrade = fn(&mut Map<String, Value>) -> Result<()>;
+
+/// For the migrations that don't change the backed-up rows.
+fn unchanged(_: &mut Map<String, Value>) -> Result<()> {
+    Ok(())
+}
+
+/// The upgrade from `FIRST_BACKUP_SCHEMA_VERSION + i` is at the index `i`: every migration adds
+/// its step here, `unchanged` if it doesn't change the backed-up tables.
+const BACKUP_UPGRADES: &[BackupUpgrade] = &[
+    unchanged, // v27: the SCRAM verifiers are optional.
+    unchanged, // v28: only an index.
+    unchanged, // v29: the TOTP parameters are optional.
+    unchanged, // v30: the last TOTP counters are optional.
+    unchanged, // v31: the refresh tokens and the sessions aren't backed up.
+    unchanged, // v32: the casings of the user IDs are optional.
+];
+
+const INSERT_BATCH_SIZE: usize = 500;
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Backup {
+    pub format_version: u32,
+    pub schema_version: u8,
+    /// The version of LLDAP that made the backup, the one that can restore it.
+    pub lldap_version: String,
+    pub created_at: chrono::DateTime<chrono::Utc>,
+    pub users: Vec<model::users::Model>,
+    pub groups: Vec<model::groups::Model>,
+    pub user_attribute_schema: Vec<model::user_attribute_schema::Model>,
+    pub group_attribute_schema: Vec<model::group_attribute_schema::Model>,
+    pub user_attributes: Vec<model::user_attributes::Model>,
+    pub group_attributes: Vec<model::group_attributes::Model>,
+    pub memberships: Vec<model::memberships::Model>,
+    pub group_memberships: Vec<model::group_memberships::Model>,
+    pub group_rules: Vec<model::group_rules::Model>,
+    pub group_rule_conditions: Vec<model::group_rule_conditions::Model>,
+    pub sudo_roles: Vec<model::sudo_roles::Model>,
+    pub sudo_role_values: Vec<model::sudo_role_values::Model>,
+    pub oidc_clients: Vec<model::oidc_clients::Model>,
+    pub webauthn_credentials: Vec<model::webauthn_credentials::Model>,
+    pub mfa_backup_codes: Vec<model::mfa_backup_codes::Model>,
+    pub sequences: Vec<model::sequences::Model>,
+    pub deletion_tombstones: Vec<model::deletion_tombstones::Model>,
+    pub audit_log: Vec<model::audit_log::Model>,
+}
+
+/// The auto-incremented columns, whose Postgres sequences must be moved past the restored IDs.
+const SERIAL_COLUMNS: &[(&str, &str)] = &[
+    ("groups", "group_id"),
+    ("group_rules", "rule_id"),
+    ("sudo_roles", "role_id"),
+    ("webauthn_credentials", "id"),
+    ("mfa_backup_codes", "id"),
+    ("deletion_tombstones", "id"),
+    ("audit_log", "id"),
+];
+
+/// Reads all the tables in a single transaction, so that the concurrent writes don't make the
+/// backup inconsistent.
+#[instrument(skip_all, level = "debug", err)]
+pub async fn create_backup(pool: &DbConnection) -> Result<Backup> {
+    let transaction = pool.begin().await?;
+    // The default isolation of Postgres takes a new snapshot for each statement. MySQL already
+    // keeps the first one, and SQLite locks the DB for the reads.
+    if pool.get_database_backend() == DbBackend::Postgres {
+        transaction
+            .execute(Statement::from_string(
+                DbBackend::Postgres,
+                "SET TRANSACTION ISOLATION LEVEL REPEATABLE READ, READ ONLY".to_owned(),
+            ))
+            .await?;
+    }
+    let schema_version = get_schema_version(&transaction)
+        .await
+        .context("The DB is not initialized")?;
+    let backup = Backup {
+        format_version: BACKUP_FORMAT_VERSION,
+        schema_version: schema_version.0,
+        lldap_version: env!("CARGO_PKG_VERSION").to_owned(),
+        created_at: chrono::Utc::now(),
+        users: model::User::find().all(&transaction).await?,
+        groups: model::Group::find().all(&transaction).await?,
+        user_attribute_schema: model::UserAttributeSchema::find().all(&transaction).await?,
+        group_attribute_schema: model::GroupAttributeSchema::find()
+            .all(&transaction)
+            .await?,
+        user_attributes: model::UserAttributes::find().all(&transaction).await?,
+        group_attributes: model::GroupAttributes::find().all(&transaction).await?,
+        memberships: model::Membership::find().all(&transaction).await?,
+        group_memberships: model::GroupMembership::find().all(&transaction).await?,
+        group_rules: model::GroupRules::find().all(&transaction).await?,
+        group_rule_conditions: model::GroupRuleConditions::find().all(&transaction).await?,
+        sudo_roles: model::SudoRoles::find().all(&transaction).await?,
+        sudo_role_values: model::SudoRoleValues::find().all(&transaction).await?,
+        oidc_clients: model::OidcClients::find().all(&transaction).await?,
+        webauthn_credentials: model::WebauthnCredentials::find().all(&transaction).await?,
+        mfa_backup_codes: model::MfaBackupCodes::find().all(&transaction).await?,
+        sequences: model::Sequences::find().all(&transaction).await?,
+        deletion_tombstones: model::DeletionTombstones::find().all(&transaction).await?,
+        audit_log: model::AuditLog::find().all(&transaction).await?,
+    };
+    transaction.commit().await?;
+    Ok(backup)
+}
+
+fn upgrade_backup(
+    content: &mut Map<String, Value>,
+    from: u8,
+    to: u8,
+    first_version: u8,
+    upgrades: &[BackupUpgrade],
+) -> Result<()> {
+    for version in from..to {
+        let upgrade = version
+            .checked_sub(first_version)
+            .and_then(|index| upgrades.get(usize::from(index)))
+            .with_context(|| {
+                format!(
+                    "No upgrade of the backups from the schema version {}",
+                    version
+                )
+            })?;
+        upgrade(content).with_context(|| {
+            format!(
+                "while upgrading the backup from the schema version {}",
+                version
+            )
+        })?;
+        content.insert("schema_version".to_owned(), (version + 1).into());
+        info!("Upgraded the backup to the schema version {}", version + 1);
+    }
+    Ok(())
+}
+
+/// Reads a backup file, upgraded to the schema version of this binary. The backups of a newer
+/// version are refused: their unknown columns would be lost.
+pub fn parse_backup(content: Value) -> Result<Backup> {
+    let mut content = match content {
+        Value::Object(content) => content,
+        _ => bail!("The backup is not a JSON object"),
+    };
+    let get_version = |name: &str| {
+        content
+            .get(name)
+            .and_then(Value::as_u64)
+            .with_context(|| format!("The backup has no {}", name))
+    };
+    let format_version = get_version("format_version")?;
+    if format_version != u64::from(BACKUP_FORMAT_VERSION) {
+        bail!(
+            "Unsupported backup format version {}, expected {}",
+            format_version,
+            BACKUP_FORMAT_VERSION
+        );
+    }
+    let schema_version = get_version("schema_version")?;
+    if schema_version > u64::from(LAST_SCHEMA_VERSION.0) {
+        bail!(
+            "The backup has the schema version {}, newer than the {} of this binary: restore it with LLDAP {}",
+            schema_version,
+            LAST_SCHEMA_VERSION.0,
+            content
+                .get("lldap_version")
+                .and_then(Value::as_str)
+                .unwrap_or("unknown")
+        );
+    }
+    upgrade_backup(
+        &mut content,
+        schema_version as u8,
+        LAST_SCHEMA_VERSION.0,
+        FIRST_BACKUP_SCHEMA_VERSION,
+        BACKUP_UPGRADES,
+    )?;
+    Ok(serde_json::from_value(Value::Object(content))?)
+}
+
+async fn insert_rows<E, A>(transaction: &DatabaseTransaction, rows: Vec<E::Model>) -> Result<()>
+where
+    E: EntityTrait,
+    E::Model: IntoActiveModel<A>,
+    A: ActiveModelTrait<Entity = E>,
+{
+    let table = E::default().table_name().to_owned();
+    let mut rows = rows.into_iter().peekable();
+    while rows.peek().is_some() {
+        E::insert_many(
+            rows.by_ref()
+                .take(INSERT_BATCH_SIZE)
+                .map(IntoActiveModel::into_active_model),
+        )
+        .exec(transaction)
+        .await
+        .with_context(|| format!("while restoring the table {}", table))?;
+    }
+    Ok(())
+}
+
+async fn delete_rows<E: EntityTrait>(transaction: &DatabaseTransaction) -> Result<(), DbErr> {
+    E::delete_many().exec(transaction).await.map(|_| ())
+}
+
+/// Replaces the content of the DB with the backup, in a single transaction. The DB must already
+/// be at the schema version of the backup, and have no users unless `force` is set.
+#[instrument(skip_all, level = "debug", err)]
+pub async fn restore_backup(pool: &DbConnection, backup: Backup, force: bool) -> Result<()> {
+    if backup.format_version != BACKUP_FORMAT_VERSION {
+        bail!(
+            "Unsupported backup format version {}, expected {}",
+            backup.format_version,
+            BACKUP_FORMAT_VERSION
+        );
+    }
+    let schema_version = get_schema_version(pool)
+        .await
+        .context("The DB is not initialized")?;
+    if backup.schema_version != schema_version.0 {
+        bail!(
+            "The backup has the schema version {} and the DB {}: restore it with LLDAP {}, then upgrade",
+            backup.schema_version,
+            schema_version.0,
+            backup.lldap_version
+        );
+    }
+    let transaction = pool.begin().await?;
+    let existing_users = model::User::find().count(&transaction).await?;
+    if existing_users > 0 && !force {
+        bail!(
+            "The DB already has {} user(s): use --force to replace its content",
+            existing_users
+        );
+    }
+    // The index of the attribute values then follows the indexed attributes of the backup.
+    let mut were_indexed = Vec::new();
+    for table in AttributeTable::ALL {
+        were_indexed.push((table, has_indexed_attributes(&transaction, table).await?));
+    }
+    // In the reverse order of the insertions, for the foreign keys.
+    delete_rows::<model::AuditLog>(&transaction).await?;
+    delete_rows::<model::DeletionTombstones>(&transaction).await?;
+    delete_rows::<model::Sequences>(&transaction).await?;
+    delete_rows::<model::MfaBackupCodes>(&transaction).await?;
+    delete_rows::<model::WebauthnCredentials>(&transaction).await?;
+    delete_rows::<model::OidcClients>(&transaction).await?;
+    delete_rows::<model::SudoRoleValues>(&transaction).await?;
+    delete_rows::<model::SudoRoles>(&transaction).await?;
+    delete_rows::<model::GroupRuleConditions>(&transaction).await?;
+    delete_rows::<model::GroupRules>(&transaction).await?;
+    delete_rows::<model::GroupMembership>(&transaction).await?;
+    delete_rows::<model::Membership>(&transaction).await?;
+    delete_rows::<model::GroupAttributes>(&transaction).await?;
+    delete_rows::<model::UserAttributes>(&transaction).await?;
+    delete_rows::<model::GroupAttributeSchema>(&transaction).await?;
+    delete_rows::<model::UserAttributeSchema>(&transaction).await?;
+    delete_rows::<model::Group>(&transaction).await?;
+    delete_rows::<model::User>(&transaction).await?;
+
+    insert_rows::<model::User, _>(&transaction, backup.users).await?;
+    insert_rows::<model::Group, _>(&transaction, backup.groups).await?;
+    insert_rows::<model::UserAttributeSchema, _>(&transaction, backup.user_attribute_schema)
+        .await?;
+    insert_rows::<model::GroupAttributeSchema, _>(&transaction, backup.group_attribute_schema)
+        .await?;
+    insert_rows::<model::UserAttributes, _>(&transaction, backup.user_attributes).await?;
+    insert_rows::<model::GroupAttributes, _>(&transaction, backup.group_attributes).await?;
+    insert_rows::<model::Membership, _>(&transaction, backup.memberships).await?;
+    insert_rows::<model::GroupMembership, _>(&transaction, backup.group_memberships).await?;
+    insert_rows::<model::GroupRules, _>(&transaction, backup.group_rules).await?;
+    insert_rows::<model::GroupRuleConditions, _>(&transaction, backup.group_rule_conditions)
+        .await?;
+    insert_rows::<model::SudoRoles, _>(&transaction, backup.sudo_roles).await?;
+    insert_rows::<model::SudoRoleValues, _>(&transaction, backup.sudo_role_values).await?;
+    insert_rows::<model::OidcClients, _>(&transaction, backup.oidc_clients).await?;
+    insert_rows::<model::WebauthnCredentials, _>(&transaction, backup.webauthn_credentials).await?;
+    insert_rows::<model::MfaBackupCodes, _>(&transaction, backup.mfa_backup_codes).await?;
+    insert_rows::<model::Sequences, _>(&transaction, backup.sequences).await?;
+    insert_rows::<model::DeletionTombstones, _>(&transaction, backup.deletion_tombstones).await?;
+    insert_rows::<model::AuditLog, _>(&transaction, backup.audit_log).await?;
+    for (table, was_indexed) in were_indexed {
+        update_attribute_values_index(&transaction, table, was_indexed).await?;
+    }
+
+    if pool.get_database_backend() == DbBackend::Postgres {
+        for (table, column) in SERIAL_COLUMNS {
+            transaction
+                .execute(Statement::from_string(
+                    DbBackend::Postgres,
+                    format!(
+                        r#"SELECT setval(pg_get_serial_sequence('"{table}"', '{column}'), COALESCE((SELECT MAX("{column}") FROM "{table}"), 0) + 1, false)"#,
+                        table = table,
+                        column = column
+                    ),
+                ))
+                .await?;
+        }
+    }
+    transaction.commit().await?;
+    if get_schema_version(pool).await != Some(schema_version) {
+        bail!("The schema version of the DB changed during the restore");
+    }
+    info!("Restored the backup made by LLDAP {}", backup.lldap_version);
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::domain::{
+        handler::{GroupBackendHandler, UserBackendHandler},
+        sql_backend_handler::{tests::*, SqlBackendHandler},
+        types::UserId,
+    };
+
+    #[tokio::test]
+    async fn test_backup_round_trip() {
+        let fixture = TestFixture::new().await;
+        let backup = create_backup(&fixture.handler.sql_pool).await.unwrap();
+        assert_eq!(backup.schema_version, LAST_SCHEMA_VERSION.0);
+        assert_eq!(backup.users.len(), 4);
+        // Through the file format.
+        let backup = parse_backup(serde_json::to_value(&backup).unwrap()).unwrap();
+
+        let restored = get_initialized_db().await;
+        restore_backup(&restored, backup.clone(), false)
+            .await
+            .unwrap();
+        let mut restored_backup = create_backup(&restored).await.unwrap();
+        restored_backup.created_at = backup.created_at;
+        assert_eq!(restored_backup, backup);
+
+        // The IDs keep increasing after the restored ones.
+        let handler = SqlBackendHandler::new(get_default_config(), restored.clone());
+        let group_id = handler.create_group("new_group").await.unwrap();
+        assert!(backup.groups.iter().all(|group| group.group_id < group_id));
+        assert!(handler.get_user_details(&UserId::new("bob")).await.is_ok());
+
+        // Not over the existing users, unless forced.
+        restore_backup(&restored, backup.clone(), false)
+            .await
+            .unwrap_err();
+        restore_backup(&restored, backup, true).await.unwrap();
+        assert!(model::Group::find_by_id(group_id)
+            .one(&restored)
+            .await
+            .unwrap()
+            .is_none());
+    }
+
+    #[tokio::test]
+    async fn test_backup_wipe_restore() {
+        let fixture = TestFixture::new().await;
+        let handler = &fixture.handler;
+        let list_directory = move || async move {
+            (
+                handler.list_users(None, true).await.unwrap(),
+                handler.list_groups(None).await.unwrap(),
+            )
+        };
+        let directory = list_directory().await;
+        let backup = create_backup(&handler.sql_pool).await.unwrap();
+
+        model::User::delete_many()
+            .exec(&handler.sql_pool)
+            .await
+            .unwrap();
+        model::Group::delete_many()
+            .exec(&handler.sql_pool)
+            .await
+            .unwrap();
+        assert!(list_directory().await.0.is_empty());
+
+        restore_backup(&handler.sql_pool, backup, false)
+            .await
+            .unwrap();
+        assert_eq!(list_directory().await, directory);
+    }
+
+    #[tokio::test]
+    async fn test_parse_backup_versions() {
+        let backup = |schema_version: u8| {
+            serde_json::json!({
+                "format_version": BACKUP_FORMAT_VERSION,
+                "schema_version": schema_version,
+                "lldap_version": "1.2.3",
+            })
+        };
+        let error = parse_backup(backup(LAST_SCHEMA_VERSION.0 + 1)).unwrap_err();
+        assert!(error.to_string().contains("LLDAP 1.2.3"), "{}", error);
+        parse_backup(backup(FIRST_BACKUP_SCHEMA_VERSION - 1)).unwrap_err();
+        // A table of a newer binary isn't ignored.
+        let sql_pool = get_initialized_db().await;
+        let mut content = match serde_json::to_value(create_backup(&sql_pool).await.unwrap()) {
+            Ok(Value::Object(content)) => content,
+            content => panic!("Unexpected backup: {:?}", content),
+        };
+        parse_backup(Value::Object(content.clone())).unwrap();
+        content.insert("unknown_table".to_owned(), Value::Array(vec![]));
+        parse_backup(Value::Object(content)).unwrap_err();
+    }
+
+    #[test]
+    fn test_upgrade_backup() {
+        fn rename_users(content: &mut Map<String, Value>) -> Result<()> {
+            let users = content.remove("people").context("no people")?;
+            content.insert("users".to_owned(), users);
+            Ok(())
+        }
+        fn add_groups(content: &mut Map<String, Value>) -> Result<()> {
+            content.insert("groups".to_owned(), Value::Array(vec![]));
+            Ok(())
+        }
+        let upgrades: &[BackupUpgrade] = &[rename_users, add_groups];
+        let mut content = match serde_json::json!({"schema_version": 3, "people": [1]}) {
+            Value::Object(content) => content,
+            _ => unreachable!(),
+        };
+        upgrade_backup(&mut content, 4, 5, 3, upgrades).unwrap();
+        assert_eq!(
+            Value::Object(content.clone()),
+            serde_json::json!({"schema_version": 5, "people": [1], "groups": []})
+        );
+        upgrade_backup(&mut content, 3, 5, 3, upgrades).unwrap();
+        assert_eq!(
+            Value::Object(content.clone()),
+            serde_json::json!({"schema_version": 5, "users": [1], "groups": []})
+        );
+        // No upgrade registered from 5.
+        upgrade_backup(&mut content, 5, 6, 3, upgrades).unwrap_err();
+    }
+}
@@ -0,0 +1,209 @@
+use super::{
+    error::Result,
+    handler::{AuditTarget, ChangeSet, Tombstone},
+    model::{self, DeletionTombstonesColumn, GroupColumn, UserColumn},
+    sql_backend_handler::SqlBackendHandler,
+    sql_tables::DbConnection,
+    types::{DateTime, Uuid},
+};
+use sea_orm::{ActiveValue, ColumnTrait, ConnectionTrait, EntityTrait, QueryFilter, QueryOrder};
+use tracing::{debug, instrument};
+
+/// The changes are timestamped before their transaction is committed: the next sync starts this
+/// much earlier, so that the changes committed during a sync are not missed.
+const MAX_TRANSACTION_DURATION_SECONDS: i64 = 5;
+
+impl From<model::deletion_tombstones::Model> for Tombstone {
+    fn from(tombstone: model::deletion_tombstones::Model) -> Self {
+        Self {
+            user_id: tombstone.user_id,
+            group_id: tombstone.group_id,
+            uuid: tombstone.uuid,
+            deleted_at: tombstone.deleted_at,
+        }
+    }
+}
+
+/// Records the deletion of a user or a group. Pass the transaction of the deletion.
+pub(crate) async fn record_tombstone(
+    connection: &impl ConnectionTrait,
+    target: AuditTarget,
+    uuid: Uuid,
+) -> Result<()> {
+    let (user_id, group_id) = match target {
+        AuditTarget::User(user_id) => (Some(user_id), None),
+        AuditTarget::Group(group_id) => (None, Some(group_id)),
+    };
+    model::DeletionTombstones::insert(model::deletion_tombstones::ActiveModel {
+        user_id: ActiveValue::Set(user_id),
+        group_id: ActiveValue::Set(group_id),
+        uuid: ActiveValue::Set(uuid),
+        deleted_at: ActiveValue::Set(chrono::Utc::now()),
+        ..Default::default()
+    })
+    .exec(connection)
+    .await?;
+    Ok(())
+}
+
+/// Forgets the deletions made before `deleted_before`. Returns the number of purged tombstones.
+#[instrument(skip_all, level = "debug", ret, err)]
+pub async fn purge_tombstones(pool: &DbConnection, deleted_before: DateTime) -> Result<u64> {
+    debug!(?deleted_before);
+    Ok(model::DeletionTombstones::delete_many()
+        .filter(DeletionTombstonesColumn::DeletedAt.lt(deleted_before))
+        .exec(pool)
+        .await?
+        .rows_affected)
+}
+
+impl SqlBackendHandler {
+    #[instrument(skip_all, level = "debug", err)]
+    pub(crate) async fn get_changes_since(&self, since: Option<DateTime>) -> Result<ChangeSet> {
+        debug!(?since);
+        let now = chrono::Utc::now();
+        // A change missing from a lagging replica would never be synced.
+        let primary = SqlBackendHandler {
+            read_sql_pool: None,
+            ..self.clone()
+        };
+        let mut users = model::User::find()
+            .filter(UserColumn::DeletedAt.is_null())
+            .order_by_asc(UserColumn::UserId);
+        let mut groups = model::Group::find().order_by_asc(GroupColumn::GroupId);
+        let mut deletions = Vec::new();
+        if let Some(since) = since {
+            users = users.filter(UserColumn::ModifiedAt.gte(since));
+            groups = groups.filter(GroupColumn::ModifiedAt.gte(since));
+            deletions = model::DeletionTombstones::find()
+                .filter(DeletionTombstonesColumn::DeletedAt.gte(since))
+                .order_by_asc(DeletionTombstonesColumn::Id)
+                .all(&self.sql_pool)
+                .await?
+                .into_iter()
+                .map(Into::into)
+                .collect();
+        }
+        let retention =
+            chrono::Duration::days(self.config.deletion_tombstones_retention_days.into());
+        Ok(ChangeSet {
+            users: users
+                .all(&self.sql_pool)
+                .await?
+                .into_iter()
+                .map(Into::into)
+                .collect(),
+            groups: primary.fetch_groups(groups).await?,
+            deletions,
+            until: now - chrono::Duration::seconds(MAX_TRANSACTION_DURATION_SECONDS),
+            resync_required: since.map(|since| since < now - retention).unwrap_or(false),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::domain::{
+        handler::{BackendHandler, GroupBackendHandler, UpdateUserRequest, UserBackendHandler},
+        sql_backend_handler::tests::*,
+        types::UserId,
+    };
+
+    #[tokio::test]
+    async fn test_changed_since() {
+        let fixture = TestFixture::new().await;
+        let everything = fixture.handler.changed_since(None).await.unwrap();
+        assert_eq!(everything.users.len(), 4);
+        assert_eq!(everything.groups.len(), 3);
+        assert!(everything.deletions.is_empty());
+        assert!(!everything.resync_required);
+
+        let since = chrono::Utc::now();
+        fixture
+            .handler
+            .update_user(UpdateUserRequest {
+                user_id: UserId::new("john"),
+                display_name: Some("Johnny".to_owned()),
+                ..Default::default()
+            })
+            .await
+            .unwrap();
+        fixture
+            .handler
+            .delete_user(&UserId::new("nogroup"))
+            .await
+            .unwrap();
+        fixture
+            .handler
+            .delete_group(fixture.groups[2])
+            .await
+            .unwrap();
+        let changes = fixture.handler.changed_since(Some(since)).await.unwrap();
+        assert_eq!(
+            changes
+                .users
+                .iter()
+                .map(|u| u.user_id.as_str())
+                .collect::<Vec<_>>(),
+            vec!["john"]
+        );
+        assert!(changes.groups.is_empty());
+        assert_eq!(
+            changes
+                .deletions
+                .iter()
+                .map(|t| (t.user_id.clone(), t.group_id))
+                .collect::<Vec<_>>(),
+            vec![
+                (Some(UserId::new("nogroup")), None),
+                (None, Some(fixture.groups[2]))
+            ]
+        );
+        let deleted_uuid = everything
+            .users
+            .iter()
+            .find(|u| u.user_id.as_str() == "nogroup")
+            .unwrap()
+            .uuid
+            .clone();
+        assert_eq!(changes.deletions[0].uuid, deleted_uuid);
+        assert!(changes.until < chrono::Utc::now());
+        assert!(!changes.resync_required);
+    }
+
+    #[tokio::test]
+    async fn test_changed_since_resync() {
+        let fixture = TestFixture::new().await;
+        let since = chrono::Utc::now() - chrono::Duration::days(31);
+        let changes = fixture.handler.changed_since(Some(since)).await.unwrap();
+        assert!(changes.resync_required);
+        // Still the changes since then, everything in this case.
+        assert_eq!(changes.users.len(), 4);
+    }
+
+    #[tokio::test]
+    async fn test_purge_tombstones() {
+        let fixture = TestFixture::new().await;
+        let since = chrono::Utc::now();
+        fixture
+            .handler
+            .delete_user(&UserId::new("nogroup"))
+            .await
+            .unwrap();
+        assert_eq!(
+            purge_tombstones(&fixture.handler.sql_pool, since)
+                .await
+                .unwrap(),
+            0
+        );
+        assert_eq!(
+            purge_tombstones(&fixture.handler.sql_pool, chrono::Utc::now())
+                .await
+                .unwrap(),
+            1
+        );
+        let changes = fixture.handler.changed_since(Some(since)).await.unwrap();
+        assert!(changes.deletions.is_empty());
+    }
+}
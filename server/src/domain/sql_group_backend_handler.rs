@@ -1,18 +1,52 @@
 use crate::domain::{
+    clock,
     error::{DomainError, Result},
-    handler::{GroupBackendHandler, GroupRequestFilter, UpdateGroupRequest},
-    model::{self, GroupColumn, MembershipColumn},
-    sql_backend_handler::SqlBackendHandler,
-    types::{Group, GroupDetails, GroupId, Uuid},
+    handler::{
+        AuditTarget, GroupBackendHandler, GroupRequestFilter, Page, Pagination, UpdateGroupRequest,
+    },
+    model::{self, GroupColumn, MembershipColumn, SequencesColumn, UserColumn},
+    sql_audit_log_handler::{self as audit, AuditChange},
+    sql_backend_handler::{encode_cursor, get_page_start, SqlBackendHandler},
+    sql_change_sync::record_tombstone,
+    sql_migrations::GROUP_ID_SEQUENCE,
+    sql_nested_group_backend_handler::GroupNesting,
+    sql_posix_numbers::{allocate_posix_number, PosixNumber},
+    types::{DateTime, Group, GroupDetails, GroupId, UserId, Uuid},
 };
 use async_trait::async_trait;
 use sea_orm::{
-    ActiveModelTrait, ActiveValue, ColumnTrait, EntityTrait, QueryFilter, QueryOrder, QuerySelect,
-    QueryTrait,
+    ActiveModelTrait, ActiveValue, ColumnTrait, DatabaseTransaction, EntityTrait, FromQueryResult,
+    IdenStatic, PaginatorTrait, QueryFilter, QueryOrder, QuerySelect, QueryTrait, Select,
+    TransactionTrait,
 };
-use sea_query::{Cond, IntoCondition, SimpleExpr};
+use sea_query::{Cond, Expr, IntoCondition, SimpleExpr};
+use std::collections::{HashMap, HashSet};
 use tracing::{debug, instrument};
 
+/// Allocates the next group ID from the sequence, rather than relying on the backend-specific
+/// auto-increment. The update locks the sequence row until the end of the transaction, so
+/// concurrent allocations are serialized.
+async fn next_group_id(transaction: &DatabaseTransaction) -> Result<GroupId> {
+    let res = model::Sequences::update_many()
+        .col_expr(
+            SequencesColumn::NextValue,
+            Expr::col(SequencesColumn::NextValue).add(1),
+        )
+        .filter(SequencesColumn::Name.eq(GROUP_ID_SEQUENCE))
+        .exec(transaction)
+        .await?;
+    if res.rows_affected != 1 {
+        return Err(DomainError::InternalError(
+            "The group ID sequence is missing".to_owned(),
+        ));
+    }
+    let sequence = model::Sequences::find_by_id(GROUP_ID_SEQUENCE.to_owned())
+        .one(transaction)
+        .await?
+        .ok_or_else(|| DomainError::InternalError("The group ID sequence is missing".to_owned()))?;
+    Ok(GroupId(sequence.next_value - 1))
+}
+
 fn get_group_filter_expr(filter: GroupRequestFilter) -> Cond {
     use GroupRequestFilter::*;
     match filter {
@@ -35,7 +69,9 @@ fn get_group_filter_expr(filter: GroupRequestFilter) -> Cond {
         Not(f) => get_group_filter_expr(*f).not(),
         DisplayName(name) => GroupColumn::DisplayName.eq(name).into_condition(),
         GroupId(id) => GroupColumn::GroupId.eq(id.0).into_condition(),
-        Uuid(uuid) => GroupColumn::Uuid.eq(uuid.to_string()).into_condition(),
+        Uuid(uuid) => GroupColumn::Uuid.eq(uuid).into_condition(),
+        GidNumber(gid_number) => GroupColumn::GidNumber.eq(gid_number).into_condition(),
+        HasGidNumber => GroupColumn::GidNumber.is_not_null().into_condition(),
         // WHERE (group_id in (SELECT group_id FROM memberships WHERE user_id = user))
         Member(user) => GroupColumn::GroupId
             .in_subquery(
@@ -49,37 +85,78 @@ fn get_group_filter_expr(filter: GroupRequestFilter) -> Cond {
     }
 }
 
-#[async_trait]
-impl GroupBackendHandler for SqlBackendHandler {
-    #[instrument(skip_all, level = "debug", ret, err)]
-    async fn list_groups(&self, filters: Option<GroupRequestFilter>) -> Result<Vec<Group>> {
-        debug!(?filters);
-        let results = model::Group::find()
-            // The order_by must be before find_with_related otherwise the primary order is by group_id.
-            .order_by_asc(GroupColumn::DisplayName)
+fn get_groups_query(filters: Option<GroupRequestFilter>) -> Select<model::Group> {
+    model::Group::find().filter(
+        filters
+            .map(|f| {
+                GroupColumn::GroupId
+                    .in_subquery(
+                        model::Group::find()
+                            .find_also_linked(model::memberships::GroupToUser)
+                            .select_only()
+                            .column(GroupColumn::GroupId)
+                            .filter(get_group_filter_expr(f))
+                            .into_query(),
+                    )
+                    .into_condition()
+            })
+            .unwrap_or_else(|| SimpleExpr::Value(true.into()).into_condition()),
+    )
+}
+
+impl SqlBackendHandler {
+    /// Fetches the groups with their members, including the members of their subgroups.
+    pub(crate) async fn fetch_groups(&self, query: Select<model::Group>) -> Result<Vec<Group>> {
+        let results = query
             .find_with_related(model::Membership)
-            .filter(
-                filters
-                    .map(|f| {
-                        GroupColumn::GroupId
-                            .in_subquery(
-                                model::Group::find()
-                                    .find_also_linked(model::memberships::GroupToUser)
-                                    .select_only()
-                                    .column(GroupColumn::GroupId)
-                                    .filter(get_group_filter_expr(f))
-                                    .into_query(),
-                            )
-                            .into_condition()
-                    })
-                    .unwrap_or_else(|| SimpleExpr::Value(true.into()).into_condition()),
-            )
-            .all(&self.sql_pool)
+            .all(self.read_pool())
             .await?;
+        let deleted_users = model::User::find()
+            .filter(UserColumn::DeletedAt.is_not_null())
+            .all(self.read_pool())
+            .await?
+            .into_iter()
+            .map(|u| u.user_id)
+            .collect::<HashSet<_>>();
+        let nesting = GroupNesting::load(self.read_pool()).await?;
+        let max_depth = self.config.max_group_nesting_depth;
+        let subgroup_members = if nesting.is_empty() {
+            HashMap::new()
+        } else {
+            let subgroups = results
+                .iter()
+                .flat_map(|(group, _)| nesting.subgroups(group.group_id, max_depth))
+                .collect::<HashSet<_>>();
+            let mut members = HashMap::<GroupId, Vec<UserId>>::new();
+            for membership in model::Membership::find()
+                .filter(MembershipColumn::GroupId.is_in(subgroups))
+                .all(self.read_pool())
+                .await?
+            {
+                members
+                    .entry(membership.group_id)
+                    .or_default()
+                    .push(membership.user_id);
+            }
+            members
+        };
         Ok(results
             .into_iter()
             .map(|(group, users)| {
-                let users: Vec<_> = users.into_iter().map(|u| u.user_id).collect();
+                let mut users: Vec<_> = users.into_iter().map(|u| u.user_id).collect();
+                let subgroups = nesting.subgroups(group.group_id, max_depth);
+                if !subgroups.is_empty() {
+                    users.extend(
+                        subgroups
+                            .iter()
+                            .filter_map(|g| subgroup_members.get(g))
+                            .flatten()
+                            .cloned(),
+                    );
+                    users.sort();
+                    users.dedup();
+                }
+                users.retain(|u| !deleted_users.contains(u));
                 Group {
                     users,
                     ..group.into()
@@ -88,6 +165,142 @@ impl GroupBackendHandler for SqlBackendHandler {
             .collect())
     }
 
+    async fn insert_group(
+        &self,
+        group_name: &str,
+        creation_date: DateTime,
+        modified_at: DateTime,
+    ) -> Result<GroupId> {
+        let uuid = Uuid::from_name_and_date(group_name, &creation_date);
+        let transaction = self.sql_pool.begin().await?;
+        let new_group = model::groups::ActiveModel {
+            group_id: ActiveValue::Set(next_group_id(&transaction).await?),
+            display_name: ActiveValue::Set(group_name.to_owned()),
+            creation_date: ActiveValue::Set(creation_date),
+            uuid: ActiveValue::Set(uuid),
+            modified_at: ActiveValue::Set(modified_at),
+            gid_number: ActiveValue::Set(Some(
+                allocate_posix_number(&transaction, PosixNumber::Gid, &self.config.posix_options)
+                    .await?,
+            )),
+        };
+        let group_id = new_group.insert(&transaction).await?.group_id;
+        let events = self
+            .write_audit_log(
+                &transaction,
+                vec![
+                    AuditChange::group(group_id, audit::CREATED),
+                    AuditChange::group(group_id, GroupColumn::DisplayName.as_str())
+                        .values(None, Some(group_name.as_bytes().to_vec())),
+                ],
+            )
+            .await?;
+        transaction.commit().await?;
+        events.publish();
+        Ok(group_id)
+    }
+}
+
+#[async_trait]
+impl GroupBackendHandler for SqlBackendHandler {
+    #[instrument(skip_all, level = "debug", ret, err)]
+    async fn list_groups(&self, filters: Option<GroupRequestFilter>) -> Result<Vec<Group>> {
+        debug!(?filters);
+        // The order_by must be before find_with_related otherwise the primary order is by group_id.
+        self.fetch_groups(get_groups_query(filters).order_by_asc(GroupColumn::DisplayName))
+            .await
+    }
+
+    #[instrument(skip_all, level = "debug", ret, err)]
+    async fn list_groups_page(
+        &self,
+        filters: Option<GroupRequestFilter>,
+        page: Pagination,
+    ) -> Result<Page<Group>> {
+        debug!(?filters, ?page);
+        let mut query = get_groups_query(filters).order_by_asc(GroupColumn::GroupId);
+        if let Some(after) = get_page_start(&page)? {
+            let after = after
+                .parse::<i32>()
+                .map_err(|_| DomainError::ValidationError(format!("Invalid cursor: {}", after)))?;
+            query = query.filter(GroupColumn::GroupId.gt(after));
+        }
+        #[derive(FromQueryResult)]
+        struct PageGroup {
+            group_id: GroupId,
+        }
+        // Fetch one more, to know if there is a next page.
+        let mut group_ids = query
+            .clone()
+            .select_only()
+            .column(GroupColumn::GroupId)
+            .limit(page.page_size as u64 + 1)
+            .into_model::<PageGroup>()
+            .all(self.read_pool())
+            .await?;
+        let has_next_page = group_ids.len() > page.page_size;
+        group_ids.truncate(page.page_size);
+        let last_group_id = match group_ids.pop() {
+            None => {
+                return Ok(Page {
+                    items: Vec::new(),
+                    next_cursor: None,
+                })
+            }
+            Some(group) => group.group_id,
+        };
+        let items = self
+            .fetch_groups(query.filter(GroupColumn::GroupId.lte(last_group_id.0)))
+            .await?;
+        Ok(Page {
+            items,
+            next_cursor: has_next_page.then(|| encode_cursor(&last_group_id.0.to_string())),
+        })
+    }
+
+    #[instrument(skip_all, level = "debug", ret, err)]
+    async fn count_groups(&self, filters: Option<GroupRequestFilter>) -> Result<usize> {
+        debug!(?filters);
+        let count = get_groups_query(filters).count(self.read_pool()).await?;
+        Ok(usize::try_from(count)?)
+    }
+
+    #[instrument(skip_all, level = "debug", err)]
+    async fn list_groups_with_member_counts(&self) -> Result<Vec<(GroupDetails, usize)>> {
+        #[derive(FromQueryResult)]
+        struct MemberCount {
+            group_id: GroupId,
+            member_count: i64,
+        }
+        let counts = model::Membership::find()
+            .inner_join(model::User)
+            .filter(UserColumn::DeletedAt.is_null())
+            .select_only()
+            .column(MembershipColumn::GroupId)
+            .column_as(
+                Expr::col((model::Membership, MembershipColumn::UserId)).count(),
+                "member_count",
+            )
+            .group_by(MembershipColumn::GroupId)
+            .into_model::<MemberCount>()
+            .all(self.read_pool())
+            .await?
+            .into_iter()
+            .map(|c| (c.group_id, c.member_count as usize))
+            .collect::<HashMap<_, _>>();
+        Ok(model::Group::find()
+            .order_by_asc(GroupColumn::DisplayName)
+            .into_model::<GroupDetails>()
+            .all(self.read_pool())
+            .await?
+            .into_iter()
+            .map(|group| {
+                let count = counts.get(&group.group_id).copied().unwrap_or(0);
+                (group, count)
+            })
+            .collect())
+    }
+
     #[instrument(skip_all, level = "debug", ret, err)]
     async fn get_group_details(&self, group_id: GroupId) -> Result<GroupDetails> {
         debug!(?group_id);
@@ -101,44 +314,71 @@ impl GroupBackendHandler for SqlBackendHandler {
     #[instrument(skip_all, level = "debug", err)]
     async fn update_group(&self, request: UpdateGroupRequest) -> Result<()> {
         debug!(?request.group_id);
+        let group_id = request.group_id;
+        let transaction = self.sql_pool.begin().await?;
+        let mut changes = Vec::new();
+        if let Some(display_name) = &request.display_name {
+            let old_group = model::Group::find_by_id(group_id).one(&transaction).await?;
+            if old_group.as_ref().map(|g| &g.display_name) != Some(display_name) {
+                changes.push(
+                    AuditChange::group(group_id, GroupColumn::DisplayName.as_str()).values(
+                        old_group.map(|g| g.display_name.into_bytes()),
+                        Some(display_name.clone().into_bytes()),
+                    ),
+                );
+            }
+        }
         let update_group = model::groups::ActiveModel {
-            group_id: ActiveValue::Set(request.group_id),
+            group_id: ActiveValue::Set(group_id),
             display_name: request
                 .display_name
                 .map(ActiveValue::Set)
                 .unwrap_or_default(),
             ..Default::default()
         };
-        update_group.update(&self.sql_pool).await?;
+        update_group.update(&transaction).await?;
+        let events = self.write_audit_log(&transaction, changes).await?;
+        transaction.commit().await?;
+        events.publish();
         Ok(())
     }
 
     #[instrument(skip_all, level = "debug", ret, err)]
     async fn create_group(&self, group_name: &str) -> Result<GroupId> {
         debug!(?group_name);
-        let now = chrono::Utc::now();
-        let uuid = Uuid::from_name_and_date(group_name, &now);
-        let new_group = model::groups::ActiveModel {
-            display_name: ActiveValue::Set(group_name.to_owned()),
-            creation_date: ActiveValue::Set(now),
-            uuid: ActiveValue::Set(uuid),
-            ..Default::default()
-        };
-        Ok(new_group.insert(&self.sql_pool).await?.group_id)
+        let now = clock::creation_date();
+        self.insert_group(group_name, now, now).await
+    }
+
+    #[instrument(skip_all, level = "debug", ret, err)]
+    async fn create_group_at(&self, group_name: &str, creation_date: DateTime) -> Result<GroupId> {
+        debug!(?group_name, ?creation_date);
+        self.insert_group(group_name, creation_date, clock::creation_date())
+            .await
     }
 
     #[instrument(skip_all, level = "debug", err)]
     async fn delete_group(&self, group_id: GroupId) -> Result<()> {
         debug!(?group_id);
-        let res = model::Group::delete_by_id(group_id)
-            .exec(&self.sql_pool)
+        let transaction = self.sql_pool.begin().await?;
+        let group = model::Group::find_by_id(group_id)
+            .one(&transaction)
+            .await?
+            .ok_or_else(|| {
+                DomainError::EntityNotFound(format!("No such group: '{:?}'", group_id))
+            })?;
+        model::Group::delete_by_id(group_id)
+            .exec(&transaction)
             .await?;
-        if res.rows_affected == 0 {
-            return Err(DomainError::EntityNotFound(format!(
-                "No such group: '{:?}'",
-                group_id
-            )));
-        }
+        record_tombstone(&transaction, AuditTarget::Group(group_id), group.uuid).await?;
+        let events = self
+            .write_audit_log(
+                &transaction,
+                vec![AuditChange::group(group_id, audit::DELETED)],
+            )
+            .await?;
+        transaction.commit().await?;
+        events.publish();
         Ok(())
     }
 }
@@ -146,7 +386,9 @@ impl GroupBackendHandler for SqlBackendHandler {
 #[cfg(test)]
 mod tests {
     use super::*;
-    use crate::domain::{sql_backend_handler::tests::*, types::UserId};
+    use crate::domain::{
+        handler::UserBackendHandler, sql_backend_handler::tests::*, types::UserId,
+    };
 
     async fn get_group_ids(
         handler: &SqlBackendHandler,
@@ -187,6 +429,98 @@ mod tests {
         );
     }
 
+    #[tokio::test]
+    async fn test_list_groups_page() {
+        let fixture = TestFixture::new().await;
+        let page = |after: Option<String>| Pagination {
+            after,
+            page_size: 2,
+        };
+        let first_page = fixture
+            .handler
+            .list_groups_page(None, page(None))
+            .await
+            .unwrap();
+        assert_eq!(
+            first_page
+                .items
+                .iter()
+                .map(|g| (g.id, g.users.len()))
+                .collect::<Vec<_>>(),
+            vec![(fixture.groups[0], 2), (fixture.groups[1], 2)]
+        );
+        let second_page = fixture
+            .handler
+            .list_groups_page(None, page(first_page.next_cursor))
+            .await
+            .unwrap();
+        assert_eq!(
+            second_page.items.iter().map(|g| g.id).collect::<Vec<_>>(),
+            vec![fixture.groups[2]]
+        );
+        assert_eq!(second_page.next_cursor, None);
+        assert!(matches!(
+            fixture
+                .handler
+                .list_groups_page(None, page(Some("not a cursor".to_owned())))
+                .await,
+            Err(DomainError::ValidationError(_))
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_count_groups() {
+        let fixture = TestFixture::new().await;
+        assert_eq!(fixture.handler.count_groups(None).await.unwrap(), 3);
+        assert_eq!(
+            fixture
+                .handler
+                .count_groups(Some(GroupRequestFilter::Member(UserId::new("patrick"))))
+                .await
+                .unwrap(),
+            2
+        );
+    }
+
+    #[tokio::test]
+    async fn test_list_groups_with_member_counts() {
+        let mut config = get_default_config();
+        config.soft_delete_users = true;
+        let fixture = TestFixture::with_config(config).await;
+        let get_counts = || async {
+            fixture
+                .handler
+                .list_groups_with_member_counts()
+                .await
+                .unwrap()
+                .into_iter()
+                .map(|(group, count)| (group.display_name, count))
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(
+            get_counts().await,
+            vec![
+                ("Best Group".to_owned(), 2),
+                ("Empty Group".to_owned(), 0),
+                ("Worst Group".to_owned(), 2)
+            ]
+        );
+        // The soft-deleted users aren't counted.
+        fixture
+            .handler
+            .delete_user(&UserId::new("bob"))
+            .await
+            .unwrap();
+        assert_eq!(
+            get_counts().await,
+            vec![
+                ("Best Group".to_owned(), 1),
+                ("Empty Group".to_owned(), 0),
+                ("Worst Group".to_owned(), 2)
+            ]
+        );
+    }
+
     #[tokio::test]
     async fn test_list_groups_simple_filter() {
         let fixture = TestFixture::new().await;
@@ -277,4 +611,37 @@ mod tests {
             vec![fixture.groups[2], fixture.groups[1]]
         );
     }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    async fn test_concurrent_group_creation() {
+        use crate::{domain::sql_tables::init_table, infra::configuration::UuidBackfill};
+        use sea_orm::Database;
+        // An in-memory database only has one connection: use a file to get real concurrency.
+        let path =
+            std::env::temp_dir().join(format!("lldap_group_ids_{}.db", Uuid::random().as_str()));
+        let mut sql_opt =
+            sea_orm::ConnectOptions::new(format!("sqlite://{}?mode=rwc", path.display()));
+        sql_opt.max_connections(8).sqlx_logging(false);
+        let sql_pool = Database::connect(sql_opt).await.unwrap();
+        init_table(&sql_pool, UuidBackfill::default())
+            .await
+            .unwrap();
+        let handler = SqlBackendHandler::new(get_default_config(), sql_pool);
+        let tasks = (0..50)
+            .map(|i| {
+                let handler = handler.clone();
+                tokio::spawn(async move { handler.create_group(&format!("group{}", i)).await })
+            })
+            .collect::<Vec<_>>();
+        let mut group_ids = Vec::new();
+        for task in tasks {
+            group_ids.push(task.await.unwrap().unwrap());
+        }
+        group_ids.sort();
+        group_ids.dedup();
+        assert_eq!(group_ids.len(), 50);
+        assert_eq!(group_ids[0], GroupId(1));
+        assert_eq!(group_ids[49], GroupId(50));
+        std::fs::remove_file(path).unwrap();
+    }
 }
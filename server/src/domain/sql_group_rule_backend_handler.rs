@@ -0,0 +1,494 @@
+use crate::domain::{
+    error::{DomainError, Result},
+    group_rule_handler::{
+        CreateGroupRuleRequest, GroupRule, GroupRuleBackendHandler, GroupRuleCondition,
+        GroupRuleOperator,
+    },
+    ldap::utils::map_user_field,
+    model::{self, GroupRulesColumn, MembershipColumn, UserAttributesColumn, UserColumn},
+    sql_audit_log_handler::AuditChange,
+    sql_backend_handler::SqlBackendHandler,
+    types::{AttributeName, AttributeType, GroupId, UserId},
+};
+use async_trait::async_trait;
+use sea_orm::{
+    ActiveModelTrait, ActiveValue, ColumnTrait, ConnectionTrait, EntityTrait, IdenStatic,
+    QueryFilter, QueryOrder, TransactionTrait,
+};
+use std::collections::{BTreeSet, HashMap, HashSet};
+use tracing::{debug, instrument};
+
+/// The built-in user fields that the conditions can use.
+const RULE_USER_COLUMNS: [UserColumn; 5] = [
+    UserColumn::UserId,
+    UserColumn::Email,
+    UserColumn::DisplayName,
+    UserColumn::FirstName,
+    UserColumn::LastName,
+];
+
+/// The name stored in the condition: the column of a built-in field, whatever its alias (e.g.
+/// `mail`), or the name of a custom attribute of type string.
+async fn normalize_condition_attribute(
+    connection: &impl ConnectionTrait,
+    name: &str,
+) -> Result<String> {
+    if let Some(column) = map_user_field(&name.to_ascii_lowercase()) {
+        return if RULE_USER_COLUMNS.contains(&column) {
+            Ok(column.as_str().to_owned())
+        } else {
+            Err(DomainError::ValidationError(format!(
+                "The user field '{}' can't be used in a group rule",
+                name
+            )))
+        };
+    }
+    let name = AttributeName::new(name);
+    match model::UserAttributeSchema::find_by_id(name.clone())
+        .one(connection)
+        .await?
+    {
+        Some(schema) if schema.attribute_type == AttributeType::String => Ok(name.into_string()),
+        Some(_) => Err(DomainError::ValidationError(format!(
+            "The attribute '{}' is not a string, it can't be used in a group rule",
+            name
+        ))),
+        None => Err(DomainError::EntityNotFound(format!(
+            "No such attribute: '{}'",
+            name
+        ))),
+    }
+}
+
+async fn get_group_rules(connection: &impl ConnectionTrait) -> Result<Vec<GroupRule>> {
+    // The order_by must be before find_with_related otherwise the primary order is by rule_id.
+    let results = model::GroupRules::find()
+        .order_by_asc(GroupRulesColumn::RuleId)
+        .find_with_related(model::GroupRuleConditions)
+        .all(connection)
+        .await?;
+    results
+        .into_iter()
+        .map(|(rule, mut conditions)| {
+            conditions.sort_by_key(|c| c.position);
+            Ok(GroupRule {
+                rule_id: rule.rule_id,
+                group_id: rule.group_id,
+                conditions: conditions
+                    .into_iter()
+                    .map(|c| {
+                        Ok(GroupRuleCondition {
+                            operator: GroupRuleOperator::from_name(&c.operator).ok_or_else(
+                                || {
+                                    DomainError::InternalError(format!(
+                                        "Invalid group rule operator: '{}'",
+                                        c.operator
+                                    ))
+                                },
+                            )?,
+                            attribute: c.attribute,
+                            value: c.value,
+                        })
+                    })
+                    .collect::<Result<_>>()?,
+                creation_date: rule.creation_date,
+            })
+        })
+        .collect()
+}
+
+/// The attributes of the users, as used by the conditions of the rules.
+async fn get_user_attributes(
+    connection: &impl ConnectionTrait,
+    user_ids: Option<&[UserId]>,
+    rules: &[GroupRule],
+) -> Result<HashMap<UserId, HashMap<String, Vec<String>>>> {
+    let built_in_fields = RULE_USER_COLUMNS
+        .iter()
+        .map(|c| c.as_str().to_owned())
+        .collect::<HashSet<_>>();
+    let custom_attributes = rules
+        .iter()
+        .flat_map(|rule| &rule.conditions)
+        .filter(|condition| !built_in_fields.contains(&condition.attribute))
+        .map(|condition| AttributeName::new(&condition.attribute))
+        .collect::<HashSet<_>>();
+    let mut users = model::User::find().filter(UserColumn::DeletedAt.is_null());
+    let mut attributes = model::UserAttributes::find()
+        .filter(UserAttributesColumn::AttributeName.is_in(custom_attributes));
+    if let Some(user_ids) = user_ids {
+        users = users.filter(UserColumn::UserId.is_in(user_ids.iter().cloned()));
+        attributes =
+            attributes.filter(UserAttributesColumn::UserId.is_in(user_ids.iter().cloned()));
+    }
+    let mut result = users
+        .all(connection)
+        .await?
+        .into_iter()
+        .map(|user| {
+            let fields = [
+                (UserColumn::UserId, Some(user.user_id.as_str().to_owned())),
+                (UserColumn::Email, Some(user.email)),
+                (UserColumn::DisplayName, user.display_name),
+                (UserColumn::FirstName, user.first_name),
+                (UserColumn::LastName, user.last_name),
+            ]
+            .into_iter()
+            .filter_map(|(column, value)| {
+                value.map(|value| (column.as_str().to_owned(), vec![value]))
+            })
+            .collect();
+            (user.user_id, fields)
+        })
+        .collect::<HashMap<_, HashMap<_, _>>>();
+    for attribute in attributes.all(connection).await? {
+        // Soft-deleted users are skipped.
+        if let Some(fields) = result.get_mut(&attribute.user_id) {
+            fields
+                .entry(attribute.attribute_name.into_string())
+                .or_insert_with(Vec::new)
+                .push(String::from_utf8_lossy(&attribute.value).into_owned());
+        }
+    }
+    Ok(result)
+}
+
+impl SqlBackendHandler {
+    /// Adds the users (all of them for `None`) to the groups of the rules they match, and removes
+    /// them from the groups added by a rule they don't match anymore. The memberships added by
+    /// hand are left alone. Returns the changes for the audit log.
+    #[instrument(skip_all, level = "debug", err)]
+    pub(crate) async fn apply_group_rules(
+        &self,
+        connection: &impl ConnectionTrait,
+        user_ids: Option<&[UserId]>,
+    ) -> Result<Vec<AuditChange>> {
+        debug!(?user_ids);
+        let rules = get_group_rules(connection).await?;
+        let users = get_user_attributes(connection, user_ids, &rules).await?;
+        let desired = users
+            .iter()
+            .flat_map(|(user_id, attributes)| {
+                rules
+                    .iter()
+                    .filter(|rule| rule.matches(attributes))
+                    .map(|rule| (user_id.clone(), rule.group_id))
+            })
+            .collect::<BTreeSet<_>>();
+        let mut memberships = model::Membership::find();
+        if let Some(user_ids) = user_ids {
+            memberships =
+                memberships.filter(MembershipColumn::UserId.is_in(user_ids.iter().cloned()));
+        }
+        let current = memberships
+            .all(connection)
+            .await?
+            .into_iter()
+            .map(|m| ((m.user_id, m.group_id), m.assigned_by_rule))
+            .collect::<HashMap<_, _>>();
+        let added = desired
+            .iter()
+            .filter(|membership| !current.contains_key(membership))
+            .cloned()
+            .collect::<Vec<_>>();
+        let removed = current
+            .iter()
+            .filter(|(membership, &assigned_by_rule)| {
+                assigned_by_rule
+                    && users.contains_key(&membership.0)
+                    && !desired.contains(membership)
+            })
+            .map(|(membership, _)| membership.clone())
+            .collect::<BTreeSet<(UserId, GroupId)>>();
+        debug!(added = added.len(), removed = removed.len());
+        if !added.is_empty() {
+            model::Membership::insert_many(added.iter().map(|(user_id, group_id)| {
+                model::memberships::ActiveModel {
+                    user_id: ActiveValue::Set(user_id.clone()),
+                    group_id: ActiveValue::Set(*group_id),
+                    assigned_by_rule: ActiveValue::Set(true),
+                }
+            }))
+            .exec(connection)
+            .await?;
+        }
+        for (user_id, group_id) in &removed {
+            model::Membership::delete_by_id((user_id.clone(), *group_id))
+                .exec(connection)
+                .await?;
+        }
+        Ok(added
+            .iter()
+            .map(|(user_id, group_id)| {
+                AuditChange::membership(user_id, *group_id)
+                    .values(None, Some(group_id.0.to_string().into_bytes()))
+            })
+            .chain(removed.iter().map(|(user_id, group_id)| {
+                AuditChange::membership(user_id, *group_id)
+                    .values(Some(group_id.0.to_string().into_bytes()), None)
+            }))
+            .collect())
+    }
+}
+
+#[async_trait]
+impl GroupRuleBackendHandler for SqlBackendHandler {
+    #[instrument(skip_all, level = "debug", ret, err)]
+    async fn list_group_rules(&self) -> Result<Vec<GroupRule>> {
+        get_group_rules(self.read_pool()).await
+    }
+
+    #[instrument(skip_all, level = "debug", ret, err)]
+    async fn create_group_rule(&self, request: CreateGroupRuleRequest) -> Result<i32> {
+        debug!(?request);
+        if request.conditions.is_empty() {
+            return Err(DomainError::ValidationError(
+                "A group rule needs at least one condition".to_owned(),
+            ));
+        }
+        let transaction = self.sql_pool.begin().await?;
+        if model::Group::find_by_id(request.group_id)
+            .one(&transaction)
+            .await?
+            .is_none()
+        {
+            return Err(DomainError::EntityNotFound(format!(
+                "No such group: '{:?}'",
+                request.group_id
+            )));
+        }
+        let mut conditions = Vec::new();
+        for (position, condition) in request.conditions.into_iter().enumerate() {
+            conditions.push(model::group_rule_conditions::ActiveModel {
+                rule_id: ActiveValue::NotSet,
+                position: ActiveValue::Set(position as i32),
+                attribute: ActiveValue::Set(
+                    normalize_condition_attribute(&transaction, &condition.attribute).await?,
+                ),
+                operator: ActiveValue::Set(condition.operator.as_str().to_owned()),
+                value: ActiveValue::Set(condition.value),
+            });
+        }
+        let rule = model::group_rules::ActiveModel {
+            group_id: ActiveValue::Set(request.group_id),
+            creation_date: ActiveValue::Set(chrono::Utc::now()),
+            ..Default::default()
+        }
+        .insert(&transaction)
+        .await?;
+        model::GroupRuleConditions::insert_many(conditions.into_iter().map(|mut condition| {
+            condition.rule_id = ActiveValue::Set(rule.rule_id);
+            condition
+        }))
+        .exec(&transaction)
+        .await?;
+        self.last_write.mark();
+        transaction.commit().await?;
+        Ok(rule.rule_id)
+    }
+
+    #[instrument(skip_all, level = "debug", err)]
+    async fn delete_group_rule(&self, rule_id: i32) -> Result<()> {
+        debug!(?rule_id);
+        let transaction = self.sql_pool.begin().await?;
+        // Not relying on the cascade: SQLite only enforces it with the foreign keys enabled.
+        model::GroupRuleConditions::delete_many()
+            .filter(model::GroupRuleConditionsColumn::RuleId.eq(rule_id))
+            .exec(&transaction)
+            .await?;
+        let res = model::GroupRules::delete_by_id(rule_id)
+            .exec(&transaction)
+            .await?;
+        if res.rows_affected == 0 {
+            return Err(DomainError::EntityNotFound(format!(
+                "No such group rule: {}",
+                rule_id
+            )));
+        }
+        self.last_write.mark();
+        transaction.commit().await?;
+        Ok(())
+    }
+
+    #[instrument(skip_all, level = "debug", ret, err)]
+    async fn reevaluate_group_rules(&self) -> Result<usize> {
+        let transaction = self.sql_pool.begin().await?;
+        let changes = self.apply_group_rules(&transaction, None).await?;
+        let num_changes = changes.len();
+        let events = self.write_audit_log(&transaction, changes).await?;
+        transaction.commit().await?;
+        events.publish();
+        Ok(num_changes)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::domain::{
+        handler::{GroupBackendHandler, UserAttributeBackendHandler, UserBackendHandler},
+        sql_backend_handler::tests::*,
+        types::{AttributeSchema, AttributeValue},
+    };
+
+    fn condition(attribute: &str, operator: GroupRuleOperator, value: &str) -> GroupRuleCondition {
+        GroupRuleCondition {
+            attribute: attribute.to_owned(),
+            operator,
+            value: value.to_owned(),
+        }
+    }
+
+    async fn get_group_members(handler: &SqlBackendHandler, group_id: GroupId) -> Vec<String> {
+        let mut members = model::Membership::find()
+            .filter(MembershipColumn::GroupId.eq(group_id))
+            .all(&handler.sql_pool)
+            .await
+            .unwrap()
+            .into_iter()
+            .map(|m| m.user_id.into_string())
+            .collect::<Vec<_>>();
+        members.sort();
+        members
+    }
+
+    #[tokio::test]
+    async fn test_create_and_list_group_rules() {
+        let fixture = TestFixture::new().await;
+        let rule_id = fixture
+            .handler
+            .create_group_rule(CreateGroupRuleRequest {
+                group_id: fixture.groups[0],
+                conditions: vec![
+                    condition("Mail", GroupRuleOperator::EndsWith, "@example.com"),
+                    condition("uid", GroupRuleOperator::StartsWith, "b"),
+                ],
+            })
+            .await
+            .unwrap();
+        let rules = fixture.handler.list_group_rules().await.unwrap();
+        assert_eq!(rules.len(), 1);
+        assert_eq!(rules[0].rule_id, rule_id);
+        assert_eq!(
+            rules[0].conditions,
+            vec![
+                condition("email", GroupRuleOperator::EndsWith, "@example.com"),
+                condition("user_id", GroupRuleOperator::StartsWith, "b"),
+            ]
+        );
+        fixture.handler.delete_group_rule(rule_id).await.unwrap();
+        assert!(fixture.handler.list_group_rules().await.unwrap().is_empty());
+        assert!(matches!(
+            fixture.handler.delete_group_rule(rule_id).await,
+            Err(DomainError::EntityNotFound(_))
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_create_group_rule_validation() {
+        let fixture = TestFixture::new().await;
+        for (group_id, conditions) in [
+            (fixture.groups[0], vec![]),
+            (
+                fixture.groups[0],
+                vec![condition("avatar", GroupRuleOperator::Equals, "x")],
+            ),
+            (
+                GroupId(1000),
+                vec![condition("email", GroupRuleOperator::Equals, "x")],
+            ),
+            (
+                fixture.groups[0],
+                vec![condition("unknown", GroupRuleOperator::Equals, "x")],
+            ),
+        ] {
+            assert!(fixture
+                .handler
+                .create_group_rule(CreateGroupRuleRequest {
+                    group_id,
+                    conditions,
+                })
+                .await
+                .is_err());
+        }
+        assert!(fixture.handler.list_group_rules().await.unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_group_rules_keep_the_manual_memberships() {
+        let fixture = TestFixture::new().await;
+        let group_id = fixture.handler.create_group("engineering").await.unwrap();
+        fixture
+            .handler
+            .create_user_attribute(AttributeSchema {
+                name: AttributeName::new("department"),
+                attribute_type: AttributeType::String,
+                is_list: false,
+                is_indexed: false,
+            })
+            .await
+            .unwrap();
+        fixture
+            .handler
+            .add_user_to_group(&UserId::new("john"), group_id)
+            .await
+            .unwrap();
+        fixture
+            .handler
+            .create_group_rule(CreateGroupRuleRequest {
+                group_id,
+                conditions: vec![condition("department", GroupRuleOperator::Equals, "eng")],
+            })
+            .await
+            .unwrap();
+        fixture
+            .handler
+            .set_user_attribute(
+                &UserId::new("bob"),
+                &AttributeName::new("department"),
+                vec![AttributeValue::String("ENG".to_owned())],
+            )
+            .await
+            .unwrap();
+        assert_eq!(
+            get_group_members(&fixture.handler, group_id).await,
+            vec!["bob", "john"]
+        );
+        fixture
+            .handler
+            .set_user_attribute(
+                &UserId::new("bob"),
+                &AttributeName::new("department"),
+                vec![],
+            )
+            .await
+            .unwrap();
+        assert_eq!(
+            get_group_members(&fixture.handler, group_id).await,
+            vec!["john"]
+        );
+        assert_eq!(fixture.handler.reevaluate_group_rules().await.unwrap(), 0);
+    }
+
+    #[tokio::test]
+    async fn test_reevaluate_group_rules() {
+        let fixture = TestFixture::new().await;
+        let group_id = fixture.groups[2];
+        let before = get_group_members(&fixture.handler, group_id).await;
+        fixture
+            .handler
+            .create_group_rule(CreateGroupRuleRequest {
+                group_id,
+                conditions: vec![condition("user_id", GroupRuleOperator::Equals, "NoGroup")],
+            })
+            .await
+            .unwrap();
+        // Existing users are only added on reevaluation.
+        assert_eq!(get_group_members(&fixture.handler, group_id).await, before);
+        assert_eq!(fixture.handler.reevaluate_group_rules().await.unwrap(), 1);
+        assert!(get_group_members(&fixture.handler, group_id)
+            .await
+            .contains(&"nogroup".to_owned()));
+        assert_eq!(fixture.handler.reevaluate_group_rules().await.unwrap(), 0);
+    }
+}
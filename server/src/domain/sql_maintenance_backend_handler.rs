@@ -0,0 +1,323 @@
+use super::{
+    change_events::PendingChangeEvents,
+    error::Result,
+    maintenance_handler::{
+        MaintenanceBackendHandler, MaintenanceReport, MaintenanceStatus, MaintenanceStep,
+    },
+    model::{
+        self, GroupColumn, GroupMembershipColumn, MembershipColumn, SequencesColumn, UserColumn,
+    },
+    sql_backend_handler::SqlBackendHandler,
+    sql_migrations::GROUP_ID_SEQUENCE,
+    sql_posix_numbers::assign_missing_posix_numbers_in,
+};
+use async_trait::async_trait;
+use sea_orm::{
+    ColumnTrait, DatabaseTransaction, EntityTrait, FromQueryResult, QueryFilter, QuerySelect,
+    QueryTrait, TransactionTrait,
+};
+use sea_query::{Cond, Expr};
+use tracing::{debug, info, instrument, warn, Instrument};
+
+impl SqlBackendHandler {
+    /// Rebuilds the data derived from the users and groups, one step per transaction to keep the
+    /// locks short on a live server. With `dry_run`, each step is rolled back once counted. Calls
+    /// `on_step_done` with the changes so far after each step.
+    #[instrument(skip_all, level = "debug", ret, err)]
+    pub async fn rebuild_derived_data(
+        &self,
+        dry_run: bool,
+        mut on_step_done: impl FnMut(MaintenanceStep, &MaintenanceReport) + Send,
+    ) -> Result<MaintenanceReport> {
+        debug!(?dry_run);
+        let mut report = MaintenanceReport::default();
+        for step in MaintenanceStep::ALL {
+            let transaction = self.sql_pool.begin().await?;
+            let events = self
+                .run_maintenance_step(&transaction, step, &mut report)
+                .await?;
+            if dry_run {
+                transaction.rollback().await?;
+            } else {
+                self.last_write.mark();
+                transaction.commit().await?;
+                if let Some(events) = events {
+                    events.publish();
+                }
+            }
+            on_step_done(step, &report);
+        }
+        Ok(report)
+    }
+
+    async fn run_maintenance_step(
+        &self,
+        transaction: &DatabaseTransaction,
+        step: MaintenanceStep,
+        report: &mut MaintenanceReport,
+    ) -> Result<Option<PendingChangeEvents>> {
+        debug!(step = step.as_str());
+        match step {
+            MaintenanceStep::OrphanMemberships => {
+                // The soft-deleted users keep their memberships.
+                let res = model::Membership::delete_many()
+                    .filter(
+                        Cond::any()
+                            .add(
+                                MembershipColumn::UserId.not_in_subquery(
+                                    model::User::find()
+                                        .select_only()
+                                        .column(UserColumn::UserId)
+                                        .into_query(),
+                                ),
+                            )
+                            .add(
+                                MembershipColumn::GroupId.not_in_subquery(
+                                    model::Group::find()
+                                        .select_only()
+                                        .column(GroupColumn::GroupId)
+                                        .into_query(),
+                                ),
+                            ),
+                    )
+                    .exec(transaction)
+                    .await?;
+                report.orphan_memberships_removed = res.rows_affected as usize;
+            }
+            MaintenanceStep::OrphanGroupNestings => {
+                let group_ids = || {
+                    model::Group::find()
+                        .select_only()
+                        .column(GroupColumn::GroupId)
+                        .into_query()
+                };
+                let res = model::GroupMembership::delete_many()
+                    .filter(
+                        Cond::any()
+                            .add(GroupMembershipColumn::ParentGroupId.not_in_subquery(group_ids()))
+                            .add(GroupMembershipColumn::ChildGroupId.not_in_subquery(group_ids())),
+                    )
+                    .exec(transaction)
+                    .await?;
+                report.orphan_group_nestings_removed = res.rows_affected as usize;
+            }
+            MaintenanceStep::GroupRules => {
+                let changes = self.apply_group_rules(transaction, None).await?;
+                let added = changes.iter().filter(|c| c.is_addition()).count();
+                report.rule_memberships_added = added;
+                report.rule_memberships_removed = changes.len() - added;
+                return Ok(Some(self.write_audit_log(transaction, changes).await?));
+            }
+            MaintenanceStep::PosixNumbers => {
+                let (num_users, num_groups) =
+                    assign_missing_posix_numbers_in(transaction, &self.config.posix_options)
+                        .await?;
+                report.posix_numbers_assigned = num_users + num_groups;
+            }
+            MaintenanceStep::GroupIdSequence => {
+                #[derive(FromQueryResult)]
+                struct MaxGroupId {
+                    max_group_id: Option<i32>,
+                }
+                let max_group_id = model::Group::find()
+                    .select_only()
+                    .column_as(Expr::col(GroupColumn::GroupId).max(), "max_group_id")
+                    .into_model::<MaxGroupId>()
+                    .one(transaction)
+                    .await?
+                    .and_then(|m| m.max_group_id);
+                if let Some(max_group_id) = max_group_id {
+                    let res = model::Sequences::update_many()
+                        .col_expr(SequencesColumn::NextValue, Expr::value(max_group_id + 1))
+                        .filter(SequencesColumn::Name.eq(GROUP_ID_SEQUENCE))
+                        .filter(SequencesColumn::NextValue.lte(max_group_id))
+                        .exec(transaction)
+                        .await?;
+                    report.group_id_sequence_advanced = res.rows_affected > 0;
+                }
+            }
+        }
+        Ok(None)
+    }
+}
+
+#[async_trait]
+impl MaintenanceBackendHandler for SqlBackendHandler {
+    #[instrument(skip_all, level = "debug", err)]
+    async fn start_maintenance(&self, dry_run: bool) -> Result<()> {
+        debug!(?dry_run);
+        self.maintenance.start(dry_run)?;
+        let handler = self.clone();
+        tokio::spawn(
+            async move {
+                let progress = handler.maintenance.clone();
+                let result = handler
+                    .rebuild_derived_data(dry_run, |_, report| progress.step_done(report))
+                    .await;
+                match &result {
+                    Ok(report) => info!(?dry_run, ?report, "Maintenance done"),
+                    Err(e) => warn!("Maintenance failed: {}", e),
+                }
+                progress.finish(&result);
+            }
+            .in_current_span(),
+        );
+        Ok(())
+    }
+
+    #[instrument(skip_all, level = "debug", ret, err)]
+    async fn get_maintenance_status(&self) -> Result<Option<MaintenanceStatus>> {
+        Ok(self.maintenance.status())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::domain::{
+        group_rule_handler::{
+            CreateGroupRuleRequest, GroupRuleBackendHandler, GroupRuleCondition, GroupRuleOperator,
+        },
+        handler::{GroupBackendHandler, UserBackendHandler, UserRequestFilter},
+        sql_backend_handler::tests::*,
+        sql_posix_numbers::assign_missing_posix_numbers,
+        types::{GroupId, UserId},
+    };
+    use sea_orm::{ActiveModelTrait, ActiveValue, ConnectionTrait, Statement};
+
+    async fn set_foreign_keys(handler: &SqlBackendHandler, enabled: bool) {
+        handler
+            .sql_pool
+            .execute(Statement::from_string(
+                handler.sql_pool.get_database_backend(),
+                format!(
+                    "PRAGMA foreign_keys = {}",
+                    if enabled { "ON" } else { "OFF" }
+                ),
+            ))
+            .await
+            .unwrap();
+    }
+
+    /// Like manual edits of a database that doesn't enforce the foreign keys.
+    async fn insert_orphans(handler: &SqlBackendHandler) {
+        set_foreign_keys(handler, false).await;
+        model::memberships::ActiveModel {
+            user_id: ActiveValue::Set(UserId::new("ghost")),
+            group_id: ActiveValue::Set(GroupId(1)),
+            assigned_by_rule: ActiveValue::Set(false),
+        }
+        .insert(&handler.sql_pool)
+        .await
+        .unwrap();
+        model::group_memberships::ActiveModel {
+            parent_group_id: ActiveValue::Set(GroupId(1)),
+            child_group_id: ActiveValue::Set(GroupId(1000)),
+        }
+        .insert(&handler.sql_pool)
+        .await
+        .unwrap();
+        set_foreign_keys(handler, true).await;
+    }
+
+    #[tokio::test]
+    async fn test_rebuild_derived_data() {
+        let fixture = TestFixture::new().await;
+        let handler = &fixture.handler;
+        insert_orphans(handler).await;
+        handler
+            .create_group_rule(CreateGroupRuleRequest {
+                group_id: fixture.groups[2],
+                conditions: vec![GroupRuleCondition {
+                    attribute: "user_id".to_owned(),
+                    operator: GroupRuleOperator::Equals,
+                    value: "bob".to_owned(),
+                }],
+            })
+            .await
+            .unwrap();
+        assign_missing_posix_numbers(&handler.sql_pool, &handler.config.posix_options)
+            .await
+            .unwrap();
+        model::User::update_many()
+            .col_expr(UserColumn::UidNumber, Expr::value(Option::<i32>::None))
+            .filter(ColumnTrait::eq(&UserColumn::UserId, UserId::new("bob")))
+            .exec(&handler.sql_pool)
+            .await
+            .unwrap();
+        model::Sequences::update_many()
+            .col_expr(SequencesColumn::NextValue, Expr::value(1))
+            .filter(SequencesColumn::Name.eq(GROUP_ID_SEQUENCE))
+            .exec(&handler.sql_pool)
+            .await
+            .unwrap();
+        let expected = MaintenanceReport {
+            orphan_memberships_removed: 1,
+            orphan_group_nestings_removed: 1,
+            rule_memberships_added: 1,
+            rule_memberships_removed: 0,
+            posix_numbers_assigned: 1,
+            group_id_sequence_advanced: true,
+        };
+
+        // The dry run reports the changes without making them.
+        let mut steps = Vec::new();
+        assert_eq!(
+            handler
+                .rebuild_derived_data(true, |step, _| steps.push(step))
+                .await
+                .unwrap(),
+            expected
+        );
+        assert_eq!(steps, MaintenanceStep::ALL);
+        let bob_in_empty_group = || async {
+            handler
+                .count_users(Some(UserRequestFilter::MemberOfId(fixture.groups[2])))
+                .await
+                .unwrap()
+        };
+        assert_eq!(bob_in_empty_group().await, 0);
+
+        assert_eq!(
+            handler
+                .rebuild_derived_data(false, |_, _| ())
+                .await
+                .unwrap(),
+            expected
+        );
+        assert_eq!(bob_in_empty_group().await, 1);
+        assert!(model::GroupMembership::find()
+            .all(&handler.sql_pool)
+            .await
+            .unwrap()
+            .is_empty());
+        // The sequence doesn't hand out the IDs in use anymore.
+        handler.create_group("New Group").await.unwrap();
+        // Nothing left to do.
+        assert!(handler
+            .rebuild_derived_data(false, |_, _| ())
+            .await
+            .unwrap()
+            .is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_start_maintenance() {
+        let fixture = TestFixture::new().await;
+        let handler = &fixture.handler;
+        insert_orphans(handler).await;
+        assert_eq!(handler.get_maintenance_status().await.unwrap(), None);
+        handler.start_maintenance(false).await.unwrap();
+        let status = loop {
+            let status = handler.get_maintenance_status().await.unwrap().unwrap();
+            if status.finished_at.is_some() {
+                break status;
+            }
+            tokio::time::sleep(std::time::Duration::from_millis(10)).await;
+        };
+        assert_eq!(status.error, None);
+        assert_eq!(status.steps_done, MaintenanceStep::ALL.len());
+        assert_eq!(status.report.orphan_memberships_removed, 1);
+        assert_eq!(status.report.orphan_group_nestings_removed, 1);
+    }
+}
@@ -0,0 +1,185 @@
+use super::{
+    error::{DomainError, Result},
+    handler::UserBackendHandler,
+    mfa_backup_codes_handler::MfaBackupCodesHandler,
+    model::{self, MfaBackupCodesColumn},
+    sql_audit_log_handler::{self as audit, AuditChange},
+    sql_backend_handler::SqlBackendHandler,
+    types::UserId,
+};
+use async_trait::async_trait;
+use hmac::{Hmac, Mac, NewMac};
+use sea_orm::{
+    ActiveValue, ColumnTrait, EntityTrait, PaginatorTrait, QueryFilter, TransactionTrait,
+};
+use sha2::Sha256;
+use tracing::{debug, instrument};
+
+const BACKUP_CODE_COUNT: usize = 10;
+/// Without the dash: about 51 bits of entropy.
+const BACKUP_CODE_LENGTH: usize = 10;
+const BACKUP_CODE_CHARACTERS: &[u8] = b"abcdefghijklmnopqrstuvwxyz0123456789";
+
+/// Shown as `xxxxx-xxxxx`.
+fn generate_backup_code() -> String {
+    use rand::seq::SliceRandom;
+    let mut rng = rand::rngs::OsRng;
+    let code = (0..BACKUP_CODE_LENGTH)
+        .map(|_| *BACKUP_CODE_CHARACTERS.choose(&mut rng).unwrap() as char)
+        .collect::<String>();
+    let (first, second) = code.split_at(BACKUP_CODE_LENGTH / 2);
+    format!("{}-{}", first, second)
+}
+
+/// Ignores the dashes, spaces and case, that users might get wrong when typing the code.
+fn normalize_backup_code(code: &str) -> String {
+    code.chars()
+        .filter(|c| *c != '-' && !c.is_whitespace())
+        .map(|c| c.to_ascii_lowercase())
+        .collect()
+}
+
+impl SqlBackendHandler {
+    /// HMAC keyed with the server key, so that a copy of the table isn't enough to brute-force
+    /// the codes.
+    fn hash_backup_code(&self, code: &str) -> String {
+        let mut mac = Hmac::<Sha256>::new_varkey(self.config.get_server_keys().private())
+            .expect("HMAC accepts keys of any size");
+        mac.update(normalize_backup_code(code).as_bytes());
+        mac.finalize()
+            .into_bytes()
+            .iter()
+            .map(|b| format!("{:02x}", b))
+            .collect()
+    }
+}
+
+#[async_trait]
+impl MfaBackupCodesHandler for SqlBackendHandler {
+    #[instrument(skip_all, level = "debug", err)]
+    async fn generate_backup_codes(&self, user_id: &UserId) -> Result<Vec<String>> {
+        debug!(?user_id);
+        // Make sure the user hasn't been deleted.
+        self.get_user_details(user_id).await?;
+        let codes = (0..BACKUP_CODE_COUNT)
+            .map(|_| generate_backup_code())
+            .collect::<Vec<_>>();
+        let now = chrono::Utc::now();
+        let transaction = self.sql_pool.begin().await?;
+        model::MfaBackupCodes::delete_many()
+            .filter(MfaBackupCodesColumn::UserId.eq(user_id))
+            .exec(&transaction)
+            .await?;
+        model::MfaBackupCodes::insert_many(codes.iter().map(|code| {
+            model::mfa_backup_codes::ActiveModel {
+                user_id: ActiveValue::Set(user_id.clone()),
+                code_hash: ActiveValue::Set(self.hash_backup_code(code)),
+                creation_date: ActiveValue::Set(now),
+                ..Default::default()
+            }
+        }))
+        .exec(&transaction)
+        .await?;
+        let events = self
+            .write_audit_log(
+                &transaction,
+                vec![AuditChange::user(user_id, audit::MFA_BACKUP_CODES)],
+            )
+            .await?;
+        transaction.commit().await?;
+        events.publish();
+        Ok(codes)
+    }
+
+    #[instrument(skip_all, level = "debug", err)]
+    async fn consume_backup_code(&self, user_id: &UserId, code: &str) -> Result<()> {
+        debug!(?user_id);
+        // A single statement: of two concurrent logins with the same code, only one deletes it.
+        let result = model::MfaBackupCodes::delete_many()
+            .filter(MfaBackupCodesColumn::UserId.eq(user_id))
+            .filter(MfaBackupCodesColumn::CodeHash.eq(self.hash_backup_code(code)))
+            .exec(&self.sql_pool)
+            .await?;
+        if result.rows_affected == 0 {
+            return Err(DomainError::AuthenticationError(format!(
+                "Invalid backup code for user '{}'",
+                user_id
+            )));
+        }
+        Ok(())
+    }
+
+    #[instrument(skip_all, level = "debug", ret, err)]
+    async fn count_backup_codes(&self, user_id: &UserId) -> Result<usize> {
+        let count = model::MfaBackupCodes::find()
+            .filter(MfaBackupCodesColumn::UserId.eq(user_id))
+            .count(&self.sql_pool)
+            .await?;
+        Ok(usize::try_from(count)?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::domain::sql_backend_handler::tests::*;
+
+    #[test]
+    fn test_generate_backup_code() {
+        let code = generate_backup_code();
+        assert_eq!(code.len(), BACKUP_CODE_LENGTH + 1);
+        assert_eq!(code.chars().nth(5), Some('-'));
+        assert_eq!(normalize_backup_code(&code).len(), BACKUP_CODE_LENGTH);
+        assert_eq!(normalize_backup_code(" ABCde-fGH12 "), "abcdefgh12");
+    }
+
+    #[tokio::test]
+    async fn test_backup_codes() {
+        let fixture = TestFixture::new().await;
+        let bob = UserId::new("bob");
+        let codes = fixture.handler.generate_backup_codes(&bob).await.unwrap();
+        assert_eq!(codes.len(), BACKUP_CODE_COUNT);
+        assert_eq!(
+            fixture.handler.count_backup_codes(&bob).await.unwrap(),
+            BACKUP_CODE_COUNT
+        );
+        // Only the hashes are stored.
+        let stored = model::MfaBackupCodes::find()
+            .all(&fixture.handler.sql_pool)
+            .await
+            .unwrap();
+        assert!(stored.iter().all(|c| !codes.contains(&c.code_hash)));
+        // The codes are for bob only.
+        fixture
+            .handler
+            .consume_backup_code(&UserId::new("patrick"), &codes[0])
+            .await
+            .unwrap_err();
+        fixture
+            .handler
+            .consume_backup_code(&bob, &codes[0].to_uppercase())
+            .await
+            .unwrap();
+        // Each code can only be used once.
+        assert!(matches!(
+            fixture.handler.consume_backup_code(&bob, &codes[0]).await,
+            Err(DomainError::AuthenticationError(_))
+        ));
+        assert_eq!(
+            fixture.handler.count_backup_codes(&bob).await.unwrap(),
+            BACKUP_CODE_COUNT - 1
+        );
+        // A new set invalidates the old one.
+        fixture.handler.generate_backup_codes(&bob).await.unwrap();
+        fixture
+            .handler
+            .consume_backup_code(&bob, &codes[1])
+            .await
+            .unwrap_err();
+        fixture
+            .handler
+            .generate_backup_codes(&UserId::new("nobody"))
+            .await
+            .unwrap_err();
+    }
+}
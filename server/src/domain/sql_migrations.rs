@@ -1,11 +1,25 @@
+use std::{
+    cmp::Ordering,
+    collections::{BTreeMap, HashMap},
+};
+
 use crate::domain::{
     sql_tables::{DbConnection, SchemaVersion},
-    types::{GroupId, UserId, Uuid},
+    totp_secret::ENCRYPTED_PREFIX,
+    types::{GroupId, UserId, Uuid, PASSWORD_MANAGER_GROUP_NAME},
+};
+use crate::infra::configuration::UuidBackfill;
+use futures::future::BoxFuture;
+use sea_orm::{
+    ConnectionTrait, DatabaseTransaction, DbBackend, DbErr, FromQueryResult, Statement,
+    TransactionTrait,
+};
+use sea_query::{
+    Alias, ColumnDef, Cond, Expr, ForeignKey, ForeignKeyAction, Iden, Index, IntoColumnRef,
+    IntoIden, Query, SimpleExpr, Table, Value,
 };
-use sea_orm::{ConnectionTrait, FromQueryResult, Statement};
-use sea_query::{ColumnDef, Expr, ForeignKey, ForeignKeyAction, Iden, Query, Table, Value};
 use serde::{Deserialize, Serialize};
-use tracing::{instrument, warn};
+use tracing::{info, instrument, warn};
 
 #[derive(Iden, PartialEq, Eq, Debug, Serialize, Deserialize, Clone)]
 pub enum Users {
@@ -21,6 +35,19 @@ pub enum Users {
     TotpSecret,
     MfaType,
     Uuid,
+    DeletedAt,
+    PasswordChangedAt,
+    MustChangePassword,
+    ModifiedAt,
+    UidNumber,
+    GidNumber,
+    HomeDirectory,
+    LoginShell,
+    EmailVerified,
+    ScramSha256Verifier,
+    TotpParameters,
+    TotpLastCounter,
+    UserIdDisplay,
 }
 
 #[derive(Iden, PartialEq, Eq, Debug, Serialize, Deserialize, Clone)]
@@ -30,13 +57,234 @@ pub enum Groups {
     DisplayName,
     CreationDate,
     Uuid,
+    ModifiedAt,
+    GidNumber,
 }
 
-#[derive(Iden)]
+#[derive(Iden, Clone, Copy)]
 pub enum Memberships {
     Table,
     UserId,
     GroupId,
+    AssignedByRule,
+}
+
+#[derive(Iden)]
+pub enum GroupMemberships {
+    Table,
+    ParentGroupId,
+    ChildGroupId,
+}
+
+#[derive(Iden)]
+pub enum WebauthnCredentials {
+    Table,
+    Id,
+    CredentialId,
+    UserId,
+    PublicKey,
+    SignCount,
+    Attestation,
+    CreationDate,
+}
+
+#[derive(Iden)]
+pub enum MfaBackupCodes {
+    Table,
+    Id,
+    UserId,
+    CodeHash,
+    CreationDate,
+}
+
+#[derive(Iden)]
+pub enum Sequences {
+    Table,
+    Name,
+    NextValue,
+}
+
+#[derive(Iden)]
+pub enum FailedLoginAttempts {
+    Table,
+    UserId,
+    Count,
+    LastAttempt,
+    LockedUntil,
+}
+
+#[derive(Iden)]
+pub enum UserAttributeSchema {
+    Table,
+    AttributeName,
+    AttributeType,
+    IsList,
+    IsIndexed,
+}
+
+#[derive(Iden)]
+pub enum UserAttributes {
+    Table,
+    UserId,
+    AttributeName,
+    Value,
+}
+
+#[derive(Iden)]
+pub enum GroupAttributeSchema {
+    Table,
+    AttributeName,
+    AttributeType,
+    IsList,
+    IsIndexed,
+}
+
+#[derive(Iden)]
+pub enum GroupAttributes {
+    Table,
+    GroupId,
+    AttributeName,
+    Value,
+}
+
+#[derive(Iden)]
+pub enum AuditLog {
+    Table,
+    Id,
+    ActorUserId,
+    TargetUserId,
+    TargetGroupId,
+    AttributeName,
+    OldValueHash,
+    NewValueHash,
+    Timestamp,
+    Source,
+    CorrelationId,
+}
+
+#[derive(Iden)]
+pub enum WebhookDeliveries {
+    Table,
+    Id,
+    Url,
+    EventType,
+    Payload,
+    Attempts,
+    NextAttemptAt,
+    CreationDate,
+    LastError,
+}
+
+#[derive(Iden)]
+pub enum OidcClients {
+    Table,
+    ClientId,
+    ClientSecretHash,
+    RedirectUris,
+    AllowedScopes,
+    CreationDate,
+}
+
+#[derive(Iden)]
+pub enum OidcAuthorizationCodes {
+    Table,
+    CodeHash,
+    ClientId,
+    UserId,
+    RedirectUri,
+    Scopes,
+    Nonce,
+    ExpiryDate,
+}
+
+#[derive(Iden)]
+pub enum DeletionTombstones {
+    Table,
+    Id,
+    UserId,
+    GroupId,
+    Uuid,
+    DeletedAt,
+}
+
+#[derive(Iden)]
+pub enum SudoRoles {
+    Table,
+    RoleId,
+    Name,
+    CreationDate,
+    ModifiedAt,
+}
+
+#[derive(Iden)]
+pub enum SudoRoleValues {
+    Table,
+    RoleId,
+    Attribute,
+    Position,
+    Value,
+}
+
+#[derive(Iden)]
+pub enum GroupRules {
+    Table,
+    RuleId,
+    GroupId,
+    CreationDate,
+}
+
+#[derive(Iden)]
+pub enum GroupRuleConditions {
+    Table,
+    RuleId,
+    Position,
+    Attribute,
+    Operator,
+    Value,
+}
+
+#[derive(Iden)]
+pub enum EmailVerificationTokens {
+    Table,
+    TokenHash,
+    UserId,
+    Email,
+    ExpiryDate,
+}
+
+/// The refresh tokens before they were rotated, replaced by `RefreshTokens`.
+#[derive(Iden)]
+pub enum JwtRefreshStorage {
+    Table,
+}
+
+/// Contains the refresh tokens of the users. A token is replaced by a new one of the same family
+/// each time it is used, and kept as revoked until it expires to detect its reuse.
+#[derive(Iden)]
+pub enum RefreshTokens {
+    Table,
+    /// The SHA-256 hash of the token.
+    TokenHash,
+    /// The hash of the first token of the family, issued at login: the ID of the session.
+    FamilyId,
+    UserId,
+    IssuedAt,
+    ExpiryDate,
+    Revoked,
+}
+
+/// Contains the sessions of the users, one per family of refresh tokens.
+#[derive(Iden)]
+pub enum Sessions {
+    Table,
+    SessionId,
+    UserId,
+    CreationDate,
+    LastSeen,
+    ExpiryDate,
+    IpAddress,
+    UserAgent,
+    Revoked,
 }
 
 // Metadata about the SQL DB.
@@ -47,13 +295,31 @@ pub enum Metadata {
     Version,
 }
 
+// History of the migrations applied to the DB.
+#[derive(Iden)]
+pub enum MigrationHistory {
+    Table,
+    Version,
+    AppliedAt,
+    // Version of LLDAP that applied the migration.
+    LldapVersion,
+}
+
+// On SQLite, the row that stands for the migration lock.
+#[derive(Iden)]
+pub enum MigrationLocks {
+    Table,
+    LockId,
+    LockedAt,
+}
+
 #[derive(FromQueryResult, PartialEq, Eq, Debug)]
 pub struct JustSchemaVersion {
     pub version: SchemaVersion,
 }
 
 #[instrument(skip_all, level = "debug", ret)]
-pub async fn get_schema_version(pool: &DbConnection) -> Option<SchemaVersion> {
+pub async fn get_schema_version(pool: &impl ConnectionTrait) -> Option<SchemaVersion> {
     JustSchemaVersion::find_by_statement(
         pool.get_database_backend().build(
             Query::select()
@@ -68,18 +334,311 @@ pub async fn get_schema_version(pool: &DbConnection) -> Option<SchemaVersion> {
     .map(|j| j.version)
 }
 
-pub async fn upgrade_to_v1(pool: &DbConnection) -> std::result::Result<(), sea_orm::DbErr> {
+#[derive(FromQueryResult, PartialEq, Eq, Debug, Clone)]
+pub struct MigrationRecord {
+    pub version: SchemaVersion,
+    pub applied_at: chrono::DateTime<chrono::Utc>,
+    pub lldap_version: String,
+}
+
+#[instrument(skip_all, level = "debug", ret, err)]
+pub async fn get_migration_history(pool: &DbConnection) -> Result<Vec<MigrationRecord>, DbErr> {
+    MigrationRecord::find_by_statement(
+        pool.get_database_backend().build(
+            Query::select()
+                .from(MigrationHistory::Table)
+                .columns(vec![
+                    MigrationHistory::Version,
+                    MigrationHistory::AppliedAt,
+                    MigrationHistory::LldapVersion,
+                ])
+                .order_by(MigrationHistory::AppliedAt, sea_query::Order::Asc)
+                .order_by(MigrationHistory::Version, sea_query::Order::Asc),
+        ),
+    )
+    .all(pool)
+    .await
+}
+
+/// Creates the migration history table if needed. DBs that were created before the history was
+/// introduced will only have the history of the later migrations.
+pub async fn create_migration_history_table(pool: &DbConnection) -> Result<(), DbErr> {
+    pool.execute(
+        pool.get_database_backend().build(
+            Table::create()
+                .table(MigrationHistory::Table)
+                .if_not_exists()
+                .col(
+                    ColumnDef::new(MigrationHistory::Version)
+                        .tiny_integer()
+                        .not_null(),
+                )
+                .col(
+                    ColumnDef::new(MigrationHistory::AppliedAt)
+                        .date_time()
+                        .not_null(),
+                )
+                .col(
+                    ColumnDef::new(MigrationHistory::LldapVersion)
+                        .string_len(64)
+                        .not_null(),
+                ),
+        ),
+    )
+    .await?;
+    Ok(())
+}
+
+async fn record_migration(
+    connection: &impl ConnectionTrait,
+    version: SchemaVersion,
+) -> Result<(), DbErr> {
+    connection
+        .execute(
+            connection.get_database_backend().build(
+                Query::insert()
+                    .into_table(MigrationHistory::Table)
+                    .columns(vec![
+                        MigrationHistory::Version,
+                        MigrationHistory::AppliedAt,
+                        MigrationHistory::LldapVersion,
+                    ])
+                    .values_panic(vec![
+                        version.into(),
+                        chrono::Utc::now().naive_utc().into(),
+                        env!("CARGO_PKG_VERSION").into(),
+                    ]),
+            ),
+        )
+        .await?;
+    Ok(())
+}
+
+async fn remove_migration_record(
+    connection: &impl ConnectionTrait,
+    version: SchemaVersion,
+) -> Result<(), DbErr> {
+    connection
+        .execute(
+            connection.get_database_backend().build(
+                Query::delete()
+                    .from_table(MigrationHistory::Table)
+                    .and_where(Expr::col(MigrationHistory::Version).eq(version)),
+            ),
+        )
+        .await?;
+    Ok(())
+}
+
+/// Derives the UUID from the name and creation date, unless that UUID was already generated for
+/// another entity (same name and date), in which case a random one is used instead. With
+/// `UuidBackfill::Random`, they are all random.
+fn generate_unique_uuid<Id: std::fmt::Debug>(
+    generated_uuids: &mut HashMap<Uuid, Id>,
+    uuid_backfill: UuidBackfill,
+    id: Id,
+    name: &str,
+    creation_date: &chrono::DateTime<chrono::Utc>,
+) -> Uuid {
+    if uuid_backfill == UuidBackfill::Random {
+        return Uuid::random();
+    }
+    let mut uuid = Uuid::from_name_and_date(name, creation_date);
+    if let Some(other_id) = generated_uuids.get(&uuid) {
+        warn!(
+            "UUID collision between {:?} and {:?} (same name and creation date), using a random UUID for {:?}",
+            other_id, id, id
+        );
+        uuid = Uuid::random();
+    }
+    generated_uuids.insert(uuid.clone(), id);
+    uuid
+}
+
+/// Whether the `uuid` column of the table has the native `uuid` type: it does on PostgreSQL from
+/// the start, while the DBs created before the schema versioning have a string column until the
+/// version 6.
+async fn has_native_uuid_column<C>(conn: &C, table: &str) -> Result<bool, DbErr>
+where
+    C: ConnectionTrait,
+{
+    if conn.get_database_backend() != DbBackend::Postgres {
+        return Ok(false);
+    }
+    #[derive(FromQueryResult)]
+    struct JustDataType {
+        data_type: String,
+    }
+    Ok(
+        JustDataType::find_by_statement(Statement::from_sql_and_values(
+            DbBackend::Postgres,
+            r#"SELECT data_type::text AS data_type FROM information_schema.columns
+           WHERE table_schema = current_schema() AND table_name = $1 AND column_name = 'uuid'"#,
+            vec![table.into()],
+        ))
+        .one(conn)
+        .await?
+        .map(|column| column.data_type == "uuid")
+        .unwrap_or(false),
+    )
+}
+
+/// The rows whose UUID the backfill sets: those left empty, or all of them with
+/// `UuidBackfill::Random`. `None` if there are none: a native `uuid` column can't be empty.
+fn uuids_to_backfill(
+    uuid_backfill: UuidBackfill,
+    native_uuid_column: bool,
+    uuid_column: impl IntoColumnRef,
+) -> Option<Cond> {
+    match (uuid_backfill, native_uuid_column) {
+        (UuidBackfill::Random, _) => Some(Cond::all()),
+        (UuidBackfill::Deterministic, true) => None,
+        (UuidBackfill::Deterministic, false) => {
+            Some(Cond::all().add(Expr::col(uuid_column).eq("")))
+        }
+    }
+}
+
+/// The new value of a `uuid` column, cast to the native type if needed.
+fn backfilled_uuid(uuid: Uuid, native_uuid_column: bool) -> SimpleExpr {
+    if native_uuid_column {
+        uuid.into_expr()
+    } else {
+        Value::from(uuid).into()
+    }
+}
+
+/// UUIDs are stored in a native `uuid` column on PostgreSQL, and as strings elsewhere.
+fn uuid_column(builder: DbBackend, column: impl IntoIden) -> ColumnDef {
+    let mut column = ColumnDef::new(column);
+    match builder {
+        DbBackend::Postgres => column.uuid(),
+        DbBackend::MySql | DbBackend::Sqlite => column.string_len(36),
+    };
+    column
+}
+
+/// MySQL doesn't give an integer primary key the rowid semantics of SQLite: the group IDs need
+/// an explicit `AUTO_INCREMENT`.
+fn group_id_column(builder: DbBackend) -> ColumnDef {
+    let mut column = ColumnDef::new(Groups::GroupId);
+    column.integer().not_null();
+    if builder == DbBackend::MySql {
+        column.auto_increment();
+    }
+    column.primary_key();
+    column
+}
+
+/// The collation of the group names on MySQL, explicit rather than the server default (which
+/// may be binary), so that two groups can't differ only by case.
+const MYSQL_GROUP_NAME_COLLATION: &str = "utf8mb4_unicode_ci";
+
+/// The name of the sequence of group IDs, in the `sequences` table.
+pub const GROUP_ID_SEQUENCE: &str = "group_id";
+
+/// The sequences of the POSIX uidNumbers and gidNumbers. They start at 0, i.e. at the beginning
+/// of the configured range.
+pub const UID_NUMBER_SEQUENCE: &str = "uid_number";
+pub const GID_NUMBER_SEQUENCE: &str = "gid_number";
+
+fn group_display_name_column(builder: DbBackend) -> ColumnDef {
+    let mut column = ColumnDef::new(Groups::DisplayName);
+    column.string_len(255).unique_key().not_null();
+    if builder == DbBackend::MySql {
+        column.extra(format!("COLLATE {}", MYSQL_GROUP_NAME_COLLATION));
+    }
+    column
+}
+
+/// Runs a statement that is allowed to fail, in a savepoint so that a failure doesn't abort the
+/// enclosing transaction (on PostgreSQL). Returns whether it succeeded.
+async fn try_execute<C>(conn: &C, statement: Statement) -> Result<bool, DbErr>
+where
+    C: ConnectionTrait + TransactionTrait,
+{
+    let savepoint = conn.begin().await?;
+    if savepoint.execute(statement).await.is_ok() {
+        savepoint.commit().await?;
+        Ok(true)
+    } else {
+        savepoint.rollback().await?;
+        Ok(false)
+    }
+}
+
+/// The metadata table is created first and the version is written last: a table without a
+/// version is the trace of an initialization that didn't complete.
+async fn check_v1_not_interrupted(pool: &DbConnection) -> Result<(), DbErr> {
+    let versions = JustSchemaVersion::find_by_statement(
+        pool.get_database_backend().build(
+            Query::select()
+                .from(Metadata::Table)
+                .column(Metadata::Version),
+        ),
+    )
+    .all(pool)
+    .await;
+    match versions {
+        Ok(versions) if versions.is_empty() => Err(DbErr::Custom(
+            "A previous initialization of the DB was interrupted and the tables may be partially \
+             created: restore a backup, or drop the tables to start over"
+                .to_owned(),
+        )),
+        // No metadata table: a new DB, or one created before the versioning.
+        _ => Ok(()),
+    }
+}
+
+/// Creates the tables, or completes those of a DB created before the schema versioning.
+///
+/// PostgreSQL and SQLite run it all in a single transaction, rolled back on failure. MySQL
+/// commits every DDL statement, so an interrupted run is only detected by the next one.
+///
+/// The users and groups without a UUID get one as per `uuid_backfill`.
+pub async fn upgrade_to_v1(
+    pool: &DbConnection,
+    uuid_backfill: UuidBackfill,
+) -> std::result::Result<(), sea_orm::DbErr> {
     let builder = pool.get_database_backend();
     // SQLite needs this pragma to be turned on. Other DB might not understand this, so ignore the
-    // error.
+    // error. It has no effect inside a transaction.
     let _ = pool
         .execute(Statement::from_string(
             builder,
             "PRAGMA foreign_keys = ON".to_owned(),
         ))
         .await;
+    check_v1_not_interrupted(pool).await?;
+    if builder == DbBackend::MySql {
+        return create_v1_tables(pool, uuid_backfill).await;
+    }
+    let transaction = pool.begin().await?;
+    match create_v1_tables(&transaction, uuid_backfill).await {
+        Ok(()) => transaction.commit().await,
+        Err(e) => {
+            transaction.rollback().await?;
+            Err(e)
+        }
+    }
+}
 
-    pool.execute(
+async fn create_v1_tables<C>(conn: &C, uuid_backfill: UuidBackfill) -> Result<(), DbErr>
+where
+    C: ConnectionTrait + TransactionTrait,
+{
+    let builder = conn.get_database_backend();
+    conn.execute(
+        builder.build(
+            Table::create()
+                .table(Metadata::Table)
+                .if_not_exists()
+                .col(ColumnDef::new(Metadata::Version).tiny_integer()),
+        ),
+    )
+    .await?;
+
+    conn.execute(
         builder.build(
             Table::create()
                 .table(Users::Table)
@@ -103,97 +662,100 @@ pub async fn upgrade_to_v1(pool: &DbConnection) -> std::result::Result<(), sea_o
                 .col(ColumnDef::new(Users::PasswordHash).binary())
                 .col(ColumnDef::new(Users::TotpSecret).string_len(64))
                 .col(ColumnDef::new(Users::MfaType).string_len(64))
-                .col(ColumnDef::new(Users::Uuid).string_len(36).not_null()),
+                .col(uuid_column(builder, Users::Uuid).not_null()),
         ),
     )
     .await?;
 
-    pool.execute(
+    conn.execute(
         builder.build(
             Table::create()
                 .table(Groups::Table)
                 .if_not_exists()
-                .col(
-                    ColumnDef::new(Groups::GroupId)
-                        .integer()
-                        .not_null()
-                        .primary_key(),
-                )
-                .col(
-                    ColumnDef::new(Groups::DisplayName)
-                        .string_len(255)
-                        .unique_key()
-                        .not_null(),
-                )
+                .col(&mut group_id_column(builder))
+                .col(&mut group_display_name_column(builder))
                 .col(ColumnDef::new(Users::CreationDate).date_time().not_null())
-                .col(ColumnDef::new(Users::Uuid).string_len(36).not_null()),
+                .col(uuid_column(builder, Users::Uuid).not_null()),
         ),
     )
     .await?;
 
-    // If the creation_date column doesn't exist, add it.
-    if pool
-        .execute(
-            builder.build(
-                Table::alter().table(Groups::Table).add_column(
-                    ColumnDef::new(Groups::CreationDate)
-                        .date_time()
-                        .not_null()
-                        .default(chrono::Utc::now().naive_utc()),
-                ),
+    // If the creation_date column doesn't exist, add it. The actual creation dates are unknown:
+    // the existing groups all get the current date.
+    let backfilled_creation_date = chrono::Utc::now().naive_utc();
+    if try_execute(
+        conn,
+        builder.build(
+            Table::alter().table(Groups::Table).add_column(
+                ColumnDef::new(Groups::CreationDate)
+                    .date_time()
+                    .not_null()
+                    .default(backfilled_creation_date),
             ),
-        )
-        .await
-        .is_ok()
+        ),
+    )
+    .await?
     {
-        warn!("`creation_date` column not found in `groups`, creating it");
+        warn!(
+            "`creation_date` column not found in `groups`, creating it: the existing groups get \
+             the current date ({}) as their creation date, not their actual one, and their UUIDs \
+             are derived from it",
+            backfilled_creation_date
+        );
     }
 
     // If the uuid column doesn't exist, add it.
-    if pool
-        .execute(
-            builder.build(
-                Table::alter().table(Groups::Table).add_column(
-                    ColumnDef::new(Groups::Uuid)
-                        .string_len(36)
-                        .not_null()
-                        .default(""),
-                ),
+    if try_execute(
+        conn,
+        builder.build(
+            Table::alter().table(Groups::Table).add_column(
+                ColumnDef::new(Groups::Uuid)
+                    .string_len(36)
+                    .not_null()
+                    .default(""),
             ),
-        )
-        .await
-        .is_ok()
+        ),
+    )
+    .await?
     {
         warn!("`uuid` column not found in `groups`, creating it");
-        #[derive(FromQueryResult)]
-        struct ShortGroupDetails {
-            group_id: GroupId,
-            display_name: String,
-            creation_date: chrono::DateTime<chrono::Utc>,
-        }
+    }
+    // Fill the UUIDs left empty, e.g. by the new column, and keep those already set, unless
+    // they are all replaced with random ones.
+    #[derive(FromQueryResult)]
+    struct ShortGroupDetails {
+        group_id: GroupId,
+        display_name: String,
+        creation_date: chrono::DateTime<chrono::Utc>,
+    }
+    let native_uuid_column = has_native_uuid_column(conn, "groups").await?;
+    let mut generated_uuids = HashMap::new();
+    if let Some(condition) = uuids_to_backfill(uuid_backfill, native_uuid_column, Groups::Uuid) {
         for result in ShortGroupDetails::find_by_statement(
             builder.build(
                 Query::select()
                     .from(Groups::Table)
                     .column(Groups::GroupId)
                     .column(Groups::DisplayName)
-                    .column(Groups::CreationDate),
+                    .column(Groups::CreationDate)
+                    .cond_where(condition),
             ),
         )
-        .all(pool)
+        .all(conn)
         .await?
         {
-            pool.execute(
+            let uuid = generate_unique_uuid(
+                &mut generated_uuids,
+                uuid_backfill,
+                result.group_id,
+                &result.display_name,
+                &result.creation_date,
+            );
+            conn.execute(
                 builder.build(
                     Query::update()
                         .table(Groups::Table)
-                        .value(
-                            Groups::Uuid,
-                            Value::from(Uuid::from_name_and_date(
-                                &result.display_name,
-                                &result.creation_date,
-                            )),
-                        )
+                        .value(Groups::Uuid, backfilled_uuid(uuid, native_uuid_column))
                         .and_where(Expr::col(Groups::GroupId).eq(result.group_id)),
                 ),
             )
@@ -201,48 +763,53 @@ pub async fn upgrade_to_v1(pool: &DbConnection) -> std::result::Result<(), sea_o
         }
     }
 
-    if pool
-        .execute(
-            builder.build(
-                Table::alter().table(Users::Table).add_column(
-                    ColumnDef::new(Users::Uuid)
-                        .string_len(36)
-                        .not_null()
-                        .default(""),
-                ),
+    if try_execute(
+        conn,
+        builder.build(
+            Table::alter().table(Users::Table).add_column(
+                ColumnDef::new(Users::Uuid)
+                    .string_len(36)
+                    .not_null()
+                    .default(""),
             ),
-        )
-        .await
-        .is_ok()
+        ),
+    )
+    .await?
     {
         warn!("`uuid` column not found in `users`, creating it");
-        #[derive(FromQueryResult)]
-        struct ShortUserDetails {
-            user_id: UserId,
-            creation_date: chrono::DateTime<chrono::Utc>,
-        }
+    }
+    #[derive(FromQueryResult)]
+    struct ShortUserDetails {
+        user_id: UserId,
+        creation_date: chrono::DateTime<chrono::Utc>,
+    }
+    let native_uuid_column = has_native_uuid_column(conn, "users").await?;
+    let mut generated_uuids = HashMap::new();
+    if let Some(condition) = uuids_to_backfill(uuid_backfill, native_uuid_column, Users::Uuid) {
         for result in ShortUserDetails::find_by_statement(
             builder.build(
                 Query::select()
                     .from(Users::Table)
                     .column(Users::UserId)
-                    .column(Users::CreationDate),
+                    .column(Users::CreationDate)
+                    .cond_where(condition),
             ),
         )
-        .all(pool)
+        .all(conn)
         .await?
         {
-            pool.execute(
+            let uuid = generate_unique_uuid(
+                &mut generated_uuids,
+                uuid_backfill,
+                result.user_id.clone(),
+                result.user_id.as_str(),
+                &result.creation_date,
+            );
+            conn.execute(
                 builder.build(
                     Query::update()
                         .table(Users::Table)
-                        .value(
-                            Users::Uuid,
-                            Value::from(Uuid::from_name_and_date(
-                                result.user_id.as_str(),
-                                &result.creation_date,
-                            )),
-                        )
+                        .value(Users::Uuid, backfilled_uuid(uuid, native_uuid_column))
                         .and_where(Expr::col(Users::UserId).eq(result.user_id)),
                 ),
             )
@@ -250,7 +817,7 @@ pub async fn upgrade_to_v1(pool: &DbConnection) -> std::result::Result<(), sea_o
         }
     }
 
-    pool.execute(
+    conn.execute(
         builder.build(
             Table::create()
                 .table(Memberships::Table)
@@ -281,7 +848,7 @@ pub async fn upgrade_to_v1(pool: &DbConnection) -> std::result::Result<(), sea_o
     )
     .await?;
 
-    if pool
+    if conn
         .query_one(
             builder.build(
                 Query::select()
@@ -293,28 +860,24 @@ pub async fn upgrade_to_v1(pool: &DbConnection) -> std::result::Result<(), sea_o
         .await
         .is_ok()
     {
-        pool.execute(
+        conn.execute(
             builder.build(
                 Query::update()
                     .table(Groups::Table)
-                    .values(vec![(Groups::DisplayName, "lldap_password_manager".into())])
+                    .values(vec![(
+                        Groups::DisplayName,
+                        PASSWORD_MANAGER_GROUP_NAME.into(),
+                    )])
                     .cond_where(Expr::col(Groups::DisplayName).eq("lldap_readonly")),
             ),
         )
         .await?;
     }
 
-    pool.execute(
-        builder.build(
-            Table::create()
-                .table(Metadata::Table)
-                .if_not_exists()
-                .col(ColumnDef::new(Metadata::Version).tiny_integer()),
-        ),
-    )
-    .await?;
+    record_migration(conn, SchemaVersion(1)).await?;
 
-    pool.execute(
+    // Last, so that an interrupted run leaves the version unset.
+    conn.execute(
         builder.build(
             Query::insert()
                 .into_table(Metadata::Table)
@@ -324,17 +887,2628 @@ pub async fn upgrade_to_v1(pool: &DbConnection) -> std::result::Result<(), sea_o
     )
     .await?;
 
-    assert_eq!(get_schema_version(pool).await.unwrap().0, 1);
+    assert_eq!(get_schema_version(conn).await.unwrap().0, 1);
 
     Ok(())
 }
 
-pub async fn migrate_from_version(
-    _pool: &DbConnection,
-    version: SchemaVersion,
-) -> anyhow::Result<()> {
-    if version.0 > 1 {
-        anyhow::bail!("DB version downgrading is not supported");
+/// Adds the custom user attributes.
+fn upgrade_to_v2(transaction: &DatabaseTransaction) -> BoxFuture<'_, Result<(), DbErr>> {
+    Box::pin(async move {
+        let builder = transaction.get_database_backend();
+        transaction
+            .execute(
+                builder.build(
+                    Table::create()
+                        .table(UserAttributeSchema::Table)
+                        .if_not_exists()
+                        .col(
+                            // Stored lowercase, to make the names case-insensitive.
+                            ColumnDef::new(UserAttributeSchema::AttributeName)
+                                .string_len(64)
+                                .not_null()
+                                .primary_key(),
+                        )
+                        .col(
+                            ColumnDef::new(UserAttributeSchema::AttributeType)
+                                .string_len(64)
+                                .not_null(),
+                        )
+                        .col(
+                            ColumnDef::new(UserAttributeSchema::IsList)
+                                .boolean()
+                                .not_null(),
+                        )
+                        .col(
+                            ColumnDef::new(UserAttributeSchema::IsIndexed)
+                                .boolean()
+                                .not_null(),
+                        ),
+                ),
+            )
+            .await?;
+        transaction
+            .execute(
+                builder.build(
+                    Table::create()
+                        .table(UserAttributes::Table)
+                        .if_not_exists()
+                        .col(
+                            ColumnDef::new(UserAttributes::UserId)
+                                .string_len(255)
+                                .not_null(),
+                        )
+                        .col(
+                            ColumnDef::new(UserAttributes::AttributeName)
+                                .string_len(64)
+                                .not_null(),
+                        )
+                        .col(ColumnDef::new(UserAttributes::Value).binary().not_null())
+                        .foreign_key(
+                            ForeignKey::create()
+                                .name("UserAttributesUserForeignKey")
+                                .from(UserAttributes::Table, UserAttributes::UserId)
+                                .to(Users::Table, Users::UserId)
+                                .on_delete(ForeignKeyAction::Cascade)
+                                .on_update(ForeignKeyAction::Cascade),
+                        )
+                        .foreign_key(
+                            ForeignKey::create()
+                                .name("UserAttributesAttributeNameForeignKey")
+                                .from(UserAttributes::Table, UserAttributes::AttributeName)
+                                .to(
+                                    UserAttributeSchema::Table,
+                                    UserAttributeSchema::AttributeName,
+                                )
+                                .on_delete(ForeignKeyAction::Cascade)
+                                .on_update(ForeignKeyAction::Cascade),
+                        ),
+                ),
+            )
+            .await?;
+        Ok(())
+    })
+}
+
+fn downgrade_from_v2(transaction: &DatabaseTransaction) -> BoxFuture<'_, Result<(), DbErr>> {
+    Box::pin(async move {
+        let builder = transaction.get_database_backend();
+        transaction
+            .execute(builder.build(Table::drop().table(UserAttributes::Table)))
+            .await?;
+        transaction
+            .execute(builder.build(Table::drop().table(UserAttributeSchema::Table)))
+            .await?;
+        Ok(())
+    })
+}
+
+/// Adds the custom group attributes.
+fn upgrade_to_v3(transaction: &DatabaseTransaction) -> BoxFuture<'_, Result<(), DbErr>> {
+    Box::pin(async move {
+        let builder = transaction.get_database_backend();
+        transaction
+            .execute(
+                builder.build(
+                    Table::create()
+                        .table(GroupAttributeSchema::Table)
+                        .if_not_exists()
+                        .col(
+                            ColumnDef::new(GroupAttributeSchema::AttributeName)
+                                .string_len(64)
+                                .not_null()
+                                .primary_key(),
+                        )
+                        .col(
+                            ColumnDef::new(GroupAttributeSchema::AttributeType)
+                                .string_len(64)
+                                .not_null(),
+                        )
+                        .col(
+                            ColumnDef::new(GroupAttributeSchema::IsList)
+                                .boolean()
+                                .not_null(),
+                        )
+                        .col(
+                            ColumnDef::new(GroupAttributeSchema::IsIndexed)
+                                .boolean()
+                                .not_null(),
+                        ),
+                ),
+            )
+            .await?;
+        transaction
+            .execute(
+                builder.build(
+                    Table::create()
+                        .table(GroupAttributes::Table)
+                        .if_not_exists()
+                        .col(
+                            ColumnDef::new(GroupAttributes::GroupId)
+                                .integer()
+                                .not_null(),
+                        )
+                        .col(
+                            ColumnDef::new(GroupAttributes::AttributeName)
+                                .string_len(64)
+                                .not_null(),
+                        )
+                        .col(ColumnDef::new(GroupAttributes::Value).binary().not_null())
+                        .foreign_key(
+                            ForeignKey::create()
+                                .name("GroupAttributesGroupForeignKey")
+                                .from(GroupAttributes::Table, GroupAttributes::GroupId)
+                                .to(Groups::Table, Groups::GroupId)
+                                .on_delete(ForeignKeyAction::Cascade)
+                                .on_update(ForeignKeyAction::Cascade),
+                        )
+                        .foreign_key(
+                            ForeignKey::create()
+                                .name("GroupAttributesAttributeNameForeignKey")
+                                .from(GroupAttributes::Table, GroupAttributes::AttributeName)
+                                .to(
+                                    GroupAttributeSchema::Table,
+                                    GroupAttributeSchema::AttributeName,
+                                )
+                                .on_delete(ForeignKeyAction::Cascade)
+                                .on_update(ForeignKeyAction::Cascade),
+                        ),
+                ),
+            )
+            .await?;
+        Ok(())
+    })
+}
+
+fn downgrade_from_v3(transaction: &DatabaseTransaction) -> BoxFuture<'_, Result<(), DbErr>> {
+    Box::pin(async move {
+        let builder = transaction.get_database_backend();
+        transaction
+            .execute(builder.build(Table::drop().table(GroupAttributes::Table)))
+            .await?;
+        transaction
+            .execute(builder.build(Table::drop().table(GroupAttributeSchema::Table)))
+            .await?;
+        Ok(())
+    })
+}
+
+#[derive(FromQueryResult)]
+struct UserEmail {
+    user_id: UserId,
+    email: String,
+}
+
+async fn get_user_emails(connection: &impl ConnectionTrait) -> Result<Vec<UserEmail>, DbErr> {
+    UserEmail::find_by_statement(
+        connection.get_database_backend().build(
+            Query::select()
+                .from(Users::Table)
+                .column(Users::UserId)
+                .column(Users::Email),
+        ),
+    )
+    .all(connection)
+    .await
+}
+
+/// Lists the (non-empty) emails that are shared by several users, along with these users.
+pub async fn find_duplicate_emails(
+    connection: &impl ConnectionTrait,
+    normalize: impl Fn(String) -> String,
+) -> Result<Vec<(String, Vec<UserId>)>, DbErr> {
+    let mut users_by_email = BTreeMap::<String, Vec<UserId>>::new();
+    for user in get_user_emails(connection).await? {
+        if user.email.is_empty() {
+            continue;
+        }
+        users_by_email
+            .entry(normalize(user.email))
+            .or_default()
+            .push(user.user_id);
     }
-    Ok(())
+    Ok(users_by_email
+        .into_iter()
+        .filter(|(_, users)| users.len() > 1)
+        .collect())
+}
+
+fn describe_duplicate_emails(duplicates: &[(String, Vec<UserId>)]) -> String {
+    duplicates
+        .iter()
+        .map(|(email, users)| {
+            format!(
+                "'{}' is used by {}",
+                email,
+                users
+                    .iter()
+                    .map(|u| format!("'{}'", u))
+                    .collect::<Vec<_>>()
+                    .join(", ")
+            )
+        })
+        .collect::<Vec<_>>()
+        .join("; ")
+}
+
+const USER_EMAIL_INDEX: &str = "unique_user_email";
+
+/// Makes the (non-empty) user emails unique.
+fn upgrade_to_v4(transaction: &DatabaseTransaction) -> BoxFuture<'_, Result<(), DbErr>> {
+    Box::pin(async move {
+        let duplicates = find_duplicate_emails(transaction, |email| email).await?;
+        if !duplicates.is_empty() {
+            return Err(DbErr::Custom(format!(
+                "Cannot make the user emails unique, some users share the same email: {}. \
+                 Change their emails, then restart LLDAP",
+                describe_duplicate_emails(&duplicates)
+            )));
+        }
+        let builder = transaction.get_database_backend();
+        match builder {
+            DbBackend::MySql => {
+                // MySQL doesn't support partial indices, but (since 8.0.13) supports functional
+                // ones. MariaDB doesn't, in which case only LLDAP enforces the uniqueness.
+                if let Err(e) = transaction
+                    .execute(Statement::from_string(
+                        builder,
+                        format!(
+                            "CREATE UNIQUE INDEX {} ON users ((NULLIF(email, '')))",
+                            USER_EMAIL_INDEX
+                        ),
+                    ))
+                    .await
+                {
+                    warn!(
+                        "Could not create a unique index on the user emails, uniqueness won't be enforced by the DB: {}",
+                        e
+                    );
+                }
+            }
+            DbBackend::Postgres | DbBackend::Sqlite => {
+                // Several users can have no email.
+                transaction
+                    .execute(Statement::from_string(
+                        builder,
+                        format!(
+                            "CREATE UNIQUE INDEX IF NOT EXISTS {} ON users (email) WHERE email <> ''",
+                            USER_EMAIL_INDEX
+                        ),
+                    ))
+                    .await?;
+            }
+        }
+        Ok(())
+    })
+}
+
+fn downgrade_from_v4(transaction: &DatabaseTransaction) -> BoxFuture<'_, Result<(), DbErr>> {
+    Box::pin(async move {
+        let builder = transaction.get_database_backend();
+        let result = transaction
+            .execute(builder.build(Index::drop().name(USER_EMAIL_INDEX).table(Users::Table)))
+            .await;
+        // The index might not have been created on MySQL.
+        if builder != DbBackend::MySql {
+            result?;
+        }
+        Ok(())
+    })
+}
+
+/// Adds the soft-deletion date of users.
+fn upgrade_to_v5(transaction: &DatabaseTransaction) -> BoxFuture<'_, Result<(), DbErr>> {
+    Box::pin(async move {
+        let builder = transaction.get_database_backend();
+        transaction
+            .execute(
+                builder.build(
+                    Table::alter()
+                        .table(Users::Table)
+                        .add_column(ColumnDef::new(Users::DeletedAt).date_time().null()),
+                ),
+            )
+            .await?;
+        Ok(())
+    })
+}
+
+fn downgrade_from_v5(transaction: &DatabaseTransaction) -> BoxFuture<'_, Result<(), DbErr>> {
+    Box::pin(async move {
+        let builder = transaction.get_database_backend();
+        // Otherwise the soft-deleted users would come back.
+        transaction
+            .execute(
+                builder.build(
+                    Query::delete()
+                        .from_table(Users::Table)
+                        .and_where(Expr::col(Users::DeletedAt).is_not_null()),
+                ),
+            )
+            .await?;
+        transaction
+            .execute(
+                builder.build(
+                    Table::alter()
+                        .table(Users::Table)
+                        .drop_column(Users::DeletedAt),
+                ),
+            )
+            .await?;
+        Ok(())
+    })
+}
+
+/// Converts the string UUID columns to the native `uuid` type on PostgreSQL. Other backends don't
+/// have a native type, so they keep the strings.
+fn upgrade_to_v6(transaction: &DatabaseTransaction) -> BoxFuture<'_, Result<(), DbErr>> {
+    Box::pin(async move {
+        if transaction.get_database_backend() == DbBackend::Postgres {
+            for table in ["users", "groups"] {
+                transaction
+                    .execute(Statement::from_string(
+                        DbBackend::Postgres,
+                        format!(
+                            r#"ALTER TABLE "{}" ALTER COLUMN "uuid" DROP DEFAULT, ALTER COLUMN "uuid" TYPE uuid USING "uuid"::uuid"#,
+                            table
+                        ),
+                    ))
+                    .await?;
+            }
+        }
+        Ok(())
+    })
+}
+
+fn downgrade_from_v6(transaction: &DatabaseTransaction) -> BoxFuture<'_, Result<(), DbErr>> {
+    Box::pin(async move {
+        if transaction.get_database_backend() == DbBackend::Postgres {
+            for table in ["users", "groups"] {
+                transaction
+                    .execute(Statement::from_string(
+                        DbBackend::Postgres,
+                        format!(
+                            r#"ALTER TABLE "{}" ALTER COLUMN "uuid" TYPE varchar(36) USING "uuid"::text"#,
+                            table
+                        ),
+                    ))
+                    .await?;
+            }
+        }
+        Ok(())
+    })
+}
+
+const MEMBERSHIP_INDEXES: [(&str, Memberships, Memberships); 2] = [
+    (
+        "membership_group_user",
+        Memberships::GroupId,
+        Memberships::UserId,
+    ),
+    (
+        "membership_user_group",
+        Memberships::UserId,
+        Memberships::GroupId,
+    ),
+];
+
+/// Indexes the memberships both ways, to list the members of a group and the groups of a user.
+fn upgrade_to_v7(transaction: &DatabaseTransaction) -> BoxFuture<'_, Result<(), DbErr>> {
+    Box::pin(async move {
+        let builder = transaction.get_database_backend();
+        for (name, first, second) in MEMBERSHIP_INDEXES {
+            transaction
+                .execute(
+                    builder.build(
+                        Index::create()
+                            .name(name)
+                            .table(Memberships::Table)
+                            .col(first)
+                            .col(second),
+                    ),
+                )
+                .await?;
+        }
+        Ok(())
+    })
+}
+
+fn downgrade_from_v7(transaction: &DatabaseTransaction) -> BoxFuture<'_, Result<(), DbErr>> {
+    Box::pin(async move {
+        let builder = transaction.get_database_backend();
+        for (name, _, _) in MEMBERSHIP_INDEXES {
+            let result = transaction
+                .execute(builder.build(Index::drop().name(name).table(Memberships::Table)))
+                .await;
+            // MySQL refuses to drop an index that replaced the implicit foreign key index.
+            if builder != DbBackend::MySql {
+                result?;
+            }
+        }
+        Ok(())
+    })
+}
+
+/// Adds the nesting of groups inside other groups.
+fn upgrade_to_v8(transaction: &DatabaseTransaction) -> BoxFuture<'_, Result<(), DbErr>> {
+    Box::pin(async move {
+        let builder = transaction.get_database_backend();
+        transaction
+            .execute(
+                builder.build(
+                    Table::create()
+                        .table(GroupMemberships::Table)
+                        .if_not_exists()
+                        .col(
+                            ColumnDef::new(GroupMemberships::ParentGroupId)
+                                .integer()
+                                .not_null(),
+                        )
+                        .col(
+                            ColumnDef::new(GroupMemberships::ChildGroupId)
+                                .integer()
+                                .not_null(),
+                        )
+                        .primary_key(
+                            Index::create()
+                                .col(GroupMemberships::ParentGroupId)
+                                .col(GroupMemberships::ChildGroupId),
+                        )
+                        .foreign_key(
+                            ForeignKey::create()
+                                .name("GroupMembershipParentForeignKey")
+                                .from(GroupMemberships::Table, GroupMemberships::ParentGroupId)
+                                .to(Groups::Table, Groups::GroupId)
+                                .on_delete(ForeignKeyAction::Cascade)
+                                .on_update(ForeignKeyAction::Cascade),
+                        )
+                        .foreign_key(
+                            ForeignKey::create()
+                                .name("GroupMembershipChildForeignKey")
+                                .from(GroupMemberships::Table, GroupMemberships::ChildGroupId)
+                                .to(Groups::Table, Groups::GroupId)
+                                .on_delete(ForeignKeyAction::Cascade)
+                                .on_update(ForeignKeyAction::Cascade),
+                        ),
+                ),
+            )
+            .await?;
+        Ok(())
+    })
+}
+
+fn downgrade_from_v8(transaction: &DatabaseTransaction) -> BoxFuture<'_, Result<(), DbErr>> {
+    Box::pin(async move {
+        let builder = transaction.get_database_backend();
+        transaction
+            .execute(builder.build(Table::drop().table(GroupMemberships::Table)))
+            .await?;
+        Ok(())
+    })
+}
+
+/// Adds the WebAuthn credentials, usable as a second factor.
+fn upgrade_to_v9(transaction: &DatabaseTransaction) -> BoxFuture<'_, Result<(), DbErr>> {
+    Box::pin(async move {
+        let builder = transaction.get_database_backend();
+        transaction
+            .execute(
+                builder.build(
+                    Table::create()
+                        .table(WebauthnCredentials::Table)
+                        .if_not_exists()
+                        .col(
+                            ColumnDef::new(WebauthnCredentials::Id)
+                                .integer()
+                                .not_null()
+                                .auto_increment()
+                                .primary_key(),
+                        )
+                        // Up to 1023 bytes, too long to be a key in MySQL.
+                        .col(
+                            ColumnDef::new(WebauthnCredentials::CredentialId)
+                                .binary()
+                                .not_null(),
+                        )
+                        .col(
+                            ColumnDef::new(WebauthnCredentials::UserId)
+                                .string_len(255)
+                                .not_null(),
+                        )
+                        .col(
+                            ColumnDef::new(WebauthnCredentials::PublicKey)
+                                .binary()
+                                .not_null(),
+                        )
+                        .col(
+                            ColumnDef::new(WebauthnCredentials::SignCount)
+                                .big_integer()
+                                .not_null(),
+                        )
+                        .col(
+                            ColumnDef::new(WebauthnCredentials::Attestation)
+                                .binary()
+                                .not_null(),
+                        )
+                        .col(
+                            ColumnDef::new(WebauthnCredentials::CreationDate)
+                                .date_time()
+                                .not_null(),
+                        )
+                        .foreign_key(
+                            ForeignKey::create()
+                                .name("WebauthnCredentialsUserForeignKey")
+                                .from(WebauthnCredentials::Table, WebauthnCredentials::UserId)
+                                .to(Users::Table, Users::UserId)
+                                .on_delete(ForeignKeyAction::Cascade)
+                                .on_update(ForeignKeyAction::Cascade),
+                        ),
+                ),
+            )
+            .await?;
+        Ok(())
+    })
+}
+
+fn downgrade_from_v9(transaction: &DatabaseTransaction) -> BoxFuture<'_, Result<(), DbErr>> {
+    Box::pin(async move {
+        let builder = transaction.get_database_backend();
+        transaction
+            .execute(builder.build(Table::drop().table(WebauthnCredentials::Table)))
+            .await?;
+        Ok(())
+    })
+}
+
+/// Tracks the age of the passwords, and whether the user must change it.
+fn upgrade_to_v10(transaction: &DatabaseTransaction) -> BoxFuture<'_, Result<(), DbErr>> {
+    Box::pin(async move {
+        let builder = transaction.get_database_backend();
+        transaction
+            .execute(
+                builder.build(
+                    Table::alter()
+                        .table(Users::Table)
+                        .add_column(ColumnDef::new(Users::PasswordChangedAt).date_time().null()),
+                ),
+            )
+            .await?;
+        transaction
+            .execute(
+                builder.build(
+                    Table::alter().table(Users::Table).add_column(
+                        ColumnDef::new(Users::MustChangePassword)
+                            .boolean()
+                            .not_null()
+                            .default(false),
+                    ),
+                ),
+            )
+            .await?;
+        Ok(())
+    })
+}
+
+fn downgrade_from_v10(transaction: &DatabaseTransaction) -> BoxFuture<'_, Result<(), DbErr>> {
+    Box::pin(async move {
+        let builder = transaction.get_database_backend();
+        // SQLite can only drop one column at a time.
+        for column in [Users::PasswordChangedAt, Users::MustChangePassword] {
+            transaction
+                .execute(builder.build(Table::alter().table(Users::Table).drop_column(column)))
+                .await?;
+        }
+        Ok(())
+    })
+}
+
+/// Tracks the consecutive failed logins, to lock out accounts under attack.
+fn upgrade_to_v11(transaction: &DatabaseTransaction) -> BoxFuture<'_, Result<(), DbErr>> {
+    Box::pin(async move {
+        let builder = transaction.get_database_backend();
+        transaction
+            .execute(
+                builder.build(
+                    Table::create()
+                        .table(FailedLoginAttempts::Table)
+                        .if_not_exists()
+                        // No foreign key: unknown users are tracked as well, so that the lockout
+                        // doesn't reveal which users exist.
+                        .col(
+                            ColumnDef::new(FailedLoginAttempts::UserId)
+                                .string_len(255)
+                                .not_null()
+                                .primary_key(),
+                        )
+                        .col(
+                            ColumnDef::new(FailedLoginAttempts::Count)
+                                .integer()
+                                .not_null(),
+                        )
+                        .col(
+                            ColumnDef::new(FailedLoginAttempts::LastAttempt)
+                                .date_time()
+                                .not_null(),
+                        )
+                        .col(
+                            ColumnDef::new(FailedLoginAttempts::LockedUntil)
+                                .date_time()
+                                .null(),
+                        ),
+                ),
+            )
+            .await?;
+        Ok(())
+    })
+}
+
+fn downgrade_from_v11(transaction: &DatabaseTransaction) -> BoxFuture<'_, Result<(), DbErr>> {
+    Box::pin(async move {
+        let builder = transaction.get_database_backend();
+        transaction
+            .execute(builder.build(Table::drop().table(FailedLoginAttempts::Table)))
+            .await?;
+        Ok(())
+    })
+}
+
+/// Brings the groups table of existing MySQL databases in line with `upgrade_to_v1`: group IDs
+/// are auto-incremented and group names compared case-insensitively. No-op elsewhere.
+fn upgrade_to_v12(transaction: &DatabaseTransaction) -> BoxFuture<'_, Result<(), DbErr>> {
+    Box::pin(async move {
+        if transaction.get_database_backend() != DbBackend::MySql {
+            return Ok(());
+        }
+        // The memberships reference the group ID, which MySQL otherwise refuses to modify.
+        transaction
+            .execute(Statement::from_string(
+                DbBackend::MySql,
+                "SET FOREIGN_KEY_CHECKS = 0".to_owned(),
+            ))
+            .await?;
+        let result = transaction
+            .execute(Statement::from_string(
+                DbBackend::MySql,
+                format!(
+                    "ALTER TABLE `groups` MODIFY `group_id` INTEGER NOT NULL AUTO_INCREMENT, MODIFY `display_name` VARCHAR(255) NOT NULL COLLATE {}",
+                    MYSQL_GROUP_NAME_COLLATION
+                ),
+            ))
+            .await;
+        transaction
+            .execute(Statement::from_string(
+                DbBackend::MySql,
+                "SET FOREIGN_KEY_CHECKS = 1".to_owned(),
+            ))
+            .await?;
+        result?;
+        Ok(())
+    })
+}
+
+/// The auto-increment and the collation are compatible with the previous versions: nothing to
+/// undo.
+fn downgrade_from_v12(_: &DatabaseTransaction) -> BoxFuture<'_, Result<(), DbErr>> {
+    Box::pin(async move { Ok(()) })
+}
+
+/// Adds the sequences used to allocate IDs identically on all the backends, starting with the
+/// group IDs.
+fn upgrade_to_v13(transaction: &DatabaseTransaction) -> BoxFuture<'_, Result<(), DbErr>> {
+    Box::pin(async move {
+        let builder = transaction.get_database_backend();
+        transaction
+            .execute(
+                builder.build(
+                    Table::create()
+                        .table(Sequences::Table)
+                        .if_not_exists()
+                        .col(
+                            ColumnDef::new(Sequences::Name)
+                                .string_len(64)
+                                .not_null()
+                                .primary_key(),
+                        )
+                        .col(ColumnDef::new(Sequences::NextValue).integer().not_null()),
+                ),
+            )
+            .await?;
+        #[derive(FromQueryResult)]
+        struct MaxGroupId {
+            max_group_id: Option<i32>,
+        }
+        let max_group_id = MaxGroupId::find_by_statement(
+            builder.build(
+                Query::select()
+                    .from(Groups::Table)
+                    .expr_as(Expr::col(Groups::GroupId).max(), Alias::new("max_group_id")),
+            ),
+        )
+        .one(transaction)
+        .await?
+        .and_then(|m| m.max_group_id)
+        .unwrap_or(0);
+        transaction
+            .execute(
+                builder.build(
+                    Query::insert()
+                        .into_table(Sequences::Table)
+                        .columns([Sequences::Name, Sequences::NextValue])
+                        .values_panic([GROUP_ID_SEQUENCE.into(), (max_group_id + 1).into()]),
+                ),
+            )
+            .await?;
+        Ok(())
+    })
+}
+
+fn downgrade_from_v13(transaction: &DatabaseTransaction) -> BoxFuture<'_, Result<(), DbErr>> {
+    Box::pin(async move {
+        let builder = transaction.get_database_backend();
+        transaction
+            .execute(builder.build(Table::drop().table(Sequences::Table)))
+            .await?;
+        Ok(())
+    })
+}
+
+/// The user columns indexed with trigrams for the substring search.
+const TRIGRAM_INDEXED_USER_COLUMNS: [Users; 4] = [
+    Users::DisplayName,
+    Users::FirstName,
+    Users::LastName,
+    Users::Email,
+];
+
+fn user_trigram_index_name(column: &Users) -> String {
+    format!("users_{}_trgm", column.to_string())
+}
+
+/// Indexes the user display fields with trigrams on PostgreSQL, so that the substring searches
+/// don't scan the whole table. This needs the `pg_trgm` extension: if it can't be created (e.g.
+/// missing privileges), the search keeps working without the indexes. No-op elsewhere.
+fn upgrade_to_v14(transaction: &DatabaseTransaction) -> BoxFuture<'_, Result<(), DbErr>> {
+    Box::pin(async move {
+        if transaction.get_database_backend() != DbBackend::Postgres {
+            return Ok(());
+        }
+        // A failed statement aborts the whole transaction on PostgreSQL, hence the savepoint.
+        let savepoint = transaction.begin().await?;
+        if let Err(e) = savepoint
+            .execute(Statement::from_string(
+                DbBackend::Postgres,
+                "CREATE EXTENSION IF NOT EXISTS pg_trgm".to_owned(),
+            ))
+            .await
+        {
+            warn!(
+                "Could not create the pg_trgm extension, the user search will not be indexed: {}",
+                e
+            );
+            savepoint.rollback().await?;
+            return Ok(());
+        }
+        savepoint.commit().await?;
+        for column in &TRIGRAM_INDEXED_USER_COLUMNS {
+            transaction
+                .execute(Statement::from_string(
+                    DbBackend::Postgres,
+                    format!(
+                        r#"CREATE INDEX IF NOT EXISTS "{}" ON "users" USING gin (lower("{}") gin_trgm_ops)"#,
+                        user_trigram_index_name(column),
+                        column.to_string()
+                    ),
+                ))
+                .await?;
+        }
+        Ok(())
+    })
+}
+
+/// The extension is left installed, other schemas in the database may rely on it.
+fn downgrade_from_v14(transaction: &DatabaseTransaction) -> BoxFuture<'_, Result<(), DbErr>> {
+    Box::pin(async move {
+        if transaction.get_database_backend() != DbBackend::Postgres {
+            return Ok(());
+        }
+        for column in &TRIGRAM_INDEXED_USER_COLUMNS {
+            transaction
+                .execute(Statement::from_string(
+                    DbBackend::Postgres,
+                    format!(
+                        r#"DROP INDEX IF EXISTS "{}""#,
+                        user_trigram_index_name(column)
+                    ),
+                ))
+                .await?;
+        }
+        Ok(())
+    })
+}
+
+/// Adds the audit log. It has no foreign keys: the entries outlive the users and groups.
+fn upgrade_to_v15(transaction: &DatabaseTransaction) -> BoxFuture<'_, Result<(), DbErr>> {
+    Box::pin(async move {
+        let builder = transaction.get_database_backend();
+        transaction
+            .execute(
+                builder.build(
+                    Table::create()
+                        .table(AuditLog::Table)
+                        .if_not_exists()
+                        .col(
+                            ColumnDef::new(AuditLog::Id)
+                                .integer()
+                                .not_null()
+                                .auto_increment()
+                                .primary_key(),
+                        )
+                        .col(ColumnDef::new(AuditLog::ActorUserId).string_len(255).null())
+                        .col(
+                            ColumnDef::new(AuditLog::TargetUserId)
+                                .string_len(255)
+                                .null(),
+                        )
+                        .col(ColumnDef::new(AuditLog::TargetGroupId).integer().null())
+                        .col(
+                            ColumnDef::new(AuditLog::AttributeName)
+                                .string_len(255)
+                                .not_null(),
+                        )
+                        .col(ColumnDef::new(AuditLog::OldValueHash).string_len(64).null())
+                        .col(ColumnDef::new(AuditLog::NewValueHash).string_len(64).null())
+                        .col(ColumnDef::new(AuditLog::Timestamp).date_time().not_null())
+                        .col(ColumnDef::new(AuditLog::Source).string_len(64).not_null()),
+                ),
+            )
+            .await?;
+        for (name, column) in [
+            ("audit_log_target_user", AuditLog::TargetUserId),
+            ("audit_log_target_group", AuditLog::TargetGroupId),
+        ] {
+            transaction
+                .execute(
+                    builder.build(
+                        Index::create()
+                            .name(name)
+                            .table(AuditLog::Table)
+                            .col(column)
+                            .col(AuditLog::Timestamp),
+                    ),
+                )
+                .await?;
+        }
+        Ok(())
+    })
+}
+
+fn downgrade_from_v15(transaction: &DatabaseTransaction) -> BoxFuture<'_, Result<(), DbErr>> {
+    Box::pin(async move {
+        let builder = transaction.get_database_backend();
+        transaction
+            .execute(builder.build(Table::drop().table(AuditLog::Table)))
+            .await?;
+        Ok(())
+    })
+}
+
+/// Adds the queue of the webhook deliveries.
+fn upgrade_to_v16(transaction: &DatabaseTransaction) -> BoxFuture<'_, Result<(), DbErr>> {
+    Box::pin(async move {
+        let builder = transaction.get_database_backend();
+        transaction
+            .execute(
+                builder.build(
+                    Table::create()
+                        .table(WebhookDeliveries::Table)
+                        .if_not_exists()
+                        .col(
+                            ColumnDef::new(WebhookDeliveries::Id)
+                                .integer()
+                                .not_null()
+                                .auto_increment()
+                                .primary_key(),
+                        )
+                        .col(ColumnDef::new(WebhookDeliveries::Url).text().not_null())
+                        .col(
+                            ColumnDef::new(WebhookDeliveries::EventType)
+                                .string_len(64)
+                                .not_null(),
+                        )
+                        .col(ColumnDef::new(WebhookDeliveries::Payload).text().not_null())
+                        .col(
+                            ColumnDef::new(WebhookDeliveries::Attempts)
+                                .integer()
+                                .not_null(),
+                        )
+                        .col(
+                            ColumnDef::new(WebhookDeliveries::NextAttemptAt)
+                                .date_time()
+                                .not_null(),
+                        )
+                        .col(
+                            ColumnDef::new(WebhookDeliveries::CreationDate)
+                                .date_time()
+                                .not_null(),
+                        )
+                        .col(ColumnDef::new(WebhookDeliveries::LastError).text().null()),
+                ),
+            )
+            .await?;
+        transaction
+            .execute(
+                builder.build(
+                    Index::create()
+                        .name("webhook_deliveries_next_attempt")
+                        .table(WebhookDeliveries::Table)
+                        .col(WebhookDeliveries::NextAttemptAt),
+                ),
+            )
+            .await?;
+        Ok(())
+    })
+}
+
+fn downgrade_from_v16(transaction: &DatabaseTransaction) -> BoxFuture<'_, Result<(), DbErr>> {
+    Box::pin(async move {
+        let builder = transaction.get_database_backend();
+        transaction
+            .execute(builder.build(Table::drop().table(WebhookDeliveries::Table)))
+            .await?;
+        Ok(())
+    })
+}
+
+/// Adds the registered OpenID Connect clients, and the authorization codes issued to them.
+fn upgrade_to_v17(transaction: &DatabaseTransaction) -> BoxFuture<'_, Result<(), DbErr>> {
+    Box::pin(async move {
+        let builder = transaction.get_database_backend();
+        transaction
+            .execute(
+                builder.build(
+                    Table::create()
+                        .table(OidcClients::Table)
+                        .if_not_exists()
+                        .col(
+                            ColumnDef::new(OidcClients::ClientId)
+                                .string_len(255)
+                                .not_null()
+                                .primary_key(),
+                        )
+                        .col(
+                            ColumnDef::new(OidcClients::ClientSecretHash)
+                                .string_len(64)
+                                .not_null(),
+                        )
+                        .col(ColumnDef::new(OidcClients::RedirectUris).text().not_null())
+                        .col(ColumnDef::new(OidcClients::AllowedScopes).text().not_null())
+                        .col(
+                            ColumnDef::new(OidcClients::CreationDate)
+                                .date_time()
+                                .not_null(),
+                        ),
+                ),
+            )
+            .await?;
+        transaction
+            .execute(
+                builder.build(
+                    Table::create()
+                        .table(OidcAuthorizationCodes::Table)
+                        .if_not_exists()
+                        .col(
+                            ColumnDef::new(OidcAuthorizationCodes::CodeHash)
+                                .string_len(64)
+                                .not_null()
+                                .primary_key(),
+                        )
+                        .col(
+                            ColumnDef::new(OidcAuthorizationCodes::ClientId)
+                                .string_len(255)
+                                .not_null(),
+                        )
+                        .col(
+                            ColumnDef::new(OidcAuthorizationCodes::UserId)
+                                .string_len(255)
+                                .not_null(),
+                        )
+                        .col(
+                            ColumnDef::new(OidcAuthorizationCodes::RedirectUri)
+                                .text()
+                                .not_null(),
+                        )
+                        .col(
+                            ColumnDef::new(OidcAuthorizationCodes::Scopes)
+                                .text()
+                                .not_null(),
+                        )
+                        .col(ColumnDef::new(OidcAuthorizationCodes::Nonce).text().null())
+                        .col(
+                            ColumnDef::new(OidcAuthorizationCodes::ExpiryDate)
+                                .date_time()
+                                .not_null(),
+                        )
+                        .foreign_key(
+                            ForeignKey::create()
+                                .name("OidcAuthorizationCodesClientForeignKey")
+                                .from(
+                                    OidcAuthorizationCodes::Table,
+                                    OidcAuthorizationCodes::ClientId,
+                                )
+                                .to(OidcClients::Table, OidcClients::ClientId)
+                                .on_delete(ForeignKeyAction::Cascade)
+                                .on_update(ForeignKeyAction::Cascade),
+                        )
+                        .foreign_key(
+                            ForeignKey::create()
+                                .name("OidcAuthorizationCodesUserForeignKey")
+                                .from(
+                                    OidcAuthorizationCodes::Table,
+                                    OidcAuthorizationCodes::UserId,
+                                )
+                                .to(Users::Table, Users::UserId)
+                                .on_delete(ForeignKeyAction::Cascade)
+                                .on_update(ForeignKeyAction::Cascade),
+                        ),
+                ),
+            )
+            .await?;
+        Ok(())
+    })
+}
+
+fn downgrade_from_v17(transaction: &DatabaseTransaction) -> BoxFuture<'_, Result<(), DbErr>> {
+    Box::pin(async move {
+        let builder = transaction.get_database_backend();
+        transaction
+            .execute(builder.build(Table::drop().table(OidcAuthorizationCodes::Table)))
+            .await?;
+        transaction
+            .execute(builder.build(Table::drop().table(OidcClients::Table)))
+            .await?;
+        Ok(())
+    })
+}
+
+/// Widens the TOTP secrets, to fit them once encrypted. SQLite doesn't enforce the lengths.
+fn upgrade_to_v18(transaction: &DatabaseTransaction) -> BoxFuture<'_, Result<(), DbErr>> {
+    Box::pin(async move { set_totp_secret_length(transaction, 255).await })
+}
+
+/// Refuses to go on with encrypted TOTP secrets, that the older versions would take for
+/// plaintext ones: the migrations don't have the keys to decrypt them.
+fn downgrade_from_v18(transaction: &DatabaseTransaction) -> BoxFuture<'_, Result<(), DbErr>> {
+    Box::pin(async move {
+        #[derive(FromQueryResult)]
+        struct EncryptedTotpSecret {
+            user_id: UserId,
+        }
+        let users = EncryptedTotpSecret::find_by_statement(
+            transaction.get_database_backend().build(
+                Query::select()
+                    .from(Users::Table)
+                    .column(Users::UserId)
+                    .and_where(Expr::col(Users::TotpSecret).like(format!("{}%", ENCRYPTED_PREFIX))),
+            ),
+        )
+        .all(transaction)
+        .await?;
+        if !users.is_empty() {
+            return Err(DbErr::Custom(format!(
+                "Cannot downgrade below version 18, the TOTP secrets of these users are \
+                 encrypted: {}. Remove their TOTP enrollment first",
+                users
+                    .iter()
+                    .map(|u| u.user_id.as_str())
+                    .collect::<Vec<_>>()
+                    .join(", ")
+            )));
+        }
+        set_totp_secret_length(transaction, 64).await
+    })
+}
+
+async fn set_totp_secret_length(
+    transaction: &DatabaseTransaction,
+    length: u32,
+) -> Result<(), DbErr> {
+    let statement = match transaction.get_database_backend() {
+        DbBackend::Postgres => format!(
+            r#"ALTER TABLE "users" ALTER COLUMN "totp_secret" TYPE varchar({})"#,
+            length
+        ),
+        DbBackend::MySql => format!(
+            "ALTER TABLE `users` MODIFY `totp_secret` varchar({}) NULL",
+            length
+        ),
+        DbBackend::Sqlite => return Ok(()),
+    };
+    transaction
+        .execute(Statement::from_string(
+            transaction.get_database_backend(),
+            statement,
+        ))
+        .await?;
+    Ok(())
+}
+
+/// Adds the one-time recovery codes of the second factor.
+fn upgrade_to_v19(transaction: &DatabaseTransaction) -> BoxFuture<'_, Result<(), DbErr>> {
+    Box::pin(async move {
+        let builder = transaction.get_database_backend();
+        transaction
+            .execute(
+                builder.build(
+                    Table::create()
+                        .table(MfaBackupCodes::Table)
+                        .if_not_exists()
+                        .col(
+                            ColumnDef::new(MfaBackupCodes::Id)
+                                .integer()
+                                .not_null()
+                                .auto_increment()
+                                .primary_key(),
+                        )
+                        .col(
+                            ColumnDef::new(MfaBackupCodes::UserId)
+                                .string_len(255)
+                                .not_null(),
+                        )
+                        .col(
+                            ColumnDef::new(MfaBackupCodes::CodeHash)
+                                .string_len(64)
+                                .not_null(),
+                        )
+                        .col(
+                            ColumnDef::new(MfaBackupCodes::CreationDate)
+                                .date_time()
+                                .not_null(),
+                        )
+                        .foreign_key(
+                            ForeignKey::create()
+                                .name("MfaBackupCodesUserForeignKey")
+                                .from(MfaBackupCodes::Table, MfaBackupCodes::UserId)
+                                .to(Users::Table, Users::UserId)
+                                .on_delete(ForeignKeyAction::Cascade)
+                                .on_update(ForeignKeyAction::Cascade),
+                        ),
+                ),
+            )
+            .await?;
+        transaction
+            .execute(
+                builder.build(
+                    Index::create()
+                        .name("mfa_backup_codes_user_code")
+                        .table(MfaBackupCodes::Table)
+                        .col(MfaBackupCodes::UserId)
+                        .col(MfaBackupCodes::CodeHash),
+                ),
+            )
+            .await?;
+        Ok(())
+    })
+}
+
+fn downgrade_from_v19(transaction: &DatabaseTransaction) -> BoxFuture<'_, Result<(), DbErr>> {
+    Box::pin(async move {
+        let builder = transaction.get_database_backend();
+        transaction
+            .execute(builder.build(Table::drop().table(MfaBackupCodes::Table)))
+            .await?;
+        Ok(())
+    })
+}
+
+/// Adds the ID of the request that made each change.
+fn upgrade_to_v20(transaction: &DatabaseTransaction) -> BoxFuture<'_, Result<(), DbErr>> {
+    Box::pin(async move {
+        let builder = transaction.get_database_backend();
+        transaction
+            .execute(
+                builder.build(
+                    Table::alter().table(AuditLog::Table).add_column(
+                        ColumnDef::new(AuditLog::CorrelationId)
+                            .string_len(64)
+                            .null(),
+                    ),
+                ),
+            )
+            .await?;
+        transaction
+            .execute(
+                builder.build(
+                    Index::create()
+                        .name("audit_log_correlation_id")
+                        .table(AuditLog::Table)
+                        .col(AuditLog::CorrelationId),
+                ),
+            )
+            .await?;
+        Ok(())
+    })
+}
+
+fn downgrade_from_v20(transaction: &DatabaseTransaction) -> BoxFuture<'_, Result<(), DbErr>> {
+    Box::pin(async move {
+        let builder = transaction.get_database_backend();
+        transaction
+            .execute(
+                builder.build(
+                    Index::drop()
+                        .name("audit_log_correlation_id")
+                        .table(AuditLog::Table),
+                ),
+            )
+            .await?;
+        transaction
+            .execute(
+                builder.build(
+                    Table::alter()
+                        .table(AuditLog::Table)
+                        .drop_column(AuditLog::CorrelationId),
+                ),
+            )
+            .await?;
+        Ok(())
+    })
+}
+
+/// Adds the date of the last change of the users and groups, starting from their creation date.
+fn upgrade_to_v21(transaction: &DatabaseTransaction) -> BoxFuture<'_, Result<(), DbErr>> {
+    Box::pin(async move {
+        let builder = transaction.get_database_backend();
+        let now = chrono::Utc::now().naive_utc();
+        transaction
+            .execute(
+                builder.build(
+                    Table::alter().table(Users::Table).add_column(
+                        ColumnDef::new(Users::ModifiedAt)
+                            .date_time()
+                            .not_null()
+                            .default(now),
+                    ),
+                ),
+            )
+            .await?;
+        transaction
+            .execute(builder.build(Query::update().table(Users::Table).value(
+                Users::ModifiedAt,
+                SimpleExpr::Column(Users::CreationDate.into_column_ref()),
+            )))
+            .await?;
+        transaction
+            .execute(
+                builder.build(
+                    Table::alter().table(Groups::Table).add_column(
+                        ColumnDef::new(Groups::ModifiedAt)
+                            .date_time()
+                            .not_null()
+                            .default(now),
+                    ),
+                ),
+            )
+            .await?;
+        transaction
+            .execute(builder.build(Query::update().table(Groups::Table).value(
+                Groups::ModifiedAt,
+                SimpleExpr::Column(Groups::CreationDate.into_column_ref()),
+            )))
+            .await?;
+        Ok(())
+    })
+}
+
+fn downgrade_from_v21(transaction: &DatabaseTransaction) -> BoxFuture<'_, Result<(), DbErr>> {
+    Box::pin(async move {
+        let builder = transaction.get_database_backend();
+        transaction
+            .execute(
+                builder.build(
+                    Table::alter()
+                        .table(Users::Table)
+                        .drop_column(Users::ModifiedAt),
+                ),
+            )
+            .await?;
+        transaction
+            .execute(
+                builder.build(
+                    Table::alter()
+                        .table(Groups::Table)
+                        .drop_column(Groups::ModifiedAt),
+                ),
+            )
+            .await?;
+        Ok(())
+    })
+}
+
+/// Adds a record of the deleted users and groups, for the incremental sync.
+fn upgrade_to_v22(transaction: &DatabaseTransaction) -> BoxFuture<'_, Result<(), DbErr>> {
+    Box::pin(async move {
+        let builder = transaction.get_database_backend();
+        transaction
+            .execute(
+                builder.build(
+                    Table::create()
+                        .table(DeletionTombstones::Table)
+                        .if_not_exists()
+                        .col(
+                            ColumnDef::new(DeletionTombstones::Id)
+                                .integer()
+                                .not_null()
+                                .auto_increment()
+                                .primary_key(),
+                        )
+                        .col(
+                            ColumnDef::new(DeletionTombstones::UserId)
+                                .string_len(255)
+                                .null(),
+                        )
+                        .col(ColumnDef::new(DeletionTombstones::GroupId).integer().null())
+                        .col(
+                            ColumnDef::new(DeletionTombstones::Uuid)
+                                .string_len(36)
+                                .not_null(),
+                        )
+                        .col(
+                            ColumnDef::new(DeletionTombstones::DeletedAt)
+                                .date_time()
+                                .not_null(),
+                        ),
+                ),
+            )
+            .await?;
+        transaction
+            .execute(
+                builder.build(
+                    Index::create()
+                        .name("deletion_tombstones_deleted_at")
+                        .table(DeletionTombstones::Table)
+                        .col(DeletionTombstones::DeletedAt),
+                ),
+            )
+            .await?;
+        Ok(())
+    })
+}
+
+fn downgrade_from_v22(transaction: &DatabaseTransaction) -> BoxFuture<'_, Result<(), DbErr>> {
+    Box::pin(async move {
+        let builder = transaction.get_database_backend();
+        transaction
+            .execute(builder.build(Table::drop().table(DeletionTombstones::Table)))
+            .await?;
+        Ok(())
+    })
+}
+
+/// Adds the POSIX attributes of the users and groups. The numbers are allocated later, from the
+/// configured ranges.
+fn upgrade_to_v23(transaction: &DatabaseTransaction) -> BoxFuture<'_, Result<(), DbErr>> {
+    Box::pin(async move {
+        let builder = transaction.get_database_backend();
+        // SQLite can only add one column at a time.
+        for mut column in [
+            ColumnDef::new(Users::UidNumber).integer().null().to_owned(),
+            ColumnDef::new(Users::GidNumber).integer().null().to_owned(),
+            ColumnDef::new(Users::HomeDirectory)
+                .string_len(255)
+                .null()
+                .to_owned(),
+            ColumnDef::new(Users::LoginShell)
+                .string_len(255)
+                .null()
+                .to_owned(),
+        ] {
+            transaction
+                .execute(builder.build(Table::alter().table(Users::Table).add_column(&mut column)))
+                .await?;
+        }
+        transaction
+            .execute(
+                builder.build(
+                    Table::alter()
+                        .table(Groups::Table)
+                        .add_column(ColumnDef::new(Groups::GidNumber).integer().null()),
+                ),
+            )
+            .await?;
+        // Several NULLs are allowed by the unique indices, on all the backends.
+        transaction
+            .execute(
+                builder.build(
+                    Index::create()
+                        .name("users_uid_number")
+                        .table(Users::Table)
+                        .col(Users::UidNumber)
+                        .unique(),
+                ),
+            )
+            .await?;
+        transaction
+            .execute(
+                builder.build(
+                    Index::create()
+                        .name("groups_gid_number")
+                        .table(Groups::Table)
+                        .col(Groups::GidNumber)
+                        .unique(),
+                ),
+            )
+            .await?;
+        transaction
+            .execute(
+                builder.build(
+                    Query::insert()
+                        .into_table(Sequences::Table)
+                        .columns([Sequences::Name, Sequences::NextValue])
+                        .values_panic([UID_NUMBER_SEQUENCE.into(), 0.into()])
+                        .values_panic([GID_NUMBER_SEQUENCE.into(), 0.into()]),
+                ),
+            )
+            .await?;
+        Ok(())
+    })
+}
+
+fn downgrade_from_v23(transaction: &DatabaseTransaction) -> BoxFuture<'_, Result<(), DbErr>> {
+    Box::pin(async move {
+        let builder = transaction.get_database_backend();
+        transaction
+            .execute(
+                builder.build(Query::delete().from_table(Sequences::Table).and_where(
+                    Expr::col(Sequences::Name).is_in([UID_NUMBER_SEQUENCE, GID_NUMBER_SEQUENCE]),
+                )),
+            )
+            .await?;
+        transaction
+            .execute(builder.build(Index::drop().name("users_uid_number").table(Users::Table)))
+            .await?;
+        transaction
+            .execute(builder.build(Index::drop().name("groups_gid_number").table(Groups::Table)))
+            .await?;
+        for column in [
+            Users::UidNumber,
+            Users::GidNumber,
+            Users::HomeDirectory,
+            Users::LoginShell,
+        ] {
+            transaction
+                .execute(builder.build(Table::alter().table(Users::Table).drop_column(column)))
+                .await?;
+        }
+        transaction
+            .execute(
+                builder.build(
+                    Table::alter()
+                        .table(Groups::Table)
+                        .drop_column(Groups::GidNumber),
+                ),
+            )
+            .await?;
+        Ok(())
+    })
+}
+
+/// Adds the sudoers rules, served as `sudoRole` entries for sudo's LDAP backend.
+fn upgrade_to_v24(transaction: &DatabaseTransaction) -> BoxFuture<'_, Result<(), DbErr>> {
+    Box::pin(async move {
+        let builder = transaction.get_database_backend();
+        transaction
+            .execute(
+                builder.build(
+                    Table::create()
+                        .table(SudoRoles::Table)
+                        .if_not_exists()
+                        .col(
+                            ColumnDef::new(SudoRoles::RoleId)
+                                .integer()
+                                .not_null()
+                                .auto_increment()
+                                .primary_key(),
+                        )
+                        .col(
+                            ColumnDef::new(SudoRoles::Name)
+                                .string_len(255)
+                                .unique_key()
+                                .not_null(),
+                        )
+                        .col(
+                            ColumnDef::new(SudoRoles::CreationDate)
+                                .date_time()
+                                .not_null(),
+                        )
+                        .col(ColumnDef::new(SudoRoles::ModifiedAt).date_time().not_null()),
+                ),
+            )
+            .await?;
+        transaction
+            .execute(
+                builder.build(
+                    Table::create()
+                        .table(SudoRoleValues::Table)
+                        .if_not_exists()
+                        .col(ColumnDef::new(SudoRoleValues::RoleId).integer().not_null())
+                        .col(
+                            ColumnDef::new(SudoRoleValues::Attribute)
+                                .string_len(32)
+                                .not_null(),
+                        )
+                        .col(
+                            ColumnDef::new(SudoRoleValues::Position)
+                                .integer()
+                                .not_null(),
+                        )
+                        .col(
+                            ColumnDef::new(SudoRoleValues::Value)
+                                .string_len(255)
+                                .not_null(),
+                        )
+                        .primary_key(
+                            Index::create()
+                                .col(SudoRoleValues::RoleId)
+                                .col(SudoRoleValues::Attribute)
+                                .col(SudoRoleValues::Position),
+                        )
+                        .foreign_key(
+                            ForeignKey::create()
+                                .name("SudoRoleValuesRoleForeignKey")
+                                .from(SudoRoleValues::Table, SudoRoleValues::RoleId)
+                                .to(SudoRoles::Table, SudoRoles::RoleId)
+                                .on_delete(ForeignKeyAction::Cascade)
+                                .on_update(ForeignKeyAction::Cascade),
+                        ),
+                ),
+            )
+            .await?;
+        // For the searches by sudoUser, sudoHost...
+        transaction
+            .execute(
+                builder.build(
+                    Index::create()
+                        .name("sudo_role_values_attribute_value")
+                        .table(SudoRoleValues::Table)
+                        .col(SudoRoleValues::Attribute)
+                        .col(SudoRoleValues::Value),
+                ),
+            )
+            .await?;
+        Ok(())
+    })
+}
+
+fn downgrade_from_v24(transaction: &DatabaseTransaction) -> BoxFuture<'_, Result<(), DbErr>> {
+    Box::pin(async move {
+        let builder = transaction.get_database_backend();
+        transaction
+            .execute(builder.build(Table::drop().table(SudoRoleValues::Table)))
+            .await?;
+        transaction
+            .execute(builder.build(Table::drop().table(SudoRoles::Table)))
+            .await?;
+        Ok(())
+    })
+}
+
+/// Adds the rules that assign the users to groups, and marks the memberships that they added.
+fn upgrade_to_v25(transaction: &DatabaseTransaction) -> BoxFuture<'_, Result<(), DbErr>> {
+    Box::pin(async move {
+        let builder = transaction.get_database_backend();
+        transaction
+            .execute(
+                builder.build(
+                    Table::create()
+                        .table(GroupRules::Table)
+                        .if_not_exists()
+                        .col(
+                            ColumnDef::new(GroupRules::RuleId)
+                                .integer()
+                                .not_null()
+                                .auto_increment()
+                                .primary_key(),
+                        )
+                        .col(ColumnDef::new(GroupRules::GroupId).integer().not_null())
+                        .col(
+                            ColumnDef::new(GroupRules::CreationDate)
+                                .date_time()
+                                .not_null(),
+                        )
+                        .foreign_key(
+                            ForeignKey::create()
+                                .name("GroupRulesGroupForeignKey")
+                                .from(GroupRules::Table, GroupRules::GroupId)
+                                .to(Groups::Table, Groups::GroupId)
+                                .on_delete(ForeignKeyAction::Cascade)
+                                .on_update(ForeignKeyAction::Cascade),
+                        ),
+                ),
+            )
+            .await?;
+        transaction
+            .execute(
+                builder.build(
+                    Table::create()
+                        .table(GroupRuleConditions::Table)
+                        .if_not_exists()
+                        .col(
+                            ColumnDef::new(GroupRuleConditions::RuleId)
+                                .integer()
+                                .not_null(),
+                        )
+                        .col(
+                            ColumnDef::new(GroupRuleConditions::Position)
+                                .integer()
+                                .not_null(),
+                        )
+                        .col(
+                            ColumnDef::new(GroupRuleConditions::Attribute)
+                                .string_len(64)
+                                .not_null(),
+                        )
+                        .col(
+                            ColumnDef::new(GroupRuleConditions::Operator)
+                                .string_len(32)
+                                .not_null(),
+                        )
+                        .col(
+                            ColumnDef::new(GroupRuleConditions::Value)
+                                .string_len(255)
+                                .not_null(),
+                        )
+                        .primary_key(
+                            Index::create()
+                                .col(GroupRuleConditions::RuleId)
+                                .col(GroupRuleConditions::Position),
+                        )
+                        .foreign_key(
+                            ForeignKey::create()
+                                .name("GroupRuleConditionsRuleForeignKey")
+                                .from(GroupRuleConditions::Table, GroupRuleConditions::RuleId)
+                                .to(GroupRules::Table, GroupRules::RuleId)
+                                .on_delete(ForeignKeyAction::Cascade)
+                                .on_update(ForeignKeyAction::Cascade),
+                        ),
+                ),
+            )
+            .await?;
+        // The existing memberships were all added by hand.
+        transaction
+            .execute(
+                builder.build(
+                    Table::alter().table(Memberships::Table).add_column(
+                        ColumnDef::new(Memberships::AssignedByRule)
+                            .boolean()
+                            .not_null()
+                            .default(false),
+                    ),
+                ),
+            )
+            .await?;
+        Ok(())
+    })
+}
+
+fn downgrade_from_v25(transaction: &DatabaseTransaction) -> BoxFuture<'_, Result<(), DbErr>> {
+    Box::pin(async move {
+        let builder = transaction.get_database_backend();
+        transaction
+            .execute(
+                builder.build(
+                    Table::alter()
+                        .table(Memberships::Table)
+                        .drop_column(Memberships::AssignedByRule),
+                ),
+            )
+            .await?;
+        transaction
+            .execute(builder.build(Table::drop().table(GroupRuleConditions::Table)))
+            .await?;
+        transaction
+            .execute(builder.build(Table::drop().table(GroupRules::Table)))
+            .await?;
+        Ok(())
+    })
+}
+
+/// Normalizes all the user emails, e.g. lowercases them when they are case-insensitive, refusing
+/// to do so if it would create duplicates.
+pub async fn normalize_emails(
+    pool: &DbConnection,
+    normalize: impl Fn(String) -> String,
+) -> anyhow::Result<()> {
+    let duplicates = find_duplicate_emails(pool, &normalize).await?;
+    if !duplicates.is_empty() {
+        anyhow::bail!(
+            "Cannot normalize the user emails, some users would share the same email: {}",
+            describe_duplicate_emails(&duplicates)
+        );
+    }
+    let builder = pool.get_database_backend();
+    for user in get_user_emails(pool).await? {
+        let normalized_email = normalize(user.email.clone());
+        if normalized_email != user.email {
+            info!("Normalizing the email of '{}'", user.user_id);
+            pool.execute(
+                builder.build(
+                    Query::update()
+                        .table(Users::Table)
+                        .value(Users::Email, Value::from(normalized_email))
+                        .and_where(Expr::col(Users::UserId).eq(user.user_id)),
+                ),
+            )
+            .await?;
+        }
+    }
+    Ok(())
+}
+
+/// A single schema migration step, run inside a transaction.
+type MigrationFn = for<'a> fn(&'a DatabaseTransaction) -> BoxFuture<'a, Result<(), DbErr>>;
+
+/// Describes how to go from `version - 1` to `version`, and optionally back.
+struct Migration {
+    version: SchemaVersion,
+    upgrade: MigrationFn,
+    downgrade: Option<MigrationFn>,
+}
+
+/// Adds the tokens to verify the emails of the users, and the flag set once verified.
+fn upgrade_to_v26(transaction: &DatabaseTransaction) -> BoxFuture<'_, Result<(), DbErr>> {
+    Box::pin(async move {
+        let builder = transaction.get_database_backend();
+        transaction
+            .execute(
+                builder.build(
+                    Table::create()
+                        .table(EmailVerificationTokens::Table)
+                        .if_not_exists()
+                        .col(
+                            ColumnDef::new(EmailVerificationTokens::TokenHash)
+                                .string_len(64)
+                                .not_null()
+                                .primary_key(),
+                        )
+                        .col(
+                            ColumnDef::new(EmailVerificationTokens::UserId)
+                                .string_len(255)
+                                .not_null(),
+                        )
+                        .col(
+                            ColumnDef::new(EmailVerificationTokens::Email)
+                                .string_len(255)
+                                .not_null(),
+                        )
+                        .col(
+                            ColumnDef::new(EmailVerificationTokens::ExpiryDate)
+                                .date_time()
+                                .not_null(),
+                        )
+                        .foreign_key(
+                            ForeignKey::create()
+                                .name("EmailVerificationTokensUserForeignKey")
+                                .from(
+                                    EmailVerificationTokens::Table,
+                                    EmailVerificationTokens::UserId,
+                                )
+                                .to(Users::Table, Users::UserId)
+                                .on_delete(ForeignKeyAction::Cascade)
+                                .on_update(ForeignKeyAction::Cascade),
+                        ),
+                ),
+            )
+            .await?;
+        transaction
+            .execute(
+                builder.build(
+                    Table::alter().table(Users::Table).add_column(
+                        ColumnDef::new(Users::EmailVerified)
+                            .boolean()
+                            .not_null()
+                            .default(false),
+                    ),
+                ),
+            )
+            .await?;
+        Ok(())
+    })
+}
+
+fn downgrade_from_v26(transaction: &DatabaseTransaction) -> BoxFuture<'_, Result<(), DbErr>> {
+    Box::pin(async move {
+        let builder = transaction.get_database_backend();
+        transaction
+            .execute(
+                builder.build(
+                    Table::alter()
+                        .table(Users::Table)
+                        .drop_column(Users::EmailVerified),
+                ),
+            )
+            .await?;
+        transaction
+            .execute(builder.build(Table::drop().table(EmailVerificationTokens::Table)))
+            .await?;
+        Ok(())
+    })
+}
+
+/// Adds the SCRAM-SHA-256 verifiers of the passwords, for the SASL binds.
+fn upgrade_to_v27(transaction: &DatabaseTransaction) -> BoxFuture<'_, Result<(), DbErr>> {
+    Box::pin(async move {
+        let builder = transaction.get_database_backend();
+        transaction
+            .execute(
+                builder.build(
+                    Table::alter()
+                        .table(Users::Table)
+                        .add_column(ColumnDef::new(Users::ScramSha256Verifier).string_len(255)),
+                ),
+            )
+            .await?;
+        Ok(())
+    })
+}
+
+fn downgrade_from_v27(transaction: &DatabaseTransaction) -> BoxFuture<'_, Result<(), DbErr>> {
+    Box::pin(async move {
+        let builder = transaction.get_database_backend();
+        transaction
+            .execute(
+                builder.build(
+                    Table::alter()
+                        .table(Users::Table)
+                        .drop_column(Users::ScramSha256Verifier),
+                ),
+            )
+            .await?;
+        Ok(())
+    })
+}
+
+/// The values of the custom attributes, of the users or of the groups.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum AttributeTable {
+    Users,
+    Groups,
+}
+
+impl AttributeTable {
+    pub(crate) const ALL: [AttributeTable; 2] = [AttributeTable::Users, AttributeTable::Groups];
+
+    /// The values of all the attributes share one index on `(attribute_name, value)`, that only
+    /// exists while at least one of the attributes is indexed.
+    fn index_name(self) -> &'static str {
+        match self {
+            AttributeTable::Users => "user_attributes_name_value",
+            AttributeTable::Groups => "group_attributes_name_value",
+        }
+    }
+}
+
+/// Whether at least one of the attributes is indexed, i.e. whether their values have an index.
+pub(crate) async fn has_indexed_attributes(
+    connection: &impl ConnectionTrait,
+    table: AttributeTable,
+) -> Result<bool, DbErr> {
+    let mut query = Query::select();
+    match table {
+        AttributeTable::Users => query
+            .column(UserAttributeSchema::AttributeName)
+            .from(UserAttributeSchema::Table)
+            .and_where(Expr::col(UserAttributeSchema::IsIndexed).eq(true)),
+        AttributeTable::Groups => query
+            .column(GroupAttributeSchema::AttributeName)
+            .from(GroupAttributeSchema::Table)
+            .and_where(Expr::col(GroupAttributeSchema::IsIndexed).eq(true)),
+    };
+    query.limit(1);
+    Ok(connection
+        .query_one(connection.get_database_backend().build(&query))
+        .await?
+        .is_some())
+}
+
+async fn create_attribute_values_index(
+    connection: &impl ConnectionTrait,
+    table: AttributeTable,
+) -> Result<(), DbErr> {
+    let builder = connection.get_database_backend();
+    let statement = match (builder, table) {
+        // MySQL can only index a prefix of the binary values.
+        (DbBackend::MySql, AttributeTable::Users) => Statement::from_string(
+            builder,
+            format!(
+                "CREATE INDEX {} ON user_attributes (attribute_name, value(255))",
+                table.index_name()
+            ),
+        ),
+        (DbBackend::MySql, AttributeTable::Groups) => Statement::from_string(
+            builder,
+            format!(
+                "CREATE INDEX {} ON group_attributes (attribute_name, value(255))",
+                table.index_name()
+            ),
+        ),
+        (_, AttributeTable::Users) => builder.build(
+            Index::create()
+                .name(table.index_name())
+                .table(UserAttributes::Table)
+                .col(UserAttributes::AttributeName)
+                .col(UserAttributes::Value),
+        ),
+        (_, AttributeTable::Groups) => builder.build(
+            Index::create()
+                .name(table.index_name())
+                .table(GroupAttributes::Table)
+                .col(GroupAttributes::AttributeName)
+                .col(GroupAttributes::Value),
+        ),
+    };
+    connection.execute(statement).await?;
+    Ok(())
+}
+
+async fn drop_attribute_values_index(
+    connection: &impl ConnectionTrait,
+    table: AttributeTable,
+) -> Result<(), DbErr> {
+    let builder = connection.get_database_backend();
+    let mut statement = Index::drop();
+    statement.name(table.index_name());
+    match table {
+        AttributeTable::Users => statement.table(UserAttributes::Table),
+        AttributeTable::Groups => statement.table(GroupAttributes::Table),
+    };
+    connection.execute(builder.build(&statement)).await?;
+    Ok(())
+}
+
+/// Creates or drops the index of the values after a change of the attributes, if the first one
+/// got indexed or the last one stopped being.
+pub(crate) async fn update_attribute_values_index(
+    connection: &impl ConnectionTrait,
+    table: AttributeTable,
+    was_indexed: bool,
+) -> Result<(), DbErr> {
+    match (
+        was_indexed,
+        has_indexed_attributes(connection, table).await?,
+    ) {
+        (false, true) => create_attribute_values_index(connection, table).await,
+        (true, false) => drop_attribute_values_index(connection, table).await,
+        _ => Ok(()),
+    }
+}
+
+/// Indexes the values of the custom attributes, for the tables with an indexed attribute.
+fn upgrade_to_v28(transaction: &DatabaseTransaction) -> BoxFuture<'_, Result<(), DbErr>> {
+    Box::pin(async move {
+        for table in AttributeTable::ALL {
+            update_attribute_values_index(transaction, table, false).await?;
+        }
+        Ok(())
+    })
+}
+
+fn downgrade_from_v28(transaction: &DatabaseTransaction) -> BoxFuture<'_, Result<(), DbErr>> {
+    Box::pin(async move {
+        for table in AttributeTable::ALL {
+            if has_indexed_attributes(transaction, table).await? {
+                drop_attribute_values_index(transaction, table).await?;
+            }
+        }
+        Ok(())
+    })
+}
+
+fn upgrade_to_v29(transaction: &DatabaseTransaction) -> BoxFuture<'_, Result<(), DbErr>> {
+    Box::pin(async move {
+        let builder = transaction.get_database_backend();
+        // NULL for the secrets enrolled before: they use the defaults of RFC 6238.
+        transaction
+            .execute(
+                builder.build(
+                    Table::alter()
+                        .table(Users::Table)
+                        .add_column(ColumnDef::new(Users::TotpParameters).string_len(64)),
+                ),
+            )
+            .await?;
+        Ok(())
+    })
+}
+
+fn downgrade_from_v29(transaction: &DatabaseTransaction) -> BoxFuture<'_, Result<(), DbErr>> {
+    Box::pin(async move {
+        let builder = transaction.get_database_backend();
+        transaction
+            .execute(
+                builder.build(
+                    Table::alter()
+                        .table(Users::Table)
+                        .drop_column(Users::TotpParameters),
+                ),
+            )
+            .await?;
+        Ok(())
+    })
+}
+
+fn upgrade_to_v30(transaction: &DatabaseTransaction) -> BoxFuture<'_, Result<(), DbErr>> {
+    Box::pin(async move {
+        let builder = transaction.get_database_backend();
+        transaction
+            .execute(
+                builder.build(
+                    Table::alter()
+                        .table(Users::Table)
+                        .add_column(ColumnDef::new(Users::TotpLastCounter).big_integer()),
+                ),
+            )
+            .await?;
+        Ok(())
+    })
+}
+
+fn downgrade_from_v30(transaction: &DatabaseTransaction) -> BoxFuture<'_, Result<(), DbErr>> {
+    Box::pin(async move {
+        let builder = transaction.get_database_backend();
+        transaction
+            .execute(
+                builder.build(
+                    Table::alter()
+                        .table(Users::Table)
+                        .drop_column(Users::TotpLastCounter),
+                ),
+            )
+            .await?;
+        Ok(())
+    })
+}
+
+fn upgrade_to_v31(transaction: &DatabaseTransaction) -> BoxFuture<'_, Result<(), DbErr>> {
+    Box::pin(async move {
+        let builder = transaction.get_database_backend();
+        // The old refresh tokens can't be rotated: the users log in again.
+        transaction
+            .execute(builder.build(Table::drop().table(JwtRefreshStorage::Table).if_exists()))
+            .await?;
+        transaction
+            .execute(
+                builder.build(
+                    Table::create()
+                        .table(RefreshTokens::Table)
+                        .if_not_exists()
+                        .col(
+                            ColumnDef::new(RefreshTokens::TokenHash)
+                                .string_len(64)
+                                .not_null()
+                                .primary_key(),
+                        )
+                        .col(
+                            ColumnDef::new(RefreshTokens::FamilyId)
+                                .string_len(64)
+                                .not_null(),
+                        )
+                        .col(
+                            ColumnDef::new(RefreshTokens::UserId)
+                                .string_len(255)
+                                .not_null(),
+                        )
+                        .col(
+                            ColumnDef::new(RefreshTokens::IssuedAt)
+                                .date_time()
+                                .not_null(),
+                        )
+                        .col(
+                            ColumnDef::new(RefreshTokens::ExpiryDate)
+                                .date_time()
+                                .not_null(),
+                        )
+                        .col(
+                            ColumnDef::new(RefreshTokens::Revoked)
+                                .boolean()
+                                .default(false)
+                                .not_null(),
+                        )
+                        .foreign_key(
+                            ForeignKey::create()
+                                .name("RefreshTokensUserForeignKey")
+                                .from(RefreshTokens::Table, RefreshTokens::UserId)
+                                .to(Users::Table, Users::UserId)
+                                .on_delete(ForeignKeyAction::Cascade)
+                                .on_update(ForeignKeyAction::Cascade),
+                        ),
+                ),
+            )
+            .await?;
+        transaction
+            .execute(
+                builder.build(
+                    Table::create()
+                        .table(Sessions::Table)
+                        .if_not_exists()
+                        .col(
+                            ColumnDef::new(Sessions::SessionId)
+                                .string_len(64)
+                                .not_null()
+                                .primary_key(),
+                        )
+                        .col(ColumnDef::new(Sessions::UserId).string_len(255).not_null())
+                        .col(
+                            ColumnDef::new(Sessions::CreationDate)
+                                .date_time()
+                                .not_null(),
+                        )
+                        .col(ColumnDef::new(Sessions::LastSeen).date_time().not_null())
+                        .col(ColumnDef::new(Sessions::ExpiryDate).date_time().not_null())
+                        .col(ColumnDef::new(Sessions::IpAddress).string_len(45))
+                        .col(ColumnDef::new(Sessions::UserAgent).string_len(255))
+                        .col(
+                            ColumnDef::new(Sessions::Revoked)
+                                .boolean()
+                                .default(false)
+                                .not_null(),
+                        )
+                        .foreign_key(
+                            ForeignKey::create()
+                                .name("SessionsUserForeignKey")
+                                .from(Sessions::Table, Sessions::UserId)
+                                .to(Users::Table, Users::UserId)
+                                .on_delete(ForeignKeyAction::Cascade)
+                                .on_update(ForeignKeyAction::Cascade),
+                        ),
+                ),
+            )
+            .await?;
+        Ok(())
+    })
+}
+
+fn downgrade_from_v31(transaction: &DatabaseTransaction) -> BoxFuture<'_, Result<(), DbErr>> {
+    Box::pin(async move {
+        let builder = transaction.get_database_backend();
+        transaction
+            .execute(builder.build(Table::drop().table(Sessions::Table)))
+            .await?;
+        transaction
+            .execute(builder.build(Table::drop().table(RefreshTokens::Table)))
+            .await?;
+        Ok(())
+    })
+}
+
+fn upgrade_to_v32(transaction: &DatabaseTransaction) -> BoxFuture<'_, Result<(), DbErr>> {
+    Box::pin(async move {
+        let builder = transaction.get_database_backend();
+        // NULL for the existing users: their IDs were only stored in lowercase.
+        transaction
+            .execute(
+                builder.build(
+                    Table::alter()
+                        .table(Users::Table)
+                        .add_column(ColumnDef::new(Users::UserIdDisplay).string_len(255)),
+                ),
+            )
+            .await?;
+        Ok(())
+    })
+}
+
+fn downgrade_from_v32(transaction: &DatabaseTransaction) -> BoxFuture<'_, Result<(), DbErr>> {
+    Box::pin(async move {
+        let builder = transaction.get_database_backend();
+        transaction
+            .execute(
+                builder.build(
+                    Table::alter()
+                        .table(Users::Table)
+                        .drop_column(Users::UserIdDisplay),
+                ),
+            )
+            .await?;
+        Ok(())
+    })
+}
+
+/// All the migrations after v1, in increasing version order.
+const MIGRATIONS: &[Migration] = &[
+    Migration {
+        version: SchemaVersion(2),
+        upgrade: upgrade_to_v2,
+        downgrade: Some(downgrade_from_v2),
+    },
+    Migration {
+        version: SchemaVersion(3),
+        upgrade: upgrade_to_v3,
+        downgrade: Some(downgrade_from_v3),
+    },
+    Migration {
+        version: SchemaVersion(4),
+        upgrade: upgrade_to_v4,
+        downgrade: Some(downgrade_from_v4),
+    },
+    Migration {
+        version: SchemaVersion(5),
+        upgrade: upgrade_to_v5,
+        downgrade: Some(downgrade_from_v5),
+    },
+    Migration {
+        version: SchemaVersion(6),
+        upgrade: upgrade_to_v6,
+        downgrade: Some(downgrade_from_v6),
+    },
+    Migration {
+        version: SchemaVersion(7),
+        upgrade: upgrade_to_v7,
+        downgrade: Some(downgrade_from_v7),
+    },
+    Migration {
+        version: SchemaVersion(8),
+        upgrade: upgrade_to_v8,
+        downgrade: Some(downgrade_from_v8),
+    },
+    Migration {
+        version: SchemaVersion(9),
+        upgrade: upgrade_to_v9,
+        downgrade: Some(downgrade_from_v9),
+    },
+    Migration {
+        version: SchemaVersion(10),
+        upgrade: upgrade_to_v10,
+        downgrade: Some(downgrade_from_v10),
+    },
+    Migration {
+        version: SchemaVersion(11),
+        upgrade: upgrade_to_v11,
+        downgrade: Some(downgrade_from_v11),
+    },
+    Migration {
+        version: SchemaVersion(12),
+        upgrade: upgrade_to_v12,
+        downgrade: Some(downgrade_from_v12),
+    },
+    Migration {
+        version: SchemaVersion(13),
+        upgrade: upgrade_to_v13,
+        downgrade: Some(downgrade_from_v13),
+    },
+    Migration {
+        version: SchemaVersion(14),
+        upgrade: upgrade_to_v14,
+        downgrade: Some(downgrade_from_v14),
+    },
+    Migration {
+        version: SchemaVersion(15),
+        upgrade: upgrade_to_v15,
+        downgrade: Some(downgrade_from_v15),
+    },
+    Migration {
+        version: SchemaVersion(16),
+        upgrade: upgrade_to_v16,
+        downgrade: Some(downgrade_from_v16),
+    },
+    Migration {
+        version: SchemaVersion(17),
+        upgrade: upgrade_to_v17,
+        downgrade: Some(downgrade_from_v17),
+    },
+    Migration {
+        version: SchemaVersion(18),
+        upgrade: upgrade_to_v18,
+        downgrade: Some(downgrade_from_v18),
+    },
+    Migration {
+        version: SchemaVersion(19),
+        upgrade: upgrade_to_v19,
+        downgrade: Some(downgrade_from_v19),
+    },
+    Migration {
+        version: SchemaVersion(20),
+        upgrade: upgrade_to_v20,
+        downgrade: Some(downgrade_from_v20),
+    },
+    Migration {
+        version: SchemaVersion(21),
+        upgrade: upgrade_to_v21,
+        downgrade: Some(downgrade_from_v21),
+    },
+    Migration {
+        version: SchemaVersion(22),
+        upgrade: upgrade_to_v22,
+        downgrade: Some(downgrade_from_v22),
+    },
+    Migration {
+        version: SchemaVersion(23),
+        upgrade: upgrade_to_v23,
+        downgrade: Some(downgrade_from_v23),
+    },
+    Migration {
+        version: SchemaVersion(24),
+        upgrade: upgrade_to_v24,
+        downgrade: Some(downgrade_from_v24),
+    },
+    Migration {
+        version: SchemaVersion(25),
+        upgrade: upgrade_to_v25,
+        downgrade: Some(downgrade_from_v25),
+    },
+    Migration {
+        version: SchemaVersion(26),
+        upgrade: upgrade_to_v26,
+        downgrade: Some(downgrade_from_v26),
+    },
+    Migration {
+        version: SchemaVersion(27),
+        upgrade: upgrade_to_v27,
+        downgrade: Some(downgrade_from_v27),
+    },
+    Migration {
+        version: SchemaVersion(28),
+        upgrade: upgrade_to_v28,
+        downgrade: Some(downgrade_from_v28),
+    },
+    Migration {
+        version: SchemaVersion(29),
+        upgrade: upgrade_to_v29,
+        downgrade: Some(downgrade_from_v29),
+    },
+    Migration {
+        version: SchemaVersion(30),
+        upgrade: upgrade_to_v30,
+        downgrade: Some(downgrade_from_v30),
+    },
+    Migration {
+        version: SchemaVersion(31),
+        upgrade: upgrade_to_v31,
+        downgrade: Some(downgrade_from_v31),
+    },
+    Migration {
+        version: SchemaVersion(32),
+        upgrade: upgrade_to_v32,
+        downgrade: Some(downgrade_from_v32),
+    },
+];
+
+fn find_migration(version: SchemaVersion) -> Option<&'static Migration> {
+    MIGRATIONS.iter().find(|m| m.version == version)
+}
+
+async fn set_schema_version(
+    transaction: &DatabaseTransaction,
+    version: SchemaVersion,
+) -> Result<(), DbErr> {
+    transaction
+        .execute(
+            transaction.get_database_backend().build(
+                Query::update()
+                    .table(Metadata::Table)
+                    .value(Metadata::Version, Value::from(version)),
+            ),
+        )
+        .await?;
+    Ok(())
+}
+
+/// Runs the migration function, sets the schema version to `new_version` and updates the
+/// migration history, in a single transaction. Backends that don't support transactional DDL
+/// (MySQL) will still commit the structural changes immediately.
+async fn run_migration_step(
+    pool: &DbConnection,
+    step: MigrationFn,
+    old_version: SchemaVersion,
+    new_version: SchemaVersion,
+) -> Result<(), DbErr> {
+    let transaction = pool.begin().await?;
+    step(&transaction).await?;
+    set_schema_version(&transaction, new_version).await?;
+    if new_version > old_version {
+        record_migration(&transaction, new_version).await?;
+    } else {
+        remove_migration_record(&transaction, old_version).await?;
+    }
+    transaction.commit().await
+}
+
+/// Brings the DB from `version` to `target`, upgrading or downgrading as needed.
+///
+/// All the required steps are looked up before touching the DB: if one of them is missing (e.g.
+/// a downgrade that was not registered), nothing is applied.
+#[instrument(skip(pool), level = "debug", err)]
+pub async fn migrate_from_version(
+    pool: &DbConnection,
+    version: SchemaVersion,
+    target: SchemaVersion,
+) -> anyhow::Result<()> {
+    match version.cmp(&target) {
+        Ordering::Equal => Ok(()),
+        Ordering::Less => {
+            let steps = (version.0 + 1..=target.0)
+                .map(|v| {
+                    find_migration(SchemaVersion(v))
+                        .map(|m| (m.upgrade, SchemaVersion(v - 1), m.version))
+                        .ok_or_else(|| anyhow::anyhow!("No migration registered for version {}", v))
+                })
+                .collect::<anyhow::Result<Vec<_>>>()?;
+            for (upgrade, old_version, new_version) in steps {
+                info!("Upgrading DB schema to version {}", new_version.0);
+                run_migration_step(pool, upgrade, old_version, new_version).await?;
+            }
+            Ok(())
+        }
+        Ordering::Greater => {
+            let steps = (target.0 + 1..=version.0)
+                .rev()
+                .map(|v| {
+                    find_migration(SchemaVersion(v))
+                        .and_then(|m| m.downgrade)
+                        .map(|downgrade| (downgrade, SchemaVersion(v), SchemaVersion(v - 1)))
+                        .ok_or_else(|| {
+                            anyhow::anyhow!(
+                                "DB version downgrading from {} to {} is not supported: no \
+                                 down-migration registered for version {}",
+                                version.0,
+                                target.0,
+                                v
+                            )
+                        })
+                })
+                .collect::<anyhow::Result<Vec<_>>>()?;
+            for (downgrade, old_version, new_version) in steps {
+                warn!("Downgrading DB schema to version {}", new_version.0);
+                run_migration_step(pool, downgrade, old_version, new_version).await?;
+            }
+            Ok(())
+        }
+    }
+}
+
+/// Arbitrary key of the advisory lock held while migrating.
+const MIGRATION_LOCK_ID: i64 = 0x11da_9001;
+const MIGRATION_LOCK_NAME: &str = "lldap_migrations";
+const MIGRATION_LOCK_POLL_INTERVAL: std::time::Duration = std::time::Duration::from_millis(500);
+/// A SQLite lock row older than this was left by an instance that crashed while migrating.
+const STALE_MIGRATION_LOCK_MINUTES: i64 = 30;
+
+#[derive(FromQueryResult)]
+struct LockResult {
+    locked: Option<i64>,
+}
+
+/// Keeps other instances from migrating the DB until it is released.
+///
+/// On PostgreSQL and MySQL, it's an advisory lock held by the connection of an open transaction,
+/// so the migrations need another connection from the pool. If the instance dies, the lock goes
+/// away with its connection. On SQLite, it's a row of the `migration_locks` table.
+pub struct MigrationLock {
+    transaction: Option<DatabaseTransaction>,
+}
+
+impl MigrationLock {
+    /// Waits up to `timeout` for the lock, and returns `None` if another instance still holds it.
+    pub async fn acquire(
+        pool: &DbConnection,
+        timeout: std::time::Duration,
+    ) -> Result<Option<Self>, DbErr> {
+        let deadline = std::time::Instant::now() + timeout;
+        let transaction = match pool.get_database_backend() {
+            DbBackend::Sqlite => {
+                create_migration_lock_table(pool).await?;
+                None
+            }
+            _ => Some(pool.begin().await?),
+        };
+        let lock = Self { transaction };
+        loop {
+            if lock.try_lock(pool).await? {
+                return Ok(Some(lock));
+            }
+            if std::time::Instant::now() >= deadline {
+                lock.release(pool).await?;
+                return Ok(None);
+            }
+            tokio::time::sleep(MIGRATION_LOCK_POLL_INTERVAL).await;
+        }
+    }
+
+    async fn try_lock(&self, pool: &DbConnection) -> Result<bool, DbErr> {
+        let transaction = match &self.transaction {
+            None => return try_insert_migration_lock_row(pool).await,
+            Some(transaction) => transaction,
+        };
+        let backend = transaction.get_database_backend();
+        let sql = if backend == DbBackend::Postgres {
+            format!(
+                "SELECT pg_try_advisory_xact_lock({})::int::bigint AS locked",
+                MIGRATION_LOCK_ID
+            )
+        } else {
+            format!("SELECT GET_LOCK('{}', 0) AS locked", MIGRATION_LOCK_NAME)
+        };
+        Ok(
+            LockResult::find_by_statement(Statement::from_string(backend, sql))
+                .one(transaction)
+                .await?
+                .and_then(|r| r.locked)
+                == Some(1),
+        )
+    }
+
+    pub async fn release(self, pool: &DbConnection) -> Result<(), DbErr> {
+        match self.transaction {
+            None => {
+                pool.execute(
+                    pool.get_database_backend().build(
+                        Query::delete()
+                            .from_table(MigrationLocks::Table)
+                            .and_where(Expr::col(MigrationLocks::LockId).eq(1)),
+                    ),
+                )
+                .await?;
+            }
+            Some(transaction) => {
+                // The PostgreSQL lock ends with the transaction, the MySQL one with the session.
+                if transaction.get_database_backend() == DbBackend::MySql {
+                    transaction
+                        .execute(Statement::from_string(
+                            DbBackend::MySql,
+                            format!("SELECT RELEASE_LOCK('{}')", MIGRATION_LOCK_NAME),
+                        ))
+                        .await?;
+                }
+                transaction.commit().await?;
+            }
+        }
+        Ok(())
+    }
+}
+
+async fn create_migration_lock_table(pool: &DbConnection) -> Result<(), DbErr> {
+    pool.execute(
+        pool.get_database_backend().build(
+            Table::create()
+                .table(MigrationLocks::Table)
+                .if_not_exists()
+                .col(
+                    ColumnDef::new(MigrationLocks::LockId)
+                        .integer()
+                        .not_null()
+                        .primary_key(),
+                )
+                .col(
+                    ColumnDef::new(MigrationLocks::LockedAt)
+                        .date_time()
+                        .not_null(),
+                ),
+        ),
+    )
+    .await?;
+    Ok(())
+}
+
+async fn try_insert_migration_lock_row(pool: &DbConnection) -> Result<bool, DbErr> {
+    let now = chrono::Utc::now().naive_utc();
+    let stale = pool
+        .execute(
+            pool.get_database_backend().build(
+                Query::delete().from_table(MigrationLocks::Table).and_where(
+                    Expr::col(MigrationLocks::LockedAt)
+                        .lt(now - chrono::Duration::minutes(STALE_MIGRATION_LOCK_MINUTES)),
+                ),
+            ),
+        )
+        .await?;
+    if stale.rows_affected() > 0 {
+        warn!("Removed a stale migration lock");
+    }
+    let inserted = pool
+        .execute(Statement::from_sql_and_values(
+            DbBackend::Sqlite,
+            "INSERT OR IGNORE INTO migration_locks (lock_id, locked_at) VALUES (1, ?)",
+            vec![now.into()],
+        ))
+        .await?;
+    Ok(inserted.rows_affected() == 1)
 }
@@ -0,0 +1,250 @@
+use super::{
+    error::{DomainError, Result},
+    handler::NestedGroupBackendHandler,
+    model::{self, MembershipColumn, UserColumn},
+    sql_audit_log_handler::{self as audit, AuditChange},
+    sql_backend_handler::SqlBackendHandler,
+    types::{GroupId, UserId},
+};
+use async_trait::async_trait;
+use sea_orm::{
+    ActiveModelTrait, ActiveValue, ColumnTrait, ConnectionTrait, EntityTrait, QueryFilter,
+    TransactionTrait,
+};
+use std::collections::{HashMap, HashSet};
+use tracing::{debug, instrument};
+
+/// The graph of the groups nested in other groups.
+#[derive(Debug, Default)]
+pub(crate) struct GroupNesting {
+    children: HashMap<GroupId, Vec<GroupId>>,
+    parents: HashMap<GroupId, Vec<GroupId>>,
+}
+
+impl GroupNesting {
+    pub(crate) async fn load(connection: &impl ConnectionTrait) -> Result<Self> {
+        let mut nesting = Self::default();
+        for edge in model::GroupMembership::find().all(connection).await? {
+            nesting
+                .children
+                .entry(edge.parent_group_id)
+                .or_default()
+                .push(edge.child_group_id);
+            nesting
+                .parents
+                .entry(edge.child_group_id)
+                .or_default()
+                .push(edge.parent_group_id);
+        }
+        Ok(nesting)
+    }
+
+    pub(crate) fn is_empty(&self) -> bool {
+        self.children.is_empty()
+    }
+
+    /// The groups reachable from `group_id` in at most `max_depth` steps, excluding `group_id`.
+    fn walk(
+        edges: &HashMap<GroupId, Vec<GroupId>>,
+        group_id: GroupId,
+        max_depth: Option<usize>,
+    ) -> Vec<GroupId> {
+        let mut visited = HashSet::from([group_id]);
+        let mut result = Vec::new();
+        let mut frontier = vec![group_id];
+        let mut depth = 0;
+        while !frontier.is_empty() && max_depth.map(|max| depth < max).unwrap_or(true) {
+            depth += 1;
+            frontier = frontier
+                .iter()
+                .filter_map(|g| edges.get(g))
+                .flatten()
+                .copied()
+                .filter(|g| visited.insert(*g))
+                .collect();
+            result.extend(&frontier);
+        }
+        result
+    }
+
+    /// The groups nested (transitively) in `group_id`.
+    pub(crate) fn subgroups(&self, group_id: GroupId, max_depth: u8) -> Vec<GroupId> {
+        Self::walk(&self.children, group_id, Some(max_depth.into()))
+    }
+
+    /// The groups that (transitively) contain `group_id`.
+    pub(crate) fn supergroups(&self, group_id: GroupId, max_depth: u8) -> Vec<GroupId> {
+        Self::walk(&self.parents, group_id, Some(max_depth.into()))
+    }
+
+    fn would_create_cycle(&self, parent: GroupId, child: GroupId) -> bool {
+        parent == child || Self::walk(&self.children, child, None).contains(&parent)
+    }
+}
+
+#[async_trait]
+impl NestedGroupBackendHandler for SqlBackendHandler {
+    #[instrument(skip_all, level = "debug", err)]
+    async fn add_group_to_group(&self, parent: GroupId, child: GroupId) -> Result<()> {
+        debug!(?parent, ?child);
+        let transaction = self.sql_pool.begin().await?;
+        if GroupNesting::load(&transaction)
+            .await?
+            .would_create_cycle(parent, child)
+        {
+            return Err(DomainError::ValidationError(format!(
+                "Adding group {:?} to group {:?} would create a cycle",
+                child, parent
+            )));
+        }
+        let new_membership = model::group_memberships::ActiveModel {
+            parent_group_id: ActiveValue::Set(parent),
+            child_group_id: ActiveValue::Set(child),
+        };
+        new_membership.insert(&transaction).await?;
+        let events = self
+            .write_audit_log(
+                &transaction,
+                vec![AuditChange::group(parent, audit::SUBGROUP)
+                    .values(None, Some(child.0.to_string().into_bytes()))],
+            )
+            .await?;
+        transaction.commit().await?;
+        events.publish();
+        Ok(())
+    }
+
+    #[instrument(skip_all, level = "debug", err)]
+    async fn remove_group_from_group(&self, parent: GroupId, child: GroupId) -> Result<()> {
+        debug!(?parent, ?child);
+        let transaction = self.sql_pool.begin().await?;
+        let res = model::GroupMembership::delete_by_id((parent, child))
+            .exec(&transaction)
+            .await?;
+        if res.rows_affected == 0 {
+            return Err(DomainError::EntityNotFound(format!(
+                "No such group membership: {:?} -> {:?}",
+                child, parent
+            )));
+        }
+        let events = self
+            .write_audit_log(
+                &transaction,
+                vec![AuditChange::group(parent, audit::SUBGROUP)
+                    .values(Some(child.0.to_string().into_bytes()), None)],
+            )
+            .await?;
+        transaction.commit().await?;
+        events.publish();
+        Ok(())
+    }
+
+    #[instrument(skip_all, level = "debug", ret, err)]
+    async fn effective_members(&self, group_id: GroupId) -> Result<HashSet<UserId>> {
+        debug!(?group_id);
+        let mut groups = GroupNesting::load(&self.sql_pool)
+            .await?
+            .subgroups(group_id, self.config.max_group_nesting_depth);
+        groups.push(group_id);
+        Ok(model::Membership::find()
+            .filter(MembershipColumn::GroupId.is_in(groups))
+            .find_also_related(model::User)
+            .filter(UserColumn::DeletedAt.is_null())
+            .all(&self.sql_pool)
+            .await?
+            .into_iter()
+            .map(|(membership, _)| membership.user_id)
+            .collect())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::domain::{
+        handler::{GroupBackendHandler, GroupRequestFilter, UserBackendHandler, UserRequestFilter},
+        sql_backend_handler::tests::*,
+    };
+
+    #[tokio::test]
+    async fn test_effective_members() {
+        let fixture = TestFixture::new().await;
+        let handler = &fixture.handler;
+        let [best, worst, empty] = [fixture.groups[0], fixture.groups[1], fixture.groups[2]];
+        handler.add_group_to_group(empty, best).await.unwrap();
+        handler.add_group_to_group(best, worst).await.unwrap();
+        assert_eq!(
+            handler.effective_members(empty).await.unwrap(),
+            HashSet::from(["bob", "patrick", "john"].map(UserId::new))
+        );
+        assert_eq!(
+            handler.effective_members(best).await.unwrap(),
+            HashSet::from(["bob", "patrick", "john"].map(UserId::new))
+        );
+        assert_eq!(
+            handler.effective_members(worst).await.unwrap(),
+            HashSet::from(["patrick", "john"].map(UserId::new))
+        );
+
+        let groups = handler
+            .list_groups(Some(GroupRequestFilter::GroupId(empty)))
+            .await
+            .unwrap();
+        assert_eq!(
+            groups[0].users,
+            ["bob", "john", "patrick"].map(UserId::new).to_vec()
+        );
+        let users = handler
+            .list_users(Some(UserRequestFilter::UserId(UserId::new("john"))), true)
+            .await
+            .unwrap();
+        assert_eq!(
+            users[0]
+                .groups
+                .as_ref()
+                .unwrap()
+                .iter()
+                .map(|g| g.display_name.as_str())
+                .collect::<Vec<_>>(),
+            vec!["Best Group", "Worst Group", "Empty Group"]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_nesting_max_depth() {
+        let mut config = get_default_config();
+        config.max_group_nesting_depth = 1;
+        let fixture = TestFixture::with_config(config).await;
+        let handler = &fixture.handler;
+        let [best, worst, empty] = [fixture.groups[0], fixture.groups[1], fixture.groups[2]];
+        handler.add_group_to_group(empty, best).await.unwrap();
+        handler.add_group_to_group(best, worst).await.unwrap();
+        assert_eq!(
+            handler.effective_members(empty).await.unwrap(),
+            HashSet::from(["bob", "patrick"].map(UserId::new))
+        );
+    }
+
+    #[tokio::test]
+    async fn test_nesting_cycle() {
+        let fixture = TestFixture::new().await;
+        let handler = &fixture.handler;
+        let [best, worst, empty] = [fixture.groups[0], fixture.groups[1], fixture.groups[2]];
+        handler.add_group_to_group(best, worst).await.unwrap();
+        handler.add_group_to_group(worst, empty).await.unwrap();
+        assert!(matches!(
+            handler.add_group_to_group(empty, best).await,
+            Err(DomainError::ValidationError(_))
+        ));
+        assert!(matches!(
+            handler.add_group_to_group(best, best).await,
+            Err(DomainError::ValidationError(_))
+        ));
+        handler.remove_group_from_group(worst, empty).await.unwrap();
+        handler.add_group_to_group(empty, best).await.unwrap();
+        assert!(matches!(
+            handler.remove_group_from_group(worst, empty).await,
+            Err(DomainError::EntityNotFound(_))
+        ));
+    }
+}
@@ -0,0 +1,423 @@
+use super::{
+    error::{DomainError, Result},
+    model::{self, OidcClientsColumn},
+    oidc_handler::*,
+    sql_backend_handler::SqlBackendHandler,
+};
+use async_trait::async_trait;
+use sea_orm::{ActiveModelTrait, ActiveValue, EntityTrait, QueryOrder};
+use sha2::{Digest, Sha256};
+use tracing::{debug, instrument};
+
+const AUTHORIZATION_CODE_VALIDITY_MINUTES: i64 = 10;
+
+pub(crate) fn gen_random_token(len: usize) -> String {
+    use rand::{distributions::Alphanumeric, Rng};
+    rand::rngs::OsRng
+        .sample_iter(Alphanumeric)
+        .map(char::from)
+        .take(len)
+        .collect()
+}
+
+/// The secrets and codes are long random strings, a plain hash is enough to protect them.
+pub(crate) fn hash_token(token: &str) -> String {
+    Sha256::digest(token.as_bytes())
+        .iter()
+        .map(|b| format!("{:02x}", b))
+        .collect()
+}
+
+fn split_list(list: &str) -> Vec<String> {
+    list.split_whitespace().map(str::to_owned).collect()
+}
+
+fn join_list(field: &str, items: &[String]) -> Result<String> {
+    if items.is_empty() {
+        return Err(DomainError::ValidationError(format!(
+            "The {} of an OIDC client cannot be empty",
+            field
+        )));
+    }
+    if let Some(item) = items
+        .iter()
+        .find(|i| i.is_empty() || i.contains(char::is_whitespace))
+    {
+        return Err(DomainError::ValidationError(format!(
+            "Invalid OIDC client {}: '{}'",
+            field, item
+        )));
+    }
+    Ok(items.join(" "))
+}
+
+impl From<model::oidc_clients::Model> for OidcClient {
+    fn from(client: model::oidc_clients::Model) -> Self {
+        Self {
+            client_id: client.client_id,
+            redirect_uris: split_list(&client.redirect_uris),
+            allowed_scopes: split_list(&client.allowed_scopes),
+            creation_date: client.creation_date,
+        }
+    }
+}
+
+impl SqlBackendHandler {
+    async fn get_oidc_client_model(&self, client_id: &str) -> Result<model::oidc_clients::Model> {
+        model::OidcClients::find_by_id(client_id.to_owned())
+            .one(&self.sql_pool)
+            .await?
+            .ok_or_else(|| DomainError::EntityNotFound(format!("OIDC client {}", client_id)))
+    }
+}
+
+#[async_trait]
+impl OidcHandler for SqlBackendHandler {
+    #[instrument(skip_all, level = "debug", err)]
+    async fn create_oidc_client(&self, request: CreateOidcClientRequest) -> Result<String> {
+        debug!(?request);
+        if request.client_id.is_empty() || request.client_id.contains(char::is_whitespace) {
+            return Err(DomainError::ValidationError(format!(
+                "Invalid OIDC client ID: '{}'",
+                request.client_id
+            )));
+        }
+        if model::OidcClients::find_by_id(request.client_id.clone())
+            .one(&self.sql_pool)
+            .await?
+            .is_some()
+        {
+            return Err(DomainError::ValidationError(format!(
+                "OIDC client {} already exists",
+                request.client_id
+            )));
+        }
+        let client_secret = gen_random_token(48);
+        model::oidc_clients::ActiveModel {
+            client_id: ActiveValue::Set(request.client_id),
+            client_secret_hash: ActiveValue::Set(hash_token(&client_secret)),
+            redirect_uris: ActiveValue::Set(join_list("redirect URIs", &request.redirect_uris)?),
+            allowed_scopes: ActiveValue::Set(join_list("scopes", &request.allowed_scopes)?),
+            creation_date: ActiveValue::Set(chrono::Utc::now()),
+        }
+        .insert(&self.sql_pool)
+        .await?;
+        Ok(client_secret)
+    }
+
+    #[instrument(skip_all, level = "debug", err)]
+    async fn list_oidc_clients(&self) -> Result<Vec<OidcClient>> {
+        Ok(model::OidcClients::find()
+            .order_by_asc(OidcClientsColumn::ClientId)
+            .all(&self.sql_pool)
+            .await?
+            .into_iter()
+            .map(OidcClient::from)
+            .collect())
+    }
+
+    #[instrument(skip_all, level = "debug", err)]
+    async fn get_oidc_client(&self, client_id: &str) -> Result<OidcClient> {
+        debug!(?client_id);
+        Ok(self.get_oidc_client_model(client_id).await?.into())
+    }
+
+    #[instrument(skip_all, level = "debug", err)]
+    async fn delete_oidc_client(&self, client_id: &str) -> Result<()> {
+        debug!(?client_id);
+        let res = model::OidcClients::delete_by_id(client_id.to_owned())
+            .exec(&self.sql_pool)
+            .await?;
+        if res.rows_affected == 0 {
+            return Err(DomainError::EntityNotFound(format!(
+                "OIDC client {}",
+                client_id
+            )));
+        }
+        Ok(())
+    }
+
+    #[instrument(skip_all, level = "debug", err)]
+    async fn authenticate_oidc_client(
+        &self,
+        client_id: &str,
+        client_secret: &str,
+    ) -> Result<OidcClient> {
+        debug!(?client_id);
+        let client = self
+            .get_oidc_client_model(client_id)
+            .await
+            .map_err(|_| DomainError::AuthenticationError("Invalid OIDC client".to_owned()))?;
+        if client.client_secret_hash != hash_token(client_secret) {
+            return Err(DomainError::AuthenticationError(
+                "Invalid OIDC client".to_owned(),
+            ));
+        }
+        Ok(client.into())
+    }
+
+    #[instrument(skip_all, level = "debug", err)]
+    async fn create_oidc_authorization_code(
+        &self,
+        authorization: OidcAuthorization,
+    ) -> Result<String> {
+        debug!(?authorization);
+        let code = gen_random_token(48);
+        model::oidc_authorization_codes::ActiveModel {
+            code_hash: ActiveValue::Set(hash_token(&code)),
+            client_id: ActiveValue::Set(authorization.client_id),
+            user_id: ActiveValue::Set(authorization.user_id),
+            redirect_uri: ActiveValue::Set(authorization.redirect_uri),
+            scopes: ActiveValue::Set(authorization.scopes.join(" ")),
+            nonce: ActiveValue::Set(authorization.nonce),
+            expiry_date: ActiveValue::Set(
+                chrono::Utc::now() + chrono::Duration::minutes(AUTHORIZATION_CODE_VALIDITY_MINUTES),
+            ),
+        }
+        .insert(&self.sql_pool)
+        .await?;
+        Ok(code)
+    }
+
+    #[instrument(skip_all, level = "debug", err)]
+    async fn consume_oidc_authorization_code(
+        &self,
+        client_id: &str,
+        code: &str,
+    ) -> Result<OidcAuthorization> {
+        debug!(?client_id);
+        let invalid_code = || DomainError::AuthenticationError("Invalid authorization code".into());
+        let code_hash = hash_token(code);
+        let stored = model::OidcAuthorizationCodes::find_by_id(code_hash.clone())
+            .one(&self.sql_pool)
+            .await?
+            .ok_or_else(invalid_code)?;
+        // Delete the code even if it's presented by the wrong client: it may have leaked.
+        let res = model::OidcAuthorizationCodes::delete_by_id(code_hash)
+            .exec(&self.sql_pool)
+            .await?;
+        // Nothing was deleted if the code was consumed concurrently.
+        if res.rows_affected == 0
+            || stored.client_id != client_id
+            || stored.expiry_date < chrono::Utc::now()
+        {
+            return Err(invalid_code());
+        }
+        Ok(OidcAuthorization {
+            client_id: stored.client_id,
+            user_id: stored.user_id,
+            redirect_uri: stored.redirect_uri,
+            scopes: split_list(&stored.scopes),
+            nonce: stored.nonce,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::domain::{sql_backend_handler::tests::*, types::UserId};
+    use sea_orm::{sea_query::Expr, ColumnTrait, QueryFilter};
+
+    fn create_request(client_id: &str) -> CreateOidcClientRequest {
+        CreateOidcClientRequest {
+            client_id: client_id.to_owned(),
+            redirect_uris: vec!["https://app.example.com/callback".to_owned()],
+            allowed_scopes: vec!["openid".to_owned(), "profile".to_owned()],
+        }
+    }
+
+    fn authorization(client_id: &str) -> OidcAuthorization {
+        OidcAuthorization {
+            client_id: client_id.to_owned(),
+            user_id: UserId::new("bob"),
+            redirect_uri: "https://app.example.com/callback".to_owned(),
+            scopes: vec!["openid".to_owned()],
+            nonce: Some("n-0S6_WzA2Mj".to_owned()),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_create_and_authenticate_client() {
+        let fixture = TestFixture::new().await;
+        let secret = fixture
+            .handler
+            .create_oidc_client(create_request("app"))
+            .await
+            .unwrap();
+        let client = fixture
+            .handler
+            .authenticate_oidc_client("app", &secret)
+            .await
+            .unwrap();
+        assert_eq!(
+            client.redirect_uris,
+            vec!["https://app.example.com/callback"]
+        );
+        assert_eq!(client.allowed_scopes, vec!["openid", "profile"]);
+        assert_eq!(
+            fixture.handler.get_oidc_client("app").await.unwrap(),
+            client
+        );
+        assert!(matches!(
+            fixture
+                .handler
+                .authenticate_oidc_client("app", "wrong")
+                .await,
+            Err(DomainError::AuthenticationError(_))
+        ));
+        assert!(matches!(
+            fixture
+                .handler
+                .authenticate_oidc_client("other", &secret)
+                .await,
+            Err(DomainError::AuthenticationError(_))
+        ));
+        // The secret is not stored.
+        let stored = model::OidcClients::find_by_id("app".to_owned())
+            .one(&fixture.handler.sql_pool)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_ne!(stored.client_secret_hash, secret);
+    }
+
+    #[tokio::test]
+    async fn test_create_invalid_client() {
+        let fixture = TestFixture::new().await;
+        fixture
+            .handler
+            .create_oidc_client(create_request("app"))
+            .await
+            .unwrap();
+        for request in [
+            create_request("app"),
+            create_request(""),
+            create_request("my app"),
+            CreateOidcClientRequest {
+                redirect_uris: vec![],
+                ..create_request("other")
+            },
+            CreateOidcClientRequest {
+                allowed_scopes: vec!["openid profile".to_owned()],
+                ..create_request("other")
+            },
+        ] {
+            assert!(matches!(
+                fixture.handler.create_oidc_client(request).await,
+                Err(DomainError::ValidationError(_))
+            ));
+        }
+        assert_eq!(fixture.handler.list_oidc_clients().await.unwrap().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_delete_client() {
+        let fixture = TestFixture::new().await;
+        fixture
+            .handler
+            .create_oidc_client(create_request("app"))
+            .await
+            .unwrap();
+        let code = fixture
+            .handler
+            .create_oidc_authorization_code(authorization("app"))
+            .await
+            .unwrap();
+        fixture.handler.delete_oidc_client("app").await.unwrap();
+        assert!(fixture
+            .handler
+            .list_oidc_clients()
+            .await
+            .unwrap()
+            .is_empty());
+        // The codes go away with the client.
+        fixture
+            .handler
+            .consume_oidc_authorization_code("app", &code)
+            .await
+            .unwrap_err();
+        assert!(matches!(
+            fixture.handler.delete_oidc_client("app").await,
+            Err(DomainError::EntityNotFound(_))
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_authorization_code_single_use() {
+        let fixture = TestFixture::new().await;
+        fixture
+            .handler
+            .create_oidc_client(create_request("app"))
+            .await
+            .unwrap();
+        let code = fixture
+            .handler
+            .create_oidc_authorization_code(authorization("app"))
+            .await
+            .unwrap();
+        assert_eq!(
+            fixture
+                .handler
+                .consume_oidc_authorization_code("app", &code)
+                .await
+                .unwrap(),
+            authorization("app")
+        );
+        assert!(matches!(
+            fixture
+                .handler
+                .consume_oidc_authorization_code("app", &code)
+                .await,
+            Err(DomainError::AuthenticationError(_))
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_authorization_code_wrong_client_or_expired() {
+        let fixture = TestFixture::new().await;
+        for client_id in ["app", "other"] {
+            fixture
+                .handler
+                .create_oidc_client(create_request(client_id))
+                .await
+                .unwrap();
+        }
+        let code = fixture
+            .handler
+            .create_oidc_authorization_code(authorization("app"))
+            .await
+            .unwrap();
+        fixture
+            .handler
+            .consume_oidc_authorization_code("other", &code)
+            .await
+            .unwrap_err();
+        // The code was burned by the wrong client.
+        fixture
+            .handler
+            .consume_oidc_authorization_code("app", &code)
+            .await
+            .unwrap_err();
+
+        let code = fixture
+            .handler
+            .create_oidc_authorization_code(authorization("app"))
+            .await
+            .unwrap();
+        model::OidcAuthorizationCodes::update_many()
+            .col_expr(
+                model::OidcAuthorizationCodesColumn::ExpiryDate,
+                Expr::value(chrono::Utc::now() - chrono::Duration::minutes(1)),
+            )
+            .filter(model::OidcAuthorizationCodesColumn::ClientId.eq("app"))
+            .exec(&fixture.handler.sql_pool)
+            .await
+            .unwrap();
+        fixture
+            .handler
+            .consume_oidc_authorization_code("app", &code)
+            .await
+            .unwrap_err();
+    }
+}
@@ -1,22 +1,32 @@
 use super::{
+    auth_backend::AuthOutcome,
     error::{DomainError, Result},
-    handler::{BindRequest, LoginHandler},
+    handler::{BindRequest, LoginHandler, UserBackendHandler},
+    legacy_password_hash::LegacyPasswordScheme,
     model::{self, UserColumn},
     opaque_handler::{login, registration, OpaqueHandler},
+    scram::ScramVerifier,
+    sql_audit_log_handler::{self as audit, AuditChange},
     sql_backend_handler::SqlBackendHandler,
     types::UserId,
 };
+use crate::infra::configuration::LegacyPasswordHashes;
 use async_trait::async_trait;
 use lldap_auth::opaque;
-use sea_orm::{ActiveModelTrait, ActiveValue, EntityTrait, FromQueryResult, QuerySelect};
+use sea_orm::{
+    sea_query::Expr, ActiveModelTrait, ActiveValue, ColumnTrait, EntityTrait, FromQueryResult,
+    QueryFilter, QuerySelect, TransactionTrait,
+};
 use secstr::SecUtf8;
 use tracing::{debug, instrument};
 
 type SqlOpaqueHandler = SqlBackendHandler;
 
+/// Without a password file, the login is run against a dummy one and always fails: this takes
+/// about as long as a real check, so the timing doesn't reveal whether the user exists.
 #[instrument(skip_all, level = "debug", err)]
 fn passwords_match(
-    password_file_bytes: &[u8],
+    password_file_bytes: Option<&[u8]>,
     clear_password: &str,
     server_setup: &opaque::server::ServerSetup,
     username: &UserId,
@@ -25,12 +35,14 @@ fn passwords_match(
     let mut rng = rand::rngs::OsRng;
     let client_login_start_result = client::login::start_login(clear_password, &mut rng)?;
 
-    let password_file = server::ServerRegistration::deserialize(password_file_bytes)
+    let password_file = password_file_bytes
+        .map(server::ServerRegistration::deserialize)
+        .transpose()
         .map_err(opaque::AuthenticationError::ProtocolError)?;
     let server_login_start_result = server::login::start_login(
         &mut rng,
         server_setup,
-        Some(password_file),
+        password_file,
         client_login_start_result.message,
         username.as_str(),
     )?;
@@ -41,8 +53,51 @@ fn passwords_match(
     Ok(())
 }
 
+fn legacy_passwords_match(
+    legacy_password_hashes: LegacyPasswordHashes,
+    scheme: LegacyPasswordScheme,
+    password_hash: &[u8],
+    clear_password: &str,
+) -> Result<()> {
+    if legacy_password_hashes == LegacyPasswordHashes::Reject {
+        return Err(DomainError::AuthenticationError(format!(
+            "{:?} password hashes are not accepted",
+            scheme
+        )));
+    }
+    if !scheme.verify(password_hash, clear_password) {
+        return Err(DomainError::AuthenticationError(format!(
+            "Wrong password for the {:?} hash",
+            scheme
+        )));
+    }
+    Ok(())
+}
+
+/// Computes the password file for the password, without storing it.
+pub(crate) fn make_password_file(
+    server_setup: &opaque::server::ServerSetup,
+    username: &UserId,
+    password: &str,
+) -> Result<Vec<u8>> {
+    use opaque::{client, server};
+    let mut rng = rand::rngs::OsRng;
+    let client_registration_start = client::registration::start_registration(password, &mut rng)?;
+    let server_registration_start = server::registration::start_registration(
+        server_setup,
+        client_registration_start.message,
+        username.as_str(),
+    )?;
+    let client_registration_finish = client::registration::finish_registration(
+        client_registration_start.state,
+        server_registration_start.message,
+        &mut rng,
+    )?;
+    Ok(server::registration::get_password_file(client_registration_finish.message).serialize())
+}
+
 impl SqlBackendHandler {
-    fn get_orion_secret_key(&self) -> Result<orion::aead::SecretKey> {
+    pub(crate) fn get_orion_secret_key(&self) -> Result<orion::aead::SecretKey> {
         Ok(orion::aead::SecretKey::from_slice(
             self.config.get_server_keys().private(),
         )?)
@@ -56,6 +111,7 @@ impl SqlBackendHandler {
         }
         // Fetch the previously registered password file from the DB.
         Ok(model::User::find_by_id(user_id)
+            .filter(UserColumn::DeletedAt.is_null())
             .select_only()
             .column(UserColumn::PasswordHash)
             .into_model::<OnlyPasswordHash>()
@@ -63,36 +119,330 @@ impl SqlBackendHandler {
             .await?
             .and_then(|u| u.password_hash))
     }
+
+    /// Fails if the user must change their password, either because it was required or because
+    /// the password is older than `password_max_age_days`.
+    #[instrument(skip_all, level = "debug", err)]
+    async fn check_password_status(&self, user_id: &UserId) -> Result<()> {
+        #[derive(FromQueryResult)]
+        struct PasswordStatus {
+            password_changed_at: Option<chrono::DateTime<chrono::Utc>>,
+            must_change_password: bool,
+        }
+        let status = model::User::find_by_id(user_id.clone())
+            .select_only()
+            .column(UserColumn::PasswordChangedAt)
+            .column(UserColumn::MustChangePassword)
+            .into_model::<PasswordStatus>()
+            .one(&self.sql_pool)
+            .await?
+            .ok_or_else(|| DomainError::EntityNotFound(user_id.to_string()))?;
+        // Passwords set before their age was tracked don't expire.
+        let expired = match (
+            self.config.password_max_age_days,
+            status.password_changed_at,
+        ) {
+            (Some(max_age), Some(changed_at)) => {
+                changed_at + chrono::Duration::days(max_age.into()) < chrono::Utc::now()
+            }
+            _ => false,
+        };
+        if status.must_change_password || expired {
+            debug!(?status.must_change_password, expired);
+            return Err(DomainError::PasswordChangeRequired(user_id.to_string()));
+        }
+        Ok(())
+    }
+
+    /// Fails if the account is locked out after too many failed logins. This is checked for
+    /// unknown users as well.
+    #[instrument(skip_all, level = "debug", err)]
+    async fn check_lockout(&self, user_id: &UserId) -> Result<()> {
+        if self.config.failed_login_lockout_threshold.is_none() {
+            return Ok(());
+        }
+        let locked_until = model::FailedLoginAttempts::find_by_id(user_id.clone())
+            .one(&self.sql_pool)
+            .await?
+            .and_then(|attempts| attempts.locked_until);
+        match locked_until {
+            Some(locked_until) if locked_until > chrono::Utc::now() => {
+                debug!(?locked_until);
+                Err(DomainError::AccountLocked(user_id.to_string()))
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// Counts a failed login, and locks the account once the threshold is reached. Every further
+    /// failure extends the lock, until a successful login resets the count.
+    #[instrument(skip_all, level = "debug", err)]
+    async fn record_failed_login(&self, user_id: &UserId) -> Result<()> {
+        let threshold = match self.config.failed_login_lockout_threshold {
+            Some(threshold) => threshold,
+            None => return Ok(()),
+        };
+        let transaction = self.sql_pool.begin().await?;
+        let now = chrono::Utc::now();
+        let previous = model::FailedLoginAttempts::find_by_id(user_id.clone())
+            .one(&transaction)
+            .await?;
+        let count = previous
+            .as_ref()
+            .map(|a| a.count)
+            .unwrap_or(0)
+            .saturating_add(1);
+        let locked_until = if i64::from(count) >= i64::from(threshold) {
+            debug!(?user_id, count, "Locking the account");
+            Some(now + chrono::Duration::minutes(self.config.failed_login_lockout_minutes.into()))
+        } else {
+            None
+        };
+        let attempts = model::failed_login_attempts::ActiveModel {
+            user_id: ActiveValue::Set(user_id.clone()),
+            count: ActiveValue::Set(count),
+            last_attempt: ActiveValue::Set(now),
+            locked_until: ActiveValue::Set(locked_until),
+        };
+        if previous.is_some() {
+            attempts.update(&transaction).await?;
+        } else {
+            attempts.insert(&transaction).await?;
+        }
+        transaction.commit().await?;
+        Ok(())
+    }
+
+    /// Replaces an imported hash with a password file or a hash of the default KDF, now that the
+    /// password is known. It's still the same password: its age is kept, and it isn't audited as
+    /// a change.
+    #[instrument(skip_all, level = "debug", err)]
+    async fn replace_legacy_password_hash(
+        &self,
+        user_id: &UserId,
+        legacy_hash: &[u8],
+        new_hash: Vec<u8>,
+    ) -> Result<()> {
+        // Unless the password was changed in the meantime.
+        model::User::update_many()
+            .col_expr(UserColumn::PasswordHash, Expr::value(new_hash))
+            .filter(ColumnTrait::eq(&UserColumn::UserId, user_id))
+            .filter(ColumnTrait::eq(
+                &UserColumn::PasswordHash,
+                legacy_hash.to_vec(),
+            ))
+            .exec(&self.sql_pool)
+            .await?;
+        Ok(())
+    }
+
+    /// Stores the SCRAM verifier of the password, now that it is known. Unless the password was
+    /// changed in the meantime.
+    #[instrument(skip_all, level = "debug", err)]
+    async fn store_scram_verifier(
+        &self,
+        user_id: &UserId,
+        password_hash: &[u8],
+        clear_password: &str,
+    ) -> Result<()> {
+        let verifier = ScramVerifier::new(clear_password).to_string();
+        model::User::update_many()
+            .col_expr(UserColumn::ScramSha256Verifier, Expr::value(verifier))
+            .filter(ColumnTrait::eq(&UserColumn::UserId, user_id))
+            .filter(ColumnTrait::eq(
+                &UserColumn::PasswordHash,
+                password_hash.to_vec(),
+            ))
+            .exec(&self.sql_pool)
+            .await?;
+        Ok(())
+    }
+
+    /// Checks the password with the authentication backend that the user is routed to, if any.
+    /// Returns `None` if the local password must be checked instead: the user isn't routed, or
+    /// the backend doesn't handle it.
+    #[instrument(skip_all, level = "debug", err)]
+    async fn check_delegated_password(&self, request: &BindRequest) -> Result<Option<bool>> {
+        if self.auth_backends.is_empty() {
+            return Ok(None);
+        }
+        // Only the existing users can be authenticated elsewhere.
+        if model::User::find_by_id(request.name.clone())
+            .filter(UserColumn::DeletedAt.is_null())
+            .one(&self.sql_pool)
+            .await?
+            .is_none()
+        {
+            return Ok(None);
+        }
+        let group_names = if self.auth_backends.has_group_routes() {
+            self.get_user_groups(&request.name)
+                .await?
+                .into_iter()
+                .map(|group| group.display_name)
+                .collect()
+        } else {
+            Vec::new()
+        };
+        let (name, backend) = match self.auth_backends.route(&request.name, &group_names) {
+            None => return Ok(None),
+            Some(route) => route,
+        };
+        let outcome = backend
+            .check_password(&request.name, &request.password)
+            .await
+            .map_err(|e| {
+                DomainError::InternalError(format!(
+                    "Error from the authentication backend `{}`: {:#}",
+                    name, e
+                ))
+            })?;
+        debug!(
+            ?outcome,
+            "Authentication backend `{}` for {}", name, &request.name
+        );
+        Ok(match outcome {
+            AuthOutcome::Accepted => Some(true),
+            AuthOutcome::Rejected => Some(false),
+            AuthOutcome::NotHandled => None,
+        })
+    }
+
+    async fn reset_failed_logins(&self, user_id: &UserId) -> Result<()> {
+        if self.config.failed_login_lockout_threshold.is_some() {
+            model::FailedLoginAttempts::delete_by_id(user_id.clone())
+                .exec(&self.sql_pool)
+                .await?;
+        }
+        Ok(())
+    }
 }
 
 #[async_trait]
 impl LoginHandler for SqlBackendHandler {
     #[instrument(skip_all, level = "debug", err)]
     async fn bind(&self, request: BindRequest) -> Result<()> {
-        if let Some(password_hash) = self
-            .get_password_file_for_user(request.name.clone())
-            .await?
-        {
-            if let Err(e) = passwords_match(
-                &password_hash,
-                &request.password,
-                self.config.get_server_setup(),
-                &request.name,
-            ) {
-                debug!(r#"Invalid password for "{}": {}"#, &request.name, e);
-            } else {
-                return Ok(());
+        if let Some(accepted) = self.check_delegated_password(&request).await? {
+            self.check_lockout(&request.name).await?;
+            if !accepted {
+                debug!(r#"Invalid delegated password for "{}""#, &request.name);
+                self.record_failed_login(&request.name).await?;
+                return Err(DomainError::AuthenticationError(format!(
+                    " for user '{}'",
+                    request.name
+                )));
             }
-        } else {
+            // The password is managed by the backend: its local expiry doesn't apply.
+            return self.reset_failed_logins(&request.name).await;
+        }
+        let password_hash = self
+            .get_password_file_for_user(request.name.clone())
+            .await?;
+        if password_hash.is_none() {
             debug!(
                 r#"User "{}" doesn't exist or has no password"#,
                 &request.name
             );
         }
-        Err(DomainError::AuthenticationError(format!(
-            " for user '{}'",
-            request.name
-        )))
+        let legacy_hash = password_hash
+            .as_deref()
+            .and_then(|hash| Some((LegacyPasswordScheme::detect(hash)?, hash)));
+        let password_check = match legacy_hash {
+            Some((scheme, hash)) => legacy_passwords_match(
+                self.config.legacy_password_hashes,
+                scheme,
+                hash,
+                &request.password,
+            ),
+            None => passwords_match(
+                password_hash.as_deref(),
+                &request.password,
+                self.config.get_server_setup(),
+                &request.name,
+            ),
+        };
+        // A locked account stays locked even with the right password.
+        self.check_lockout(&request.name).await?;
+        if let Err(e) = password_check {
+            debug!(r#"Invalid password for "{}": {}"#, &request.name, e);
+            self.record_failed_login(&request.name).await?;
+            return Err(DomainError::AuthenticationError(format!(
+                " for user '{}'",
+                request.name
+            )));
+        }
+        self.reset_failed_logins(&request.name).await?;
+        if let Some(hash) = &password_hash {
+            // Before the hash is upgraded: the verifier is for the same password.
+            if self.config.ldap_sasl_scram_sha256
+                && self.get_scram_verifier(&request.name).await?.is_none()
+            {
+                debug!("Storing the SCRAM verifier of {}", &request.name);
+                self.store_scram_verifier(&request.name, hash, &request.password)
+                    .await?;
+            }
+        }
+        if let Some((scheme, hash)) = legacy_hash {
+            let default_scheme = LegacyPasswordScheme::from(self.config.default_password_kdf);
+            match self.config.legacy_password_hashes {
+                LegacyPasswordHashes::Upgrade => {
+                    debug!(?scheme, "Upgrading the password hash of {}", &request.name);
+                    let password_file = make_password_file(
+                        self.config.get_server_setup(),
+                        &request.name,
+                        &request.password,
+                    )?;
+                    self.replace_legacy_password_hash(&request.name, hash, password_file)
+                        .await?;
+                }
+                LegacyPasswordHashes::Keep if scheme != default_scheme => {
+                    debug!(
+                        ?scheme,
+                        ?default_scheme,
+                        "Re-hashing the password of {}",
+                        &request.name
+                    );
+                    let new_hash = default_scheme
+                        .hash(&request.password)
+                        .map_err(|e| DomainError::InternalError(e.to_string()))?;
+                    self.replace_legacy_password_hash(&request.name, hash, new_hash)
+                        .await?;
+                }
+                LegacyPasswordHashes::Keep | LegacyPasswordHashes::Reject => {}
+            }
+        }
+        self.check_password_status(&request.name).await
+    }
+
+    #[instrument(skip_all, level = "debug", err)]
+    async fn get_scram_verifier(&self, user_id: &UserId) -> Result<Option<String>> {
+        #[derive(FromQueryResult)]
+        struct OnlyScramVerifier {
+            scram_sha256_verifier: Option<String>,
+        }
+        Ok(model::User::find_by_id(user_id.clone())
+            .filter(UserColumn::DeletedAt.is_null())
+            .select_only()
+            .column(UserColumn::ScramSha256Verifier)
+            .into_model::<OnlyScramVerifier>()
+            .one(&self.sql_pool)
+            .await?
+            .and_then(|u| u.scram_sha256_verifier))
+    }
+
+    #[instrument(skip_all, level = "debug", err)]
+    async fn finish_scram_bind(&self, user_id: &UserId, valid_proof: bool) -> Result<()> {
+        self.check_lockout(user_id).await?;
+        if !valid_proof {
+            debug!(r#"Invalid SCRAM proof for "{}""#, user_id);
+            self.record_failed_login(user_id).await?;
+            return Err(DomainError::AuthenticationError(format!(
+                " for user '{}'",
+                user_id
+            )));
+        }
+        self.reset_failed_logins(user_id).await?;
+        self.check_password_status(user_id).await
     }
 }
 
@@ -106,6 +456,9 @@ impl OpaqueHandler for SqlOpaqueHandler {
         let maybe_password_file = self
             .get_password_file_for_user(UserId::new(&request.username))
             .await?
+            // The imported hashes can only be checked with the password in clear: the login
+            // fails as if there was no password, until a simple bind upgrades the hash.
+            .filter(|bytes| LegacyPasswordScheme::detect(bytes).is_none())
             .map(|bytes| {
                 opaque::server::ServerRegistration::deserialize(&bytes).map_err(|_| {
                     DomainError::InternalError(format!(
@@ -150,11 +503,18 @@ impl OpaqueHandler for SqlOpaqueHandler {
         )?)?;
         // Finish the login: this makes sure the client data is correct, and gives a session key we
         // don't need.
-        let _session_key =
-            opaque::server::login::finish_login(server_login, request.credential_finalization)?
-                .session_key;
+        let finish_result =
+            opaque::server::login::finish_login(server_login, request.credential_finalization);
+        let user_id = UserId::new(&username);
+        self.check_lockout(&user_id).await?;
+        if finish_result.is_err() {
+            self.record_failed_login(&user_id).await?;
+        }
+        let _session_key = finish_result?.session_key;
 
-        Ok(UserId::new(&username))
+        self.reset_failed_logins(&user_id).await?;
+        self.check_password_status(&user_id).await?;
+        Ok(user_id)
     }
 
     #[instrument(skip_all, level = "debug", err)]
@@ -192,21 +552,36 @@ impl OpaqueHandler for SqlOpaqueHandler {
 
         let password_file =
             opaque::server::registration::get_password_file(request.registration_upload);
-        // Set the user password to the new password.
+        let user_id = UserId::new(&username);
+        // Set the user password to the new password. The single update clears the flag atomically.
         let user_update = model::users::ActiveModel {
-            user_id: ActiveValue::Set(UserId::new(&username)),
+            user_id: ActiveValue::Set(user_id.clone()),
             password_hash: ActiveValue::Set(Some(password_file.serialize())),
+            password_changed_at: ActiveValue::Set(Some(chrono::Utc::now())),
+            must_change_password: ActiveValue::Set(false),
+            // The new password isn't known here: its verifier is stored at the next simple bind.
+            scram_sha256_verifier: ActiveValue::Set(None),
             ..Default::default()
         };
-        user_update.update(&self.sql_pool).await?;
+        let transaction = self.sql_pool.begin().await?;
+        user_update.update(&transaction).await?;
+        // Only the fact that the password changed is logged, not even its hash.
+        let events = self
+            .write_audit_log(
+                &transaction,
+                vec![AuditChange::user(&user_id, audit::PASSWORD)],
+            )
+            .await?;
+        transaction.commit().await?;
+        events.publish();
         Ok(())
     }
 }
 
 /// Convenience function to set a user's password.
 #[instrument(skip_all, level = "debug", err)]
-pub(crate) async fn register_password(
-    opaque_handler: &SqlOpaqueHandler,
+pub(crate) async fn register_password<Handler: OpaqueHandler>(
+    opaque_handler: &Handler,
     username: &UserId,
     password: &SecUtf8,
 ) -> Result<()> {
@@ -233,10 +608,24 @@ pub(crate) async fn register_password(
         .await
 }
 
+/// Sets the password of an existing user, and clears the lockout and the forced password
+/// change: the recovery path when nobody can log in to change it.
+#[instrument(skip_all, level = "debug", err)]
+pub(crate) async fn reset_password<Handler: OpaqueHandler + UserBackendHandler>(
+    handler: &Handler,
+    user_id: &UserId,
+    password: &SecUtf8,
+) -> Result<()> {
+    // Otherwise the registration would fail after the OPAQUE exchange, with a less clear error.
+    handler.get_user_details(user_id).await?;
+    register_password(handler, user_id, password).await?;
+    handler.unlock_user(user_id).await
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
-    use crate::domain::sql_backend_handler::tests::*;
+    use crate::domain::{sql_backend_handler::tests::*, sql_tables::DbConnection};
 
     async fn attempt_login(
         opaque_handler: &SqlOpaqueHandler,
@@ -320,6 +709,98 @@ mod tests {
             .unwrap_err();
     }
 
+    async fn set_password_hash(sql_pool: &DbConnection, password_hash: Vec<u8>) {
+        model::User::update_many()
+            .col_expr(UserColumn::PasswordHash, Expr::value(password_hash))
+            .filter(ColumnTrait::eq(&UserColumn::UserId, UserId::new("bob")))
+            .exec(sql_pool)
+            .await
+            .unwrap();
+    }
+
+    async fn get_password_hash(sql_pool: &DbConnection) -> Vec<u8> {
+        model::User::find_by_id(UserId::new("bob"))
+            .one(sql_pool)
+            .await
+            .unwrap()
+            .unwrap()
+            .password_hash
+            .unwrap()
+    }
+
+    async fn check_legacy_password_hash_upgrade(
+        legacy_hash: Vec<u8>,
+        expected_scheme: LegacyPasswordScheme,
+    ) {
+        let sql_pool = get_initialized_db().await;
+        let handler = SqlOpaqueHandler::new(get_default_config(), sql_pool.clone());
+        insert_user_no_password(&handler, "bob").await;
+        set_password_hash(&sql_pool, legacy_hash).await;
+        // The OPAQUE login can't check the imported hash.
+        attempt_login(&handler, "bob", "bob00").await.unwrap_err();
+        handler
+            .bind(BindRequest {
+                name: UserId::new("bob"),
+                password: "wrong_password".to_string(),
+            })
+            .await
+            .unwrap_err();
+        assert_eq!(
+            LegacyPasswordScheme::detect(&get_password_hash(&sql_pool).await),
+            Some(expected_scheme)
+        );
+        handler.bind(bob_bind_request()).await.unwrap();
+        // Upgraded to a password file.
+        assert_eq!(
+            LegacyPasswordScheme::detect(&get_password_hash(&sql_pool).await),
+            None
+        );
+        handler.bind(bob_bind_request()).await.unwrap();
+        attempt_login(&handler, "bob", "bob00").await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_legacy_password_hash_upgrade() {
+        use crate::domain::legacy_password_hash::tests::{argon2_hash, bcrypt_hash, scrypt_hash};
+        check_legacy_password_hash_upgrade(bcrypt_hash("bob00"), LegacyPasswordScheme::Bcrypt)
+            .await;
+        check_legacy_password_hash_upgrade(argon2_hash("bob00"), LegacyPasswordScheme::Argon2)
+            .await;
+        check_legacy_password_hash_upgrade(scrypt_hash("bob00"), LegacyPasswordScheme::Scrypt)
+            .await;
+    }
+
+    #[tokio::test]
+    async fn test_legacy_password_hash_keep_or_reject() {
+        use crate::domain::legacy_password_hash::tests::{argon2_hash, scrypt_hash};
+        let sql_pool = get_initialized_db().await;
+        let mut config = get_default_config();
+        config.legacy_password_hashes = LegacyPasswordHashes::Keep;
+        let handler = SqlOpaqueHandler::new(config.clone(), sql_pool.clone());
+        insert_user_no_password(&handler, "bob").await;
+        // Re-hashed with the default KDF.
+        set_password_hash(&sql_pool, scrypt_hash("bob00")).await;
+        handler.bind(bob_bind_request()).await.unwrap();
+        let hash = get_password_hash(&sql_pool).await;
+        assert_eq!(
+            LegacyPasswordScheme::detect(&hash),
+            Some(LegacyPasswordScheme::Argon2)
+        );
+        assert!(LegacyPasswordScheme::Argon2.verify(&hash, "bob00"));
+        // Already hashed with the default KDF.
+        let legacy_hash = argon2_hash("bob00");
+        set_password_hash(&sql_pool, legacy_hash.clone()).await;
+        handler.bind(bob_bind_request()).await.unwrap();
+        assert_eq!(get_password_hash(&sql_pool).await, legacy_hash);
+
+        config.legacy_password_hashes = LegacyPasswordHashes::Reject;
+        let handler = SqlOpaqueHandler::new(config, sql_pool.clone());
+        assert!(matches!(
+            handler.bind(bob_bind_request()).await,
+            Err(DomainError::AuthenticationError(_))
+        ));
+    }
+
     #[tokio::test]
     async fn test_user_no_password() {
         let sql_pool = get_initialized_db().await;
@@ -335,4 +816,267 @@ mod tests {
             .await
             .unwrap_err();
     }
+
+    fn bob_bind_request() -> BindRequest {
+        BindRequest {
+            name: UserId::new("bob"),
+            password: "bob00".to_string(),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_must_change_password() {
+        use crate::domain::handler::UpdateUserRequest;
+        let sql_pool = get_initialized_db().await;
+        let config = get_default_config();
+        let handler = SqlOpaqueHandler::new(config, sql_pool.clone());
+        insert_user(&handler, "bob", "bob00").await;
+        handler
+            .update_user(UpdateUserRequest {
+                user_id: UserId::new("bob"),
+                must_change_password: Some(true),
+                ..Default::default()
+            })
+            .await
+            .unwrap();
+        assert!(matches!(
+            handler.bind(bob_bind_request()).await,
+            Err(DomainError::PasswordChangeRequired(_))
+        ));
+        assert!(matches!(
+            attempt_login(&handler, "bob", "bob00").await,
+            Err(DomainError::PasswordChangeRequired(_))
+        ));
+        // A wrong password is still rejected as such.
+        assert!(matches!(
+            attempt_login(&handler, "bob", "wrong_password").await,
+            Err(DomainError::AuthenticationProtocolError(_))
+        ));
+
+        register_password(&handler, &UserId::new("bob"), &SecUtf8::from("bob00"))
+            .await
+            .unwrap();
+        handler.bind(bob_bind_request()).await.unwrap();
+        attempt_login(&handler, "bob", "bob00").await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_password_max_age() {
+        use sea_orm::sea_query::Expr;
+        let sql_pool = get_initialized_db().await;
+        let mut config = get_default_config();
+        config.password_max_age_days = Some(90);
+        let handler = SqlOpaqueHandler::new(config, sql_pool.clone());
+        insert_user(&handler, "bob", "bob00").await;
+        handler.bind(bob_bind_request()).await.unwrap();
+
+        model::User::update_many()
+            .col_expr(
+                UserColumn::PasswordChangedAt,
+                Expr::value(chrono::Utc::now() - chrono::Duration::days(91)),
+            )
+            .exec(&sql_pool)
+            .await
+            .unwrap();
+        assert!(matches!(
+            handler.bind(bob_bind_request()).await,
+            Err(DomainError::PasswordChangeRequired(_))
+        ));
+
+        register_password(&handler, &UserId::new("bob"), &SecUtf8::from("bob00"))
+            .await
+            .unwrap();
+        handler.bind(bob_bind_request()).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_account_lockout() {
+        let sql_pool = get_initialized_db().await;
+        let mut config = get_default_config();
+        config.failed_login_lockout_threshold = Some(3);
+        let handler = SqlOpaqueHandler::new(config, sql_pool.clone());
+        insert_user(&handler, "bob", "bob00").await;
+        let bind = |name: &str, password: &str| {
+            handler.bind(BindRequest {
+                name: UserId::new(name),
+                password: password.to_string(),
+            })
+        };
+        for _ in 0..2 {
+            assert!(matches!(
+                bind("bob", "wrong_password").await,
+                Err(DomainError::AuthenticationError(_))
+            ));
+        }
+        // A successful bind resets the count.
+        bind("bob", "bob00").await.unwrap();
+        for _ in 0..3 {
+            assert!(matches!(
+                bind("bob", "wrong_password").await,
+                Err(DomainError::AuthenticationError(_))
+            ));
+        }
+        assert!(matches!(
+            bind("bob", "bob00").await,
+            Err(DomainError::AccountLocked(_))
+        ));
+        assert!(matches!(
+            attempt_login(&handler, "bob", "bob00").await,
+            Err(DomainError::AccountLocked(_))
+        ));
+        // Unknown users get locked out the same way.
+        for _ in 0..3 {
+            bind("andrew", "wrong_password").await.unwrap_err();
+        }
+        assert!(matches!(
+            bind("andrew", "bob00").await,
+            Err(DomainError::AccountLocked(_))
+        ));
+
+        handler.unlock_user(&UserId::new("bob")).await.unwrap();
+        bind("bob", "bob00").await.unwrap();
+    }
+
+    struct UpstreamBackend;
+
+    #[async_trait]
+    impl crate::domain::auth_backend::AuthBackend for UpstreamBackend {
+        async fn check_password(
+            &self,
+            user_id: &UserId,
+            password: &str,
+        ) -> anyhow::Result<AuthOutcome> {
+            Ok(match (user_id.as_str(), password) {
+                ("john", _) => AuthOutcome::NotHandled,
+                (_, "upstream") => AuthOutcome::Accepted,
+                _ => AuthOutcome::Rejected,
+            })
+        }
+    }
+
+    #[tokio::test]
+    async fn test_delegated_bind() {
+        let sql_pool = get_initialized_db().await;
+        let mut config = get_default_config();
+        config.failed_login_lockout_threshold = Some(2);
+        let handler = SqlOpaqueHandler::new(config, sql_pool.clone()).with_auth_backends(
+            crate::domain::auth_backend::AuthBackendRouter::default().with_backend(
+                "upstream",
+                std::sync::Arc::new(UpstreamBackend),
+                [UserId::new("bob"), UserId::new("john")],
+                ["Contractors".to_owned()],
+            ),
+        );
+        insert_user_no_password(&handler, "bob").await;
+        insert_user(&handler, "john", "john00").await;
+        insert_user(&handler, "patrick", "patrick00").await;
+        insert_user(&handler, "nick", "nick00").await;
+        let group_id = insert_group(&handler, "contractors").await;
+        insert_membership(&handler, group_id, "patrick").await;
+        let bind = |name: &str, password: &str| {
+            handler.bind(BindRequest {
+                name: UserId::new(name),
+                password: password.to_string(),
+            })
+        };
+        // Routed by user ID, without a local password.
+        bind("bob", "upstream").await.unwrap();
+        // Routed by group: the local password isn't accepted anymore.
+        bind("patrick", "upstream").await.unwrap();
+        bind("patrick", "patrick00").await.unwrap_err();
+        // Not handled by the backend: the local password is checked.
+        bind("john", "john00").await.unwrap();
+        bind("john", "upstream").await.unwrap_err();
+        // Not routed.
+        bind("nick", "nick00").await.unwrap();
+        bind("nick", "upstream").await.unwrap_err();
+        // The rejections count towards the lockout.
+        for _ in 0..2 {
+            assert!(matches!(
+                bind("bob", "wrong_password").await,
+                Err(DomainError::AuthenticationError(_))
+            ));
+        }
+        assert!(matches!(
+            bind("bob", "upstream").await,
+            Err(DomainError::AccountLocked(_))
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_scram_verifier() {
+        let sql_pool = get_initialized_db().await;
+        let mut config = get_default_config();
+        config.ldap_sasl_scram_sha256 = true;
+        config.failed_login_lockout_threshold = Some(2);
+        let handler = SqlOpaqueHandler::new(config, sql_pool.clone());
+        insert_user(&handler, "bob", "bob00").await;
+        let bob = UserId::new("bob");
+        // Only known once the server sees the password.
+        assert_eq!(handler.get_scram_verifier(&bob).await.unwrap(), None);
+        handler
+            .bind(BindRequest {
+                name: bob.clone(),
+                password: "bob00".to_string(),
+            })
+            .await
+            .unwrap();
+        let verifier = handler.get_scram_verifier(&bob).await.unwrap().unwrap();
+        assert!(ScramVerifier::parse(&verifier).is_some());
+        // The failed proofs count for the lockout.
+        assert!(matches!(
+            handler.finish_scram_bind(&bob, false).await,
+            Err(DomainError::AuthenticationError(_))
+        ));
+        handler.finish_scram_bind(&bob, true).await.unwrap();
+        handler.finish_scram_bind(&bob, false).await.unwrap_err();
+        handler.finish_scram_bind(&bob, false).await.unwrap_err();
+        assert!(matches!(
+            handler.finish_scram_bind(&bob, true).await,
+            Err(DomainError::AccountLocked(_))
+        ));
+        // A new password invalidates the verifier.
+        register_password(&handler, &bob, &secstr::SecUtf8::from("bob01"))
+            .await
+            .unwrap();
+        assert_eq!(handler.get_scram_verifier(&bob).await.unwrap(), None);
+    }
+
+    #[tokio::test]
+    async fn test_reset_password() {
+        use crate::domain::handler::UpdateUserRequest;
+        let sql_pool = get_initialized_db().await;
+        let mut config = get_default_config();
+        config.failed_login_lockout_threshold = Some(1);
+        let handler = SqlOpaqueHandler::new(config, sql_pool.clone());
+        insert_user(&handler, "bob", "bob00").await;
+        handler
+            .update_user(UpdateUserRequest {
+                user_id: UserId::new("bob"),
+                must_change_password: Some(true),
+                ..Default::default()
+            })
+            .await
+            .unwrap();
+        let bind = |password: &str| {
+            handler.bind(BindRequest {
+                name: UserId::new("bob"),
+                password: password.to_string(),
+            })
+        };
+        bind("wrong_password").await.unwrap_err();
+        assert!(matches!(
+            bind("bob00").await,
+            Err(DomainError::AccountLocked(_))
+        ));
+
+        reset_password(&handler, &UserId::new("bob"), &SecUtf8::from("new_pass"))
+            .await
+            .unwrap();
+        bind("new_pass").await.unwrap();
+        assert!(matches!(
+            reset_password(&handler, &UserId::new("andrew"), &SecUtf8::from("new_pass")).await,
+            Err(DomainError::EntityNotFound(_))
+        ));
+    }
 }
@@ -0,0 +1,308 @@
+use super::{
+    error::{DomainError, Result},
+    model::{self, GroupColumn, SequencesColumn, UserColumn},
+    sql_migrations::{GID_NUMBER_SEQUENCE, UID_NUMBER_SEQUENCE},
+    sql_tables::DbConnection,
+    types::{GroupId, UserId},
+};
+use crate::infra::configuration::PosixOptions;
+use sea_orm::{
+    ColumnTrait, DatabaseTransaction, EntityTrait, FromQueryResult, PaginatorTrait, QueryFilter,
+    QueryOrder, QuerySelect, TransactionTrait,
+};
+use sea_query::Expr;
+use tracing::{debug, info, instrument};
+
+/// The POSIX numbers allocated by LLDAP: the uidNumbers of the users, and the gidNumbers of the
+/// groups.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum PosixNumber {
+    Uid,
+    Gid,
+}
+
+impl PosixNumber {
+    fn name(self) -> &'static str {
+        match self {
+            PosixNumber::Uid => "uidNumber",
+            PosixNumber::Gid => "gidNumber",
+        }
+    }
+
+    fn sequence(self) -> &'static str {
+        match self {
+            PosixNumber::Uid => UID_NUMBER_SEQUENCE,
+            PosixNumber::Gid => GID_NUMBER_SEQUENCE,
+        }
+    }
+
+    fn range(self, options: &PosixOptions) -> (i32, i32) {
+        match self {
+            PosixNumber::Uid => (options.uid_number_min, options.uid_number_max),
+            PosixNumber::Gid => (options.gid_number_min, options.gid_number_max),
+        }
+    }
+
+    async fn is_taken(self, transaction: &DatabaseTransaction, number: i32) -> Result<bool> {
+        Ok(match self {
+            PosixNumber::Uid => {
+                model::User::find()
+                    .filter(ColumnTrait::eq(&UserColumn::UidNumber, number))
+                    .count(transaction)
+                    .await?
+                    > 0
+            }
+            PosixNumber::Gid => {
+                model::Group::find()
+                    .filter(GroupColumn::GidNumber.eq(number))
+                    .count(transaction)
+                    .await?
+                    > 0
+            }
+        })
+    }
+}
+
+/// Allocates the next free number of the configured range. The numbers that are already taken,
+/// e.g. set by hand, are skipped, and the numbers of the deleted entities are not reused. Like for
+/// the group IDs, the update locks the sequence row until the end of the transaction, so
+/// concurrent allocations are serialized.
+pub(crate) async fn allocate_posix_number(
+    transaction: &DatabaseTransaction,
+    kind: PosixNumber,
+    options: &PosixOptions,
+) -> Result<i32> {
+    let sequence = kind.sequence();
+    let missing_sequence =
+        || DomainError::InternalError(format!("The {} sequence is missing", kind.name()));
+    let res = model::Sequences::update_many()
+        .col_expr(
+            SequencesColumn::NextValue,
+            Expr::col(SequencesColumn::NextValue).add(1),
+        )
+        .filter(SequencesColumn::Name.eq(sequence))
+        .exec(transaction)
+        .await?;
+    if res.rows_affected != 1 {
+        return Err(missing_sequence());
+    }
+    let next_value = model::Sequences::find_by_id(sequence.to_owned())
+        .one(transaction)
+        .await?
+        .ok_or_else(missing_sequence)?
+        .next_value
+        - 1;
+    let (min, max) = kind.range(options);
+    let mut number = next_value.max(min);
+    while number <= max && kind.is_taken(transaction, number).await? {
+        number += 1;
+    }
+    if number > max {
+        return Err(DomainError::ValidationError(format!(
+            "No {} left in the range {}-{}",
+            kind.name(),
+            min,
+            max
+        )));
+    }
+    model::Sequences::update_many()
+        .col_expr(SequencesColumn::NextValue, Expr::value(number + 1))
+        .filter(SequencesColumn::Name.eq(sequence))
+        .exec(transaction)
+        .await?;
+    debug!(?kind, number);
+    Ok(number)
+}
+
+/// Allocates the numbers of the users and groups that don't have one yet, e.g. the ones created
+/// before the POSIX attributes. Returns the number of updated users and groups.
+#[instrument(skip_all, level = "debug", ret, err)]
+pub async fn assign_missing_posix_numbers(
+    pool: &DbConnection,
+    options: &PosixOptions,
+) -> Result<usize> {
+    let transaction = pool.begin().await?;
+    let (num_users, num_groups) = assign_missing_posix_numbers_in(&transaction, options).await?;
+    transaction.commit().await?;
+    if num_users > 0 || num_groups > 0 {
+        info!(
+            "Allocated the POSIX numbers of {} users and {} groups",
+            num_users, num_groups
+        );
+    }
+    Ok(num_users + num_groups)
+}
+
+/// Same as `assign_missing_posix_numbers`, in the transaction. Returns the number of updated
+/// users, then of groups.
+pub(crate) async fn assign_missing_posix_numbers_in(
+    transaction: &DatabaseTransaction,
+    options: &PosixOptions,
+) -> Result<(usize, usize)> {
+    #[derive(FromQueryResult)]
+    struct MissingUser {
+        user_id: UserId,
+    }
+    #[derive(FromQueryResult)]
+    struct MissingGroup {
+        group_id: GroupId,
+    }
+    let now = chrono::Utc::now();
+    let users = model::User::find()
+        .select_only()
+        .column(UserColumn::UserId)
+        .filter(UserColumn::UidNumber.is_null())
+        .order_by_asc(UserColumn::CreationDate)
+        .into_model::<MissingUser>()
+        .all(transaction)
+        .await?;
+    for user in &users {
+        let uid_number = allocate_posix_number(transaction, PosixNumber::Uid, options).await?;
+        model::User::update_many()
+            .col_expr(UserColumn::UidNumber, Expr::value(uid_number))
+            .col_expr(UserColumn::ModifiedAt, Expr::value(now))
+            .filter(ColumnTrait::eq(&UserColumn::UserId, &user.user_id))
+            .exec(transaction)
+            .await?;
+    }
+    let groups = model::Group::find()
+        .select_only()
+        .column(GroupColumn::GroupId)
+        .filter(GroupColumn::GidNumber.is_null())
+        .order_by_asc(GroupColumn::GroupId)
+        .into_model::<MissingGroup>()
+        .all(transaction)
+        .await?;
+    for group in &groups {
+        let gid_number = allocate_posix_number(transaction, PosixNumber::Gid, options).await?;
+        model::Group::update_many()
+            .col_expr(GroupColumn::GidNumber, Expr::value(gid_number))
+            .col_expr(GroupColumn::ModifiedAt, Expr::value(now))
+            .filter(GroupColumn::GroupId.eq(group.group_id))
+            .exec(transaction)
+            .await?;
+    }
+    Ok((users.len(), groups.len()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::domain::{
+        handler::{CreateUserRequest, GroupBackendHandler, UserBackendHandler},
+        sql_backend_handler::tests::*,
+    };
+
+    #[tokio::test]
+    async fn test_allocate_uid_numbers() {
+        let fixture = TestFixture::new().await;
+        let options = &fixture.handler.config.posix_options;
+        let bob = fixture
+            .handler
+            .get_user_details(&UserId::new("bob"))
+            .await
+            .unwrap();
+        let patrick = fixture
+            .handler
+            .get_user_details(&UserId::new("patrick"))
+            .await
+            .unwrap();
+        assert!(bob.uid_number.unwrap() >= options.uid_number_min);
+        assert_ne!(bob.uid_number, patrick.uid_number);
+
+        // A number taken by hand is skipped.
+        let next = patrick.uid_number.unwrap().max(bob.uid_number.unwrap()) + 3;
+        model::User::update_many()
+            .col_expr(UserColumn::UidNumber, Expr::value(next))
+            .filter(ColumnTrait::eq(&UserColumn::UserId, "bob"))
+            .exec(&fixture.handler.sql_pool)
+            .await
+            .unwrap();
+        let mut numbers = Vec::new();
+        for i in 0..4 {
+            let user_id = UserId::new(&format!("posix{}", i));
+            insert_user_no_password(&fixture.handler, user_id.as_str()).await;
+            numbers.push(
+                fixture
+                    .handler
+                    .get_user_details(&user_id)
+                    .await
+                    .unwrap()
+                    .uid_number
+                    .unwrap(),
+            );
+        }
+        assert!(!numbers.contains(&next));
+        numbers.sort_unstable();
+        numbers.dedup();
+        assert_eq!(numbers.len(), 4);
+    }
+
+    #[tokio::test]
+    async fn test_allocate_posix_numbers_range_exhausted() {
+        let mut fixture = TestFixture::new().await;
+        let taken = fixture
+            .handler
+            .get_user_details(&UserId::new("nogroup"))
+            .await
+            .unwrap()
+            .uid_number
+            .unwrap();
+        fixture.handler.config.posix_options.uid_number_max = taken;
+        let err = fixture
+            .handler
+            .create_user(CreateUserRequest {
+                user_id: UserId::new("extra"),
+                email: "extra@bob.bob".to_owned(),
+                ..Default::default()
+            })
+            .await
+            .unwrap_err();
+        assert!(matches!(err, DomainError::ValidationError(_)), "{}", err);
+        // Nothing was created.
+        assert!(fixture
+            .handler
+            .get_user_details(&UserId::new("extra"))
+            .await
+            .is_err());
+    }
+
+    #[tokio::test]
+    async fn test_assign_missing_posix_numbers() {
+        let fixture = TestFixture::new().await;
+        model::User::update_many()
+            .col_expr(UserColumn::UidNumber, Expr::value(Option::<i32>::None))
+            .filter(ColumnTrait::eq(&UserColumn::UserId, "john"))
+            .exec(&fixture.handler.sql_pool)
+            .await
+            .unwrap();
+        model::Group::update_many()
+            .col_expr(GroupColumn::GidNumber, Expr::value(Option::<i32>::None))
+            .exec(&fixture.handler.sql_pool)
+            .await
+            .unwrap();
+        let num_groups = fixture.handler.list_groups(None).await.unwrap().len();
+        let options = &fixture.handler.config.posix_options;
+        assert_eq!(
+            assign_missing_posix_numbers(&fixture.handler.sql_pool, options)
+                .await
+                .unwrap(),
+            1 + num_groups
+        );
+        assert_eq!(
+            assign_missing_posix_numbers(&fixture.handler.sql_pool, options)
+                .await
+                .unwrap(),
+            0
+        );
+        assert!(fixture
+            .handler
+            .get_user_details(&UserId::new("john"))
+            .await
+            .unwrap()
+            .uid_number
+            .is_some());
+        let groups = fixture.handler.list_groups(None).await.unwrap();
+        assert!(groups.iter().all(|g| g.gid_number.is_some()));
+    }
+}
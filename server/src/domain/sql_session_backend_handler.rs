@@ -0,0 +1,184 @@
+use crate::domain::{
+    error::{DomainError, Result},
+    model::{self, RefreshTokensColumn, SessionsColumn},
+    session_handler::{Session, SessionBackendHandler},
+    sql_backend_handler::SqlBackendHandler,
+    types::UserId,
+};
+use async_trait::async_trait;
+use sea_orm::{ColumnTrait, ConnectionTrait, EntityTrait, QueryFilter, QueryOrder};
+use sea_query::Expr;
+use tracing::{debug, instrument};
+
+/// Revokes the sessions and all their refresh tokens.
+pub(crate) async fn revoke_sessions(
+    connection: &impl ConnectionTrait,
+    session_ids: Vec<String>,
+) -> Result<()> {
+    if session_ids.is_empty() {
+        return Ok(());
+    }
+    model::RefreshTokens::update_many()
+        .col_expr(RefreshTokensColumn::Revoked, Expr::value(true))
+        .filter(RefreshTokensColumn::FamilyId.is_in(session_ids.clone()))
+        .exec(connection)
+        .await?;
+    model::Sessions::update_many()
+        .col_expr(SessionsColumn::Revoked, Expr::value(true))
+        .filter(SessionsColumn::SessionId.is_in(session_ids))
+        .exec(connection)
+        .await?;
+    Ok(())
+}
+
+#[async_trait]
+impl SessionBackendHandler for SqlBackendHandler {
+    #[instrument(skip_all, level = "debug", ret, err)]
+    async fn list_sessions(&self, user_id: &UserId) -> Result<Vec<Session>> {
+        debug!(?user_id);
+        Ok(model::Sessions::find()
+            .filter(SessionsColumn::UserId.eq(user_id))
+            .filter(SessionsColumn::Revoked.eq(false))
+            .filter(SessionsColumn::ExpiryDate.gt(chrono::Utc::now()))
+            .order_by_desc(SessionsColumn::LastSeen)
+            .all(&self.sql_pool)
+            .await?
+            .into_iter()
+            .map(Into::into)
+            .collect())
+    }
+
+    #[instrument(skip_all, level = "debug", ret, err)]
+    async fn get_session(&self, session_id: &str) -> Result<Session> {
+        debug!(?session_id);
+        model::Sessions::find_by_id(session_id.to_owned())
+            .one(&self.sql_pool)
+            .await?
+            .map(Into::into)
+            .ok_or_else(|| {
+                DomainError::EntityNotFound(format!("No such session: '{}'", session_id))
+            })
+    }
+
+    #[instrument(skip_all, level = "debug", err)]
+    async fn revoke_session(&self, session_id: &str) -> Result<()> {
+        debug!(?session_id);
+        revoke_sessions(&self.sql_pool, vec![session_id.to_owned()]).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        domain::{session_handler::SessionClient, sql_backend_handler::tests::*},
+        infra::tcp_backend_handler::TcpBackendHandler,
+    };
+
+    async fn setup() -> TestFixture {
+        let fixture = TestFixture::new().await;
+        crate::infra::jwt_sql_tables::init_table(&fixture.handler.sql_pool)
+            .await
+            .unwrap();
+        fixture
+    }
+
+    fn client(ip_address: &str) -> SessionClient {
+        SessionClient {
+            ip_address: Some(ip_address.to_owned()),
+            user_agent: Some("curl/7.88.1".to_owned()),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_list_sessions() {
+        let fixture = setup().await;
+        let bob = UserId::new("bob");
+        let token = fixture
+            .handler
+            .create_refresh_token(&bob, &client("10.0.0.1"))
+            .await
+            .unwrap()
+            .token;
+        fixture
+            .handler
+            .create_refresh_token(&bob, &client("10.0.0.2"))
+            .await
+            .unwrap();
+        fixture
+            .handler
+            .create_refresh_token(&UserId::new("patrick"), &client("10.0.0.3"))
+            .await
+            .unwrap();
+        // The refresh updates the session.
+        fixture
+            .handler
+            .rotate_refresh_token(&token, &bob, &client("10.0.0.4"))
+            .await
+            .unwrap();
+        let sessions = fixture.handler.list_sessions(&bob).await.unwrap();
+        assert_eq!(
+            sessions
+                .iter()
+                .map(|s| s.client.ip_address.as_deref().unwrap())
+                .collect::<Vec<_>>(),
+            vec!["10.0.0.4", "10.0.0.2"]
+        );
+        assert!(sessions.iter().all(|s| s.user_id == bob));
+        assert_eq!(
+            fixture
+                .handler
+                .get_session(&sessions[0].session_id)
+                .await
+                .unwrap(),
+            sessions[0]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_revoke_session() {
+        let fixture = setup().await;
+        let bob = UserId::new("bob");
+        let token = fixture
+            .handler
+            .create_refresh_token(&bob, &client("10.0.0.1"))
+            .await
+            .unwrap()
+            .token;
+        let other_token = fixture
+            .handler
+            .create_refresh_token(&bob, &client("10.0.0.2"))
+            .await
+            .unwrap()
+            .token;
+        let session = fixture
+            .handler
+            .list_sessions(&bob)
+            .await
+            .unwrap()
+            .into_iter()
+            .find(|s| s.client.ip_address.as_deref() == Some("10.0.0.1"))
+            .unwrap();
+        fixture
+            .handler
+            .revoke_session(&session.session_id)
+            .await
+            .unwrap();
+        assert!(fixture
+            .handler
+            .rotate_refresh_token(&token, &bob, &client("10.0.0.1"))
+            .await
+            .is_err());
+        assert_eq!(fixture.handler.list_sessions(&bob).await.unwrap().len(), 1);
+        // The other session is still valid.
+        fixture
+            .handler
+            .rotate_refresh_token(&other_token, &bob, &client("10.0.0.2"))
+            .await
+            .unwrap();
+        assert!(matches!(
+            fixture.handler.get_session("unknown").await,
+            Err(DomainError::EntityNotFound(_))
+        ));
+    }
+}
@@ -0,0 +1,493 @@
+use crate::domain::{
+    error::{DomainError, Result},
+    model::{self, SudoRoleValuesColumn, SudoRolesColumn},
+    sql_backend_handler::SqlBackendHandler,
+    sql_user_backend_handler::escape_like,
+    sudo_role_handler::{
+        SubstringPattern, SudoAttribute, SudoRole, SudoRoleBackendHandler, SudoRoleRequest,
+        SudoRoleRequestFilter,
+    },
+};
+use async_trait::async_trait;
+use sea_orm::{
+    ActiveModelTrait, ActiveValue, ColumnTrait, DatabaseTransaction, EntityTrait, QueryFilter,
+    QueryOrder, QuerySelect, QueryTrait, TransactionTrait,
+};
+use sea_query::{Cond, Expr, Func, IntoCondition, LikeExpr, SimpleExpr};
+use tracing::{debug, instrument};
+
+/// The role holding the global sudo options, which doesn't need any user, host or command.
+const DEFAULTS_ROLE: &str = "defaults";
+
+/// Characters that would have to be escaped in the DN of the role.
+const FORBIDDEN_NAME_CHARACTERS: &[char] = &[',', '=', '+', '<', '>', '#', ';', '"', '\\'];
+
+/// WHERE (role_id in (SELECT role_id FROM sudo_role_values WHERE attribute = ... AND condition))
+fn has_value(attribute: SudoAttribute, condition: Cond) -> Cond {
+    SudoRolesColumn::RoleId
+        .in_subquery(
+            model::SudoRoleValues::find()
+                .select_only()
+                .column(SudoRoleValuesColumn::RoleId)
+                .filter(SudoRoleValuesColumn::Attribute.eq(attribute.ldap_name()))
+                .filter(condition)
+                .into_query(),
+        )
+        .into_condition()
+}
+
+fn get_like_pattern(pattern: &SubstringPattern) -> String {
+    let mut like = pattern
+        .initial
+        .as_deref()
+        .map(escape_like)
+        .unwrap_or_default();
+    like.push('%');
+    for any in &pattern.any {
+        like.push_str(&escape_like(any));
+        like.push('%');
+    }
+    if let Some(final_) = &pattern.final_ {
+        like.push_str(&escape_like(final_));
+    }
+    like
+}
+
+fn get_sudo_role_filter_expr(filter: SudoRoleRequestFilter) -> Cond {
+    use SudoRoleRequestFilter::*;
+    match filter {
+        And(fs) => {
+            if fs.is_empty() {
+                SimpleExpr::Value(true.into()).into_condition()
+            } else {
+                fs.into_iter()
+                    .fold(Cond::all(), |c, f| c.add(get_sudo_role_filter_expr(f)))
+            }
+        }
+        Or(fs) => {
+            if fs.is_empty() {
+                SimpleExpr::Value(false.into()).into_condition()
+            } else {
+                fs.into_iter()
+                    .fold(Cond::any(), |c, f| c.add(get_sudo_role_filter_expr(f)))
+            }
+        }
+        Not(f) => get_sudo_role_filter_expr(*f).not(),
+        Name(name) => Expr::expr(Func::lower(Expr::col(SudoRolesColumn::Name)))
+            .eq(name.to_lowercase())
+            .into_condition(),
+        Equality(attribute, value) => has_value(
+            attribute,
+            SudoRoleValuesColumn::Value.eq(value).into_condition(),
+        ),
+        Present(attribute) => has_value(attribute, Cond::all()),
+        Substring(attribute, pattern) => has_value(
+            attribute,
+            Expr::col(SudoRoleValuesColumn::Value)
+                .like(LikeExpr::str(&get_like_pattern(&pattern)).escape('\\'))
+                .into_condition(),
+        ),
+    }
+}
+
+fn validate_request(request: &SudoRoleRequest) -> Result<()> {
+    if request.name.is_empty() || request.name.len() > 255 {
+        return Err(DomainError::ValidationError(
+            "The name of a sudo role must be between 1 and 255 characters".to_owned(),
+        ));
+    }
+    if request.name.contains(FORBIDDEN_NAME_CHARACTERS) {
+        return Err(DomainError::ValidationError(format!(
+            "Invalid sudo role name: '{}'",
+            request.name
+        )));
+    }
+    for attribute in SudoAttribute::ALL {
+        if let Some(value) = request
+            .values(attribute)
+            .iter()
+            .find(|v| v.trim().is_empty() || v.len() > 255)
+        {
+            return Err(DomainError::ValidationError(format!(
+                "Invalid {} value: '{}'",
+                attribute.ldap_name(),
+                value
+            )));
+        }
+    }
+    if !request.name.eq_ignore_ascii_case(DEFAULTS_ROLE) {
+        for attribute in [
+            SudoAttribute::User,
+            SudoAttribute::Host,
+            SudoAttribute::Command,
+        ] {
+            // sudo ignores the roles without users, hosts or commands.
+            if request.values(attribute).is_empty() {
+                return Err(DomainError::ValidationError(format!(
+                    "The sudo role '{}' needs a {} value, use ALL to match everything",
+                    request.name,
+                    attribute.ldap_name()
+                )));
+            }
+        }
+    }
+    Ok(())
+}
+
+async fn find_role_id(transaction: &DatabaseTransaction, name: &str) -> Result<Option<i32>> {
+    Ok(model::SudoRoles::find()
+        .filter(get_sudo_role_filter_expr(SudoRoleRequestFilter::Name(
+            name.to_owned(),
+        )))
+        .one(transaction)
+        .await?
+        .map(|role| role.role_id))
+}
+
+async fn insert_values(
+    transaction: &DatabaseTransaction,
+    role_id: i32,
+    request: &SudoRoleRequest,
+) -> Result<()> {
+    let values = SudoAttribute::ALL
+        .into_iter()
+        .flat_map(|attribute| {
+            request
+                .values(attribute)
+                .iter()
+                .enumerate()
+                .map(
+                    move |(position, value)| model::sudo_role_values::ActiveModel {
+                        role_id: ActiveValue::Set(role_id),
+                        attribute: ActiveValue::Set(attribute.ldap_name().to_owned()),
+                        position: ActiveValue::Set(position as i32),
+                        value: ActiveValue::Set(value.clone()),
+                    },
+                )
+        })
+        .collect::<Vec<_>>();
+    if !values.is_empty() {
+        model::SudoRoleValues::insert_many(values)
+            .exec(transaction)
+            .await?;
+    }
+    Ok(())
+}
+
+#[async_trait]
+impl SudoRoleBackendHandler for SqlBackendHandler {
+    #[instrument(skip_all, level = "debug", ret, err)]
+    async fn list_sudo_roles(
+        &self,
+        filters: Option<SudoRoleRequestFilter>,
+    ) -> Result<Vec<SudoRole>> {
+        debug!(?filters);
+        // The order_by must be before find_with_related otherwise the primary order is by role_id.
+        let results = model::SudoRoles::find()
+            .filter(
+                filters
+                    .map(get_sudo_role_filter_expr)
+                    .unwrap_or_else(|| SimpleExpr::Value(true.into()).into_condition()),
+            )
+            .order_by_asc(SudoRolesColumn::Name)
+            .find_with_related(model::SudoRoleValues)
+            .all(self.read_pool())
+            .await?;
+        Ok(results
+            .into_iter()
+            .map(|(role, mut values)| {
+                values.sort_by_key(|v| v.position);
+                let get_values = |attribute: SudoAttribute| {
+                    values
+                        .iter()
+                        .filter(|v| v.attribute == attribute.ldap_name())
+                        .map(|v| v.value.clone())
+                        .collect()
+                };
+                SudoRole {
+                    users: get_values(SudoAttribute::User),
+                    hosts: get_values(SudoAttribute::Host),
+                    commands: get_values(SudoAttribute::Command),
+                    options: get_values(SudoAttribute::Option),
+                    name: role.name,
+                    creation_date: role.creation_date,
+                    modified_at: role.modified_at,
+                }
+            })
+            .collect())
+    }
+
+    #[instrument(skip_all, level = "debug", err)]
+    async fn create_sudo_role(&self, request: SudoRoleRequest) -> Result<()> {
+        debug!(?request);
+        validate_request(&request)?;
+        let transaction = self.sql_pool.begin().await?;
+        if find_role_id(&transaction, &request.name).await?.is_some() {
+            return Err(DomainError::ValidationError(format!(
+                "The sudo role '{}' already exists",
+                request.name
+            )));
+        }
+        let now = chrono::Utc::now();
+        let role = model::sudo_roles::ActiveModel {
+            name: ActiveValue::Set(request.name.clone()),
+            creation_date: ActiveValue::Set(now),
+            modified_at: ActiveValue::Set(now),
+            ..Default::default()
+        }
+        .insert(&transaction)
+        .await?;
+        insert_values(&transaction, role.role_id, &request).await?;
+        self.last_write.mark();
+        transaction.commit().await?;
+        Ok(())
+    }
+
+    #[instrument(skip_all, level = "debug", err)]
+    async fn update_sudo_role(&self, request: SudoRoleRequest) -> Result<()> {
+        debug!(?request);
+        validate_request(&request)?;
+        let transaction = self.sql_pool.begin().await?;
+        let role_id = find_role_id(&transaction, &request.name)
+            .await?
+            .ok_or_else(|| {
+                DomainError::EntityNotFound(format!("No such sudo role: '{}'", request.name))
+            })?;
+        model::SudoRoleValues::delete_many()
+            .filter(SudoRoleValuesColumn::RoleId.eq(role_id))
+            .exec(&transaction)
+            .await?;
+        insert_values(&transaction, role_id, &request).await?;
+        model::sudo_roles::ActiveModel {
+            role_id: ActiveValue::Set(role_id),
+            modified_at: ActiveValue::Set(chrono::Utc::now()),
+            ..Default::default()
+        }
+        .update(&transaction)
+        .await?;
+        self.last_write.mark();
+        transaction.commit().await?;
+        Ok(())
+    }
+
+    #[instrument(skip_all, level = "debug", err)]
+    async fn delete_sudo_role(&self, name: &str) -> Result<()> {
+        debug!(?name);
+        let transaction = self.sql_pool.begin().await?;
+        let role_id = find_role_id(&transaction, name)
+            .await?
+            .ok_or_else(|| DomainError::EntityNotFound(format!("No such sudo role: '{}'", name)))?;
+        // Not relying on the cascade: SQLite only enforces it with the foreign keys enabled.
+        model::SudoRoleValues::delete_many()
+            .filter(SudoRoleValuesColumn::RoleId.eq(role_id))
+            .exec(&transaction)
+            .await?;
+        model::SudoRoles::delete_by_id(role_id)
+            .exec(&transaction)
+            .await?;
+        self.last_write.mark();
+        transaction.commit().await?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::domain::sql_backend_handler::tests::*;
+
+    fn make_request(name: &str, users: &[&str]) -> SudoRoleRequest {
+        SudoRoleRequest {
+            name: name.to_owned(),
+            users: users.iter().map(|u| u.to_string()).collect(),
+            hosts: vec!["ALL".to_owned()],
+            commands: vec!["/usr/bin/apt".to_owned(), "/usr/bin/systemctl".to_owned()],
+            options: vec![],
+        }
+    }
+
+    async fn get_role_names(
+        handler: &SqlBackendHandler,
+        filters: Option<SudoRoleRequestFilter>,
+    ) -> Vec<String> {
+        handler
+            .list_sudo_roles(filters)
+            .await
+            .unwrap()
+            .into_iter()
+            .map(|r| r.name)
+            .collect()
+    }
+
+    async fn insert_roles(handler: &SqlBackendHandler) {
+        handler
+            .create_sudo_role(make_request("admins", &["%admins", "bob"]))
+            .await
+            .unwrap();
+        handler
+            .create_sudo_role(make_request("everyone", &["ALL"]))
+            .await
+            .unwrap();
+        handler
+            .create_sudo_role(SudoRoleRequest {
+                name: "defaults".to_owned(),
+                options: vec!["!lecture".to_owned(), "env_reset".to_owned()],
+                ..Default::default()
+            })
+            .await
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_create_and_list_sudo_roles() {
+        let fixture = TestFixture::new().await;
+        insert_roles(&fixture.handler).await;
+        let roles = fixture.handler.list_sudo_roles(None).await.unwrap();
+        assert_eq!(
+            roles.iter().map(|r| r.name.as_str()).collect::<Vec<_>>(),
+            vec!["admins", "defaults", "everyone"]
+        );
+        assert_eq!(roles[0].users, vec!["%admins", "bob"]);
+        assert_eq!(
+            roles[0].commands,
+            vec!["/usr/bin/apt", "/usr/bin/systemctl"]
+        );
+        assert!(roles[0].options.is_empty());
+        assert_eq!(roles[1].options, vec!["!lecture", "env_reset"]);
+        assert!(roles[1].users.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_create_sudo_role_validation() {
+        let fixture = TestFixture::new().await;
+        insert_roles(&fixture.handler).await;
+        for request in [
+            make_request("", &["bob"]),
+            make_request("a,b", &["bob"]),
+            make_request("no_users", &[]),
+            make_request("empty_value", &[" "]),
+            // Already exists, case-insensitively.
+            make_request("Admins", &["bob"]),
+        ] {
+            let err = fixture
+                .handler
+                .create_sudo_role(request.clone())
+                .await
+                .unwrap_err();
+            assert!(
+                matches!(err, DomainError::ValidationError(_)),
+                "{:?}: {}",
+                request,
+                err
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn test_list_sudo_roles_filters() {
+        let fixture = TestFixture::new().await;
+        insert_roles(&fixture.handler).await;
+        use SudoRoleRequestFilter::*;
+        assert_eq!(
+            get_role_names(
+                &fixture.handler,
+                Some(Or(vec![
+                    Equality(SudoAttribute::User, "bob".to_owned()),
+                    Equality(SudoAttribute::User, "ALL".to_owned()),
+                ]))
+            )
+            .await,
+            vec!["admins", "everyone"]
+        );
+        assert_eq!(
+            get_role_names(&fixture.handler, Some(Name("ADMINS".to_owned()))).await,
+            vec!["admins"]
+        );
+        assert_eq!(
+            get_role_names(&fixture.handler, Some(Present(SudoAttribute::Option))).await,
+            vec!["defaults"]
+        );
+        assert_eq!(
+            get_role_names(
+                &fixture.handler,
+                Some(Not(Box::new(Present(SudoAttribute::User))))
+            )
+            .await,
+            vec!["defaults"]
+        );
+        assert_eq!(
+            get_role_names(
+                &fixture.handler,
+                Some(Substring(
+                    SudoAttribute::User,
+                    SubstringPattern {
+                        initial: Some("%".to_owned()),
+                        ..Default::default()
+                    }
+                ))
+            )
+            .await,
+            vec!["admins"]
+        );
+        assert!(get_role_names(&fixture.handler, Some(Or(vec![])))
+            .await
+            .is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_update_sudo_role() {
+        let fixture = TestFixture::new().await;
+        insert_roles(&fixture.handler).await;
+        fixture
+            .handler
+            .update_sudo_role(SudoRoleRequest {
+                options: vec!["!authenticate".to_owned()],
+                ..make_request("ADMINS", &["patrick"])
+            })
+            .await
+            .unwrap();
+        let role = fixture
+            .handler
+            .list_sudo_roles(Some(SudoRoleRequestFilter::Name("admins".to_owned())))
+            .await
+            .unwrap()
+            .pop()
+            .unwrap();
+        // The name is kept as created.
+        assert_eq!(role.name, "admins");
+        assert_eq!(role.users, vec!["patrick"]);
+        assert_eq!(role.options, vec!["!authenticate"]);
+        assert!(role.modified_at >= role.creation_date);
+
+        let err = fixture
+            .handler
+            .update_sudo_role(make_request("unknown", &["bob"]))
+            .await
+            .unwrap_err();
+        assert!(matches!(err, DomainError::EntityNotFound(_)), "{}", err);
+    }
+
+    #[tokio::test]
+    async fn test_delete_sudo_role() {
+        let fixture = TestFixture::new().await;
+        insert_roles(&fixture.handler).await;
+        fixture.handler.delete_sudo_role("admins").await.unwrap();
+        assert_eq!(
+            get_role_names(&fixture.handler, None).await,
+            vec!["defaults", "everyone"]
+        );
+        assert_eq!(
+            model::SudoRoleValues::find()
+                .all(&fixture.handler.sql_pool)
+                .await
+                .unwrap()
+                .len(),
+            // everyone: 1 user, 1 host, 2 commands. defaults: 2 options.
+            6
+        );
+        assert!(matches!(
+            fixture.handler.delete_sudo_role("admins").await,
+            Err(DomainError::EntityNotFound(_))
+        ));
+    }
+}
@@ -1,11 +1,21 @@
-use super::sql_migrations::{get_schema_version, migrate_from_version, upgrade_to_v1};
+use super::sql_migrations::{
+    create_migration_history_table, get_migration_history, get_schema_version,
+    migrate_from_version, upgrade_to_v1, MigrationLock,
+};
+use crate::infra::configuration::UuidBackfill;
+use anyhow::bail;
 use sea_orm::Value;
+use std::time::Duration;
+use tracing::info;
 
 pub type DbConnection = sea_orm::DatabaseConnection;
 
-#[derive(Copy, PartialEq, Eq, Debug, Clone)]
+#[derive(Copy, PartialEq, Eq, PartialOrd, Ord, Debug, Clone)]
 pub struct SchemaVersion(pub u8);
 
+/// The schema version that this binary expects.
+pub const LAST_SCHEMA_VERSION: SchemaVersion = SchemaVersion(32);
+
 impl sea_orm::TryGetable for SchemaVersion {
     fn try_get(
         res: &sea_orm::QueryResult,
@@ -22,29 +32,91 @@ impl From<SchemaVersion> for Value {
     }
 }
 
-pub async fn init_table(pool: &DbConnection) -> anyhow::Result<()> {
+/// How long to wait for another instance to finish migrating before checking the version again.
+const MIGRATION_LOCK_TIMEOUT: Duration = Duration::from_secs(30);
+
+async fn migrate_to_last_version(
+    pool: &DbConnection,
+    uuid_backfill: UuidBackfill,
+) -> anyhow::Result<()> {
+    create_migration_history_table(pool).await?;
     let version = {
         if let Some(version) = get_schema_version(pool).await {
             version
         } else {
-            upgrade_to_v1(pool).await?;
+            upgrade_to_v1(pool, uuid_backfill).await?;
             SchemaVersion(1)
         }
     };
-    migrate_from_version(pool, version).await?;
+    migrate_from_version(pool, version, LAST_SCHEMA_VERSION).await
+}
+
+/// Fails if the DB was migrated by a newer binary: this one doesn't know the newer migrations, so
+/// it can't undo them. Otherwise, returns the schema version and logs the upgrades to run, if any.
+pub async fn check_schema_version(pool: &DbConnection) -> anyhow::Result<Option<SchemaVersion>> {
+    let version = get_schema_version(pool).await;
+    match version {
+        Some(version) if version > LAST_SCHEMA_VERSION => {
+            // The history is missing on the DBs migrated before it was introduced.
+            let required = get_migration_history(pool)
+                .await
+                .ok()
+                .and_then(|history| history.into_iter().rev().find(|r| r.version == version))
+                .map(|record| format!("LLDAP {} or newer", record.lldap_version))
+                .unwrap_or_else(|| "a newer LLDAP".to_owned());
+            bail!(
+                "The DB schema is at version {}, but this binary (LLDAP {}) only supports up to \
+                 version {}. The DB was upgraded by a newer binary, and can't be downgraded: run \
+                 {}, or restore a backup taken before the upgrade",
+                version.0,
+                env!("CARGO_PKG_VERSION"),
+                LAST_SCHEMA_VERSION.0,
+                required
+            )
+        }
+        Some(version) if version < LAST_SCHEMA_VERSION => info!(
+            "The DB schema is at version {}, the migrations to versions {} to {} will run",
+            version.0,
+            version.0 + 1,
+            LAST_SCHEMA_VERSION.0
+        ),
+        _ => (),
+    }
+    Ok(version)
+}
+
+/// Migrates the DB to the expected version, unless another instance sharing the DB does it first.
+pub async fn init_table(pool: &DbConnection, uuid_backfill: UuidBackfill) -> anyhow::Result<()> {
+    check_schema_version(pool).await?;
+    loop {
+        if let Some(lock) = MigrationLock::acquire(pool, MIGRATION_LOCK_TIMEOUT).await? {
+            let result = migrate_to_last_version(pool, uuid_backfill).await;
+            lock.release(pool).await?;
+            result?;
+            break;
+        }
+        if get_schema_version(pool).await == Some(LAST_SCHEMA_VERSION) {
+            break;
+        }
+        info!("Waiting for another instance to finish migrating the DB");
+    }
     Ok(())
 }
 
 #[cfg(test)]
 mod tests {
     use crate::domain::{
+        model::{self, UserColumn},
         sql_migrations,
         types::{GroupId, Uuid},
     };
 
     use super::*;
     use chrono::prelude::*;
-    use sea_orm::{ConnectionTrait, Database, DbBackend, FromQueryResult};
+    use sea_orm::{
+        ColumnTrait, ConnectionTrait, Database, DbBackend, EntityTrait, FromQueryResult,
+        QueryFilter, QuerySelect,
+    };
 
     async fn get_in_memory_db() -> DbConnection {
         let mut sql_opt = sea_orm::ConnectOptions::new("sqlite::memory:".to_owned());
@@ -59,7 +131,9 @@ mod tests {
     #[tokio::test]
     async fn test_init_table() {
         let sql_pool = get_in_memory_db().await;
-        init_table(&sql_pool).await.unwrap();
+        init_table(&sql_pool, UuidBackfill::default())
+            .await
+            .unwrap();
         sql_pool.execute(raw_statement(
         r#"INSERT INTO users
       (user_id, email, display_name, first_name, last_name, creation_date, password_hash, uuid)
@@ -89,8 +163,12 @@ mod tests {
     async fn test_already_init_table() {
         crate::infra::logging::init_for_tests();
         let sql_pool = get_in_memory_db().await;
-        init_table(&sql_pool).await.unwrap();
-        init_table(&sql_pool).await.unwrap();
+        init_table(&sql_pool, UuidBackfill::default())
+            .await
+            .unwrap();
+        init_table(&sql_pool, UuidBackfill::default())
+            .await
+            .unwrap();
     }
 
     #[tokio::test]
@@ -123,7 +201,9 @@ mod tests {
             ))
             .await
             .unwrap();
-        init_table(&sql_pool).await.unwrap();
+        init_table(&sql_pool, UuidBackfill::default())
+            .await
+            .unwrap();
         sql_pool
             .execute(raw_statement(
                 r#"INSERT INTO groups (display_name, creation_date, uuid)
@@ -180,11 +260,188 @@ mod tests {
             .unwrap()
             .unwrap(),
             sql_migrations::JustSchemaVersion {
-                version: SchemaVersion(1)
+                version: LAST_SCHEMA_VERSION
             }
         );
     }
 
+    #[tokio::test]
+    async fn test_migrate_tables_uuid_collision() {
+        let sql_pool = get_in_memory_db().await;
+        sql_pool
+            .execute(raw_statement(
+                r#"CREATE TABLE groups ( group_id INTEGER PRIMARY KEY, display_name TEXT );"#,
+            ))
+            .await
+            .unwrap();
+        // Same name, and the creation date will be the same when the column is added.
+        sql_pool
+            .execute(raw_statement(
+                r#"INSERT INTO groups (display_name)
+                      VALUES ("duplicate"), ("duplicate")"#,
+            ))
+            .await
+            .unwrap();
+        init_table(&sql_pool, UuidBackfill::default())
+            .await
+            .unwrap();
+        #[derive(FromQueryResult, PartialEq, Eq, Debug)]
+        struct JustUuid {
+            uuid: Uuid,
+        }
+        let uuids = JustUuid::find_by_statement(raw_statement(
+            r#"SELECT uuid FROM groups WHERE display_name = "duplicate""#,
+        ))
+        .all(&sql_pool)
+        .await
+        .unwrap();
+        assert_eq!(uuids.len(), 2);
+        assert_ne!(uuids[0], uuids[1]);
+    }
+
+    async fn backfill_users_uuids(uuid_backfill: UuidBackfill) -> Vec<Uuid> {
+        let sql_pool = get_in_memory_db().await;
+        sql_pool
+            .execute(raw_statement(
+                r#"CREATE TABLE users ( user_id TEXT , creation_date TEXT, uuid TEXT NOT NULL DEFAULT "");"#,
+            ))
+            .await
+            .unwrap();
+        // The UUID of bob comes from another instance.
+        sql_pool
+            .execute(raw_statement(
+                r#"INSERT INTO users (user_id, creation_date, uuid)
+                       VALUES ("bob", "1970-01-01 00:00:00", "b9c7ed26-3f4b-4c35-8d52-a6b36e1d0bc4"),
+                              ("john", "1970-01-01 00:00:00", "")"#,
+            ))
+            .await
+            .unwrap();
+        init_table(&sql_pool, uuid_backfill).await.unwrap();
+        #[derive(FromQueryResult)]
+        struct JustUuid {
+            uuid: Uuid,
+        }
+        JustUuid::find_by_statement(raw_statement(r#"SELECT uuid FROM users ORDER BY user_id"#))
+            .all(&sql_pool)
+            .await
+            .unwrap()
+            .into_iter()
+            .map(|u| u.uuid)
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn test_migrate_tables_uuid_backfill() {
+        let bob_uuid = crate::uuid!("b9c7ed26-3f4b-4c35-8d52-a6b36e1d0bc4");
+        let john_uuid = Uuid::from_name_and_date("john", &Utc.timestamp_opt(0, 0).unwrap());
+        assert_eq!(
+            backfill_users_uuids(UuidBackfill::Deterministic).await,
+            vec![bob_uuid.clone(), john_uuid.clone()]
+        );
+        // Even the UUID already set is replaced.
+        let uuids = backfill_users_uuids(UuidBackfill::Random).await;
+        assert_ne!(uuids[0], bob_uuid);
+        assert_ne!(uuids[1], john_uuid);
+        assert_ne!(uuids[0], uuids[1]);
+        assert_ne!(uuids[1].as_str(), "");
+    }
+
+    #[tokio::test]
+    async fn test_newer_schema_version() {
+        let sql_pool = get_in_memory_db().await;
+        init_table(&sql_pool, UuidBackfill::default())
+            .await
+            .unwrap();
+        let newer_version = LAST_SCHEMA_VERSION.0 + 1;
+        sql_pool
+            .execute(raw_statement(&format!(
+                "UPDATE metadata SET version = {}",
+                newer_version
+            )))
+            .await
+            .unwrap();
+        let error = init_table(&sql_pool, UuidBackfill::default())
+            .await
+            .unwrap_err()
+            .to_string();
+        assert!(error.contains("run a newer LLDAP"), "{}", error);
+        sql_pool
+            .execute(raw_statement(&format!(
+                r#"INSERT INTO migration_history (version, applied_at, lldap_version)
+                      VALUES ({}, "2030-01-01 00:00:00", "9.1.0")"#,
+                newer_version
+            )))
+            .await
+            .unwrap();
+        let error = init_table(&sql_pool, UuidBackfill::default())
+            .await
+            .unwrap_err()
+            .to_string();
+        assert!(
+            error.contains(&format!(
+                "The DB schema is at version {}, but this binary (LLDAP {}) only supports up to \
+                 version {}",
+                newer_version,
+                env!("CARGO_PKG_VERSION"),
+                LAST_SCHEMA_VERSION.0
+            )),
+            "{}",
+            error
+        );
+        assert!(error.contains("run LLDAP 9.1.0 or newer"), "{}", error);
+        // Nothing was touched.
+        assert_eq!(
+            sql_migrations::get_schema_version(&sql_pool).await,
+            Some(SchemaVersion(newer_version))
+        );
+    }
+
+    #[tokio::test]
+    async fn test_unique_email_with_duplicates() {
+        let sql_pool = get_in_memory_db().await;
+        init_table(&sql_pool, UuidBackfill::default())
+            .await
+            .unwrap();
+        migrate_from_version(&sql_pool, LAST_SCHEMA_VERSION, SchemaVersion(3))
+            .await
+            .unwrap();
+        sql_pool
+            .execute(raw_statement(
+                r#"INSERT INTO users
+                      (user_id, email, display_name, creation_date, uuid)
+                      VALUES ("bob", "bob@bob.bob", "Bob", "1970-01-01 00:00:00", "abc"),
+                             ("robert", "bob@bob.bob", "Robert", "1970-01-01 00:00:00", "def"),
+                             ("noemail1", "", "", "1970-01-01 00:00:00", "ghi"),
+                             ("noemail2", "", "", "1970-01-01 00:00:00", "jkl")"#,
+            ))
+            .await
+            .unwrap();
+        let error = migrate_from_version(&sql_pool, SchemaVersion(3), LAST_SCHEMA_VERSION)
+            .await
+            .unwrap_err()
+            .to_string();
+        assert!(error.contains("'bob', 'robert'"), "{}", error);
+        assert_eq!(
+            sql_migrations::get_schema_version(&sql_pool).await,
+            Some(SchemaVersion(3))
+        );
+        sql_pool
+            .execute(raw_statement(
+                r#"UPDATE users SET email = "robert@bob.bob" WHERE user_id = "robert""#,
+            ))
+            .await
+            .unwrap();
+        migrate_from_version(&sql_pool, SchemaVersion(3), LAST_SCHEMA_VERSION)
+            .await
+            .unwrap();
+        assert!(sql_pool
+            .execute(raw_statement(
+                r#"UPDATE users SET email = "bob@bob.bob" WHERE user_id = "robert""#,
+            ))
+            .await
+            .is_err());
+    }
+
     #[tokio::test]
     async fn test_too_high_version() {
         let sql_pool = get_in_memory_db().await;
@@ -201,6 +458,441 @@ mod tests {
             ))
             .await
             .unwrap();
-        assert!(init_table(&sql_pool).await.is_err());
+        assert!(init_table(&sql_pool, UuidBackfill::default())
+            .await
+            .is_err());
+    }
+
+    #[tokio::test]
+    async fn test_interrupted_initialization() {
+        let sql_pool = get_in_memory_db().await;
+        // What an interrupted initialization leaves on MySQL: some tables, but no version.
+        sql_pool
+            .execute(raw_statement(
+                r#"CREATE TABLE metadata ( version INTEGER);"#,
+            ))
+            .await
+            .unwrap();
+        sql_pool
+            .execute(raw_statement(
+                r#"CREATE TABLE users ( user_id TEXT , creation_date TEXT);"#,
+            ))
+            .await
+            .unwrap();
+        let error = init_table(&sql_pool, UuidBackfill::default())
+            .await
+            .unwrap_err()
+            .to_string();
+        assert!(error.contains("was interrupted"), "{}", error);
+        assert_eq!(sql_migrations::get_schema_version(&sql_pool).await, None);
+    }
+
+    #[tokio::test]
+    async fn test_downgrade_without_registered_step() {
+        let sql_pool = get_in_memory_db().await;
+        init_table(&sql_pool, UuidBackfill::default())
+            .await
+            .unwrap();
+        sql_pool
+            .execute(raw_statement(r#"UPDATE metadata SET version = 3"#))
+            .await
+            .unwrap();
+        assert!(
+            migrate_from_version(&sql_pool, SchemaVersion(3), LAST_SCHEMA_VERSION)
+                .await
+                .is_err()
+        );
+        // Nothing was applied.
+        assert_eq!(
+            sql_migrations::get_schema_version(&sql_pool).await,
+            Some(SchemaVersion(3))
+        );
+    }
+
+    #[tokio::test]
+    async fn test_downgrade_to_v1() {
+        let sql_pool = get_in_memory_db().await;
+        init_table(&sql_pool, UuidBackfill::default())
+            .await
+            .unwrap();
+        migrate_from_version(&sql_pool, LAST_SCHEMA_VERSION, SchemaVersion(1))
+            .await
+            .unwrap();
+        assert_eq!(
+            sql_migrations::get_schema_version(&sql_pool).await,
+            Some(SchemaVersion(1))
+        );
+        assert!(sql_pool
+            .execute(raw_statement(r#"SELECT * FROM user_attributes"#))
+            .await
+            .is_err());
+        assert!(sql_pool
+            .execute(raw_statement(r#"SELECT * FROM group_attributes"#))
+            .await
+            .is_err());
+        // And back up.
+        init_table(&sql_pool, UuidBackfill::default())
+            .await
+            .unwrap();
+        assert_eq!(
+            sql_migrations::get_schema_version(&sql_pool).await,
+            Some(LAST_SCHEMA_VERSION)
+        );
+    }
+
+    #[tokio::test]
+    async fn test_downgrade_with_encrypted_totp_secrets() {
+        let sql_pool = get_in_memory_db().await;
+        init_table(&sql_pool, UuidBackfill::default())
+            .await
+            .unwrap();
+        sql_pool
+            .execute(raw_statement(
+                r#"INSERT INTO users (user_id, email, display_name, creation_date, uuid, totp_secret)
+                      VALUES ("bob", "bob@bob.bob", "", "1970-01-01 00:00:00",
+                              "a02eaf13-48a7-30f6-a3d4-040ff7c52b04", "enc:key:secret")"#,
+            ))
+            .await
+            .unwrap();
+        let error = migrate_from_version(&sql_pool, LAST_SCHEMA_VERSION, SchemaVersion(1))
+            .await
+            .unwrap_err()
+            .to_string();
+        assert!(error.contains("bob"), "{}", error);
+        // Stopped before the secrets could be taken for plaintext ones.
+        assert_eq!(
+            sql_migrations::get_schema_version(&sql_pool).await,
+            Some(SchemaVersion(18))
+        );
+    }
+
+    #[tokio::test]
+    async fn test_migration_history() {
+        let sql_pool = get_in_memory_db().await;
+        init_table(&sql_pool, UuidBackfill::default())
+            .await
+            .unwrap();
+        let versions = |history: Vec<sql_migrations::MigrationRecord>| {
+            history
+                .into_iter()
+                .map(|r| {
+                    assert_eq!(r.lldap_version, env!("CARGO_PKG_VERSION"));
+                    r.version.0
+                })
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(
+            versions(
+                sql_migrations::get_migration_history(&sql_pool)
+                    .await
+                    .unwrap()
+            ),
+            (1..=LAST_SCHEMA_VERSION.0).collect::<Vec<_>>()
+        );
+        migrate_from_version(&sql_pool, LAST_SCHEMA_VERSION, SchemaVersion(1))
+            .await
+            .unwrap();
+        assert_eq!(
+            versions(
+                sql_migrations::get_migration_history(&sql_pool)
+                    .await
+                    .unwrap()
+            ),
+            vec![1]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_migrate_to_same_version() {
+        let sql_pool = get_in_memory_db().await;
+        init_table(&sql_pool, UuidBackfill::default())
+            .await
+            .unwrap();
+        migrate_from_version(&sql_pool, LAST_SCHEMA_VERSION, LAST_SCHEMA_VERSION)
+            .await
+            .unwrap();
+        assert_eq!(
+            sql_migrations::get_schema_version(&sql_pool).await,
+            Some(LAST_SCHEMA_VERSION)
+        );
+    }
+
+    async fn check_uuid_round_trip(sql_pool: &DbConnection) {
+        use sea_orm::sea_query::{Expr, Query};
+        use sql_migrations::Users;
+        init_table(sql_pool, UuidBackfill::default()).await.unwrap();
+        let builder = sql_pool.get_database_backend();
+        let uuid = crate::uuid!("a02eaf13-48a7-30f6-a3d4-040ff7c52b04");
+        sql_pool
+            .execute(
+                builder.build(
+                    Query::delete()
+                        .from_table(Users::Table)
+                        .and_where(Expr::col(Users::UserId).eq("uuid_round_trip")),
+                ),
+            )
+            .await
+            .unwrap();
+        sql_pool
+            .execute(
+                builder.build(
+                    Query::insert()
+                        .into_table(Users::Table)
+                        .columns([
+                            Users::UserId,
+                            Users::Email,
+                            Users::DisplayName,
+                            Users::CreationDate,
+                            Users::Uuid,
+                        ])
+                        .values_panic([
+                            "uuid_round_trip".into(),
+                            "uuid_round_trip@example.com".into(),
+                            "".into(),
+                            chrono::Utc.timestamp_opt(0, 0).unwrap().naive_utc().into(),
+                            uuid.clone().into_expr(),
+                        ]),
+                ),
+            )
+            .await
+            .unwrap();
+        #[derive(FromQueryResult, PartialEq, Eq, Debug)]
+        struct JustUuid {
+            uuid: Uuid,
+        }
+        assert_eq!(
+            JustUuid::find_by_statement(
+                builder.build(
+                    Query::select()
+                        .column(Users::Uuid)
+                        .from(Users::Table)
+                        .and_where(Expr::col(Users::Uuid).eq(uuid.clone().into_expr())),
+                )
+            )
+            .all(sql_pool)
+            .await
+            .unwrap(),
+            vec![JustUuid { uuid: uuid.clone() }]
+        );
+        // Through the entity.
+        assert_eq!(
+            model::User::find()
+                .select_only()
+                .column(UserColumn::Uuid)
+                .filter(ColumnTrait::eq(&UserColumn::Uuid, uuid.clone()))
+                .into_model::<JustUuid>()
+                .all(sql_pool)
+                .await
+                .unwrap(),
+            vec![JustUuid { uuid }]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_uuid_round_trip() {
+        check_uuid_round_trip(&get_in_memory_db().await).await;
+        // The other backends need a running server: set the variables to a throwaway database.
+        for var in ["LLDAP_TEST_POSTGRES_URL", "LLDAP_TEST_MYSQL_URL"] {
+            if let Ok(url) = std::env::var(var) {
+                check_uuid_round_trip(&Database::connect(url).await.unwrap()).await;
+            }
+        }
+    }
+
+    /// Runs against a throwaway PostgreSQL database, if LLDAP_TEST_POSTGRES_URL is set: its UUID
+    /// columns are native from the creation of the tables.
+    #[tokio::test]
+    async fn test_postgres_fresh_install() {
+        let url = match std::env::var("LLDAP_TEST_POSTGRES_URL") {
+            Ok(url) => url,
+            Err(_) => return,
+        };
+        let sql_pool = Database::connect(url).await.unwrap();
+        for uuid_backfill in [UuidBackfill::Deterministic, UuidBackfill::Random] {
+            // Start from an empty database.
+            for statement in ["DROP SCHEMA public CASCADE", "CREATE SCHEMA public"] {
+                sql_pool
+                    .execute(sea_orm::Statement::from_string(
+                        DbBackend::Postgres,
+                        statement.to_owned(),
+                    ))
+                    .await
+                    .unwrap();
+            }
+            init_table(&sql_pool, uuid_backfill).await.unwrap();
+            assert_eq!(
+                sql_migrations::get_schema_version(&sql_pool).await,
+                Some(LAST_SCHEMA_VERSION)
+            );
+            check_uuid_round_trip(&sql_pool).await;
+        }
+    }
+
+    /// Runs against a throwaway MySQL/MariaDB database, if LLDAP_TEST_MYSQL_URL is set.
+    #[tokio::test]
+    async fn test_mysql_migrations() {
+        use sea_orm::sea_query::{Expr, Query};
+        use sql_migrations::Groups;
+        let url = match std::env::var("LLDAP_TEST_MYSQL_URL") {
+            Ok(url) => url,
+            Err(_) => return,
+        };
+        let sql_pool = Database::connect(url).await.unwrap();
+        init_table(&sql_pool, UuidBackfill::default())
+            .await
+            .unwrap();
+        // Go through all the migrations, both ways.
+        migrate_from_version(&sql_pool, LAST_SCHEMA_VERSION, SchemaVersion(1))
+            .await
+            .unwrap();
+        migrate_from_version(&sql_pool, SchemaVersion(1), LAST_SCHEMA_VERSION)
+            .await
+            .unwrap();
+        assert_eq!(
+            sql_migrations::get_schema_version(&sql_pool).await,
+            Some(LAST_SCHEMA_VERSION)
+        );
+
+        let builder = sql_pool.get_database_backend();
+        let delete_groups = builder.build(
+            Query::delete()
+                .from_table(Groups::Table)
+                .and_where(Expr::col(Groups::DisplayName).eq("mysql_test_group")),
+        );
+        sql_pool.execute(delete_groups.clone()).await.unwrap();
+        let insert_group = |name: &str| {
+            builder.build(
+                Query::insert()
+                    .into_table(Groups::Table)
+                    .columns([Groups::DisplayName, Groups::CreationDate, Groups::Uuid])
+                    .values_panic([
+                        name.into(),
+                        chrono::Utc::now().naive_utc().into(),
+                        Uuid::random().into(),
+                    ]),
+            )
+        };
+        // The group ID is allocated by the database.
+        sql_pool
+            .execute(insert_group("mysql_test_group"))
+            .await
+            .unwrap();
+        // Group names are unique regardless of the case.
+        sql_pool
+            .execute(insert_group("MYSQL_TEST_GROUP"))
+            .await
+            .unwrap_err();
+        sql_pool.execute(delete_groups).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_membership_indexes() {
+        use sea_orm::sea_query::Query;
+        use sql_migrations::{Groups, Memberships, Users};
+        let sql_pool = get_in_memory_db().await;
+        init_table(&sql_pool, UuidBackfill::default())
+            .await
+            .unwrap();
+        let builder = sql_pool.get_database_backend();
+        let creation_date = chrono::Utc.timestamp_opt(0, 0).unwrap().naive_utc();
+        let mut groups = Query::insert();
+        groups.into_table(Groups::Table).columns([
+            Groups::GroupId,
+            Groups::DisplayName,
+            Groups::CreationDate,
+            Groups::Uuid,
+        ]);
+        for group in 0..100 {
+            groups.values_panic([
+                group.into(),
+                format!("group{}", group).into(),
+                creation_date.into(),
+                Uuid::random().into(),
+            ]);
+        }
+        sql_pool.execute(builder.build(&groups)).await.unwrap();
+        for user in 0..100 {
+            let user_id = format!("user{}", user);
+            sql_pool
+                .execute(
+                    builder.build(
+                        Query::insert()
+                            .into_table(Users::Table)
+                            .columns([
+                                Users::UserId,
+                                Users::Email,
+                                Users::DisplayName,
+                                Users::CreationDate,
+                                Users::Uuid,
+                            ])
+                            .values_panic([
+                                user_id.clone().into(),
+                                format!("{}@example.com", user_id).into(),
+                                "".into(),
+                                creation_date.into(),
+                                Uuid::random().into(),
+                            ]),
+                    ),
+                )
+                .await
+                .unwrap();
+            let mut memberships = Query::insert();
+            memberships
+                .into_table(Memberships::Table)
+                .columns([Memberships::UserId, Memberships::GroupId]);
+            for group in 0..100 {
+                memberships.values_panic([user_id.clone().into(), group.into()]);
+            }
+            sql_pool.execute(builder.build(&memberships)).await.unwrap();
+        }
+
+        #[derive(FromQueryResult)]
+        struct QueryPlan {
+            detail: String,
+        }
+        async fn query_plan(sql_pool: &DbConnection, query: &str) -> String {
+            QueryPlan::find_by_statement(raw_statement(&format!("EXPLAIN QUERY PLAN {}", query)))
+                .all(sql_pool)
+                .await
+                .unwrap()
+                .into_iter()
+                .map(|p| p.detail)
+                .collect::<Vec<_>>()
+                .join("\n")
+        }
+        let plan = query_plan(
+            &sql_pool,
+            "SELECT user_id FROM memberships WHERE group_id = 42",
+        )
+        .await;
+        assert!(plan.contains("membership_group_user"), "{}", plan);
+        let plan = query_plan(
+            &sql_pool,
+            "SELECT group_id FROM memberships WHERE user_id = 'user42'",
+        )
+        .await;
+        assert!(plan.contains("membership_user_group"), "{}", plan);
+    }
+
+    #[tokio::test]
+    async fn test_migration_lock() {
+        let sql_pool = get_in_memory_db().await;
+        let lock = MigrationLock::acquire(&sql_pool, Duration::ZERO)
+            .await
+            .unwrap()
+            .unwrap();
+        assert!(MigrationLock::acquire(&sql_pool, Duration::from_millis(10))
+            .await
+            .unwrap()
+            .is_none());
+        lock.release(&sql_pool).await.unwrap();
+        // The lock is free again, and the tables can be initialized.
+        init_table(&sql_pool, UuidBackfill::default())
+            .await
+            .unwrap();
+        MigrationLock::acquire(&sql_pool, Duration::ZERO)
+            .await
+            .unwrap()
+            .unwrap();
     }
 }
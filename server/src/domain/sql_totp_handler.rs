@@ -0,0 +1,315 @@
+use super::{
+    error::{DomainError, Result},
+    handler::UserBackendHandler,
+    model::{self, UserColumn},
+    sql_audit_log_handler::{self as audit, AuditChange},
+    sql_backend_handler::SqlBackendHandler,
+    totp::{base32_decode, base32_encode, TotpParameters},
+    totp_handler::{TotpEnrollment, TotpHandler},
+    totp_secret::ENCRYPTED_PREFIX,
+    types::UserId,
+};
+use async_trait::async_trait;
+use sea_orm::{
+    sea_query::{Cond, Expr},
+    ColumnTrait, EntityTrait, FromQueryResult, QueryFilter, QuerySelect, TransactionTrait,
+};
+use tracing::{debug, instrument};
+
+fn internal_error(e: anyhow::Error) -> DomainError {
+    DomainError::InternalError(format!("{:#}", e))
+}
+
+impl SqlBackendHandler {
+    fn encrypt_totp_secret(&self, secret: String) -> Result<String> {
+        match &self.totp_keys {
+            Some(keys) => keys.encrypt(&secret).map_err(internal_error),
+            None => Ok(secret),
+        }
+    }
+
+    fn decrypt_totp_secret(&self, stored: &str) -> Result<String> {
+        match &self.totp_keys {
+            Some(keys) => keys.decrypt(stored).map_err(internal_error),
+            None if stored.starts_with(ENCRYPTED_PREFIX) => Err(DomainError::InternalError(
+                "The TOTP secret is encrypted, but no `totp_encryption_key_file` is set".to_owned(),
+            )),
+            None => Ok(stored.to_owned()),
+        }
+    }
+}
+
+#[async_trait]
+impl TotpHandler for SqlBackendHandler {
+    #[instrument(skip_all, level = "debug", err)]
+    async fn enroll_totp(&self, user_id: &UserId) -> Result<TotpEnrollment> {
+        debug!(?user_id);
+        // Make sure the user hasn't been deleted.
+        self.get_user_details(user_id).await?;
+        let options = &self.config.totp_options;
+        let parameters = options.parameters();
+        let secret = parameters.generate_secret();
+        let encoded = base32_encode(&secret);
+        let transaction = self.sql_pool.begin().await?;
+        model::User::update_many()
+            .col_expr(
+                UserColumn::TotpSecret,
+                Expr::value(self.encrypt_totp_secret(encoded.clone())?),
+            )
+            .col_expr(
+                UserColumn::TotpParameters,
+                Expr::value(parameters.to_stored()),
+            )
+            // The counters of the previous secret don't apply to the new one.
+            .col_expr(
+                UserColumn::TotpLastCounter,
+                Expr::value(Option::<i64>::None),
+            )
+            .filter(ColumnTrait::eq(&UserColumn::UserId, user_id))
+            .exec(&transaction)
+            .await?;
+        let events = self
+            .write_audit_log(
+                &transaction,
+                vec![AuditChange::user(user_id, audit::TOTP_SECRET)],
+            )
+            .await?;
+        transaction.commit().await?;
+        events.publish();
+        Ok(TotpEnrollment {
+            uri: parameters.otpauth_uri(&options.issuer, user_id.as_str(), &secret),
+            secret: encoded,
+        })
+    }
+
+    #[instrument(skip_all, level = "debug", err)]
+    async fn verify_totp_code(&self, user_id: &UserId, code: &str) -> Result<()> {
+        debug!(?user_id);
+        #[derive(FromQueryResult)]
+        struct Totp {
+            totp_secret: Option<String>,
+            totp_parameters: Option<String>,
+        }
+        let totp = model::User::find_by_id(user_id.clone())
+            .select_only()
+            .column(UserColumn::TotpSecret)
+            .column(UserColumn::TotpParameters)
+            .into_model::<Totp>()
+            .one(&self.sql_pool)
+            .await?;
+        let (stored, parameters) = match totp {
+            Some(Totp {
+                totp_secret: Some(stored),
+                totp_parameters,
+            }) => (stored, totp_parameters),
+            _ => {
+                return Err(DomainError::AuthenticationError(format!(
+                    "No TOTP secret for user '{}'",
+                    user_id
+                )))
+            }
+        };
+        // The secrets enrolled before the parameters were stored use the defaults.
+        let parameters = parameters
+            .map(|p| TotpParameters::from_stored(&p))
+            .transpose()
+            .map_err(internal_error)?
+            .unwrap_or_default();
+        let secret = base32_decode(&self.decrypt_totp_secret(&stored)?).map_err(internal_error)?;
+        let now = chrono::Utc::now().timestamp().max(0) as u64;
+        let counter = parameters
+            .matching_counter(&secret, code, now, self.config.totp_options.drift_steps)
+            .ok_or_else(|| {
+                DomainError::AuthenticationError(format!(
+                    "Invalid TOTP code for user '{}'",
+                    user_id
+                ))
+            })?;
+        // A single statement: of two concurrent logins with the same code, only one succeeds.
+        // The earlier counters are refused too, they would allow replaying a code within the
+        // drift window once a later one was used.
+        let counter =
+            i64::try_from(counter).map_err(|e| DomainError::InternalError(e.to_string()))?;
+        let result = model::User::update_many()
+            .col_expr(UserColumn::TotpLastCounter, Expr::value(counter))
+            .filter(ColumnTrait::eq(&UserColumn::UserId, user_id))
+            .filter(
+                Cond::any()
+                    .add(UserColumn::TotpLastCounter.is_null())
+                    .add(UserColumn::TotpLastCounter.lt(counter)),
+            )
+            .exec(&self.sql_pool)
+            .await?;
+        if result.rows_affected == 0 {
+            return Err(DomainError::AuthenticationError(format!(
+                "TOTP code already used for user '{}'",
+                user_id
+            )));
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        domain::{sql_backend_handler::tests::*, totp::TotpAlgorithm, totp_secret::TotpSecretKeys},
+        infra::configuration::TotpOptionsBuilder,
+    };
+
+    fn now() -> u64 {
+        chrono::Utc::now().timestamp() as u64
+    }
+
+    fn secret_of(enrollment: &TotpEnrollment) -> Vec<u8> {
+        base32_decode(&enrollment.secret).unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_enroll_and_verify() {
+        let mut fixture = TestFixture::new().await;
+        fixture.handler.config.totp_options = TotpOptionsBuilder::default()
+            .algorithm(TotpAlgorithm::Sha256)
+            .digits(8)
+            .period_seconds(60)
+            .drift_steps(2)
+            .issuer("Example Corp".to_owned())
+            .build()
+            .unwrap();
+        let bob = UserId::new("bob");
+        let enrollment = fixture.handler.enroll_totp(&bob).await.unwrap();
+        assert!(enrollment
+            .uri
+            .starts_with("otpauth://totp/Example%20Corp:bob?"));
+        assert!(enrollment
+            .uri
+            .ends_with("&algorithm=SHA256&digits=8&period=60"));
+        let parameters = fixture.handler.config.totp_options.parameters();
+        let secret = secret_of(&enrollment);
+        assert_eq!(secret.len(), 32);
+        // Within the drift window.
+        fixture
+            .handler
+            .verify_totp_code(&bob, &parameters.code_at(&secret, now() - 60))
+            .await
+            .unwrap();
+        fixture
+            .handler
+            .verify_totp_code(&bob, &parameters.code_at(&secret, now()))
+            .await
+            .unwrap();
+        assert!(matches!(
+            fixture
+                .handler
+                .verify_totp_code(&bob, &parameters.code_at(&secret, now() - 240))
+                .await,
+            Err(DomainError::AuthenticationError(_))
+        ));
+        // The codes are for bob only.
+        fixture
+            .handler
+            .verify_totp_code(&UserId::new("patrick"), &parameters.code_at(&secret, now()))
+            .await
+            .unwrap_err();
+        fixture
+            .handler
+            .enroll_totp(&UserId::new("nobody"))
+            .await
+            .unwrap_err();
+    }
+
+    #[tokio::test]
+    async fn test_enrollment_keeps_its_parameters() {
+        let mut fixture = TestFixture::new().await;
+        let bob = UserId::new("bob");
+        let enrollment = fixture.handler.enroll_totp(&bob).await.unwrap();
+        let secret = secret_of(&enrollment);
+        assert_eq!(secret.len(), 20);
+        // The configuration changes after the enrollment.
+        fixture.handler.config.totp_options = TotpOptionsBuilder::default()
+            .algorithm(TotpAlgorithm::Sha512)
+            .digits(7)
+            .build()
+            .unwrap();
+        fixture
+            .handler
+            .verify_totp_code(&bob, &TotpParameters::default().code_at(&secret, now()))
+            .await
+            .unwrap();
+        // The secrets enrolled before the parameters were stored use the defaults.
+        model::User::update_many()
+            .col_expr(
+                UserColumn::TotpParameters,
+                Expr::value(Option::<String>::None),
+            )
+            .filter(ColumnTrait::eq(&UserColumn::UserId, &bob))
+            .exec(&fixture.handler.sql_pool)
+            .await
+            .unwrap();
+        // The code of the next period, as the current one was just used.
+        fixture
+            .handler
+            .verify_totp_code(
+                &bob,
+                &TotpParameters::default().code_at(&secret, now() + 30),
+            )
+            .await
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_replayed_code() {
+        let fixture = TestFixture::new().await;
+        let bob = UserId::new("bob");
+        let secret = secret_of(&fixture.handler.enroll_totp(&bob).await.unwrap());
+        let parameters = TotpParameters::default();
+        let time = now();
+        let code = parameters.code_at(&secret, time);
+        fixture.handler.verify_totp_code(&bob, &code).await.unwrap();
+        assert!(matches!(
+            fixture.handler.verify_totp_code(&bob, &code).await,
+            Err(DomainError::AuthenticationError(_))
+        ));
+        // Nor an earlier code of the drift window.
+        fixture
+            .handler
+            .verify_totp_code(&bob, &parameters.code_at(&secret, time - 30))
+            .await
+            .unwrap_err();
+        // A new enrollment starts over.
+        let secret = secret_of(&fixture.handler.enroll_totp(&bob).await.unwrap());
+        fixture
+            .handler
+            .verify_totp_code(&bob, &parameters.code_at(&secret, now() - 30))
+            .await
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_encrypted_secret() {
+        let fixture = TestFixture::new().await;
+        let dir = std::env::temp_dir().join(format!("lldap-totp-keys-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let keys = TotpSecretKeys::from_file(dir.join("keys").to_str().unwrap()).unwrap();
+        std::fs::remove_dir_all(&dir).unwrap();
+        let handler = fixture.handler.clone().with_totp_keys(keys);
+        let bob = UserId::new("bob");
+        let enrollment = handler.enroll_totp(&bob).await.unwrap();
+        let stored = model::User::find_by_id(bob.clone())
+            .one(&handler.sql_pool)
+            .await
+            .unwrap()
+            .unwrap()
+            .totp_secret
+            .unwrap();
+        assert!(stored.starts_with(ENCRYPTED_PREFIX));
+        let code = TotpParameters::default().code_at(&secret_of(&enrollment), now());
+        handler.verify_totp_code(&bob, &code).await.unwrap();
+        // Without the keys, it can't be decrypted.
+        assert!(matches!(
+            fixture.handler.verify_totp_code(&bob, &code).await,
+            Err(DomainError::InternalError(_))
+        ));
+    }
+}
@@ -1,20 +1,37 @@
 use super::{
+    avatar,
+    change_events::PendingChangeEvents,
+    clock, email_policy,
     error::{DomainError, Result},
-    handler::{CreateUserRequest, UpdateUserRequest, UserBackendHandler, UserRequestFilter},
-    model::{self, GroupColumn, UserColumn},
-    sql_backend_handler::SqlBackendHandler,
-    types::{GroupDetails, GroupId, User, UserAndGroups, UserId, Uuid},
+    handler::{
+        AuditTarget, CreateUserRequest, MembershipChange, MembershipChangeStatus, Page, Pagination,
+        UpdateUserRequest, UserBackendHandler, UserRequestFilter, UserSortKey,
+    },
+    model::{self, GroupColumn, MembershipColumn, UserAttributesColumn, UserColumn},
+    sql_audit_log_handler::{self as audit, AuditChange},
+    sql_backend_handler::{encode_cursor, get_page_start, SqlBackendHandler},
+    sql_change_sync::record_tombstone,
+    sql_nested_group_backend_handler::GroupNesting,
+    sql_posix_numbers::{allocate_posix_number, PosixNumber},
+    sql_tables::DbConnection,
+    types::{
+        AttributeName, AttributeSchema, AttributeValue, Comparison, DateTime, GroupDetails,
+        GroupId, JpegPhoto, OrderedValue, User, UserAndGroups, UserId, Uuid,
+    },
+    user_id_policy::check_user_id,
 };
+use crate::infra::configuration::Configuration;
 use async_trait::async_trait;
 use sea_orm::{
     entity::IntoActiveValue,
-    sea_query::{Cond, Expr, IntoCondition, SimpleExpr},
-    ActiveModelTrait, ActiveValue, ColumnTrait, EntityTrait, ModelTrait, QueryFilter, QueryOrder,
-    QuerySelect, QueryTrait, Set,
+    sea_query::{Cond, Expr, Func, IntoCondition, LikeExpr, SimpleExpr},
+    ActiveModelTrait, ActiveValue, ColumnTrait, DatabaseTransaction, EntityTrait, FromQueryResult,
+    IdenStatic, ModelTrait, Order, PaginatorTrait, QueryFilter, QueryOrder, QuerySelect,
+    QueryTrait, Select, Set, TransactionTrait, Value,
 };
 use sea_query::{Alias, IntoColumnRef};
-use std::collections::HashSet;
-use tracing::{debug, instrument};
+use std::collections::{HashMap, HashSet};
+use tracing::{debug, instrument, warn};
 
 fn get_user_filter_expr(filter: UserRequestFilter) -> Cond {
     use UserRequestFilter::*;
@@ -37,6 +54,15 @@ fn get_user_filter_expr(filter: UserRequestFilter) -> Cond {
         Or(fs) => get_repeated_filter(fs, Cond::any(), false),
         Not(f) => get_user_filter_expr(*f).not(),
         UserId(user_id) => ColumnTrait::eq(&UserColumn::UserId, user_id).into_condition(),
+        Equality(UserColumn::Uuid, uuid) => match Uuid::try_from(uuid.as_str()) {
+            Ok(uuid) => ColumnTrait::eq(&UserColumn::Uuid, uuid).into_condition(),
+            // Not a valid UUID, it cannot match any user.
+            Err(_) => SimpleExpr::Value(false.into()).into_condition(),
+        },
+        Equality(UserColumn::UidNumber, uid_number) => match uid_number.parse::<i32>() {
+            Ok(uid_number) => ColumnTrait::eq(&UserColumn::UidNumber, uid_number).into_condition(),
+            Err(_) => SimpleExpr::Value(false.into()).into_condition(),
+        },
         Equality(s1, s2) => {
             if s1 == UserColumn::UserId {
                 panic!("User id should be wrapped")
@@ -44,15 +70,144 @@ fn get_user_filter_expr(filter: UserRequestFilter) -> Cond {
                 ColumnTrait::eq(&s1, s2).into_condition()
             }
         }
+        EqualityIgnoreCase(column @ (UserColumn::Uuid | UserColumn::UidNumber), value) => {
+            get_user_filter_expr(Equality(column, value))
+        }
+        EqualityIgnoreCase(column, value) => {
+            Expr::expr(Func::lower(Expr::col((model::User, column))))
+                .eq(value.to_lowercase())
+                .into_condition()
+        }
+        Present(column) => column.is_not_null().into_condition(),
+        Ordering(column, comparison, bound) => {
+            let bound = match bound {
+                OrderedValue::Integer(bound) => Value::from(bound),
+                OrderedValue::DateTime(bound) => Value::from(bound),
+            };
+            match comparison {
+                Comparison::GreaterOrEqual => column.gte(bound),
+                Comparison::LessOrEqual => column.lte(bound),
+            }
+            .into_condition()
+        }
+        // Replaced by `resolve_attribute_filters`.
+        AttributeOrdering(..) => SimpleExpr::Value(false.into()).into_condition(),
+        // Kept by `resolve_attribute_filters` for the indexed attributes only, with the value in
+        // its stored form: the lookup uses the index on (attribute_name, value).
+        AttributeEquality(name, value) => UserColumn::UserId
+            .in_subquery(
+                model::UserAttributes::find()
+                    .select_only()
+                    .column(UserAttributesColumn::UserId)
+                    .filter(UserAttributesColumn::AttributeName.eq(name))
+                    .filter(UserAttributesColumn::Value.eq(value.into_bytes()))
+                    .into_query(),
+            )
+            .into_condition(),
+        UserIds(user_ids) => UserColumn::UserId.is_in(user_ids).into_condition(),
         MemberOf(group) => Expr::col((group_table, GroupColumn::DisplayName))
             .eq(group)
             .into_condition(),
         MemberOfId(group_id) => Expr::col((group_table, GroupColumn::GroupId))
             .eq(group_id)
             .into_condition(),
+        IncludeDeleted => SimpleExpr::Value(true.into()).into_condition(),
+    }
+}
+
+/// A filter on a custom attribute, that depends on its schema.
+enum AttributeFilter {
+    Ordering(Comparison, String),
+    Equality(String),
+}
+
+/// The filters on custom attributes.
+fn get_attribute_filters(
+    filter: &UserRequestFilter,
+    attribute_filters: &mut Vec<(AttributeName, AttributeFilter)>,
+) {
+    match filter {
+        UserRequestFilter::And(fs) | UserRequestFilter::Or(fs) => fs
+            .iter()
+            .for_each(|f| get_attribute_filters(f, attribute_filters)),
+        UserRequestFilter::Not(f) => get_attribute_filters(f, attribute_filters),
+        UserRequestFilter::AttributeOrdering(name, comparison, bound) => attribute_filters.push((
+            name.clone(),
+            AttributeFilter::Ordering(*comparison, bound.clone()),
+        )),
+        UserRequestFilter::AttributeEquality(name, value) => {
+            attribute_filters.push((name.clone(), AttributeFilter::Equality(value.clone())))
+        }
+        _ => (),
+    }
+}
+
+/// Replaces the filters on custom attributes with their resolved form, in the same order as
+/// `get_attribute_filters`.
+fn replace_attribute_filters(
+    filter: UserRequestFilter,
+    resolved: &mut impl Iterator<Item = UserRequestFilter>,
+) -> UserRequestFilter {
+    use UserRequestFilter::*;
+    match filter {
+        And(fs) => And(fs
+            .into_iter()
+            .map(|f| replace_attribute_filters(f, resolved))
+            .collect()),
+        Or(fs) => Or(fs
+            .into_iter()
+            .map(|f| replace_attribute_filters(f, resolved))
+            .collect()),
+        Not(f) => Not(Box::new(replace_attribute_filters(*f, resolved))),
+        AttributeOrdering(..) | AttributeEquality(..) => {
+            resolved.next().unwrap_or_else(|| UserIds(Vec::new()))
+        }
+        f => f,
+    }
+}
+
+fn includes_deleted_users(filter: &UserRequestFilter) -> bool {
+    match filter {
+        UserRequestFilter::IncludeDeleted => true,
+        UserRequestFilter::And(fs) => fs.iter().any(includes_deleted_users),
+        _ => false,
+    }
+}
+
+/// The unique placeholder that replaces the email of a soft-deleted user.
+fn deleted_user_email(uuid: &Uuid) -> String {
+    format!("{}@deleted.invalid", uuid.as_str())
+}
+
+/// Hard-deletes the users that were soft-deleted before `deleted_before`. Returns the number of
+/// purged users.
+#[instrument(skip_all, level = "debug", ret, err)]
+pub async fn purge_deleted_users(pool: &DbConnection, deleted_before: DateTime) -> Result<u64> {
+    debug!(?deleted_before);
+    Ok(model::User::delete_many()
+        .filter(UserColumn::DeletedAt.lt(deleted_before))
+        .exec(pool)
+        .await?
+        .rows_affected)
+}
+// Emails are stored normalized, e.g. lowercase when they are case-insensitive, so the filters
+// need to match.
+fn normalize_email_filters(config: &Configuration, filter: UserRequestFilter) -> UserRequestFilter {
+    use UserRequestFilter::*;
+    let normalize = |f: UserRequestFilter| normalize_email_filters(config, f);
+    match filter {
+        And(fs) => And(fs.into_iter().map(normalize).collect()),
+        Or(fs) => Or(fs.into_iter().map(normalize).collect()),
+        Not(f) => Not(Box::new(normalize(*f))),
+        Equality(UserColumn::Email, email) => Equality(
+            UserColumn::Email,
+            email_policy::normalize_email(config, email),
+        ),
+        f => f,
     }
 }
-fn to_value(opt_name: &Option<String>) -> ActiveValue<Option<String>> {
+
+pub(crate) fn to_value(opt_name: &Option<String>) -> ActiveValue<Option<String>> {
     match opt_name {
         None => ActiveValue::NotSet,
         Some(name) => ActiveValue::Set(if name.is_empty() {
@@ -63,37 +218,266 @@ fn to_value(opt_name: &Option<String>) -> ActiveValue<Option<String>> {
     }
 }
 
-#[async_trait]
-impl UserBackendHandler for SqlBackendHandler {
-    #[instrument(skip_all, level = "debug", ret, err)]
-    async fn list_users(
+/// The user attributes recorded in the audit log. The password has its own entries.
+const AUDITED_USER_COLUMNS: [UserColumn; 6] = [
+    UserColumn::Email,
+    UserColumn::DisplayName,
+    UserColumn::FirstName,
+    UserColumn::LastName,
+    UserColumn::Avatar,
+    UserColumn::MustChangePassword,
+];
+
+/// The bytes that are hashed in the audit log, `None` for NULL.
+fn get_audit_bytes(value: Value) -> Option<Vec<u8>> {
+    match value {
+        Value::String(s) => s.map(|s| s.into_bytes()),
+        Value::Bytes(b) => b.map(|b| *b),
+        Value::Bool(b) => b.map(|b| b.to_string().into_bytes()),
+        other => Some(format!("{:?}", other).into_bytes()),
+    }
+}
+
+/// The audited attributes that `new` changes, compared to `old` (`None` for a new user).
+pub(crate) fn get_user_changes(
+    user_id: &UserId,
+    old: Option<&model::users::Model>,
+    new: &model::users::ActiveModel,
+) -> Vec<AuditChange> {
+    AUDITED_USER_COLUMNS
+        .iter()
+        .filter_map(|column| {
+            let new_value = match new.get(*column) {
+                ActiveValue::Set(value) | ActiveValue::Unchanged(value) => get_audit_bytes(value),
+                ActiveValue::NotSet => return None,
+            };
+            let old_value = old.and_then(|old| get_audit_bytes(old.get(*column)));
+            (old_value != new_value)
+                .then(|| AuditChange::user(user_id, column.as_str()).values(old_value, new_value))
+        })
+        .collect()
+}
+
+/// The columns that `search_users` looks into.
+const SEARCHABLE_USER_COLUMNS: [UserColumn; 4] = [
+    UserColumn::DisplayName,
+    UserColumn::FirstName,
+    UserColumn::LastName,
+    UserColumn::Email,
+];
+
+fn get_searchable_value(user: &User, column: UserColumn) -> Option<&str> {
+    match column {
+        UserColumn::DisplayName => user.display_name.as_deref(),
+        UserColumn::FirstName => user.first_name.as_deref(),
+        UserColumn::LastName => user.last_name.as_deref(),
+        UserColumn::Email => Some(&user.email),
+        _ => None,
+    }
+}
+
+/// How well the value matches the (lowercase) query, lower is better: the whole value, then the
+/// start of the value, the start of a word, and anywhere in the value.
+fn match_rank(value: &str, query: &str) -> Option<u8> {
+    let value = value.to_lowercase();
+    if value == query {
+        Some(0)
+    } else if value.starts_with(query) {
+        Some(1)
+    } else if value
+        .split(|c: char| !c.is_alphanumeric())
+        .any(|word| word.starts_with(query))
+    {
+        Some(2)
+    } else if value.contains(query) {
+        Some(3)
+    } else {
+        None
+    }
+}
+
+pub(crate) fn escape_like(query: &str) -> String {
+    query
+        .replace('\\', "\\\\")
+        .replace('%', "\\%")
+        .replace('_', "\\_")
+}
+
+impl SqlBackendHandler {
+    pub(crate) fn normalize_email(&self, email: String) -> String {
+        email_policy::normalize_email(&self.config, email)
+    }
+
+    /// The normalized email, if the policy accepts it.
+    fn check_email(&self, email: String) -> Result<String> {
+        let email = self.normalize_email(email);
+        email_policy::check_email(&self.config.email_policy, &email)?;
+        Ok(email)
+    }
+
+    fn limit_avatar(&self, avatar: Option<JpegPhoto>) -> Result<Option<JpegPhoto>> {
+        Ok(avatar
+            .map(|avatar| {
+                avatar::limit_avatar(
+                    avatar,
+                    self.config.avatar_max_dimension,
+                    self.config.avatar_max_size_kb as usize * 1024,
+                )
+            })
+            .transpose()?)
+    }
+
+    /// The custom attributes are stored as bytes: the filters on them are resolved here, according
+    /// to the type of the attribute. The equalities on the indexed attributes are left to the
+    /// query, to use their index; the other filters are replaced with the matching users, from
+    /// the values of the attribute. An unknown attribute matches no user.
+    async fn resolve_attribute_filters(
+        &self,
+        filters: Option<UserRequestFilter>,
+    ) -> Result<Option<UserRequestFilter>> {
+        let filters = match filters {
+            None => return Ok(None),
+            Some(filters) => filters,
+        };
+        let mut attribute_filters = Vec::new();
+        get_attribute_filters(&filters, &mut attribute_filters);
+        if attribute_filters.is_empty() {
+            return Ok(Some(filters));
+        }
+        let mut resolved = Vec::with_capacity(attribute_filters.len());
+        for (name, filter) in attribute_filters {
+            let schema = match model::UserAttributeSchema::find_by_id(name.clone())
+                .one(self.read_pool())
+                .await?
+            {
+                None => {
+                    warn!(
+                        r#"Ignoring unknown user attribute "{}" in filter.\n\
+                              To disable this warning, add it to "ignored_user_attributes" in the config"#,
+                        name
+                    );
+                    resolved.push(UserRequestFilter::UserIds(Vec::new()));
+                    continue;
+                }
+                Some(schema) => AttributeSchema::from(schema),
+            };
+            let matches: Box<dyn Fn(&AttributeValue) -> bool + Send> = match filter {
+                AttributeFilter::Ordering(comparison, bound) => {
+                    let bound =
+                        OrderedValue::parse(schema.attribute_type, &bound).map_err(|e| {
+                            DomainError::ValidationError(format!(
+                                "Cannot compare the attribute '{}': {}",
+                                name, e
+                            ))
+                        })?;
+                    Box::new(move |value| bound.matches(comparison, value))
+                }
+                AttributeFilter::Equality(value) => {
+                    let value =
+                        match AttributeValue::from_bytes(schema.attribute_type, value.into_bytes())
+                        {
+                            Ok(value) => value,
+                            // Not a valid value of the attribute, it cannot match any user.
+                            Err(_) => {
+                                resolved.push(UserRequestFilter::UserIds(Vec::new()));
+                                continue;
+                            }
+                        };
+                    if schema.is_indexed {
+                        if let Ok(stored) = String::from_utf8(value.to_bytes()) {
+                            resolved.push(UserRequestFilter::AttributeEquality(name, stored));
+                            continue;
+                        }
+                    }
+                    Box::new(move |other| other == &value)
+                }
+            };
+            let mut user_ids = model::UserAttributes::find()
+                .filter(UserAttributesColumn::AttributeName.eq(name))
+                .all(self.read_pool())
+                .await?
+                .into_iter()
+                .filter_map(|row| {
+                    AttributeValue::from_bytes(schema.attribute_type, row.value)
+                        .ok()
+                        .filter(|value| matches(value))
+                        .map(|_| row.user_id)
+                })
+                .collect::<Vec<_>>();
+            // The list attributes have several values per user.
+            user_ids.sort();
+            user_ids.dedup();
+            resolved.push(UserRequestFilter::UserIds(user_ids));
+        }
+        Ok(Some(replace_attribute_filters(
+            filters,
+            &mut resolved.into_iter(),
+        )))
+    }
+
+    /// The users matching the filters, sorted by user ID. The soft-deleted users are excluded
+    /// unless the filters include them. The filters on custom attributes must be resolved
+    /// first.
+    fn get_users_query(&self, filters: Option<UserRequestFilter>) -> Select<model::User> {
+        self.get_sorted_users_query(filters, &[])
+    }
+
+    /// The users are sorted by the keys, then by ID.
+    fn get_sorted_users_query(
         &self,
         filters: Option<UserRequestFilter>,
+        sort: &[UserSortKey],
+    ) -> Select<model::User> {
+        let filters = filters.map(|f| normalize_email_filters(&self.config, f));
+        let include_deleted = filters
+            .as_ref()
+            .map(includes_deleted_users)
+            .unwrap_or(false);
+        let query = model::User::find().filter(
+            filters
+                .map(|f| {
+                    UserColumn::UserId
+                        .in_subquery(
+                            model::User::find()
+                                .find_also_linked(model::memberships::UserToGroup)
+                                .select_only()
+                                .column(UserColumn::UserId)
+                                .filter(get_user_filter_expr(f))
+                                .into_query(),
+                        )
+                        .into_condition()
+                })
+                .unwrap_or_else(|| SimpleExpr::Value(true.into()).into_condition()),
+        );
+        let query = sort
+            .iter()
+            .fold(query, |query, key| {
+                query.order_by(
+                    key.column,
+                    if key.descending {
+                        Order::Desc
+                    } else {
+                        Order::Asc
+                    },
+                )
+            })
+            .order_by_asc(UserColumn::UserId);
+        if include_deleted {
+            query
+        } else {
+            query.filter(UserColumn::DeletedAt.is_null())
+        }
+    }
+
+    async fn fetch_users(
+        &self,
+        query: Select<model::User>,
         get_groups: bool,
     ) -> Result<Vec<UserAndGroups>> {
-        debug!(?filters);
-        let query = model::User::find()
-            .filter(
-                filters
-                    .map(|f| {
-                        UserColumn::UserId
-                            .in_subquery(
-                                model::User::find()
-                                    .find_also_linked(model::memberships::UserToGroup)
-                                    .select_only()
-                                    .column(UserColumn::UserId)
-                                    .filter(get_user_filter_expr(f))
-                                    .into_query(),
-                            )
-                            .into_condition()
-                    })
-                    .unwrap_or_else(|| SimpleExpr::Value(true.into()).into_condition()),
-            )
-            .order_by_asc(UserColumn::UserId);
         if !get_groups {
             Ok(query
                 .into_model::<User>()
-                .all(&self.sql_pool)
+                .all(self.read_pool())
                 .await?
                 .into_iter()
                 .map(|u| UserAndGroups {
@@ -108,10 +492,10 @@ impl UserBackendHandler for SqlBackendHandler {
                 .order_by_asc(SimpleExpr::Column(
                     (Alias::new("r1"), GroupColumn::GroupId).into_column_ref(),
                 ))
-                .all(&self.sql_pool)
+                .all(self.read_pool())
                 .await?;
             use itertools::Itertools;
-            Ok(results
+            let mut users: Vec<_> = results
                 .iter()
                 .group_by(|(u, _)| u)
                 .into_iter()
@@ -126,14 +510,255 @@ impl UserBackendHandler for SqlBackendHandler {
                         groups: Some(groups),
                     }
                 })
-                .collect())
+                .collect();
+            self.add_inherited_groups(&mut users).await?;
+            Ok(users)
         }
     }
 
+    /// Adds the groups that contain the users' groups, sorted by group ID.
+    async fn add_inherited_groups(&self, users: &mut [UserAndGroups]) -> Result<()> {
+        let nesting = GroupNesting::load(self.read_pool()).await?;
+        if nesting.is_empty() {
+            return Ok(());
+        }
+        let max_depth = self.config.max_group_nesting_depth;
+        let supergroup_ids = users
+            .iter()
+            .flat_map(|u| u.groups.iter().flatten())
+            .flat_map(|g| nesting.supergroups(g.group_id, max_depth))
+            .collect::<HashSet<_>>();
+        let supergroups = model::Group::find()
+            .filter(GroupColumn::GroupId.is_in(supergroup_ids))
+            .into_model::<GroupDetails>()
+            .all(self.read_pool())
+            .await?
+            .into_iter()
+            .map(|g| (g.group_id, g))
+            .collect::<HashMap<_, _>>();
+        for groups in users.iter_mut().filter_map(|u| u.groups.as_mut()) {
+            let direct_groups = groups.iter().map(|g| g.group_id).collect::<HashSet<_>>();
+            let inherited_groups = direct_groups
+                .iter()
+                .flat_map(|g| nesting.supergroups(*g, max_depth))
+                .filter(|g| !direct_groups.contains(g))
+                .collect::<HashSet<_>>();
+            groups.extend(
+                inherited_groups
+                    .iter()
+                    .filter_map(|g| supergroups.get(g))
+                    .cloned(),
+            );
+            groups.sort_by_key(|g| g.group_id.0);
+        }
+        Ok(())
+    }
+}
+
+/// What adding (or removing) each user to the group would do, in the order of `user_ids`, without
+/// the duplicates. The users and the group are checked here rather than by the foreign keys of
+/// the memberships, so that the errors say which user or group is missing.
+async fn get_membership_changes(
+    transaction: &DatabaseTransaction,
+    group_id: GroupId,
+    user_ids: &[UserId],
+    adding: bool,
+) -> Result<Vec<MembershipChange>> {
+    if model::Group::find_by_id(group_id)
+        .one(transaction)
+        .await?
+        .is_none()
+    {
+        return Err(DomainError::EntityNotFound(format!(
+            "No such group: '{:?}'",
+            group_id
+        )));
+    }
+    #[derive(FromQueryResult)]
+    struct UserIdRow {
+        user_id: UserId,
+    }
+    let mut known_users = model::User::find()
+        .select_only()
+        .column(UserColumn::UserId)
+        .filter(UserColumn::UserId.is_in(user_ids.iter().cloned()));
+    if adding {
+        // The soft-deleted users can't get new groups.
+        known_users = known_users.filter(UserColumn::DeletedAt.is_null());
+    }
+    let known_users = known_users
+        .into_model::<UserIdRow>()
+        .all(transaction)
+        .await?
+        .into_iter()
+        .map(|row| row.user_id)
+        .collect::<HashSet<_>>();
+    let members = model::Membership::find()
+        .filter(MembershipColumn::GroupId.eq(group_id))
+        .filter(MembershipColumn::UserId.is_in(user_ids.iter().cloned()))
+        .all(transaction)
+        .await?
+        .into_iter()
+        .map(|membership| membership.user_id)
+        .collect::<HashSet<_>>();
+    let mut seen = HashSet::new();
+    Ok(user_ids
+        .iter()
+        .filter(|user_id| seen.insert(*user_id))
+        .map(|user_id| MembershipChange {
+            user_id: user_id.clone(),
+            status: if !known_users.contains(user_id) {
+                MembershipChangeStatus::NoSuchUser
+            } else if members.contains(user_id) == adding {
+                MembershipChangeStatus::Unchanged
+            } else {
+                MembershipChangeStatus::Changed
+            },
+        })
+        .collect())
+}
+
+fn check_unknown_users(changes: &[MembershipChange], all_or_nothing: bool) -> Result<()> {
+    let unknown_users = changes
+        .iter()
+        .filter(|change| change.status == MembershipChangeStatus::NoSuchUser)
+        .map(|change| change.user_id.as_str())
+        .collect::<Vec<_>>();
+    if all_or_nothing && !unknown_users.is_empty() {
+        return Err(DomainError::EntityNotFound(format!(
+            "No such users: {}",
+            unknown_users.join(", ")
+        )));
+    }
+    Ok(())
+}
+
+#[async_trait]
+impl UserBackendHandler for SqlBackendHandler {
+    #[instrument(skip_all, level = "debug", ret, err)]
+    async fn list_users(
+        &self,
+        filters: Option<UserRequestFilter>,
+        get_groups: bool,
+    ) -> Result<Vec<UserAndGroups>> {
+        debug!(?filters);
+        let filters = self.resolve_attribute_filters(filters).await?;
+        self.fetch_users(self.get_users_query(filters), get_groups)
+            .await
+    }
+
+    #[instrument(skip_all, level = "debug", ret, err)]
+    async fn list_users_page(
+        &self,
+        filters: Option<UserRequestFilter>,
+        get_groups: bool,
+        page: Pagination,
+    ) -> Result<Page<UserAndGroups>> {
+        debug!(?filters, ?page);
+        let filters = self.resolve_attribute_filters(filters).await?;
+        let mut query = self.get_users_query(filters);
+        if let Some(after) = get_page_start(&page)? {
+            query = query.filter(UserColumn::UserId.gt(UserId::new(&after)));
+        }
+        #[derive(FromQueryResult)]
+        struct PageUser {
+            user_id: UserId,
+        }
+        // Fetch one more, to know if there is a next page.
+        let mut user_ids = query
+            .clone()
+            .select_only()
+            .column(UserColumn::UserId)
+            .limit(page.page_size as u64 + 1)
+            .into_model::<PageUser>()
+            .all(self.read_pool())
+            .await?;
+        let has_next_page = user_ids.len() > page.page_size;
+        user_ids.truncate(page.page_size);
+        let last_user_id = match user_ids.pop() {
+            None => {
+                return Ok(Page {
+                    items: Vec::new(),
+                    next_cursor: None,
+                })
+            }
+            Some(user) => user.user_id,
+        };
+        let items = self
+            .fetch_users(
+                query.filter(UserColumn::UserId.lte(last_user_id.clone())),
+                get_groups,
+            )
+            .await?;
+        Ok(Page {
+            items,
+            next_cursor: has_next_page.then(|| encode_cursor(last_user_id.as_str())),
+        })
+    }
+
+    #[instrument(skip_all, level = "debug", ret, err)]
+    async fn list_users_sorted(
+        &self,
+        filters: Option<UserRequestFilter>,
+        get_groups: bool,
+        sort: Vec<UserSortKey>,
+        offset: usize,
+        limit: Option<usize>,
+    ) -> Result<Vec<UserAndGroups>> {
+        debug!(?filters, ?sort, offset, ?limit);
+        let filters = self.resolve_attribute_filters(filters).await?;
+        let query = self.get_sorted_users_query(filters, &sort);
+        let limit = match limit {
+            // SQL has no offset without a limit.
+            None => {
+                return Ok(self
+                    .fetch_users(query, get_groups)
+                    .await?
+                    .into_iter()
+                    .skip(offset)
+                    .collect())
+            }
+            Some(limit) => limit,
+        };
+        #[derive(FromQueryResult)]
+        struct PageUser {
+            user_id: UserId,
+        }
+        let user_ids = query
+            .clone()
+            .select_only()
+            .column(UserColumn::UserId)
+            .offset(offset as u64)
+            .limit(limit as u64)
+            .into_model::<PageUser>()
+            .all(self.read_pool())
+            .await?;
+        if user_ids.is_empty() {
+            return Ok(Vec::new());
+        }
+        self.fetch_users(
+            query.filter(UserColumn::UserId.is_in(user_ids.into_iter().map(|u| u.user_id))),
+            get_groups,
+        )
+        .await
+    }
+
+    #[instrument(skip_all, level = "debug", ret, err)]
+    async fn count_users(&self, filters: Option<UserRequestFilter>) -> Result<usize> {
+        debug!(?filters);
+        let filters = self.resolve_attribute_filters(filters).await?;
+        let count = self
+            .get_users_query(filters)
+            .count(self.read_pool())
+            .await?;
+        Ok(usize::try_from(count)?)
+    }
+
     #[instrument(skip_all, level = "debug", ret)]
     async fn get_user_details(&self, user_id: &UserId) -> Result<User> {
         debug!(?user_id);
         model::User::find_by_id(user_id.to_owned())
+            .filter(UserColumn::DeletedAt.is_null())
             .into_model::<User>()
             .one(&self.sql_pool)
             .await?
@@ -144,6 +769,7 @@ impl UserBackendHandler for SqlBackendHandler {
     async fn get_user_groups(&self, user_id: &UserId) -> Result<HashSet<GroupDetails>> {
         debug!(?user_id);
         let user = model::User::find_by_id(user_id.to_owned())
+            .filter(UserColumn::DeletedAt.is_null())
             .one(&self.sql_pool)
             .await?
             .ok_or_else(|| DomainError::EntityNotFound(user_id.to_string()))?;
@@ -155,54 +781,233 @@ impl UserBackendHandler for SqlBackendHandler {
         ))
     }
 
+    #[instrument(skip_all, level = "debug", err)]
+    async fn get_users_groups(
+        &self,
+        user_ids: &[UserId],
+    ) -> Result<HashMap<UserId, HashSet<GroupDetails>>> {
+        debug!(?user_ids);
+        let mut groups = HashMap::<UserId, HashSet<GroupDetails>>::new();
+        for (membership, group) in model::Membership::find()
+            .inner_join(model::User)
+            .filter(MembershipColumn::UserId.is_in(user_ids.iter().cloned()))
+            .filter(UserColumn::DeletedAt.is_null())
+            .find_also_related(model::Group)
+            .all(self.read_pool())
+            .await?
+        {
+            if let Some(group) = group {
+                groups
+                    .entry(membership.user_id)
+                    .or_default()
+                    .insert(group.into());
+            }
+        }
+        Ok(groups)
+    }
+
     #[instrument(skip_all, level = "debug", err)]
     async fn create_user(&self, request: CreateUserRequest) -> Result<()> {
         debug!(user_id = ?request.user_id);
-        let now = chrono::Utc::now();
-        let uuid = Uuid::from_name_and_date(request.user_id.as_str(), &now);
-        let new_user = model::users::ActiveModel {
+        check_user_id(&self.config.user_id_policy, &request.user_id)
+            .map_err(DomainError::ValidationError)?;
+        let now = clock::creation_date();
+        let creation_date = request.creation_date.unwrap_or(now);
+        let uuid = Uuid::from_name_and_date(request.user_id.as_str(), &creation_date);
+        let new_user_id = request.user_id.clone();
+        let mut new_user = model::users::ActiveModel {
+            user_id_display: Set(Some(request.user_id.as_display_str().to_owned())),
             user_id: Set(request.user_id),
-            email: Set(request.email),
+            email: Set(self.check_email(request.email)?),
             display_name: to_value(&request.display_name),
             first_name: to_value(&request.first_name),
             last_name: to_value(&request.last_name),
-            avatar: request.avatar.into_active_value(),
-            creation_date: ActiveValue::Set(now),
+            avatar: self.limit_avatar(request.avatar)?.into_active_value(),
+            creation_date: ActiveValue::Set(creation_date),
             uuid: ActiveValue::Set(uuid),
+            modified_at: ActiveValue::Set(now),
             ..Default::default()
         };
-        new_user.insert(&self.sql_pool).await?;
+        let mut changes = vec![AuditChange::user(&new_user_id, audit::CREATED)];
+        changes.extend(get_user_changes(&new_user_id, None, &new_user));
+        let transaction = self.sql_pool.begin().await?;
+        // A soft-deleted user with the same ID can't be restored anymore.
+        model::User::delete_many()
+            .filter(ColumnTrait::eq(&UserColumn::UserId, &new_user_id))
+            .filter(UserColumn::DeletedAt.is_not_null())
+            .exec(&transaction)
+            .await?;
+        new_user.uid_number = ActiveValue::Set(Some(
+            allocate_posix_number(&transaction, PosixNumber::Uid, &self.config.posix_options)
+                .await?,
+        ));
+        new_user.insert(&transaction).await?;
+        changes.extend(
+            self.apply_group_rules(&transaction, Some(std::slice::from_ref(&new_user_id)))
+                .await?,
+        );
+        let events = self.write_audit_log(&transaction, changes).await?;
+        transaction.commit().await?;
+        events.publish();
         Ok(())
     }
 
     #[instrument(skip_all, level = "debug", err)]
     async fn update_user(&self, request: UpdateUserRequest) -> Result<()> {
         debug!(user_id = ?request.user_id);
-        let update_user = model::users::ActiveModel {
+        let user_id = request.user_id.clone();
+        let mut update_user = model::users::ActiveModel {
             user_id: ActiveValue::Set(request.user_id),
-            email: request.email.map(ActiveValue::Set).unwrap_or_default(),
+            email: request
+                .email
+                .map(|email| self.check_email(email))
+                .transpose()?
+                .map(ActiveValue::Set)
+                .unwrap_or_default(),
             display_name: to_value(&request.display_name),
             first_name: to_value(&request.first_name),
             last_name: to_value(&request.last_name),
-            avatar: request.avatar.into_active_value(),
+            avatar: self.limit_avatar(request.avatar)?.into_active_value(),
+            must_change_password: request
+                .must_change_password
+                .map_or(ActiveValue::NotSet, ActiveValue::Set),
             ..Default::default()
         };
-        update_user.update(&self.sql_pool).await?;
+        let transaction = self.sql_pool.begin().await?;
+        let old_user = model::User::find_by_id(user_id.clone())
+            .one(&transaction)
+            .await?;
+        let mut changes = get_user_changes(&user_id, old_user.as_ref(), &update_user);
+        if let (Some(old_user), ActiveValue::Set(email)) = (&old_user, &update_user.email) {
+            // The new email has to be verified again.
+            if &old_user.email != email {
+                update_user.email_verified = ActiveValue::Set(false);
+            }
+        }
+        update_user.update(&transaction).await?;
+        changes.extend(
+            self.apply_group_rules(&transaction, Some(std::slice::from_ref(&user_id)))
+                .await?,
+        );
+        let events = self.write_audit_log(&transaction, changes).await?;
+        transaction.commit().await?;
+        events.publish();
         Ok(())
     }
 
     #[instrument(skip_all, level = "debug", err)]
     async fn delete_user(&self, user_id: &UserId) -> Result<()> {
         debug!(?user_id);
-        let res = model::User::delete_by_id(user_id.clone())
-            .exec(&self.sql_pool)
+        let transaction = self.sql_pool.begin().await?;
+        let user = model::User::find_by_id(user_id.clone())
+            .filter(UserColumn::DeletedAt.is_null())
+            .one(&transaction)
+            .await?
+            .ok_or_else(|| DomainError::EntityNotFound(format!("No such user: '{}'", user_id)))?;
+        if self.config.soft_delete_users {
+            // The email is replaced by a placeholder, so that a new user can take it.
+            model::User::update_many()
+                .col_expr(UserColumn::DeletedAt, Expr::value(chrono::Utc::now()))
+                .col_expr(
+                    UserColumn::Email,
+                    Expr::value(deleted_user_email(&user.uuid)),
+                )
+                .filter(ColumnTrait::eq(&UserColumn::UserId, user_id))
+                .exec(&transaction)
+                .await?;
+        } else {
+            model::User::delete_by_id(user_id.clone())
+                .exec(&transaction)
+                .await?;
+        }
+        record_tombstone(&transaction, AuditTarget::User(user_id.clone()), user.uuid).await?;
+        let events = self
+            .write_audit_log(
+                &transaction,
+                vec![AuditChange::user(user_id, audit::DELETED)],
+            )
             .await?;
-        if res.rows_affected == 0 {
-            return Err(DomainError::EntityNotFound(format!(
-                "No such user: '{}'",
+        transaction.commit().await?;
+        events.publish();
+        Ok(())
+    }
+
+    #[instrument(skip_all, level = "debug", err)]
+    async fn rename_user(&self, user_id: &UserId, new_user_id: &UserId) -> Result<()> {
+        debug!(?user_id, ?new_user_id);
+        check_user_id(&self.config.user_id_policy, new_user_id)
+            .map_err(DomainError::ValidationError)?;
+        if user_id == new_user_id {
+            return Err(DomainError::ValidationError(format!(
+                "The user is already named '{}'",
                 user_id
             )));
         }
+        let transaction = self.sql_pool.begin().await?;
+        model::User::find_by_id(user_id.clone())
+            .filter(UserColumn::DeletedAt.is_null())
+            .one(&transaction)
+            .await?
+            .ok_or_else(|| DomainError::EntityNotFound(format!("No such user: '{}'", user_id)))?;
+        match model::User::find_by_id(new_user_id.clone())
+            .one(&transaction)
+            .await?
+        {
+            Some(user) if user.deleted_at.is_none() => {
+                return Err(DomainError::ValidationError(format!(
+                    "User '{}' already exists",
+                    new_user_id
+                )))
+            }
+            // Like for a creation, a soft-deleted user with the same ID can't be restored anymore.
+            Some(_) => {
+                model::User::delete_by_id(new_user_id.clone())
+                    .exec(&transaction)
+                    .await?;
+            }
+            None => {}
+        }
+        model::User::update_many()
+            .col_expr(UserColumn::UserId, Expr::value(new_user_id))
+            .col_expr(
+                UserColumn::UserIdDisplay,
+                Expr::value(new_user_id.as_display_str()),
+            )
+            .filter(ColumnTrait::eq(&UserColumn::UserId, user_id))
+            .exec(&transaction)
+            .await?;
+        // The foreign keys rename the user in the other tables (ON UPDATE CASCADE), on all the
+        // backends. Unless they are not enforced, e.g. on a SQLite DB opened without them: the
+        // rows of the old ID would be left behind, so refuse the rename.
+        let left_behind = model::Membership::find()
+            .filter(MembershipColumn::UserId.eq(user_id))
+            .count(&transaction)
+            .await?
+            + model::UserAttributes::find()
+                .filter(UserAttributesColumn::UserId.eq(user_id))
+                .count(&transaction)
+                .await?;
+        if left_behind > 0 {
+            return Err(DomainError::InternalError(format!(
+                "The foreign keys didn't rename the memberships and attributes of '{}', are they \
+                 enforced by the database?",
+                user_id
+            )));
+        }
+        let mut changes = vec![
+            AuditChange::user(new_user_id, UserColumn::UserId.as_str()).values(
+                Some(user_id.as_str().as_bytes().to_vec()),
+                Some(new_user_id.as_str().as_bytes().to_vec()),
+            ),
+        ];
+        // The rules can match on the user ID.
+        changes.extend(
+            self.apply_group_rules(&transaction, Some(std::slice::from_ref(new_user_id)))
+                .await?,
+        );
+        let events = self.write_audit_log(&transaction, changes).await?;
+        transaction.commit().await?;
+        events.publish();
         Ok(())
     }
 
@@ -212,16 +1017,28 @@ impl UserBackendHandler for SqlBackendHandler {
         let new_membership = model::memberships::ActiveModel {
             user_id: ActiveValue::Set(user_id.clone()),
             group_id: ActiveValue::Set(group_id),
+            assigned_by_rule: ActiveValue::Set(false),
         };
-        new_membership.insert(&self.sql_pool).await?;
+        let transaction = self.sql_pool.begin().await?;
+        new_membership.insert(&transaction).await?;
+        let events = self
+            .write_audit_log(
+                &transaction,
+                vec![AuditChange::membership(user_id, group_id)
+                    .values(None, Some(group_id.0.to_string().into_bytes()))],
+            )
+            .await?;
+        transaction.commit().await?;
+        events.publish();
         Ok(())
     }
 
     #[instrument(skip_all, level = "debug", err)]
     async fn remove_user_from_group(&self, user_id: &UserId, group_id: GroupId) -> Result<()> {
         debug!(?user_id, ?group_id);
+        let transaction = self.sql_pool.begin().await?;
         let res = model::Membership::delete_by_id((user_id.clone(), group_id))
-            .exec(&self.sql_pool)
+            .exec(&transaction)
             .await?;
         if res.rows_affected == 0 {
             return Err(DomainError::EntityNotFound(format!(
@@ -229,17 +1046,205 @@ impl UserBackendHandler for SqlBackendHandler {
                 user_id, group_id
             )));
         }
+        let events = self
+            .write_audit_log(
+                &transaction,
+                vec![AuditChange::membership(user_id, group_id)
+                    .values(Some(group_id.0.to_string().into_bytes()), None)],
+            )
+            .await?;
+        transaction.commit().await?;
+        events.publish();
+        Ok(())
+    }
+
+    #[instrument(skip_all, level = "debug", err)]
+    async fn add_users_to_group(
+        &self,
+        group_id: GroupId,
+        user_ids: &[UserId],
+        all_or_nothing: bool,
+    ) -> Result<Vec<MembershipChange>> {
+        debug!(?group_id, ?user_ids);
+        let transaction = self.sql_pool.begin().await?;
+        let changes = get_membership_changes(&transaction, group_id, user_ids, true).await?;
+        check_unknown_users(&changes, all_or_nothing)?;
+        let added = changes
+            .iter()
+            .filter(|change| change.status == MembershipChangeStatus::Changed)
+            .map(|change| &change.user_id)
+            .collect::<Vec<_>>();
+        let mut events = PendingChangeEvents::default();
+        if !added.is_empty() {
+            model::Membership::insert_many(added.iter().map(|&user_id| {
+                model::memberships::ActiveModel {
+                    user_id: ActiveValue::Set(user_id.clone()),
+                    group_id: ActiveValue::Set(group_id),
+                    assigned_by_rule: ActiveValue::Set(false),
+                }
+            }))
+            .exec(&transaction)
+            .await?;
+            events = self
+                .write_audit_log(
+                    &transaction,
+                    added
+                        .into_iter()
+                        .map(|user_id| {
+                            AuditChange::membership(user_id, group_id)
+                                .values(None, Some(group_id.0.to_string().into_bytes()))
+                        })
+                        .collect(),
+                )
+                .await?;
+        }
+        transaction.commit().await?;
+        events.publish();
+        Ok(changes)
+    }
+
+    #[instrument(skip_all, level = "debug", err)]
+    async fn remove_users_from_group(
+        &self,
+        group_id: GroupId,
+        user_ids: &[UserId],
+        all_or_nothing: bool,
+    ) -> Result<Vec<MembershipChange>> {
+        debug!(?group_id, ?user_ids);
+        let transaction = self.sql_pool.begin().await?;
+        let changes = get_membership_changes(&transaction, group_id, user_ids, false).await?;
+        check_unknown_users(&changes, all_or_nothing)?;
+        let removed = changes
+            .iter()
+            .filter(|change| change.status == MembershipChangeStatus::Changed)
+            .map(|change| &change.user_id)
+            .collect::<Vec<_>>();
+        let mut events = PendingChangeEvents::default();
+        if !removed.is_empty() {
+            model::Membership::delete_many()
+                .filter(MembershipColumn::GroupId.eq(group_id))
+                .filter(
+                    MembershipColumn::UserId.is_in(removed.iter().map(|&user_id| user_id.clone())),
+                )
+                .exec(&transaction)
+                .await?;
+            events = self
+                .write_audit_log(
+                    &transaction,
+                    removed
+                        .into_iter()
+                        .map(|user_id| {
+                            AuditChange::membership(user_id, group_id)
+                                .values(Some(group_id.0.to_string().into_bytes()), None)
+                        })
+                        .collect(),
+                )
+                .await?;
+        }
+        transaction.commit().await?;
+        events.publish();
+        Ok(changes)
+    }
+
+    #[instrument(skip_all, level = "debug", err)]
+    async fn unlock_user(&self, user_id: &UserId) -> Result<()> {
+        debug!(?user_id);
+        let transaction = self.sql_pool.begin().await?;
+        model::FailedLoginAttempts::delete_by_id(user_id.clone())
+            .exec(&transaction)
+            .await?;
+        let events = self
+            .write_audit_log(
+                &transaction,
+                vec![AuditChange::user(user_id, audit::UNLOCKED)],
+            )
+            .await?;
+        transaction.commit().await?;
+        events.publish();
         Ok(())
     }
+
+    /// On PostgreSQL, the `LIKE` filter uses the trigram indexes. SQLite and MySQL have to scan
+    /// the users table, and SQLite only lowercases ASCII characters, so non-ASCII matches are
+    /// case-sensitive there. The ranking happens after the filter, on all the matching users.
+    #[instrument(skip_all, level = "debug", ret, err)]
+    async fn search_users(
+        &self,
+        query: &str,
+        fields: &[UserColumn],
+        limit: usize,
+    ) -> Result<Vec<User>> {
+        debug!(?query, ?fields, ?limit);
+        let query = query.trim().to_lowercase();
+        if query.is_empty() {
+            return Err(DomainError::ValidationError(
+                "The search query is empty".to_owned(),
+            ));
+        }
+        let fields: &[UserColumn] = if fields.is_empty() {
+            &SEARCHABLE_USER_COLUMNS
+        } else {
+            fields
+        };
+        if let Some(column) = fields
+            .iter()
+            .find(|c| !SEARCHABLE_USER_COLUMNS.contains(*c))
+        {
+            return Err(DomainError::ValidationError(format!(
+                "Cannot search users by {:?}",
+                column
+            )));
+        }
+        let pattern = format!("%{}%", escape_like(&query));
+        let condition = fields.iter().fold(Cond::any(), |condition, column| {
+            condition.add(
+                Expr::expr(Func::lower(Expr::col(*column)))
+                    .like(LikeExpr::str(&pattern).escape('\\')),
+            )
+        });
+        let mut users = model::User::find()
+            .filter(UserColumn::DeletedAt.is_null())
+            .filter(condition)
+            .into_model::<User>()
+            .all(self.read_pool())
+            .await?
+            .into_iter()
+            .filter_map(|user| {
+                let rank = fields
+                    .iter()
+                    .filter_map(|column| get_searchable_value(&user, *column))
+                    .filter_map(|value| match_rank(value, &query))
+                    .min()?;
+                Some((rank, user))
+            })
+            .collect::<Vec<_>>();
+        users.sort_by(|(rank_1, user_1), (rank_2, user_2)| {
+            rank_1
+                .cmp(rank_2)
+                .then_with(|| user_1.user_id.cmp(&user_2.user_id))
+        });
+        Ok(users
+            .into_iter()
+            .take(limit)
+            .map(|(_, user)| user)
+            .collect())
+    }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
     use crate::domain::{
+        avatar::AvatarError,
+        handler::{
+            AuditLogBackendHandler, AuditTarget, GroupBackendHandler, GroupRequestFilter,
+            UserAttributeBackendHandler,
+        },
         sql_backend_handler::tests::*,
-        types::{JpegPhoto, UserColumn},
+        types::{AttributeType, UserColumn},
     };
+    use chrono::TimeZone;
+    use sea_orm::ConnectionTrait;
 
     #[tokio::test]
     async fn test_list_users_no_filter() {
@@ -248,6 +1253,283 @@ mod tests {
         assert_eq!(users, vec!["bob", "john", "nogroup", "patrick"]);
     }
 
+    #[tokio::test]
+    async fn test_list_users_page_5000_users() {
+        let fixture = TestFixture::new().await;
+        let now = chrono::Utc::now();
+        let new_user_ids = (0..5000)
+            .map(|i| format!("user{:04}", i))
+            .collect::<Vec<_>>();
+        for chunk in new_user_ids.chunks(500) {
+            model::User::insert_many(chunk.iter().map(|user_id| model::users::ActiveModel {
+                user_id: Set(UserId::new(user_id)),
+                email: Set(format!("{}@example.com", user_id)),
+                creation_date: Set(now),
+                modified_at: Set(now),
+                uuid: Set(Uuid::from_name_and_date(user_id, &now)),
+                ..Default::default()
+            }))
+            .exec(&fixture.handler.sql_pool)
+            .await
+            .unwrap();
+        }
+        let mut expected_user_ids = get_user_names(&fixture.handler, None).await;
+        assert_eq!(expected_user_ids.len(), 5004);
+
+        let mut user_ids = Vec::new();
+        let mut cursor = None;
+        loop {
+            let page = fixture
+                .handler
+                .list_users_page(
+                    None,
+                    false,
+                    Pagination {
+                        after: cursor,
+                        page_size: 128,
+                    },
+                )
+                .await
+                .unwrap();
+            assert!(page.items.len() <= 128);
+            if user_ids.is_empty() {
+                // A user inserted before the cursor doesn't shift the next pages.
+                insert_user_no_password(&fixture.handler, "aaa").await;
+            }
+            user_ids.extend(page.items.into_iter().map(|u| u.user.user_id.to_string()));
+            cursor = page.next_cursor;
+            if cursor.is_none() {
+                break;
+            }
+        }
+        assert_eq!(user_ids, expected_user_ids);
+
+        // With the groups, and with a page size that divides the number of users exactly.
+        expected_user_ids.insert(0, "aaa".to_owned());
+        let mut user_ids = Vec::new();
+        let mut cursor = None;
+        loop {
+            let page = fixture
+                .handler
+                .list_users_page(
+                    None,
+                    true,
+                    Pagination {
+                        after: cursor,
+                        page_size: 1001,
+                    },
+                )
+                .await
+                .unwrap();
+            for user in page.items {
+                if user.user.user_id.as_str() == "bob" {
+                    assert_eq!(user.groups.unwrap().len(), 1);
+                }
+                user_ids.push(user.user.user_id.to_string());
+            }
+            cursor = page.next_cursor;
+            if cursor.is_none() {
+                break;
+            }
+        }
+        assert_eq!(user_ids, expected_user_ids);
+    }
+
+    #[tokio::test]
+    async fn test_unique_email() {
+        let fixture = TestFixture::new().await;
+        fixture
+            .handler
+            .create_user(CreateUserRequest {
+                user_id: UserId::new("bob2"),
+                email: "bob@bob.bob".to_owned(),
+                ..Default::default()
+            })
+            .await
+            .unwrap_err();
+        // Several users can have no email.
+        for user in ["noemail1", "noemail2"] {
+            fixture
+                .handler
+                .create_user(CreateUserRequest {
+                    user_id: UserId::new(user),
+                    ..Default::default()
+                })
+                .await
+                .unwrap();
+        }
+    }
+
+    #[tokio::test]
+    async fn test_create_user_invalid_id() {
+        let fixture = TestFixture::new().await;
+        let result = fixture
+            .handler
+            .create_user(CreateUserRequest {
+                user_id: UserId::new("john doe"),
+                email: "john@example.com".to_owned(),
+                ..Default::default()
+            })
+            .await;
+        assert!(
+            matches!(&result, Err(DomainError::ValidationError(e)) if e.contains("invalid character")),
+            "{:?}",
+            result
+        );
+        assert!(fixture
+            .handler
+            .get_user_details(&UserId::new("john doe"))
+            .await
+            .is_err());
+    }
+
+    #[tokio::test]
+    async fn test_user_id_casing() {
+        let fixture = TestFixture::new().await;
+        fixture
+            .handler
+            .create_user(CreateUserRequest {
+                user_id: UserId::new("Alice"),
+                email: "alice@example.com".to_owned(),
+                ..Default::default()
+            })
+            .await
+            .unwrap();
+        let alice = UserId::new("alice");
+        let user = fixture.handler.get_user_details(&alice).await.unwrap();
+        assert_eq!(user.user_id.as_str(), "alice");
+        assert_eq!(user.display_user_id(), "Alice");
+        // Another casing is the same user.
+        fixture
+            .handler
+            .create_user(CreateUserRequest {
+                user_id: UserId::new("ALICE"),
+                email: "alice2@example.com".to_owned(),
+                ..Default::default()
+            })
+            .await
+            .unwrap_err();
+        fixture
+            .handler
+            .rename_user(&UserId::new("ALICE"), &UserId::new("Alicia"))
+            .await
+            .unwrap();
+        let user = fixture
+            .handler
+            .get_user_details(&UserId::new("alicia"))
+            .await
+            .unwrap();
+        assert_eq!(user.display_user_id(), "Alicia");
+        // The IDs typed in lowercase are displayed as is.
+        let user = fixture
+            .handler
+            .get_user_details(&UserId::new("bob"))
+            .await
+            .unwrap();
+        assert_eq!(user.display_user_id(), "bob");
+    }
+
+    #[tokio::test]
+    async fn test_case_insensitive_emails() {
+        let mut config = get_default_config();
+        config.case_insensitive_emails = true;
+        let handler = SqlBackendHandler::new(config, get_initialized_db().await);
+        handler
+            .create_user(CreateUserRequest {
+                user_id: UserId::new("bob"),
+                email: "Bob@Bob.bob".to_owned(),
+                ..Default::default()
+            })
+            .await
+            .unwrap();
+        assert_eq!(
+            handler
+                .get_user_details(&UserId::new("bob"))
+                .await
+                .unwrap()
+                .email,
+            "bob@bob.bob"
+        );
+        assert_eq!(
+            get_user_names(
+                &handler,
+                Some(UserRequestFilter::Equality(
+                    UserColumn::Email,
+                    "BOB@bob.bob".to_owned()
+                ))
+            )
+            .await,
+            vec!["bob"]
+        );
+        handler
+            .create_user(CreateUserRequest {
+                user_id: UserId::new("robert"),
+                email: "bob@BOB.bob".to_owned(),
+                ..Default::default()
+            })
+            .await
+            .unwrap_err();
+    }
+
+    #[tokio::test]
+    async fn test_email_policy() {
+        let mut config = get_default_config();
+        config.email_policy.validate = true;
+        config.email_policy.normalize = true;
+        config.email_policy.blocked_domains = vec!["spam.bob".to_owned()];
+        let handler = SqlBackendHandler::new(config, get_initialized_db().await);
+        let create = |user_id: &str, email: &str| {
+            handler.create_user(CreateUserRequest {
+                user_id: UserId::new(user_id),
+                email: email.to_owned(),
+                ..Default::default()
+            })
+        };
+        create("bob", " Bob@Bob.BOB ").await.unwrap();
+        let bob = UserId::new("bob");
+        assert_eq!(
+            handler.get_user_details(&bob).await.unwrap().email,
+            "Bob@bob.bob"
+        );
+        assert_eq!(
+            get_user_names(
+                &handler,
+                Some(UserRequestFilter::Equality(
+                    UserColumn::Email,
+                    "Bob@BOB.bob".to_owned()
+                ))
+            )
+            .await,
+            vec!["bob"]
+        );
+        // Same email once normalized.
+        create("robert", "Bob@bob.Bob").await.unwrap_err();
+        // Only the domain is case-insensitive.
+        create("robert", "bob@bob.bob").await.unwrap();
+        assert!(matches!(
+            create("john", "john").await,
+            Err(DomainError::InvalidEmail(_))
+        ));
+        assert!(matches!(
+            create("john", "john@eu.spam.bob").await,
+            Err(DomainError::InvalidEmail(_))
+        ));
+        assert!(matches!(
+            handler
+                .update_user(UpdateUserRequest {
+                    user_id: bob.clone(),
+                    email: Some("bob at bob.bob".to_owned()),
+                    ..Default::default()
+                })
+                .await,
+            Err(DomainError::InvalidEmail(_))
+        ));
+        assert_eq!(
+            handler.get_user_details(&bob).await.unwrap().email,
+            "Bob@bob.bob"
+        );
+    }
+
     #[tokio::test]
     async fn test_list_users_user_id_filter() {
         let fixture = TestFixture::new().await;
@@ -274,17 +1556,305 @@ mod tests {
     }
 
     #[tokio::test]
-    async fn test_list_users_other_filter() {
+    async fn test_list_users_other_filter() {
+        let fixture = TestFixture::new().await;
+        let users = get_user_names(
+            &fixture.handler,
+            Some(UserRequestFilter::Equality(
+                UserColumn::FirstName,
+                "first bob".to_string(),
+            )),
+        )
+        .await;
+        assert_eq!(users, vec!["bob"]);
+    }
+
+    #[tokio::test]
+    async fn test_list_users_ignore_case_filter() {
+        let fixture = TestFixture::new().await;
+        let users = get_user_names(
+            &fixture.handler,
+            Some(UserRequestFilter::EqualityIgnoreCase(
+                UserColumn::DisplayName,
+                "Display BOB".to_string(),
+            )),
+        )
+        .await;
+        assert_eq!(users, vec!["bob"]);
+        let users = get_user_names(
+            &fixture.handler,
+            Some(UserRequestFilter::Equality(
+                UserColumn::DisplayName,
+                "Display BOB".to_string(),
+            )),
+        )
+        .await;
+        assert_eq!(users, Vec::<String>::new());
+    }
+
+    #[tokio::test]
+    async fn test_list_users_present_filter() {
+        let fixture = TestFixture::new().await;
+        fixture
+            .handler
+            .create_user(CreateUserRequest {
+                user_id: UserId::new("nameless"),
+                email: "nameless@bob.bob".to_owned(),
+                ..Default::default()
+            })
+            .await
+            .unwrap();
+        let users = get_user_names(
+            &fixture.handler,
+            Some(UserRequestFilter::Not(Box::new(
+                UserRequestFilter::Present(UserColumn::FirstName),
+            ))),
+        )
+        .await;
+        assert_eq!(users, vec!["nameless"]);
+        let users = get_user_names(
+            &fixture.handler,
+            Some(UserRequestFilter::Present(UserColumn::Email)),
+        )
+        .await;
+        assert_eq!(users, vec!["bob", "john", "nameless", "nogroup", "patrick"]);
+    }
+
+    #[tokio::test]
+    async fn test_list_users_ordering_filter() {
+        let fixture = TestFixture::new().await;
+        for (user, uid_number, year) in [("bob", 1000, 2020), ("john", 1001, 2021)] {
+            model::User::update_many()
+                .col_expr(UserColumn::UidNumber, Expr::value(uid_number))
+                .col_expr(
+                    UserColumn::CreationDate,
+                    Expr::value(chrono::Utc.with_ymd_and_hms(year, 1, 1, 0, 0, 0).unwrap()),
+                )
+                .filter(ColumnTrait::eq(&UserColumn::UserId, UserId::new(user)))
+                .exec(&fixture.handler.sql_pool)
+                .await
+                .unwrap();
+        }
+        // The bounds are included.
+        let users = get_user_names(
+            &fixture.handler,
+            Some(UserRequestFilter::And(vec![
+                UserRequestFilter::Ordering(
+                    UserColumn::UidNumber,
+                    Comparison::GreaterOrEqual,
+                    OrderedValue::Integer(1000),
+                ),
+                UserRequestFilter::Ordering(
+                    UserColumn::UidNumber,
+                    Comparison::LessOrEqual,
+                    OrderedValue::Integer(1001),
+                ),
+            ])),
+        )
+        .await;
+        assert_eq!(users, vec!["bob", "john"]);
+        let users = get_user_names(
+            &fixture.handler,
+            Some(UserRequestFilter::Ordering(
+                UserColumn::CreationDate,
+                Comparison::LessOrEqual,
+                OrderedValue::DateTime(chrono::Utc.with_ymd_and_hms(2021, 1, 1, 0, 0, 0).unwrap()),
+            )),
+        )
+        .await;
+        assert_eq!(users, vec!["bob", "john"]);
+        let users = get_user_names(
+            &fixture.handler,
+            Some(UserRequestFilter::Ordering(
+                UserColumn::CreationDate,
+                Comparison::LessOrEqual,
+                OrderedValue::DateTime(
+                    chrono::Utc
+                        .with_ymd_and_hms(2020, 12, 31, 23, 59, 59)
+                        .unwrap(),
+                ),
+            )),
+        )
+        .await;
+        assert_eq!(users, vec!["bob"]);
+    }
+
+    #[tokio::test]
+    async fn test_list_users_attribute_ordering_filter() {
         let fixture = TestFixture::new().await;
+        for (name, attribute_type) in [
+            ("employeenumber", AttributeType::Integer),
+            ("nickname", AttributeType::String),
+        ] {
+            fixture
+                .handler
+                .create_user_attribute(AttributeSchema {
+                    name: AttributeName::new(name),
+                    attribute_type,
+                    is_list: false,
+                    is_indexed: false,
+                })
+                .await
+                .unwrap();
+        }
+        let employee_number = AttributeName::new("employeenumber");
+        for (user, number) in [("bob", 9), ("john", 10), ("patrick", 11)] {
+            fixture
+                .handler
+                .set_user_attribute(
+                    &UserId::new(user),
+                    &employee_number,
+                    vec![AttributeValue::Integer(number)],
+                )
+                .await
+                .unwrap();
+        }
+        let ordering = |comparison, bound: &str| {
+            UserRequestFilter::AttributeOrdering(
+                employee_number.clone(),
+                comparison,
+                bound.to_owned(),
+            )
+        };
+        // Compared as integers, not as strings, and the bounds are included.
         let users = get_user_names(
             &fixture.handler,
-            Some(UserRequestFilter::Equality(
-                UserColumn::FirstName,
-                "first bob".to_string(),
+            Some(ordering(Comparison::GreaterOrEqual, "10")),
+        )
+        .await;
+        assert_eq!(users, vec!["john", "patrick"]);
+        let users = get_user_names(
+            &fixture.handler,
+            Some(UserRequestFilter::Not(Box::new(ordering(
+                Comparison::LessOrEqual,
+                "10",
+            )))),
+        )
+        .await;
+        assert_eq!(users, vec!["nogroup", "patrick"]);
+        let count = fixture
+            .handler
+            .count_users(Some(UserRequestFilter::Or(vec![
+                ordering(Comparison::LessOrEqual, "9"),
+                ordering(Comparison::GreaterOrEqual, "11"),
+            ])))
+            .await
+            .unwrap();
+        assert_eq!(count, 2);
+        // An unknown attribute matches no user.
+        let users = get_user_names(
+            &fixture.handler,
+            Some(UserRequestFilter::AttributeOrdering(
+                AttributeName::new("unknown"),
+                Comparison::GreaterOrEqual,
+                "1".to_owned(),
             )),
         )
         .await;
-        assert_eq!(users, vec!["bob"]);
+        assert_eq!(users, Vec::<String>::new());
+        // The bound must have the type of the attribute, and the attribute must be ordered.
+        for (name, bound) in [("employeenumber", "ten"), ("nickname", "bob")] {
+            assert!(matches!(
+                fixture
+                    .handler
+                    .list_users(
+                        Some(UserRequestFilter::AttributeOrdering(
+                            AttributeName::new(name),
+                            Comparison::GreaterOrEqual,
+                            bound.to_owned(),
+                        )),
+                        false,
+                    )
+                    .await,
+                Err(DomainError::ValidationError(_))
+            ));
+        }
+    }
+
+    #[tokio::test]
+    async fn test_list_users_attribute_equality_filter() {
+        let fixture = TestFixture::new().await;
+        let employee_number = AttributeName::new("employeenumber");
+        fixture
+            .handler
+            .create_user_attribute(AttributeSchema {
+                name: employee_number.clone(),
+                attribute_type: AttributeType::Integer,
+                is_list: true,
+                is_indexed: false,
+            })
+            .await
+            .unwrap();
+        for (user, numbers) in [
+            ("bob", vec![9, 10]),
+            ("john", vec![10]),
+            ("patrick", vec![11]),
+        ] {
+            fixture
+                .handler
+                .set_user_attribute(
+                    &UserId::new(user),
+                    &employee_number,
+                    numbers.into_iter().map(AttributeValue::Integer).collect(),
+                )
+                .await
+                .unwrap();
+        }
+        let has_index = || async {
+            fixture
+                .handler
+                .sql_pool
+                .query_one(sea_orm::Statement::from_string(
+                    sea_orm::DbBackend::Sqlite,
+                    "SELECT name FROM sqlite_master WHERE name = 'user_attributes_name_value'"
+                        .to_owned(),
+                ))
+                .await
+                .unwrap()
+                .is_some()
+        };
+        let equality = |value: &str| {
+            UserRequestFilter::AttributeEquality(employee_number.clone(), value.to_owned())
+        };
+        for is_indexed in [false, true, false] {
+            fixture
+                .handler
+                .set_user_attribute_indexed(&employee_number, is_indexed)
+                .await
+                .unwrap();
+            assert_eq!(has_index().await, is_indexed);
+            // Compared as integers, not as strings.
+            let users = get_user_names(&fixture.handler, Some(equality("010"))).await;
+            assert_eq!(users, vec!["bob", "john"]);
+            let users = get_user_names(
+                &fixture.handler,
+                Some(UserRequestFilter::And(vec![
+                    equality("9"),
+                    UserRequestFilter::Not(Box::new(equality("11"))),
+                ])),
+            )
+            .await;
+            assert_eq!(users, vec!["bob"]);
+            // Not an integer, or an unknown attribute: no user.
+            let users = get_user_names(&fixture.handler, Some(equality("ten"))).await;
+            assert_eq!(users, Vec::<String>::new());
+            let users = get_user_names(
+                &fixture.handler,
+                Some(UserRequestFilter::AttributeEquality(
+                    AttributeName::new("unknown"),
+                    "10".to_owned(),
+                )),
+            )
+            .await;
+            assert_eq!(users, Vec::<String>::new());
+        }
+        assert!(matches!(
+            fixture
+                .handler
+                .set_user_attribute_indexed(&AttributeName::new("unknown"), true)
+                .await,
+            Err(DomainError::EntityNotFound(_))
+        ));
     }
 
     #[tokio::test]
@@ -336,6 +1906,58 @@ mod tests {
         assert_eq!(users, vec!["bob", "patrick"]);
     }
 
+    #[tokio::test]
+    async fn test_count_users() {
+        let fixture = TestFixture::new().await;
+        assert_eq!(fixture.handler.count_users(None).await.unwrap(), 4);
+        assert_eq!(
+            fixture
+                .handler
+                .count_users(Some(UserRequestFilter::MemberOfId(fixture.groups[1])))
+                .await
+                .unwrap(),
+            2
+        );
+        fixture
+            .handler
+            .delete_user(&UserId::new("bob"))
+            .await
+            .unwrap();
+        assert_eq!(fixture.handler.count_users(None).await.unwrap(), 3);
+    }
+
+    #[tokio::test]
+    async fn test_get_users_groups() {
+        let fixture = TestFixture::new().await;
+        let groups = fixture
+            .handler
+            .get_users_groups(&[
+                UserId::new("bob"),
+                UserId::new("patrick"),
+                UserId::new("nogroup"),
+                UserId::new("unknown"),
+            ])
+            .await
+            .unwrap()
+            .into_iter()
+            .map(|(user_id, groups)| {
+                let mut group_ids = groups.into_iter().map(|g| g.group_id).collect::<Vec<_>>();
+                group_ids.sort();
+                (user_id, group_ids)
+            })
+            .collect::<HashMap<_, _>>();
+        assert_eq!(
+            groups,
+            HashMap::from([
+                (UserId::new("bob"), vec![fixture.groups[0]]),
+                (
+                    UserId::new("patrick"),
+                    vec![fixture.groups[0], fixture.groups[1]]
+                ),
+            ])
+        );
+    }
+
     #[tokio::test]
     #[should_panic]
     async fn test_list_users_invalid_userid_filter() {
@@ -443,6 +2065,49 @@ mod tests {
         );
     }
 
+    #[tokio::test]
+    async fn test_list_users_sorted() {
+        let fixture = TestFixture::new().await;
+        let sort = vec![UserSortKey {
+            column: UserColumn::DisplayName,
+            descending: true,
+        }];
+        let list = |offset, limit| {
+            let sort = sort.clone();
+            let handler = &fixture.handler;
+            async move {
+                handler
+                    .list_users_sorted(None, true, sort, offset, limit)
+                    .await
+                    .unwrap()
+                    .into_iter()
+                    .map(|u| {
+                        (
+                            u.user.user_id.to_string(),
+                            u.groups.unwrap_or_default().len(),
+                        )
+                    })
+                    .collect::<Vec<_>>()
+            }
+        };
+        // Case-sensitive: the capitals come first.
+        assert_eq!(
+            list(0, None).await,
+            vec![
+                ("patrick".to_string(), 2),
+                ("bob".to_string(), 1),
+                ("nogroup".to_string(), 0),
+                ("john".to_string(), 1),
+            ]
+        );
+        assert_eq!(
+            list(1, Some(2)).await,
+            vec![("bob".to_string(), 1), ("nogroup".to_string(), 0)]
+        );
+        assert_eq!(list(3, None).await, vec![("john".to_string(), 1)]);
+        assert_eq!(list(4, Some(2)).await, vec![]);
+    }
+
     #[tokio::test]
     async fn test_list_users_groups_have_different_creation_date_than_users() {
         let fixture = TestFixture::new().await;
@@ -535,6 +2200,152 @@ mod tests {
         );
     }
 
+    #[tokio::test]
+    async fn test_rename_user() {
+        let fixture = TestFixture::new().await;
+        let bob = UserId::new("bob");
+        let robert = UserId::new("robert");
+        let uuid = fixture.handler.get_user_details(&bob).await.unwrap().uuid;
+        let before = chrono::Utc::now();
+        fixture.handler.rename_user(&bob, &robert).await.unwrap();
+        assert_eq!(
+            get_user_names(&fixture.handler, None).await,
+            vec!["john", "nogroup", "patrick", "robert"]
+        );
+        // The user keeps its UUID and memberships.
+        assert_eq!(
+            fixture
+                .handler
+                .get_user_details(&robert)
+                .await
+                .unwrap()
+                .uuid,
+            uuid
+        );
+        let groups = fixture.handler.get_user_groups(&robert).await.unwrap();
+        assert_eq!(
+            groups.into_iter().map(|g| g.group_id).collect::<Vec<_>>(),
+            vec![fixture.groups[0]]
+        );
+        assert!(matches!(
+            fixture.handler.get_user_groups(&bob).await,
+            Err(DomainError::EntityNotFound(_))
+        ));
+        let entries = fixture
+            .handler
+            .get_audit_log(
+                AuditTarget::User(robert.clone()),
+                before,
+                chrono::Utc::now(),
+            )
+            .await
+            .unwrap();
+        assert!(entries.iter().any(|e| e.attribute_name == "user_id"));
+        // The old ID is free, the new one can't be taken.
+        assert!(matches!(
+            fixture.handler.rename_user(&bob, &robert).await,
+            Err(DomainError::EntityNotFound(_))
+        ));
+        assert!(matches!(
+            fixture
+                .handler
+                .rename_user(&robert, &UserId::new("patrick"))
+                .await,
+            Err(DomainError::ValidationError(_))
+        ));
+        assert!(matches!(
+            fixture.handler.rename_user(&robert, &robert).await,
+            Err(DomainError::ValidationError(_))
+        ));
+        assert!(matches!(
+            fixture
+                .handler
+                .rename_user(&robert, &UserId::new("bob smith"))
+                .await,
+            Err(DomainError::ValidationError(_))
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_soft_delete_user() {
+        let mut config = get_default_config();
+        config.soft_delete_users = true;
+        let fixture = TestFixture::with_config(config).await;
+        fixture
+            .handler
+            .delete_user(&UserId::new("bob"))
+            .await
+            .unwrap();
+
+        assert_eq!(
+            get_user_names(&fixture.handler, None).await,
+            vec!["john", "nogroup", "patrick"]
+        );
+        assert_eq!(
+            get_user_names(
+                &fixture.handler,
+                Some(UserRequestFilter::And(vec![
+                    UserRequestFilter::MemberOfId(fixture.groups[0]),
+                    UserRequestFilter::IncludeDeleted,
+                ]))
+            )
+            .await,
+            vec!["bob", "patrick"]
+        );
+        assert!(matches!(
+            fixture.handler.get_user_details(&UserId::new("bob")).await,
+            Err(DomainError::EntityNotFound(_))
+        ));
+        assert_eq!(
+            fixture
+                .handler
+                .list_groups(Some(GroupRequestFilter::GroupId(fixture.groups[0])))
+                .await
+                .unwrap()[0]
+                .users,
+            vec![UserId::new("patrick")]
+        );
+        // The email of the deleted user is free again.
+        fixture
+            .handler
+            .create_user(CreateUserRequest {
+                user_id: UserId::new("robert"),
+                email: "bob@bob.bob".to_string(),
+                ..Default::default()
+            })
+            .await
+            .unwrap();
+        // Already deleted.
+        fixture
+            .handler
+            .delete_user(&UserId::new("bob"))
+            .await
+            .unwrap_err();
+
+        assert_eq!(
+            purge_deleted_users(
+                &fixture.handler.sql_pool,
+                chrono::Utc::now() - chrono::Duration::days(1)
+            )
+            .await
+            .unwrap(),
+            0
+        );
+        assert_eq!(
+            purge_deleted_users(
+                &fixture.handler.sql_pool,
+                chrono::Utc::now() + chrono::Duration::seconds(1)
+            )
+            .await
+            .unwrap(),
+            1
+        );
+        assert_eq!(
+            get_user_names(&fixture.handler, Some(UserRequestFilter::IncludeDeleted)).await,
+            vec!["john", "nogroup", "patrick", "robert"]
+        );
+    }
+
     #[tokio::test]
     async fn test_get_user_groups() {
         let fixture = TestFixture::new().await;
@@ -571,6 +2382,7 @@ mod tests {
                 first_name: Some("first_name".to_string()),
                 last_name: Some("last_name".to_string()),
                 avatar: Some(JpegPhoto::for_tests()),
+                must_change_password: Some(true),
             })
             .await
             .unwrap();
@@ -587,6 +2399,121 @@ mod tests {
         assert_eq!(user.avatar, Some(JpegPhoto::for_tests()));
     }
 
+    #[tokio::test]
+    async fn test_search_users() {
+        let mut config = get_default_config();
+        config.soft_delete_users = true;
+        let fixture = TestFixture::with_config(config).await;
+        for (user_id, display_name, email) in [
+            ("blacksmith", "Blacksmith", "b@example.com"),
+            ("jsmith", "John Smithers", "j@example.com"),
+            ("smith", "Smith", "smith@example.com"),
+            ("percent", "100% Smith-free", "p@example.com"),
+        ] {
+            fixture
+                .handler
+                .create_user(CreateUserRequest {
+                    user_id: UserId::new(user_id),
+                    email: email.to_owned(),
+                    display_name: Some(display_name.to_owned()),
+                    ..Default::default()
+                })
+                .await
+                .unwrap();
+        }
+        let search = |query: &'static str, fields: &'static [UserColumn], limit: usize| {
+            let handler = fixture.handler.clone();
+            async move {
+                handler
+                    .search_users(query, fields, limit)
+                    .await
+                    .unwrap()
+                    .into_iter()
+                    .map(|u| u.user_id.to_string())
+                    .collect::<Vec<_>>()
+            }
+        };
+        // Exact match, then start of the value, start of a word, and anywhere.
+        assert_eq!(
+            search("SMITH", &[], 10).await,
+            vec!["smith", "jsmith", "percent", "blacksmith"]
+        );
+        assert_eq!(search("smith", &[], 2).await, vec!["smith", "jsmith"]);
+        assert_eq!(
+            search("smith", &[UserColumn::Email], 10).await,
+            vec!["smith"]
+        );
+        assert_eq!(
+            search("first j", &[UserColumn::FirstName], 10).await,
+            vec!["john"]
+        );
+        // The LIKE wildcards are matched literally.
+        assert_eq!(search("%", &[], 10).await, vec!["percent"]);
+        assert_eq!(search("_", &[], 10).await, Vec::<String>::new());
+
+        fixture
+            .handler
+            .delete_user(&UserId::new("smith"))
+            .await
+            .unwrap();
+        assert_eq!(
+            search("smith", &[], 10).await,
+            vec!["jsmith", "percent", "blacksmith"]
+        );
+        assert!(matches!(
+            fixture
+                .handler
+                .search_users("smith", &[UserColumn::Avatar], 10)
+                .await,
+            Err(DomainError::ValidationError(_))
+        ));
+        assert!(matches!(
+            fixture.handler.search_users("  ", &[], 10).await,
+            Err(DomainError::ValidationError(_))
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_update_user_avatar_limits() {
+        let mut config = get_default_config();
+        config.avatar_max_dimension = 16;
+        let fixture = TestFixture::with_config(config).await;
+        fixture
+            .handler
+            .update_user(UpdateUserRequest {
+                user_id: UserId::new("bob"),
+                avatar: Some(JpegPhoto::for_tests()),
+                ..Default::default()
+            })
+            .await
+            .unwrap();
+        let avatar = fixture
+            .handler
+            .get_user_details(&UserId::new("bob"))
+            .await
+            .unwrap()
+            .avatar
+            .unwrap()
+            .into_bytes();
+        let image = image::load_from_memory(&avatar).unwrap();
+        assert_eq!((image.width(), image.height()), (16, 16));
+
+        let mut config = get_default_config();
+        config.avatar_max_size_kb = 0;
+        let fixture = TestFixture::with_config(config).await;
+        assert!(matches!(
+            fixture
+                .handler
+                .update_user(UpdateUserRequest {
+                    user_id: UserId::new("bob"),
+                    avatar: Some(JpegPhoto::for_tests()),
+                    ..Default::default()
+                })
+                .await,
+            Err(DomainError::InvalidAvatar(AvatarError::TooLarge { .. }))
+        ));
+    }
+
     #[tokio::test]
     async fn test_update_user_some_values() {
         let fixture = TestFixture::new().await;
@@ -632,4 +2559,106 @@ mod tests {
             vec!["patrick"]
         );
     }
+
+    fn make_changes(changes: &[(&str, MembershipChangeStatus)]) -> Vec<MembershipChange> {
+        changes
+            .iter()
+            .map(|(user_id, status)| MembershipChange {
+                user_id: UserId::new(user_id),
+                status: *status,
+            })
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn test_add_users_to_group() {
+        use MembershipChangeStatus::*;
+        let fixture = TestFixture::new().await;
+        let user_ids = ["bob", "patrick", "NoGroup", "bob", "unknown"]
+            .into_iter()
+            .map(UserId::new)
+            .collect::<Vec<_>>();
+        // With all_or_nothing, the unknown user fails everything.
+        assert!(matches!(
+            fixture
+                .handler
+                .add_users_to_group(fixture.groups[1], &user_ids, true)
+                .await,
+            Err(DomainError::EntityNotFound(_))
+        ));
+        assert_eq!(
+            get_user_names(
+                &fixture.handler,
+                Some(UserRequestFilter::MemberOfId(fixture.groups[1])),
+            )
+            .await,
+            vec!["john", "patrick"]
+        );
+        assert_eq!(
+            fixture
+                .handler
+                .add_users_to_group(fixture.groups[1], &user_ids, false)
+                .await
+                .unwrap(),
+            make_changes(&[
+                ("bob", Changed),
+                ("patrick", Unchanged),
+                ("nogroup", Changed),
+                ("unknown", NoSuchUser),
+            ])
+        );
+        assert_eq!(
+            get_user_names(
+                &fixture.handler,
+                Some(UserRequestFilter::MemberOfId(fixture.groups[1])),
+            )
+            .await,
+            vec!["bob", "john", "nogroup", "patrick"]
+        );
+        assert!(matches!(
+            fixture
+                .handler
+                .add_users_to_group(GroupId(1000), &user_ids, false)
+                .await,
+            Err(DomainError::EntityNotFound(_))
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_remove_users_from_group() {
+        use MembershipChangeStatus::*;
+        let fixture = TestFixture::new().await;
+        let user_ids = ["patrick", "NoGroup", "unknown", "bob"]
+            .into_iter()
+            .map(UserId::new)
+            .collect::<Vec<_>>();
+        assert_eq!(
+            fixture
+                .handler
+                .remove_users_from_group(fixture.groups[0], &user_ids, false)
+                .await
+                .unwrap(),
+            make_changes(&[
+                ("patrick", Changed),
+                ("nogroup", Unchanged),
+                ("unknown", NoSuchUser),
+                ("bob", Changed),
+            ])
+        );
+        assert!(get_user_names(
+            &fixture.handler,
+            Some(UserRequestFilter::MemberOfId(fixture.groups[0])),
+        )
+        .await
+        .is_empty());
+        // The other groups are untouched.
+        assert_eq!(
+            get_user_names(
+                &fixture.handler,
+                Some(UserRequestFilter::MemberOfId(fixture.groups[1])),
+            )
+            .await,
+            vec!["john", "patrick"]
+        );
+    }
 }
@@ -0,0 +1,327 @@
+use super::{
+    clock,
+    email_policy::check_email,
+    error::{DomainError, Result},
+    handler::{ImportFailure, ImportReport, ImportUserRequest, UserImportBackendHandler},
+    legacy_password_hash::LegacyPasswordScheme,
+    model::{self, UserColumn},
+    password_policy::PasswordPolicy,
+    sql_audit_log_handler::{self as audit, AuditChange},
+    sql_backend_handler::SqlBackendHandler,
+    sql_opaque_handler::make_password_file,
+    sql_posix_numbers::{allocate_posix_number, PosixNumber},
+    sql_user_backend_handler::{get_user_changes, to_value},
+    types::{UserId, Uuid},
+    user_id_policy::check_user_id,
+};
+use crate::infra::configuration::EmailPolicyOptions;
+use async_trait::async_trait;
+use sea_orm::{
+    ActiveModelTrait, ActiveValue, ColumnTrait, EntityTrait, QueryFilter, QuerySelect,
+    TransactionTrait,
+};
+use std::collections::HashSet;
+use tracing::{debug, instrument};
+
+/// Unlike for a single user, the email is required.
+fn validate_email(options: &EmailPolicyOptions, email: &str) -> std::result::Result<(), String> {
+    if email.is_empty() {
+        return Err("The email is empty".to_owned());
+    }
+    // The malformed emails are always rejected, whatever the policy.
+    let options = EmailPolicyOptions {
+        validate: true,
+        ..options.clone()
+    };
+    check_email(&options, email).map_err(|e| e.to_string())
+}
+
+impl SqlBackendHandler {
+    /// Checks every user, returning all the failures rather than the first one.
+    async fn validate_import(&self, users: &[ImportUserRequest]) -> Result<Vec<ImportFailure>> {
+        let password_policy = PasswordPolicy::new(&self.config.password_policy)
+            .map_err(|e| DomainError::InternalError(format!("{:#}", e)))?;
+        #[derive(sea_orm::FromQueryResult)]
+        struct ExistingUser {
+            user_id: UserId,
+            email: String,
+        }
+        let existing_users = model::User::find()
+            .select_only()
+            .column(UserColumn::UserId)
+            .column(UserColumn::Email)
+            .filter(UserColumn::DeletedAt.is_null())
+            .into_model::<ExistingUser>()
+            .all(&self.sql_pool)
+            .await?;
+        let mut user_ids = existing_users
+            .iter()
+            .map(|u| u.user_id.clone())
+            .collect::<HashSet<_>>();
+        let mut emails = existing_users
+            .into_iter()
+            .map(|u| self.normalize_email(u.email))
+            .collect::<HashSet<_>>();
+        let mut failures = Vec::new();
+        for (row, user) in users.iter().enumerate() {
+            let email = self.normalize_email(user.email.clone());
+            let result = check_user_id(&self.config.user_id_policy, &user.user_id)
+                .and_then(|()| validate_email(&self.config.email_policy, &email))
+                .and_then(|()| {
+                    if user_ids.insert(user.user_id.clone()) {
+                        Ok(())
+                    } else {
+                        Err(format!("The user ID '{}' is already taken", user.user_id))
+                    }
+                })
+                .and_then(|()| {
+                    if emails.insert(email.clone()) {
+                        Ok(())
+                    } else {
+                        Err(format!("The email '{}' is already taken", email))
+                    }
+                });
+            let result = match (result, &user.password, &user.password_hash) {
+                (Ok(()), Some(_), Some(_)) => {
+                    Err("Both a password and a password hash are given".to_owned())
+                }
+                (Ok(()), Some(password), None) => password_policy
+                    .check(password.unsecure())
+                    .await
+                    .map_err(|e| e.to_string()),
+                (Ok(()), None, Some(password_hash)) => {
+                    LegacyPasswordScheme::detect(password_hash.as_bytes())
+                        .map(|_| ())
+                        .ok_or_else(|| "Unsupported password hash scheme".to_owned())
+                }
+                (result, _, _) => result,
+            };
+            if let Err(reason) = result {
+                failures.push(ImportFailure {
+                    row,
+                    user_id: user.user_id.clone(),
+                    reason,
+                });
+            }
+        }
+        Ok(failures)
+    }
+}
+
+#[async_trait]
+impl UserImportBackendHandler for SqlBackendHandler {
+    #[instrument(skip_all, level = "debug", ret, err)]
+    async fn import_users(&self, users: Vec<ImportUserRequest>) -> Result<ImportReport> {
+        debug!(num_users = users.len());
+        let failures = self.validate_import(&users).await?;
+        if !failures.is_empty() {
+            return Ok(ImportReport {
+                imported: 0,
+                failures,
+            });
+        }
+        let transaction = self.sql_pool.begin().await?;
+        let mut changes = Vec::new();
+        for user in &users {
+            let now = clock::creation_date();
+            let password_hash = match (&user.password, &user.password_hash) {
+                (Some(password), _) => Some(make_password_file(
+                    self.config.get_server_setup(),
+                    &user.user_id,
+                    password.unsecure(),
+                )?),
+                // Checked on the first bind, and replaced with a password file.
+                (None, Some(password_hash)) => Some(password_hash.clone().into_bytes()),
+                (None, None) => None,
+            };
+            // Soft-deleted users with the same ID are replaced, like in `create_user`.
+            model::User::delete_many()
+                .filter(ColumnTrait::eq(&UserColumn::UserId, &user.user_id))
+                .filter(UserColumn::DeletedAt.is_not_null())
+                .exec(&transaction)
+                .await?;
+            changes.push(AuditChange::user(&user.user_id, audit::CREATED));
+            if password_hash.is_some() {
+                changes.push(AuditChange::user(&user.user_id, audit::PASSWORD));
+            }
+            let new_user = model::users::ActiveModel {
+                user_id: ActiveValue::Set(user.user_id.clone()),
+                user_id_display: ActiveValue::Set(Some(user.user_id.as_display_str().to_owned())),
+                email: ActiveValue::Set(self.normalize_email(user.email.clone())),
+                display_name: to_value(&user.display_name),
+                first_name: to_value(&user.first_name),
+                last_name: to_value(&user.last_name),
+                creation_date: ActiveValue::Set(now),
+                modified_at: ActiveValue::Set(now),
+                uuid: ActiveValue::Set(Uuid::from_name_and_date(user.user_id.as_str(), &now)),
+                password_changed_at: ActiveValue::Set(password_hash.as_ref().map(|_| now)),
+                password_hash: ActiveValue::Set(password_hash),
+                uid_number: ActiveValue::Set(Some(
+                    allocate_posix_number(
+                        &transaction,
+                        PosixNumber::Uid,
+                        &self.config.posix_options,
+                    )
+                    .await?,
+                )),
+                ..Default::default()
+            };
+            changes.extend(get_user_changes(&user.user_id, None, &new_user));
+            new_user.insert(&transaction).await?;
+        }
+        let user_ids = users.iter().map(|u| u.user_id.clone()).collect::<Vec<_>>();
+        changes.extend(
+            self.apply_group_rules(&transaction, Some(&user_ids))
+                .await?,
+        );
+        let events = self.write_audit_log(&transaction, changes).await?;
+        transaction.commit().await?;
+        events.publish();
+        Ok(ImportReport {
+            imported: users.len(),
+            failures: Vec::new(),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::domain::{
+        handler::{BindRequest, LoginHandler},
+        sql_backend_handler::tests::*,
+    };
+
+    fn import_request(user_id: &str, email: &str) -> ImportUserRequest {
+        ImportUserRequest {
+            user_id: UserId::new(user_id),
+            email: email.to_owned(),
+            display_name: Some(format!("Display {}", user_id)),
+            first_name: None,
+            last_name: None,
+            password: None,
+            password_hash: None,
+        }
+    }
+
+    #[tokio::test]
+    async fn test_import_users() {
+        let fixture = TestFixture::new().await;
+        let report = fixture
+            .handler
+            .import_users(vec![
+                import_request("alice", "alice@example.com"),
+                ImportUserRequest {
+                    password: Some("carol-password".into()),
+                    ..import_request("carol", "carol@example.com")
+                },
+            ])
+            .await
+            .unwrap();
+        assert_eq!(
+            report,
+            ImportReport {
+                imported: 2,
+                failures: vec![]
+            }
+        );
+        assert_eq!(
+            get_user_names(&fixture.handler, None).await,
+            vec!["alice", "bob", "carol", "john", "nogroup", "patrick"]
+        );
+        fixture
+            .handler
+            .bind(BindRequest {
+                name: UserId::new("carol"),
+                password: "carol-password".to_owned(),
+            })
+            .await
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_import_users_with_password_hashes() {
+        use crate::domain::legacy_password_hash::tests::bcrypt_hash;
+        let fixture = TestFixture::new().await;
+        let carol_hash = String::from_utf8(bcrypt_hash("carol-password")).unwrap();
+        let report = fixture
+            .handler
+            .import_users(vec![
+                ImportUserRequest {
+                    password_hash: Some("{SSHA}unsupported".to_owned()),
+                    ..import_request("alice", "alice@example.com")
+                },
+                ImportUserRequest {
+                    password: Some("carol-password".into()),
+                    password_hash: Some(carol_hash.clone()),
+                    ..import_request("carol", "carol@example.com")
+                },
+            ])
+            .await
+            .unwrap();
+        assert_eq!(report.failures.len(), 2);
+        fixture
+            .handler
+            .import_users(vec![ImportUserRequest {
+                password_hash: Some(carol_hash),
+                ..import_request("carol", "carol@example.com")
+            }])
+            .await
+            .unwrap();
+        fixture
+            .handler
+            .bind(BindRequest {
+                name: UserId::new("carol"),
+                password: "carol-password".to_owned(),
+            })
+            .await
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_import_users_invalid_rows() {
+        let fixture = TestFixture::new().await;
+        let report = fixture
+            .handler
+            .import_users(vec![
+                import_request("alice", "alice@example.com"),
+                import_request("bad user", "bad@example.com"),
+                import_request("dave", "not an email"),
+                import_request("Alice", "alice2@example.com"),
+                import_request("erin", "alice@example.com"),
+                import_request("bob", "bob2@example.com"),
+                import_request("frank", "frank@example.com"),
+                ImportUserRequest {
+                    password: Some("short".into()),
+                    ..import_request("grace", "grace@example.com")
+                },
+            ])
+            .await
+            .unwrap();
+        assert_eq!(report.imported, 0);
+        assert_eq!(
+            report
+                .failures
+                .iter()
+                .map(|f| (f.row, f.user_id.as_str()))
+                .collect::<Vec<_>>(),
+            vec![
+                (1, "bad user"),
+                (2, "dave"),
+                (3, "alice"),
+                (4, "erin"),
+                (5, "bob"),
+                (7, "grace")
+            ]
+        );
+        assert_eq!(
+            report.failures[5].reason,
+            "The password must be at least 8 characters long"
+        );
+        // Nothing was imported, not even the valid rows.
+        assert_eq!(
+            get_user_names(&fixture.handler, None).await,
+            vec!["bob", "john", "nogroup", "patrick"]
+        );
+    }
+}
@@ -0,0 +1,256 @@
+use super::{
+    error::{DomainError, Result},
+    handler::UserBackendHandler,
+    model::{self, UserColumn, WebauthnCredentialsColumn},
+    sql_audit_log_handler::{self as audit, AuditChange},
+    sql_backend_handler::SqlBackendHandler,
+    types::{MfaType, UserId},
+    webauthn_handler::*,
+};
+use async_trait::async_trait;
+use sea_orm::{
+    sea_query::Expr, ActiveModelTrait, ActiveValue, ColumnTrait, EntityTrait, QueryFilter,
+    TransactionTrait,
+};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use tracing::{debug, instrument};
+use webauthn_rs::prelude::{
+    Passkey, PasskeyAuthentication, PasskeyRegistration, Url, Webauthn, WebauthnBuilder,
+};
+
+#[derive(Serialize, Deserialize)]
+struct ServerData<State> {
+    user_id: UserId,
+    state: State,
+}
+
+/// A signature counter that doesn't increase means that the authenticator was probably cloned.
+/// Authenticators that don't implement the counter always return 0.
+fn check_sign_count(stored: i64, received: u32) -> Result<()> {
+    if (stored != 0 || received != 0) && i64::from(received) <= stored {
+        return Err(DomainError::AuthenticationError(format!(
+            "WebAuthn signature counter went from {} to {}, the authenticator may have been cloned",
+            stored, received
+        )));
+    }
+    Ok(())
+}
+
+impl SqlBackendHandler {
+    fn get_webauthn(&self) -> Result<Webauthn> {
+        let origin = Url::parse(&self.config.http_url).map_err(|e| {
+            DomainError::InternalError(format!("Invalid http_url for WebAuthn: {}", e))
+        })?;
+        let rp_id = origin
+            .host_str()
+            .ok_or_else(|| DomainError::InternalError("http_url has no host".to_owned()))?
+            .to_owned();
+        Ok(WebauthnBuilder::new(&rp_id, &origin)?
+            .rp_name("LLDAP")
+            .build()?)
+    }
+
+    fn seal_server_data<State: Serialize>(&self, user_id: &UserId, state: State) -> Result<String> {
+        let server_data = serde_json::to_vec(&ServerData {
+            user_id: user_id.clone(),
+            state,
+        })
+        .map_err(|e| {
+            DomainError::InternalError(format!("Cannot serialize WebAuthn state: {}", e))
+        })?;
+        Ok(base64::encode(orion::aead::seal(
+            &self.get_orion_secret_key()?,
+            &server_data,
+        )?))
+    }
+
+    fn open_server_data<State: DeserializeOwned>(
+        &self,
+        server_data: &str,
+    ) -> Result<ServerData<State>> {
+        let server_data =
+            orion::aead::open(&self.get_orion_secret_key()?, &base64::decode(server_data)?)?;
+        serde_json::from_slice(&server_data)
+            .map_err(|e| DomainError::AuthenticationError(format!("Invalid WebAuthn state: {}", e)))
+    }
+
+    async fn get_credentials(
+        &self,
+        user_id: &UserId,
+    ) -> Result<Vec<(model::webauthn_credentials::Model, Passkey)>> {
+        model::WebauthnCredentials::find()
+            .filter(WebauthnCredentialsColumn::UserId.eq(user_id))
+            .all(&self.sql_pool)
+            .await?
+            .into_iter()
+            .map(|credential| {
+                let passkey = serde_json::from_slice(&credential.public_key).map_err(|_| {
+                    DomainError::InternalError(format!(
+                        "Corrupted WebAuthn credential for {}",
+                        user_id
+                    ))
+                })?;
+                Ok((credential, passkey))
+            })
+            .collect()
+    }
+}
+
+#[async_trait]
+impl WebauthnHandler for SqlBackendHandler {
+    #[instrument(skip_all, level = "debug", err)]
+    async fn registration_start(&self, user_id: &UserId) -> Result<RegistrationStartResponse> {
+        debug!(?user_id);
+        let user = self.get_user_details(user_id).await?;
+        let existing_credentials = self
+            .get_credentials(user_id)
+            .await?
+            .into_iter()
+            .map(|(_, passkey)| passkey.cred_id().clone())
+            .collect::<Vec<_>>();
+        let (challenge, state) = self.get_webauthn()?.start_passkey_registration(
+            uuid::Uuid::parse_str(user.uuid.as_str())
+                .map_err(|e| DomainError::InternalError(format!("Invalid user UUID: {}", e)))?,
+            user_id.as_str(),
+            user.display_name
+                .as_deref()
+                .unwrap_or_else(|| user_id.as_str()),
+            Some(existing_credentials),
+        )?;
+        Ok(RegistrationStartResponse {
+            server_data: self.seal_server_data(user_id, state)?,
+            challenge,
+        })
+    }
+
+    #[instrument(skip_all, level = "debug", err)]
+    async fn registration_finish(&self, request: RegistrationFinishRequest) -> Result<()> {
+        let ServerData { user_id, state } =
+            self.open_server_data::<PasskeyRegistration>(&request.server_data)?;
+        debug!(?user_id);
+        let passkey = self
+            .get_webauthn()?
+            .finish_passkey_registration(&request.credential, &state)?;
+        let new_credential = model::webauthn_credentials::ActiveModel {
+            credential_id: ActiveValue::Set(passkey.cred_id().0.clone()),
+            user_id: ActiveValue::Set(user_id.clone()),
+            public_key: ActiveValue::Set(serde_json::to_vec(&passkey).map_err(|e| {
+                DomainError::InternalError(format!("Cannot serialize WebAuthn credential: {}", e))
+            })?),
+            sign_count: ActiveValue::Set(0),
+            attestation: ActiveValue::Set(request.credential.response.attestation_object.0),
+            creation_date: ActiveValue::Set(chrono::Utc::now()),
+            ..Default::default()
+        };
+        let transaction = self.sql_pool.begin().await?;
+        new_credential.insert(&transaction).await?;
+        // Users that already use TOTP keep it as their main second factor.
+        model::User::update_many()
+            .col_expr(UserColumn::MfaType, Expr::value(MfaType::Webauthn))
+            .filter(ColumnTrait::eq(&UserColumn::UserId, &user_id))
+            .filter(UserColumn::MfaType.is_null())
+            .exec(&transaction)
+            .await?;
+        let events = self
+            .write_audit_log(
+                &transaction,
+                vec![AuditChange::user(&user_id, audit::WEBAUTHN_CREDENTIAL)],
+            )
+            .await?;
+        transaction.commit().await?;
+        events.publish();
+        Ok(())
+    }
+
+    #[instrument(skip_all, level = "debug", err)]
+    async fn assertion_start(&self, user_id: &UserId) -> Result<AssertionStartResponse> {
+        debug!(?user_id);
+        // Make sure the user hasn't been deleted.
+        self.get_user_details(user_id).await?;
+        let passkeys = self
+            .get_credentials(user_id)
+            .await?
+            .into_iter()
+            .map(|(_, passkey)| passkey)
+            .collect::<Vec<_>>();
+        if passkeys.is_empty() {
+            return Err(DomainError::AuthenticationError(format!(
+                "No WebAuthn credential registered for {}",
+                user_id
+            )));
+        }
+        let (challenge, state) = self
+            .get_webauthn()?
+            .start_passkey_authentication(&passkeys)?;
+        Ok(AssertionStartResponse {
+            server_data: self.seal_server_data(user_id, state)?,
+            challenge,
+        })
+    }
+
+    #[instrument(skip_all, level = "debug", err)]
+    async fn assertion_finish(&self, request: AssertionFinishRequest) -> Result<UserId> {
+        let ServerData { user_id, state } =
+            self.open_server_data::<PasskeyAuthentication>(&request.server_data)?;
+        debug!(?user_id);
+        let result = self
+            .get_webauthn()?
+            .finish_passkey_authentication(&request.credential, &state)?;
+        let (credential, mut passkey) = self
+            .get_credentials(&user_id)
+            .await?
+            .into_iter()
+            .find(|(_, passkey)| passkey.cred_id() == result.cred_id())
+            .ok_or_else(|| {
+                DomainError::AuthenticationError(format!(
+                    "Unknown WebAuthn credential for {}",
+                    user_id
+                ))
+            })?;
+        check_sign_count(credential.sign_count, result.counter())?;
+        passkey.update_credential(&result);
+        let updated_credential = model::webauthn_credentials::ActiveModel {
+            id: ActiveValue::Set(credential.id),
+            public_key: ActiveValue::Set(serde_json::to_vec(&passkey).map_err(|e| {
+                DomainError::InternalError(format!("Cannot serialize WebAuthn credential: {}", e))
+            })?),
+            sign_count: ActiveValue::Set(result.counter().into()),
+            ..Default::default()
+        };
+        updated_credential.update(&self.sql_pool).await?;
+        Ok(user_id)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::domain::sql_backend_handler::tests::*;
+
+    #[test]
+    fn test_check_sign_count() {
+        // Counter not supported by the authenticator.
+        check_sign_count(0, 0).unwrap();
+        check_sign_count(0, 1).unwrap();
+        check_sign_count(41, 42).unwrap();
+        check_sign_count(42, 42).unwrap_err();
+        check_sign_count(42, 3).unwrap_err();
+        check_sign_count(42, 0).unwrap_err();
+    }
+
+    #[tokio::test]
+    async fn test_assertion_without_credentials() {
+        let fixture = TestFixture::new().await;
+        assert!(matches!(
+            fixture.handler.assertion_start(&UserId::new("bob")).await,
+            Err(DomainError::AuthenticationError(_))
+        ));
+        assert!(matches!(
+            fixture
+                .handler
+                .assertion_start(&UserId::new("nobody"))
+                .await,
+            Err(DomainError::EntityNotFound(_))
+        ));
+    }
+}
@@ -0,0 +1,303 @@
+use super::{
+    error::{DomainError, Result},
+    model::{self, WebhookDeliveriesColumn},
+    sql_backend_handler::SqlBackendHandler,
+    sql_tables::DbConnection,
+    types::{DateTime, GroupId, UserId},
+};
+use sea_orm::{
+    sea_query::Expr, ActiveValue, ColumnTrait, ConnectionTrait, EntityTrait, QueryFilter,
+    QueryOrder, QuerySelect,
+};
+use serde::{Deserialize, Serialize};
+use tracing::{debug, instrument};
+
+/// A change that the webhook receivers are notified of.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum WebhookEvent {
+    UserCreated { user_id: UserId },
+    UserDeleted { user_id: UserId },
+    UserPasswordChanged { user_id: UserId },
+    GroupCreated { group_id: GroupId },
+    MembershipAdded { user_id: UserId, group_id: GroupId },
+    MembershipRemoved { user_id: UserId, group_id: GroupId },
+}
+
+impl WebhookEvent {
+    pub fn name(&self) -> &'static str {
+        match self {
+            WebhookEvent::UserCreated { .. } => "user_created",
+            WebhookEvent::UserDeleted { .. } => "user_deleted",
+            WebhookEvent::UserPasswordChanged { .. } => "user_password_changed",
+            WebhookEvent::GroupCreated { .. } => "group_created",
+            WebhookEvent::MembershipAdded { .. } => "membership_added",
+            WebhookEvent::MembershipRemoved { .. } => "membership_removed",
+        }
+    }
+
+    pub fn user_id(&self) -> Option<&UserId> {
+        match self {
+            WebhookEvent::UserCreated { user_id }
+            | WebhookEvent::UserDeleted { user_id }
+            | WebhookEvent::UserPasswordChanged { user_id }
+            | WebhookEvent::MembershipAdded { user_id, .. }
+            | WebhookEvent::MembershipRemoved { user_id, .. } => Some(user_id),
+            WebhookEvent::GroupCreated { .. } => None,
+        }
+    }
+
+    pub fn group_id(&self) -> Option<GroupId> {
+        match self {
+            WebhookEvent::GroupCreated { group_id }
+            | WebhookEvent::MembershipAdded { group_id, .. }
+            | WebhookEvent::MembershipRemoved { group_id, .. } => Some(*group_id),
+            WebhookEvent::UserCreated { .. }
+            | WebhookEvent::UserDeleted { .. }
+            | WebhookEvent::UserPasswordChanged { .. } => None,
+        }
+    }
+}
+
+/// The JSON body posted to the webhooks.
+#[derive(Serialize)]
+struct WebhookPayload<'a> {
+    #[serde(flatten)]
+    event: &'a WebhookEvent,
+    /// `None` for the changes made by the server itself.
+    actor: Option<&'a UserId>,
+    timestamp: DateTime,
+    /// The ID of the request that made the change, also in the audit log.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    correlation_id: Option<&'a str>,
+}
+
+impl SqlBackendHandler {
+    /// Queues a delivery of each event to each webhook. Pass the transaction of the change: the
+    /// events are only sent if the change is committed.
+    pub(crate) async fn enqueue_webhook_events(
+        &self,
+        connection: &impl ConnectionTrait,
+        events: &[WebhookEvent],
+    ) -> Result<()> {
+        let urls = &self.config.webhook_options.urls;
+        if events.is_empty() || urls.is_empty() {
+            return Ok(());
+        }
+        let now = chrono::Utc::now();
+        let actor = self.audit_actor.as_ref().map(|actor| &actor.user_id);
+        let mut deliveries = Vec::new();
+        for event in events {
+            debug!(?event);
+            let payload = serde_json::to_string(&WebhookPayload {
+                event,
+                actor,
+                timestamp: now,
+                correlation_id: self.correlation_id.as_deref(),
+            })
+            .map_err(|e| {
+                DomainError::InternalError(format!("Cannot serialize the webhook event: {}", e))
+            })?;
+            deliveries.extend(
+                urls.iter()
+                    .map(|url| model::webhook_deliveries::ActiveModel {
+                        url: ActiveValue::Set(url.clone()),
+                        event_type: ActiveValue::Set(event.name().to_owned()),
+                        payload: ActiveValue::Set(payload.clone()),
+                        attempts: ActiveValue::Set(0),
+                        next_attempt_at: ActiveValue::Set(now),
+                        creation_date: ActiveValue::Set(now),
+                        last_error: ActiveValue::Set(None),
+                        ..Default::default()
+                    }),
+            );
+        }
+        model::WebhookDeliveries::insert_many(deliveries)
+            .exec(connection)
+            .await?;
+        Ok(())
+    }
+}
+
+/// The deliveries due at `now`, oldest first.
+#[instrument(skip_all, level = "debug", err)]
+pub async fn get_due_webhook_deliveries(
+    pool: &DbConnection,
+    now: DateTime,
+    limit: u64,
+) -> Result<Vec<model::webhook_deliveries::Model>> {
+    Ok(model::WebhookDeliveries::find()
+        .filter(WebhookDeliveriesColumn::NextAttemptAt.lte(now))
+        .order_by_asc(WebhookDeliveriesColumn::Id)
+        .limit(limit)
+        .all(pool)
+        .await?)
+}
+
+/// Removes a delivery from the queue, once it is delivered or abandoned.
+pub async fn delete_webhook_delivery(pool: &DbConnection, id: i32) -> Result<()> {
+    model::WebhookDeliveries::delete_by_id(id)
+        .exec(pool)
+        .await?;
+    Ok(())
+}
+
+/// Records a failed attempt, and when to try again.
+pub async fn reschedule_webhook_delivery(
+    pool: &DbConnection,
+    id: i32,
+    next_attempt_at: DateTime,
+    error: String,
+) -> Result<()> {
+    model::WebhookDeliveries::update_many()
+        .col_expr(
+            WebhookDeliveriesColumn::Attempts,
+            Expr::col(WebhookDeliveriesColumn::Attempts).add(1),
+        )
+        .col_expr(
+            WebhookDeliveriesColumn::NextAttemptAt,
+            Expr::value(next_attempt_at),
+        )
+        .col_expr(WebhookDeliveriesColumn::LastError, Expr::value(error))
+        .filter(WebhookDeliveriesColumn::Id.eq(id))
+        .exec(pool)
+        .await?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        domain::{
+            handler::{GroupBackendHandler, UserBackendHandler},
+            sql_backend_handler::tests::*,
+        },
+        infra::configuration::WebhookOptions,
+    };
+
+    fn get_config_with_webhooks() -> crate::infra::configuration::Configuration {
+        let mut config = get_default_config();
+        config.webhook_options = WebhookOptions {
+            urls: vec![
+                "http://localhost/a".to_owned(),
+                "http://localhost/b".to_owned(),
+            ],
+            ..Default::default()
+        };
+        config
+    }
+
+    async fn get_all_deliveries(
+        handler: &SqlBackendHandler,
+    ) -> Vec<model::webhook_deliveries::Model> {
+        get_due_webhook_deliveries(&handler.sql_pool, chrono::Utc::now(), 1000)
+            .await
+            .unwrap()
+    }
+
+    #[test]
+    fn test_payload_format() {
+        let payload = serde_json::to_value(WebhookPayload {
+            event: &WebhookEvent::MembershipAdded {
+                user_id: UserId::new("bob"),
+                group_id: GroupId(3),
+            },
+            actor: Some(&UserId::new("admin")),
+            timestamp: chrono::DateTime::parse_from_rfc3339("2023-01-02T03:04:05Z")
+                .unwrap()
+                .into(),
+            correlation_id: Some("0f8fad5b-d9cb-469f-a165-70867728950e"),
+        })
+        .unwrap();
+        assert_eq!(
+            payload,
+            serde_json::json!({
+                "event": "membership_added",
+                "user_id": "bob",
+                "group_id": 3,
+                "actor": "admin",
+                "timestamp": "2023-01-02T03:04:05Z",
+                "correlation_id": "0f8fad5b-d9cb-469f-a165-70867728950e",
+            })
+        );
+    }
+
+    #[tokio::test]
+    async fn test_no_webhooks_configured() {
+        let fixture = TestFixture::new().await;
+        assert!(get_all_deliveries(&fixture.handler).await.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_events_are_queued() {
+        let fixture = TestFixture::with_config(get_config_with_webhooks()).await;
+        let deliveries = get_all_deliveries(&fixture.handler).await;
+        // 4 users, 3 groups and 4 memberships, to 2 URLs.
+        assert_eq!(deliveries.len(), 22);
+        assert_eq!(deliveries[0].event_type, "user_created");
+        assert_eq!(deliveries[0].url, "http://localhost/a");
+        assert_eq!(deliveries[1].url, "http://localhost/b");
+        assert_eq!(deliveries[0].payload, deliveries[1].payload);
+
+        fixture
+            .handler
+            .remove_user_from_group(&UserId::new("bob"), fixture.groups[0])
+            .await
+            .unwrap();
+        // A failed change doesn't notify anything.
+        fixture
+            .handler
+            .delete_group(GroupId(1000))
+            .await
+            .unwrap_err();
+        let deliveries = get_all_deliveries(&fixture.handler).await;
+        assert_eq!(deliveries.len(), 24);
+        assert_eq!(
+            serde_json::from_str::<WebhookEvent>(&deliveries[23].payload).unwrap(),
+            WebhookEvent::MembershipRemoved {
+                user_id: UserId::new("bob"),
+                group_id: fixture.groups[0],
+            }
+        );
+    }
+
+    #[tokio::test]
+    async fn test_reschedule_delivery() {
+        let fixture = TestFixture::with_config(get_config_with_webhooks()).await;
+        let delivery = get_all_deliveries(&fixture.handler).await.remove(0);
+        let later = chrono::Utc::now() + chrono::Duration::minutes(5);
+        reschedule_webhook_delivery(
+            &fixture.handler.sql_pool,
+            delivery.id,
+            later,
+            "Connection refused".to_owned(),
+        )
+        .await
+        .unwrap();
+        let deliveries = get_all_deliveries(&fixture.handler).await;
+        assert_eq!(deliveries.len(), 21);
+        assert!(deliveries.iter().all(|d| d.id != delivery.id));
+        let rescheduled = get_due_webhook_deliveries(&fixture.handler.sql_pool, later, 1000)
+            .await
+            .unwrap()
+            .into_iter()
+            .find(|d| d.id == delivery.id)
+            .unwrap();
+        assert_eq!(rescheduled.attempts, 1);
+        assert_eq!(
+            rescheduled.last_error.as_deref(),
+            Some("Connection refused")
+        );
+        delete_webhook_delivery(&fixture.handler.sql_pool, delivery.id)
+            .await
+            .unwrap();
+        assert_eq!(
+            get_due_webhook_deliveries(&fixture.handler.sql_pool, later, 1000)
+                .await
+                .unwrap()
+                .len(),
+            21
+        );
+    }
+}
@@ -0,0 +1,119 @@
+use crate::domain::{error::Result, types::DateTime};
+use async_trait::async_trait;
+use serde::{Deserialize, Serialize};
+
+/// The multi-valued attributes of a `sudoRole`, as defined by the sudo-ldap schema.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub enum SudoAttribute {
+    User,
+    Host,
+    Command,
+    Option,
+}
+
+impl SudoAttribute {
+    pub const ALL: [SudoAttribute; 4] = [
+        SudoAttribute::User,
+        SudoAttribute::Host,
+        SudoAttribute::Command,
+        SudoAttribute::Option,
+    ];
+
+    /// The name of the LDAP attribute, also stored in the database.
+    pub fn ldap_name(self) -> &'static str {
+        match self {
+            SudoAttribute::User => "sudoUser",
+            SudoAttribute::Host => "sudoHost",
+            SudoAttribute::Command => "sudoCommand",
+            SudoAttribute::Option => "sudoOption",
+        }
+    }
+
+    /// Case-insensitive, like the LDAP attribute names.
+    pub fn from_ldap_name(name: &str) -> Option<Self> {
+        Self::ALL
+            .into_iter()
+            .find(|attribute| attribute.ldap_name().eq_ignore_ascii_case(name))
+    }
+}
+
+/// A sudoers rule. The values are kept as given, in order: `ALL`, the `%group` and `#uid` forms
+/// and the negations are interpreted by sudo itself.
+#[derive(PartialEq, Eq, Debug, Serialize, Deserialize, Clone)]
+pub struct SudoRole {
+    pub name: String,
+    pub users: Vec<String>,
+    pub hosts: Vec<String>,
+    pub commands: Vec<String>,
+    pub options: Vec<String>,
+    pub creation_date: DateTime,
+    pub modified_at: DateTime,
+}
+
+impl SudoRole {
+    pub fn values(&self, attribute: SudoAttribute) -> &[String] {
+        match attribute {
+            SudoAttribute::User => &self.users,
+            SudoAttribute::Host => &self.hosts,
+            SudoAttribute::Command => &self.commands,
+            SudoAttribute::Option => &self.options,
+        }
+    }
+}
+
+/// Creates a role, or replaces all the values of an existing one.
+#[derive(PartialEq, Eq, Debug, Serialize, Deserialize, Clone, Default)]
+pub struct SudoRoleRequest {
+    pub name: String,
+    pub users: Vec<String>,
+    pub hosts: Vec<String>,
+    pub commands: Vec<String>,
+    pub options: Vec<String>,
+}
+
+impl SudoRoleRequest {
+    pub fn values(&self, attribute: SudoAttribute) -> &[String] {
+        match attribute {
+            SudoAttribute::User => &self.users,
+            SudoAttribute::Host => &self.hosts,
+            SudoAttribute::Command => &self.commands,
+            SudoAttribute::Option => &self.options,
+        }
+    }
+}
+
+/// An LDAP substring match: the value starts with `initial`, then contains each of `any` in
+/// order, and ends with `final_`.
+#[derive(PartialEq, Eq, Debug, Serialize, Deserialize, Clone, Default)]
+pub struct SubstringPattern {
+    pub initial: Option<String>,
+    pub any: Vec<String>,
+    pub final_: Option<String>,
+}
+
+#[derive(PartialEq, Eq, Debug, Serialize, Deserialize, Clone)]
+pub enum SudoRoleRequestFilter {
+    And(Vec<SudoRoleRequestFilter>),
+    Or(Vec<SudoRoleRequestFilter>),
+    Not(Box<SudoRoleRequestFilter>),
+    /// Case-insensitive, like the `cn` of the role.
+    Name(String),
+    /// One of the values is exactly this one.
+    Equality(SudoAttribute, String),
+    /// The role has at least one value for the attribute.
+    Present(SudoAttribute),
+    Substring(SudoAttribute, SubstringPattern),
+}
+
+#[async_trait]
+pub trait SudoRoleBackendHandler {
+    /// Sorted by name.
+    async fn list_sudo_roles(
+        &self,
+        filters: Option<SudoRoleRequestFilter>,
+    ) -> Result<Vec<SudoRole>>;
+    async fn create_sudo_role(&self, request: SudoRoleRequest) -> Result<()>;
+    /// Replaces all the values of the role.
+    async fn update_sudo_role(&self, request: SudoRoleRequest) -> Result<()>;
+    async fn delete_sudo_role(&self, name: &str) -> Result<()>;
+}
@@ -0,0 +1,365 @@
+//! Time-based one-time passwords (RFC 6238), with the parameters of each enrollment.
+use anyhow::{anyhow, bail, Result};
+use hmac::{Hmac, Mac, NewMac};
+use serde::{Deserialize, Serialize};
+use sha1::Sha1;
+use sha2::{Sha256, Sha512};
+
+const BASE32_ALPHABET: &[u8; 32] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZ234567";
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum TotpAlgorithm {
+    /// The only one that all the authenticator apps support.
+    #[default]
+    Sha1,
+    Sha256,
+    Sha512,
+}
+
+impl TotpAlgorithm {
+    /// As in the `otpauth://` URIs.
+    pub fn as_str(self) -> &'static str {
+        match self {
+            TotpAlgorithm::Sha1 => "SHA1",
+            TotpAlgorithm::Sha256 => "SHA256",
+            TotpAlgorithm::Sha512 => "SHA512",
+        }
+    }
+
+    /// The size of the hash, the recommended size of the secrets.
+    fn output_size(self) -> usize {
+        match self {
+            TotpAlgorithm::Sha1 => 20,
+            TotpAlgorithm::Sha256 => 32,
+            TotpAlgorithm::Sha512 => 64,
+        }
+    }
+
+    fn hmac(self, key: &[u8], message: &[u8]) -> Vec<u8> {
+        const VALID_KEY: &str = "HMAC accepts keys of any size";
+        match self {
+            TotpAlgorithm::Sha1 => {
+                let mut mac = Hmac::<Sha1>::new_varkey(key).expect(VALID_KEY);
+                mac.update(message);
+                mac.finalize().into_bytes().to_vec()
+            }
+            TotpAlgorithm::Sha256 => {
+                let mut mac = Hmac::<Sha256>::new_varkey(key).expect(VALID_KEY);
+                mac.update(message);
+                mac.finalize().into_bytes().to_vec()
+            }
+            TotpAlgorithm::Sha512 => {
+                let mut mac = Hmac::<Sha512>::new_varkey(key).expect(VALID_KEY);
+                mac.update(message);
+                mac.finalize().into_bytes().to_vec()
+            }
+        }
+    }
+}
+
+impl std::str::FromStr for TotpAlgorithm {
+    type Err = anyhow::Error;
+    fn from_str(s: &str) -> Result<Self> {
+        Ok(match s.to_ascii_uppercase().as_str() {
+            "SHA1" => TotpAlgorithm::Sha1,
+            "SHA256" => TotpAlgorithm::Sha256,
+            "SHA512" => TotpAlgorithm::Sha512,
+            _ => bail!("Unknown TOTP algorithm: {}", s),
+        })
+    }
+}
+
+/// How the codes of a secret are computed. Stored with each secret, so that changing the
+/// configured parameters doesn't break the existing enrollments.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct TotpParameters {
+    pub algorithm: TotpAlgorithm,
+    pub digits: u32,
+    pub period_seconds: u64,
+}
+
+/// The parameters of RFC 6238, assumed for the secrets enrolled before they were stored.
+impl Default for TotpParameters {
+    fn default() -> Self {
+        Self {
+            algorithm: TotpAlgorithm::Sha1,
+            digits: 6,
+            period_seconds: 30,
+        }
+    }
+}
+
+impl TotpParameters {
+    pub fn validate(&self) -> Result<()> {
+        if !(6..=8).contains(&self.digits) {
+            bail!(
+                "The TOTP codes must have 6 to 8 digits, not {}",
+                self.digits
+            );
+        }
+        if self.period_seconds == 0 {
+            bail!("The TOTP period must be positive");
+        }
+        Ok(())
+    }
+
+    /// `sha1:6:30`, as stored in the DB.
+    pub fn to_stored(&self) -> String {
+        format!(
+            "{}:{}:{}",
+            self.algorithm.as_str().to_ascii_lowercase(),
+            self.digits,
+            self.period_seconds
+        )
+    }
+
+    pub fn from_stored(stored: &str) -> Result<Self> {
+        let mut parts = stored.split(':');
+        let (algorithm, digits, period_seconds) = match (parts.next(), parts.next(), parts.next()) {
+            (Some(algorithm), Some(digits), Some(period_seconds)) if parts.next().is_none() => {
+                (algorithm, digits, period_seconds)
+            }
+            _ => bail!("Malformed TOTP parameters `{}`", stored),
+        };
+        let parameters = Self {
+            algorithm: algorithm.parse()?,
+            digits: digits.parse()?,
+            period_seconds: period_seconds.parse()?,
+        };
+        parameters.validate()?;
+        Ok(parameters)
+    }
+
+    /// A new random secret, of the size of the hash.
+    pub fn generate_secret(&self) -> Vec<u8> {
+        use rand::RngCore;
+        let mut secret = vec![0; self.algorithm.output_size()];
+        rand::rngs::OsRng.fill_bytes(&mut secret);
+        secret
+    }
+
+    /// The code of the period that contains the time, in seconds since the epoch (RFC 4226).
+    pub fn code_at(&self, secret: &[u8], unix_time: u64) -> String {
+        let counter = unix_time / self.period_seconds;
+        let hash = self.algorithm.hmac(secret, &counter.to_be_bytes());
+        let offset = usize::from(hash[hash.len() - 1] & 0xf);
+        let truncated = u32::from_be_bytes([
+            hash[offset] & 0x7f,
+            hash[offset + 1],
+            hash[offset + 2],
+            hash[offset + 3],
+        ]);
+        format!(
+            "{:0width$}",
+            truncated % 10u32.pow(self.digits),
+            width = self.digits as usize
+        )
+    }
+
+    /// Accepts the codes of the current period, and of the `drift_steps` periods before and
+    /// after it, for the clocks that drift.
+    pub fn verify(&self, secret: &[u8], code: &str, unix_time: u64, drift_steps: u64) -> bool {
+        self.matching_counter(secret, code, unix_time, drift_steps)
+            .is_some()
+    }
+
+    /// Like `verify`, but returns the counter (the time step) of the code, to refuse it if it
+    /// was already used.
+    pub fn matching_counter(
+        &self,
+        secret: &[u8],
+        code: &str,
+        unix_time: u64,
+        drift_steps: u64,
+    ) -> Option<u64> {
+        let code = code.trim();
+        let current_step = unix_time / self.period_seconds;
+        (current_step.saturating_sub(drift_steps)..=current_step.saturating_add(drift_steps))
+            .find(|step| self.code_at(secret, step * self.period_seconds) == code)
+    }
+
+    /// The URI of the QR code scanned by the authenticator apps.
+    pub fn otpauth_uri(&self, issuer: &str, account: &str, secret: &[u8]) -> String {
+        format!(
+            "otpauth://totp/{}:{}?secret={}&issuer={}&algorithm={}&digits={}&period={}",
+            percent_encode(issuer),
+            percent_encode(account),
+            base32_encode(secret),
+            percent_encode(issuer),
+            self.algorithm.as_str(),
+            self.digits,
+            self.period_seconds
+        )
+    }
+}
+
+/// Keeps only the unreserved characters of RFC 3986.
+fn percent_encode(text: &str) -> String {
+    text.bytes()
+        .map(|b| match b {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' => {
+                char::from(b).to_string()
+            }
+            _ => format!("%{:02X}", b),
+        })
+        .collect()
+}
+
+/// RFC 4648, without the padding, as the authenticator apps expect the secrets.
+pub fn base32_encode(data: &[u8]) -> String {
+    let mut encoded = String::new();
+    let mut buffer = 0u32;
+    let mut bits = 0;
+    for byte in data {
+        buffer = (buffer << 8) | u32::from(*byte);
+        bits += 8;
+        while bits >= 5 {
+            bits -= 5;
+            encoded.push(BASE32_ALPHABET[((buffer >> bits) & 0x1f) as usize] as char);
+        }
+    }
+    if bits > 0 {
+        encoded.push(BASE32_ALPHABET[((buffer << (5 - bits)) & 0x1f) as usize] as char);
+    }
+    encoded
+}
+
+/// Ignores the case, the spaces and the padding, that the users may add.
+pub fn base32_decode(encoded: &str) -> Result<Vec<u8>> {
+    let mut decoded = Vec::new();
+    let mut buffer = 0u32;
+    let mut bits = 0;
+    for c in encoded.chars().filter(|c| *c != '=' && !c.is_whitespace()) {
+        let value = BASE32_ALPHABET
+            .iter()
+            .position(|a| char::from(*a) == c.to_ascii_uppercase())
+            .ok_or_else(|| anyhow!("Invalid base32 character `{}`", c))?;
+        buffer = (buffer << 5) | value as u32;
+        bits += 5;
+        if bits >= 8 {
+            bits -= 8;
+            decoded.push((buffer >> bits) as u8);
+        }
+    }
+    Ok(decoded)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// The test vectors of RFC 6238: each algorithm has its own seed.
+    #[test]
+    fn test_rfc_6238_codes() {
+        let sha1_seed = b"12345678901234567890".to_vec();
+        let sha256_seed = b"12345678901234567890123456789012".to_vec();
+        let sha512_seed =
+            b"1234567890123456789012345678901234567890123456789012345678901234".to_vec();
+        let cases = [
+            (59, TotpAlgorithm::Sha1, "94287082"),
+            (59, TotpAlgorithm::Sha256, "46119246"),
+            (59, TotpAlgorithm::Sha512, "90693936"),
+            (1111111109, TotpAlgorithm::Sha1, "07081804"),
+            (1111111109, TotpAlgorithm::Sha256, "68084774"),
+            (1111111109, TotpAlgorithm::Sha512, "25091201"),
+            (20000000000, TotpAlgorithm::Sha1, "65353130"),
+            (20000000000, TotpAlgorithm::Sha256, "77737706"),
+            (20000000000, TotpAlgorithm::Sha512, "47863826"),
+        ];
+        for (time, algorithm, expected) in cases {
+            let seed = match algorithm {
+                TotpAlgorithm::Sha1 => &sha1_seed,
+                TotpAlgorithm::Sha256 => &sha256_seed,
+                TotpAlgorithm::Sha512 => &sha512_seed,
+            };
+            let parameters = TotpParameters {
+                algorithm,
+                digits: 8,
+                period_seconds: 30,
+            };
+            assert_eq!(parameters.code_at(seed, time), expected, "{:?}", algorithm);
+            // The 6-digit codes are the last digits of the same number.
+            let parameters = TotpParameters {
+                digits: 6,
+                ..parameters
+            };
+            assert_eq!(parameters.code_at(seed, time), &expected[2..]);
+        }
+    }
+
+    #[test]
+    fn test_verify_drift() {
+        for (algorithm, digits, period_seconds) in [
+            (TotpAlgorithm::Sha1, 6, 30),
+            (TotpAlgorithm::Sha256, 8, 30),
+            (TotpAlgorithm::Sha512, 7, 60),
+        ] {
+            let parameters = TotpParameters {
+                algorithm,
+                digits,
+                period_seconds,
+            };
+            let secret = parameters.generate_secret();
+            assert_eq!(secret.len(), algorithm.output_size());
+            let now = 1_700_000_000;
+            let code = parameters.code_at(&secret, now);
+            assert_eq!(code.len(), digits as usize);
+            assert!(parameters.verify(&secret, &code, now, 0));
+            let previous = now - period_seconds;
+            let next = now + period_seconds;
+            assert!(parameters.verify(&secret, &code, previous, 1));
+            assert!(parameters.verify(&secret, &code, next, 1));
+            assert!(!parameters.verify(&secret, &code, next, 0));
+            assert!(!parameters.verify(&secret, &code, now + 2 * period_seconds, 1));
+            assert!(parameters.verify(&secret, &code, now + 2 * period_seconds, 2));
+            assert!(parameters.verify(&secret, &format!(" {} ", code), now, 0));
+            assert_eq!(
+                parameters.matching_counter(&secret, &code, next, 1),
+                Some(now / period_seconds)
+            );
+        }
+    }
+
+    #[test]
+    fn test_stored_parameters() {
+        let parameters = TotpParameters {
+            algorithm: TotpAlgorithm::Sha256,
+            digits: 8,
+            period_seconds: 60,
+        };
+        assert_eq!(parameters.to_stored(), "sha256:8:60");
+        assert_eq!(
+            TotpParameters::from_stored("sha256:8:60").unwrap(),
+            parameters
+        );
+        TotpParameters::from_stored("sha256:8").unwrap_err();
+        TotpParameters::from_stored("md5:6:30").unwrap_err();
+        TotpParameters::from_stored("sha1:9:30").unwrap_err();
+        TotpParameters::from_stored("sha1:6:0").unwrap_err();
+    }
+
+    #[test]
+    fn test_otpauth_uri() {
+        let parameters = TotpParameters {
+            algorithm: TotpAlgorithm::Sha256,
+            digits: 8,
+            period_seconds: 60,
+        };
+        assert_eq!(
+            parameters.otpauth_uri("My LLDAP", "bob", b"Hello!\xde\xad\xbe\xef"),
+            "otpauth://totp/My%20LLDAP:bob?secret=JBSWY3DPEHPK3PXP&issuer=My%20LLDAP\
+             &algorithm=SHA256&digits=8&period=60"
+        );
+    }
+
+    #[test]
+    fn test_base32() {
+        for data in [&b""[..], b"f", b"fo", b"foo", b"foob", b"fooba", b"foobar"] {
+            assert_eq!(base32_decode(&base32_encode(data)).unwrap(), data);
+        }
+        assert_eq!(base32_encode(b"foobar"), "MZXW6YTBOI");
+        assert_eq!(base32_decode("mzxw 6ytb oi======").unwrap(), b"foobar");
+        base32_decode("MZXW1").unwrap_err();
+    }
+}
@@ -0,0 +1,19 @@
+use crate::domain::{error::Result, types::UserId};
+use async_trait::async_trait;
+
+/// A new TOTP secret, to show once to the user, e.g. as a QR code of the URI.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TotpEnrollment {
+    /// Base32, for the authenticator apps that can't scan the QR code.
+    pub secret: String,
+    pub uri: String,
+}
+
+#[async_trait]
+pub trait TotpHandler: Clone + Send {
+    /// Replaces the secret of the user with a new one, with the configured parameters. They are
+    /// stored with it: changing the configuration doesn't invalidate the existing enrollments.
+    async fn enroll_totp(&self, user_id: &UserId) -> Result<TotpEnrollment>;
+    /// Checks the code with the parameters of the user's enrollment.
+    async fn verify_totp_code(&self, user_id: &UserId, code: &str) -> Result<()>;
+}
@@ -0,0 +1,272 @@
+use super::{
+    model::{self, UserColumn},
+    sql_tables::DbConnection,
+    types::UserId,
+};
+use crate::infra::configuration::write_to_readonly_file;
+use anyhow::{anyhow, bail, Context, Result};
+use orion::aead::SecretKey;
+use sea_orm::{
+    sea_query::Expr, ColumnTrait, EntityTrait, FromQueryResult, QueryFilter, QuerySelect,
+};
+use tracing::{info, instrument};
+
+/// Marks the encrypted secrets: the plaintext ones are base32, they can't contain a colon.
+pub(crate) const ENCRYPTED_PREFIX: &str = "enc:";
+
+/// The keys that encrypt the TOTP secrets at rest, one `key_id:base64_key` per line. The last one
+/// encrypts the secrets, the previous ones are only kept to decrypt the secrets that haven't been
+/// re-encrypted yet: to rotate the key, append a new line.
+pub struct TotpSecretKeys {
+    keys: Vec<(String, SecretKey)>,
+}
+
+/// Only the key IDs: the keys stay out of the logs.
+impl std::fmt::Debug for TotpSecretKeys {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("TotpSecretKeys")
+            .field(
+                "key_ids",
+                &self.keys.iter().map(|(id, _)| id).collect::<Vec<_>>(),
+            )
+            .finish()
+    }
+}
+
+impl TotpSecretKeys {
+    /// Reads the keys, or generates the file with a first key if it doesn't exist.
+    pub fn from_file(file_path: &str) -> Result<Self> {
+        let path = std::path::Path::new(file_path);
+        if path.exists() {
+            let contents = std::fs::read_to_string(path)
+                .context(format!("Could not read TOTP key file `{}`", file_path))?;
+            Self::parse(&contents).context(format!("Invalid TOTP key file `{}`", file_path))
+        } else {
+            let key = SecretKey::default();
+            let contents = format!("1:{}\n", base64::encode(key.unprotected_as_bytes()));
+            write_to_readonly_file(path, contents.as_bytes()).context(format!(
+                "Could not write the generated TOTP key to file `{}`",
+                file_path
+            ))?;
+            Ok(Self {
+                keys: vec![("1".to_owned(), key)],
+            })
+        }
+    }
+
+    fn parse(contents: &str) -> Result<Self> {
+        let keys = contents
+            .lines()
+            .map(str::trim)
+            .filter(|line| !line.is_empty())
+            .map(|line| {
+                let (key_id, key) = line
+                    .split_once(':')
+                    .ok_or_else(|| anyhow!("Expected `key_id:base64_key`"))?;
+                if key_id.is_empty() {
+                    bail!("Empty key ID");
+                }
+                let key = SecretKey::from_slice(&base64::decode(key)?)
+                    .map_err(|_| anyhow!("Invalid key `{}`", key_id))?;
+                Ok((key_id.to_owned(), key))
+            })
+            .collect::<Result<Vec<_>>>()?;
+        if keys.is_empty() {
+            bail!("No key");
+        }
+        Ok(Self { keys })
+    }
+
+    fn current_key(&self) -> &(String, SecretKey) {
+        self.keys.last().unwrap()
+    }
+
+    /// `enc:<key_id>:<base64 of the nonce, ciphertext and tag>`.
+    pub fn encrypt(&self, secret: &str) -> Result<String> {
+        let (key_id, key) = self.current_key();
+        let ciphertext = orion::aead::seal(key, secret.as_bytes())?;
+        Ok(format!(
+            "{}{}:{}",
+            ENCRYPTED_PREFIX,
+            key_id,
+            base64::encode(ciphertext)
+        ))
+    }
+
+    /// Decrypts a stored secret. The ones that are not encrypted yet are returned as they are.
+    pub fn decrypt(&self, stored: &str) -> Result<String> {
+        let encrypted = match stored.strip_prefix(ENCRYPTED_PREFIX) {
+            Some(encrypted) => encrypted,
+            None => return Ok(stored.to_owned()),
+        };
+        let (key_id, ciphertext) = encrypted
+            .split_once(':')
+            .ok_or_else(|| anyhow!("Malformed encrypted TOTP secret"))?;
+        let (_, key) = self
+            .keys
+            .iter()
+            .find(|(id, _)| id == key_id)
+            .ok_or_else(|| anyhow!("Unknown TOTP key `{}`", key_id))?;
+        let secret = orion::aead::open(key, &base64::decode(ciphertext)?)
+            .map_err(|_| anyhow!("Could not decrypt the TOTP secret"))?;
+        Ok(String::from_utf8(secret)?)
+    }
+
+    /// Whether the secret is in plaintext, or encrypted with an older key.
+    fn needs_encryption(&self, stored: &str) -> bool {
+        let (key_id, _) = self.current_key();
+        !stored
+            .strip_prefix(ENCRYPTED_PREFIX)
+            .and_then(|encrypted| encrypted.split_once(':'))
+            .map(|(id, _)| id == key_id)
+            .unwrap_or(false)
+    }
+}
+
+/// Encrypts the plaintext TOTP secrets, and re-encrypts the ones of the previous keys with the
+/// current one. Each secret is updated on its own, only if it didn't change in the meantime, so
+/// an interrupted run is simply resumed by the next one.
+#[instrument(skip_all, level = "debug", err)]
+pub async fn encrypt_totp_secrets(pool: &DbConnection, keys: &TotpSecretKeys) -> Result<usize> {
+    #[derive(FromQueryResult)]
+    struct TotpSecret {
+        user_id: UserId,
+        totp_secret: Option<String>,
+    }
+    let secrets = model::User::find()
+        .select_only()
+        .column(UserColumn::UserId)
+        .column(UserColumn::TotpSecret)
+        .filter(UserColumn::TotpSecret.is_not_null())
+        .into_model::<TotpSecret>()
+        .all(pool)
+        .await?;
+    let mut count = 0;
+    for TotpSecret {
+        user_id,
+        totp_secret,
+    } in secrets
+    {
+        let stored = match totp_secret {
+            Some(stored) if keys.needs_encryption(&stored) => stored,
+            _ => continue,
+        };
+        let encrypted = keys
+            .encrypt(&keys.decrypt(&stored)?)
+            .with_context(|| format!("while encrypting the TOTP secret of '{}'", user_id))?;
+        let result = model::User::update_many()
+            .col_expr(UserColumn::TotpSecret, Expr::value(encrypted))
+            .filter(ColumnTrait::eq(&UserColumn::UserId, &user_id))
+            .filter(ColumnTrait::eq(&UserColumn::TotpSecret, stored))
+            .exec(pool)
+            .await?;
+        count += result.rows_affected as usize;
+    }
+    if count > 0 {
+        info!("Encrypted {} TOTP secrets", count);
+    }
+    Ok(count)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::domain::sql_backend_handler::{tests::*, SqlBackendHandler};
+
+    fn make_keys(key_ids: &[&str]) -> TotpSecretKeys {
+        TotpSecretKeys {
+            keys: key_ids
+                .iter()
+                .map(|id| (id.to_string(), SecretKey::default()))
+                .collect(),
+        }
+    }
+
+    async fn get_totp_secret(pool: &DbConnection, user_id: &str) -> String {
+        model::User::find_by_id(UserId::new(user_id))
+            .one(pool)
+            .await
+            .unwrap()
+            .unwrap()
+            .totp_secret
+            .unwrap()
+    }
+
+    async fn set_totp_secret(pool: &DbConnection, user_id: &str, secret: &str) {
+        model::User::update_many()
+            .col_expr(UserColumn::TotpSecret, Expr::value(secret))
+            .filter(ColumnTrait::eq(&UserColumn::UserId, UserId::new(user_id)))
+            .exec(pool)
+            .await
+            .unwrap();
+    }
+
+    #[test]
+    fn test_parse_keys() {
+        let keys = TotpSecretKeys::parse(&format!(
+            "old:{}\n\nnew:{}\n",
+            base64::encode([1; 32]),
+            base64::encode([2; 32])
+        ))
+        .unwrap();
+        assert_eq!(keys.current_key().0, "new");
+        assert_eq!(
+            format!("{:?}", keys),
+            r#"TotpSecretKeys { key_ids: ["old", "new"] }"#
+        );
+        TotpSecretKeys::parse("").unwrap_err();
+        TotpSecretKeys::parse("no_key_id").unwrap_err();
+        TotpSecretKeys::parse(&format!("id:{}", base64::encode([1; 8]))).unwrap_err();
+    }
+
+    #[test]
+    fn test_encrypt_and_decrypt() {
+        let keys = make_keys(&["1"]);
+        let encrypted = keys.encrypt("JBSWY3DPEHPK3PXP").unwrap();
+        assert!(encrypted.starts_with("enc:1:"));
+        assert_eq!(keys.decrypt(&encrypted).unwrap(), "JBSWY3DPEHPK3PXP");
+        // Not encrypted yet.
+        assert_eq!(
+            keys.decrypt("JBSWY3DPEHPK3PXP").unwrap(),
+            "JBSWY3DPEHPK3PXP"
+        );
+        // Another key with the same ID.
+        make_keys(&["1"]).decrypt(&encrypted).unwrap_err();
+        make_keys(&["2"]).decrypt(&encrypted).unwrap_err();
+    }
+
+    #[tokio::test]
+    async fn test_encrypt_totp_secrets() {
+        let sql_pool = get_initialized_db().await;
+        let handler = SqlBackendHandler::new(get_default_config(), sql_pool.clone());
+        insert_user_no_password(&handler, "bob").await;
+        insert_user_no_password(&handler, "patrick").await;
+        insert_user_no_password(&handler, "john").await;
+        set_totp_secret(&sql_pool, "bob", "JBSWY3DPEHPK3PXP").await;
+        let mut keys = make_keys(&["1"]);
+        // Patrick's was encrypted by a previous, interrupted run.
+        set_totp_secret(
+            &sql_pool,
+            "patrick",
+            &keys.encrypt("KRSXG5CTMVRXEZLU").unwrap(),
+        )
+        .await;
+        assert_eq!(encrypt_totp_secrets(&sql_pool, &keys).await.unwrap(), 1);
+        let bob_secret = get_totp_secret(&sql_pool, "bob").await;
+        assert!(bob_secret.starts_with("enc:1:"));
+        assert_eq!(keys.decrypt(&bob_secret).unwrap(), "JBSWY3DPEHPK3PXP");
+        assert_eq!(encrypt_totp_secrets(&sql_pool, &keys).await.unwrap(), 0);
+        // After a rotation, everything is re-encrypted with the new key.
+        keys.keys.push(("2".to_owned(), SecretKey::default()));
+        assert_eq!(encrypt_totp_secrets(&sql_pool, &keys).await.unwrap(), 2);
+        let patrick_secret = get_totp_secret(&sql_pool, "patrick").await;
+        assert!(patrick_secret.starts_with("enc:2:"));
+        assert_eq!(keys.decrypt(&patrick_secret).unwrap(), "KRSXG5CTMVRXEZLU");
+        keys.keys.remove(0);
+        assert_eq!(
+            keys.decrypt(&get_totp_secret(&sql_pool, "bob").await)
+                .unwrap(),
+            "JBSWY3DPEHPK3PXP"
+        );
+    }
+}
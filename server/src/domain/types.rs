@@ -1,6 +1,9 @@
 use sea_orm::{
     entity::IntoActiveValue,
-    sea_query::{value::ValueType, ArrayType, ColumnType, Nullable, ValueTypeErr},
+    sea_query::{
+        value::ValueType, Alias, ArrayType, ColumnType, DynIden, Expr, Nullable, SeaRc, SimpleExpr,
+        ValueTypeErr,
+    },
     DbErr, FromQueryResult, QueryResult, TryFromU64, TryGetError, TryGetable, Value,
 };
 use serde::{Deserialize, Serialize};
@@ -9,6 +12,13 @@ pub use super::model::{GroupColumn, UserColumn};
 
 pub type DateTime = chrono::DateTime<chrono::Utc>;
 
+/// The members of this group are LLDAP administrators.
+pub const ADMIN_GROUP_NAME: &str = "lldap_admin";
+/// The members of this group can change the passwords of the non-admin users.
+pub const PASSWORD_MANAGER_GROUP_NAME: &str = "lldap_password_manager";
+/// The members of this group can read everything, but not change anything.
+pub const READONLY_GROUP_NAME: &str = "lldap_strict_readonly";
+
 #[derive(PartialEq, Hash, Eq, Clone, Debug, Default, Serialize, Deserialize)]
 #[serde(try_from = "&str")]
 pub struct Uuid(String);
@@ -24,6 +34,11 @@ impl Uuid {
         )
     }
 
+    /// A random (v4) UUID, for when the name and date are not enough to make it unique.
+    pub fn random() -> Self {
+        Uuid(uuid::Uuid::new_v4().to_string())
+    }
+
     pub fn as_str(&self) -> &str {
         &self.0
     }
@@ -31,6 +46,28 @@ impl Uuid {
     pub fn into_string(self) -> String {
         self.0
     }
+
+    /// The value for a raw query on a `uuid` column of the users or groups, cast to the native
+    /// type on PostgreSQL. The entities do the same through `uuid_column_type`.
+    pub(crate) fn into_expr(self) -> SimpleExpr {
+        Expr::val(self).as_enum(uuid_type_name())
+    }
+}
+
+/// The native type of the UUID columns on PostgreSQL.
+pub(crate) fn uuid_type_name() -> DynIden {
+    SeaRc::new(Alias::new("uuid"))
+}
+
+/// The UUIDs of the users and groups are stored in a native `uuid` column on PostgreSQL, and as
+/// strings elsewhere. Declaring the column as an enum of that type makes sea-orm send the values
+/// as `CAST($1 AS uuid)` and read them as `CAST("uuid" AS text)` on PostgreSQL only, so the values
+/// are always strings, whatever the backend.
+pub(crate) fn uuid_column_type() -> sea_orm::ColumnType {
+    sea_orm::ColumnType::Enum {
+        name: uuid_type_name(),
+        variants: Vec::new(),
+    }
 }
 
 impl<'a> std::convert::TryFrom<&'a str> for Uuid {
@@ -48,12 +85,20 @@ impl std::string::ToString for Uuid {
 
 impl TryGetable for Uuid {
     fn try_get(res: &QueryResult, pre: &str, col: &str) -> std::result::Result<Self, TryGetError> {
-        Ok(Uuid(String::try_get(res, pre, col)?))
+        match String::try_get(res, pre, col) {
+            Ok(uuid) => Ok(Uuid(uuid)),
+            // Native uuid column.
+            Err(TryGetError::DbErr(_)) => Ok(Uuid(uuid::Uuid::try_get(res, pre, col)?.to_string())),
+            Err(e) => Err(e),
+        }
     }
 }
 
 impl ValueType for Uuid {
     fn try_from(v: Value) -> Result<Self, ValueTypeErr> {
+        if let Value::Uuid(Some(uuid)) = v {
+            return Ok(Uuid(uuid.to_string()));
+        }
         <Self as std::convert::TryFrom<_>>::try_from(
             <std::string::String as sea_orm::sea_query::ValueType>::try_from(v)?.as_str(),
         )
@@ -75,7 +120,7 @@ impl ValueType for Uuid {
 
 impl From<Uuid> for Value {
     fn from(uuid: Uuid) -> Self {
-        uuid.as_str().into()
+        (&uuid).into()
     }
 }
 
@@ -93,27 +138,76 @@ macro_rules! uuid {
     };
 }
 
-#[derive(PartialEq, Eq, Clone, Debug, Default, Serialize, Deserialize)]
-#[serde(from = "String")]
-pub struct UserId(String);
+/// ID of a user. User IDs are case-insensitive: they are compared, stored and looked up in
+/// lowercase, so "Alice" and "alice" are the same user, for the binds, the lookups and the
+/// creation. The casing as typed is kept alongside, for the display: see `User::user_id_display`.
+#[derive(Clone, Default, Serialize, Deserialize)]
+#[serde(from = "String", into = "String")]
+pub struct UserId {
+    /// The lowercase form, the key of the user.
+    id: String,
+    /// The form as typed.
+    display: String,
+}
 
 impl UserId {
     pub fn new(user_id: &str) -> Self {
-        Self(user_id.to_lowercase())
+        Self {
+            id: user_id.to_lowercase(),
+            display: user_id.to_owned(),
+        }
     }
 
     pub fn as_str(&self) -> &str {
-        self.0.as_str()
+        self.id.as_str()
+    }
+
+    /// The casing as typed, e.g. when creating the user. The IDs read from the database are
+    /// lowercase.
+    pub fn as_display_str(&self) -> &str {
+        self.display.as_str()
     }
 
     pub fn into_string(self) -> String {
-        self.0
+        self.id
+    }
+}
+
+impl PartialEq for UserId {
+    fn eq(&self, other: &Self) -> bool {
+        self.id == other.id
+    }
+}
+
+impl Eq for UserId {}
+
+impl PartialOrd for UserId {
+    fn partial_cmp(&self, other: &Self) -> Option<std::cmp::Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for UserId {
+    fn cmp(&self, other: &Self) -> std::cmp::Ordering {
+        self.id.cmp(&other.id)
+    }
+}
+
+impl std::hash::Hash for UserId {
+    fn hash<H: std::hash::Hasher>(&self, state: &mut H) {
+        self.id.hash(state)
+    }
+}
+
+impl std::fmt::Debug for UserId {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        f.debug_tuple("UserId").field(&self.id).finish()
     }
 }
 
 impl std::fmt::Display for UserId {
     fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
-        write!(f, "{}", self.0)
+        write!(f, "{}", self.id)
     }
 }
 
@@ -123,6 +217,12 @@ impl From<String> for UserId {
     }
 }
 
+impl From<UserId> for String {
+    fn from(user_id: UserId) -> Self {
+        user_id.into_string()
+    }
+}
+
 impl From<UserId> for Value {
     fn from(user_id: UserId) -> Self {
         user_id.into_string().into()
@@ -167,6 +267,12 @@ impl ValueType for UserId {
     }
 }
 
+impl Nullable for UserId {
+    fn null() -> Value {
+        Value::String(None)
+    }
+}
+
 #[derive(PartialEq, Eq, Clone, Debug, Serialize, Deserialize)]
 pub struct JpegPhoto(#[serde(with = "serde_bytes")] Vec<u8>);
 
@@ -309,7 +415,29 @@ pub struct User {
     pub last_name: Option<String>,
     pub avatar: Option<JpegPhoto>,
     pub creation_date: DateTime,
+    /// The last change to the user, or to its memberships.
+    pub modified_at: DateTime,
     pub uuid: Uuid,
+    /// The POSIX account, `None` until the numbers are allocated.
+    pub uid_number: Option<i32>,
+    /// The primary group, if not the default one.
+    pub gid_number: Option<i32>,
+    pub home_directory: Option<String>,
+    pub login_shell: Option<String>,
+    /// Set once the user confirms the email, and reset when it changes.
+    pub email_verified: bool,
+    /// The casing of the user ID at its creation or last rename, `None` for the users created
+    /// before it was kept.
+    pub user_id_display: Option<String>,
+}
+
+impl User {
+    /// The user ID with its original casing, e.g. "Alice" for the user "alice".
+    pub fn display_user_id(&self) -> &str {
+        self.user_id_display
+            .as_deref()
+            .unwrap_or_else(|| self.user_id.as_str())
+    }
 }
 
 #[cfg(test)]
@@ -325,12 +453,19 @@ impl Default for User {
             last_name: None,
             avatar: None,
             creation_date: epoch,
+            modified_at: epoch,
             uuid: Uuid::from_name_and_date("", &epoch),
+            uid_number: None,
+            gid_number: None,
+            home_directory: None,
+            login_shell: None,
+            email_verified: false,
+            user_id_display: None,
         }
     }
 }
 
-#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[derive(Debug, Copy, Clone, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
 pub struct GroupId(pub i32);
 
 impl From<GroupId> for Value {
@@ -369,12 +504,21 @@ impl TryFromU64 for GroupId {
     }
 }
 
+impl Nullable for GroupId {
+    fn null() -> Value {
+        Value::Int(None)
+    }
+}
+
 #[derive(PartialEq, Eq, Debug, Serialize, Deserialize)]
 pub struct Group {
     pub id: GroupId,
     pub display_name: String,
     pub creation_date: DateTime,
+    /// The last change to the group, or to its members.
+    pub modified_at: DateTime,
     pub uuid: Uuid,
+    pub gid_number: Option<i32>,
     pub users: Vec<UserId>,
 }
 
@@ -391,3 +535,601 @@ pub struct UserAndGroups {
     pub user: User,
     pub groups: Option<Vec<GroupDetails>>,
 }
+
+/// Name of a custom attribute. Attribute names are case-insensitive, so they are normalized to
+/// lowercase.
+#[derive(PartialEq, Eq, Hash, Clone, Debug, Default, Serialize, Deserialize)]
+#[serde(from = "String")]
+pub struct AttributeName(String);
+
+impl AttributeName {
+    pub fn new(name: &str) -> Self {
+        Self(name.to_lowercase())
+    }
+
+    pub fn as_str(&self) -> &str {
+        self.0.as_str()
+    }
+
+    pub fn into_string(self) -> String {
+        self.0
+    }
+}
+
+impl std::fmt::Display for AttributeName {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+impl From<String> for AttributeName {
+    fn from(s: String) -> Self {
+        Self::new(&s)
+    }
+}
+
+impl From<AttributeName> for Value {
+    fn from(name: AttributeName) -> Self {
+        name.into_string().into()
+    }
+}
+
+impl From<&AttributeName> for Value {
+    fn from(name: &AttributeName) -> Self {
+        name.as_str().into()
+    }
+}
+
+impl TryGetable for AttributeName {
+    fn try_get(res: &QueryResult, pre: &str, col: &str) -> Result<Self, TryGetError> {
+        Ok(AttributeName::new(&String::try_get(res, pre, col)?))
+    }
+}
+
+impl TryFromU64 for AttributeName {
+    fn try_from_u64(_n: u64) -> Result<Self, DbErr> {
+        Err(DbErr::ConvertFromU64(
+            "AttributeName cannot be constructed from u64",
+        ))
+    }
+}
+
+impl ValueType for AttributeName {
+    fn try_from(v: Value) -> Result<Self, ValueTypeErr> {
+        Ok(AttributeName::new(
+            <String as ValueType>::try_from(v)?.as_str(),
+        ))
+    }
+
+    fn type_name() -> String {
+        "AttributeName".to_owned()
+    }
+
+    fn array_type() -> ArrayType {
+        ArrayType::String
+    }
+
+    fn column_type() -> ColumnType {
+        ColumnType::String(Some(64))
+    }
+}
+
+/// A second authentication factor.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub enum MfaType {
+    Totp,
+    Webauthn,
+}
+
+impl MfaType {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            MfaType::Totp => "totp",
+            MfaType::Webauthn => "webauthn",
+        }
+    }
+}
+
+impl std::str::FromStr for MfaType {
+    type Err = anyhow::Error;
+    fn from_str(s: &str) -> anyhow::Result<Self> {
+        Ok(match s {
+            "totp" => MfaType::Totp,
+            "webauthn" => MfaType::Webauthn,
+            _ => anyhow::bail!("Unknown MFA type: {}", s),
+        })
+    }
+}
+
+impl From<MfaType> for Value {
+    fn from(mfa_type: MfaType) -> Self {
+        mfa_type.as_str().into()
+    }
+}
+
+impl TryGetable for MfaType {
+    fn try_get(res: &QueryResult, pre: &str, col: &str) -> Result<Self, TryGetError> {
+        String::try_get(res, pre, col)?
+            .parse()
+            .map_err(|e: anyhow::Error| {
+                TryGetError::DbErr(DbErr::TryIntoErr {
+                    from: "String",
+                    into: "MfaType",
+                    source: e.into(),
+                })
+            })
+    }
+}
+
+impl ValueType for MfaType {
+    fn try_from(v: Value) -> Result<Self, ValueTypeErr> {
+        <String as ValueType>::try_from(v)?
+            .parse()
+            .map_err(|_| ValueTypeErr {})
+    }
+
+    fn type_name() -> String {
+        "MfaType".to_owned()
+    }
+
+    fn array_type() -> ArrayType {
+        ArrayType::String
+    }
+
+    fn column_type() -> ColumnType {
+        ColumnType::String(Some(64))
+    }
+}
+
+impl Nullable for MfaType {
+    fn null() -> Value {
+        Value::String(None)
+    }
+}
+
+/// Where a change recorded in the audit log came from.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub enum AuditSource {
+    Ldap,
+    Graphql,
+    /// The HTTP endpoints outside of GraphQL, e.g. the password changes.
+    Http,
+    /// The SCIM provisioning endpoints.
+    Scim,
+    /// Changes made by the server itself or from the command line.
+    Internal,
+}
+
+impl AuditSource {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            AuditSource::Ldap => "ldap",
+            AuditSource::Graphql => "graphql",
+            AuditSource::Http => "http",
+            AuditSource::Scim => "scim",
+            AuditSource::Internal => "internal",
+        }
+    }
+}
+
+impl std::str::FromStr for AuditSource {
+    type Err = anyhow::Error;
+    fn from_str(s: &str) -> anyhow::Result<Self> {
+        Ok(match s {
+            "ldap" => AuditSource::Ldap,
+            "graphql" => AuditSource::Graphql,
+            "http" => AuditSource::Http,
+            "scim" => AuditSource::Scim,
+            "internal" => AuditSource::Internal,
+            _ => anyhow::bail!("Unknown audit source: {}", s),
+        })
+    }
+}
+
+impl From<AuditSource> for Value {
+    fn from(source: AuditSource) -> Self {
+        source.as_str().into()
+    }
+}
+
+impl TryGetable for AuditSource {
+    fn try_get(res: &QueryResult, pre: &str, col: &str) -> Result<Self, TryGetError> {
+        String::try_get(res, pre, col)?
+            .parse()
+            .map_err(|e: anyhow::Error| {
+                TryGetError::DbErr(DbErr::TryIntoErr {
+                    from: "String",
+                    into: "AuditSource",
+                    source: e.into(),
+                })
+            })
+    }
+}
+
+impl ValueType for AuditSource {
+    fn try_from(v: Value) -> Result<Self, ValueTypeErr> {
+        <String as ValueType>::try_from(v)?
+            .parse()
+            .map_err(|_| ValueTypeErr {})
+    }
+
+    fn type_name() -> String {
+        "AuditSource".to_owned()
+    }
+
+    fn array_type() -> ArrayType {
+        ArrayType::String
+    }
+
+    fn column_type() -> ColumnType {
+        ColumnType::String(Some(64))
+    }
+}
+
+/// The type of the values of a custom attribute.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub enum AttributeType {
+    String,
+    Integer,
+    DateTime,
+    Bytes,
+    JpegPhoto,
+}
+
+impl AttributeType {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            AttributeType::String => "String",
+            AttributeType::Integer => "Integer",
+            AttributeType::DateTime => "DateTime",
+            AttributeType::Bytes => "Bytes",
+            AttributeType::JpegPhoto => "JpegPhoto",
+        }
+    }
+}
+
+impl std::str::FromStr for AttributeType {
+    type Err = anyhow::Error;
+    fn from_str(s: &str) -> anyhow::Result<Self> {
+        Ok(match s {
+            "String" => AttributeType::String,
+            "Integer" => AttributeType::Integer,
+            "DateTime" => AttributeType::DateTime,
+            "Bytes" => AttributeType::Bytes,
+            "JpegPhoto" => AttributeType::JpegPhoto,
+            _ => anyhow::bail!("Unknown attribute type: {}", s),
+        })
+    }
+}
+
+impl From<AttributeType> for Value {
+    fn from(attribute_type: AttributeType) -> Self {
+        attribute_type.as_str().into()
+    }
+}
+
+impl TryGetable for AttributeType {
+    fn try_get(res: &QueryResult, pre: &str, col: &str) -> Result<Self, TryGetError> {
+        String::try_get(res, pre, col)?
+            .parse()
+            .map_err(|e: anyhow::Error| {
+                TryGetError::DbErr(DbErr::TryIntoErr {
+                    from: "String",
+                    into: "AttributeType",
+                    source: e.into(),
+                })
+            })
+    }
+}
+
+impl ValueType for AttributeType {
+    fn try_from(v: Value) -> Result<Self, ValueTypeErr> {
+        <String as ValueType>::try_from(v)?
+            .parse()
+            .map_err(|_| ValueTypeErr {})
+    }
+
+    fn type_name() -> String {
+        "AttributeType".to_owned()
+    }
+
+    fn array_type() -> ArrayType {
+        ArrayType::String
+    }
+
+    fn column_type() -> ColumnType {
+        ColumnType::String(Some(64))
+    }
+}
+
+/// A single typed value of a custom attribute.
+#[derive(PartialEq, Eq, Clone, Debug)]
+pub enum AttributeValue {
+    String(String),
+    Integer(i64),
+    DateTime(DateTime),
+    Bytes(Vec<u8>),
+    JpegPhoto(JpegPhoto),
+}
+
+impl AttributeValue {
+    pub fn attribute_type(&self) -> AttributeType {
+        match self {
+            AttributeValue::String(_) => AttributeType::String,
+            AttributeValue::Integer(_) => AttributeType::Integer,
+            AttributeValue::DateTime(_) => AttributeType::DateTime,
+            AttributeValue::Bytes(_) => AttributeType::Bytes,
+            AttributeValue::JpegPhoto(_) => AttributeType::JpegPhoto,
+        }
+    }
+
+    /// Serializes the value for storage in the DB.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        match self {
+            AttributeValue::String(s) => s.as_bytes().to_vec(),
+            AttributeValue::Integer(i) => i.to_string().into_bytes(),
+            AttributeValue::DateTime(d) => d.to_rfc3339().into_bytes(),
+            AttributeValue::Bytes(b) => b.clone(),
+            AttributeValue::JpegPhoto(p) => p.clone().into_bytes(),
+        }
+    }
+
+    /// Parses a value stored in the DB with `to_bytes`.
+    pub fn from_bytes(attribute_type: AttributeType, bytes: Vec<u8>) -> anyhow::Result<Self> {
+        Ok(match attribute_type {
+            AttributeType::String => AttributeValue::String(String::from_utf8(bytes)?),
+            AttributeType::Integer => {
+                AttributeValue::Integer(std::str::from_utf8(&bytes)?.parse()?)
+            }
+            AttributeType::DateTime => AttributeValue::DateTime(
+                chrono::DateTime::parse_from_rfc3339(std::str::from_utf8(&bytes)?)?
+                    .with_timezone(&chrono::Utc),
+            ),
+            AttributeType::Bytes => AttributeValue::Bytes(bytes),
+            AttributeType::JpegPhoto => {
+                AttributeValue::JpegPhoto(<JpegPhoto as TryFrom<_>>::try_from(bytes)?)
+            }
+        })
+    }
+}
+
+/// The bound of an ordering filter, e.g. LDAP's `(uidNumber>=1000)`. The bound is included.
+#[derive(PartialEq, Eq, Clone, Copy, Debug, Serialize, Deserialize)]
+pub enum Comparison {
+    GreaterOrEqual,
+    LessOrEqual,
+}
+
+impl Comparison {
+    pub fn matches<T: Ord>(self, value: &T, bound: &T) -> bool {
+        match self {
+            Comparison::GreaterOrEqual => value >= bound,
+            Comparison::LessOrEqual => value <= bound,
+        }
+    }
+}
+
+/// The bound of an ordering filter: only the integers and the dates are ordered.
+#[derive(PartialEq, Eq, Clone, Debug, Serialize, Deserialize)]
+pub enum OrderedValue {
+    Integer(i64),
+    DateTime(DateTime),
+}
+
+impl OrderedValue {
+    /// Parses a bound for the attributes of the given type: an integer, or a date as an LDAP
+    /// GeneralizedTime (`20240101000000Z`) or in RFC 3339.
+    pub fn parse(attribute_type: AttributeType, value: &str) -> std::result::Result<Self, String> {
+        match attribute_type {
+            AttributeType::Integer => value
+                .parse()
+                .map(OrderedValue::Integer)
+                .map_err(|_| format!("Invalid integer: {:?}", value)),
+            AttributeType::DateTime => parse_generalized_time(value)
+                .or_else(|| {
+                    chrono::DateTime::parse_from_rfc3339(value)
+                        .ok()
+                        .map(|date| date.with_timezone(&chrono::Utc))
+                })
+                .map(OrderedValue::DateTime)
+                .ok_or_else(|| {
+                    format!(
+                        "Invalid date: {:?}, expected a GeneralizedTime like 20240101000000Z",
+                        value
+                    )
+                }),
+            _ => Err(format!(
+                "The attributes of type {} have no ordering",
+                attribute_type.as_str()
+            )),
+        }
+    }
+
+    /// Whether the value is within the bound. The values of another type never are.
+    pub fn matches(&self, comparison: Comparison, value: &AttributeValue) -> bool {
+        match (self, value) {
+            (OrderedValue::Integer(bound), AttributeValue::Integer(value)) => {
+                comparison.matches(value, bound)
+            }
+            (OrderedValue::DateTime(bound), AttributeValue::DateTime(value)) => {
+                comparison.matches(value, bound)
+            }
+            _ => false,
+        }
+    }
+}
+
+/// Parses an LDAP GeneralizedTime (RFC 4517): `YYYYMMDDHH[MM[SS]][(.|,)fraction](Z|(+|-)HH[MM])`.
+/// The fraction applies to the last unit. The local times, without a time zone, are rejected.
+pub fn parse_generalized_time(value: &str) -> Option<DateTime> {
+    use chrono::TimeZone;
+    let (value, offset_seconds) = if let Some(value) = value.strip_suffix('Z') {
+        (value, 0)
+    } else {
+        let sign_index = value.rfind(&['+', '-'][..])?;
+        let offset = &value[sign_index + 1..];
+        if ![2, 4].contains(&offset.len()) || !offset.bytes().all(|b| b.is_ascii_digit()) {
+            return None;
+        }
+        let hours: i32 = offset[..2].parse().ok()?;
+        let minutes: i32 = if offset.len() == 4 {
+            offset[2..].parse().ok()?
+        } else {
+            0
+        };
+        let seconds = hours * 3600 + minutes * 60;
+        (
+            &value[..sign_index],
+            if value[sign_index..].starts_with('-') {
+                -seconds
+            } else {
+                seconds
+            },
+        )
+    };
+    let (time, fraction) = match value.find(&['.', ','][..]) {
+        Some(index) => (&value[..index], Some(&value[index + 1..])),
+        None => (value, None),
+    };
+    if !time.bytes().all(|b| b.is_ascii_digit()) {
+        return None;
+    }
+    let field = |range: std::ops::Range<usize>| time.get(range).map(|f| f.parse::<u32>().ok());
+    let unit_seconds: u128 = match time.len() {
+        10 => 3600,
+        12 => 60,
+        14 => 1,
+        _ => return None,
+    };
+    let year = time[..4].parse().ok()?;
+    let date = chrono::NaiveDate::from_ymd_opt(year, field(4..6)??, field(6..8)??)?.and_hms_opt(
+        field(8..10)??,
+        field(10..12).unwrap_or(Some(0))?,
+        field(12..14).unwrap_or(Some(0))?,
+    )?;
+    let fraction_nanoseconds = match fraction {
+        None => 0,
+        Some(fraction) => {
+            if fraction.is_empty() || !fraction.bytes().all(|b| b.is_ascii_digit()) {
+                return None;
+            }
+            // Nanoseconds are enough.
+            let fraction = &fraction[..fraction.len().min(9)];
+            let scale = 10u128.pow(fraction.len() as u32);
+            fraction.parse::<u128>().ok()? * unit_seconds * 1_000_000_000 / scale
+        }
+    };
+    let date = chrono::FixedOffset::east_opt(offset_seconds)?
+        .from_local_datetime(&date)
+        .single()?;
+    Some(
+        date.with_timezone(&chrono::Utc)
+            + chrono::Duration::nanoseconds(fraction_nanoseconds as i64),
+    )
+}
+
+/// Describes a custom attribute.
+#[derive(PartialEq, Eq, Clone, Debug, Serialize, Deserialize)]
+pub struct AttributeSchema {
+    pub name: AttributeName,
+    pub attribute_type: AttributeType,
+    pub is_list: bool,
+    /// The values are indexed, for the equality filters on the attribute.
+    pub is_indexed: bool,
+}
+
+/// All the values of a custom attribute for a given user or group.
+#[derive(PartialEq, Eq, Clone, Debug)]
+pub struct Attribute {
+    pub name: AttributeName,
+    pub values: Vec<AttributeValue>,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeZone;
+
+    #[test]
+    fn test_user_id_case_insensitive() {
+        let user_id = UserId::new("Alice");
+        assert_eq!(user_id, UserId::new("alice"));
+        assert_eq!(user_id.as_str(), "alice");
+        assert_eq!(user_id.as_display_str(), "Alice");
+        assert_eq!(format!("{:?}", user_id), r#"UserId("alice")"#);
+        assert_eq!(serde_json::to_string(&user_id).unwrap(), r#""alice""#);
+        let user = User {
+            user_id: UserId::new("alice"),
+            ..Default::default()
+        };
+        assert_eq!(user.display_user_id(), "alice");
+        let user = User {
+            user_id_display: Some("Alice".to_owned()),
+            ..user
+        };
+        assert_eq!(user.display_user_id(), "Alice");
+    }
+
+    #[test]
+    fn test_parse_generalized_time() {
+        let date = |y, mo, d, h, mi, s| chrono::Utc.with_ymd_and_hms(y, mo, d, h, mi, s).unwrap();
+        let cases = [
+            ("20240101000000Z", Some(date(2024, 1, 1, 0, 0, 0))),
+            ("202401011230Z", Some(date(2024, 1, 1, 12, 30, 0))),
+            ("2024010112Z", Some(date(2024, 1, 1, 12, 0, 0))),
+            ("20240101000000+0100", Some(date(2023, 12, 31, 23, 0, 0))),
+            ("20240101000000-05", Some(date(2024, 1, 1, 5, 0, 0))),
+            ("2024010112.5Z", Some(date(2024, 1, 1, 12, 30, 0))),
+            ("202401011230,5Z", Some(date(2024, 1, 1, 12, 30, 30))),
+            (
+                "20240101000000.25Z",
+                Some(date(2024, 1, 1, 0, 0, 0) + chrono::Duration::milliseconds(250)),
+            ),
+            ("20241231235959Z", Some(date(2024, 12, 31, 23, 59, 59))),
+            // No time zone.
+            ("20240101000000", None),
+            // No hour.
+            ("20240101Z", None),
+            ("20241301000000Z", None),
+            ("20240230000000Z", None),
+            ("20240101250000Z", None),
+            ("20240101000000.Z", None),
+            ("20240101000000+1", None),
+            ("2024-01-01T00:00:00Z", None),
+            ("", None),
+        ];
+        for (value, expected) in cases {
+            assert_eq!(parse_generalized_time(value), expected, "{}", value);
+        }
+    }
+
+    #[test]
+    fn test_ordered_value() {
+        assert_eq!(
+            OrderedValue::parse(AttributeType::Integer, "-12"),
+            Ok(OrderedValue::Integer(-12))
+        );
+        assert!(OrderedValue::parse(AttributeType::Integer, "12.5").is_err());
+        assert_eq!(
+            OrderedValue::parse(AttributeType::DateTime, "2024-01-01T01:00:00+01:00"),
+            OrderedValue::parse(AttributeType::DateTime, "20240101000000Z"),
+        );
+        assert_eq!(
+            OrderedValue::parse(AttributeType::Bytes, "1"),
+            Err("The attributes of type Bytes have no ordering".to_owned())
+        );
+        let bound = OrderedValue::Integer(1000);
+        assert!(bound.matches(Comparison::GreaterOrEqual, &AttributeValue::Integer(1000)));
+        assert!(bound.matches(Comparison::LessOrEqual, &AttributeValue::Integer(1000)));
+        assert!(!bound.matches(Comparison::GreaterOrEqual, &AttributeValue::Integer(999)));
+        assert!(!bound.matches(Comparison::LessOrEqual, &AttributeValue::Integer(1001)));
+        assert!(OrderedValue::Integer(i64::MAX).matches(
+            Comparison::GreaterOrEqual,
+            &AttributeValue::Integer(i64::MAX)
+        ));
+        assert!(!bound.matches(
+            Comparison::GreaterOrEqual,
+            &AttributeValue::String("2000".to_owned())
+        ));
+    }
+}
@@ -0,0 +1,93 @@
+use crate::{domain::types::UserId, infra::configuration::UserIdPolicyOptions};
+
+/// The maximum length of the user ID column.
+const MAX_USER_ID_LENGTH: usize = 255;
+
+/// Characters that would have to be escaped in a DN: never allowed, whatever the policy.
+const FORBIDDEN_USER_ID_CHARACTERS: &[char] = &[',', '=', '+', '<', '>', '#', ';', '"', '\\'];
+
+/// Checks the ID of a new user against the policy. The existing users are not affected.
+pub fn check_user_id(
+    options: &UserIdPolicyOptions,
+    user_id: &UserId,
+) -> std::result::Result<(), String> {
+    let user_id = user_id.as_str();
+    let length = user_id.chars().count();
+    let max_length = options.max_length.min(MAX_USER_ID_LENGTH);
+    if user_id.is_empty() {
+        return Err("The user ID is empty".to_owned());
+    }
+    if length < options.min_length {
+        return Err(format!(
+            "The user ID must be at least {} characters long",
+            options.min_length
+        ));
+    }
+    if length > max_length {
+        return Err(format!(
+            "The user ID must be at most {} characters long",
+            max_length
+        ));
+    }
+    let is_allowed = |c: char| {
+        !c.is_whitespace()
+            && !FORBIDDEN_USER_ID_CHARACTERS.contains(&c)
+            && (c.is_ascii_alphanumeric()
+                || (options.allow_unicode_letters && c.is_alphanumeric())
+                || options.allowed_symbols.contains(c))
+    };
+    match user_id.chars().find(|&c| !is_allowed(c)) {
+        Some(c) => Err(format!(
+            "The user ID contains the invalid character {:?}",
+            c
+        )),
+        None => Ok(()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn check(options: &UserIdPolicyOptions, user_id: &str) -> std::result::Result<(), String> {
+        check_user_id(options, &UserId::new(user_id))
+    }
+
+    #[test]
+    fn test_default_policy() {
+        let options = UserIdPolicyOptions::default();
+        assert_eq!(check(&options, "john.doe-2_b"), Ok(()));
+        assert_eq!(check(&options, "John"), Ok(()));
+        assert_eq!(check(&options, ""), Err("The user ID is empty".to_owned()));
+        assert_eq!(
+            check(&options, "bad user"),
+            Err("The user ID contains the invalid character ' '".to_owned())
+        );
+        assert!(check(&options, "bob@example.com").is_err());
+        assert!(check(&options, "josé").is_err());
+        assert!(check(&options, &"a".repeat(256)).is_err());
+    }
+
+    #[test]
+    fn test_configured_policy() {
+        let options = UserIdPolicyOptions {
+            min_length: 3,
+            max_length: 1000,
+            allowed_symbols: "@.,".to_owned(),
+            allow_unicode_letters: true,
+        };
+        assert_eq!(check(&options, "bob@example.com"), Ok(()));
+        assert_eq!(check(&options, "josé"), Ok(()));
+        assert_eq!(
+            check(&options, "bo"),
+            Err("The user ID must be at least 3 characters long".to_owned())
+        );
+        // Capped by the size of the column.
+        assert_eq!(
+            check(&options, &"a".repeat(256)),
+            Err("The user ID must be at most 255 characters long".to_owned())
+        );
+        // Even if the policy allows it.
+        assert!(check(&options, "doe,john").is_err());
+    }
+}
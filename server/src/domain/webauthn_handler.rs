@@ -0,0 +1,44 @@
+use crate::domain::{error::Result, types::UserId};
+use async_trait::async_trait;
+use serde::{Deserialize, Serialize};
+
+pub use webauthn_rs::prelude::{
+    CreationChallengeResponse, PublicKeyCredential, RegisterPublicKeyCredential,
+    RequestChallengeResponse,
+};
+
+/// The server state is encrypted and sent to the client, which must send it back to finish the
+/// ceremony.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct RegistrationStartResponse {
+    pub server_data: String,
+    pub challenge: CreationChallengeResponse,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct RegistrationFinishRequest {
+    pub server_data: String,
+    pub credential: RegisterPublicKeyCredential,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct AssertionStartResponse {
+    pub server_data: String,
+    pub challenge: RequestChallengeResponse,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct AssertionFinishRequest {
+    pub server_data: String,
+    pub credential: PublicKeyCredential,
+}
+
+/// WebAuthn (FIDO2) second factor. A user can register several credentials.
+#[async_trait]
+pub trait WebauthnHandler: Clone + Send {
+    async fn registration_start(&self, user_id: &UserId) -> Result<RegistrationStartResponse>;
+    async fn registration_finish(&self, request: RegistrationFinishRequest) -> Result<()>;
+    async fn assertion_start(&self, user_id: &UserId) -> Result<AssertionStartResponse>;
+    /// Returns the user that was authenticated.
+    async fn assertion_finish(&self, request: AssertionFinishRequest) -> Result<UserId>;
+}
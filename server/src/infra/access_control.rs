@@ -0,0 +1,343 @@
+//! The attribute-level access control lists: which attributes of the users the members of a
+//! group can read or modify. The first matching rule decides. Without one, the permissions of the
+//! special groups apply: everyone reads their own account and modifies its self-service
+//! attributes, the admins and the readonly users read all of them, and the admins modify all of
+//! them.
+use crate::{
+    domain::{
+        handler::UserRequestFilter,
+        types::{UserColumn, UserId},
+    },
+    infra::auth_service::ValidationResults,
+};
+use serde::{Deserialize, Serialize};
+use std::sync::Arc;
+
+/// The attributes of the users that the rules control. The ID, the UUID and the dates can always
+/// be read.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum AclAttribute {
+    Email,
+    DisplayName,
+    FirstName,
+    LastName,
+    Avatar,
+}
+
+impl AclAttribute {
+    pub const ALL: &'static [Self] = &[
+        Self::Email,
+        Self::DisplayName,
+        Self::FirstName,
+        Self::LastName,
+        Self::Avatar,
+    ];
+
+    pub fn from_column(column: &UserColumn) -> Option<Self> {
+        Some(match column {
+            UserColumn::Email => Self::Email,
+            UserColumn::DisplayName => Self::DisplayName,
+            UserColumn::FirstName => Self::FirstName,
+            UserColumn::LastName => Self::LastName,
+            UserColumn::Avatar => Self::Avatar,
+            _ => return None,
+        })
+    }
+
+    /// The attribute of an LDAP user entry, in lowercase.
+    pub fn from_ldap_attribute(attribute: &str) -> Option<Self> {
+        Some(match attribute {
+            "mail" => Self::Email,
+            "cn" | "displayname" | "gecos" => Self::DisplayName,
+            "givenname" => Self::FirstName,
+            "sn" => Self::LastName,
+            "jpegphoto" => Self::Avatar,
+            _ => return None,
+        })
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum AclOperation {
+    Read,
+    Write,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum AclAccess {
+    Allow,
+    Deny,
+}
+
+/// Whose accounts a rule applies to, relative to the user of the session.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum AclTarget {
+    #[default]
+    All,
+    Own,
+    Others,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Deserialize, Serialize)]
+pub struct AttributeAclRule {
+    /// A group of the user of the session, or `*` for all the users.
+    pub group: String,
+    pub attributes: Vec<AclAttribute>,
+    pub operation: AclOperation,
+    #[serde(default)]
+    pub target: AclTarget,
+    pub access: AclAccess,
+}
+
+#[derive(Clone, Debug)]
+pub struct AttributeAcl {
+    rules: Arc<Vec<AttributeAclRule>>,
+    self_service_attributes: Arc<Vec<AclAttribute>>,
+}
+
+impl Default for AttributeAcl {
+    fn default() -> Self {
+        Self {
+            rules: Arc::default(),
+            self_service_attributes: Arc::new(AclAttribute::ALL.to_vec()),
+        }
+    }
+}
+
+impl AttributeAcl {
+    pub fn new(rules: Vec<AttributeAclRule>) -> Self {
+        Self {
+            rules: Arc::new(rules),
+            ..Self::default()
+        }
+    }
+
+    /// The attributes that the users can modify on their own account, without a rule.
+    pub fn with_self_service_attributes(mut self, attributes: Vec<AclAttribute>) -> Self {
+        self.self_service_attributes = Arc::new(attributes);
+        self
+    }
+
+    /// The rules that apply to the session of this user.
+    pub fn for_user<'a>(&'a self, viewer: &'a ValidationResults) -> UserAttributeAccess<'a> {
+        UserAttributeAccess {
+            rules: self
+                .rules
+                .iter()
+                .filter(|rule| rule.group == "*" || viewer.groups.contains(&rule.group))
+                .collect(),
+            self_service_attributes: &self.self_service_attributes,
+            viewer,
+        }
+    }
+}
+
+pub struct UserAttributeAccess<'a> {
+    rules: Vec<&'a AttributeAclRule>,
+    self_service_attributes: &'a [AclAttribute],
+    viewer: &'a ValidationResults,
+}
+
+impl UserAttributeAccess<'_> {
+    /// The access given by the first matching rule, if any.
+    fn get_rule_access(
+        &self,
+        own: bool,
+        attribute: AclAttribute,
+        operation: AclOperation,
+    ) -> Option<bool> {
+        self.rules
+            .iter()
+            .find(|rule| {
+                rule.operation == operation
+                    && rule.attributes.contains(&attribute)
+                    && match rule.target {
+                        AclTarget::All => true,
+                        AclTarget::Own => own,
+                        AclTarget::Others => !own,
+                    }
+            })
+            .map(|rule| rule.access == AclAccess::Allow)
+    }
+
+    #[must_use]
+    pub fn can_read(&self, user: &UserId, attribute: AclAttribute) -> bool {
+        self.get_rule_access(user == &self.viewer.user, attribute, AclOperation::Read)
+            .unwrap_or_else(|| self.viewer.can_read(user))
+    }
+
+    #[must_use]
+    pub fn can_write(&self, user: &UserId, attribute: AclAttribute) -> bool {
+        let own = user == &self.viewer.user;
+        self.get_rule_access(own, attribute, AclOperation::Write)
+            .unwrap_or_else(|| {
+                self.viewer.is_admin() || (own && self.self_service_attributes.contains(&attribute))
+            })
+    }
+
+    /// Same as `can_read`, for the attributes that are not controlled by the rules.
+    #[must_use]
+    pub fn can_read_ldap_attribute(&self, user: &UserId, attribute: &str) -> bool {
+        AclAttribute::from_ldap_attribute(&attribute.to_ascii_lowercase())
+            .map_or(true, |attribute| self.can_read(user, attribute))
+    }
+
+    fn can_read_others(&self, attribute: AclAttribute) -> bool {
+        self.get_rule_access(false, attribute, AclOperation::Read)
+            .unwrap_or_else(|| self.viewer.is_admin_or_readonly())
+    }
+
+    /// Whether the user can list the other users: with the read permission, or if a rule lets
+    /// them read an attribute of the others. Then they only see the IDs and the readable
+    /// attributes.
+    #[must_use]
+    pub fn can_list_users(&self) -> bool {
+        self.viewer.is_admin_or_readonly()
+            || self.rules.iter().any(|rule| {
+                rule.operation == AclOperation::Read
+                    && rule.access == AclAccess::Allow
+                    && rule.target != AclTarget::Own
+            })
+    }
+
+    /// Whether the filter only looks at the attributes that the user can read on the other users:
+    /// the others could be found by their values.
+    #[must_use]
+    pub fn can_filter(&self, filter: &UserRequestFilter) -> bool {
+        match filter {
+            UserRequestFilter::And(filters) | UserRequestFilter::Or(filters) => {
+                filters.iter().all(|filter| self.can_filter(filter))
+            }
+            UserRequestFilter::Not(filter) => self.can_filter(filter),
+            UserRequestFilter::Equality(column, _)
+            | UserRequestFilter::EqualityIgnoreCase(column, _)
+            | UserRequestFilter::Present(column) => AclAttribute::from_column(column)
+                .map_or(true, |attribute| self.can_read_others(attribute)),
+            _ => true,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::infra::auth_service::Permission;
+
+    fn viewer(user: &str, groups: &[&str]) -> ValidationResults {
+        ValidationResults {
+            user: UserId::new(user),
+            permission: Permission::from_groups(groups.iter().copied()),
+            groups: groups.iter().map(|group| group.to_string()).collect(),
+        }
+    }
+
+    fn rule(
+        group: &str,
+        attributes: &[AclAttribute],
+        operation: AclOperation,
+        target: AclTarget,
+        access: AclAccess,
+    ) -> AttributeAclRule {
+        AttributeAclRule {
+            group: group.to_string(),
+            attributes: attributes.to_vec(),
+            operation,
+            target,
+            access,
+        }
+    }
+
+    #[test]
+    fn test_default_permissions() {
+        let acl = AttributeAcl::default();
+        let bob = viewer("bob", &[]);
+        let access = acl.for_user(&bob);
+        assert!(access.can_read(&UserId::new("bob"), AclAttribute::Email));
+        assert!(access.can_write(&UserId::new("bob"), AclAttribute::Email));
+        assert!(!access.can_read(&UserId::new("john"), AclAttribute::Email));
+        assert!(!access.can_list_users());
+        let admin = viewer("admin", &["lldap_admin"]);
+        let access = acl.for_user(&admin);
+        assert!(access.can_write(&UserId::new("john"), AclAttribute::Avatar));
+        assert!(access.can_list_users());
+        assert!(access.can_filter(&UserRequestFilter::Equality(
+            UserColumn::Email,
+            "john@example.com".to_string()
+        )));
+    }
+
+    #[test]
+    fn test_self_service_attributes() {
+        let acl = AttributeAcl::default()
+            .with_self_service_attributes(vec![AclAttribute::DisplayName, AclAttribute::Avatar]);
+        let bob = viewer("bob", &[]);
+        let access = acl.for_user(&bob);
+        let own = UserId::new("bob");
+        assert!(access.can_write(&own, AclAttribute::DisplayName));
+        assert!(access.can_write(&own, AclAttribute::Avatar));
+        assert!(!access.can_write(&own, AclAttribute::Email));
+        // Only their own account.
+        assert!(!access.can_write(&UserId::new("john"), AclAttribute::DisplayName));
+        // The admins are not restricted.
+        let admin = viewer("admin", &["lldap_admin"]);
+        let access = acl.for_user(&admin);
+        assert!(access.can_write(&UserId::new("admin"), AclAttribute::Email));
+        assert!(access.can_write(&UserId::new("john"), AclAttribute::Email));
+    }
+
+    #[test]
+    fn test_rules() {
+        let acl = AttributeAcl::new(vec![
+            rule(
+                "helpdesk",
+                &[AclAttribute::DisplayName],
+                AclOperation::Write,
+                AclTarget::Others,
+                AclAccess::Allow,
+            ),
+            rule(
+                "helpdesk",
+                &[AclAttribute::Email],
+                AclOperation::Write,
+                AclTarget::All,
+                AclAccess::Deny,
+            ),
+            rule(
+                "*",
+                &[AclAttribute::DisplayName],
+                AclOperation::Read,
+                AclTarget::All,
+                AclAccess::Allow,
+            ),
+        ]);
+        let helpdesk = viewer("bob", &["helpdesk"]);
+        let access = acl.for_user(&helpdesk);
+        let john = UserId::new("john");
+        assert!(access.can_write(&john, AclAttribute::DisplayName));
+        assert!(!access.can_write(&john, AclAttribute::Email));
+        assert!(!access.can_write(&UserId::new("bob"), AclAttribute::Email));
+        // No rule: the default.
+        assert!(access.can_write(&UserId::new("bob"), AclAttribute::FirstName));
+        assert!(!access.can_write(&john, AclAttribute::FirstName));
+        let regular = viewer("alice", &["users"]);
+        let access = acl.for_user(&regular);
+        assert!(!access.can_write(&john, AclAttribute::DisplayName));
+        assert!(access.can_read(&john, AclAttribute::DisplayName));
+        assert!(!access.can_read(&john, AclAttribute::Email));
+        assert!(access.can_read_ldap_attribute(&john, "cn"));
+        assert!(!access.can_read_ldap_attribute(&john, "mail"));
+        assert!(access.can_read_ldap_attribute(&john, "uid"));
+        assert!(access.can_list_users());
+        assert!(access.can_filter(&UserRequestFilter::Equality(
+            UserColumn::DisplayName,
+            "John".to_string()
+        )));
+        assert!(!access.can_filter(&UserRequestFilter::Not(Box::new(
+            UserRequestFilter::Equality(UserColumn::Email, "john@example.com".to_string())
+        ))));
+    }
+}
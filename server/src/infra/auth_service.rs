@@ -1,5 +1,6 @@
 use std::collections::{hash_map::DefaultHasher, HashSet};
 use std::hash::{Hash, Hasher};
+use std::net::IpAddr;
 use std::pin::Pin;
 use std::task::{Context, Poll};
 
@@ -16,34 +17,50 @@ use futures::future::{ok, Ready};
 use futures_util::FutureExt;
 use hmac::Hmac;
 use jwt::{SignWithKey, VerifyWithKey};
+use secstr::SecUtf8;
 use sha2::Sha512;
 use time::ext::NumericalDuration;
-use tracing::{debug, instrument, warn};
+use tracing::{debug, instrument, warn, Span};
 
 use lldap_auth::{login, password_reset, registration, JWTClaims};
 
 use crate::{
     domain::{
         error::DomainError,
-        handler::{BackendHandler, BindRequest, LoginHandler, UserRequestFilter},
+        handler::{AuditActor, BackendHandler, BindRequest, LoginHandler, UserRequestFilter},
         opaque_handler::OpaqueHandler,
-        types::{GroupDetails, UserColumn, UserId},
+        session_handler::SessionClient,
+        sql_opaque_handler::register_password,
+        types::{
+            AuditSource, GroupDetails, UserColumn, UserId, ADMIN_GROUP_NAME,
+            PASSWORD_MANAGER_GROUP_NAME, READONLY_GROUP_NAME,
+        },
     },
     infra::{
+        i18n::request_locales,
+        rate_limiter::RateLimitKey,
         tcp_backend_handler::*,
         tcp_server::{error_to_http_response, AppState, TcpError, TcpResult},
     },
 };
 
+pub(crate) const JWT_VALIDITY_DAYS: i64 = 1;
+
 type Token<S> = jwt::Token<jwt::Header, JWTClaims, S>;
 type SignedToken = Token<jwt::token::Signed>;
 
-fn create_jwt(key: &Hmac<Sha512>, user: String, groups: HashSet<GroupDetails>) -> SignedToken {
+fn create_jwt(
+    key: &Hmac<Sha512>,
+    user: String,
+    groups: HashSet<GroupDetails>,
+    session: Option<String>,
+) -> SignedToken {
     let claims = JWTClaims {
-        exp: Utc::now() + chrono::Duration::days(1),
+        exp: Utc::now() + chrono::Duration::days(JWT_VALIDITY_DAYS),
         iat: Utc::now(),
         user,
         groups: groups.into_iter().map(|g| g.display_name).collect(),
+        session,
     };
     let header = jwt::Header {
         algorithm: jwt::AlgorithmType::Hs512,
@@ -52,21 +69,26 @@ fn create_jwt(key: &Hmac<Sha512>, user: String, groups: HashSet<GroupDetails>) -
     jwt::Token::new(header, claims).sign_with_key(key).unwrap()
 }
 
-fn parse_refresh_token(token: &str) -> TcpResult<(u64, UserId)> {
+fn parse_refresh_token(token: &str) -> TcpResult<(String, UserId)> {
     match token.split_once('+') {
         None => Err(DomainError::AuthenticationError("Invalid refresh token".to_string()).into()),
-        Some((token, u)) => {
-            let refresh_token_hash = {
-                let mut s = DefaultHasher::new();
-                token.hash(&mut s);
-                s.finish()
-            };
-            Ok((refresh_token_hash, UserId::new(u)))
-        }
+        Some((token, u)) => Ok((token.to_owned(), UserId::new(u))),
+    }
+}
+
+/// Where the request comes from, to show in the sessions of the user.
+fn get_session_client(client_ip: Option<IpAddr>, request: &HttpRequest) -> SessionClient {
+    SessionClient {
+        ip_address: client_ip.map(|ip| ip.to_string()),
+        user_agent: request
+            .headers()
+            .get(actix_http::header::USER_AGENT)
+            .and_then(|user_agent| user_agent.to_str().ok())
+            .map(|user_agent| user_agent.chars().take(255).collect()),
     }
 }
 
-fn get_refresh_token(request: HttpRequest) -> TcpResult<(u64, UserId)> {
+fn get_refresh_token(request: &HttpRequest) -> TcpResult<(String, UserId)> {
     match (
         request.cookie("refresh_token"),
         request.headers().get("refresh-token"),
@@ -87,37 +109,46 @@ async fn get_refresh<Backend>(
 where
     Backend: TcpBackendHandler + BackendHandler + 'static,
 {
-    let backend_handler = &data.backend_handler;
-    let jwt_key = &data.jwt_key;
-    let (refresh_token_hash, user) = get_refresh_token(request)?;
-    let found = data
+    let (refresh_token, user) = get_refresh_token(&request)?;
+    // Each refresh token is single use.
+    let refresh_token = data
         .backend_handler
-        .check_token(refresh_token_hash, &user)
+        .rotate_refresh_token(
+            &refresh_token,
+            &user,
+            &get_session_client(data.client_ip(&request), &request),
+        )
         .await?;
-    if !found {
-        return Err(TcpError::DomainError(DomainError::AuthenticationError(
-            "Invalid refresh token".to_string(),
-        )));
-    }
-    Ok(backend_handler
-        .get_user_groups(&user)
-        .await
-        .map(|groups| create_jwt(jwt_key, user.to_string(), groups))
-        .map(|token| {
-            HttpResponse::Ok()
-                .cookie(
-                    Cookie::build("token", token.as_str())
-                        .max_age(1.days())
-                        .path("/")
-                        .http_only(true)
-                        .same_site(SameSite::Strict)
-                        .finish(),
-                )
-                .json(&login::ServerLoginResponse {
-                    token: token.as_str().to_owned(),
-                    refresh_token: None,
-                })
-        })?)
+    let groups = data.backend_handler.get_user_groups(&user).await?;
+    let token = create_jwt(
+        &data.jwt_key,
+        user.to_string(),
+        groups,
+        Some(refresh_token.session_id),
+    );
+    let max_age = refresh_token.duration;
+    let refresh_token_plus_name = refresh_token.token + "+" + user.as_str();
+    Ok(HttpResponse::Ok()
+        .cookie(
+            Cookie::build("token", token.as_str())
+                .max_age(1.days())
+                .path("/")
+                .http_only(true)
+                .same_site(SameSite::Strict)
+                .finish(),
+        )
+        .cookie(
+            Cookie::build("refresh_token", refresh_token_plus_name.clone())
+                .max_age(max_age.num_days().days())
+                .path("/auth")
+                .http_only(true)
+                .same_site(SameSite::Strict)
+                .finish(),
+        )
+        .json(&login::ServerLoginResponse {
+            token: token.as_str().to_owned(),
+            refresh_token: Some(refresh_token_plus_name),
+        }))
 }
 
 async fn get_refresh_handler<Backend>(
@@ -144,6 +175,10 @@ where
         .match_info()
         .get("user_id")
         .ok_or_else(|| TcpError::BadRequest("Missing user ID".to_string()))?;
+    data.rate_limiter
+        .check_password_reset(data.client_ip(&request), user_string)
+        .await
+        .map_err(TcpError::TooManyRequests)?;
     let user_results = data
         .backend_handler
         .list_users(
@@ -170,23 +205,29 @@ where
         None => return Ok(()),
         Some(token) => token,
     };
-    if let Err(e) = super::mail::send_password_reset_email(
-        user.display_name
-            .as_deref()
-            .unwrap_or_else(|| user.user_id.as_str()),
-        &user.email,
-        &token,
-        &data.server_url,
-        &data.mail_options,
-    )
-    .await
-    {
-        warn!("Error sending email: {:#?}", e);
-        return Err(TcpError::InternalServerError(format!(
-            "Could not send email: {}",
-            e
-        )));
-    }
+    // The email is sent in the background: the response doesn't wait for the SMTP server, and
+    // doesn't tell whether the user exists.
+    let mail = data.mail.clone();
+    let user_id = user.user_id.as_str().to_owned();
+    let display_name = user.display_name.clone().unwrap_or_else(|| user_id.clone());
+    let email = user.email.clone();
+    let server_url = data.server_url.clone();
+    let locales = request_locales(&request);
+    actix_rt::spawn(async move {
+        if let Err(e) = mail
+            .send_password_reset_email(
+                &user_id,
+                &display_name,
+                &email,
+                &token,
+                &server_url,
+                &locales,
+            )
+            .await
+        {
+            warn!("Error sending the password reset email: {:#?}", e);
+        }
+    });
     Ok(())
 }
 
@@ -217,14 +258,10 @@ where
         .ok_or_else(|| TcpError::BadRequest("Missing reset token".to_string()))?;
     let user_id = data
         .backend_handler
-        .get_user_id_for_password_reset_token(token)
+        .consume_password_reset_token(token)
         .await?;
-    let _ = data
-        .backend_handler
-        .delete_password_reset_token(token)
-        .await;
     let groups = HashSet::new();
-    let token = create_jwt(&data.jwt_key, user_id.to_string(), groups);
+    let token = create_jwt(&data.jwt_key, user_id.to_string(), groups, None);
     Ok(HttpResponse::Ok()
         .cookie(
             Cookie::build("token", token.as_str())
@@ -253,6 +290,152 @@ where
         .unwrap_or_else(error_to_http_response)
 }
 
+#[instrument(skip_all, level = "debug")]
+async fn post_password_reset_complete<Backend>(
+    data: web::Data<AppState<Backend>>,
+    http_request: HttpRequest,
+    request: web::Json<password_reset::ClientPasswordResetCompleteRequest>,
+) -> TcpResult<()>
+where
+    Backend: TcpBackendHandler + OpaqueHandler + 'static,
+{
+    if let Some(ip) = data.client_ip(&http_request) {
+        data.rate_limiter
+            .check(&RateLimitKey::Ip(ip))
+            .await
+            .map_err(TcpError::TooManyRequests)?;
+    }
+    // Before consuming the token, so that it can be retried with a better password.
+    data.password_policy
+        .check(&request.new_password)
+        .await
+        .map_err(|e| {
+            TcpError::BadRequest(e.localize(&data.messages, &request_locales(&http_request)))
+        })?;
+    let user_id = data
+        .backend_handler
+        .consume_password_reset_token(&request.token)
+        .await?;
+    register_password(
+        &data.backend_handler,
+        &user_id,
+        &SecUtf8::from(request.new_password.as_str()),
+    )
+    .await?;
+    Ok(())
+}
+
+async fn post_password_reset_complete_handler<Backend>(
+    data: web::Data<AppState<Backend>>,
+    http_request: HttpRequest,
+    request: web::Json<password_reset::ClientPasswordResetCompleteRequest>,
+) -> HttpResponse
+where
+    Backend: TcpBackendHandler + OpaqueHandler + 'static,
+{
+    post_password_reset_complete(data, http_request, request)
+        .await
+        .map(|()| HttpResponse::Ok().finish())
+        .unwrap_or_else(error_to_http_response)
+}
+
+#[instrument(skip_all, level = "debug")]
+async fn post_email_verification_start<Backend>(
+    request: HttpRequest,
+    mut payload: web::Payload,
+    data: web::Data<AppState<Backend>>,
+) -> TcpResult<()>
+where
+    Backend: TcpBackendHandler + BackendHandler + 'static,
+{
+    use actix_web::FromRequest;
+    let validation_result = BearerAuth::from_request(&request, &mut payload.0)
+        .await
+        .ok()
+        .and_then(|bearer| check_if_token_is_valid(&data, bearer.token()).ok())
+        .ok_or_else(|| TcpError::UnauthorizedError("Not logged in".to_string()))?;
+    let user = data
+        .backend_handler
+        .get_user_details(&validation_result.user)
+        .await?;
+    if user.email_verified {
+        debug!("Email already verified");
+        return Ok(());
+    }
+    let token = data
+        .backend_handler
+        .start_email_verification(&user.user_id, &user.email)
+        .await?;
+    if let Err(e) = data
+        .mail
+        .send_email_verification_email(
+            user.user_id.as_str(),
+            user.display_name
+                .as_deref()
+                .unwrap_or_else(|| user.user_id.as_str()),
+            &user.email,
+            &token,
+            &data.server_url,
+            &request_locales(&request),
+        )
+        .await
+    {
+        warn!("Error sending email: {:#?}", e);
+        return Err(TcpError::InternalServerError(format!(
+            "Could not send email: {}",
+            e
+        )));
+    }
+    Ok(())
+}
+
+async fn post_email_verification_start_handler<Backend>(
+    request: HttpRequest,
+    payload: web::Payload,
+    data: web::Data<AppState<Backend>>,
+) -> HttpResponse
+where
+    Backend: TcpBackendHandler + BackendHandler + 'static,
+{
+    post_email_verification_start(request, payload, data)
+        .await
+        .map(|()| HttpResponse::Ok().finish())
+        .unwrap_or_else(error_to_http_response)
+}
+
+#[instrument(skip_all, level = "debug")]
+async fn get_email_verification_confirm<Backend>(
+    data: web::Data<AppState<Backend>>,
+    request: HttpRequest,
+) -> TcpResult<UserId>
+where
+    Backend: TcpBackendHandler + BackendHandler + 'static,
+{
+    let token = request
+        .match_info()
+        .get("token")
+        .ok_or_else(|| TcpError::BadRequest("Missing verification token".to_string()))?;
+    Ok(data
+        .backend_handler
+        .confirm_email_verification(token)
+        .await?)
+}
+
+async fn get_email_verification_confirm_handler<Backend>(
+    data: web::Data<AppState<Backend>>,
+    request: HttpRequest,
+) -> HttpResponse
+where
+    Backend: TcpBackendHandler + BackendHandler + 'static,
+{
+    get_email_verification_confirm(data, request)
+        .await
+        .map(|user_id| {
+            HttpResponse::Ok().body(format!("The email of '{}' is now verified.", user_id))
+        })
+        .unwrap_or_else(error_to_http_response)
+}
+
 #[instrument(skip_all, level = "debug")]
 async fn get_logout<Backend>(
     data: web::Data<AppState<Backend>>,
@@ -261,15 +444,15 @@ async fn get_logout<Backend>(
 where
     Backend: TcpBackendHandler + BackendHandler + 'static,
 {
-    let (refresh_token_hash, user) = get_refresh_token(request)?;
-    data.backend_handler
-        .delete_refresh_token(refresh_token_hash)
-        .await?;
-    let new_blacklisted_jwts = data.backend_handler.blacklist_jwts(&user).await?;
-    let mut jwt_blacklist = data.jwt_blacklist.write().unwrap();
-    for jwt in new_blacklisted_jwts {
-        jwt_blacklist.insert(jwt);
+    let (refresh_token, user) = get_refresh_token(&request)?;
+    if let Some(session_id) = data
+        .backend_handler
+        .revoke_refresh_token(&refresh_token)
+        .await?
+    {
+        data.revoked_sessions.write().unwrap().insert(session_id);
     }
+    blacklist_jwts(&data, &user).await?;
     Ok(HttpResponse::Ok()
         .cookie(
             Cookie::build("token", "")
@@ -302,20 +485,98 @@ where
         .unwrap_or_else(error_to_http_response)
 }
 
+async fn blacklist_jwts<Backend>(data: &AppState<Backend>, user: &UserId) -> TcpResult<()>
+where
+    Backend: TcpBackendHandler,
+{
+    let new_blacklisted_jwts = data.backend_handler.blacklist_jwts(user).await?;
+    let mut jwt_blacklist = data.jwt_blacklist.write().unwrap();
+    for jwt in new_blacklisted_jwts {
+        jwt_blacklist.insert(jwt);
+    }
+    Ok(())
+}
+
+/// Logs the user out of all their sessions. Allowed to the admins, and to the user themselves.
+#[instrument(skip_all, level = "debug")]
+async fn post_revoke_sessions<Backend>(
+    request: HttpRequest,
+    mut payload: web::Payload,
+    data: web::Data<AppState<Backend>>,
+) -> TcpResult<()>
+where
+    Backend: TcpBackendHandler + BackendHandler + 'static,
+{
+    use actix_web::FromRequest;
+    let validation_result = BearerAuth::from_request(&request, &mut payload.0)
+        .await
+        .ok()
+        .and_then(|bearer| check_if_token_is_valid(&data, bearer.token()).ok())
+        .ok_or_else(|| TcpError::UnauthorizedError("Not logged in".to_string()))?;
+    let user_id = request
+        .match_info()
+        .get("user_id")
+        .map(UserId::new)
+        .ok_or_else(|| TcpError::BadRequest("Missing user ID".to_string()))?;
+    if !validation_result.can_write(&user_id) {
+        return Err(TcpError::UnauthorizedError(
+            "Not authorized to revoke the sessions of the user".to_string(),
+        ));
+    }
+    let session_ids = data
+        .backend_handler
+        .revoke_all_refresh_tokens(&user_id)
+        .await?;
+    data.revoked_sessions.write().unwrap().extend(session_ids);
+    blacklist_jwts(&data, &user_id).await
+}
+
+async fn post_revoke_sessions_handler<Backend>(
+    request: HttpRequest,
+    payload: web::Payload,
+    data: web::Data<AppState<Backend>>,
+) -> HttpResponse
+where
+    Backend: TcpBackendHandler + BackendHandler + 'static,
+{
+    post_revoke_sessions(request, payload, data)
+        .await
+        .map(|()| HttpResponse::Ok().finish())
+        .unwrap_or_else(error_to_http_response)
+}
+
 pub(crate) fn error_to_api_response<T, E: Into<TcpError>>(error: E) -> ApiResult<T> {
     ApiResult::Right(error_to_http_response(error.into()))
 }
 
 pub type ApiResult<M> = actix_web::Either<web::Json<M>, HttpResponse>;
 
+/// Counts a login attempt for the user, from the address of the request.
+pub(crate) async fn check_login_rate_limit<Backend>(
+    data: &AppState<Backend>,
+    http_request: &HttpRequest,
+    user_id: &UserId,
+) -> TcpResult<()> {
+    data.rate_limiter
+        .check_login(data.client_ip(http_request), user_id)
+        .await
+        .map_err(TcpError::TooManyRequests)
+}
+
 #[instrument(skip_all, level = "debug")]
 async fn opaque_login_start<Backend>(
     data: web::Data<AppState<Backend>>,
+    http_request: HttpRequest,
     request: web::Json<login::ClientLoginStartRequest>,
 ) -> ApiResult<login::ServerLoginStartResponse>
 where
     Backend: OpaqueHandler + 'static,
 {
+    if let Err(e) =
+        check_login_rate_limit(&data, &http_request, &UserId::new(&request.username)).await
+    {
+        return error_to_api_response(e);
+    }
     data.backend_handler
         .login_start(request.into_inner())
         .await
@@ -326,6 +587,7 @@ where
 #[instrument(skip_all, level = "debug")]
 async fn get_login_successful_response<Backend>(
     data: &web::Data<AppState<Backend>>,
+    http_request: &HttpRequest,
     name: &UserId,
 ) -> TcpResult<HttpResponse>
 where
@@ -334,9 +596,21 @@ where
     // The authentication was successful, we need to fetch the groups to create the JWT
     // token.
     let groups = data.backend_handler.get_user_groups(name).await?;
-    let (refresh_token, max_age) = data.backend_handler.create_refresh_token(name).await?;
-    let token = create_jwt(&data.jwt_key, name.to_string(), groups);
-    let refresh_token_plus_name = refresh_token + "+" + name.as_str();
+    let refresh_token = data
+        .backend_handler
+        .create_refresh_token(
+            name,
+            &get_session_client(data.client_ip(http_request), http_request),
+        )
+        .await?;
+    let token = create_jwt(
+        &data.jwt_key,
+        name.to_string(),
+        groups,
+        Some(refresh_token.session_id),
+    );
+    let max_age = refresh_token.duration;
+    let refresh_token_plus_name = refresh_token.token + "+" + name.as_str();
 
     Ok(HttpResponse::Ok()
         .cookie(
@@ -364,6 +638,7 @@ where
 #[instrument(skip_all, level = "debug")]
 async fn opaque_login_finish<Backend>(
     data: web::Data<AppState<Backend>>,
+    http_request: HttpRequest,
     request: web::Json<login::ClientLoginFinishRequest>,
 ) -> TcpResult<HttpResponse>
 where
@@ -373,17 +648,18 @@ where
         .backend_handler
         .login_finish(request.into_inner())
         .await?;
-    get_login_successful_response(&data, &name).await
+    get_login_successful_response(&data, &http_request, &name).await
 }
 
 async fn opaque_login_finish_handler<Backend>(
     data: web::Data<AppState<Backend>>,
+    http_request: HttpRequest,
     request: web::Json<login::ClientLoginFinishRequest>,
 ) -> HttpResponse
 where
     Backend: TcpBackendHandler + BackendHandler + OpaqueHandler + 'static,
 {
-    opaque_login_finish(data, request)
+    opaque_login_finish(data, http_request, request)
         .await
         .unwrap_or_else(error_to_http_response)
 }
@@ -391,28 +667,31 @@ where
 #[instrument(skip_all, level = "debug")]
 async fn simple_login<Backend>(
     data: web::Data<AppState<Backend>>,
+    http_request: HttpRequest,
     request: web::Json<login::ClientSimpleLoginRequest>,
 ) -> TcpResult<HttpResponse>
 where
     Backend: TcpBackendHandler + BackendHandler + OpaqueHandler + LoginHandler + 'static,
 {
     let user_id = UserId::new(&request.username);
+    check_login_rate_limit(&data, &http_request, &user_id).await?;
     let bind_request = BindRequest {
         name: user_id.clone(),
         password: request.password.clone(),
     };
     data.backend_handler.bind(bind_request).await?;
-    get_login_successful_response(&data, &user_id).await
+    get_login_successful_response(&data, &http_request, &user_id).await
 }
 
 async fn simple_login_handler<Backend>(
     data: web::Data<AppState<Backend>>,
+    http_request: HttpRequest,
     request: web::Json<login::ClientSimpleLoginRequest>,
 ) -> HttpResponse
 where
     Backend: TcpBackendHandler + BackendHandler + OpaqueHandler + LoginHandler + 'static,
 {
-    simple_login(data, request)
+    simple_login(data, http_request, request)
         .await
         .unwrap_or_else(error_to_http_response)
 }
@@ -420,6 +699,7 @@ where
 #[instrument(skip_all, level = "debug")]
 async fn post_authorize<Backend>(
     data: web::Data<AppState<Backend>>,
+    http_request: HttpRequest,
     request: web::Json<BindRequest>,
 ) -> TcpResult<HttpResponse>
 where
@@ -427,18 +707,20 @@ where
 {
     let name = request.name.clone();
     debug!(%name);
+    check_login_rate_limit(&data, &http_request, &name).await?;
     data.backend_handler.bind(request.into_inner()).await?;
-    get_login_successful_response(&data, &name).await
+    get_login_successful_response(&data, &http_request, &name).await
 }
 
 async fn post_authorize_handler<Backend>(
     data: web::Data<AppState<Backend>>,
+    http_request: HttpRequest,
     request: web::Json<BindRequest>,
 ) -> HttpResponse
 where
     Backend: TcpBackendHandler + BackendHandler + LoginHandler + 'static,
 {
-    post_authorize(data, request)
+    post_authorize(data, http_request, request)
         .await
         .unwrap_or_else(error_to_http_response)
 }
@@ -474,7 +756,7 @@ where
         .get_user_groups(&user_id)
         .await?
         .iter()
-        .any(|g| g.display_name == "lldap_admin");
+        .any(|g| g.display_name == ADMIN_GROUP_NAME);
     if !validation_result.can_change_password(&user_id, user_is_admin) {
         return Err(TcpError::UnauthorizedError(
             "Not authorized to change the user's password".to_string(),
@@ -503,12 +785,24 @@ where
 #[instrument(skip_all, level = "debug")]
 async fn opaque_register_finish<Backend>(
     data: web::Data<AppState<Backend>>,
+    bearer: Option<BearerAuth>,
     request: web::Json<registration::ClientRegistrationFinishRequest>,
 ) -> TcpResult<HttpResponse>
 where
     Backend: TcpBackendHandler + BackendHandler + OpaqueHandler + 'static,
 {
-    data.backend_handler
+    // The request was authorized by `opaque_register_start`, the token is only used to
+    // attribute the change in the audit log.
+    let mut backend_handler = data.backend_handler.clone();
+    if let Some(validation_result) =
+        bearer.and_then(|bearer| check_if_token_is_valid(&data, bearer.token()).ok())
+    {
+        backend_handler.set_audit_actor(AuditActor {
+            user_id: validation_result.user,
+            source: AuditSource::Http,
+        });
+    }
+    backend_handler
         .registration_finish(request.into_inner())
         .await?;
     Ok(HttpResponse::Ok().finish())
@@ -516,12 +810,13 @@ where
 
 async fn opaque_register_finish_handler<Backend>(
     data: web::Data<AppState<Backend>>,
+    bearer: Option<BearerAuth>,
     request: web::Json<registration::ClientRegistrationFinishRequest>,
 ) -> HttpResponse
 where
     Backend: TcpBackendHandler + BackendHandler + OpaqueHandler + 'static,
 {
-    opaque_register_finish(data, request)
+    opaque_register_finish(data, bearer, request)
         .await
         .unwrap_or_else(error_to_http_response)
 }
@@ -582,6 +877,17 @@ where
     }
 }
 
+/// What a user can do to the accounts of others. Everyone can read and modify their own account.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Capability {
+    /// List and read all the users and groups.
+    Read,
+    /// Reset the password of the users that are not admins.
+    ChangePassword,
+    /// Create, modify and delete users and groups.
+    Admin,
+}
+
 #[derive(Clone, Copy, PartialEq, Eq, Debug)]
 pub enum Permission {
     Admin,
@@ -590,10 +896,48 @@ pub enum Permission {
     Regular,
 }
 
+impl Permission {
+    /// The permission given by the group memberships of a user. If the user is a member of
+    /// several of the special groups, the most powerful one wins.
+    pub fn from_groups<'a>(groups: impl IntoIterator<Item = &'a str>) -> Self {
+        let groups = groups.into_iter().collect::<HashSet<_>>();
+        if groups.contains(ADMIN_GROUP_NAME) {
+            Permission::Admin
+        } else if groups.contains(PASSWORD_MANAGER_GROUP_NAME) {
+            Permission::PasswordManager
+        } else if groups.contains(READONLY_GROUP_NAME) {
+            Permission::Readonly
+        } else {
+            Permission::Regular
+        }
+    }
+
+    #[must_use]
+    pub fn capabilities(&self) -> &'static [Capability] {
+        match self {
+            Permission::Admin => &[
+                Capability::Read,
+                Capability::ChangePassword,
+                Capability::Admin,
+            ],
+            Permission::PasswordManager => &[Capability::Read, Capability::ChangePassword],
+            Permission::Readonly => &[Capability::Read],
+            Permission::Regular => &[],
+        }
+    }
+
+    #[must_use]
+    pub fn has(&self, capability: Capability) -> bool {
+        self.capabilities().contains(&capability)
+    }
+}
+
 #[derive(Debug, Clone, PartialEq, Eq)]
 pub struct ValidationResults {
     pub user: UserId,
     pub permission: Permission,
+    /// For the attribute access control lists.
+    pub groups: HashSet<String>,
 }
 
 impl ValidationResults {
@@ -602,39 +946,35 @@ impl ValidationResults {
         Self {
             user: UserId::new("admin"),
             permission: Permission::Admin,
+            groups: HashSet::from([ADMIN_GROUP_NAME.to_string()]),
         }
     }
 
     #[must_use]
     pub fn is_admin(&self) -> bool {
-        self.permission == Permission::Admin
+        self.permission.has(Capability::Admin)
     }
 
     #[must_use]
     pub fn is_admin_or_readonly(&self) -> bool {
-        self.permission == Permission::Admin
-            || self.permission == Permission::Readonly
-            || self.permission == Permission::PasswordManager
+        self.permission.has(Capability::Read)
     }
 
     #[must_use]
     pub fn can_read(&self, user: &UserId) -> bool {
-        self.permission == Permission::Admin
-            || self.permission == Permission::PasswordManager
-            || self.permission == Permission::Readonly
-            || &self.user == user
+        self.permission.has(Capability::Read) || &self.user == user
     }
 
     #[must_use]
     pub fn can_change_password(&self, user: &UserId, user_is_admin: bool) -> bool {
-        self.permission == Permission::Admin
-            || (self.permission == Permission::PasswordManager && !user_is_admin)
+        self.permission.has(Capability::Admin)
+            || (self.permission.has(Capability::ChangePassword) && !user_is_admin)
             || &self.user == user
     }
 
     #[must_use]
     pub fn can_write(&self, user: &UserId) -> bool {
-        self.permission == Permission::Admin || &self.user == user
+        self.permission.has(Capability::Admin) || &self.user == user
     }
 }
 
@@ -662,18 +1002,17 @@ pub(crate) fn check_if_token_is_valid<Backend>(
     if state.jwt_blacklist.read().unwrap().contains(&jwt_hash) {
         return Err(ErrorUnauthorized("JWT was logged out"));
     }
-    let is_in_group = |name| token.claims().groups.contains(name);
+    if let Some(session) = &token.claims().session {
+        if state.revoked_sessions.read().unwrap().contains(session) {
+            return Err(ErrorUnauthorized("The session was revoked"));
+        }
+    }
+    // The root span of the request has a field for it.
+    Span::current().record("user", &token.claims().user.as_str());
     Ok(ValidationResults {
         user: UserId::new(&token.claims().user),
-        permission: if is_in_group("lldap_admin") {
-            Permission::Admin
-        } else if is_in_group("lldap_password_manager") {
-            Permission::PasswordManager
-        } else if is_in_group("lldap_strict_readonly") {
-            Permission::Readonly
-        } else {
-            Permission::Regular
-        },
+        permission: Permission::from_groups(token.claims().groups.iter().map(String::as_str)),
+        groups: token.claims().groups.clone(),
     })
 }
 
@@ -702,7 +1041,31 @@ where
             web::resource("/reset/step2/{token}")
                 .route(web::get().to(get_password_reset_step2_handler::<Backend>)),
         )
+        .service(
+            web::resource("/reset/complete")
+                .route(web::post().to(post_password_reset_complete_handler::<Backend>)),
+        )
         .service(web::resource("/logout").route(web::get().to(get_logout_handler::<Backend>)))
+        .service(
+            web::scope("/sessions")
+                .wrap(CookieToHeaderTranslatorFactory)
+                .service(
+                    web::resource("/revoke/{user_id}")
+                        .route(web::post().to(post_revoke_sessions_handler::<Backend>)),
+                ),
+        )
+        .service(
+            web::scope("/email_verification")
+                .wrap(CookieToHeaderTranslatorFactory)
+                .service(
+                    web::resource("/start")
+                        .route(web::post().to(post_email_verification_start_handler::<Backend>)),
+                )
+                .service(
+                    web::resource("/confirm/{token}")
+                        .route(web::get().to(get_email_verification_confirm_handler::<Backend>)),
+                ),
+        )
         .service(
             web::scope("/opaque/register")
                 .wrap(CookieToHeaderTranslatorFactory)
@@ -716,3 +1079,57 @@ where
                 ),
         );
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn validation_results(user: &str, groups: &[&str]) -> ValidationResults {
+        ValidationResults {
+            user: UserId::new(user),
+            permission: Permission::from_groups(groups.iter().copied()),
+            groups: groups.iter().map(|group| group.to_string()).collect(),
+        }
+    }
+
+    #[test]
+    fn test_permission_from_groups() {
+        assert_eq!(Permission::from_groups([]), Permission::Regular);
+        assert_eq!(
+            Permission::from_groups(["users", READONLY_GROUP_NAME]),
+            Permission::Readonly
+        );
+        assert_eq!(
+            Permission::from_groups([READONLY_GROUP_NAME, PASSWORD_MANAGER_GROUP_NAME]),
+            Permission::PasswordManager
+        );
+        assert_eq!(
+            Permission::from_groups([PASSWORD_MANAGER_GROUP_NAME, ADMIN_GROUP_NAME]),
+            Permission::Admin
+        );
+    }
+
+    #[test]
+    fn test_strict_readonly_cannot_modify() {
+        let readonly = validation_results("reader", &[READONLY_GROUP_NAME]);
+        let bob = UserId::new("bob");
+        assert!(readonly.can_read(&bob));
+        assert!(readonly.is_admin_or_readonly());
+        assert!(!readonly.can_change_password(&bob, false));
+        assert!(!readonly.can_write(&bob));
+        assert!(!readonly.is_admin());
+        // Their own account is still theirs.
+        assert!(readonly.can_change_password(&UserId::new("reader"), false));
+    }
+
+    #[test]
+    fn test_password_manager_can_change_passwords() {
+        let password_manager = validation_results("manager", &[PASSWORD_MANAGER_GROUP_NAME]);
+        let bob = UserId::new("bob");
+        assert!(password_manager.can_read(&bob));
+        assert!(password_manager.can_change_password(&bob, false));
+        assert!(!password_manager.can_change_password(&bob, true));
+        assert!(!password_manager.can_write(&bob));
+        assert!(!password_manager.is_admin());
+    }
+}
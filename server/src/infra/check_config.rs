@@ -0,0 +1,469 @@
+use crate::{
+    domain::{
+        model,
+        sql_migrations::get_schema_version,
+        sql_tables::{DbConnection, LAST_SCHEMA_VERSION},
+        totp_secret::TotpSecretKeys,
+    },
+    infra::{
+        configuration::{read_server_setup, Configuration},
+        tls_certificate::load_certified_key,
+    },
+};
+use sea_orm::{Database, EntityTrait, PaginatorTrait};
+use secstr::SecUtf8;
+use std::path::Path;
+
+/// The length of the secrets generated by the command in the config template.
+const MIN_JWT_SECRET_LENGTH: usize = 32;
+
+/// The problems found in the configuration. Only the errors would stop the server, or make it
+/// unsafe to run.
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct ConfigCheck {
+    pub errors: Vec<String>,
+    pub warnings: Vec<String>,
+}
+
+impl ConfigCheck {
+    fn error(&mut self, message: String) {
+        self.errors.push(message)
+    }
+
+    fn warning(&mut self, message: String) {
+        self.warnings.push(message)
+    }
+}
+
+/// Checks the secrets and the files they're read from. The missing key files are only warnings:
+/// they are generated on the first start.
+pub fn check_secrets(config: &Configuration, check: &mut ConfigCheck) {
+    if config.jwt_secret == SecUtf8::from("secretjwtsecret") {
+        check.error("The default JWT secret is used, anyone could log in as admin".to_owned());
+    } else if config.jwt_secret.unsecure().len() < MIN_JWT_SECRET_LENGTH {
+        check.error(format!(
+            "The JWT secret is too short, it should be at least {} characters long",
+            MIN_JWT_SECRET_LENGTH
+        ));
+    }
+    if config.ldap_user_pass == SecUtf8::from("password") {
+        check.warning("The default admin password is used".to_owned());
+    }
+    if Path::new(&config.key_file).exists() {
+        if let Err(e) = read_server_setup(&config.key_file) {
+            check.error(format!("Invalid server key file: {:#}", e));
+        }
+    } else {
+        check.warning(format!(
+            "The server key file `{}` doesn't exist, it will be generated",
+            config.key_file
+        ));
+    }
+    if let Some(key_file) = &config.totp_encryption_key_file {
+        if Path::new(key_file).exists() {
+            if let Err(e) = TotpSecretKeys::from_file(key_file) {
+                check.error(format!("{:#}", e));
+            }
+        } else {
+            check.warning(format!(
+                "The TOTP key file `{}` doesn't exist, it will be generated",
+                key_file
+            ));
+        }
+    }
+    if !config.webhook_options.urls.is_empty()
+        && config.webhook_options.secret.unsecure().is_empty()
+    {
+        check.warning(
+            "No webhook secret set, the receivers can't verify the webhook signatures".to_owned(),
+        );
+    }
+    let mut certificates = config
+        .get_listeners()
+        .into_iter()
+        .filter_map(|listener| listener.tls)
+        .map(|tls| (tls.cert_file, tls.key_file))
+        .collect::<Vec<_>>();
+    if config.uses_ldaps_certificate() {
+        certificates.push((
+            config.ldaps_options.cert_file.clone(),
+            config.ldaps_options.key_file.clone(),
+        ));
+    }
+    certificates.sort();
+    certificates.dedup();
+    for (cert_file, key_file) in certificates {
+        check_certificate(&cert_file, &key_file, check);
+    }
+    if let Some(client_ca_file) = &config.ldaps_options.client_ca_file {
+        if !Path::new(client_ca_file).exists() {
+            check.error(format!(
+                "The client CA file `{}` doesn't exist",
+                client_ca_file
+            ));
+        }
+        if !config.has_tls_listener() {
+            check
+                .warning("The client certificates need LDAPS or StartTLS to be enabled".to_owned());
+        }
+    }
+}
+
+fn check_certificate(cert_file: &str, key_file: &str, check: &mut ConfigCheck) {
+    let mut exist = true;
+    for file in [cert_file, key_file] {
+        if !Path::new(file).exists() {
+            check.error(format!("The LDAPS file `{}` doesn't exist", file));
+            exist = false;
+        }
+    }
+    if exist {
+        if let Err(e) = load_certified_key(cert_file, key_file) {
+            check.error(format!("Invalid LDAPS certificate: {:#}", e));
+        }
+    }
+}
+
+/// Checks the options that the server would refuse to start with.
+pub fn check_options(config: &Configuration, check: &mut ConfigCheck) {
+    if let Err(e) = config.check_ldap_base_dn() {
+        check.error(e.to_string());
+    }
+    if let Err(e) = config.check_user_rdn_attribute() {
+        check.error(e.to_string());
+    }
+    if let Err(e) = config.check_posix_options() {
+        check.error(e.to_string());
+    }
+    if let Err(e) = config.check_totp_options() {
+        check.error(format!("{:#}", e));
+    }
+    if let Err(e) = config.check_email_policy() {
+        check.error(e.to_string());
+    }
+    if let Err(e) = config.check_tls_options() {
+        check.error(e.to_string());
+    }
+    if let Err(e) = config.check_security_headers() {
+        check.error(format!("{:#}", e));
+    }
+    if let Err(e) = config.check_proxy_options() {
+        check.error(format!("{:#}", e));
+    }
+    if let Err(e) = config.check_sudoers_base_dn() {
+        check.error(e.to_string());
+    }
+    if let Err(e) = config.check_database_pool_options() {
+        check.error(e.to_string());
+    }
+    if let Err(e) = config.check_auth_backends() {
+        check.error(format!("{:#}", e));
+    }
+    if let Err(e) = config.check_listeners() {
+        check.error(e.to_string());
+    }
+}
+
+/// Checks that the schema version can be read, or that the DB is still empty.
+async fn check_schema_version(pool: &DbConnection, check: &mut ConfigCheck) {
+    match get_schema_version(pool).await {
+        Some(version) if version > LAST_SCHEMA_VERSION => check.error(format!(
+            "The DB schema is at version {}, newer than the version {} of this binary",
+            version.0, LAST_SCHEMA_VERSION.0
+        )),
+        Some(version) if version < LAST_SCHEMA_VERSION => check.warning(format!(
+            "The DB schema will be upgraded from version {} to version {}",
+            version.0, LAST_SCHEMA_VERSION.0
+        )),
+        Some(_) => (),
+        // Without the metadata table, the users table is only there if the DB is from another
+        // application, or damaged.
+        None => {
+            if model::User::find().count(pool).await.is_ok() {
+                check.error("The DB has a users table, but no readable schema version".to_owned());
+            }
+        }
+    }
+}
+
+pub async fn check_database(config: &Configuration, check: &mut ConfigCheck) {
+    let mut connect_options = sea_orm::ConnectOptions::new(config.database_url.clone());
+    connect_options.max_connections(1).sqlx_logging(false);
+    match Database::connect(connect_options).await {
+        Ok(pool) => check_schema_version(&pool, check).await,
+        Err(e) => check.error(format!("Could not connect to the DB: {:#}", e)),
+    }
+    if let Some(database_replica_url) = &config.database_replica_url {
+        let mut connect_options = sea_orm::ConnectOptions::new(database_replica_url.clone());
+        connect_options.max_connections(1).sqlx_logging(false);
+        if let Err(e) = Database::connect(connect_options).await {
+            check.error(format!("Could not connect to the DB replica: {:#}", e));
+        }
+    }
+}
+
+pub async fn check_config(config: &Configuration) -> ConfigCheck {
+    let mut check = ConfigCheck::default();
+    check_secrets(config, &mut check);
+    check_options(config, &mut check);
+    check_database(config, &mut check).await;
+    check
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        domain::{sql_tables::init_table, types::UserId},
+        infra::configuration::{
+            AuthBackendOptions, ConfigurationBuilder, ListenerOptions, ListenerProtocol,
+            ListenerTlsOptions, TlsVersion, UserRdnAttribute, UuidBackfill,
+        },
+    };
+
+    fn make_config() -> Configuration {
+        let mut config = ConfigurationBuilder::for_tests();
+        config.jwt_secret = SecUtf8::from("a".repeat(MIN_JWT_SECRET_LENGTH));
+        config.ldap_user_pass = SecUtf8::from("admin_password");
+        config.key_file = "/nonexistent/server_key".to_owned();
+        config
+    }
+
+    #[test]
+    fn test_check_secrets() {
+        let mut check = ConfigCheck::default();
+        check_secrets(&make_config(), &mut check);
+        assert!(check.errors.is_empty());
+        assert_eq!(
+            check.warnings,
+            vec![
+                "The server key file `/nonexistent/server_key` doesn't exist, it will be generated"
+            ]
+        );
+
+        let mut config = make_config();
+        config.jwt_secret = SecUtf8::from("short");
+        config.ldaps_options.enabled = true;
+        config.ldaps_options.cert_file = "/nonexistent/cert.pem".to_owned();
+        config.ldaps_options.key_file = "/nonexistent/key.pem".to_owned();
+        let mut check = ConfigCheck::default();
+        check_secrets(&config, &mut check);
+        assert_eq!(
+            check.errors,
+            vec![
+                "The JWT secret is too short, it should be at least 32 characters long",
+                "The LDAPS file `/nonexistent/cert.pem` doesn't exist",
+                "The LDAPS file `/nonexistent/key.pem` doesn't exist",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_check_options() {
+        let mut config = make_config();
+        let mut check = ConfigCheck::default();
+        check_options(&config, &mut check);
+        assert_eq!(check, ConfigCheck::default());
+
+        config.ldap_user_rdn_attribute = UserRdnAttribute::Mail;
+        check_options(&config, &mut check);
+        assert_eq!(check.errors.len(), 1);
+        assert!(check.errors[0].contains("case_insensitive_emails"));
+
+        config.case_insensitive_emails = true;
+        let mut check = ConfigCheck::default();
+        check_options(&config, &mut check);
+        assert_eq!(check, ConfigCheck::default());
+
+        // Rejected when the config is read.
+        assert_eq!(
+            UserRdnAttribute::try_from("Mail".to_owned()),
+            Ok(UserRdnAttribute::Mail)
+        );
+        assert!(UserRdnAttribute::try_from("displayName".to_owned())
+            .unwrap_err()
+            .contains("not unique"));
+
+        config.posix_options.uid_number_max = 1000;
+        check_options(&config, &mut check);
+        assert_eq!(check.errors.len(), 1);
+        assert!(check.errors[0].contains("uidNumber range"));
+
+        config.posix_options.uid_number_max = 59999;
+        let mut check = ConfigCheck::default();
+        config.totp_options.digits = 10;
+        check_options(&config, &mut check);
+        assert_eq!(check.errors.len(), 1);
+        assert!(check.errors[0].contains("6 to 8 digits"));
+
+        config.totp_options.digits = 6;
+        let mut check = ConfigCheck::default();
+        config.email_policy.blocked_domains = vec!["spam@example.com".to_owned()];
+        check_options(&config, &mut check);
+        assert_eq!(check.errors.len(), 1);
+        assert!(check.errors[0].contains("email_policy"));
+
+        config.email_policy.blocked_domains = vec![];
+        let mut check = ConfigCheck::default();
+        config.ldaps_options.min_tls_version = TlsVersion::Tls13;
+        config.ldaps_options.cipher_suites =
+            vec!["TLS_ECDHE_RSA_WITH_AES_128_GCM_SHA256".to_owned()];
+        check_options(&config, &mut check);
+        assert_eq!(check.errors.len(), 1);
+        assert!(check.errors[0].contains("min_tls_version"));
+
+        config.ldaps_options = Default::default();
+        let mut check = ConfigCheck::default();
+        config.proxy_options.ldap_proxy_protocol = true;
+        check_options(&config, &mut check);
+        config.proxy_options.trusted_proxies = vec!["10.0.0.0/40".to_owned()];
+        check_options(&config, &mut check);
+        assert_eq!(check.errors.len(), 2);
+        assert!(check.errors[0].contains("requires `proxy_options.trusted_proxies`"));
+        assert!(check.errors[1].contains("Invalid prefix length"));
+
+        config.proxy_options = Default::default();
+        let mut check = ConfigCheck::default();
+        config.ldap_sudoers_base_dn = Some("ou=sudoers,dc=example,dc=org".to_owned());
+        check_options(&config, &mut check);
+        config.ldap_sudoers_base_dn = Some("ou=sudoers,ou=people,dc=example,dc=com".to_owned());
+        check_options(&config, &mut check);
+        config.ldap_sudoers_base_dn = Some("OU=SUDOers,dc=Example,dc=com".to_owned());
+        check_options(&config, &mut check);
+        assert_eq!(check.errors.len(), 2);
+        assert!(check.errors[0].contains("must be under the base DN"));
+        assert!(check.errors[1].contains("users or the groups"));
+
+        let mut check = ConfigCheck::default();
+        config.ldap_sudoers_base_dn = None;
+        config.ldap_users_ou = "Staff".to_owned();
+        config.ldap_groups_ou = "teams".to_owned();
+        check_options(&config, &mut check);
+        assert_eq!(check, ConfigCheck::default());
+        config.ldap_sudoers_base_dn = Some("ou=sudoers,ou=staff,dc=example,dc=com".to_owned());
+        check_options(&config, &mut check);
+        config.ldap_sudoers_base_dn = None;
+        config.ldap_groups_ou = "STAFF".to_owned();
+        check_options(&config, &mut check);
+        config.ldap_groups_ou = "groups".to_owned();
+        config.ldap_base_dn = "dc=example,,dc=com".to_owned();
+        check_options(&config, &mut check);
+        config.ldap_base_dn = "example.com".to_owned();
+        check_options(&config, &mut check);
+        // The invalid base DNs also fail the check of the sudoers base DN.
+        assert_eq!(check.errors.len(), 6);
+        assert!(check.errors[0].contains("users or the groups"));
+        assert!(check.errors[1].contains("share the OU"));
+        assert!(check.errors[2].starts_with("Invalid `ldap_base_dn"));
+        assert!(check.errors[4].starts_with("Invalid `ldap_base_dn"));
+        config.ldap_base_dn = "dc=example,dc=com".to_owned();
+
+        let mut check = ConfigCheck::default();
+        config.database_pool_options.min_idle_connections = 10;
+        check_options(&config, &mut check);
+        config.database_pool_options.max_connections = 0;
+        check_options(&config, &mut check);
+        assert_eq!(check.errors.len(), 2);
+        assert!(check.errors[0].contains("min_idle_connections"));
+        assert!(check.errors[1].contains("must be positive"));
+        config.database_pool_options = Default::default();
+
+        let mut check = ConfigCheck::default();
+        let backend = |name: &str, ldap_url: &str, bind_dn_template: &str| AuthBackendOptions {
+            name: name.to_owned(),
+            ldap_url: ldap_url.to_owned(),
+            bind_dn_template: bind_dn_template.to_owned(),
+            users: vec![UserId::new("bob")],
+            groups: Vec::new(),
+            timeout_seconds: None,
+        };
+        config.auth_backends = vec![
+            backend(
+                "corp",
+                "ldaps://ldap.corp.com",
+                "uid={user_id},dc=corp,dc=com",
+            ),
+            backend("partners", "ldap://10.0.0.1:3890", "{user_id}@partners.com"),
+        ];
+        check_options(&config, &mut check);
+        assert_eq!(check, ConfigCheck::default());
+        config.auth_backends[1].name = "corp".to_owned();
+        check_options(&config, &mut check);
+        config.auth_backends[1] = backend("partners", "http://10.0.0.1", "{user_id}");
+        check_options(&config, &mut check);
+        config.auth_backends[1] = backend("partners", "ldap://10.0.0.1", "cn=admin");
+        check_options(&config, &mut check);
+        config.auth_backends[1].users.clear();
+        check_options(&config, &mut check);
+        assert_eq!(check.errors.len(), 4);
+        assert!(check.errors[0].contains("Duplicate"));
+        assert!(check.errors[1].contains("ldap://"));
+        assert!(check.errors[2].contains("{user_id}"));
+        assert!(check.errors[3].contains("neither"));
+        config.auth_backends.clear();
+
+        let mut check = ConfigCheck::default();
+        let listener = |protocol, host: &str, port| ListenerOptions {
+            protocol,
+            host: host.to_owned(),
+            port,
+            tls: None,
+        };
+        config.listeners = vec![
+            listener(ListenerProtocol::Ldap, "10.0.0.1", 3890),
+            listener(ListenerProtocol::Ldaps, "::", 6360),
+            listener(ListenerProtocol::Http, "0.0.0.0", 17170),
+        ];
+        check_options(&config, &mut check);
+        assert_eq!(check, ConfigCheck::default());
+        config.listeners[0].port = 6360;
+        config.listeners[0].host = "::".to_owned();
+        check_options(&config, &mut check);
+        config.listeners[0] = listener(ListenerProtocol::Ldap, "127.0.0.1", 3890);
+        let tls = ListenerTlsOptions {
+            cert_file: "cert.pem".to_owned(),
+            key_file: "key.pem".to_owned(),
+        };
+        config.listeners[2].tls = Some(tls.clone());
+        check_options(&config, &mut check);
+        config.listeners[2].tls = None;
+        config.listeners.push(ListenerOptions {
+            tls: Some(tls),
+            ..listener(ListenerProtocol::Metrics, "127.0.0.1", 9090)
+        });
+        check_options(&config, &mut check);
+        config.listeners.truncate(2);
+        check_options(&config, &mut check);
+        assert_eq!(check.errors.len(), 4);
+        assert_eq!(check.errors[0], "Several listeners on [::]:6360");
+        assert!(check.errors[1].contains("reverse proxy"));
+        assert!(check.errors[2].starts_with("The metrics listener on 127.0.0.1:9090"));
+        assert!(check.errors[3].starts_with("No HTTP listener"));
+    }
+
+    #[tokio::test]
+    async fn test_check_schema_version() {
+        let mut sql_opt = sea_orm::ConnectOptions::new("sqlite::memory:".to_owned());
+        sql_opt.max_connections(1).sqlx_logging(false);
+        let sql_pool = Database::connect(sql_opt).await.unwrap();
+        // An empty DB.
+        let mut check = ConfigCheck::default();
+        check_schema_version(&sql_pool, &mut check).await;
+        assert_eq!(check, ConfigCheck::default());
+        init_table(&sql_pool, UuidBackfill::default())
+            .await
+            .unwrap();
+        check_schema_version(&sql_pool, &mut check).await;
+        assert_eq!(check, ConfigCheck::default());
+    }
+
+    #[tokio::test]
+    async fn test_check_database_unreachable() {
+        let mut config = make_config();
+        config.database_url = "sqlite:///nonexistent/users.db?mode=ro".to_owned();
+        let mut check = ConfigCheck::default();
+        check_database(&config, &mut check).await;
+        assert_eq!(check.errors.len(), 1);
+        assert!(check.errors[0].starts_with("Could not connect to the DB"));
+    }
+}
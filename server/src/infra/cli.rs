@@ -1,5 +1,6 @@
 use clap::Parser;
 use lettre::message::Mailbox;
+use secstr::SecUtf8;
 use serde::{Deserialize, Serialize};
 
 /// lldap is a lightweight LDAP server
@@ -26,6 +27,32 @@ pub enum Command {
     /// Send a test email.
     #[clap(name = "send_test_email")]
     SendTestEmail(TestEmailOpts),
+    /// Upgrade the DB schema to the version of this binary, without starting the server.
+    #[clap(name = "migrate")]
+    Migrate(MigrateOpts),
+    /// Check the configuration, the secrets and the DB connection, without starting the server.
+    /// Exits with an error if anything is wrong.
+    #[clap(name = "check_config", alias = "check-config")]
+    CheckConfig(RunOpts),
+    /// Apply the group rules to all the users, e.g. after creating a rule, and print the number
+    /// of memberships added or removed.
+    #[clap(name = "reevaluate_group_rules")]
+    ReevaluateGroupRules(RunOpts),
+    /// Rebuild the data derived from the users and groups, e.g. after a bulk import or manual
+    /// edits of the DB, and print what changed. The server can keep running.
+    #[clap(name = "rebuild_derived_data", alias = "rebuild-derived-data")]
+    RebuildDerivedData(MaintenanceOpts),
+    /// Set the password of a user directly in the DB, and unlock the account, e.g. when the admin
+    /// is locked out. The server doesn't need to be running.
+    #[clap(name = "reset_password", alias = "reset-password")]
+    ResetPassword(ResetPasswordOpts),
+    /// Export the content of the DB to a JSON file, from a consistent snapshot. The server can
+    /// keep running.
+    #[clap(name = "backup")]
+    Backup(BackupOpts),
+    /// Replace the content of the DB with a backup made by the same version of LLDAP.
+    #[clap(name = "restore")]
+    Restore(RestoreOpts),
 }
 
 #[derive(Debug, Parser, Clone)]
@@ -79,6 +106,10 @@ pub struct RunOpts {
 
     #[clap(flatten)]
     pub ldaps_opts: LdapsOpts,
+
+    /// Only check the configuration, like the `check_config` command, then exit.
+    #[clap(long)]
+    pub check_only: bool,
 }
 
 #[derive(Debug, Parser, Clone)]
@@ -94,6 +125,62 @@ pub struct TestEmailOpts {
     pub smtp_opts: SmtpOpts,
 }
 
+#[derive(Debug, Parser, Clone)]
+pub struct MigrateOpts {
+    #[clap(flatten)]
+    pub general_config: GeneralConfigOpts,
+
+    /// Only print the migrations that would run.
+    #[clap(long)]
+    pub dry_run: bool,
+}
+
+#[derive(Debug, Parser, Clone)]
+pub struct MaintenanceOpts {
+    #[clap(flatten)]
+    pub general_config: GeneralConfigOpts,
+
+    /// Only print what would change.
+    #[clap(long)]
+    pub dry_run: bool,
+}
+
+#[derive(Debug, Parser, Clone)]
+pub struct ResetPasswordOpts {
+    #[clap(flatten)]
+    pub general_config: GeneralConfigOpts,
+
+    /// The user whose password to reset.
+    pub user_id: String,
+
+    /// The new password. Read from the standard input if not set, to keep it out of the shell
+    /// history.
+    #[clap(long)]
+    pub password: Option<String>,
+}
+
+#[derive(Debug, Parser, Clone)]
+pub struct BackupOpts {
+    #[clap(flatten)]
+    pub general_config: GeneralConfigOpts,
+
+    /// The file to create. It contains the password hashes: keep it safe.
+    pub output: String,
+}
+
+#[derive(Debug, Parser, Clone)]
+pub struct RestoreOpts {
+    #[clap(flatten)]
+    pub general_config: GeneralConfigOpts,
+
+    /// The file created by the backup command.
+    pub input: String,
+
+    /// Replace the content of the DB even if it already has users.
+    #[clap(long)]
+    pub force: bool,
+}
+
 #[derive(Debug, Parser, Clone)]
 #[clap(next_help_heading = Some("LDAPS"), setting = clap::AppSettings::DeriveDisplayOrder)]
 pub struct LdapsOpts {
@@ -112,6 +199,18 @@ pub struct LdapsOpts {
     /// Ldaps certificate key file. Default: key.pem
     #[clap(long, env = "LLDAP_LDAPS_OPTIONS__KEY_FILE")]
     pub ldaps_key_file: Option<String>,
+
+    /// Enable StartTLS on the LDAP port, with the LDAPS certificate. Default: false.
+    #[clap(long, env = "LLDAP_LDAPS_OPTIONS__START_TLS")]
+    pub ldaps_start_tls: Option<bool>,
+
+    /// Reject the binds over unencrypted connections. Default: false.
+    #[clap(long, env = "LLDAP_LDAPS_OPTIONS__REQUIRE_TLS")]
+    pub ldaps_require_tls: Option<bool>,
+
+    /// The CAs of the client certificates, for the SASL EXTERNAL binds.
+    #[clap(long, env = "LLDAP_LDAPS_OPTIONS__CLIENT_CA_FILE")]
+    pub ldaps_client_ca_file: Option<String>,
 }
 
 clap::arg_enum! {
@@ -119,6 +218,7 @@ clap::arg_enum! {
 pub enum SmtpEncryption {
     TLS,
     STARTTLS,
+    NONE,
 }
 }
 
@@ -146,8 +246,13 @@ pub struct SmtpOpts {
     pub smtp_user: Option<String>,
 
     /// SMTP password.
-    #[clap(long, env = "LLDAP_SMTP_OPTIONS__PASSWORD", hide_env_values = true)]
-    pub smtp_password: Option<String>,
+    #[clap(
+        long,
+        env = "LLDAP_SMTP_OPTIONS__PASSWORD",
+        hide_env_values = true,
+        parse(from_str = SecUtf8::from)
+    )]
+    pub smtp_password: Option<SecUtf8>,
 
     /// Whether TLS should be used to connect to SMTP.
     #[clap(long, env = "LLDAP_SMTP_OPTIONS__TLS_REQUIRED", setting=clap::ArgSettings::Hidden)]
@@ -0,0 +1,207 @@
+//! The identity in the client certificates of the LDAPS and StartTLS connections, for the SASL
+//! EXTERNAL binds. The chain and the validity dates are checked by rustls during the handshake:
+//! only the names are read here.
+use super::ldap_controls::{elements, split_element, OCTET_STRING_TAG, SEQUENCE_TAG};
+
+const SET_TAG: u8 = 0x31;
+const OID_TAG: u8 = 0x06;
+const VERSION_TAG: u8 = 0xA0;
+const EXTENSIONS_TAG: u8 = 0xA3;
+/// `rfc822Name [1] IA5String` of a `GeneralName`.
+const RFC822_NAME_TAG: u8 = 0x81;
+/// `id-at-commonName` (2.5.4.3), encoded.
+const COMMON_NAME_OID: &[u8] = &[0x55, 0x04, 0x03];
+/// `id-ce-subjectAltName` (2.5.29.17), encoded.
+const SUBJECT_ALT_NAME_OID: &[u8] = &[0x55, 0x1D, 0x11];
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ClientCertificate {
+    /// The last CN of the subject.
+    pub common_name: Option<String>,
+    /// The emails of the subject alternative names.
+    pub emails: Vec<String>,
+}
+
+impl ClientCertificate {
+    /// Reads the names of a DER certificate: `Certificate ::= SEQUENCE { tbsCertificate
+    /// TBSCertificate, signatureAlgorithm, signatureValue }`.
+    pub fn parse(der: &[u8]) -> Option<Self> {
+        let certificate = match split_element(der)? {
+            (SEQUENCE_TAG, certificate, _) => certificate,
+            _ => return None,
+        };
+        let tbs_certificate = match split_element(certificate)? {
+            (SEQUENCE_TAG, tbs_certificate, _) => tbs_certificate,
+            _ => return None,
+        };
+        // `TBSCertificate ::= SEQUENCE { version [0] EXPLICIT OPTIONAL, serialNumber, signature,
+        // issuer, validity, subject, subjectPublicKeyInfo, issuerUniqueID [1] IMPLICIT OPTIONAL,
+        // subjectUniqueID [2] IMPLICIT OPTIONAL, extensions [3] EXPLICIT OPTIONAL }`
+        let fields = elements(tbs_certificate)
+            .filter(|(tag, _)| *tag != VERSION_TAG)
+            .collect::<Vec<_>>();
+        let subject = match fields.get(4)? {
+            (SEQUENCE_TAG, subject) => subject,
+            _ => return None,
+        };
+        let emails = fields
+            .iter()
+            .find(|(tag, _)| *tag == EXTENSIONS_TAG)
+            .and_then(|(_, extensions)| parse_alt_name_emails(extensions))
+            .unwrap_or_default();
+        Some(Self {
+            common_name: parse_common_name(subject),
+            emails,
+        })
+    }
+}
+
+/// `Name ::= SEQUENCE OF SET OF SEQUENCE { type OBJECT IDENTIFIER, value ANY }`, without the
+/// outer sequence.
+fn parse_common_name(name: &[u8]) -> Option<String> {
+    elements(name)
+        .filter(|(tag, _)| *tag == SET_TAG)
+        .flat_map(|(_, attributes)| elements(attributes))
+        .filter(|(tag, _)| *tag == SEQUENCE_TAG)
+        .filter_map(|(_, attribute)| {
+            let mut fields = elements(attribute);
+            match fields.next()? {
+                (OID_TAG, COMMON_NAME_OID) => {
+                    // A UTF8String, PrintableString or IA5String.
+                    String::from_utf8(fields.next()?.1.to_vec()).ok()
+                }
+                _ => None,
+            }
+        })
+        .last()
+}
+
+/// `[3] EXPLICIT Extensions`, with `Extensions ::= SEQUENCE OF SEQUENCE { extnID OBJECT
+/// IDENTIFIER, critical BOOLEAN DEFAULT FALSE, extnValue OCTET STRING }`.
+fn parse_alt_name_emails(extensions: &[u8]) -> Option<Vec<String>> {
+    let extensions = match split_element(extensions)? {
+        (SEQUENCE_TAG, extensions, _) => extensions,
+        _ => return None,
+    };
+    let alt_names = elements(extensions)
+        .filter(|(tag, _)| *tag == SEQUENCE_TAG)
+        .find_map(|(_, extension)| {
+            let mut fields = elements(extension);
+            match fields.next()? {
+                (OID_TAG, SUBJECT_ALT_NAME_OID) => fields
+                    .find(|(tag, _)| *tag == OCTET_STRING_TAG)
+                    .map(|(_, value)| value),
+                _ => None,
+            }
+        })?;
+    let alt_names = match split_element(alt_names)? {
+        (SEQUENCE_TAG, alt_names, _) => alt_names,
+        _ => return None,
+    };
+    Some(
+        elements(alt_names)
+            .filter(|(tag, _)| *tag == RFC822_NAME_TAG)
+            .filter_map(|(_, email)| String::from_utf8(email.to_vec()).ok())
+            .collect(),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::infra::ldap_controls::make_element;
+
+    fn attribute(oid: &[u8], value: &str) -> Vec<u8> {
+        make_element(
+            SET_TAG,
+            &make_element(
+                SEQUENCE_TAG,
+                &[
+                    make_element(OID_TAG, oid),
+                    make_element(0x0C, value.as_bytes()),
+                ]
+                .concat(),
+            ),
+        )
+    }
+
+    fn certificate(subject: &[Vec<u8>], emails: Option<&[&str]>) -> Vec<u8> {
+        let mut tbs_certificate = [
+            make_element(VERSION_TAG, &make_element(0x02, &[2])),
+            make_element(0x02, &[42]),
+            make_element(SEQUENCE_TAG, &[]),
+            make_element(SEQUENCE_TAG, &attribute(COMMON_NAME_OID, "Example CA")),
+            make_element(SEQUENCE_TAG, &[]),
+            make_element(SEQUENCE_TAG, &subject.concat()),
+            make_element(SEQUENCE_TAG, &[]),
+        ]
+        .concat();
+        if let Some(emails) = emails {
+            let alt_names = make_element(
+                SEQUENCE_TAG,
+                &emails
+                    .iter()
+                    .map(|email| make_element(RFC822_NAME_TAG, email.as_bytes()))
+                    .chain(std::iter::once(make_element(0x82, b"example.com")))
+                    .collect::<Vec<_>>()
+                    .concat(),
+            );
+            let extension = make_element(
+                SEQUENCE_TAG,
+                &[
+                    make_element(OID_TAG, SUBJECT_ALT_NAME_OID),
+                    make_element(OCTET_STRING_TAG, &alt_names),
+                ]
+                .concat(),
+            );
+            tbs_certificate.extend(make_element(
+                EXTENSIONS_TAG,
+                &make_element(SEQUENCE_TAG, &extension),
+            ));
+        }
+        make_element(
+            SEQUENCE_TAG,
+            &[
+                make_element(SEQUENCE_TAG, &tbs_certificate),
+                make_element(SEQUENCE_TAG, &[]),
+                make_element(0x03, &[0]),
+            ]
+            .concat(),
+        )
+    }
+
+    #[test]
+    fn test_parse_common_name() {
+        // The organization (2.5.4.10) is not a name.
+        let der = certificate(
+            &[
+                attribute(&[0x55, 0x04, 0x0A], "Example"),
+                attribute(COMMON_NAME_OID, "bob"),
+            ],
+            None,
+        );
+        assert_eq!(
+            ClientCertificate::parse(&der),
+            Some(ClientCertificate {
+                common_name: Some("bob".to_string()),
+                emails: vec![],
+            })
+        );
+    }
+
+    #[test]
+    fn test_parse_alt_name_emails() {
+        let der = certificate(&[], Some(&["bob@example.com", "robert@example.com"]));
+        assert_eq!(
+            ClientCertificate::parse(&der),
+            Some(ClientCertificate {
+                common_name: None,
+                emails: vec![
+                    "bob@example.com".to_string(),
+                    "robert@example.com".to_string()
+                ],
+            })
+        );
+        assert_eq!(ClientCertificate::parse(&der[..der.len() - 1]), None);
+    }
+}
@@ -0,0 +1,462 @@
+//! The address of the client, for the rate limits, the connection limits, the sessions and the
+//! logs. Behind a reverse proxy, the peer is the proxy: the address of the client is then taken
+//! from the `X-Forwarded-For` or `Forwarded` header (HTTP) or from the PROXY protocol header
+//! (LDAP), but only if the peer is one of the trusted proxies. Anyone else could forge them.
+use crate::infra::configuration::{ForwardedHeader, ProxyOptions};
+use actix_web::http::header::HeaderMap;
+use anyhow::{anyhow, bail, Context, Result};
+use std::{
+    net::{IpAddr, Ipv4Addr, Ipv6Addr},
+    str::FromStr,
+};
+use tokio::io::{AsyncRead, AsyncReadExt};
+
+/// An IPv4-mapped IPv6 address (`::ffff:10.0.0.1`), as given by a dual-stack socket, is the
+/// IPv4 address.
+fn canonical(ip: IpAddr) -> IpAddr {
+    match ip {
+        IpAddr::V6(v6) => match v6.segments() {
+            [0, 0, 0, 0, 0, 0xffff, high, low] => IpAddr::V4(Ipv4Addr::new(
+                (high >> 8) as u8,
+                high as u8,
+                (low >> 8) as u8,
+                low as u8,
+            )),
+            _ => ip,
+        },
+        IpAddr::V4(_) => ip,
+    }
+}
+
+/// An address or a CIDR network, e.g. `10.0.0.0/8` or `fd00::/8`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct IpNetwork {
+    address: IpAddr,
+    prefix_length: u32,
+}
+
+impl FromStr for IpNetwork {
+    type Err = anyhow::Error;
+
+    fn from_str(network: &str) -> Result<Self> {
+        let (address, prefix_length) = match network.split_once('/') {
+            Some((address, prefix_length)) => (address, Some(prefix_length)),
+            None => (network, None),
+        };
+        let address = canonical(
+            address
+                .trim()
+                .parse::<IpAddr>()
+                .with_context(|| format!("Invalid address in `{}`", network))?,
+        );
+        let max_length = if address.is_ipv4() { 32 } else { 128 };
+        let prefix_length = match prefix_length {
+            Some(prefix_length) => prefix_length
+                .trim()
+                .parse::<u32>()
+                .ok()
+                .filter(|length| *length <= max_length)
+                .ok_or_else(|| anyhow!("Invalid prefix length in `{}`", network))?,
+            None => max_length,
+        };
+        Ok(Self {
+            address,
+            prefix_length,
+        })
+    }
+}
+
+impl IpNetwork {
+    pub fn contains(&self, ip: IpAddr) -> bool {
+        // A zero prefix length shifts all the bits out: the network matches everything.
+        let mask = |bits: u32| {
+            u128::MAX
+                .checked_shl(bits - self.prefix_length)
+                .unwrap_or(0)
+        };
+        match (self.address, canonical(ip)) {
+            (IpAddr::V4(network), IpAddr::V4(ip)) => {
+                let mask = mask(32) as u32;
+                u32::from(network) & mask == u32::from(ip) & mask
+            }
+            (IpAddr::V6(network), IpAddr::V6(ip)) => {
+                let mask = mask(128);
+                u128::from(network) & mask == u128::from(ip) & mask
+            }
+            _ => false,
+        }
+    }
+}
+
+/// The `for` addresses of a `Forwarded` header (RFC 7239), from the client to the last proxy.
+/// The obfuscated and unknown ones are `None`.
+fn parse_forwarded(value: &str) -> Vec<Option<IpAddr>> {
+    value
+        .split(',')
+        .filter_map(|element| {
+            element.split(';').find_map(|pair| {
+                let (name, node) = pair.split_once('=')?;
+                name.trim()
+                    .eq_ignore_ascii_case("for")
+                    .then(|| parse_node(node.trim().trim_matches('"')))
+            })
+        })
+        .collect()
+}
+
+/// `192.0.2.43`, `192.0.2.43:47011`, `[2001:db8::1]` or `[2001:db8::1]:4711`.
+fn parse_node(node: &str) -> Option<IpAddr> {
+    let address = match node.strip_prefix('[') {
+        Some(rest) => rest.split_once(']')?.0,
+        None if node.matches(':').count() == 1 => node.split_once(':')?.0,
+        None => node,
+    };
+    address.parse().ok()
+}
+
+/// The addresses of an `X-Forwarded-For` header, from the client to the last proxy.
+fn parse_x_forwarded_for(value: &str) -> Vec<Option<IpAddr>> {
+    value
+        .split(',')
+        .map(|node| parse_node(node.trim()))
+        .collect()
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct TrustedProxies {
+    networks: Vec<IpNetwork>,
+    forwarded_header: ForwardedHeader,
+}
+
+impl TrustedProxies {
+    pub fn new(options: &ProxyOptions) -> Result<Self> {
+        Ok(Self {
+            networks: options
+                .trusted_proxies
+                .iter()
+                .map(|network| network.parse())
+                .collect::<Result<_>>()
+                .context("Invalid `proxy_options.trusted_proxies`")?,
+            forwarded_header: options.forwarded_header,
+        })
+    }
+
+    pub fn is_trusted(&self, ip: IpAddr) -> bool {
+        self.networks.iter().any(|network| network.contains(ip))
+    }
+
+    /// Walks back the hops, from the peer, as long as they are trusted proxies: the first one
+    /// that isn't is the client. Each trusted proxy adds the address it received the request
+    /// from at the end of the list, so the entries before the last untrusted hop are ignored,
+    /// however the client forged them.
+    fn walk_back(&self, peer: IpAddr, hops: Vec<Option<IpAddr>>) -> IpAddr {
+        let mut client = canonical(peer);
+        for hop in hops.into_iter().rev() {
+            if !self.is_trusted(client) {
+                break;
+            }
+            match hop {
+                Some(hop) => client = canonical(hop),
+                // An unknown or obfuscated hop hides the ones before it.
+                None => break,
+            }
+        }
+        client
+    }
+
+    /// The address of the client of an HTTP request.
+    pub fn http_client_ip(&self, peer: Option<IpAddr>, headers: &HeaderMap) -> Option<IpAddr> {
+        let peer = peer?;
+        if !self.is_trusted(peer) {
+            return Some(canonical(peer));
+        }
+        let (name, parse): (_, fn(&str) -> Vec<Option<IpAddr>>) = match self.forwarded_header {
+            ForwardedHeader::XForwardedFor => ("x-forwarded-for", parse_x_forwarded_for),
+            ForwardedHeader::Forwarded => ("forwarded", parse_forwarded),
+        };
+        // The header can be repeated, each proxy adding its own line.
+        let mut hops = Vec::new();
+        for value in headers.get_all(name) {
+            match value.to_str() {
+                Ok(value) => hops.extend(parse(value)),
+                Err(_) => hops.push(None),
+            }
+        }
+        Some(self.walk_back(peer, hops))
+    }
+}
+
+const PROXY_V1_PREFIX: &[u8] = b"PROXY ";
+const PROXY_V2_SIGNATURE: &[u8; 12] = b"\r\n\r\n\0\r\nQUIT\n";
+/// "PROXY TCP6 <39 chars> <39 chars> 65535 65535\r\n".
+const PROXY_V1_MAX_LENGTH: usize = 107;
+
+fn parse_proxy_v1(line: &str) -> Result<Option<IpAddr>> {
+    let fields = line.split(' ').collect::<Vec<_>>();
+    match fields.as_slice() {
+        ["PROXY", "UNKNOWN", ..] => Ok(None),
+        ["PROXY", "TCP4", source, _, _, _] => Ok(Some(IpAddr::V4(source.parse()?))),
+        ["PROXY", "TCP6", source, _, _, _] => Ok(Some(IpAddr::V6(source.parse()?))),
+        _ => bail!("Invalid PROXY protocol v1 header {:?}", line),
+    }
+}
+
+fn parse_proxy_v2(version_command: u8, family: u8, addresses: &[u8]) -> Result<Option<IpAddr>> {
+    if version_command >> 4 != 2 {
+        bail!(
+            "Unsupported PROXY protocol version {}",
+            version_command >> 4
+        );
+    }
+    match version_command & 0x0F {
+        // LOCAL: a health check of the proxy itself.
+        0 => return Ok(None),
+        1 => (),
+        command => bail!("Unsupported PROXY protocol command {}", command),
+    }
+    // The high nibble is the address family, the low one the transport.
+    match family >> 4 {
+        0 => Ok(None),
+        1 if addresses.len() >= 12 => {
+            let source: [u8; 4] = addresses[..4].try_into()?;
+            Ok(Some(IpAddr::V4(Ipv4Addr::from(source))))
+        }
+        2 if addresses.len() >= 36 => {
+            let source: [u8; 16] = addresses[..16].try_into()?;
+            Ok(Some(IpAddr::V6(Ipv6Addr::from(source))))
+        }
+        // Unix sockets.
+        3 => Ok(None),
+        _ => bail!("Invalid PROXY protocol v2 addresses"),
+    }
+}
+
+/// Reads the PROXY protocol header (v1 or v2) that starts the connection, and nothing more:
+/// the rest is the LDAP or TLS stream. Returns the source address, `None` if the proxy doesn't
+/// give one, e.g. for its own health checks.
+pub async fn read_proxy_header<Stream: AsyncRead + Unpin>(
+    stream: &mut Stream,
+) -> Result<Option<IpAddr>> {
+    // Both versions are at least this long.
+    let mut start = [0u8; 12];
+    stream.read_exact(&mut start).await?;
+    if &start == PROXY_V2_SIGNATURE {
+        let mut header = [0u8; 4];
+        stream.read_exact(&mut header).await?;
+        let length = u16::from_be_bytes([header[2], header[3]]) as usize;
+        let mut addresses = vec![0u8; length];
+        stream.read_exact(&mut addresses).await?;
+        return parse_proxy_v2(header[0], header[1], &addresses);
+    }
+    if !start.starts_with(PROXY_V1_PREFIX) {
+        bail!("The connection doesn't start with a PROXY protocol header");
+    }
+    let mut line = start.to_vec();
+    while !line.ends_with(b"\r\n") {
+        if line.len() >= PROXY_V1_MAX_LENGTH {
+            bail!("The PROXY protocol v1 header is too long");
+        }
+        line.push(stream.read_u8().await?);
+    }
+    line.truncate(line.len() - 2);
+    parse_proxy_v1(std::str::from_utf8(&line)?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::infra::configuration::ProxyOptionsBuilder;
+    use actix_web::http::header::{HeaderName, HeaderValue};
+
+    fn ip(ip: &str) -> IpAddr {
+        ip.parse().unwrap()
+    }
+
+    fn trusted_proxies(networks: &[&str], forwarded_header: ForwardedHeader) -> TrustedProxies {
+        TrustedProxies::new(
+            &ProxyOptionsBuilder::default()
+                .trusted_proxies(networks.iter().map(|n| n.to_string()).collect())
+                .forwarded_header(forwarded_header)
+                .build()
+                .unwrap(),
+        )
+        .unwrap()
+    }
+
+    fn headers(headers: &[(&'static str, &'static str)]) -> HeaderMap {
+        let mut map = HeaderMap::new();
+        for (name, value) in headers {
+            map.append(
+                HeaderName::from_static(name),
+                HeaderValue::from_static(value),
+            );
+        }
+        map
+    }
+
+    #[test]
+    fn test_ip_network() {
+        let network = "10.1.0.0/16".parse::<IpNetwork>().unwrap();
+        assert!(network.contains(ip("10.1.200.3")));
+        assert!(network.contains(ip("::ffff:10.1.0.1")));
+        assert!(!network.contains(ip("10.2.0.1")));
+        assert!(!network.contains(ip("fd00::1")));
+        let network = "fd00::/8".parse::<IpNetwork>().unwrap();
+        assert!(network.contains(ip("fd12:3456::1")));
+        assert!(!network.contains(ip("fe80::1")));
+        let single = "192.168.1.1".parse::<IpNetwork>().unwrap();
+        assert!(single.contains(ip("192.168.1.1")));
+        assert!(!single.contains(ip("192.168.1.2")));
+        assert!("0.0.0.0/0"
+            .parse::<IpNetwork>()
+            .unwrap()
+            .contains(ip("8.8.8.8")));
+        assert!("10.0.0.0/33".parse::<IpNetwork>().is_err());
+        assert!("proxy.local".parse::<IpNetwork>().is_err());
+    }
+
+    #[test]
+    fn test_x_forwarded_for() {
+        let proxies = trusted_proxies(&["10.0.0.0/8"], ForwardedHeader::XForwardedFor);
+        let client_ip = |peer: &str, values: &[&'static str]| {
+            proxies.http_client_ip(
+                Some(ip(peer)),
+                &headers(
+                    &values
+                        .iter()
+                        .map(|value| ("x-forwarded-for", *value))
+                        .collect::<Vec<_>>(),
+                ),
+            )
+        };
+        assert_eq!(
+            client_ip("10.0.0.1", &["203.0.113.7"]),
+            Some(ip("203.0.113.7"))
+        );
+        // Through two proxies, over two header lines.
+        assert_eq!(
+            client_ip("10.0.0.1", &["203.0.113.7", "10.0.0.2"]),
+            Some(ip("203.0.113.7"))
+        );
+        // The entries that the client added are ignored.
+        assert_eq!(
+            client_ip("10.0.0.1", &["1.2.3.4, 203.0.113.7"]),
+            Some(ip("203.0.113.7"))
+        );
+        // An untrusted peer can't spoof its address.
+        assert_eq!(
+            client_ip("198.51.100.1", &["10.0.0.5"]),
+            Some(ip("198.51.100.1"))
+        );
+        // Without the header, or with garbage, the client is the last known hop.
+        assert_eq!(client_ip("10.0.0.1", &[]), Some(ip("10.0.0.1")));
+        assert_eq!(
+            client_ip("10.0.0.1", &["203.0.113.7, unknown"]),
+            Some(ip("10.0.0.1"))
+        );
+        // All trusted: the first one.
+        assert_eq!(
+            client_ip("10.0.0.1", &["10.0.0.3, 10.0.0.2"]),
+            Some(ip("10.0.0.3"))
+        );
+        assert_eq!(
+            client_ip("::ffff:10.0.0.1", &["[2001:db8::1]:4711"]),
+            Some(ip("2001:db8::1"))
+        );
+    }
+
+    #[test]
+    fn test_forwarded() {
+        let proxies = trusted_proxies(&["10.0.0.1", "fd00::/8"], ForwardedHeader::Forwarded);
+        let client_ip = |peer: &str, value: &'static str| {
+            proxies.http_client_ip(Some(ip(peer)), &headers(&[("forwarded", value)]))
+        };
+        assert_eq!(
+            client_ip(
+                "10.0.0.1",
+                "for=198.51.100.2;proto=https, for=\"[fd00::5]:8080\";by=10.0.0.1"
+            ),
+            Some(ip("198.51.100.2"))
+        );
+        assert_eq!(
+            client_ip("10.0.0.1", "for=\"192.0.2.43:47011\""),
+            Some(ip("192.0.2.43"))
+        );
+        assert_eq!(client_ip("10.0.0.1", "for=_hidden"), Some(ip("10.0.0.1")));
+        // The other header is ignored.
+        assert_eq!(
+            proxies.http_client_ip(
+                Some(ip("10.0.0.1")),
+                &headers(&[("x-forwarded-for", "192.0.2.43")])
+            ),
+            Some(ip("10.0.0.1"))
+        );
+        assert_eq!(client_ip("10.0.0.2", "for=1.2.3.4"), Some(ip("10.0.0.2")));
+    }
+
+    #[tokio::test]
+    async fn test_proxy_protocol_v1() {
+        let read = |header: &'static [u8]| async move {
+            let mut stream = header;
+            let result = read_proxy_header(&mut stream).await;
+            (result.map_err(|e| e.to_string()), stream)
+        };
+        let (result, rest) = read(b"PROXY TCP4 203.0.113.7 10.0.0.1 51234 389\r\n0\x0c").await;
+        assert_eq!(result, Ok(Some(ip("203.0.113.7"))));
+        // The LDAP message is left in the stream.
+        assert_eq!(rest, b"0\x0c");
+        let (result, _) = read(b"PROXY TCP6 2001:db8::1 fd00::1 51234 636\r\n").await;
+        assert_eq!(result, Ok(Some(ip("2001:db8::1"))));
+        let (result, _) = read(b"PROXY UNKNOWN\r\n").await;
+        assert_eq!(result, Ok(None));
+        let (result, _) = read(b"PROXY TCP4 nonsense 10.0.0.1 1 2\r\n").await;
+        assert!(result.is_err());
+        // A plain LDAP connection.
+        let (result, _) = read(b"0\x0c\x02\x01\x01`\x07\x02\x01\x03\x04\x00\x80\x00").await;
+        assert!(result.is_err());
+        let too_long = [PROXY_V1_PREFIX, &[b'x'; 200]].concat();
+        assert!(read_proxy_header(&mut too_long.as_slice())
+            .await
+            .unwrap_err()
+            .to_string()
+            .contains("too long"));
+    }
+
+    #[tokio::test]
+    async fn test_proxy_protocol_v2() {
+        let header = |command: u8, family: u8, addresses: &[u8]| {
+            let mut header = PROXY_V2_SIGNATURE.to_vec();
+            header.extend([0x20 | command, family]);
+            header.extend((addresses.len() as u16).to_be_bytes());
+            header.extend(addresses);
+            header.extend(b"rest");
+            header
+        };
+        let read = |bytes: Vec<u8>| async move {
+            let mut stream = bytes.as_slice();
+            let result = read_proxy_header(&mut stream)
+                .await
+                .map_err(|e| e.to_string());
+            (result, stream.to_vec())
+        };
+        let ipv4 = [203, 0, 113, 7, 10, 0, 0, 1, 0xC8, 0x22, 0x01, 0x85];
+        let (result, rest) = read(header(1, 0x11, &ipv4)).await;
+        assert_eq!(result, Ok(Some(ip("203.0.113.7"))));
+        assert_eq!(rest, b"rest");
+        let mut ipv6 = ip_bytes("2001:db8::1");
+        ipv6.extend(ip_bytes("fd00::1"));
+        ipv6.extend([0, 1, 0, 2]);
+        let (result, _) = read(header(1, 0x21, &ipv6)).await;
+        assert_eq!(result, Ok(Some(ip("2001:db8::1"))));
+        // LOCAL, e.g. the health checks of the proxy.
+        let (result, rest) = read(header(0, 0x00, &[])).await;
+        assert_eq!(result, Ok(None));
+        assert_eq!(rest, b"rest");
+        let (result, _) = read(header(1, 0x11, &ipv4[..4])).await;
+        assert!(result.is_err());
+    }
+
+    fn ip_bytes(ip: &str) -> Vec<u8> {
+        ip.parse::<Ipv6Addr>().unwrap().octets().to_vec()
+    }
+}
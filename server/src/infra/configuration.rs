@@ -1,8 +1,21 @@
 use crate::{
-    domain::types::UserId,
-    infra::cli::{GeneralConfigOpts, LdapsOpts, RunOpts, SmtpEncryption, SmtpOpts, TestEmailOpts},
+    domain::{
+        ldap::utils::{is_subtree, parse_distinguished_name},
+        totp::{TotpAlgorithm, TotpParameters},
+        types::UserId,
+    },
+    infra::{
+        access_control::{AclAttribute, AttributeAclRule},
+        cli::{
+            BackupOpts, GeneralConfigOpts, LdapsOpts, MaintenanceOpts, MigrateOpts,
+            ResetPasswordOpts, RestoreOpts, RunOpts, SmtpEncryption, SmtpOpts, TestEmailOpts,
+        },
+        client_ip::TrustedProxies,
+        security_headers::security_headers,
+        tls_policy::TlsPolicy,
+    },
 };
-use anyhow::{Context, Result};
+use anyhow::{anyhow, bail, Context, Result};
 use figment::{
     providers::{Env, Format, Serialized, Toml},
     Figment,
@@ -34,6 +47,12 @@ pub struct MailOptions {
     /// Deprecated.
     #[builder(default = "None")]
     pub tls_required: Option<bool>,
+    /// Log the emails instead of sending them, for testing.
+    #[builder(default = "false")]
+    pub log_only: bool,
+    /// Overrides the templates of the emails, e.g. `password_reset.txt`.
+    #[builder(default = "None")]
+    pub templates_dir: Option<String>,
 }
 
 impl std::default::Default for MailOptions {
@@ -53,6 +72,28 @@ pub struct LdapsOptions {
     pub cert_file: String,
     #[builder(default = r#"String::from("key.pem")"#)]
     pub key_file: String,
+    /// The certificate is reloaded when its files change, checked this often, and on SIGHUP. 0
+    /// to only reload on SIGHUP.
+    #[builder(default = "60")]
+    pub cert_reload_interval_seconds: u64,
+    /// Lets the clients of the plaintext LDAP port upgrade the connection with StartTLS, with the
+    /// certificate above.
+    #[builder(default = "false")]
+    pub start_tls: bool,
+    /// Rejects the binds over an unencrypted connection, with confidentialityRequired.
+    #[builder(default = "false")]
+    pub require_tls: bool,
+    /// Accepts the client certificates signed by these CAs, for the SASL EXTERNAL binds.
+    #[builder(default = "None")]
+    pub client_ca_file: Option<String>,
+    #[builder(default = "ClientCertificateUserMapping::CommonName")]
+    pub client_certificate_user_mapping: ClientCertificateUserMapping,
+    #[builder(default)]
+    pub min_tls_version: TlsVersion,
+    /// The accepted cipher suites, by IANA name, e.g. "TLS13_AES_256_GCM_SHA384". Empty for
+    /// the defaults of rustls, all with forward secrecy and authenticated encryption.
+    #[builder(default)]
+    pub cipher_suites: Vec<String>,
 }
 
 impl std::default::Default for LdapsOptions {
@@ -61,6 +102,566 @@ impl std::default::Default for LdapsOptions {
     }
 }
 
+#[derive(Clone, Debug, Deserialize, Serialize, derive_builder::Builder)]
+#[builder(pattern = "owned")]
+pub struct WebhookOptions {
+    /// Every event is posted to each of the URLs.
+    #[builder(default)]
+    pub urls: Vec<String>,
+    /// Key of the HMAC-SHA256 signature of the payloads.
+    #[builder(default = r#"SecUtf8::from("")"#)]
+    pub secret: SecUtf8,
+    /// After that many failed deliveries, the event is dropped.
+    #[builder(default = "20")]
+    pub max_attempts: u32,
+}
+
+impl std::default::Default for WebhookOptions {
+    fn default() -> Self {
+        WebhookOptionsBuilder::default().build().unwrap()
+    }
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize, derive_builder::Builder)]
+#[builder(pattern = "owned")]
+pub struct OidcOptions {
+    #[builder(default = "false")]
+    pub enabled: bool,
+    /// RSA key that signs the tokens. It will be created if it doesn't exist.
+    #[builder(default = r#"String::from("oidc_private_key.pem")"#)]
+    pub private_key_file: String,
+    /// Name of the claim that lists the groups of the user, with the "groups" scope.
+    #[builder(default = r#"String::from("groups")"#)]
+    pub groups_claim: String,
+    /// Validity of the ID and access tokens.
+    #[builder(default = "60")]
+    pub token_expiry_minutes: u32,
+}
+
+impl std::default::Default for OidcOptions {
+    fn default() -> Self {
+        OidcOptionsBuilder::default().build().unwrap()
+    }
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize, derive_builder::Builder)]
+#[builder(pattern = "owned")]
+pub struct RateLimitOptions {
+    #[builder(default = "true")]
+    pub enabled: bool,
+    /// Login attempts allowed in a row from a single IP address.
+    #[builder(default = "30")]
+    pub ip_burst: u32,
+    /// Login attempts regained every minute by an IP address, up to the burst.
+    #[builder(default = "30")]
+    pub ip_attempts_per_minute: u32,
+    /// Login attempts allowed in a row for a single user, from any address.
+    #[builder(default = "10")]
+    pub user_burst: u32,
+    #[builder(default = "5")]
+    pub user_attempts_per_minute: u32,
+}
+
+impl std::default::Default for RateLimitOptions {
+    fn default() -> Self {
+        RateLimitOptionsBuilder::default().build().unwrap()
+    }
+}
+
+/// The limits on the LDAP connections. 0 means no limit.
+#[derive(Clone, Debug, Deserialize, Serialize, derive_builder::Builder)]
+#[builder(pattern = "owned")]
+pub struct ConnectionLimitOptions {
+    /// Open connections, from all the clients.
+    #[builder(default = "0")]
+    pub max_connections: u32,
+    /// Open connections from a single IP address.
+    #[builder(default = "0")]
+    pub max_connections_per_ip: u32,
+    /// The connections without any request for this long are closed.
+    #[builder(default = "0")]
+    pub idle_timeout_seconds: u64,
+}
+
+impl std::default::Default for ConnectionLimitOptions {
+    fn default() -> Self {
+        ConnectionLimitOptionsBuilder::default().build().unwrap()
+    }
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize, derive_builder::Builder)]
+#[builder(pattern = "owned")]
+pub struct PasswordPolicyOptions {
+    #[builder(default = "8")]
+    pub min_length: usize,
+    #[builder(default = "128")]
+    pub max_length: usize,
+    #[builder(default = "false")]
+    pub require_lowercase: bool,
+    #[builder(default = "false")]
+    pub require_uppercase: bool,
+    #[builder(default = "false")]
+    pub require_digit: bool,
+    #[builder(default = "false")]
+    pub require_symbol: bool,
+    /// File with one forbidden password per line, compared case-insensitively.
+    #[builder(default = "None")]
+    pub banned_passwords_file: Option<String>,
+    /// Whether to look the passwords up in the Have I Been Pwned database. Only the first 5
+    /// characters of the SHA-1 of the password are sent.
+    #[builder(default = "false")]
+    pub breach_check_enabled: bool,
+    #[builder(default = r#"String::from("https://api.pwnedpasswords.com")"#)]
+    pub breach_check_url: String,
+}
+
+impl std::default::Default for PasswordPolicyOptions {
+    fn default() -> Self {
+        PasswordPolicyOptionsBuilder::default().build().unwrap()
+    }
+}
+
+/// The IDs accepted for the new users. The characters that must be escaped in a DN are never
+/// allowed.
+#[derive(Clone, Debug, Deserialize, Serialize, derive_builder::Builder)]
+#[builder(pattern = "owned")]
+pub struct UserIdPolicyOptions {
+    #[builder(default = "1")]
+    pub min_length: usize,
+    /// At most 255, the size of the column.
+    #[builder(default = "255")]
+    pub max_length: usize,
+    /// The characters allowed besides the ASCII letters and digits.
+    #[builder(default = r#"String::from("._-")"#)]
+    pub allowed_symbols: String,
+    /// Also allows the non-ASCII letters and digits, e.g. "josé".
+    #[builder(default = "false")]
+    pub allow_unicode_letters: bool,
+}
+
+impl std::default::Default for UserIdPolicyOptions {
+    fn default() -> Self {
+        UserIdPolicyOptionsBuilder::default().build().unwrap()
+    }
+}
+
+/// The header in which the trusted proxies pass the address of the client. Only the one that
+/// the proxies set is read: the other one would come from the client.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum ForwardedHeader {
+    #[default]
+    XForwardedFor,
+    Forwarded,
+}
+
+/// The reverse proxies in front of LLDAP, whose requests carry the address of the client.
+#[derive(Clone, Debug, Deserialize, Serialize, derive_builder::Builder)]
+#[builder(pattern = "owned")]
+pub struct ProxyOptions {
+    /// The addresses or CIDR networks of the proxies, e.g. "10.0.0.0/8".
+    #[builder(default)]
+    pub trusted_proxies: Vec<String>,
+    #[builder(default)]
+    pub forwarded_header: ForwardedHeader,
+    /// The LDAP and LDAPS connections of the trusted proxies start with a PROXY protocol
+    /// header, v1 or v2.
+    #[builder(default = "false")]
+    pub ldap_proxy_protocol: bool,
+}
+
+impl std::default::Default for ProxyOptions {
+    fn default() -> Self {
+        ProxyOptionsBuilder::default().build().unwrap()
+    }
+}
+
+/// The security headers of the HTTP responses.
+#[derive(Clone, Debug, Deserialize, Serialize, derive_builder::Builder)]
+#[builder(pattern = "owned")]
+pub struct SecurityHeadersOptions {
+    /// Sends `Strict-Transport-Security`. The browsers ignore it over plain HTTP.
+    #[builder(default = "true")]
+    pub hsts: bool,
+    #[builder(default = "31536000")]
+    pub hsts_max_age_seconds: u64,
+    #[builder(default = "false")]
+    pub hsts_include_subdomains: bool,
+    /// `X-Frame-Options`, empty to omit it.
+    #[builder(default = r#"String::from("DENY")"#)]
+    pub frame_options: String,
+    /// `Content-Security-Policy`, empty to omit it. The default allows the web app, its
+    /// WebAssembly and the CDNs of its styles and fonts.
+    #[builder(default = "DEFAULT_CONTENT_SECURITY_POLICY.to_owned()")]
+    pub content_security_policy: String,
+}
+
+const DEFAULT_CONTENT_SECURITY_POLICY: &str = "default-src 'self'; \
+    script-src 'self' 'wasm-unsafe-eval' https://cdn.jsdelivr.net; \
+    style-src 'self' 'unsafe-inline' https://cdn.jsdelivr.net https://cdnjs.cloudflare.com \
+    https://fonts.googleapis.com; \
+    font-src 'self' https://cdn.jsdelivr.net https://cdnjs.cloudflare.com https://fonts.gstatic.com; \
+    img-src 'self' data:; frame-ancestors 'none'";
+
+impl std::default::Default for SecurityHeadersOptions {
+    fn default() -> Self {
+        SecurityHeadersOptionsBuilder::default().build().unwrap()
+    }
+}
+
+/// The checks of the emails of the new and updated users.
+#[derive(Clone, Debug, Deserialize, Serialize, derive_builder::Builder)]
+#[builder(pattern = "owned")]
+pub struct EmailPolicyOptions {
+    /// Rejects the malformed emails. Off by default, to keep accepting the emails that were
+    /// accepted before; the imports always reject them.
+    #[builder(default = "false")]
+    pub validate: bool,
+    /// Trims the emails and lowercases their domain before storing them. The existing emails
+    /// are normalized on startup.
+    #[builder(default = "false")]
+    pub normalize: bool,
+    /// If not empty, only the emails of these domains and their subdomains are accepted.
+    #[builder(default)]
+    pub allowed_domains: Vec<String>,
+    /// The emails of these domains and their subdomains are rejected.
+    #[builder(default)]
+    pub blocked_domains: Vec<String>,
+}
+
+impl std::default::Default for EmailPolicyOptions {
+    fn default() -> Self {
+        EmailPolicyOptionsBuilder::default().build().unwrap()
+    }
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize, derive_builder::Builder)]
+#[builder(pattern = "owned")]
+pub struct PosixOptions {
+    /// The range of the automatically allocated uidNumbers, inclusive.
+    #[builder(default = "10000")]
+    pub uid_number_min: i32,
+    #[builder(default = "59999")]
+    pub uid_number_max: i32,
+    /// The range of the automatically allocated gidNumbers of the groups, inclusive.
+    #[builder(default = "10000")]
+    pub gid_number_min: i32,
+    #[builder(default = "59999")]
+    pub gid_number_max: i32,
+    /// The primary group of the users without one. If unset, their gidNumber is their
+    /// uidNumber (one private group per user).
+    #[builder(default)]
+    pub default_gid_number: Option<i32>,
+    /// The users without a home directory get `<prefix>/<user ID>`.
+    #[builder(default = r#"String::from("/home")"#)]
+    pub home_directory_prefix: String,
+    #[builder(default = r#"String::from("/bin/bash")"#)]
+    pub default_login_shell: String,
+}
+
+impl std::default::Default for PosixOptions {
+    fn default() -> Self {
+        PosixOptionsBuilder::default().build().unwrap()
+    }
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize, derive_builder::Builder)]
+#[builder(pattern = "owned")]
+pub struct TotpOptions {
+    /// The parameters of the new enrollments. The existing ones keep theirs.
+    #[builder(default)]
+    pub algorithm: TotpAlgorithm,
+    #[builder(default = "6")]
+    pub digits: u32,
+    #[builder(default = "30")]
+    pub period_seconds: u64,
+    /// How many periods before and after the current one are also accepted, for the clocks
+    /// that drift.
+    #[builder(default = "1")]
+    pub drift_steps: u64,
+    /// Shown by the authenticator apps next to the account.
+    #[builder(default = r#"String::from("LLDAP")"#)]
+    pub issuer: String,
+}
+
+impl std::default::Default for TotpOptions {
+    fn default() -> Self {
+        TotpOptionsBuilder::default().build().unwrap()
+    }
+}
+
+impl TotpOptions {
+    pub fn parameters(&self) -> TotpParameters {
+        TotpParameters {
+            algorithm: self.algorithm,
+            digits: self.digits,
+            period_seconds: self.period_seconds,
+        }
+    }
+}
+
+/// The objectClass values of the LDAP entries, and the ones that match in the search filters.
+#[derive(Clone, Debug, Deserialize, Serialize, derive_builder::Builder)]
+#[builder(pattern = "owned")]
+pub struct LdapObjectClasses {
+    #[builder(
+        default = r#"vec!["inetOrgPerson".to_owned(), "posixAccount".to_owned(), "mailAccount".to_owned(), "person".to_owned()]"#
+    )]
+    pub user: Vec<String>,
+    /// `posixGroup` is only returned for the groups with a gidNumber.
+    #[builder(default = r#"vec!["groupOfUniqueNames".to_owned(), "posixGroup".to_owned()]"#)]
+    pub group: Vec<String>,
+}
+
+impl std::default::Default for LdapObjectClasses {
+    fn default() -> Self {
+        LdapObjectClassesBuilder::default().build().unwrap()
+    }
+}
+
+/// The sizing of the database connection pools, the primary and the replica alike.
+#[derive(Clone, Debug, Deserialize, Serialize, derive_builder::Builder)]
+#[builder(pattern = "owned")]
+pub struct DatabasePoolOptions {
+    #[builder(default = "5")]
+    pub max_connections: u32,
+    /// The connections kept open even when idle.
+    #[builder(default = "0")]
+    pub min_idle_connections: u32,
+    /// How long a request waits for a free connection before failing with "server busy".
+    #[builder(default = "30")]
+    pub acquire_timeout_seconds: u64,
+    /// The idle connections above `min_idle_connections` are closed after this long, 0 to never
+    /// close them.
+    #[builder(default = "600")]
+    pub idle_timeout_seconds: u64,
+    /// The connections are replaced after this long, 0 to keep them forever.
+    #[builder(default = "1800")]
+    pub max_lifetime_seconds: u64,
+    /// The LDAP searches and compares, and the GraphQL queries, are aborted after this long, 0
+    /// to let them run.
+    #[builder(default = "30")]
+    pub search_timeout_seconds: u64,
+    /// Same for the LDAP modifications and the GraphQL mutations.
+    #[builder(default = "60")]
+    pub modification_timeout_seconds: u64,
+}
+
+impl std::default::Default for DatabasePoolOptions {
+    fn default() -> Self {
+        DatabasePoolOptionsBuilder::default().build().unwrap()
+    }
+}
+
+/// What to do with the password hashes imported from other systems (bcrypt, Argon2, scrypt). The
+/// new passwords are always stored as OPAQUE password files.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum LegacyPasswordHashes {
+    /// Replaced with a password file after the first successful bind.
+    Upgrade,
+    Keep,
+    Reject,
+}
+
+/// The scheme of the imported password hashes that are kept: after a successful bind, a hash of
+/// another scheme is replaced with one of this scheme.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum PasswordKdf {
+    #[default]
+    Argon2id,
+    Bcrypt,
+    Scrypt,
+}
+
+/// The minimum TLS version of LDAPS and StartTLS. The older versions are never accepted.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, PartialOrd, Ord, Deserialize, Serialize)]
+pub enum TlsVersion {
+    #[default]
+    #[serde(rename = "TLSv1.2")]
+    Tls12,
+    #[serde(rename = "TLSv1.3")]
+    Tls13,
+}
+
+impl TlsVersion {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            TlsVersion::Tls12 => "TLSv1.2",
+            TlsVersion::Tls13 => "TLSv1.3",
+        }
+    }
+}
+
+/// How a SASL EXTERNAL bind finds the user of the client certificate.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ClientCertificateUserMapping {
+    /// The CN of the subject is the user ID.
+    CommonName,
+    /// An email of the subject alternative names is the email of the user.
+    SanEmail,
+}
+
+/// What to do with a server-side sort control that the database can't handle, i.e. not on the
+/// users or not by uid, mail, cn/displayName or createTimestamp.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum LdapUnindexedSort {
+    /// Sorted in memory, with a warning.
+    Allow,
+    /// Fails the search with unwillingToPerform, or returns it unsorted if the control is not
+    /// critical.
+    Reject,
+}
+
+/// What the anonymous sessions can read: the ones without a bind, or after a bind with an empty
+/// DN and password.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum LdapAnonymousBind {
+    /// The anonymous binds fail with inappropriateAuthentication, and even the root DSE requires
+    /// a bind.
+    Disallow,
+    /// Only the root DSE.
+    AllowRootDseOnly,
+    /// The users and groups, with only the `ldap_anonymous_read_attributes`.
+    AllowRead,
+}
+
+/// The format of the logs on the standard output.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum LogFormat {
+    /// Human-readable, with the spans as a tree.
+    Text,
+    /// One JSON object per line, with the fields of the enclosing spans.
+    Json,
+}
+
+/// The attribute that names the users in their LDAP DN. It must identify a single user.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case", try_from = "String")]
+pub enum UserRdnAttribute {
+    /// `uid=<user ID>,ou=people,<base DN>`, with the default `ldap_users_ou`.
+    Uid,
+    /// `mail=<email>,ou=people,<base DN>`. The users without an email keep their `uid` DN.
+    Mail,
+}
+
+impl TryFrom<String> for UserRdnAttribute {
+    type Error = String;
+
+    fn try_from(attribute: String) -> std::result::Result<Self, Self::Error> {
+        match attribute.to_ascii_lowercase().as_str() {
+            "uid" => Ok(Self::Uid),
+            "mail" => Ok(Self::Mail),
+            "cn" | "displayname" => Err(format!(
+                "`{}` cannot name the users: the display names are not unique",
+                attribute
+            )),
+            _ => Err(format!(
+                "Unsupported user RDN attribute `{}`, expected `uid` or `mail`",
+                attribute
+            )),
+        }
+    }
+}
+
+/// How the migration of the DBs created before the schema versioning sets the UUIDs of the users
+/// and groups.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum UuidBackfill {
+    /// The missing UUIDs are derived from the name and the creation date: the same data always
+    /// gets the same UUIDs. Those already set, e.g. by an import, are kept.
+    #[default]
+    Deterministic,
+    /// All the UUIDs, even those already set, are replaced with random (v4) ones, e.g. to merge
+    /// instances whose derived UUIDs would collide.
+    Random,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ListenerProtocol {
+    Ldap,
+    Ldaps,
+    Http,
+    /// Only serves the Prometheus metrics, on an address apart from the public HTTP server.
+    Metrics,
+}
+
+impl ListenerProtocol {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            ListenerProtocol::Ldap => "ldap",
+            ListenerProtocol::Ldaps => "ldaps",
+            ListenerProtocol::Http => "http",
+            ListenerProtocol::Metrics => "metrics",
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Deserialize, Serialize)]
+pub struct ListenerTlsOptions {
+    pub cert_file: String,
+    pub key_file: String,
+}
+
+/// An address that one of the servers listens on.
+#[derive(Clone, Debug, PartialEq, Eq, Deserialize, Serialize)]
+pub struct ListenerOptions {
+    pub protocol: ListenerProtocol,
+    pub host: String,
+    pub port: u16,
+    /// For LDAPS, the certificate instead of the one of `ldaps_options`. For LDAP, enables
+    /// StartTLS with this certificate.
+    #[serde(default)]
+    pub tls: Option<ListenerTlsOptions>,
+}
+
+impl ListenerOptions {
+    fn new(protocol: ListenerProtocol, host: &str, port: u16) -> Self {
+        Self {
+            protocol,
+            host: host.to_owned(),
+            port,
+            tls: None,
+        }
+    }
+
+    /// `host:port`, with the IPv6 addresses in brackets.
+    pub fn address(&self) -> String {
+        if self.host.contains(':') {
+            format!("[{}]:{}", self.host, self.port)
+        } else {
+            format!("{}:{}", self.host, self.port)
+        }
+    }
+}
+
+/// An upstream LDAP server that checks the passwords of some users, with a simple bind.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct AuthBackendOptions {
+    /// Shown in the logs.
+    pub name: String,
+    /// `ldap://host:port` or `ldaps://host:port`.
+    pub ldap_url: String,
+    /// The DN of the bind, where `{user_id}` is replaced with the ID of the user.
+    pub bind_dn_template: String,
+    /// The users whose password is checked by this server.
+    #[serde(default)]
+    pub users: Vec<UserId>,
+    /// The groups whose members have their password checked by this server.
+    #[serde(default)]
+    pub groups: Vec<String>,
+    /// 10 seconds if unset.
+    #[serde(default)]
+    pub timeout_seconds: Option<u64>,
+}
+
 #[derive(Clone, Debug, Deserialize, Serialize, derive_builder::Builder)]
 #[builder(pattern = "owned", build_fn(name = "private_build"))]
 pub struct Configuration {
@@ -72,6 +673,17 @@ pub struct Configuration {
     pub http_host: String,
     #[builder(default = "17170")]
     pub http_port: u16,
+    /// The Prometheus metrics are served on their own address, without authentication.
+    #[builder(default = r#"String::from("127.0.0.1")"#)]
+    pub metrics_host: String,
+    /// 0 disables the metrics.
+    #[builder(default = "0")]
+    pub metrics_port: u16,
+    /// The addresses of the LDAP, LDAPS, HTTP and metrics servers. If set, they replace
+    /// `ldap_host`, `ldap_port`, `http_host`, `http_port`, `metrics_host`, `metrics_port`,
+    /// `ldaps_options.enabled` and `ldaps_options.port`.
+    #[builder(default)]
+    pub listeners: Vec<ListenerOptions>,
     #[builder(default = r#"SecUtf8::from("secretjwtsecret")"#)]
     pub jwt_secret: SecUtf8,
     #[builder(default = r#"String::from("dc=example,dc=com")"#)]
@@ -80,22 +692,134 @@ pub struct Configuration {
     pub ldap_user_dn: UserId,
     #[builder(default = r#"String::default()"#)]
     pub ldap_user_email: String,
+    #[builder(default = "UserRdnAttribute::Uid")]
+    pub ldap_user_rdn_attribute: UserRdnAttribute,
+    /// The users are served under `ou=<ldap_users_ou>,<ldap_base_dn>`.
+    #[builder(default = r#"String::from("people")"#)]
+    pub ldap_users_ou: String,
+    /// The groups are served under `ou=<ldap_groups_ou>,<ldap_base_dn>`.
+    #[builder(default = r#"String::from("groups")"#)]
+    pub ldap_groups_ou: String,
+    /// Where the sudo roles are served, `ou=sudoers,<ldap_base_dn>` by default.
+    #[builder(default)]
+    pub ldap_sudoers_base_dn: Option<String>,
+    #[builder(default = "LdapUnindexedSort::Allow")]
+    pub ldap_unindexed_sort: LdapUnindexedSort,
+    #[builder(default = "LdapAnonymousBind::AllowRootDseOnly")]
+    pub ldap_anonymous_bind: LdapAnonymousBind,
+    /// The attributes of the users and groups that the anonymous sessions can read and filter
+    /// on, with `ldap_anonymous_bind = "allow_read"`. objectClass is always readable.
+    #[builder(
+        default = r#"vec!["cn".to_owned(), "mail".to_owned(), "givenName".to_owned(), "sn".to_owned()]"#
+    )]
+    pub ldap_anonymous_read_attributes: Vec<String>,
+    /// Accepts the SASL SCRAM-SHA-256 binds over LDAP, where the password isn't sent. The server
+    /// stores a verifier of each password, at the first simple bind after it is set.
+    #[builder(default = "false")]
+    pub ldap_sasl_scram_sha256: bool,
+    /// Which attributes of the users the members of a group can read or modify, over LDAP and
+    /// GraphQL. Empty by default: only the special groups give permissions.
+    #[builder(default)]
+    pub attribute_acl: Vec<AttributeAclRule>,
+    /// The attributes that the users can modify on their own account, unless a rule of
+    /// `attribute_acl` decides otherwise. All of them by default.
+    #[builder(default = "AclAttribute::ALL.to_vec()")]
+    pub self_service_attributes: Vec<AclAttribute>,
     #[builder(default = r#"SecUtf8::from("password")"#)]
     pub ldap_user_pass: SecUtf8,
     #[builder(default = r#"String::from("sqlite://users.db?mode=rwc")"#)]
     pub database_url: String,
+    /// Read replica of the database, for the user and group listings and searches.
+    #[builder(default)]
+    pub database_replica_url: Option<String>,
+    #[builder(default)]
+    pub database_pool_options: DatabasePoolOptions,
+    /// After a write, the reads of the same session go to the primary database for this long.
+    #[builder(default = "5")]
+    pub database_replica_max_lag_seconds: u64,
+    #[builder(default)]
+    pub uuid_backfill: UuidBackfill,
+    #[builder(default = "false")]
+    pub case_insensitive_emails: bool,
+    /// Only send the password reset emails to the verified emails.
+    #[builder(default = "false")]
+    pub require_verified_email: bool,
+    #[builder(default = "false")]
+    pub soft_delete_users: bool,
+    #[builder(default = "30")]
+    pub deleted_users_retention_days: u32,
+    /// How long the deletions are kept for the incremental sync.
+    #[builder(default = "30")]
+    pub deletion_tombstones_retention_days: u32,
+    #[builder(default = "10")]
+    pub max_group_nesting_depth: u8,
+    #[builder(default)]
+    pub password_max_age_days: Option<u32>,
+    #[builder(default = "LegacyPasswordHashes::Upgrade")]
+    pub legacy_password_hashes: LegacyPasswordHashes,
+    /// With `legacy_password_hashes = "keep"`, the scheme of the kept hashes.
+    #[builder(default)]
+    pub default_password_kdf: PasswordKdf,
+    /// Servers that check the passwords of some users instead of their local password, for the
+    /// LDAP binds and the simple logins. The first matching server is used, by user before by
+    /// group.
+    #[builder(default)]
+    pub auth_backends: Vec<AuthBackendOptions>,
+    #[builder(default)]
+    pub failed_login_lockout_threshold: Option<u32>,
+    #[builder(default = "15")]
+    pub failed_login_lockout_minutes: u32,
+    #[builder(default = "512")]
+    pub avatar_max_dimension: u32,
+    #[builder(default = "256")]
+    pub avatar_max_size_kb: u32,
     #[builder(default)]
     pub ignored_user_attributes: Vec<String>,
     #[builder(default)]
     pub ignored_group_attributes: Vec<String>,
     #[builder(default = "false")]
     pub verbose: bool,
+    #[builder(default = "LogFormat::Text")]
+    pub log_format: LogFormat,
     #[builder(default = r#"String::from("server_key")"#)]
     pub key_file: String,
+    /// Keys that encrypt the TOTP secrets at rest. They are stored in clear if unset.
+    #[builder(default)]
+    pub totp_encryption_key_file: Option<String>,
+    #[builder(default)]
+    pub totp_options: TotpOptions,
     #[builder(default)]
     pub smtp_options: MailOptions,
     #[builder(default)]
     pub ldaps_options: LdapsOptions,
+    #[builder(default)]
+    pub webhook_options: WebhookOptions,
+    #[builder(default)]
+    pub oidc_options: OidcOptions,
+    #[builder(default)]
+    pub rate_limit_options: RateLimitOptions,
+    #[builder(default)]
+    pub ldap_connection_limits: ConnectionLimitOptions,
+    #[builder(default)]
+    pub password_policy: PasswordPolicyOptions,
+    #[builder(default)]
+    pub user_id_policy: UserIdPolicyOptions,
+    #[builder(default)]
+    pub email_policy: EmailPolicyOptions,
+    #[builder(default)]
+    pub security_headers: SecurityHeadersOptions,
+    #[builder(default)]
+    pub proxy_options: ProxyOptions,
+    #[builder(default)]
+    pub posix_options: PosixOptions,
+    #[builder(default)]
+    pub ldap_object_classes: LdapObjectClasses,
+    /// The translations of the messages shown to the users, one `<locale>.toml` file per locale.
+    #[builder(default)]
+    pub translations_dir: Option<String>,
+    /// On shutdown, how long the requests in flight have to finish.
+    #[builder(default = "30")]
+    pub shutdown_grace_period_seconds: u64,
     #[builder(default = r#"String::from("http://localhost")"#)]
     pub http_url: String,
     #[serde(skip)]
@@ -133,6 +857,273 @@ impl Configuration {
     pub fn get_server_keys(&self) -> &KeyPair {
         self.get_server_setup().keypair()
     }
+
+    /// The DNs are case-insensitive: the emails can only name the users if they are unique
+    /// regardless of case, i.e. with the case-insensitive emails and their unique index.
+    pub fn check_user_rdn_attribute(&self) -> Result<()> {
+        if self.ldap_user_rdn_attribute == UserRdnAttribute::Mail && !self.case_insensitive_emails {
+            bail!(
+                "`ldap_user_rdn_attribute = \"mail\"` requires `case_insensitive_emails = true`, \
+                 otherwise several users could have the same DN"
+            );
+        }
+        Ok(())
+    }
+
+    /// The base DN must be a valid DN, and the OUs of the users and of the groups must be
+    /// distinct.
+    pub fn check_ldap_base_dn(&self) -> Result<()> {
+        if self.ldap_base_dn.trim().is_empty() {
+            bail!("`ldap_base_dn` is empty");
+        }
+        parse_distinguished_name(&self.ldap_base_dn.to_ascii_lowercase()).map_err(|e| {
+            anyhow!(
+                "Invalid `ldap_base_dn = \"{}\"`: {}",
+                self.ldap_base_dn,
+                e.message
+            )
+        })?;
+        for (option, ou) in [
+            ("ldap_users_ou", &self.ldap_users_ou),
+            ("ldap_groups_ou", &self.ldap_groups_ou),
+        ] {
+            if ou.trim().is_empty() {
+                bail!("`{}` is empty", option);
+            }
+        }
+        if self
+            .ldap_users_ou
+            .eq_ignore_ascii_case(&self.ldap_groups_ou)
+        {
+            bail!(
+                "The users and the groups can't share the OU `{}`",
+                self.ldap_users_ou
+            );
+        }
+        Ok(())
+    }
+
+    /// Lowercase, like the base DN in the LDAP handler.
+    pub fn get_sudoers_base_dn(&self) -> String {
+        self.ldap_sudoers_base_dn
+            .clone()
+            .unwrap_or_else(|| format!("ou=sudoers,{}", self.ldap_base_dn))
+            .to_ascii_lowercase()
+    }
+
+    /// The sudo roles are served next to the users and groups, so their base DN must be under
+    /// the base DN, and not under the users or groups.
+    pub fn check_sudoers_base_dn(&self) -> Result<()> {
+        let sudoers_base_dn = self.get_sudoers_base_dn();
+        let parse = |dn: &str| {
+            parse_distinguished_name(dn).map_err(|e| anyhow!("Invalid DN `{}`: {}", dn, e.message))
+        };
+        let base_dn = parse(&self.ldap_base_dn.to_ascii_lowercase())?;
+        let sudoers = parse(&sudoers_base_dn)?;
+        if sudoers.len() <= base_dn.len() || !is_subtree(&sudoers, &base_dn) {
+            bail!(
+                "`ldap_sudoers_base_dn = \"{}\"` must be under the base DN `{}`",
+                sudoers_base_dn,
+                self.ldap_base_dn
+            );
+        }
+        let first_ou = &sudoers[sudoers.len() - base_dn.len() - 1];
+        if first_ou.0 == "ou"
+            && (first_ou.1.eq_ignore_ascii_case(&self.ldap_users_ou)
+                || first_ou.1.eq_ignore_ascii_case(&self.ldap_groups_ou))
+        {
+            bail!(
+                "`ldap_sudoers_base_dn = \"{}\"` can't be under the users or the groups",
+                sudoers_base_dn
+            );
+        }
+        Ok(())
+    }
+
+    pub fn check_database_pool_options(&self) -> Result<()> {
+        let options = &self.database_pool_options;
+        if options.max_connections == 0 {
+            bail!("`database_pool_options.max_connections` must be positive");
+        }
+        if options.min_idle_connections > options.max_connections {
+            bail!(
+                "`database_pool_options.min_idle_connections = {}` is more than `max_connections = {}`",
+                options.min_idle_connections,
+                options.max_connections
+            );
+        }
+        if options.acquire_timeout_seconds == 0 {
+            bail!("`database_pool_options.acquire_timeout_seconds` must be positive");
+        }
+        Ok(())
+    }
+
+    pub fn check_posix_options(&self) -> Result<()> {
+        let options = &self.posix_options;
+        if options.uid_number_min < 1 || options.uid_number_min > options.uid_number_max {
+            bail!(
+                "Invalid uidNumber range {}-{}",
+                options.uid_number_min,
+                options.uid_number_max
+            );
+        }
+        if options.gid_number_min < 1 || options.gid_number_min > options.gid_number_max {
+            bail!(
+                "Invalid gidNumber range {}-{}",
+                options.gid_number_min,
+                options.gid_number_max
+            );
+        }
+        Ok(())
+    }
+
+    pub fn check_totp_options(&self) -> Result<()> {
+        self.totp_options
+            .parameters()
+            .validate()
+            .context("Invalid `totp_options`")
+    }
+
+    pub fn check_proxy_options(&self) -> Result<()> {
+        TrustedProxies::new(&self.proxy_options)?;
+        if self.proxy_options.ldap_proxy_protocol && self.proxy_options.trusted_proxies.is_empty() {
+            bail!("`proxy_options.ldap_proxy_protocol` requires `proxy_options.trusted_proxies`");
+        }
+        Ok(())
+    }
+
+    pub fn check_security_headers(&self) -> Result<()> {
+        security_headers(&self.security_headers).map(|_| ())
+    }
+
+    pub fn check_tls_options(&self) -> Result<()> {
+        TlsPolicy::new(&self.ldaps_options).map(|_| ())
+    }
+
+    pub fn check_email_policy(&self) -> Result<()> {
+        let options = &self.email_policy;
+        for domain in options
+            .allowed_domains
+            .iter()
+            .chain(&options.blocked_domains)
+        {
+            if domain.is_empty() || domain.contains('@') {
+                bail!("Invalid domain {:?} in `email_policy`", domain);
+            }
+        }
+        Ok(())
+    }
+
+    /// The configured listeners, or the ones of the single address options.
+    pub fn get_listeners(&self) -> Vec<ListenerOptions> {
+        if !self.listeners.is_empty() {
+            return self.listeners.clone();
+        }
+        let mut listeners = vec![ListenerOptions::new(
+            ListenerProtocol::Ldap,
+            &self.ldap_host,
+            self.ldap_port,
+        )];
+        if self.ldaps_options.enabled {
+            listeners.push(ListenerOptions::new(
+                ListenerProtocol::Ldaps,
+                &self.ldap_host,
+                self.ldaps_options.port,
+            ));
+        }
+        listeners.push(ListenerOptions::new(
+            ListenerProtocol::Http,
+            &self.http_host,
+            self.http_port,
+        ));
+        if self.metrics_port != 0 {
+            listeners.push(ListenerOptions::new(
+                ListenerProtocol::Metrics,
+                &self.metrics_host,
+                self.metrics_port,
+            ));
+        }
+        listeners
+    }
+
+    /// Whether a listener uses the certificate of `ldaps_options`.
+    pub fn uses_ldaps_certificate(&self) -> bool {
+        self.get_listeners().iter().any(|listener| {
+            listener.tls.is_none()
+                && (listener.protocol == ListenerProtocol::Ldaps
+                    || (listener.protocol == ListenerProtocol::Ldap
+                        && self.ldaps_options.start_tls))
+        })
+    }
+
+    /// Whether a listener accepts TLS connections, with LDAPS or StartTLS.
+    pub fn has_tls_listener(&self) -> bool {
+        self.uses_ldaps_certificate()
+            || self
+                .get_listeners()
+                .iter()
+                .any(|listener| listener.tls.is_some())
+    }
+
+    pub fn check_listeners(&self) -> Result<()> {
+        let listeners = self.get_listeners();
+        let has_protocol = |protocol| listeners.iter().any(|l| l.protocol == protocol);
+        if !has_protocol(ListenerProtocol::Http) {
+            bail!("No HTTP listener: the web UI and the API need one");
+        }
+        if !has_protocol(ListenerProtocol::Ldap) && !has_protocol(ListenerProtocol::Ldaps) {
+            bail!("No LDAP or LDAPS listener");
+        }
+        let mut addresses = std::collections::HashSet::new();
+        for listener in &listeners {
+            if listener.protocol == ListenerProtocol::Http && listener.tls.is_some() {
+                bail!(
+                    "The HTTP listener on {} can't have TLS options: put it behind a reverse \
+                     proxy for HTTPS",
+                    listener.address()
+                );
+            }
+            if listener.protocol == ListenerProtocol::Metrics && listener.tls.is_some() {
+                bail!(
+                    "The metrics listener on {} can't have TLS options",
+                    listener.address()
+                );
+            }
+            if !addresses.insert((listener.host.as_str(), listener.port)) {
+                bail!("Several listeners on {}", listener.address());
+            }
+        }
+        Ok(())
+    }
+
+    pub fn check_auth_backends(&self) -> Result<()> {
+        let mut names = std::collections::HashSet::new();
+        for options in &self.auth_backends {
+            if options.name.is_empty() {
+                bail!("The `auth_backends` need a name");
+            }
+            if !names.insert(options.name.as_str()) {
+                bail!("Duplicate auth backend name `{}`", options.name);
+            }
+            if options.users.is_empty() && options.groups.is_empty() {
+                bail!(
+                    "The auth backend `{}` has neither `users` nor `groups`",
+                    options.name
+                );
+            }
+            crate::infra::ldap_auth_backend::LdapAuthBackend::new(options)
+                .with_context(|| format!("Invalid auth backend `{}`", options.name))?;
+        }
+        Ok(())
+    }
+
+    pub fn check_ldap_object_classes(&self) -> Result<()> {
+        let object_classes = &self.ldap_object_classes;
+        if object_classes.user.is_empty() || object_classes.group.is_empty() {
+            bail!("`ldap_object_classes.user` and `ldap_object_classes.group` can't be empty");
+        }
+        Ok(())
+    }
 }
 
 fn generate_random_private_key() -> ServerSetup {
@@ -140,7 +1131,7 @@ fn generate_random_private_key() -> ServerSetup {
     ServerSetup::new(&mut rng)
 }
 
-fn write_to_readonly_file(path: &std::path::Path, buffer: &[u8]) -> Result<()> {
+pub(crate) fn write_to_readonly_file(path: &std::path::Path, buffer: &[u8]) -> Result<()> {
     use std::{fs::File, io::Write};
     assert!(!path.exists());
     let mut file = File::create(path)?;
@@ -155,11 +1146,9 @@ fn write_to_readonly_file(path: &std::path::Path, buffer: &[u8]) -> Result<()> {
 }
 
 fn get_server_setup(file_path: &str) -> Result<ServerSetup> {
-    use std::fs::read;
     let path = std::path::Path::new(file_path);
     if path.exists() {
-        let bytes = read(file_path).context(format!("Could not read key file `{}`", file_path))?;
-        Ok(ServerSetup::deserialize(&bytes)?)
+        read_server_setup(file_path)
     } else {
         let server_setup = generate_random_private_key();
         write_to_readonly_file(path, &server_setup.serialize()).context(format!(
@@ -214,6 +1203,66 @@ impl ConfigOverrider for RunOpts {
     }
 }
 
+impl TopLevelCommandOpts for MigrateOpts {
+    fn general_config(&self) -> &GeneralConfigOpts {
+        &self.general_config
+    }
+}
+
+impl ConfigOverrider for MigrateOpts {
+    fn override_config(&self, config: &mut Configuration) {
+        self.general_config.override_config(config);
+    }
+}
+
+impl TopLevelCommandOpts for MaintenanceOpts {
+    fn general_config(&self) -> &GeneralConfigOpts {
+        &self.general_config
+    }
+}
+
+impl ConfigOverrider for MaintenanceOpts {
+    fn override_config(&self, config: &mut Configuration) {
+        self.general_config.override_config(config);
+    }
+}
+
+impl TopLevelCommandOpts for ResetPasswordOpts {
+    fn general_config(&self) -> &GeneralConfigOpts {
+        &self.general_config
+    }
+}
+
+impl ConfigOverrider for ResetPasswordOpts {
+    fn override_config(&self, config: &mut Configuration) {
+        self.general_config.override_config(config);
+    }
+}
+
+impl TopLevelCommandOpts for BackupOpts {
+    fn general_config(&self) -> &GeneralConfigOpts {
+        &self.general_config
+    }
+}
+
+impl ConfigOverrider for BackupOpts {
+    fn override_config(&self, config: &mut Configuration) {
+        self.general_config.override_config(config);
+    }
+}
+
+impl TopLevelCommandOpts for RestoreOpts {
+    fn general_config(&self) -> &GeneralConfigOpts {
+        &self.general_config
+    }
+}
+
+impl ConfigOverrider for RestoreOpts {
+    fn override_config(&self, config: &mut Configuration) {
+        self.general_config.override_config(config);
+    }
+}
+
 impl ConfigOverrider for TestEmailOpts {
     fn override_config(&self, config: &mut Configuration) {
         self.general_config.override_config(config);
@@ -235,6 +1284,15 @@ impl ConfigOverrider for LdapsOpts {
         if let Some(path) = self.ldaps_key_file.as_ref() {
             config.ldaps_options.key_file = path.clone();
         }
+        if let Some(start_tls) = self.ldaps_start_tls {
+            config.ldaps_options.start_tls = start_tls;
+        }
+        if let Some(require_tls) = self.ldaps_require_tls {
+            config.ldaps_options.require_tls = require_tls;
+        }
+        if let Some(path) = self.ldaps_client_ca_file.as_ref() {
+            config.ldaps_options.client_ca_file = Some(path.clone());
+        }
     }
 }
 
@@ -264,7 +1322,7 @@ impl ConfigOverrider for SmtpOpts {
             config.smtp_options.user = user.clone();
         }
         if let Some(password) = &self.smtp_password {
-            config.smtp_options.password = SecUtf8::from(password.clone());
+            config.smtp_options.password = password.clone();
         }
         if let Some(tls_required) = self.smtp_tls_required {
             config.smtp_options.tls_required = Some(tls_required);
@@ -272,7 +1330,8 @@ impl ConfigOverrider for SmtpOpts {
     }
 }
 
-pub fn init<C>(overrides: C) -> Result<Configuration>
+/// Reads the configuration, without the server key: unlike `init`, it never writes a file.
+pub fn load<C>(overrides: C) -> Result<Configuration>
 where
     C: TopLevelCommandOpts + ConfigOverrider,
 {
@@ -284,7 +1343,7 @@ where
     );
 
     use figment_file_provider_adapter::FileAdapter;
-    let ignore_keys = ["key_file", "cert_file"];
+    let ignore_keys = ["key_file", "cert_file", "private_key_file"];
     let mut config: Configuration = Figment::from(Serialized::defaults(
         ConfigurationBuilder::default().private_build().unwrap(),
     ))
@@ -296,6 +1355,34 @@ where
     if config.verbose {
         println!("Configuration: {:#?}", &config);
     }
+    Ok(config)
+}
+
+/// Reads the server key file, without generating it if it's missing.
+pub(crate) fn read_server_setup(file_path: &str) -> Result<ServerSetup> {
+    let bytes =
+        std::fs::read(file_path).context(format!("Could not read key file `{}`", file_path))?;
+    Ok(ServerSetup::deserialize(&bytes)?)
+}
+
+pub fn init<C>(overrides: C) -> Result<Configuration>
+where
+    C: TopLevelCommandOpts + ConfigOverrider,
+{
+    let mut config = load(overrides)?;
+    config.check_ldap_base_dn()?;
+    config.check_user_rdn_attribute()?;
+    config.check_posix_options()?;
+    config.check_totp_options()?;
+    config.check_email_policy()?;
+    config.check_tls_options()?;
+    config.check_security_headers()?;
+    config.check_proxy_options()?;
+    config.check_ldap_object_classes()?;
+    config.check_database_pool_options()?;
+    config.check_sudoers_base_dn()?;
+    config.check_auth_backends()?;
+    config.check_listeners()?;
     config.server_setup = Some(get_server_setup(&config.key_file)?);
     if config.jwt_secret == SecUtf8::from("secretjwtsecret") {
         println!("WARNING: Default JWT secret used! This is highly unsafe and can allow attackers to log in as admin.");
@@ -303,6 +1390,13 @@ where
     if config.ldap_user_pass == SecUtf8::from("password") {
         println!("WARNING: Unsecure default admin password is used.");
     }
+    if !config.webhook_options.urls.is_empty()
+        && config.webhook_options.secret.unsecure().is_empty()
+    {
+        println!(
+            "WARNING: No webhook secret set, the receivers can't verify the webhook signatures."
+        );
+    }
     if config.smtp_options.tls_required.is_some() {
         println!("DEPRECATED: smtp_options.tls_required field is deprecated, it never did anything. You can replace it with smtp_options.smtp_encryption.");
     }
@@ -0,0 +1,144 @@
+//! Caps the number of open LDAP connections, in total and from each source address, so that a
+//! single client can't exhaust the file descriptors and the database connections.
+use crate::infra::{configuration::ConnectionLimitOptions, metrics};
+use std::{
+    collections::HashMap,
+    net::IpAddr,
+    sync::{Arc, Mutex},
+};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ConnectionRejected {
+    TooManyFromIp,
+    TooMany,
+}
+
+impl ConnectionRejected {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            ConnectionRejected::TooManyFromIp => "ip_limit",
+            ConnectionRejected::TooMany => "global_limit",
+        }
+    }
+}
+
+#[derive(Default)]
+struct Counts {
+    total: usize,
+    by_ip: HashMap<IpAddr, usize>,
+}
+
+/// Counts the open connections. 0 means no limit.
+#[derive(Clone)]
+pub struct ConnectionLimiter {
+    max_connections: usize,
+    max_connections_per_ip: usize,
+    counts: Arc<Mutex<Counts>>,
+}
+
+impl ConnectionLimiter {
+    pub fn new(options: &ConnectionLimitOptions) -> Self {
+        Self {
+            max_connections: options.max_connections as usize,
+            max_connections_per_ip: options.max_connections_per_ip as usize,
+            counts: Arc::default(),
+        }
+    }
+
+    /// Counts a new connection, until the slot is dropped, unless it's over one of the limits.
+    pub fn acquire(&self, ip: Option<IpAddr>) -> Result<ConnectionSlot, ConnectionRejected> {
+        let mut counts = self.counts.lock().unwrap();
+        if self.max_connections != 0 && counts.total >= self.max_connections {
+            metrics::record_ldap_connection_rejected(ConnectionRejected::TooMany);
+            return Err(ConnectionRejected::TooMany);
+        }
+        if let Some(ip) = ip {
+            let count = counts.by_ip.entry(ip).or_default();
+            if self.max_connections_per_ip != 0 && *count >= self.max_connections_per_ip {
+                metrics::record_ldap_connection_rejected(ConnectionRejected::TooManyFromIp);
+                return Err(ConnectionRejected::TooManyFromIp);
+            }
+            *count += 1;
+        }
+        counts.total += 1;
+        metrics::record_ldap_connections(counts.total);
+        Ok(ConnectionSlot {
+            limiter: self.clone(),
+            ip,
+        })
+    }
+
+    fn release(&self, ip: Option<IpAddr>) {
+        let mut counts = self.counts.lock().unwrap();
+        counts.total -= 1;
+        metrics::record_ldap_connections(counts.total);
+        if let Some(ip) = ip {
+            if let Some(count) = counts.by_ip.get_mut(&ip) {
+                *count -= 1;
+                if *count == 0 {
+                    counts.by_ip.remove(&ip);
+                }
+            }
+        }
+    }
+}
+
+/// An open connection, released when dropped.
+pub struct ConnectionSlot {
+    limiter: ConnectionLimiter,
+    ip: Option<IpAddr>,
+}
+
+impl Drop for ConnectionSlot {
+    fn drop(&mut self) {
+        self.limiter.release(self.ip);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_connection_limits() {
+        let limiter = ConnectionLimiter::new(&ConnectionLimitOptions {
+            max_connections: 3,
+            max_connections_per_ip: 2,
+            idle_timeout_seconds: 0,
+        });
+        let first_ip: IpAddr = "10.0.0.1".parse().unwrap();
+        let second_ip: IpAddr = "10.0.0.2".parse().unwrap();
+        let first = limiter.acquire(Some(first_ip)).unwrap();
+        let _second = limiter.acquire(Some(first_ip)).unwrap();
+        assert_eq!(
+            limiter.acquire(Some(first_ip)).err(),
+            Some(ConnectionRejected::TooManyFromIp)
+        );
+        let _third = limiter.acquire(Some(second_ip)).unwrap();
+        assert_eq!(
+            limiter.acquire(Some(second_ip)).err(),
+            Some(ConnectionRejected::TooMany)
+        );
+        assert_eq!(
+            limiter.acquire(None).err(),
+            Some(ConnectionRejected::TooMany)
+        );
+        drop(first);
+        let _fourth = limiter.acquire(Some(first_ip)).unwrap();
+        assert_eq!(limiter.counts.lock().unwrap().by_ip[&first_ip], 2);
+    }
+
+    #[test]
+    fn test_no_limits() {
+        let limiter = ConnectionLimiter::new(&ConnectionLimitOptions::default());
+        let ip: IpAddr = "10.0.0.3".parse().unwrap();
+        let slots = (0..100)
+            .map(|_| limiter.acquire(Some(ip)).unwrap())
+            .collect::<Vec<_>>();
+        assert_eq!(limiter.counts.lock().unwrap().total, 100);
+        drop(slots);
+        let counts = limiter.counts.lock().unwrap();
+        assert_eq!(counts.total, 0);
+        assert!(counts.by_ip.is_empty());
+    }
+}
@@ -0,0 +1,52 @@
+//! The IDs that tie together the logs, the audit log entries and the webhook payloads of a
+//! single request. The HTTP clients can pass their own, to follow a request across services.
+
+pub const CORRELATION_ID_HEADER: &str = "x-correlation-id";
+
+/// Longer IDs are replaced, to fit in the audit log.
+const MAX_CORRELATION_ID_LENGTH: usize = 64;
+
+pub fn new_correlation_id() -> String {
+    uuid::Uuid::new_v4().to_string()
+}
+
+/// The ID passed by the client if it's usable, a new one otherwise. Only the characters that are
+/// safe in a header and in the logs are accepted.
+pub fn get_or_create_correlation_id(from_client: Option<&str>) -> String {
+    match from_client {
+        Some(id)
+            if !id.is_empty()
+                && id.len() <= MAX_CORRELATION_ID_LENGTH
+                && id
+                    .bytes()
+                    .all(|b| b.is_ascii_alphanumeric() || b"-_.:".contains(&b)) =>
+        {
+            id.to_owned()
+        }
+        _ => new_correlation_id(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_get_or_create_correlation_id() {
+        assert_eq!(
+            get_or_create_correlation_id(Some("frontend:1234-abcd")),
+            "frontend:1234-abcd"
+        );
+        for invalid in [
+            None,
+            Some(""),
+            Some("with space"),
+            Some("new\nline"),
+            Some(&"a".repeat(65)),
+        ] {
+            let id = get_or_create_correlation_id(invalid);
+            assert_eq!(id.len(), 36, "{:?}", invalid);
+            assert_ne!(Some(id.as_str()), invalid);
+        }
+    }
+}
@@ -1,10 +1,15 @@
 use crate::domain::{
-    model::{self, JwtRefreshStorageColumn, JwtStorageColumn, PasswordResetTokensColumn},
+    model::{
+        self, EmailVerificationTokensColumn, FailedLoginAttemptsColumn, JwtStorageColumn,
+        OidcAuthorizationCodesColumn, PasswordResetTokensColumn, RefreshTokensColumn,
+    },
+    sql_change_sync::purge_tombstones,
     sql_tables::DbConnection,
+    sql_user_backend_handler::purge_deleted_users,
 };
 use actix::prelude::{Actor, AsyncContext, Context};
 use cron::Schedule;
-use sea_orm::{ColumnTrait, EntityTrait, QueryFilter};
+use sea_orm::{sea_query::Cond, ColumnTrait, EntityTrait, QueryFilter};
 use std::{str::FromStr, time::Duration};
 use tracing::{error, info, instrument};
 
@@ -12,6 +17,10 @@ use tracing::{error, info, instrument};
 pub struct Scheduler {
     schedule: Schedule,
     sql_pool: DbConnection,
+    // How long to keep soft-deleted users, if they should be purged.
+    deleted_users_retention: Option<chrono::Duration>,
+    // How long to keep the deletions for the incremental sync.
+    tombstones_retention: chrono::Duration,
 }
 
 // Provide Actor implementation for our actor
@@ -32,13 +41,27 @@ impl Actor for Scheduler {
 }
 
 impl Scheduler {
-    pub fn new(cron_expression: &str, sql_pool: DbConnection) -> Self {
+    pub fn new(
+        cron_expression: &str,
+        sql_pool: DbConnection,
+        deleted_users_retention: Option<chrono::Duration>,
+        tombstones_retention: chrono::Duration,
+    ) -> Self {
         let schedule = Schedule::from_str(cron_expression).unwrap();
-        Self { schedule, sql_pool }
+        Self {
+            schedule,
+            sql_pool,
+            deleted_users_retention,
+            tombstones_retention,
+        }
     }
 
     fn schedule_task(&self, ctx: &mut Context<Self>) {
-        let future = actix::fut::wrap_future::<_, Self>(Self::cleanup_db(self.sql_pool.clone()));
+        let future = actix::fut::wrap_future::<_, Self>(Self::cleanup_db(
+            self.sql_pool.clone(),
+            self.deleted_users_retention,
+            self.tombstones_retention,
+        ));
         ctx.spawn(future);
 
         ctx.run_later(self.duration_until_next(), move |this, ctx| {
@@ -47,10 +70,14 @@ impl Scheduler {
     }
 
     #[instrument(skip_all)]
-    async fn cleanup_db(sql_pool: DbConnection) {
+    async fn cleanup_db(
+        sql_pool: DbConnection,
+        deleted_users_retention: Option<chrono::Duration>,
+        tombstones_retention: chrono::Duration,
+    ) {
         info!("Cleaning DB");
-        if let Err(e) = model::JwtRefreshStorage::delete_many()
-            .filter(JwtRefreshStorageColumn::ExpiryDate.lt(chrono::Utc::now().naive_utc()))
+        if let Err(e) = model::RefreshTokens::delete_many()
+            .filter(RefreshTokensColumn::ExpiryDate.lt(chrono::Utc::now().naive_utc()))
             .exec(&sql_pool)
             .await
         {
@@ -70,6 +97,51 @@ impl Scheduler {
         {
             error!("DB error while cleaning up password reset tokens: {}", e);
         };
+        if let Err(e) = model::OidcAuthorizationCodes::delete_many()
+            .filter(OidcAuthorizationCodesColumn::ExpiryDate.lt(chrono::Utc::now()))
+            .exec(&sql_pool)
+            .await
+        {
+            error!("DB error while cleaning up OIDC authorization codes: {}", e);
+        };
+        if let Err(e) = model::EmailVerificationTokens::delete_many()
+            .filter(EmailVerificationTokensColumn::ExpiryDate.lt(chrono::Utc::now()))
+            .exec(&sql_pool)
+            .await
+        {
+            error!(
+                "DB error while cleaning up email verification tokens: {}",
+                e
+            );
+        };
+        // Failed logins are also tracked for unknown users: forget the ones that are no longer
+        // locked after a day without failures.
+        if let Err(e) = model::FailedLoginAttempts::delete_many()
+            .filter(
+                FailedLoginAttemptsColumn::LastAttempt
+                    .lt(chrono::Utc::now() - chrono::Duration::days(1)),
+            )
+            .filter(
+                Cond::any()
+                    .add(FailedLoginAttemptsColumn::LockedUntil.is_null())
+                    .add(FailedLoginAttemptsColumn::LockedUntil.lt(chrono::Utc::now())),
+            )
+            .exec(&sql_pool)
+            .await
+        {
+            error!("DB error while cleaning up failed login attempts: {}", e);
+        };
+        if let Some(retention) = deleted_users_retention {
+            match purge_deleted_users(&sql_pool, chrono::Utc::now() - retention).await {
+                Ok(0) => {}
+                Ok(count) => info!("Purged {} deleted users", count),
+                Err(e) => error!("DB error while purging deleted users: {}", e),
+            }
+        }
+        if let Err(e) = purge_tombstones(&sql_pool, chrono::Utc::now() - tombstones_retention).await
+        {
+            error!("DB error while cleaning up deletion tombstones: {}", e);
+        }
         info!("DB cleaned!");
     }
 
@@ -1,33 +1,66 @@
 use crate::{
-    domain::handler::BackendHandler,
+    domain::{
+        change_events::ChangeEventSender,
+        handler::{AuditActor, BackendHandler},
+        types::AuditSource,
+    },
     infra::{
+        access_control::{AttributeAcl, UserAttributeAccess},
         auth_service::{check_if_token_is_valid, ValidationResults},
         cli::ExportGraphQLSchemaOpts,
-        tcp_server::AppState,
+        correlation_id::{get_or_create_correlation_id, CORRELATION_ID_HEADER},
+        metrics,
+        operation_timeout::{run_with_timeout, OperationKind},
+        tcp_server::{AppState, RevokedSessions},
+    },
+};
+use actix_web::{
+    http::{
+        header::{HeaderName, HeaderValue},
+        Method,
     },
+    web, Error, HttpMessage, HttpResponse,
 };
-use actix_web::{web, Error, HttpResponse};
 use actix_web_httpauth::extractors::bearer::BearerAuth;
-use juniper::{EmptySubscription, RootNode};
+use futures::StreamExt;
+use juniper::{
+    http::{GraphQLBatchRequest, GraphQLRequest, GraphQLResponse},
+    Object, RootNode, Value,
+};
 use juniper_actix::{graphiql_handler, graphql_handler, playground_handler};
+use serde::Deserialize;
+use std::time::Instant;
+use tokio::sync::mpsc;
+use tracing::Span;
 
-use super::{mutation::Mutation, query::Query};
+use super::{
+    loader::MembershipLoader, mutation::Mutation, query::Query, subscription::Subscription,
+};
 
 pub struct Context<Handler: BackendHandler> {
     pub handler: Box<Handler>,
     pub validation_result: ValidationResults,
+    pub change_events: ChangeEventSender,
+    pub attribute_acl: AttributeAcl,
+    pub revoked_sessions: RevokedSessions,
+    pub membership_loader: MembershipLoader,
 }
 
 impl<Handler: BackendHandler> juniper::Context for Context<Handler> {}
 
-type Schema<Handler> =
-    RootNode<'static, Query<Handler>, Mutation<Handler>, EmptySubscription<Context<Handler>>>;
+impl<Handler: BackendHandler> Context<Handler> {
+    pub fn get_attribute_access(&self) -> UserAttributeAccess<'_> {
+        self.attribute_acl.for_user(&self.validation_result)
+    }
+}
+
+type Schema<Handler> = RootNode<'static, Query<Handler>, Mutation<Handler>, Subscription<Handler>>;
 
 fn schema<Handler: BackendHandler + Sync>() -> Schema<Handler> {
     Schema::new(
         Query::<Handler>::new(),
         Mutation::<Handler>::new(),
-        EmptySubscription::<Context<Handler>>::new(),
+        Subscription::<Handler>::new(),
     )
 }
 
@@ -58,19 +91,234 @@ async fn playground_route() -> Result<HttpResponse, Error> {
     playground_handler("/api/graphql", None).await
 }
 
+/// Tags the request with the correlation ID of the client (or a new one), and returns it in the
+/// response.
 async fn graphql_route<Handler: BackendHandler + Sync>(
+    req: actix_web::HttpRequest,
+    payload: actix_web::web::Payload,
+    data: web::Data<AppState<Handler>>,
+) -> Result<HttpResponse, Error> {
+    let correlation_id = get_or_create_correlation_id(
+        req.headers()
+            .get(CORRELATION_ID_HEADER)
+            .and_then(|value| value.to_str().ok()),
+    );
+    Span::current().record("correlation_id", &correlation_id.as_str());
+    let mut response = graphql_request(req, payload, data, correlation_id.clone()).await?;
+    if let Ok(value) = HeaderValue::from_str(&correlation_id) {
+        response
+            .headers_mut()
+            .insert(HeaderName::from_static(CORRELATION_ID_HEADER), value);
+    }
+    Ok(response)
+}
+
+async fn graphql_request<Handler: BackendHandler + Sync>(
     req: actix_web::HttpRequest,
     mut payload: actix_web::web::Payload,
     data: web::Data<AppState<Handler>>,
+    correlation_id: String,
 ) -> Result<HttpResponse, Error> {
     use actix_web::FromRequest;
     let bearer = BearerAuth::from_request(&req, &mut payload.0).await?;
     let validation_result = check_if_token_is_valid(&data, bearer.token())?;
+    let mut handler = data.backend_handler.clone();
+    handler.set_audit_actor(AuditActor {
+        user_id: validation_result.user.clone(),
+        source: AuditSource::Graphql,
+    });
+    handler.set_correlation_id(correlation_id);
+    let context = Context::<Handler> {
+        handler: Box::new(handler),
+        validation_result,
+        change_events: data.change_events.clone(),
+        attribute_acl: data.attribute_acl.clone(),
+        revoked_sessions: data.revoked_sessions.clone(),
+        membership_loader: MembershipLoader::default(),
+    };
+    let start = Instant::now();
+    if req.method() != Method::POST {
+        let operation = web::Query::<OperationInfo>::from_query(req.query_string()).ok();
+        let timeout = data
+            .operation_timeouts
+            .get(get_operation_kind(operation.iter().map(|o| &o.0)));
+        let response =
+            run_with_timeout(timeout, graphql_handler(&schema(), &context, req, payload)).await;
+        if let Some(operation) = operation {
+            operation.record(start);
+        }
+        return response.unwrap_or_else(|_| Ok(make_timeout_response()));
+    }
+    // The body is parsed here rather than by juniper_actix, to get the operations for the
+    // metrics.
+    let body = web::Bytes::from_request(&req, &mut payload.0).await?;
+    let (request, operations) = match req.content_type() {
+        "application/json" => (
+            serde_json::from_slice::<GraphQLBatchRequest>(&body)
+                .map_err(actix_web::error::ErrorBadRequest)?,
+            match serde_json::from_slice::<OperationInfos>(&body) {
+                Ok(OperationInfos::Single(operation)) => vec![operation],
+                Ok(OperationInfos::Batch(operations)) => operations,
+                Err(_) => Vec::new(),
+            },
+        ),
+        "application/graphql" => {
+            let query =
+                String::from_utf8(body.to_vec()).map_err(actix_web::error::ErrorBadRequest)?;
+            (
+                GraphQLBatchRequest::Single(GraphQLRequest::new(query.clone(), None, None)),
+                vec![OperationInfo { query }],
+            )
+        }
+        _ => {
+            return Err(actix_web::error::ErrorUnsupportedMediaType(
+                "GraphQL requests should be application/json or application/graphql",
+            ))
+        }
+    };
+    let timeout = data
+        .operation_timeouts
+        .get(get_operation_kind(operations.iter()));
+    let schema = schema();
+    let response = run_with_timeout(timeout, request.execute(&schema, &context)).await;
+    for operation in operations {
+        operation.record(start);
+    }
+    let response = match response {
+        Ok(response) => response,
+        Err(_) => return Ok(make_timeout_response()),
+    };
+    Ok(if response.is_ok() {
+        HttpResponse::Ok().json(&response)
+    } else {
+        HttpResponse::BadRequest().json(&response)
+    })
+}
+
+/// Runs a subscription over server-sent events, in the "distinct connections" mode of the GraphQL
+/// over SSE protocol: a `next` event per result, then `complete` if the subscription ends. The
+/// subscription stops when the client disconnects.
+async fn graphql_subscription_route<Handler: BackendHandler + Sync + 'static>(
+    req: actix_web::HttpRequest,
+    mut payload: actix_web::web::Payload,
+    data: web::Data<AppState<Handler>>,
+) -> Result<HttpResponse, Error> {
+    use actix_web::FromRequest;
+    let bearer = BearerAuth::from_request(&req, &mut payload.0).await?;
+    let validation_result = check_if_token_is_valid(&data, bearer.token())?;
+    let request = web::Json::<GraphQLRequest>::from_request(&req, &mut payload.0)
+        .await?
+        .into_inner();
     let context = Context::<Handler> {
         handler: Box::new(data.backend_handler.clone()),
         validation_result,
+        change_events: data.change_events.clone(),
+        attribute_acl: data.attribute_acl.clone(),
+        revoked_sessions: data.revoked_sessions.clone(),
+        membership_loader: MembershipLoader::default(),
     };
-    graphql_handler(&schema(), &context, req, payload).await
+    // The stream borrows the schema and the context: they live in the task that feeds the
+    // response.
+    let (sender, receiver) = mpsc::channel(1);
+    actix_rt::spawn(async move {
+        send_subscription_events(&schema(), &context, &request, sender).await;
+    });
+    Ok(HttpResponse::Ok()
+        .content_type("text/event-stream")
+        .insert_header(("Cache-Control", "no-cache"))
+        .streaming(
+            tokio_stream::wrappers::ReceiverStream::new(receiver)
+                .map(Ok::<_, std::convert::Infallible>),
+        ))
+}
+
+fn make_sse_event(response: &GraphQLResponse) -> web::Bytes {
+    let data = serde_json::to_string(response).unwrap_or_else(|e| {
+        serde_json::json!({ "errors": [{ "message": e.to_string() }] }).to_string()
+    });
+    web::Bytes::from(format!("event: next\ndata: {}\n\n", data))
+}
+
+async fn send_subscription_events<Handler: BackendHandler + Sync>(
+    schema: &Schema<Handler>,
+    context: &Context<Handler>,
+    request: &GraphQLRequest,
+    sender: mpsc::Sender<web::Bytes>,
+) {
+    let (name, mut stream) =
+        match juniper::http::resolve_into_stream(request, schema, context).await {
+            Ok((Value::Object(fields), errors)) if errors.is_empty() => {
+                match fields.into_iter().next() {
+                    Some((name, Value::Scalar(stream))) => (name, stream),
+                    _ => return,
+                }
+            }
+            Ok((_, errors)) => {
+                let response = GraphQLResponse::from_result(Ok((Value::Null, errors)));
+                let _ = sender.send(make_sse_event(&response)).await;
+                return;
+            }
+            Err(e) => {
+                let response = GraphQLResponse::from_result(Err(e));
+                let _ = sender.send(make_sse_event(&response)).await;
+                return;
+            }
+        };
+    loop {
+        let result = tokio::select! {
+            result = stream.next() => result,
+            _ = sender.closed() => return,
+        };
+        let response = match result {
+            Some(Ok(value)) => {
+                let mut data = Object::with_capacity(1);
+                data.add_field(name.clone(), value);
+                GraphQLResponse::from_result(Ok((Value::Object(data), Vec::new())))
+            }
+            Some(Err(e)) => GraphQLResponse::from_result(Ok((Value::Null, vec![e]))),
+            None => break,
+        };
+        if sender.send(make_sse_event(&response)).await.is_err() {
+            return;
+        }
+    }
+    let _ = sender
+        .send(web::Bytes::from_static(b"event: complete\ndata:\n\n"))
+        .await;
+}
+
+/// A batch with a mutation gets the (longer) timeout of the modifications.
+fn get_operation_kind<'a>(
+    mut operations: impl Iterator<Item = &'a OperationInfo>,
+) -> OperationKind {
+    if operations.any(|o| metrics::graphql_operation_type(&o.query) == "mutation") {
+        OperationKind::Modification
+    } else {
+        OperationKind::Search
+    }
+}
+
+fn make_timeout_response() -> HttpResponse {
+    tracing::warn!("GraphQL operation timed out");
+    HttpResponse::ServiceUnavailable().body("The operation took too long")
+}
+
+#[derive(Deserialize)]
+struct OperationInfo {
+    query: String,
+}
+
+impl OperationInfo {
+    fn record(&self, start: Instant) {
+        metrics::record_graphql_operation(metrics::graphql_operation_type(&self.query), start);
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum OperationInfos {
+    Single(OperationInfo),
+    Batch(Vec<OperationInfo>),
 }
 
 pub fn configure_endpoint<Backend>(cfg: &mut web::ServiceConfig)
@@ -96,6 +344,10 @@ where
             .route(web::post().to(graphql_route::<Backend>))
             .route(web::get().to(graphql_route::<Backend>)),
     );
+    cfg.service(
+        web::resource("/graphql/stream")
+            .route(web::post().to(graphql_subscription_route::<Backend>)),
+    );
     cfg.service(web::resource("/graphql/playground").route(web::get().to(playground_route)));
     cfg.service(web::resource("/graphql/graphiql").route(web::get().to(graphiql_route)));
 }
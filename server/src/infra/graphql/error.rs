@@ -0,0 +1,208 @@
+use crate::domain::error::DomainError;
+use juniper::{FieldError, IntoFieldError, Object, ScalarValue, Value};
+
+/// The error of a resolver: a message for humans, and a stable code for the clients to match on,
+/// sent as `extensions.code` in the response.
+///
+/// The codes are:
+/// - `PERMISSION_DENIED`: the user is not allowed to make the query or the change.
+/// - `NOT_FOUND`: the user, group or other entity doesn't exist.
+/// - `ALREADY_EXISTS`: the value must be unique, e.g. the email of a user.
+/// - `INVALID_VALUE`: an argument is invalid, e.g. a malformed user ID or filter.
+/// - `INVALID_AVATAR`: the image is not a JPEG or a PNG, or is too large.
+/// - `INVALID_EMAIL`: the email is malformed, or its domain is not allowed.
+/// - `AUTHENTICATION_FAILED`, `PASSWORD_CHANGE_REQUIRED`, `ACCOUNT_LOCKED`: the authentication
+///   errors.
+/// - `SERVER_BUSY`: the database is overloaded, try again later.
+/// - `INTERNAL_ERROR`: anything else, see the server logs.
+///
+/// The membership changes use `NO_SUCH_USER` and `NO_SUCH_GROUP` rather than `NOT_FOUND`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Error {
+    pub code: &'static str,
+    pub message: String,
+}
+
+/// The result of a resolver.
+pub type FieldResult<T> = std::result::Result<T, Error>;
+
+impl Error {
+    pub fn new(code: &'static str, message: impl Into<String>) -> Self {
+        Self {
+            code,
+            message: message.into(),
+        }
+    }
+
+    pub fn permission_denied(message: impl Into<String>) -> Self {
+        Self::new("PERMISSION_DENIED", message)
+    }
+}
+
+/// The unique constraints of the database, e.g. on the user emails, as worded by SQLite, MySQL
+/// and Postgres.
+fn is_unique_violation(error: &sea_orm::DbErr) -> bool {
+    let message = error.to_string();
+    [
+        "UNIQUE constraint failed",
+        "Duplicate entry",
+        "duplicate key value",
+    ]
+    .iter()
+    .any(|pattern| message.contains(pattern))
+}
+
+fn domain_error_code(error: &DomainError) -> &'static str {
+    match error {
+        DomainError::AuthenticationError(_)
+        | DomainError::AuthenticationProtocolError(_)
+        | DomainError::WebauthnError(_) => "AUTHENTICATION_FAILED",
+        DomainError::PasswordChangeRequired(_) => "PASSWORD_CHANGE_REQUIRED",
+        DomainError::AccountLocked(_) => "ACCOUNT_LOCKED",
+        DomainError::ServerBusy => "SERVER_BUSY",
+        DomainError::DatabaseError(e) if is_unique_violation(e) => "ALREADY_EXISTS",
+        DomainError::DatabaseError(_)
+        | DomainError::InternalError(_)
+        | DomainError::UnknownCryptoError(_) => "INTERNAL_ERROR",
+        DomainError::EntityNotFound(_) => "NOT_FOUND",
+        DomainError::InvalidAvatar(_) => "INVALID_AVATAR",
+        DomainError::InvalidEmail(_) => "INVALID_EMAIL",
+        DomainError::Base64DecodeError(_)
+        | DomainError::BinarySerializationError(_)
+        | DomainError::ValidationError(_) => "INVALID_VALUE",
+    }
+}
+
+impl From<DomainError> for Error {
+    fn from(error: DomainError) -> Self {
+        Self::new(domain_error_code(&error), error.to_string())
+    }
+}
+
+/// Only used for the context of the errors decoding the arguments.
+impl From<anyhow::Error> for Error {
+    fn from(error: anyhow::Error) -> Self {
+        let code = error
+            .downcast_ref::<DomainError>()
+            .map(domain_error_code)
+            .unwrap_or("INVALID_VALUE");
+        Self::new(code, error.to_string())
+    }
+}
+
+/// The plain messages are invalid arguments.
+impl From<String> for Error {
+    fn from(message: String) -> Self {
+        Self::new("INVALID_VALUE", message)
+    }
+}
+
+impl From<&str> for Error {
+    fn from(message: &str) -> Self {
+        Self::new("INVALID_VALUE", message)
+    }
+}
+
+impl<S: ScalarValue> IntoFieldError<S> for Error {
+    fn into_field_error(self) -> FieldError<S> {
+        let mut extensions = Object::with_capacity(1);
+        extensions.add_field("code", Value::scalar(self.code.to_owned()));
+        FieldError::new(self.message, Value::Object(extensions))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::domain::{avatar::AvatarError, email_policy::EmailError};
+    use juniper::graphql_value;
+
+    #[test]
+    fn test_domain_error_codes() {
+        let code = |error: DomainError| Error::from(error).code;
+        assert_eq!(
+            code(DomainError::AuthenticationError("bob".to_owned())),
+            "AUTHENTICATION_FAILED"
+        );
+        assert_eq!(
+            code(DomainError::PasswordChangeRequired("bob".to_owned())),
+            "PASSWORD_CHANGE_REQUIRED"
+        );
+        assert_eq!(
+            code(DomainError::AccountLocked("bob".to_owned())),
+            "ACCOUNT_LOCKED"
+        );
+        assert_eq!(code(DomainError::ServerBusy), "SERVER_BUSY");
+        assert_eq!(
+            code(DomainError::DatabaseError(sea_orm::DbErr::Custom(
+                "UNIQUE constraint failed: users.email".to_owned()
+            ))),
+            "ALREADY_EXISTS"
+        );
+        assert_eq!(
+            code(DomainError::DatabaseError(sea_orm::DbErr::Custom(
+                "disk I/O error".to_owned()
+            ))),
+            "INTERNAL_ERROR"
+        );
+        assert_eq!(
+            code(DomainError::InternalError("down".to_owned())),
+            "INTERNAL_ERROR"
+        );
+        assert_eq!(
+            code(DomainError::UnknownCryptoError(
+                orion::errors::UnknownCryptoError
+            )),
+            "INTERNAL_ERROR"
+        );
+        assert_eq!(
+            code(DomainError::EntityNotFound("No such user".to_owned())),
+            "NOT_FOUND"
+        );
+        assert_eq!(
+            code(DomainError::InvalidAvatar(AvatarError::InvalidImage(
+                "truncated".to_owned()
+            ))),
+            "INVALID_AVATAR"
+        );
+        assert_eq!(
+            code(DomainError::InvalidEmail(EmailError::DomainBlocked(
+                "example.com".to_owned()
+            ))),
+            "INVALID_EMAIL"
+        );
+        assert_eq!(
+            code(DomainError::Base64DecodeError(
+                base64::DecodeError::InvalidLength
+            )),
+            "INVALID_VALUE"
+        );
+        assert_eq!(
+            code(DomainError::BinarySerializationError(Box::new(
+                bincode::ErrorKind::SizeLimit
+            ))),
+            "INVALID_VALUE"
+        );
+        assert_eq!(
+            code(DomainError::ValidationError("bad".to_owned())),
+            "INVALID_VALUE"
+        );
+    }
+
+    #[test]
+    fn test_into_field_error() {
+        let error: FieldError =
+            Error::permission_denied("Unauthorized user creation").into_field_error();
+        assert_eq!(error.message(), "Unauthorized user creation");
+        assert_eq!(
+            error.extensions(),
+            &graphql_value!({ "code": "PERMISSION_DENIED" })
+        );
+        // The message is kept for humans.
+        let error = Error::from(DomainError::EntityNotFound("No such user".to_owned()));
+        assert_eq!(error.message, "Entity not found: `No such user`");
+        let error = Error::from(anyhow::anyhow!("not a PNG").context("Invalid base64 image"));
+        assert_eq!(error.code, "INVALID_VALUE");
+        assert_eq!(error.message, "Invalid base64 image");
+    }
+}
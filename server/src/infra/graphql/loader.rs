@@ -0,0 +1,187 @@
+use crate::domain::{
+    error::Result,
+    handler::{BackendHandler, UserRequestFilter},
+    types::{GroupDetails, GroupId, User, UserId},
+};
+use std::{
+    collections::{HashMap, HashSet},
+    future::Future,
+    hash::Hash,
+};
+
+/// Collects the keys requested by concurrent resolvers, and fetches them all at once.
+///
+/// The resolvers of the items of a list run concurrently: each one queues its key and yields, so
+/// that by the time the first one fetches, the others have queued theirs. The values are only
+/// kept until their resolver takes them, so that a later mutation of the same request doesn't
+/// read stale ones.
+struct BatchLoader<K, V> {
+    pending: std::sync::Mutex<HashSet<K>>,
+    loaded: tokio::sync::Mutex<HashMap<K, V>>,
+}
+
+impl<K, V> Default for BatchLoader<K, V> {
+    fn default() -> Self {
+        Self {
+            pending: Default::default(),
+            loaded: Default::default(),
+        }
+    }
+}
+
+impl<K: Hash + Eq + Clone, V: Default> BatchLoader<K, V> {
+    /// The keys missing from the result of `fetch` get the default value.
+    async fn load<F, Fut>(&self, key: K, fetch: F) -> Result<V>
+    where
+        F: FnOnce(Vec<K>) -> Fut,
+        Fut: Future<Output = Result<HashMap<K, V>>>,
+    {
+        self.pending.lock().unwrap().insert(key.clone());
+        tokio::task::yield_now().await;
+        let mut loaded = self.loaded.lock().await;
+        if let Some(value) = loaded.remove(&key) {
+            return Ok(value);
+        }
+        let keys = {
+            let mut pending = self.pending.lock().unwrap();
+            // The same key may be requested twice, or its batch may have failed.
+            pending.insert(key.clone());
+            pending.drain().collect::<Vec<_>>()
+        };
+        let mut values = fetch(keys.clone()).await?;
+        for k in keys {
+            if k != key {
+                let value = values.remove(&k).unwrap_or_default();
+                loaded.insert(k, value);
+            }
+        }
+        Ok(values.remove(&key).unwrap_or_default())
+    }
+}
+
+/// Batches the membership lookups of a GraphQL request, to resolve the groups of a list of users
+/// (or the members of a list of groups) in a constant number of queries rather than one per item.
+#[derive(Default)]
+pub struct MembershipLoader {
+    user_groups: BatchLoader<UserId, Vec<GroupDetails>>,
+    group_members: BatchLoader<GroupId, Vec<User>>,
+}
+
+impl MembershipLoader {
+    /// The direct groups of the user, sorted by ID.
+    pub async fn user_groups<Handler: BackendHandler>(
+        &self,
+        handler: &Handler,
+        user_id: &UserId,
+    ) -> Result<Vec<GroupDetails>> {
+        self.user_groups
+            .load(user_id.clone(), |user_ids| async move {
+                Ok(handler
+                    .get_users_groups(&user_ids)
+                    .await?
+                    .into_iter()
+                    .map(|(user_id, groups)| {
+                        let mut groups = groups.into_iter().collect::<Vec<_>>();
+                        groups.sort_by_key(|g| g.group_id);
+                        (user_id, groups)
+                    })
+                    .collect())
+            })
+            .await
+    }
+
+    /// The direct members of the group, without the deleted users.
+    pub async fn group_members<Handler: BackendHandler>(
+        &self,
+        handler: &Handler,
+        group_id: GroupId,
+    ) -> Result<Vec<User>> {
+        self.group_members
+            .load(group_id, |group_ids| async move {
+                let users = handler
+                    .list_users(
+                        Some(UserRequestFilter::Or(
+                            group_ids
+                                .iter()
+                                .copied()
+                                .map(UserRequestFilter::MemberOfId)
+                                .collect(),
+                        )),
+                        false,
+                    )
+                    .await?;
+                let user_ids = users
+                    .iter()
+                    .map(|u| u.user.user_id.clone())
+                    .collect::<Vec<_>>();
+                let user_groups = handler.get_users_groups(&user_ids).await?;
+                let mut members = HashMap::<GroupId, Vec<User>>::new();
+                for user in users {
+                    for group in user_groups.get(&user.user.user_id).into_iter().flatten() {
+                        if group_ids.contains(&group.group_id) {
+                            members
+                                .entry(group.group_id)
+                                .or_default()
+                                .push(user.user.clone());
+                        }
+                    }
+                }
+                Ok(members)
+            })
+            .await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::domain::error::DomainError;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    #[tokio::test]
+    async fn test_batch_loader() {
+        let loader = BatchLoader::<i32, i32>::default();
+        let fetches = AtomicUsize::new(0);
+        let fetch = |keys: Vec<i32>| {
+            fetches.fetch_add(1, Ordering::Relaxed);
+            async move {
+                Ok(keys
+                    .into_iter()
+                    .filter(|k| *k != 3)
+                    .map(|k| (k, k * 10))
+                    .collect())
+            }
+        };
+        let values = futures::future::join_all((1..=4).map(|k| loader.load(k, fetch))).await;
+        assert_eq!(
+            values.into_iter().map(Result::unwrap).collect::<Vec<_>>(),
+            vec![10, 20, 0, 40]
+        );
+        assert_eq!(fetches.load(Ordering::Relaxed), 1);
+        // Not cached beyond the batch.
+        assert_eq!(loader.load(2, fetch).await.unwrap(), 20);
+        assert_eq!(fetches.load(Ordering::Relaxed), 2);
+    }
+
+    #[tokio::test]
+    async fn test_batch_loader_error() {
+        let loader = BatchLoader::<i32, i32>::default();
+        assert!(loader
+            .load(1, |_| async {
+                Err(DomainError::InternalError("down".to_owned()))
+            })
+            .await
+            .is_err());
+        // The failed keys are fetched again.
+        assert_eq!(
+            loader
+                .load(1, |keys| async move {
+                    assert_eq!(keys, vec![1]);
+                    Ok(HashMap::from([(1, 5)]))
+                })
+                .await
+                .unwrap(),
+            5
+        );
+    }
+}
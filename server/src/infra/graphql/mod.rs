@@ -1,3 +1,6 @@
 pub mod api;
+pub mod error;
+pub mod loader;
 pub mod mutation;
 pub mod query;
+pub mod subscription;
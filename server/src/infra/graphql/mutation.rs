@@ -1,12 +1,23 @@
-use crate::domain::{
-    handler::{BackendHandler, CreateUserRequest, UpdateGroupRequest, UpdateUserRequest},
-    types::{GroupId, JpegPhoto, UserId},
+use crate::{
+    domain::{
+        avatar::decode_avatar,
+        error::DomainError,
+        group_rule_handler::{CreateGroupRuleRequest, GroupRuleCondition},
+        handler::{self, BackendHandler, CreateUserRequest, UpdateGroupRequest, UpdateUserRequest},
+        sudo_role_handler::SudoRoleRequest,
+        types::{GroupId, UserId},
+    },
+    infra::access_control::AclAttribute,
 };
 use anyhow::Context as AnyhowContext;
-use juniper::{graphql_object, FieldResult, GraphQLInputObject, GraphQLObject};
+use juniper::{graphql_object, GraphQLEnum, GraphQLInputObject, GraphQLObject};
 use tracing::{debug, debug_span, Instrument};
 
-use super::api::Context;
+use super::{
+    api::Context,
+    error::{Error, FieldResult},
+    query::GroupRuleOperator,
+};
 
 #[derive(PartialEq, Eq, Debug)]
 /// The top-level GraphQL mutation type.
@@ -30,7 +41,7 @@ pub struct CreateUserInput {
     display_name: Option<String>,
     first_name: Option<String>,
     last_name: Option<String>,
-    // Base64 encoded JpegPhoto.
+    // Base64 encoded JPEG or PNG.
     avatar: Option<String>,
 }
 
@@ -42,8 +53,10 @@ pub struct UpdateUserInput {
     display_name: Option<String>,
     first_name: Option<String>,
     last_name: Option<String>,
-    // Base64 encoded JpegPhoto.
+    // Base64 encoded JPEG or PNG.
     avatar: Option<String>,
+    // Only admins can set it.
+    must_change_password: Option<bool>,
 }
 
 #[derive(PartialEq, Eq, Debug, GraphQLInputObject)]
@@ -53,6 +66,60 @@ pub struct UpdateGroupInput {
     display_name: Option<String>,
 }
 
+#[derive(PartialEq, Eq, Debug, GraphQLInputObject)]
+/// A sudoers rule. The missing lists are empty, and the update replaces all of them.
+pub struct SudoRoleInput {
+    name: String,
+    users: Option<Vec<String>>,
+    hosts: Option<Vec<String>>,
+    commands: Option<Vec<String>>,
+    options: Option<Vec<String>>,
+}
+
+impl From<SudoRoleInput> for SudoRoleRequest {
+    fn from(role: SudoRoleInput) -> Self {
+        Self {
+            name: role.name,
+            users: role.users.unwrap_or_default(),
+            hosts: role.hosts.unwrap_or_default(),
+            commands: role.commands.unwrap_or_default(),
+            options: role.options.unwrap_or_default(),
+        }
+    }
+}
+
+#[derive(PartialEq, Eq, Debug, GraphQLInputObject)]
+/// A condition on a built-in user field (e.g. `email`) or on a custom attribute of type string.
+pub struct GroupRuleConditionInput {
+    attribute: String,
+    operator: GroupRuleOperator,
+    value: String,
+}
+
+#[derive(PartialEq, Eq, Debug, GraphQLInputObject)]
+/// Keeps the users matching all the conditions in the group.
+pub struct GroupRuleInput {
+    group_id: i32,
+    conditions: Vec<GroupRuleConditionInput>,
+}
+
+impl From<GroupRuleInput> for CreateGroupRuleRequest {
+    fn from(rule: GroupRuleInput) -> Self {
+        Self {
+            group_id: GroupId(rule.group_id),
+            conditions: rule
+                .conditions
+                .into_iter()
+                .map(|condition| GroupRuleCondition {
+                    attribute: condition.attribute,
+                    operator: condition.operator.into(),
+                    value: condition.value,
+                })
+                .collect(),
+        }
+    }
+}
+
 #[derive(PartialEq, Eq, Debug, GraphQLObject)]
 pub struct Success {
     ok: bool,
@@ -64,6 +131,50 @@ impl Success {
     }
 }
 
+#[derive(PartialEq, Eq, Debug, Clone, Copy, GraphQLEnum)]
+pub enum MembershipChangeStatus {
+    Changed,
+    /// Already a member when adding, or not a member when removing.
+    Unchanged,
+    NoSuchUser,
+}
+
+#[derive(PartialEq, Eq, Debug, GraphQLObject)]
+/// What a bulk membership change did for one user.
+pub struct MembershipChange {
+    user_id: String,
+    status: MembershipChangeStatus,
+}
+
+impl From<handler::MembershipChange> for MembershipChange {
+    fn from(change: handler::MembershipChange) -> Self {
+        Self {
+            user_id: change.user_id.into_string(),
+            status: match change.status {
+                handler::MembershipChangeStatus::Changed => MembershipChangeStatus::Changed,
+                handler::MembershipChangeStatus::Unchanged => MembershipChangeStatus::Unchanged,
+                handler::MembershipChangeStatus::NoSuchUser => MembershipChangeStatus::NoSuchUser,
+            },
+        }
+    }
+}
+
+/// The missing user or group of a membership change, with a code that clients can match on
+/// instead of the database error.
+fn membership_error(error: DomainError) -> Error {
+    match error {
+        DomainError::EntityNotFound(message) => {
+            let code = if message.starts_with("No such group") {
+                "NO_SUCH_GROUP"
+            } else {
+                "NO_SUCH_USER"
+            };
+            Error::new(code, message)
+        }
+        e => e.into(),
+    }
+}
+
 #[graphql_object(context = Context<Handler>)]
 impl<Handler: BackendHandler + Sync> Mutation<Handler> {
     async fn create_user(
@@ -76,7 +187,7 @@ impl<Handler: BackendHandler + Sync> Mutation<Handler> {
         });
         if !context.validation_result.is_admin() {
             span.in_scope(|| debug!("Unauthorized"));
-            return Err("Unauthorized user creation".into());
+            return Err(Error::permission_denied("Unauthorized user creation"));
         }
         let user_id = UserId::new(&user.id);
         let avatar = user
@@ -84,9 +195,9 @@ impl<Handler: BackendHandler + Sync> Mutation<Handler> {
             .map(base64::decode)
             .transpose()
             .context("Invalid base64 image")?
-            .map(JpegPhoto::try_from)
+            .map(|bytes| decode_avatar(&bytes))
             .transpose()
-            .context("Provided image is not a valid JPEG")?;
+            .context("Provided image is not a valid JPEG or PNG")?;
         context
             .handler
             .create_user(CreateUserRequest {
@@ -96,6 +207,7 @@ impl<Handler: BackendHandler + Sync> Mutation<Handler> {
                 first_name: user.first_name,
                 last_name: user.last_name,
                 avatar,
+                ..Default::default()
             })
             .instrument(span.clone())
             .await?;
@@ -117,7 +229,7 @@ impl<Handler: BackendHandler + Sync> Mutation<Handler> {
         });
         if !context.validation_result.is_admin() {
             span.in_scope(|| debug!("Unauthorized"));
-            return Err("Unauthorized group creation".into());
+            return Err(Error::permission_denied("Unauthorized group creation"));
         }
         let group_id = context.handler.create_group(&name).await?;
         Ok(context
@@ -137,18 +249,47 @@ impl<Handler: BackendHandler + Sync> Mutation<Handler> {
             debug!(?user.id);
         });
         let user_id = UserId::new(&user.id);
-        if !context.validation_result.can_write(&user_id) {
+        // The attribute ACL can give or deny the modification of each attribute.
+        let access = context.get_attribute_access();
+        let attributes = [
+            (AclAttribute::Email, user.email.is_some()),
+            (AclAttribute::DisplayName, user.display_name.is_some()),
+            (AclAttribute::FirstName, user.first_name.is_some()),
+            (AclAttribute::LastName, user.last_name.is_some()),
+            (AclAttribute::Avatar, user.avatar.is_some()),
+        ]
+        .into_iter()
+        .filter(|(_, is_set)| *is_set)
+        .map(|(attribute, _)| attribute)
+        .collect::<Vec<_>>();
+        if attributes.is_empty() && !context.validation_result.can_write(&user_id) {
+            span.in_scope(|| debug!("Unauthorized"));
+            return Err(Error::permission_denied("Unauthorized user update"));
+        }
+        if let Some(attribute) = attributes
+            .into_iter()
+            .find(|attribute| !access.can_write(&user_id, *attribute))
+        {
+            span.in_scope(|| debug!(?attribute, "Unauthorized"));
+            return Err(Error::permission_denied(format!(
+                "Unauthorized update of the attribute {:?}",
+                attribute
+            )));
+        }
+        if user.must_change_password.is_some() && !context.validation_result.is_admin() {
             span.in_scope(|| debug!("Unauthorized"));
-            return Err("Unauthorized user update".into());
+            return Err(Error::permission_denied(
+                "Only admins can require a password change",
+            ));
         }
         let avatar = user
             .avatar
             .map(base64::decode)
             .transpose()
             .context("Invalid base64 image")?
-            .map(JpegPhoto::try_from)
+            .map(|bytes| decode_avatar(&bytes))
             .transpose()
-            .context("Provided image is not a valid JPEG")?;
+            .context("Provided image is not a valid JPEG or PNG")?;
         context
             .handler
             .update_user(UpdateUserRequest {
@@ -158,6 +299,7 @@ impl<Handler: BackendHandler + Sync> Mutation<Handler> {
                 first_name: user.first_name,
                 last_name: user.last_name,
                 avatar,
+                must_change_password: user.must_change_password,
             })
             .instrument(span)
             .await?;
@@ -174,11 +316,13 @@ impl<Handler: BackendHandler + Sync> Mutation<Handler> {
         });
         if !context.validation_result.is_admin() {
             span.in_scope(|| debug!("Unauthorized"));
-            return Err("Unauthorized group update".into());
+            return Err(Error::permission_denied("Unauthorized group update"));
         }
         if group.id == 1 {
             span.in_scope(|| debug!("Cannot change admin group details"));
-            return Err("Cannot change admin group details".into());
+            return Err(Error::permission_denied(
+                "Cannot change admin group details",
+            ));
         }
         context
             .handler
@@ -202,7 +346,9 @@ impl<Handler: BackendHandler + Sync> Mutation<Handler> {
         });
         if !context.validation_result.is_admin() {
             span.in_scope(|| debug!("Unauthorized"));
-            return Err("Unauthorized group membership modification".into());
+            return Err(Error::permission_denied(
+                "Unauthorized group membership modification",
+            ));
         }
         context
             .handler
@@ -223,12 +369,16 @@ impl<Handler: BackendHandler + Sync> Mutation<Handler> {
         });
         if !context.validation_result.is_admin() {
             span.in_scope(|| debug!("Unauthorized"));
-            return Err("Unauthorized group membership modification".into());
+            return Err(Error::permission_denied(
+                "Unauthorized group membership modification",
+            ));
         }
         let user_id = UserId::new(&user_id);
         if context.validation_result.user == user_id && group_id == 1 {
             span.in_scope(|| debug!("Cannot remove admin rights for current user"));
-            return Err("Cannot remove admin rights for current user".into());
+            return Err(Error::permission_denied(
+                "Cannot remove admin rights for current user",
+            ));
         }
         context
             .handler
@@ -238,6 +388,85 @@ impl<Handler: BackendHandler + Sync> Mutation<Handler> {
         Ok(Success::new())
     }
 
+    /// Adds all the users to the group at once. The unknown users are reported in the result,
+    /// unless `allOrNothing` is set: then they fail the whole change.
+    async fn add_users_to_group(
+        context: &Context<Handler>,
+        group_id: i32,
+        user_ids: Vec<String>,
+        all_or_nothing: Option<bool>,
+    ) -> FieldResult<Vec<MembershipChange>> {
+        let span = debug_span!("[GraphQL mutation] add_users_to_group");
+        span.in_scope(|| {
+            debug!(?group_id, ?user_ids);
+        });
+        if !context.validation_result.is_admin() {
+            span.in_scope(|| debug!("Unauthorized"));
+            return Err(Error::permission_denied(
+                "Unauthorized group membership modification",
+            ));
+        }
+        let user_ids = user_ids
+            .iter()
+            .map(|id| UserId::new(id))
+            .collect::<Vec<_>>();
+        Ok(context
+            .handler
+            .add_users_to_group(
+                GroupId(group_id),
+                &user_ids,
+                all_or_nothing.unwrap_or(false),
+            )
+            .instrument(span)
+            .await
+            .map_err(membership_error)?
+            .into_iter()
+            .map(Into::into)
+            .collect())
+    }
+
+    /// Same as `addUsersToGroup`, for the removals.
+    async fn remove_users_from_group(
+        context: &Context<Handler>,
+        group_id: i32,
+        user_ids: Vec<String>,
+        all_or_nothing: Option<bool>,
+    ) -> FieldResult<Vec<MembershipChange>> {
+        let span = debug_span!("[GraphQL mutation] remove_users_from_group");
+        span.in_scope(|| {
+            debug!(?group_id, ?user_ids);
+        });
+        if !context.validation_result.is_admin() {
+            span.in_scope(|| debug!("Unauthorized"));
+            return Err(Error::permission_denied(
+                "Unauthorized group membership modification",
+            ));
+        }
+        let user_ids = user_ids
+            .iter()
+            .map(|id| UserId::new(id))
+            .collect::<Vec<_>>();
+        if group_id == 1 && user_ids.contains(&context.validation_result.user) {
+            span.in_scope(|| debug!("Cannot remove admin rights for current user"));
+            return Err(Error::permission_denied(
+                "Cannot remove admin rights for current user",
+            ));
+        }
+        Ok(context
+            .handler
+            .remove_users_from_group(
+                GroupId(group_id),
+                &user_ids,
+                all_or_nothing.unwrap_or(false),
+            )
+            .instrument(span)
+            .await
+            .map_err(membership_error)?
+            .into_iter()
+            .map(Into::into)
+            .collect())
+    }
+
     async fn delete_user(context: &Context<Handler>, user_id: String) -> FieldResult<Success> {
         let span = debug_span!("[GraphQL mutation] delete_user");
         span.in_scope(|| {
@@ -246,11 +475,11 @@ impl<Handler: BackendHandler + Sync> Mutation<Handler> {
         let user_id = UserId::new(&user_id);
         if !context.validation_result.is_admin() {
             span.in_scope(|| debug!("Unauthorized"));
-            return Err("Unauthorized user deletion".into());
+            return Err(Error::permission_denied("Unauthorized user deletion"));
         }
         if context.validation_result.user == user_id {
             span.in_scope(|| debug!("Cannot delete current user"));
-            return Err("Cannot delete current user".into());
+            return Err(Error::permission_denied("Cannot delete current user"));
         }
         context
             .handler
@@ -260,6 +489,57 @@ impl<Handler: BackendHandler + Sync> Mutation<Handler> {
         Ok(Success::new())
     }
 
+    /// Changes the ID of a user. The memberships, attributes and credentials follow it.
+    async fn rename_user(
+        context: &Context<Handler>,
+        user_id: String,
+        new_user_id: String,
+    ) -> FieldResult<super::query::User<Handler>> {
+        let span = debug_span!("[GraphQL mutation] rename_user");
+        span.in_scope(|| {
+            debug!(?user_id, ?new_user_id);
+        });
+        let user_id = UserId::new(&user_id);
+        let new_user_id = UserId::new(&new_user_id);
+        if !context.validation_result.is_admin() {
+            span.in_scope(|| debug!("Unauthorized"));
+            return Err(Error::permission_denied("Unauthorized user rename"));
+        }
+        // The sessions and tokens of the current user are tied to its ID.
+        if context.validation_result.user == user_id {
+            span.in_scope(|| debug!("Cannot rename current user"));
+            return Err(Error::permission_denied("Cannot rename current user"));
+        }
+        context
+            .handler
+            .rename_user(&user_id, &new_user_id)
+            .instrument(span.clone())
+            .await?;
+        Ok(context
+            .handler
+            .get_user_details(&new_user_id)
+            .instrument(span)
+            .await
+            .map(Into::into)?)
+    }
+
+    async fn unlock_user(context: &Context<Handler>, user_id: String) -> FieldResult<Success> {
+        let span = debug_span!("[GraphQL mutation] unlock_user");
+        span.in_scope(|| {
+            debug!(?user_id);
+        });
+        if !context.validation_result.is_admin() {
+            span.in_scope(|| debug!("Unauthorized"));
+            return Err(Error::permission_denied("Unauthorized user unlock"));
+        }
+        context
+            .handler
+            .unlock_user(&UserId::new(&user_id))
+            .instrument(span)
+            .await?;
+        Ok(Success::new())
+    }
+
     async fn delete_group(context: &Context<Handler>, group_id: i32) -> FieldResult<Success> {
         let span = debug_span!("[GraphQL mutation] delete_group");
         span.in_scope(|| {
@@ -267,11 +547,11 @@ impl<Handler: BackendHandler + Sync> Mutation<Handler> {
         });
         if !context.validation_result.is_admin() {
             span.in_scope(|| debug!("Unauthorized"));
-            return Err("Unauthorized group deletion".into());
+            return Err(Error::permission_denied("Unauthorized group deletion"));
         }
         if group_id == 1 {
             span.in_scope(|| debug!("Cannot delete admin group"));
-            return Err("Cannot delete admin group".into());
+            return Err(Error::permission_denied("Cannot delete admin group"));
         }
         context
             .handler
@@ -280,4 +560,167 @@ impl<Handler: BackendHandler + Sync> Mutation<Handler> {
             .await?;
         Ok(Success::new())
     }
+
+    async fn create_sudo_role(
+        context: &Context<Handler>,
+        role: SudoRoleInput,
+    ) -> FieldResult<Success> {
+        let span = debug_span!("[GraphQL mutation] create_sudo_role");
+        span.in_scope(|| {
+            debug!(?role.name);
+        });
+        if !context.validation_result.is_admin() {
+            span.in_scope(|| debug!("Unauthorized"));
+            return Err(Error::permission_denied("Unauthorized sudo role creation"));
+        }
+        context
+            .handler
+            .create_sudo_role(role.into())
+            .instrument(span)
+            .await?;
+        Ok(Success::new())
+    }
+
+    async fn update_sudo_role(
+        context: &Context<Handler>,
+        role: SudoRoleInput,
+    ) -> FieldResult<Success> {
+        let span = debug_span!("[GraphQL mutation] update_sudo_role");
+        span.in_scope(|| {
+            debug!(?role.name);
+        });
+        if !context.validation_result.is_admin() {
+            span.in_scope(|| debug!("Unauthorized"));
+            return Err(Error::permission_denied("Unauthorized sudo role update"));
+        }
+        context
+            .handler
+            .update_sudo_role(role.into())
+            .instrument(span)
+            .await?;
+        Ok(Success::new())
+    }
+
+    async fn delete_sudo_role(context: &Context<Handler>, name: String) -> FieldResult<Success> {
+        let span = debug_span!("[GraphQL mutation] delete_sudo_role");
+        span.in_scope(|| {
+            debug!(?name);
+        });
+        if !context.validation_result.is_admin() {
+            span.in_scope(|| debug!("Unauthorized"));
+            return Err(Error::permission_denied("Unauthorized sudo role deletion"));
+        }
+        context
+            .handler
+            .delete_sudo_role(&name)
+            .instrument(span)
+            .await?;
+        Ok(Success::new())
+    }
+
+    /// Returns the ID of the rule. It applies to the existing users once the rules are
+    /// reevaluated.
+    async fn create_group_rule(
+        context: &Context<Handler>,
+        rule: GroupRuleInput,
+    ) -> FieldResult<i32> {
+        let span = debug_span!("[GraphQL mutation] create_group_rule");
+        span.in_scope(|| {
+            debug!(?rule.group_id);
+        });
+        if !context.validation_result.is_admin() {
+            span.in_scope(|| debug!("Unauthorized"));
+            return Err(Error::permission_denied("Unauthorized group rule creation"));
+        }
+        Ok(context
+            .handler
+            .create_group_rule(rule.into())
+            .instrument(span)
+            .await?)
+    }
+
+    async fn delete_group_rule(context: &Context<Handler>, rule_id: i32) -> FieldResult<Success> {
+        let span = debug_span!("[GraphQL mutation] delete_group_rule");
+        span.in_scope(|| {
+            debug!(?rule_id);
+        });
+        if !context.validation_result.is_admin() {
+            span.in_scope(|| debug!("Unauthorized"));
+            return Err(Error::permission_denied("Unauthorized group rule deletion"));
+        }
+        context
+            .handler
+            .delete_group_rule(rule_id)
+            .instrument(span)
+            .await?;
+        Ok(Success::new())
+    }
+
+    /// Applies the rules to all the users. Returns the number of memberships added or removed.
+    async fn reevaluate_group_rules(context: &Context<Handler>) -> FieldResult<i32> {
+        let span = debug_span!("[GraphQL mutation] reevaluate_group_rules");
+        if !context.validation_result.is_admin() {
+            span.in_scope(|| debug!("Unauthorized"));
+            return Err(Error::permission_denied(
+                "Unauthorized group rule reevaluation",
+            ));
+        }
+        let num_changes = context
+            .handler
+            .reevaluate_group_rules()
+            .instrument(span)
+            .await?;
+        Ok(i32::try_from(num_changes).map_err(|e| DomainError::InternalError(e.to_string()))?)
+    }
+
+    /// Starts rebuilding the data derived from the users and groups in the background: the
+    /// memberships of the group rules, the missing POSIX numbers, the group ID sequence, and the
+    /// leftovers of the deleted users and groups. Follow it with the `maintenanceStatus` query.
+    /// With `dryRun`, nothing is changed.
+    async fn start_maintenance(
+        context: &Context<Handler>,
+        dry_run: Option<bool>,
+    ) -> FieldResult<Success> {
+        let span = debug_span!("[GraphQL mutation] start_maintenance");
+        span.in_scope(|| {
+            debug!(?dry_run);
+        });
+        if !context.validation_result.is_admin() {
+            span.in_scope(|| debug!("Unauthorized"));
+            return Err(Error::permission_denied("Unauthorized maintenance"));
+        }
+        context
+            .handler
+            .start_maintenance(dry_run.unwrap_or(false))
+            .instrument(span)
+            .await?;
+        Ok(Success::new())
+    }
+
+    /// Logs the session out: its refresh token and its JWTs are refused from now on.
+    async fn revoke_session(
+        context: &Context<Handler>,
+        session_id: String,
+    ) -> FieldResult<Success> {
+        let span = debug_span!("[GraphQL mutation] revoke_session");
+        span.in_scope(|| {
+            debug!(?session_id);
+        });
+        let session = context
+            .handler
+            .get_session(&session_id)
+            .instrument(span.clone())
+            .await?;
+        if !context.validation_result.can_write(&session.user_id) {
+            span.in_scope(|| debug!("Unauthorized"));
+            return Err(Error::permission_denied("Unauthorized session revocation"));
+        }
+        context
+            .handler
+            .revoke_session(&session_id)
+            .instrument(span)
+            .await?;
+        context.revoked_sessions.write().unwrap().insert(session_id);
+        Ok(Success::new())
+    }
 }
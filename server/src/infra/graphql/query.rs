@@ -1,9 +1,11 @@
 use crate::domain::{
-    handler::BackendHandler,
+    group_rule_handler::GroupRuleOperator as DomainGroupRuleOperator,
+    handler::{BackendHandler, Pagination, Tombstone as DomainTombstone},
     ldap::utils::map_user_field,
+    maintenance_handler::MaintenanceStep,
     types::{GroupDetails, GroupId, UserColumn, UserId},
 };
-use juniper::{graphql_object, FieldResult, GraphQLInputObject};
+use juniper::{graphql_object, GraphQLEnum, GraphQLInputObject, GraphQLObject};
 use serde::{Deserialize, Serialize};
 use tracing::{debug, debug_span, Instrument};
 
@@ -11,7 +13,39 @@ type DomainRequestFilter = crate::domain::handler::UserRequestFilter;
 type DomainUser = crate::domain::types::User;
 type DomainGroup = crate::domain::types::Group;
 type DomainUserAndGroups = crate::domain::types::UserAndGroups;
-use super::api::Context;
+type DomainChangeSet = crate::domain::handler::ChangeSet;
+type DomainSudoRole = crate::domain::sudo_role_handler::SudoRole;
+type DomainSession = crate::domain::session_handler::Session;
+type DomainMaintenanceStatus = crate::domain::maintenance_handler::MaintenanceStatus;
+type DomainMaintenanceReport = crate::domain::maintenance_handler::MaintenanceReport;
+type DomainGroupRule = crate::domain::group_rule_handler::GroupRule;
+type DomainGroupRuleCondition = crate::domain::group_rule_handler::GroupRuleCondition;
+use super::{
+    api::Context,
+    error::{Error, FieldResult},
+};
+use crate::infra::access_control::AclAttribute;
+
+const DEFAULT_SEARCH_LIMIT: i32 = 20;
+
+/// The sync cursors are the start of the next sync, encoded to keep them opaque to the clients.
+fn encode_sync_cursor(until: chrono::DateTime<chrono::Utc>) -> String {
+    base64::encode(until.to_rfc3339())
+}
+
+fn decode_sync_cursor(cursor: &str) -> FieldResult<chrono::DateTime<chrono::Utc>> {
+    base64::decode(cursor)
+        .ok()
+        .and_then(|bytes| String::from_utf8(bytes).ok())
+        .and_then(|date| chrono::DateTime::parse_from_rfc3339(&date).ok())
+        .map(|date| date.with_timezone(&chrono::Utc))
+        .ok_or_else(|| "Invalid sync cursor".into())
+}
+
+/// GraphQL only has 32-bit integers: the counts saturate.
+fn to_graphql_count(count: usize) -> i32 {
+    i32::try_from(count).unwrap_or(i32::MAX)
+}
 
 #[derive(PartialEq, Eq, Debug, GraphQLInputObject)]
 /// A filter for requests, specifying a boolean expression based on field constraints. Only one of
@@ -96,6 +130,41 @@ pub struct EqualityConstraint {
     value: String,
 }
 
+fn get_user_filters(
+    filters: Option<RequestFilter>,
+    include_deleted: Option<bool>,
+) -> Result<Option<DomainRequestFilter>, String> {
+    let filters: Option<DomainRequestFilter> = filters.map(TryInto::try_into).transpose()?;
+    Ok(if include_deleted.unwrap_or(false) {
+        Some(DomainRequestFilter::And(
+            filters
+                .into_iter()
+                .chain(std::iter::once(DomainRequestFilter::IncludeDeleted))
+                .collect(),
+        ))
+    } else {
+        filters
+    })
+}
+
+/// The users can list the others with the read permission, or if the attribute ACL lets them read
+/// some of their attributes. Then they can only filter on the readable attributes.
+fn check_user_list_access<Handler: BackendHandler>(
+    context: &Context<Handler>,
+    filters: &Option<DomainRequestFilter>,
+) -> FieldResult<()> {
+    let access = context.get_attribute_access();
+    if !access.can_list_users() {
+        return Err(Error::permission_denied("Unauthorized access to user list"));
+    }
+    if !filters.iter().all(|filters| access.can_filter(filters)) {
+        return Err(Error::permission_denied(
+            "Unauthorized filter on an attribute that cannot be read",
+        ));
+    }
+    Ok(())
+}
+
 #[derive(PartialEq, Eq, Debug)]
 /// The top-level GraphQL query type.
 pub struct Query<Handler: BackendHandler> {
@@ -122,9 +191,11 @@ impl<Handler: BackendHandler + Sync> Query<Handler> {
             debug!(?user_id);
         });
         let user_id = UserId::new(&user_id);
-        if !context.validation_result.can_read(&user_id) {
+        if !context.validation_result.can_read(&user_id)
+            && !context.get_attribute_access().can_list_users()
+        {
             span.in_scope(|| debug!("Unauthorized"));
-            return Err("Unauthorized access to user data".into());
+            return Err(Error::permission_denied("Unauthorized access to user data"));
         }
         Ok(context
             .handler
@@ -137,28 +208,111 @@ impl<Handler: BackendHandler + Sync> Query<Handler> {
     async fn users(
         context: &Context<Handler>,
         #[graphql(name = "where")] filters: Option<RequestFilter>,
+        include_deleted: Option<bool>,
     ) -> FieldResult<Vec<User<Handler>>> {
         let span = debug_span!("[GraphQL query] users");
         span.in_scope(|| {
-            debug!(?filters);
+            debug!(?filters, ?include_deleted);
+        });
+        let filters = get_user_filters(filters, include_deleted)?;
+        check_user_list_access(context, &filters).map_err(|e| {
+            span.in_scope(|| debug!("Unauthorized"));
+            e
+        })?;
+        Ok(context
+            .handler
+            .list_users(filters, false)
+            .instrument(span)
+            .await
+            .map(|v| v.into_iter().map(Into::into).collect())?)
+    }
+
+    /// Same as `users`, sorted by ID, one page at a time. Pass the `nextCursor` of a page as
+    /// `after` to get the next one.
+    async fn users_page(
+        context: &Context<Handler>,
+        #[graphql(name = "where")] filters: Option<RequestFilter>,
+        include_deleted: Option<bool>,
+        first: i32,
+        after: Option<String>,
+    ) -> FieldResult<UserPage<Handler>> {
+        let span = debug_span!("[GraphQL query] users_page");
+        span.in_scope(|| {
+            debug!(?filters, ?include_deleted, ?first, ?after);
+        });
+        let filters = get_user_filters(filters, include_deleted)?;
+        check_user_list_access(context, &filters).map_err(|e| {
+            span.in_scope(|| debug!("Unauthorized"));
+            e
+        })?;
+        let page_size = usize::try_from(first).map_err(|_| "`first` cannot be negative")?;
+        let page = context
+            .handler
+            .list_users_page(filters, false, Pagination { after, page_size })
+            .instrument(span)
+            .await?;
+        Ok(UserPage {
+            users: page.items.into_iter().map(|u| u.user.into()).collect(),
+            next_cursor: page.next_cursor,
+        })
+    }
+
+    /// Substring search in the names and emails of the users, best matches first.
+    async fn search_users(
+        context: &Context<Handler>,
+        query: String,
+        limit: Option<i32>,
+    ) -> FieldResult<Vec<User<Handler>>> {
+        let span = debug_span!("[GraphQL query] search_users");
+        span.in_scope(|| {
+            debug!(?query, ?limit);
         });
         if !context.validation_result.is_admin_or_readonly() {
             span.in_scope(|| debug!("Unauthorized"));
-            return Err("Unauthorized access to user list".into());
+            return Err(Error::permission_denied("Unauthorized access to user list"));
         }
+        let limit = usize::try_from(limit.unwrap_or(DEFAULT_SEARCH_LIMIT))
+            .map_err(|_| "The limit cannot be negative")?;
         Ok(context
             .handler
-            .list_users(filters.map(TryInto::try_into).transpose()?, false)
+            .search_users(&query, &[], limit)
             .instrument(span)
             .await
             .map(|v| v.into_iter().map(Into::into).collect())?)
     }
 
+    /// The users and groups changed since the sync that returned `since`, or all of them without
+    /// `since`. Pass the `nextCursor` to the next sync.
+    async fn changes(
+        context: &Context<Handler>,
+        since: Option<String>,
+    ) -> FieldResult<ChangeSet<Handler>> {
+        let span = debug_span!("[GraphQL query] changes");
+        span.in_scope(|| {
+            debug!(?since);
+        });
+        if !context.validation_result.is_admin_or_readonly() {
+            span.in_scope(|| debug!("Unauthorized"));
+            return Err(Error::permission_denied(
+                "Unauthorized access to the changes",
+            ));
+        }
+        let since = since.as_deref().map(decode_sync_cursor).transpose()?;
+        Ok(context
+            .handler
+            .changed_since(since)
+            .instrument(span)
+            .await
+            .map(Into::into)?)
+    }
+
     async fn groups(context: &Context<Handler>) -> FieldResult<Vec<Group<Handler>>> {
         let span = debug_span!("[GraphQL query] groups");
         if !context.validation_result.is_admin_or_readonly() {
             span.in_scope(|| debug!("Unauthorized"));
-            return Err("Unauthorized access to group list".into());
+            return Err(Error::permission_denied(
+                "Unauthorized access to group list",
+            ));
         }
         Ok(context
             .handler
@@ -175,7 +329,9 @@ impl<Handler: BackendHandler + Sync> Query<Handler> {
         });
         if !context.validation_result.is_admin_or_readonly() {
             span.in_scope(|| debug!("Unauthorized"));
-            return Err("Unauthorized access to group data".into());
+            return Err(Error::permission_denied(
+                "Unauthorized access to group data",
+            ));
         }
         Ok(context
             .handler
@@ -184,6 +340,146 @@ impl<Handler: BackendHandler + Sync> Query<Handler> {
             .await
             .map(Into::into)?)
     }
+
+    /// The number of direct members of the group, without the deleted users.
+    async fn group_member_count(context: &Context<Handler>, group_id: i32) -> FieldResult<i32> {
+        let span = debug_span!("[GraphQL query] group_member_count");
+        span.in_scope(|| {
+            debug!(?group_id);
+        });
+        if !context.validation_result.is_admin_or_readonly() {
+            span.in_scope(|| debug!("Unauthorized"));
+            return Err(Error::permission_denied(
+                "Unauthorized access to group data",
+            ));
+        }
+        let count = context
+            .handler
+            .count_users(Some(DomainRequestFilter::MemberOfId(GroupId(group_id))))
+            .instrument(span)
+            .await?;
+        Ok(to_graphql_count(count))
+    }
+
+    /// The number of users, without the deleted ones.
+    async fn total_user_count(context: &Context<Handler>) -> FieldResult<i32> {
+        let span = debug_span!("[GraphQL query] total_user_count");
+        check_user_list_access(context, &None).map_err(|e| {
+            span.in_scope(|| debug!("Unauthorized"));
+            e
+        })?;
+        let count = context.handler.count_users(None).instrument(span).await?;
+        Ok(to_graphql_count(count))
+    }
+
+    async fn total_group_count(context: &Context<Handler>) -> FieldResult<i32> {
+        let span = debug_span!("[GraphQL query] total_group_count");
+        if !context.validation_result.is_admin_or_readonly() {
+            span.in_scope(|| debug!("Unauthorized"));
+            return Err(Error::permission_denied(
+                "Unauthorized access to group list",
+            ));
+        }
+        let count = context.handler.count_groups(None).instrument(span).await?;
+        Ok(to_graphql_count(count))
+    }
+
+    /// All the groups with their number of direct members, without fetching the members.
+    async fn groups_with_counts(
+        context: &Context<Handler>,
+    ) -> FieldResult<Vec<GroupWithCount<Handler>>> {
+        let span = debug_span!("[GraphQL query] groups_with_counts");
+        if !context.validation_result.is_admin_or_readonly() {
+            span.in_scope(|| debug!("Unauthorized"));
+            return Err(Error::permission_denied(
+                "Unauthorized access to group list",
+            ));
+        }
+        Ok(context
+            .handler
+            .list_groups_with_member_counts()
+            .instrument(span)
+            .await?
+            .into_iter()
+            .map(|(group, count)| GroupWithCount {
+                group: group.into(),
+                member_count: to_graphql_count(count),
+            })
+            .collect())
+    }
+
+    async fn sudo_roles(context: &Context<Handler>) -> FieldResult<Vec<SudoRole>> {
+        let span = debug_span!("[GraphQL query] sudo_roles");
+        if !context.validation_result.is_admin_or_readonly() {
+            span.in_scope(|| debug!("Unauthorized"));
+            return Err(Error::permission_denied(
+                "Unauthorized access to the sudo roles",
+            ));
+        }
+        Ok(context
+            .handler
+            .list_sudo_roles(None)
+            .instrument(span)
+            .await
+            .map(|v| v.into_iter().map(Into::into).collect())?)
+    }
+
+    /// The active sessions of the user, most recently seen first.
+    async fn sessions(context: &Context<Handler>, user_id: String) -> FieldResult<Vec<Session>> {
+        let span = debug_span!("[GraphQL query] sessions");
+        span.in_scope(|| {
+            debug!(?user_id);
+        });
+        let user_id = UserId::new(&user_id);
+        if !context.validation_result.can_write(&user_id) {
+            span.in_scope(|| debug!("Unauthorized"));
+            return Err(Error::permission_denied(
+                "Unauthorized access to the sessions",
+            ));
+        }
+        Ok(context
+            .handler
+            .list_sessions(&user_id)
+            .instrument(span)
+            .await
+            .map(|v| v.into_iter().map(Into::into).collect())?)
+    }
+
+    /// The progress of the last rebuild of the derived data, if any was started since the server
+    /// started.
+    async fn maintenance_status(
+        context: &Context<Handler>,
+    ) -> FieldResult<Option<MaintenanceStatus>> {
+        let span = debug_span!("[GraphQL query] maintenance_status");
+        if !context.validation_result.is_admin() {
+            span.in_scope(|| debug!("Unauthorized"));
+            return Err(Error::permission_denied(
+                "Unauthorized access to the maintenance status",
+            ));
+        }
+        Ok(context
+            .handler
+            .get_maintenance_status()
+            .instrument(span)
+            .await?
+            .map(Into::into))
+    }
+
+    async fn group_rules(context: &Context<Handler>) -> FieldResult<Vec<GroupRule>> {
+        let span = debug_span!("[GraphQL query] group_rules");
+        if !context.validation_result.is_admin_or_readonly() {
+            span.in_scope(|| debug!("Unauthorized"));
+            return Err(Error::permission_denied(
+                "Unauthorized access to the group rules",
+            ));
+        }
+        Ok(context
+            .handler
+            .list_group_rules()
+            .instrument(span)
+            .await
+            .map(|v| v.into_iter().map(Into::into).collect())?)
+    }
 }
 
 #[derive(PartialEq, Eq, Debug, Serialize, Deserialize)]
@@ -203,29 +499,63 @@ impl<Handler: BackendHandler> Default for User<Handler> {
     }
 }
 
+impl<Handler: BackendHandler> User<Handler> {
+    /// The attributes that the attribute ACL denies are empty.
+    fn can_read(&self, context: &Context<Handler>, attribute: AclAttribute) -> bool {
+        context
+            .get_attribute_access()
+            .can_read(&self.user.user_id, attribute)
+    }
+}
+
 #[graphql_object(context = Context<Handler>)]
 impl<Handler: BackendHandler + Sync> User<Handler> {
     fn id(&self) -> &str {
         self.user.user_id.as_str()
     }
 
-    fn email(&self) -> &str {
+    /// The user ID with the casing it was created with, e.g. "Alice" for the user "alice".
+    fn display_id(&self) -> &str {
+        self.user.display_user_id()
+    }
+
+    fn email(&self, context: &Context<Handler>) -> &str {
+        if !self.can_read(context, AclAttribute::Email) {
+            return "";
+        }
         &self.user.email
     }
 
-    fn display_name(&self) -> &str {
+    /// Reset when the email changes.
+    fn email_verified(&self) -> bool {
+        self.user.email_verified
+    }
+
+    fn display_name(&self, context: &Context<Handler>) -> &str {
+        if !self.can_read(context, AclAttribute::DisplayName) {
+            return "";
+        }
         self.user.display_name.as_deref().unwrap_or("")
     }
 
-    fn first_name(&self) -> &str {
+    fn first_name(&self, context: &Context<Handler>) -> &str {
+        if !self.can_read(context, AclAttribute::FirstName) {
+            return "";
+        }
         self.user.first_name.as_deref().unwrap_or("")
     }
 
-    fn last_name(&self) -> &str {
+    fn last_name(&self, context: &Context<Handler>) -> &str {
+        if !self.can_read(context, AclAttribute::LastName) {
+            return "";
+        }
         self.user.last_name.as_deref().unwrap_or("")
     }
 
-    fn avatar(&self) -> Option<String> {
+    fn avatar(&self, context: &Context<Handler>) -> Option<String> {
+        if !self.can_read(context, AclAttribute::Avatar) {
+            return None;
+        }
         self.user.avatar.as_ref().map(String::from)
     }
 
@@ -244,11 +574,289 @@ impl<Handler: BackendHandler + Sync> User<Handler> {
             debug!(user_id = ?self.user.user_id);
         });
         Ok(context
-            .handler
-            .get_user_groups(&self.user.user_id)
+            .membership_loader
+            .user_groups(context.handler.as_ref(), &self.user.user_id)
             .instrument(span)
             .await
-            .map(|set| set.into_iter().map(Into::into).collect())?)
+            .map(|groups| groups.into_iter().map(Into::into).collect())?)
+    }
+}
+
+#[derive(PartialEq, Eq, Debug)]
+/// A group and its number of direct members.
+pub struct GroupWithCount<Handler: BackendHandler> {
+    group: Group<Handler>,
+    member_count: i32,
+}
+
+#[graphql_object(context = Context<Handler>)]
+impl<Handler: BackendHandler + Sync> GroupWithCount<Handler> {
+    fn group(&self) -> &Group<Handler> {
+        &self.group
+    }
+
+    fn member_count(&self) -> i32 {
+        self.member_count
+    }
+}
+
+#[derive(PartialEq, Eq, Debug)]
+/// A page of users.
+pub struct UserPage<Handler: BackendHandler> {
+    users: Vec<User<Handler>>,
+    next_cursor: Option<String>,
+}
+
+#[graphql_object(context = Context<Handler>)]
+impl<Handler: BackendHandler + Sync> UserPage<Handler> {
+    fn users(&self) -> &[User<Handler>] {
+        &self.users
+    }
+
+    /// Null on the last page.
+    fn next_cursor(&self) -> Option<&str> {
+        self.next_cursor.as_deref()
+    }
+}
+
+#[derive(PartialEq, Eq, Debug, GraphQLObject)]
+/// A deleted user or group.
+pub struct Tombstone {
+    /// Exactly one of the two is set.
+    user_id: Option<String>,
+    group_id: Option<i32>,
+    uuid: String,
+    deleted_at: chrono::DateTime<chrono::Utc>,
+}
+
+impl From<DomainTombstone> for Tombstone {
+    fn from(tombstone: DomainTombstone) -> Self {
+        Self {
+            user_id: tombstone.user_id.map(UserId::into_string),
+            group_id: tombstone.group_id.map(|id| id.0),
+            uuid: tombstone.uuid.into_string(),
+            deleted_at: tombstone.deleted_at,
+        }
+    }
+}
+
+#[derive(PartialEq, Eq, Debug, GraphQLObject)]
+/// A sudoers rule, served over LDAP as a `sudoRole`.
+pub struct SudoRole {
+    name: String,
+    users: Vec<String>,
+    hosts: Vec<String>,
+    commands: Vec<String>,
+    options: Vec<String>,
+    creation_date: chrono::DateTime<chrono::Utc>,
+    modified_at: chrono::DateTime<chrono::Utc>,
+}
+
+impl From<DomainSudoRole> for SudoRole {
+    fn from(role: DomainSudoRole) -> Self {
+        Self {
+            name: role.name,
+            users: role.users,
+            hosts: role.hosts,
+            commands: role.commands,
+            options: role.options,
+            creation_date: role.creation_date,
+            modified_at: role.modified_at,
+        }
+    }
+}
+
+#[derive(PartialEq, Eq, Debug, GraphQLObject)]
+/// A login of a user, kept alive by refreshing its JWT.
+pub struct Session {
+    id: String,
+    user_id: String,
+    creation_date: chrono::DateTime<chrono::Utc>,
+    /// The last refresh of the JWT.
+    last_seen: chrono::DateTime<chrono::Utc>,
+    /// As of the last refresh.
+    ip_address: Option<String>,
+    user_agent: Option<String>,
+}
+
+impl From<DomainSession> for Session {
+    fn from(session: DomainSession) -> Self {
+        Self {
+            id: session.session_id,
+            user_id: session.user_id.into_string(),
+            creation_date: session.creation_date,
+            last_seen: session.last_seen,
+            ip_address: session.client.ip_address,
+            user_agent: session.client.user_agent,
+        }
+    }
+}
+
+#[derive(PartialEq, Eq, Debug, GraphQLObject)]
+/// What a rebuild of the derived data changed, or would change for a dry run.
+pub struct MaintenanceReport {
+    orphan_memberships_removed: i32,
+    orphan_group_nestings_removed: i32,
+    rule_memberships_added: i32,
+    rule_memberships_removed: i32,
+    posix_numbers_assigned: i32,
+    group_id_sequence_advanced: bool,
+}
+
+impl From<DomainMaintenanceReport> for MaintenanceReport {
+    fn from(report: DomainMaintenanceReport) -> Self {
+        Self {
+            orphan_memberships_removed: to_graphql_count(report.orphan_memberships_removed),
+            orphan_group_nestings_removed: to_graphql_count(report.orphan_group_nestings_removed),
+            rule_memberships_added: to_graphql_count(report.rule_memberships_added),
+            rule_memberships_removed: to_graphql_count(report.rule_memberships_removed),
+            posix_numbers_assigned: to_graphql_count(report.posix_numbers_assigned),
+            group_id_sequence_advanced: report.group_id_sequence_advanced,
+        }
+    }
+}
+
+#[derive(PartialEq, Eq, Debug, GraphQLObject)]
+/// A rebuild of the derived data, running or finished.
+pub struct MaintenanceStatus {
+    dry_run: bool,
+    started_at: chrono::DateTime<chrono::Utc>,
+    /// Null while running.
+    finished_at: Option<chrono::DateTime<chrono::Utc>>,
+    steps_done: i32,
+    steps_total: i32,
+    /// The changes of the steps done so far.
+    report: MaintenanceReport,
+    /// Why it stopped. The steps done before were applied, unless it is a dry run.
+    error: Option<String>,
+}
+
+impl From<DomainMaintenanceStatus> for MaintenanceStatus {
+    fn from(status: DomainMaintenanceStatus) -> Self {
+        Self {
+            dry_run: status.dry_run,
+            started_at: status.started_at,
+            finished_at: status.finished_at,
+            steps_done: to_graphql_count(status.steps_done),
+            steps_total: to_graphql_count(MaintenanceStep::ALL.len()),
+            report: status.report.into(),
+            error: status.error,
+        }
+    }
+}
+
+#[derive(PartialEq, Eq, Debug, Clone, Copy, GraphQLEnum)]
+/// How a condition compares the values of the attribute, case-insensitively.
+pub enum GroupRuleOperator {
+    Equals,
+    StartsWith,
+    EndsWith,
+}
+
+impl From<DomainGroupRuleOperator> for GroupRuleOperator {
+    fn from(operator: DomainGroupRuleOperator) -> Self {
+        match operator {
+            DomainGroupRuleOperator::Equals => GroupRuleOperator::Equals,
+            DomainGroupRuleOperator::StartsWith => GroupRuleOperator::StartsWith,
+            DomainGroupRuleOperator::EndsWith => GroupRuleOperator::EndsWith,
+        }
+    }
+}
+
+impl From<GroupRuleOperator> for DomainGroupRuleOperator {
+    fn from(operator: GroupRuleOperator) -> Self {
+        match operator {
+            GroupRuleOperator::Equals => DomainGroupRuleOperator::Equals,
+            GroupRuleOperator::StartsWith => DomainGroupRuleOperator::StartsWith,
+            GroupRuleOperator::EndsWith => DomainGroupRuleOperator::EndsWith,
+        }
+    }
+}
+
+#[derive(PartialEq, Eq, Debug, GraphQLObject)]
+/// A condition on a built-in user field or on a custom attribute of type string.
+pub struct GroupRuleCondition {
+    attribute: String,
+    operator: GroupRuleOperator,
+    value: String,
+}
+
+impl From<DomainGroupRuleCondition> for GroupRuleCondition {
+    fn from(condition: DomainGroupRuleCondition) -> Self {
+        Self {
+            attribute: condition.attribute,
+            operator: condition.operator.into(),
+            value: condition.value,
+        }
+    }
+}
+
+#[derive(PartialEq, Eq, Debug, GraphQLObject)]
+/// Keeps the users matching all the conditions in the group.
+pub struct GroupRule {
+    id: i32,
+    group_id: i32,
+    conditions: Vec<GroupRuleCondition>,
+    creation_date: chrono::DateTime<chrono::Utc>,
+}
+
+impl From<DomainGroupRule> for GroupRule {
+    fn from(rule: DomainGroupRule) -> Self {
+        Self {
+            id: rule.rule_id,
+            group_id: rule.group_id.0,
+            conditions: rule.conditions.into_iter().map(Into::into).collect(),
+            creation_date: rule.creation_date,
+        }
+    }
+}
+
+#[derive(PartialEq, Eq, Debug)]
+/// The changes since the last sync.
+pub struct ChangeSet<Handler: BackendHandler> {
+    users: Vec<User<Handler>>,
+    groups: Vec<Group<Handler>>,
+    deletions: Vec<Tombstone>,
+    next_cursor: String,
+    resync_required: bool,
+}
+
+#[graphql_object(context = Context<Handler>)]
+impl<Handler: BackendHandler + Sync> ChangeSet<Handler> {
+    /// The users created or modified, in their current state.
+    fn users(&self) -> &[User<Handler>] {
+        &self.users
+    }
+
+    /// The groups created or modified, in their current state, including the membership changes.
+    fn groups(&self) -> &[Group<Handler>] {
+        &self.groups
+    }
+
+    /// Apply them before the other changes: a user or group can be deleted then recreated.
+    fn deletions(&self) -> &[Tombstone] {
+        &self.deletions
+    }
+
+    fn next_cursor(&self) -> &str {
+        &self.next_cursor
+    }
+
+    /// The cursor is older than the retention of the deletions: start over without `since`.
+    fn resync_required(&self) -> bool {
+        self.resync_required
+    }
+}
+
+impl<Handler: BackendHandler> From<DomainChangeSet> for ChangeSet<Handler> {
+    fn from(changes: DomainChangeSet) -> Self {
+        Self {
+            users: changes.users.into_iter().map(Into::into).collect(),
+            groups: changes.groups.into_iter().map(Into::into).collect(),
+            deletions: changes.deletions.into_iter().map(Into::into).collect(),
+            next_cursor: encode_sync_cursor(changes.until),
+            resync_required: changes.resync_required,
+        }
     }
 }
 
@@ -303,14 +911,13 @@ impl<Handler: BackendHandler + Sync> Group<Handler> {
         });
         if !context.validation_result.is_admin_or_readonly() {
             span.in_scope(|| debug!("Unauthorized"));
-            return Err("Unauthorized access to group data".into());
+            return Err(Error::permission_denied(
+                "Unauthorized access to group data",
+            ));
         }
         Ok(context
-            .handler
-            .list_users(
-                Some(DomainRequestFilter::MemberOfId(GroupId(self.group_id))),
-                false,
-            )
+            .membership_loader
+            .group_members(context.handler.as_ref(), GroupId(self.group_id))
             .instrument(span)
             .await
             .map(|v| v.into_iter().map(Into::into).collect())?)
@@ -346,14 +953,20 @@ impl<Handler: BackendHandler> From<DomainGroup> for Group<Handler> {
 #[cfg(test)]
 mod tests {
     use super::*;
-    use crate::{domain::handler::MockTestBackendHandler, infra::auth_service::ValidationResults};
+    use crate::{
+        domain::{change_events::ChangeEventSender, handler::MockTestBackendHandler},
+        infra::{
+            access_control::AttributeAcl,
+            auth_service::{Permission, ValidationResults},
+        },
+    };
     use chrono::TimeZone;
     use juniper::{
         execute, graphql_value, DefaultScalarValue, EmptyMutation, EmptySubscription, GraphQLType,
         RootNode, Variables,
     };
     use mockall::predicate::eq;
-    use std::collections::HashSet;
+    use std::collections::{HashMap, HashSet};
 
     fn schema<'q, C, Q>(query_root: Q) -> RootNode<'q, Q, EmptyMutation<C>, EmptySubscription<C>>
     where
@@ -401,13 +1014,17 @@ mod tests {
             creation_date: chrono::Utc.timestamp_nanos(42),
             uuid: crate::uuid!("a1a2a3a4b1b2c1c2d1d2d3d4d5d6d7d8"),
         });
-        mock.expect_get_user_groups()
-            .with(eq(UserId::new("bob")))
-            .return_once(|_| Ok(groups));
+        mock.expect_get_users_groups()
+            .withf(|user_ids| user_ids == [UserId::new("bob")])
+            .return_once(|_| Ok(HashMap::from([(UserId::new("bob"), groups)])));
 
         let context = Context::<MockTestBackendHandler> {
             handler: Box::new(mock),
             validation_result: ValidationResults::admin(),
+            change_events: ChangeEventSender::default(),
+            attribute_acl: AttributeAcl::default(),
+            revoked_sessions: Default::default(),
+            membership_loader: Default::default(),
         };
 
         let schema = schema(Query::<MockTestBackendHandler>::new());
@@ -488,6 +1105,10 @@ mod tests {
         let context = Context::<MockTestBackendHandler> {
             handler: Box::new(mock),
             validation_result: ValidationResults::admin(),
+            change_events: ChangeEventSender::default(),
+            attribute_acl: AttributeAcl::default(),
+            revoked_sessions: Default::default(),
+            membership_loader: Default::default(),
         };
 
         let schema = schema(Query::<MockTestBackendHandler>::new());
@@ -511,4 +1132,317 @@ mod tests {
             ))
         );
     }
+
+    #[tokio::test]
+    async fn list_users_with_groups_in_one_query() {
+        const QUERY: &str = r#"{
+          users {
+            id
+            groups {
+              id
+            }
+          }
+        }"#;
+
+        let user_ids = (0..100)
+            .map(|i| UserId::new(&format!("user{}", i)))
+            .collect::<Vec<_>>();
+        let group = GroupDetails {
+            group_id: GroupId(3),
+            display_name: "Bobbersons".to_string(),
+            creation_date: chrono::Utc.timestamp_nanos(42),
+            uuid: crate::uuid!("a1a2a3a4b1b2c1c2d1d2d3d4d5d6d7d8"),
+        };
+        let mut mock = MockTestBackendHandler::new();
+        let users = user_ids
+            .iter()
+            .map(|user_id| DomainUserAndGroups {
+                user: DomainUser {
+                    user_id: user_id.clone(),
+                    ..Default::default()
+                },
+                groups: None,
+            })
+            .collect::<Vec<_>>();
+        mock.expect_list_users()
+            .times(1)
+            .return_once(|_, _| Ok(users));
+        // The even users are in the group. No per-user lookup is expected.
+        let user_groups = user_ids
+            .iter()
+            .step_by(2)
+            .map(|id| (id.clone(), HashSet::from([group.clone()])))
+            .collect::<HashMap<_, _>>();
+        mock.expect_get_users_groups()
+            .times(1)
+            .withf(move |ids| ids.iter().collect::<HashSet<_>>() == user_ids.iter().collect())
+            .return_once(|_| Ok(user_groups));
+
+        let context = Context::<MockTestBackendHandler> {
+            handler: Box::new(mock),
+            validation_result: ValidationResults::admin(),
+            change_events: ChangeEventSender::default(),
+            attribute_acl: AttributeAcl::default(),
+            revoked_sessions: Default::default(),
+            membership_loader: Default::default(),
+        };
+
+        let schema = schema(Query::<MockTestBackendHandler>::new());
+        let (result, errors) = execute(QUERY, None, &schema, &Variables::new(), &context)
+            .await
+            .unwrap();
+        assert!(errors.is_empty(), "{:?}", errors);
+        let users = result
+            .as_object_value()
+            .unwrap()
+            .get_field_value("users")
+            .unwrap()
+            .as_list_value()
+            .unwrap();
+        assert_eq!(users.len(), 100);
+        for (i, user) in users.iter().enumerate() {
+            let groups = user
+                .as_object_value()
+                .unwrap()
+                .get_field_value("groups")
+                .unwrap()
+                .as_list_value()
+                .unwrap();
+            assert_eq!(groups.len(), if i % 2 == 0 { 1 } else { 0 });
+        }
+    }
+
+    #[tokio::test]
+    async fn changes() {
+        const QUERY: &str = r#"{
+          changes(since: "MTk3MC0wMS0wMVQwMDowMDowMC4wNDIrMDA6MDA=") {
+            users {
+              id
+            }
+            groups {
+              id
+            }
+            deletions {
+              userId
+              deletedAt
+            }
+            nextCursor
+            resyncRequired
+          }
+        }"#;
+
+        let since = chrono::Utc.timestamp_millis_opt(42).unwrap();
+        let until = chrono::Utc.timestamp_millis_opt(1234).unwrap();
+        let mut mock = MockTestBackendHandler::new();
+        mock.expect_changed_since()
+            .with(eq(Some(since)))
+            .return_once(move |_| {
+                Ok(DomainChangeSet {
+                    users: vec![DomainUser {
+                        user_id: UserId::new("bob"),
+                        ..Default::default()
+                    }],
+                    groups: Vec::new(),
+                    deletions: vec![DomainTombstone {
+                        user_id: Some(UserId::new("john")),
+                        group_id: None,
+                        uuid: crate::uuid!("698e1d5f-7a40-3151-8745-b9b8a37839da"),
+                        deleted_at: until,
+                    }],
+                    until,
+                    resync_required: false,
+                })
+            });
+
+        let context = Context::<MockTestBackendHandler> {
+            handler: Box::new(mock),
+            validation_result: ValidationResults::admin(),
+            change_events: ChangeEventSender::default(),
+            attribute_acl: AttributeAcl::default(),
+            revoked_sessions: Default::default(),
+            membership_loader: Default::default(),
+        };
+
+        let schema = schema(Query::<MockTestBackendHandler>::new());
+        assert_eq!(
+            execute(QUERY, None, &schema, &Variables::new(), &context).await,
+            Ok((
+                graphql_value!(
+                {
+                    "changes": {
+                        "users": [{"id": "bob"}],
+                        "groups": [],
+                        "deletions": [{
+                            "userId": "john",
+                            "deletedAt": "1970-01-01T00:00:01.234+00:00",
+                        }],
+                        "nextCursor": "MTk3MC0wMS0wMVQwMDowMDowMS4yMzQrMDA6MDA=",
+                        "resyncRequired": false,
+                    }
+                }),
+                vec![]
+            ))
+        );
+    }
+
+    #[tokio::test]
+    async fn list_sessions_of_another_user() {
+        const QUERY: &str = r#"{
+          sessions(userId: "bob") {
+            id
+            ipAddress
+          }
+        }"#;
+
+        let mut mock = MockTestBackendHandler::new();
+        mock.expect_list_sessions()
+            .with(eq(UserId::new("bob")))
+            .return_once(|_| {
+                Ok(vec![DomainSession {
+                    session_id: "abc".to_string(),
+                    user_id: UserId::new("bob"),
+                    creation_date: chrono::Utc.timestamp_millis_opt(42).unwrap(),
+                    last_seen: chrono::Utc.timestamp_millis_opt(42).unwrap(),
+                    client: crate::domain::session_handler::SessionClient {
+                        ip_address: Some("10.0.0.1".to_string()),
+                        user_agent: None,
+                    },
+                }])
+            });
+        let context = Context::<MockTestBackendHandler> {
+            handler: Box::new(mock),
+            validation_result: ValidationResults::admin(),
+            change_events: ChangeEventSender::default(),
+            attribute_acl: AttributeAcl::default(),
+            revoked_sessions: Default::default(),
+            membership_loader: Default::default(),
+        };
+        let schema = schema(Query::<MockTestBackendHandler>::new());
+        assert_eq!(
+            execute(QUERY, None, &schema, &Variables::new(), &context).await,
+            Ok((
+                graphql_value!(
+                {
+                    "sessions": [{
+                        "id": "abc",
+                        "ipAddress": "10.0.0.1",
+                    }]
+                }),
+                vec![]
+            ))
+        );
+
+        // A regular user can only see their own sessions.
+        let context = Context::<MockTestBackendHandler> {
+            handler: Box::new(MockTestBackendHandler::new()),
+            validation_result: ValidationResults {
+                user: UserId::new("patrick"),
+                permission: Permission::Regular,
+                groups: HashSet::new(),
+            },
+            change_events: ChangeEventSender::default(),
+            attribute_acl: AttributeAcl::default(),
+            revoked_sessions: Default::default(),
+            membership_loader: Default::default(),
+        };
+        let (result, errors) = execute(QUERY, None, &schema, &Variables::new(), &context)
+            .await
+            .unwrap();
+        assert_eq!(result, graphql_value!(None));
+        assert_eq!(errors.len(), 1);
+        assert_eq!(
+            errors[0].error().extensions(),
+            &graphql_value!({ "code": "PERMISSION_DENIED" })
+        );
+    }
+
+    #[tokio::test]
+    async fn group_counts() {
+        const QUERY: &str = r#"{
+          groupMemberCount(groupId: 3)
+          totalUserCount
+          totalGroupCount
+          groupsWithCounts {
+            group {
+              id
+              displayName
+            }
+            memberCount
+          }
+        }"#;
+
+        let mut mock = MockTestBackendHandler::new();
+        mock.expect_count_users()
+            .with(eq(Some(DomainRequestFilter::MemberOfId(GroupId(3)))))
+            .return_once(|_| Ok(2));
+        mock.expect_count_users()
+            .with(eq(None))
+            .return_once(|_| Ok(5));
+        mock.expect_count_groups()
+            .with(eq(None))
+            .return_once(|_| Ok(2));
+        mock.expect_list_groups_with_member_counts()
+            .return_once(|| {
+                Ok(vec![
+                    (
+                        GroupDetails {
+                            group_id: GroupId(3),
+                            display_name: "Bobbersons".to_string(),
+                            creation_date: chrono::Utc.timestamp_nanos(42),
+                            uuid: crate::uuid!("a1a2a3a4b1b2c1c2d1d2d3d4d5d6d7d8"),
+                        },
+                        2,
+                    ),
+                    (
+                        GroupDetails {
+                            group_id: GroupId(4),
+                            display_name: "Empty".to_string(),
+                            creation_date: chrono::Utc.timestamp_nanos(42),
+                            uuid: crate::uuid!("b1a2a3a4b1b2c1c2d1d2d3d4d5d6d7d8"),
+                        },
+                        0,
+                    ),
+                ])
+            });
+
+        let context = Context::<MockTestBackendHandler> {
+            handler: Box::new(mock),
+            validation_result: ValidationResults::admin(),
+            change_events: ChangeEventSender::default(),
+            attribute_acl: AttributeAcl::default(),
+            revoked_sessions: Default::default(),
+            membership_loader: Default::default(),
+        };
+
+        let schema = schema(Query::<MockTestBackendHandler>::new());
+        assert_eq!(
+            execute(QUERY, None, &schema, &Variables::new(), &context).await,
+            Ok((
+                graphql_value!(
+                {
+                    "groupMemberCount": 2,
+                    "totalUserCount": 5,
+                    "totalGroupCount": 2,
+                    "groupsWithCounts": [
+                        {
+                            "group": {"id": 3, "displayName": "Bobbersons"},
+                            "memberCount": 2,
+                        },
+                        {
+                            "group": {"id": 4, "displayName": "Empty"},
+                            "memberCount": 0,
+                        },
+                    ]
+                }),
+                vec![]
+            ))
+        );
+    }
+
+    #[test]
+    fn sync_cursor_round_trip() {
+        let date = chrono::Utc.timestamp_millis_opt(1234).unwrap();
+        assert_eq!(decode_sync_cursor(&encode_sync_cursor(date)).unwrap(), date);
+        assert!(decode_sync_cursor("not a cursor").is_err());
+    }
 }
@@ -0,0 +1,238 @@
+use crate::{
+    domain::{
+        change_events::ChangeEvent as DomainChangeEvent,
+        handler::BackendHandler,
+        sql_webhook_queue::WebhookEvent,
+        types::{GroupId, UserId},
+    },
+    infra::auth_service::ValidationResults,
+};
+use futures::Stream;
+use juniper::{graphql_subscription, GraphQLEnum, GraphQLObject};
+use std::pin::Pin;
+use tracing::debug;
+
+use super::{
+    api::Context,
+    error::{Error, FieldResult},
+};
+
+#[derive(PartialEq, Eq, Debug, Clone, Copy, GraphQLEnum)]
+pub enum ChangeEventType {
+    UserCreated,
+    UserDeleted,
+    UserPasswordChanged,
+    GroupCreated,
+    MembershipAdded,
+    MembershipRemoved,
+}
+
+impl From<&WebhookEvent> for ChangeEventType {
+    fn from(event: &WebhookEvent) -> Self {
+        match event {
+            WebhookEvent::UserCreated { .. } => ChangeEventType::UserCreated,
+            WebhookEvent::UserDeleted { .. } => ChangeEventType::UserDeleted,
+            WebhookEvent::UserPasswordChanged { .. } => ChangeEventType::UserPasswordChanged,
+            WebhookEvent::GroupCreated { .. } => ChangeEventType::GroupCreated,
+            WebhookEvent::MembershipAdded { .. } => ChangeEventType::MembershipAdded,
+            WebhookEvent::MembershipRemoved { .. } => ChangeEventType::MembershipRemoved,
+        }
+    }
+}
+
+#[derive(PartialEq, Eq, Debug, GraphQLObject)]
+/// A committed change to the users or groups, the same as sent to the webhooks.
+pub struct ChangeEvent {
+    event_type: ChangeEventType,
+    /// Set for the changes of a user or of a membership.
+    user_id: Option<String>,
+    /// Set for the changes of a group or of a membership.
+    group_id: Option<i32>,
+    /// Not set for the changes made by the server itself.
+    actor: Option<String>,
+    timestamp: chrono::DateTime<chrono::Utc>,
+}
+
+impl From<DomainChangeEvent> for ChangeEvent {
+    fn from(change: DomainChangeEvent) -> Self {
+        Self {
+            event_type: (&change.event).into(),
+            user_id: change.event.user_id().map(ToString::to_string),
+            group_id: change.event.group_id().map(|group_id| group_id.0),
+            actor: change.actor.map(UserId::into_string),
+            timestamp: change.timestamp,
+        }
+    }
+}
+
+/// Which events a subscriber gets: the ones it asked for, among the ones it can see.
+#[derive(Debug)]
+struct ChangeEventFilter {
+    event_types: Option<Vec<ChangeEventType>>,
+    user_id: Option<UserId>,
+    group_id: Option<GroupId>,
+    validation_result: ValidationResults,
+}
+
+impl ChangeEventFilter {
+    /// Checked at the start of the subscription: only the admins and the readonly users can
+    /// follow the groups or the other users.
+    fn check_access(&self) -> FieldResult<()> {
+        if self.validation_result.is_admin_or_readonly() {
+            return Ok(());
+        }
+        match &self.user_id {
+            Some(user_id) if self.validation_result.can_read(user_id) => Ok(()),
+            _ => Err(Error::permission_denied(
+                "Unauthorized access to the changes",
+            )),
+        }
+    }
+
+    /// The users only see the events about themselves, not the creation of the groups.
+    fn is_visible(&self, event: &WebhookEvent) -> bool {
+        self.validation_result.is_admin_or_readonly()
+            || event
+                .user_id()
+                .map(|user_id| self.validation_result.can_read(user_id))
+                .unwrap_or(false)
+    }
+
+    fn matches(&self, event: &WebhookEvent) -> bool {
+        self.event_types
+            .as_ref()
+            .map(|event_types| event_types.contains(&event.into()))
+            .unwrap_or(true)
+            && self
+                .user_id
+                .as_ref()
+                .map(|user_id| event.user_id() == Some(user_id))
+                .unwrap_or(true)
+            && self
+                .group_id
+                .map(|group_id| event.group_id() == Some(group_id))
+                .unwrap_or(true)
+            && self.is_visible(event)
+    }
+}
+
+type ChangeEventStream = Pin<Box<dyn Stream<Item = ChangeEvent> + Send>>;
+
+#[derive(PartialEq, Eq, Debug)]
+/// The top-level GraphQL subscription type.
+pub struct Subscription<Handler: BackendHandler> {
+    _phantom: std::marker::PhantomData<Box<Handler>>,
+}
+
+impl<Handler: BackendHandler> Subscription<Handler> {
+    pub fn new() -> Self {
+        Self {
+            _phantom: std::marker::PhantomData,
+        }
+    }
+}
+
+#[graphql_subscription(context = Context<Handler>)]
+impl<Handler: BackendHandler + Sync> Subscription<Handler> {
+    /// The changes to the users and groups, as they are committed. All of them by default, or
+    /// only the ones of these types, of this user or of this group. A subscriber that falls
+    /// behind skips the oldest changes.
+    async fn changes(
+        context: &Context<Handler>,
+        event_types: Option<Vec<ChangeEventType>>,
+        user_id: Option<String>,
+        group_id: Option<i32>,
+    ) -> FieldResult<ChangeEventStream> {
+        debug!(
+            ?event_types,
+            ?user_id,
+            ?group_id,
+            "[GraphQL subscription] changes"
+        );
+        let filter = ChangeEventFilter {
+            event_types,
+            user_id: user_id.as_deref().map(UserId::new),
+            group_id: group_id.map(GroupId),
+            validation_result: context.validation_result.clone(),
+        };
+        if let Err(e) = filter.check_access() {
+            debug!("Unauthorized");
+            return Err(e);
+        }
+        Ok(context
+            .change_events
+            .subscribe()
+            .filter(move |change| futures::future::ready(filter.matches(&change.event)))
+            .map(ChangeEvent::from)
+            .boxed())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::infra::auth_service::Permission;
+    use std::collections::HashSet;
+
+    fn make_filter(validation_result: ValidationResults) -> ChangeEventFilter {
+        ChangeEventFilter {
+            event_types: None,
+            user_id: None,
+            group_id: None,
+            validation_result,
+        }
+    }
+
+    fn membership_added(user_id: &str, group_id: i32) -> WebhookEvent {
+        WebhookEvent::MembershipAdded {
+            user_id: UserId::new(user_id),
+            group_id: GroupId(group_id),
+        }
+    }
+
+    #[test]
+    fn test_filter_by_type_user_and_group() {
+        let filter = ChangeEventFilter {
+            event_types: Some(vec![ChangeEventType::MembershipAdded]),
+            group_id: Some(GroupId(3)),
+            ..make_filter(ValidationResults::admin())
+        };
+        assert!(filter.check_access().is_ok());
+        assert!(filter.matches(&membership_added("bob", 3)));
+        assert!(!filter.matches(&membership_added("bob", 4)));
+        assert!(!filter.matches(&WebhookEvent::GroupCreated {
+            group_id: GroupId(3)
+        }));
+        let filter = ChangeEventFilter {
+            user_id: Some(UserId::new("bob")),
+            ..make_filter(ValidationResults::admin())
+        };
+        assert!(filter.matches(&membership_added("bob", 4)));
+        assert!(!filter.matches(&membership_added("john", 4)));
+    }
+
+    #[test]
+    fn test_regular_users_only_see_their_own_changes() {
+        let bob = ValidationResults {
+            user: UserId::new("bob"),
+            permission: Permission::Regular,
+            groups: HashSet::new(),
+        };
+        assert!(make_filter(bob.clone()).check_access().is_err());
+        let filter = ChangeEventFilter {
+            user_id: Some(UserId::new("john")),
+            ..make_filter(bob.clone())
+        };
+        assert!(filter.check_access().is_err());
+        let filter = ChangeEventFilter {
+            user_id: Some(UserId::new("bob")),
+            ..make_filter(bob)
+        };
+        assert!(filter.check_access().is_ok());
+        assert!(filter.matches(&membership_added("bob", 3)));
+        assert!(!filter.is_visible(&membership_added("john", 3)));
+        assert!(!filter.is_visible(&WebhookEvent::GroupCreated {
+            group_id: GroupId(3)
+        }));
+    }
+}
@@ -1,4 +1,4 @@
-use crate::infra::configuration::LdapsOptions;
+use crate::infra::configuration::{ListenerOptions, ListenerProtocol};
 use anyhow::{anyhow, bail, ensure, Context, Result};
 use futures_util::SinkExt;
 use ldap3_proto::{
@@ -69,8 +69,8 @@ where
 }
 
 #[instrument(skip_all, level = "info", err)]
-pub async fn check_ldap(port: u16) -> Result<()> {
-    check_ldap_endpoint(TcpStream::connect(format!("localhost:{}", port)).await?).await
+pub async fn check_ldap(host: &str, port: u16) -> Result<()> {
+    check_ldap_endpoint(TcpStream::connect((host, port)).await?).await
 }
 
 fn get_root_certificates() -> rustls::RootCertStore {
@@ -85,7 +85,7 @@ fn get_root_certificates() -> rustls::RootCertStore {
     root_store
 }
 
-fn get_tls_connector() -> Result<RustlsTlsConnector> {
+pub(crate) fn get_tls_connector() -> Result<RustlsTlsConnector> {
     use rustls::ClientConfig;
     let client_config = std::sync::Arc::new(
         ClientConfig::builder()
@@ -97,28 +97,54 @@ fn get_tls_connector() -> Result<RustlsTlsConnector> {
 }
 
 #[instrument(skip_all, level = "info", err)]
-pub async fn check_ldaps(ldaps_options: &LdapsOptions) -> Result<()> {
-    if !ldaps_options.enabled {
-        return Ok(());
-    };
+pub async fn check_ldaps(host: &str, port: u16) -> Result<()> {
     let tls_connector = get_tls_connector()?;
-    let url = format!("localhost:{}", ldaps_options.port);
     check_ldap_endpoint(
         tls_connector
             .connect(
-                rustls::ServerName::try_from(url.as_str())?,
-                TcpStream::connect(&url).await?,
+                rustls::ServerName::try_from(host)?,
+                TcpStream::connect((host, port)).await?,
             )
             .await?,
     )
     .await
 }
 
-#[instrument(skip_all, level = "info", err)]
-pub async fn check_api(port: u16) -> Result<()> {
-    reqwest::get(format!("http://localhost:{}/health", port))
+async fn check_http_path(host: &str, port: u16, path: &str) -> Result<()> {
+    let host = if host.contains(':') {
+        format!("[{}]", host)
+    } else {
+        host.to_owned()
+    };
+    reqwest::get(format!("http://{}:{}{}", host, port, path))
         .await?
         .error_for_status()?;
     info!("Success");
     Ok(())
 }
+
+#[instrument(skip_all, level = "info", err)]
+pub async fn check_api(host: &str, port: u16) -> Result<()> {
+    check_http_path(host, port, "/health").await
+}
+
+#[instrument(skip_all, level = "info", err)]
+pub async fn check_metrics(host: &str, port: u16) -> Result<()> {
+    check_http_path(host, port, "/metrics").await
+}
+
+/// Connects to the listener on this machine: through localhost if it listens on all the
+/// addresses.
+pub async fn check_listener(listener: &ListenerOptions) -> Result<()> {
+    let host = match listener.host.parse::<std::net::IpAddr>() {
+        Ok(ip) if ip.is_unspecified() => "localhost",
+        _ => listener.host.as_str(),
+    };
+    match listener.protocol {
+        ListenerProtocol::Ldap => check_ldap(host, listener.port).await,
+        ListenerProtocol::Ldaps => check_ldaps(host, listener.port).await,
+        ListenerProtocol::Http => check_api(host, listener.port).await,
+        ListenerProtocol::Metrics => check_metrics(host, listener.port).await,
+    }
+    .with_context(|| format!("{} on {}", listener.protocol.as_str(), listener.address()))
+}
@@ -0,0 +1,266 @@
+use actix_web::HttpRequest;
+use anyhow::{Context, Result};
+use figment::{
+    providers::{Format, Toml},
+    Figment,
+};
+use std::{collections::HashMap, path::Path};
+use tracing::warn;
+
+/// The language of the built-in messages, and the fallback for the missing translations.
+pub const DEFAULT_LOCALE: &str = "en";
+
+/// The messages shown to the users, by a stable ID that doesn't depend on the language.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum MessageId {
+    PasswordTooShort,
+    PasswordTooLong,
+    PasswordMissingLowercase,
+    PasswordMissingUppercase,
+    PasswordMissingDigit,
+    PasswordMissingSymbol,
+    PasswordBanned,
+    PasswordBreached,
+    InvalidCredentials,
+    AccountLocked,
+    PasswordExpired,
+    TooManyLoginAttempts,
+}
+
+impl MessageId {
+    pub const ALL: [MessageId; 12] = [
+        MessageId::PasswordTooShort,
+        MessageId::PasswordTooLong,
+        MessageId::PasswordMissingLowercase,
+        MessageId::PasswordMissingUppercase,
+        MessageId::PasswordMissingDigit,
+        MessageId::PasswordMissingSymbol,
+        MessageId::PasswordBanned,
+        MessageId::PasswordBreached,
+        MessageId::InvalidCredentials,
+        MessageId::AccountLocked,
+        MessageId::PasswordExpired,
+        MessageId::TooManyLoginAttempts,
+    ];
+
+    /// The key of the message in the translation files.
+    pub fn as_str(self) -> &'static str {
+        match self {
+            MessageId::PasswordTooShort => "password_too_short",
+            MessageId::PasswordTooLong => "password_too_long",
+            MessageId::PasswordMissingLowercase => "password_missing_lowercase",
+            MessageId::PasswordMissingUppercase => "password_missing_uppercase",
+            MessageId::PasswordMissingDigit => "password_missing_digit",
+            MessageId::PasswordMissingSymbol => "password_missing_symbol",
+            MessageId::PasswordBanned => "password_banned",
+            MessageId::PasswordBreached => "password_breached",
+            MessageId::InvalidCredentials => "invalid_credentials",
+            MessageId::AccountLocked => "account_locked",
+            MessageId::PasswordExpired => "password_expired",
+            MessageId::TooManyLoginAttempts => "too_many_login_attempts",
+        }
+    }
+
+    /// The built-in message, with `{{variable}}` placeholders.
+    pub fn english(self) -> &'static str {
+        match self {
+            MessageId::PasswordTooShort => {
+                "The password must be at least {{min_length}} characters long"
+            }
+            MessageId::PasswordTooLong => {
+                "The password must be at most {{max_length}} characters long"
+            }
+            MessageId::PasswordMissingLowercase => "The password must contain a lowercase letter",
+            MessageId::PasswordMissingUppercase => "The password must contain an uppercase letter",
+            MessageId::PasswordMissingDigit => "The password must contain a digit",
+            MessageId::PasswordMissingSymbol => "The password must contain a symbol",
+            MessageId::PasswordBanned => "The password is too common",
+            MessageId::PasswordBreached => "The password appeared {{count}} times in data breaches",
+            MessageId::InvalidCredentials => "Invalid username or password",
+            MessageId::AccountLocked => {
+                "Too many failed attempts, the account is temporarily locked"
+            }
+            MessageId::PasswordExpired => "Your password has expired, change it before signing in",
+            MessageId::TooManyLoginAttempts => "Too many login attempts, retry later",
+        }
+    }
+
+    fn from_key(key: &str) -> Option<Self> {
+        Self::ALL.into_iter().find(|id| id.as_str() == key)
+    }
+}
+
+/// Replaces the `{{variable}}` placeholders.
+pub fn substitute(text: &str, variables: &[(&str, &str)]) -> String {
+    variables
+        .iter()
+        .fold(text.to_owned(), |text, (name, value)| {
+            text.replace(&format!("{{{{{}}}}}", name), value)
+        })
+}
+
+/// Lowercase, with `-` separators: `pt_BR` becomes `pt-br`.
+pub fn normalize_locale(locale: &str) -> String {
+    locale.trim().replace('_', "-").to_lowercase()
+}
+
+/// The locales of an `Accept-Language` header, by decreasing preference.
+pub fn parse_accept_language(header: &str) -> Vec<String> {
+    let mut locales = header
+        .split(',')
+        .filter_map(|item| {
+            let mut parts = item.split(';');
+            let locale = normalize_locale(parts.next()?);
+            let quality = parts
+                .find_map(|param| param.trim().strip_prefix("q="))
+                .map_or(Some(1.0), |q| q.trim().parse::<f32>().ok())?;
+            (!locale.is_empty() && locale != "*" && quality > 0.0).then_some((locale, quality))
+        })
+        .collect::<Vec<_>>();
+    // Stable, to keep the order of the header for the same quality.
+    locales.sort_by(|(_, a), (_, b)| b.total_cmp(a));
+    locales.into_iter().map(|(locale, _)| locale).collect()
+}
+
+/// The preferred locales of the client, from the `Accept-Language` header.
+pub fn request_locales(request: &HttpRequest) -> Vec<String> {
+    request
+        .headers()
+        .get(actix_http::header::ACCEPT_LANGUAGE)
+        .and_then(|header| header.to_str().ok())
+        .map(parse_accept_language)
+        .unwrap_or_default()
+}
+
+/// The locales to try in order: each preferred locale, then its language without the region.
+pub fn candidate_locales(locales: &[String]) -> impl Iterator<Item = &str> {
+    locales.iter().flat_map(|locale| {
+        let language = locale.split_once('-').map(|(language, _)| language);
+        std::iter::once(locale.as_str()).chain(language)
+    })
+}
+
+/// The translations of the messages, from the `<locale>.toml` files of the translations
+/// directory. English is built in, and is the fallback for the missing messages. A file can
+/// also override the English messages, as `en.toml`.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct MessageCatalog {
+    translations: HashMap<String, HashMap<String, String>>,
+}
+
+impl MessageCatalog {
+    pub fn load(translations_dir: Option<&Path>) -> Result<Self> {
+        let mut catalog = Self::default();
+        let dir = match translations_dir {
+            Some(dir) => dir,
+            None => return Ok(catalog),
+        };
+        let entries = std::fs::read_dir(dir)
+            .with_context(|| format!("Could not read the translations directory {:?}", dir))?;
+        for entry in entries {
+            let path = entry?.path();
+            if path.extension().and_then(|e| e.to_str()) != Some("toml") {
+                continue;
+            }
+            let locale = match path.file_stem().and_then(|s| s.to_str()) {
+                Some(locale) => normalize_locale(locale),
+                None => continue,
+            };
+            let messages = Figment::from(Toml::file(&path))
+                .extract::<HashMap<String, String>>()
+                .with_context(|| format!("while loading the translations {:?}", path))?;
+            for key in messages.keys() {
+                if MessageId::from_key(key).is_none() {
+                    warn!("Unknown message `{}` in the translations {:?}", key, path);
+                }
+            }
+            catalog.translations.insert(locale, messages);
+        }
+        Ok(catalog)
+    }
+
+    /// The message in the first of the locales that has it, or in English.
+    pub fn message(&self, locales: &[String], id: MessageId, variables: &[(&str, &str)]) -> String {
+        let default_locale = [DEFAULT_LOCALE.to_owned()];
+        let text = candidate_locales(locales)
+            .chain(candidate_locales(&default_locale))
+            .find_map(|locale| self.translations.get(locale)?.get(id.as_str()))
+            .map_or(id.english(), String::as_str);
+        substitute(text, variables)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_accept_language() {
+        assert_eq!(
+            parse_accept_language("fr-CH, fr;q=0.9, en;q=0.8, de;q=0.7, *;q=0.5"),
+            vec!["fr-ch", "fr", "en", "de"]
+        );
+        assert_eq!(
+            parse_accept_language("en;q=0.5, pt_BR, es;q=0"),
+            vec!["pt-br", "en"]
+        );
+        assert_eq!(parse_accept_language(""), Vec::<String>::new());
+        assert_eq!(
+            candidate_locales(&["fr-ch".to_owned(), "de".to_owned()]).collect::<Vec<_>>(),
+            vec!["fr-ch", "fr", "de"]
+        );
+    }
+
+    #[test]
+    fn test_message_catalog() {
+        let dir = std::env::temp_dir().join(format!("lldap-translations-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(
+            dir.join("fr.toml"),
+            "password_too_short = \"Le mot de passe doit faire au moins {{min_length}} caractères\"\n",
+        )
+        .unwrap();
+        std::fs::write(
+            dir.join("en.toml"),
+            "account_locked = \"Locked, contact the helpdesk\"\n",
+        )
+        .unwrap();
+        let catalog = MessageCatalog::load(Some(&dir)).unwrap();
+        std::fs::remove_dir_all(&dir).unwrap();
+        let message = |locales: &[&str], id| {
+            catalog.message(
+                &locales.iter().map(|l| l.to_string()).collect::<Vec<_>>(),
+                id,
+                &[("min_length", "8")],
+            )
+        };
+        assert_eq!(
+            message(&["fr-ca"], MessageId::PasswordTooShort),
+            "Le mot de passe doit faire au moins 8 caractères"
+        );
+        assert_eq!(
+            message(&["de", "fr"], MessageId::PasswordTooShort),
+            "Le mot de passe doit faire au moins 8 caractères"
+        );
+        // The missing translations fall back to English, overridden or built in.
+        assert_eq!(
+            message(&["fr"], MessageId::AccountLocked),
+            "Locked, contact the helpdesk"
+        );
+        assert_eq!(
+            message(&["fr"], MessageId::PasswordBanned),
+            "The password is too common"
+        );
+        assert_eq!(
+            message(&[], MessageId::PasswordTooShort),
+            "The password must be at least 8 characters long"
+        );
+    }
+
+    #[test]
+    fn test_message_keys() {
+        for id in MessageId::ALL {
+            assert_eq!(MessageId::from_key(id.as_str()), Some(id));
+        }
+    }
+}
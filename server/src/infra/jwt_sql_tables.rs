@@ -3,15 +3,6 @@ use sea_query::{ColumnDef, ForeignKey, ForeignKeyAction, Iden, Table};
 
 pub use crate::domain::{sql_migrations::Users, sql_tables::DbConnection};
 
-/// Contains the refresh tokens for a given user.
-#[derive(Iden)]
-pub enum JwtRefreshStorage {
-    Table,
-    RefreshTokenHash,
-    UserId,
-    ExpiryDate,
-}
-
 /// Contains the blacklisted JWT that haven't expired yet.
 #[derive(Iden)]
 pub enum JwtStorage {
@@ -26,6 +17,7 @@ pub enum JwtStorage {
 #[derive(Iden)]
 pub enum PasswordResetTokens {
     Table,
+    /// The SHA-256 hash of the token.
     Token,
     UserId,
     ExpiryDate,
@@ -35,39 +27,6 @@ pub enum PasswordResetTokens {
 pub async fn init_table(pool: &DbConnection) -> std::result::Result<(), sea_orm::DbErr> {
     let builder = pool.get_database_backend();
 
-    pool.execute(
-        builder.build(
-            Table::create()
-                .table(JwtRefreshStorage::Table)
-                .if_not_exists()
-                .col(
-                    ColumnDef::new(JwtRefreshStorage::RefreshTokenHash)
-                        .big_integer()
-                        .not_null()
-                        .primary_key(),
-                )
-                .col(
-                    ColumnDef::new(JwtRefreshStorage::UserId)
-                        .string_len(255)
-                        .not_null(),
-                )
-                .col(
-                    ColumnDef::new(JwtRefreshStorage::ExpiryDate)
-                        .date_time()
-                        .not_null(),
-                )
-                .foreign_key(
-                    ForeignKey::create()
-                        .name("JwtRefreshStorageUserForeignKey")
-                        .from(JwtRefreshStorage::Table, JwtRefreshStorage::UserId)
-                        .to(Users::Table, Users::UserId)
-                        .on_delete(ForeignKeyAction::Cascade)
-                        .on_update(ForeignKeyAction::Cascade),
-                ),
-        ),
-    )
-    .await?;
-
     pool.execute(
         builder.build(
             Table::create()
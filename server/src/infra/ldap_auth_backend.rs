@@ -0,0 +1,262 @@
+use crate::{
+    domain::{
+        auth_backend::{AuthBackend, AuthBackendRouter, AuthOutcome},
+        ldap::utils::escape_dn_value,
+        types::UserId,
+    },
+    infra::{configuration::AuthBackendOptions, healthcheck::get_tls_connector},
+};
+use anyhow::{anyhow, bail, ensure, Context, Result};
+use async_trait::async_trait;
+use futures_util::SinkExt;
+use ldap3_proto::{
+    proto::{LdapBindCred, LdapBindRequest, LdapMsg, LdapOp, LdapResultCode},
+    LdapCodec,
+};
+use std::{sync::Arc, time::Duration};
+use tokio::net::TcpStream;
+use tokio_util::codec::{FramedRead, FramedWrite};
+
+const DEFAULT_TIMEOUT_SECONDS: u64 = 10;
+
+/// Checks the passwords with a simple bind to an upstream LDAP server, as the user.
+#[derive(Debug)]
+pub struct LdapAuthBackend {
+    host: String,
+    port: u16,
+    tls: bool,
+    bind_dn_template: String,
+    timeout: Duration,
+}
+
+impl LdapAuthBackend {
+    pub fn new(options: &AuthBackendOptions) -> Result<Self> {
+        let (tls, address) = if let Some(address) = options.ldap_url.strip_prefix("ldaps://") {
+            (true, address)
+        } else if let Some(address) = options.ldap_url.strip_prefix("ldap://") {
+            (false, address)
+        } else {
+            bail!(
+                "`ldap_url = \"{}\"` must start with ldap:// or ldaps://",
+                options.ldap_url
+            );
+        };
+        let address = address.trim_end_matches('/');
+        let (host, port) = match address.rsplit_once(':') {
+            Some((host, port)) => (
+                host,
+                port.parse()
+                    .with_context(|| format!("Invalid port in `{}`", options.ldap_url))?,
+            ),
+            None => (address, if tls { 636 } else { 389 }),
+        };
+        ensure!(!host.is_empty(), "No host in `{}`", options.ldap_url);
+        ensure!(
+            options.bind_dn_template.contains("{user_id}"),
+            "`bind_dn_template = \"{}\"` doesn't contain {{user_id}}",
+            options.bind_dn_template
+        );
+        Ok(Self {
+            host: host.to_owned(),
+            port,
+            tls,
+            bind_dn_template: options.bind_dn_template.clone(),
+            timeout: Duration::from_secs(
+                options.timeout_seconds.unwrap_or(DEFAULT_TIMEOUT_SECONDS),
+            ),
+        })
+    }
+
+    fn bind_dn(&self, user_id: &UserId) -> String {
+        self.bind_dn_template
+            .replace("{user_id}", &escape_dn_value(user_id.as_str()))
+    }
+
+    async fn connect_and_bind(&self, dn: String, password: String) -> Result<LdapResultCode> {
+        let address = format!("{}:{}", self.host, self.port);
+        let stream = TcpStream::connect(&address)
+            .await
+            .with_context(|| format!("Could not connect to {}", address))?;
+        if self.tls {
+            let stream = get_tls_connector()?
+                .connect(rustls::ServerName::try_from(self.host.as_str())?, stream)
+                .await?;
+            simple_bind(stream, dn, password).await
+        } else {
+            simple_bind(stream, dn, password).await
+        }
+    }
+}
+
+async fn simple_bind<Stream>(stream: Stream, dn: String, password: String) -> Result<LdapResultCode>
+where
+    Stream: tokio::io::AsyncRead + tokio::io::AsyncWrite,
+{
+    use tokio_stream::StreamExt;
+    let (r, w) = tokio::io::split(stream);
+    let mut responses = FramedRead::new(r, LdapCodec);
+    let mut requests = FramedWrite::new(w, LdapCodec);
+    requests
+        .send(LdapMsg {
+            msgid: 1,
+            op: LdapOp::BindRequest(LdapBindRequest {
+                dn,
+                cred: LdapBindCred::Simple(password),
+            }),
+            ctrl: vec![],
+        })
+        .await?;
+    requests.flush().await?;
+    let msg = responses
+        .next()
+        .await
+        .ok_or_else(|| anyhow!("No answer from LDAP server"))?
+        .context("Invalid answer from LDAP server")?;
+    let code = match msg.op {
+        LdapOp::BindResponse(response) => response.res.code,
+        op => bail!("Unexpected answer from LDAP server: {:?}", op),
+    };
+    // The connection is closed right after anyway.
+    let _ = requests
+        .send(LdapMsg {
+            msgid: 2,
+            op: LdapOp::UnbindRequest,
+            ctrl: vec![],
+        })
+        .await;
+    Ok(code)
+}
+
+#[async_trait]
+impl AuthBackend for LdapAuthBackend {
+    async fn check_password(&self, user_id: &UserId, password: &str) -> Result<AuthOutcome> {
+        // A bind without a password is an unauthenticated bind, that many servers accept.
+        if password.is_empty() {
+            return Ok(AuthOutcome::Rejected);
+        }
+        let code = tokio::time::timeout(
+            self.timeout,
+            self.connect_and_bind(self.bind_dn(user_id), password.to_owned()),
+        )
+        .await
+        .map_err(|_| anyhow!("Timeout after {:?}", self.timeout))??;
+        Ok(match code {
+            LdapResultCode::Success => AuthOutcome::Accepted,
+            LdapResultCode::InvalidCredentials => AuthOutcome::Rejected,
+            LdapResultCode::NoSuchObject => AuthOutcome::NotHandled,
+            code => bail!("Unexpected result of the bind: {:?}", code),
+        })
+    }
+}
+
+pub fn build_auth_backends(options: &[AuthBackendOptions]) -> Result<AuthBackendRouter> {
+    options
+        .iter()
+        .try_fold(AuthBackendRouter::default(), |router, options| {
+            let backend = LdapAuthBackend::new(options)
+                .with_context(|| format!("Invalid auth backend `{}`", options.name))?;
+            Ok(router.with_backend(
+                &options.name,
+                Arc::new(backend),
+                options.users.iter().cloned(),
+                options.groups.iter().cloned(),
+            ))
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ldap3_proto::proto::{LdapBindResponse, LdapResult};
+    use tokio_stream::StreamExt;
+
+    fn make_options(ldap_url: &str) -> AuthBackendOptions {
+        AuthBackendOptions {
+            name: "corp".to_owned(),
+            ldap_url: ldap_url.to_owned(),
+            bind_dn_template: "uid={user_id},ou=people,dc=corp,dc=com".to_owned(),
+            users: vec![UserId::new("bob")],
+            groups: Vec::new(),
+            timeout_seconds: None,
+        }
+    }
+
+    #[test]
+    fn test_parse_options() {
+        let backend = LdapAuthBackend::new(&make_options("ldaps://ldap.corp.com")).unwrap();
+        assert_eq!(
+            (backend.host.as_str(), backend.port, backend.tls),
+            ("ldap.corp.com", 636, true)
+        );
+        let backend = LdapAuthBackend::new(&make_options("ldap://10.0.0.1:3890/")).unwrap();
+        assert_eq!(
+            (backend.host.as_str(), backend.port, backend.tls),
+            ("10.0.0.1", 3890, false)
+        );
+        assert_eq!(
+            backend.bind_dn(&UserId::new("bob,admin")),
+            "uid=bob\\,admin,ou=people,dc=corp,dc=com"
+        );
+        LdapAuthBackend::new(&make_options("ldap://")).unwrap_err();
+        LdapAuthBackend::new(&make_options("ldap://host:port")).unwrap_err();
+        LdapAuthBackend::new(&make_options("https://host")).unwrap_err();
+    }
+
+    #[tokio::test]
+    async fn test_simple_bind() {
+        let (client, server) = tokio::io::duplex(4096);
+        let server = tokio::spawn(async move {
+            let (r, w) = tokio::io::split(server);
+            let mut requests = FramedRead::new(r, LdapCodec);
+            let mut responses = FramedWrite::new(w, LdapCodec);
+            let msg = requests.next().await.unwrap().unwrap();
+            let code = match msg.op {
+                LdapOp::BindRequest(LdapBindRequest {
+                    dn,
+                    cred: LdapBindCred::Simple(password),
+                }) if dn == "uid=bob" && password == "pass" => LdapResultCode::Success,
+                _ => LdapResultCode::InvalidCredentials,
+            };
+            responses
+                .send(LdapMsg {
+                    msgid: msg.msgid,
+                    op: LdapOp::BindResponse(LdapBindResponse {
+                        res: LdapResult {
+                            code,
+                            matcheddn: "".to_owned(),
+                            message: "".to_owned(),
+                            referral: vec![],
+                        },
+                        saslcreds: None,
+                    }),
+                    ctrl: vec![],
+                })
+                .await
+                .unwrap();
+            responses.flush().await.unwrap();
+            assert!(matches!(
+                requests.next().await.unwrap().unwrap().op,
+                LdapOp::UnbindRequest
+            ));
+        });
+        assert_eq!(
+            simple_bind(client, "uid=bob".to_owned(), "pass".to_owned())
+                .await
+                .unwrap(),
+            LdapResultCode::Success
+        );
+        server.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_empty_password() {
+        let backend = LdapAuthBackend::new(&make_options("ldap://127.0.0.1:1")).unwrap();
+        assert_eq!(
+            backend
+                .check_password(&UserId::new("bob"), "")
+                .await
+                .unwrap(),
+            AuthOutcome::Rejected
+        );
+    }
+}
@@ -0,0 +1,819 @@
+//! The controls of the LDAP requests, as sent by the client. `ldap3_proto` only decodes the
+//! controls that it knows, and drops the others: their OID and criticality are read here from the
+//! raw message, so that the critical ones can be rejected instead of silently ignored. The SASL
+//! binds and the approximate, ordering and extensible match filters, that `ldap3_proto` can't decode, are
+//! handled here too.
+use crate::domain::ldap::sort::SortKey;
+use bytes::BytesMut;
+use ldap3_proto::{
+    proto::{LdapBindCred, LdapBindRequest, LdapControl, LdapMsg, LdapOp},
+    LdapCodec,
+};
+use tokio_util::codec::{Decoder, Encoder};
+
+pub const PAGED_RESULTS_OID: &str = "1.2.840.113556.1.4.319";
+pub const SUBTREE_DELETE_OID: &str = "1.2.840.113556.1.4.805";
+pub const MANAGE_DSA_IT_OID: &str = "2.16.840.1.113730.3.4.2";
+pub const SORT_REQUEST_OID: &str = "1.2.840.113556.1.4.473";
+pub const SORT_RESPONSE_OID: &str = "1.2.840.113556.1.4.474";
+
+/// `SaslCredentials ::= SEQUENCE { mechanism LDAPString, credentials OCTET STRING OPTIONAL }`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SaslCredentials {
+    pub mechanism: String,
+    pub credentials: Option<Vec<u8>>,
+}
+
+/// A request decoded by the `LdapControlsCodec`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct LdapRequest {
+    pub msg: LdapMsg,
+    pub controls: Vec<RequestControl>,
+    /// Set for the SASL binds: `msg` is then a bind request with an empty password.
+    pub sasl_credentials: Option<SaslCredentials>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RequestControl {
+    pub oid: String,
+    pub criticality: bool,
+    pub value: Option<Vec<u8>>,
+}
+
+/// The `sortResult` of the server-side sort response control.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SortResultCode {
+    Success = 0,
+    UnwillingToPerform = 53,
+}
+
+/// The controls of a response: the ones that `ldap3_proto` can't encode are encoded by the
+/// `LdapControlsCodec`.
+#[derive(Debug, Clone, PartialEq)]
+pub enum ResponseControl {
+    Ldap(LdapControl),
+    SortResult {
+        code: SortResultCode,
+        /// The attribute that couldn't be sorted by.
+        attribute: Option<String>,
+    },
+}
+
+pub(crate) const SEQUENCE_TAG: u8 = 0x30;
+const BOOLEAN_TAG: u8 = 0x01;
+const INTEGER_TAG: u8 = 0x02;
+pub(crate) const OCTET_STRING_TAG: u8 = 0x04;
+const ENUMERATED_TAG: u8 = 0x0A;
+const CONTROLS_TAG: u8 = 0xA0;
+const CONTEXT_0_TAG: u8 = 0x80;
+const CONTEXT_1_TAG: u8 = 0x81;
+const BIND_REQUEST_TAG: u8 = 0x60;
+const SASL_CREDENTIALS_TAG: u8 = 0xA3;
+const SEARCH_REQUEST_TAG: u8 = 0x63;
+const AND_FILTER_TAG: u8 = 0xA0;
+const OR_FILTER_TAG: u8 = 0xA1;
+const NOT_FILTER_TAG: u8 = 0xA2;
+const EQUALITY_FILTER_TAG: u8 = 0xA3;
+const GREATER_OR_EQUAL_FILTER_TAG: u8 = 0xA5;
+const LESS_OR_EQUAL_FILTER_TAG: u8 = 0xA6;
+const APPROX_FILTER_TAG: u8 = 0xA8;
+const EXTENSIBLE_FILTER_TAG: u8 = 0xA9;
+const MATCHING_RULE_TAG: u8 = 0x81;
+const MATCHING_TYPE_TAG: u8 = 0x82;
+const MATCH_VALUE_TAG: u8 = 0x83;
+const DN_ATTRIBUTES_TAG: u8 = 0x84;
+
+/// Splits the first BER element of `data` into its tag, its contents and the rest of the data.
+/// Only the definite lengths are valid in LDAP.
+pub(crate) fn split_element(data: &[u8]) -> Option<(u8, &[u8], &[u8])> {
+    let (&tag, data) = data.split_first()?;
+    let (&first_length_byte, data) = data.split_first()?;
+    let (length, data) = if first_length_byte < 0x80 {
+        (first_length_byte as usize, data)
+    } else {
+        let num_bytes = (first_length_byte & 0x7F) as usize;
+        if num_bytes == 0 || num_bytes > std::mem::size_of::<usize>() || data.len() < num_bytes {
+            return None;
+        }
+        let (length_bytes, data) = data.split_at(num_bytes);
+        (
+            length_bytes
+                .iter()
+                .fold(0usize, |length, &b| (length << 8) | b as usize),
+            data,
+        )
+    };
+    if data.len() < length {
+        return None;
+    }
+    let (contents, rest) = data.split_at(length);
+    Some((tag, contents, rest))
+}
+
+/// The BER element with the definite length encoding, short or long form.
+pub(crate) fn make_element(tag: u8, contents: &[u8]) -> Vec<u8> {
+    let mut element = vec![tag];
+    if contents.len() < 0x80 {
+        element.push(contents.len() as u8);
+    } else {
+        let length_bytes = contents
+            .len()
+            .to_be_bytes()
+            .into_iter()
+            .skip_while(|&b| b == 0)
+            .collect::<Vec<_>>();
+        element.push(0x80 | length_bytes.len() as u8);
+        element.extend(length_bytes);
+    }
+    element.extend(contents);
+    element
+}
+
+pub(crate) fn elements(mut data: &[u8]) -> impl Iterator<Item = (u8, &[u8])> {
+    std::iter::from_fn(move || {
+        let (tag, contents, rest) = split_element(data)?;
+        data = rest;
+        Some((tag, contents))
+    })
+}
+
+/// `Control ::= SEQUENCE { controlType LDAPOID, criticality BOOLEAN DEFAULT FALSE, controlValue
+/// OCTET STRING OPTIONAL }`
+fn parse_control(contents: &[u8]) -> Option<RequestControl> {
+    let mut fields = elements(contents);
+    let oid = match fields.next()? {
+        (OCTET_STRING_TAG, oid) => String::from_utf8(oid.to_vec()).ok()?,
+        _ => return None,
+    };
+    let (criticality, value) = match fields.next() {
+        Some((BOOLEAN_TAG, [criticality])) => (*criticality != 0, fields.next()),
+        field => (false, field),
+    };
+    let value = match value {
+        Some((OCTET_STRING_TAG, value)) => Some(value.to_vec()),
+        _ => None,
+    };
+    Some(RequestControl {
+        oid,
+        criticality,
+        value,
+    })
+}
+
+/// The value of the server-side sort request control (RFC 2891): `SortKeyList ::= SEQUENCE OF
+/// SEQUENCE { attributeType AttributeDescription, orderingRule [0] MatchingRuleId OPTIONAL,
+/// reverseOrder [1] BOOLEAN DEFAULT FALSE }`. Returns `None` if it is invalid or empty.
+pub fn parse_sort_keys(value: &[u8]) -> Option<Vec<SortKey>> {
+    let (tag, keys, _) = split_element(value)?;
+    if tag != SEQUENCE_TAG {
+        return None;
+    }
+    let keys = elements(keys)
+        .map(|(tag, key)| {
+            if tag != SEQUENCE_TAG {
+                return None;
+            }
+            let mut fields = elements(key);
+            let attribute = match fields.next()? {
+                (OCTET_STRING_TAG, attribute) => String::from_utf8(attribute.to_vec()).ok()?,
+                _ => return None,
+            };
+            let reverse =
+                fields.any(|field| matches!(field, (CONTEXT_1_TAG, [value]) if *value != 0));
+            Some(SortKey { attribute, reverse })
+        })
+        .collect::<Option<Vec<_>>>()?;
+    Some(keys).filter(|keys| !keys.is_empty())
+}
+
+/// `SortResult ::= SEQUENCE { sortResult ENUMERATED, attributeType [0] AttributeDescription
+/// OPTIONAL }`, in a `Control`.
+fn encode_sort_result(code: SortResultCode, attribute: Option<&str>) -> Vec<u8> {
+    let mut sort_result = make_element(ENUMERATED_TAG, &[code as u8]);
+    if let Some(attribute) = attribute {
+        sort_result.extend(make_element(CONTEXT_0_TAG, attribute.as_bytes()));
+    }
+    let mut control = make_element(OCTET_STRING_TAG, SORT_RESPONSE_OID.as_bytes());
+    control.extend(make_element(
+        OCTET_STRING_TAG,
+        &make_element(SEQUENCE_TAG, &sort_result),
+    ));
+    make_element(SEQUENCE_TAG, &control)
+}
+
+/// Adds the encoded controls to the ones of the encoded `LDAPMessage`, always last.
+fn add_encoded_controls(message: &[u8], controls: &[Vec<u8>]) -> Option<Vec<u8>> {
+    let (tag, contents, _) = split_element(message)?;
+    if tag != SEQUENCE_TAG {
+        return None;
+    }
+    let mut new_contents = Vec::new();
+    let mut new_controls = Vec::new();
+    for (tag, element) in elements(contents) {
+        if tag == CONTROLS_TAG {
+            new_controls.extend(element);
+        } else {
+            new_contents.extend(make_element(tag, element));
+        }
+    }
+    new_controls.extend(controls.concat());
+    new_contents.extend(make_element(CONTROLS_TAG, &new_controls));
+    Some(make_element(SEQUENCE_TAG, &new_contents))
+}
+
+/// The controls of a complete `LDAPMessage ::= SEQUENCE { messageID, protocolOp, controls [0]
+/// Controls OPTIONAL }`. Returns `None` if the message isn't complete yet.
+pub fn parse_request_controls(message: &[u8]) -> Option<Vec<RequestControl>> {
+    let (tag, contents, _) = split_element(message)?;
+    if tag != SEQUENCE_TAG {
+        return Some(Vec::new());
+    }
+    Some(
+        elements(contents)
+            .find(|(tag, _)| *tag == CONTROLS_TAG)
+            .map(|(_, controls)| {
+                elements(controls)
+                    .filter(|(tag, _)| *tag == SEQUENCE_TAG)
+                    .filter_map(|(_, control)| parse_control(control))
+                    .collect()
+            })
+            .unwrap_or_default(),
+    )
+}
+
+/// A complete `LDAPMessage` with a `BindRequest ::= [APPLICATION 0] SEQUENCE { version INTEGER,
+/// name LDAPDN, authentication AuthenticationChoice }` of the `sasl [3]` choice. Returns the
+/// message ID, the DN, the credentials and the length of the message.
+fn parse_sasl_bind(message: &[u8]) -> Option<(i32, String, SaslCredentials, usize)> {
+    let (tag, contents, rest) = split_element(message)?;
+    if tag != SEQUENCE_TAG {
+        return None;
+    }
+    let mut fields = elements(contents);
+    let msgid = match fields.next()? {
+        // `MessageID ::= INTEGER (0 .. maxInt)`, at most 4 bytes.
+        (INTEGER_TAG, msgid) if !msgid.is_empty() && msgid.len() <= 4 => msgid
+            .iter()
+            .fold(0i32, |msgid, &b| (msgid << 8) | i32::from(b)),
+        _ => return None,
+    };
+    let bind = match fields.next()? {
+        (BIND_REQUEST_TAG, bind) => bind,
+        _ => return None,
+    };
+    let mut bind_fields = elements(bind);
+    bind_fields.next().filter(|(tag, _)| *tag == INTEGER_TAG)?;
+    let dn = match bind_fields.next()? {
+        (OCTET_STRING_TAG, dn) => String::from_utf8(dn.to_vec()).ok()?,
+        _ => return None,
+    };
+    let sasl = match bind_fields.next()? {
+        (SASL_CREDENTIALS_TAG, sasl) => sasl,
+        _ => return None,
+    };
+    let mut sasl_fields = elements(sasl);
+    let mechanism = match sasl_fields.next()? {
+        (OCTET_STRING_TAG, mechanism) => String::from_utf8(mechanism.to_vec()).ok()?,
+        _ => return None,
+    };
+    let credentials = match sasl_fields.next() {
+        Some((OCTET_STRING_TAG, credentials)) => Some(credentials.to_vec()),
+        _ => None,
+    };
+    Some((
+        msgid,
+        dn,
+        SaslCredentials {
+            mechanism,
+            credentials,
+        },
+        message.len() - rest.len(),
+    ))
+}
+
+/// Rewrites the filters that `ldap3_proto` can't decode into equality filters: `(cn~=Bob)` into
+/// `(cn=Bob)`, and the `MatchingRuleAssertion ::= SEQUENCE { matchingRule [1] OPTIONAL, type [2]
+/// OPTIONAL, matchValue [3], dnAttributes [4] BOOLEAN DEFAULT FALSE }` of
+/// `(cn:dn:caseExactMatch:=Bob)` into an equality on the attribute `cn:dn:caseExactMatch`, for the
+/// handler to interpret the matching rule. Likewise, `(uidNumber>=1000)` becomes an equality on the
+/// attribute `uidNumber>=`.
+fn rewrite_filter(tag: u8, contents: &[u8]) -> Option<Vec<u8>> {
+    Some(match tag {
+        AND_FILTER_TAG | OR_FILTER_TAG | NOT_FILTER_TAG => {
+            let mut filters = Vec::new();
+            let mut rest = contents;
+            while !rest.is_empty() {
+                let (tag, contents, next) = split_element(rest)?;
+                filters.extend(rewrite_filter(tag, contents)?);
+                rest = next;
+            }
+            make_element(tag, &filters)
+        }
+        APPROX_FILTER_TAG => make_element(EQUALITY_FILTER_TAG, contents),
+        GREATER_OR_EQUAL_FILTER_TAG | LESS_OR_EQUAL_FILTER_TAG => {
+            // `AttributeValueAssertion ::= SEQUENCE { attributeDesc, assertionValue }`
+            let mut fields = elements(contents);
+            let (attribute, value) = match (fields.next()?, fields.next()?) {
+                ((OCTET_STRING_TAG, attribute), (OCTET_STRING_TAG, value)) => (attribute, value),
+                _ => return None,
+            };
+            let mut attribute = attribute.to_vec();
+            attribute.extend_from_slice(if tag == GREATER_OR_EQUAL_FILTER_TAG {
+                b">="
+            } else {
+                b"<="
+            });
+            let mut assertion = make_element(OCTET_STRING_TAG, &attribute);
+            assertion.extend(make_element(OCTET_STRING_TAG, value));
+            make_element(EQUALITY_FILTER_TAG, &assertion)
+        }
+        EXTENSIBLE_FILTER_TAG => {
+            let (mut rule, mut attribute, mut value, mut dn_attributes) = (None, None, None, false);
+            for (tag, field) in elements(contents) {
+                match tag {
+                    MATCHING_RULE_TAG => rule = Some(std::str::from_utf8(field).ok()?),
+                    MATCHING_TYPE_TAG => attribute = Some(std::str::from_utf8(field).ok()?),
+                    MATCH_VALUE_TAG => value = Some(field),
+                    DN_ATTRIBUTES_TAG => dn_attributes = field.iter().any(|&b| b != 0),
+                    _ => return None,
+                }
+            }
+            let mut attribute = attribute.unwrap_or_default().to_owned();
+            if dn_attributes {
+                attribute.push_str(":dn");
+            }
+            if let Some(rule) = rule {
+                attribute.push(':');
+                attribute.push_str(rule);
+            }
+            let mut assertion = make_element(OCTET_STRING_TAG, attribute.as_bytes());
+            assertion.extend(make_element(OCTET_STRING_TAG, value?));
+            make_element(EQUALITY_FILTER_TAG, &assertion)
+        }
+        _ => make_element(tag, contents),
+    })
+}
+
+/// A complete `LDAPMessage` with a `SearchRequest ::= [APPLICATION 3] SEQUENCE { baseObject,
+/// scope, derefAliases, sizeLimit, timeLimit, typesOnly, filter Filter, attributes }` whose filter
+/// needs rewriting. Returns the rewritten message and the length of the original one.
+fn rewrite_search_filter(message: &[u8]) -> Option<(Vec<u8>, usize)> {
+    let (tag, contents, rest) = split_element(message)?;
+    if tag != SEQUENCE_TAG {
+        return None;
+    }
+    let (msgid_tag, msgid, after_msgid) = split_element(contents)?;
+    let (op_tag, search, controls) = split_element(after_msgid)?;
+    if op_tag != SEARCH_REQUEST_TAG {
+        return None;
+    }
+    let mut filter_start = search;
+    for _ in 0..6 {
+        filter_start = split_element(filter_start)?.2;
+    }
+    let (filter_tag, filter, attributes) = split_element(filter_start)?;
+    let rewritten_filter = rewrite_filter(filter_tag, filter)?;
+    if rewritten_filter == filter_start[..filter_start.len() - attributes.len()] {
+        return None;
+    }
+    let mut rewritten_search = search[..search.len() - filter_start.len()].to_vec();
+    rewritten_search.extend(rewritten_filter);
+    rewritten_search.extend(attributes);
+    let mut rewritten = make_element(msgid_tag, msgid);
+    rewritten.extend(make_element(SEARCH_REQUEST_TAG, &rewritten_search));
+    rewritten.extend(controls);
+    Some((
+        make_element(SEQUENCE_TAG, &rewritten),
+        message.len() - rest.len(),
+    ))
+}
+
+/// The `LdapCodec`, that also returns the raw controls of each request, decodes the SASL binds and
+/// rewrites the filters that `ldap3_proto` can't decode.
+pub struct LdapControlsCodec;
+
+impl Decoder for LdapControlsCodec {
+    type Item = LdapRequest;
+    type Error = std::io::Error;
+
+    fn decode(&mut self, buf: &mut BytesMut) -> Result<Option<Self::Item>, Self::Error> {
+        let controls = parse_request_controls(buf).unwrap_or_default();
+        if let Some((msgid, dn, sasl_credentials, length)) = parse_sasl_bind(buf) {
+            let _ = buf.split_to(length);
+            return Ok(Some(LdapRequest {
+                msg: LdapMsg {
+                    msgid,
+                    op: LdapOp::BindRequest(LdapBindRequest {
+                        dn,
+                        cred: LdapBindCred::Simple(String::new()),
+                    }),
+                    ctrl: vec![],
+                },
+                controls,
+                sasl_credentials: Some(sasl_credentials),
+            }));
+        }
+        if let Some((message, length)) = rewrite_search_filter(buf) {
+            let _ = buf.split_to(length);
+            let msg = LdapCodec
+                .decode(&mut BytesMut::from(message.as_slice()))?
+                .ok_or_else(|| {
+                    std::io::Error::new(std::io::ErrorKind::InvalidData, "Invalid search request")
+                })?;
+            return Ok(Some(LdapRequest {
+                msg,
+                controls,
+                sasl_credentials: None,
+            }));
+        }
+        Ok(LdapCodec.decode(buf)?.map(|msg| LdapRequest {
+            msg,
+            controls,
+            sasl_credentials: None,
+        }))
+    }
+}
+
+impl Encoder<(LdapMsg, Vec<ResponseControl>)> for LdapControlsCodec {
+    type Error = std::io::Error;
+
+    fn encode(
+        &mut self,
+        (mut msg, controls): (LdapMsg, Vec<ResponseControl>),
+        buf: &mut BytesMut,
+    ) -> Result<(), Self::Error> {
+        let mut encoded_controls = Vec::new();
+        for control in controls {
+            match control {
+                ResponseControl::Ldap(control) => msg.ctrl.push(control),
+                ResponseControl::SortResult { code, attribute } => {
+                    encoded_controls.push(encode_sort_result(code, attribute.as_deref()))
+                }
+            }
+        }
+        if encoded_controls.is_empty() {
+            return LdapCodec.encode(msg, buf);
+        }
+        let mut message = BytesMut::new();
+        LdapCodec.encode(msg, &mut message)?;
+        let message = add_encoded_controls(&message, &encoded_controls).ok_or_else(|| {
+            std::io::Error::new(std::io::ErrorKind::InvalidData, "Invalid encoded message")
+        })?;
+        buf.extend_from_slice(&message);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn element(tag: u8, contents: &[u8]) -> Vec<u8> {
+        let mut result = vec![tag];
+        if contents.len() < 0x80 {
+            result.push(contents.len() as u8);
+        } else {
+            result.extend([0x82, (contents.len() >> 8) as u8, contents.len() as u8]);
+        }
+        result.extend(contents);
+        result
+    }
+
+    fn sort_key(attribute: &str, reverse: bool) -> SortKey {
+        SortKey {
+            attribute: attribute.to_string(),
+            reverse,
+        }
+    }
+
+    fn control(oid: &str, criticality: Option<bool>) -> Vec<u8> {
+        let mut contents = element(OCTET_STRING_TAG, oid.as_bytes());
+        if let Some(criticality) = criticality {
+            contents.extend(element(BOOLEAN_TAG, &[if criticality { 0xFF } else { 0 }]));
+        }
+        contents.extend(element(OCTET_STRING_TAG, b"value"));
+        element(SEQUENCE_TAG, &contents)
+    }
+
+    fn message(controls: Option<Vec<Vec<u8>>>) -> Vec<u8> {
+        // messageID 3, DelRequest "ou=groups,dc=example,dc=com".
+        let mut contents = element(0x02, &[3]);
+        contents.extend(element(0x4A, b"ou=groups,dc=example,dc=com"));
+        if let Some(controls) = controls {
+            contents.extend(element(CONTROLS_TAG, &controls.concat()));
+        }
+        element(SEQUENCE_TAG, &contents)
+    }
+
+    #[test]
+    fn test_parse_request_controls() {
+        assert_eq!(parse_request_controls(&message(None)), Some(vec![]));
+        assert_eq!(
+            parse_request_controls(&message(Some(vec![
+                control(SUBTREE_DELETE_OID, Some(true)),
+                control(MANAGE_DSA_IT_OID, None),
+                control("1.2.3.4", Some(false)),
+            ]))),
+            Some(vec![
+                RequestControl {
+                    oid: SUBTREE_DELETE_OID.to_string(),
+                    criticality: true,
+                    value: Some(b"value".to_vec()),
+                },
+                RequestControl {
+                    oid: MANAGE_DSA_IT_OID.to_string(),
+                    criticality: false,
+                    value: Some(b"value".to_vec()),
+                },
+                RequestControl {
+                    oid: "1.2.3.4".to_string(),
+                    criticality: false,
+                    value: Some(b"value".to_vec()),
+                },
+            ])
+        );
+    }
+
+    #[test]
+    fn test_parse_request_controls_lengths() {
+        let message = message(Some(vec![control(SUBTREE_DELETE_OID, Some(true))]));
+        assert_eq!(parse_request_controls(&message[..message.len() - 1]), None);
+        assert_eq!(parse_request_controls(&[]), None);
+        // A long form length.
+        let long_oid = "1.".repeat(100);
+        assert_eq!(
+            parse_request_controls(&element(
+                SEQUENCE_TAG,
+                &element(CONTROLS_TAG, &control(&long_oid, Some(true)))
+            )),
+            Some(vec![RequestControl {
+                oid: long_oid,
+                criticality: true,
+                value: Some(b"value".to_vec()),
+            }])
+        );
+    }
+
+    #[test]
+    fn test_make_element() {
+        let contents = vec![0x42; 300];
+        assert_eq!(
+            make_element(OCTET_STRING_TAG, &contents),
+            element(OCTET_STRING_TAG, &contents)
+        );
+        assert_eq!(make_element(BOOLEAN_TAG, &[0]), element(BOOLEAN_TAG, &[0]));
+    }
+
+    #[test]
+    fn test_parse_sort_keys() {
+        let keys = element(
+            SEQUENCE_TAG,
+            &[
+                element(SEQUENCE_TAG, &element(OCTET_STRING_TAG, b"cn")),
+                element(
+                    SEQUENCE_TAG,
+                    &[
+                        element(OCTET_STRING_TAG, b"createTimestamp"),
+                        element(CONTEXT_0_TAG, b"2.5.13.28"),
+                        element(CONTEXT_1_TAG, &[0xFF]),
+                    ]
+                    .concat(),
+                ),
+            ]
+            .concat(),
+        );
+        assert_eq!(
+            parse_sort_keys(&keys),
+            Some(vec![
+                sort_key("cn", false),
+                sort_key("createTimestamp", true)
+            ])
+        );
+        assert_eq!(parse_sort_keys(&element(SEQUENCE_TAG, &[])), None);
+        assert_eq!(parse_sort_keys(b"cn"), None);
+    }
+
+    #[test]
+    fn test_decode_sasl_bind() {
+        let bind = |authentication: Vec<u8>| {
+            element(
+                SEQUENCE_TAG,
+                &[
+                    element(INTEGER_TAG, &[1, 2]),
+                    element(
+                        BIND_REQUEST_TAG,
+                        &[
+                            element(INTEGER_TAG, &[3]),
+                            element(OCTET_STRING_TAG, b""),
+                            authentication,
+                        ]
+                        .concat(),
+                    ),
+                ]
+                .concat(),
+            )
+        };
+        let sasl = bind(element(
+            SASL_CREDENTIALS_TAG,
+            &[
+                element(OCTET_STRING_TAG, b"EXTERNAL"),
+                element(OCTET_STRING_TAG, b"u:bob"),
+            ]
+            .concat(),
+        ));
+        let simple = bind(element(CONTEXT_0_TAG, b"password"));
+        let mut buf = BytesMut::from(&[sasl.as_slice(), simple.as_slice()].concat()[..]);
+        let mut codec = LdapControlsCodec;
+        assert_eq!(
+            codec.decode(&mut buf).unwrap(),
+            Some(LdapRequest {
+                msg: LdapMsg {
+                    msgid: 258,
+                    op: LdapOp::BindRequest(LdapBindRequest {
+                        dn: "".to_string(),
+                        cred: LdapBindCred::Simple(String::new()),
+                    }),
+                    ctrl: vec![],
+                },
+                controls: vec![],
+                sasl_credentials: Some(SaslCredentials {
+                    mechanism: "EXTERNAL".to_string(),
+                    credentials: Some(b"u:bob".to_vec()),
+                }),
+            })
+        );
+        // The next message is left for the `LdapCodec`.
+        assert_eq!(buf.to_vec(), simple);
+        assert_eq!(
+            codec.decode(&mut buf).unwrap().unwrap().sasl_credentials,
+            None
+        );
+        // An incomplete message waits for the rest.
+        let mut buf = BytesMut::from(&sasl[..sasl.len() - 1]);
+        assert_eq!(codec.decode(&mut buf).unwrap(), None);
+    }
+
+    #[test]
+    fn test_decode_rewritten_filters() {
+        use ldap3_proto::proto::{
+            LdapDerefAliases, LdapFilter, LdapSearchRequest, LdapSearchScope,
+        };
+        let assertion = |attribute: &[u8], value: &[u8]| {
+            [
+                element(OCTET_STRING_TAG, attribute),
+                element(OCTET_STRING_TAG, value),
+            ]
+            .concat()
+        };
+        let filter = element(
+            AND_FILTER_TAG,
+            &[
+                element(APPROX_FILTER_TAG, &assertion(b"cn", b"Bob")),
+                element(
+                    EXTENSIBLE_FILTER_TAG,
+                    &[
+                        element(MATCHING_RULE_TAG, b"caseExactMatch"),
+                        element(MATCHING_TYPE_TAG, b"cn"),
+                        element(MATCH_VALUE_TAG, b"Bob"),
+                        element(DN_ATTRIBUTES_TAG, &[0xFF]),
+                    ]
+                    .concat(),
+                ),
+                element(
+                    NOT_FILTER_TAG,
+                    &element(
+                        EXTENSIBLE_FILTER_TAG,
+                        &[
+                            element(MATCHING_RULE_TAG, b"2.5.13.2"),
+                            element(MATCHING_TYPE_TAG, b"uid"),
+                            element(MATCH_VALUE_TAG, b"bob"),
+                        ]
+                        .concat(),
+                    ),
+                ),
+                element(
+                    GREATER_OR_EQUAL_FILTER_TAG,
+                    &assertion(b"uidNumber", b"1000"),
+                ),
+                element(
+                    LESS_OR_EQUAL_FILTER_TAG,
+                    &assertion(b"createTimestamp", b"20240101000000Z"),
+                ),
+                element(0x87, b"objectClass"),
+            ]
+            .concat(),
+        );
+        let search = |filter: Vec<u8>| {
+            element(
+                SEQUENCE_TAG,
+                &[
+                    element(INTEGER_TAG, &[5]),
+                    element(
+                        SEARCH_REQUEST_TAG,
+                        &[
+                            element(OCTET_STRING_TAG, b"dc=example,dc=com"),
+                            element(ENUMERATED_TAG, &[2]),
+                            element(ENUMERATED_TAG, &[0]),
+                            element(INTEGER_TAG, &[0]),
+                            element(INTEGER_TAG, &[0]),
+                            element(BOOLEAN_TAG, &[0]),
+                            filter,
+                            element(SEQUENCE_TAG, &element(OCTET_STRING_TAG, b"cn")),
+                        ]
+                        .concat(),
+                    ),
+                    element(CONTROLS_TAG, &control(MANAGE_DSA_IT_OID, None)),
+                ]
+                .concat(),
+            )
+        };
+        let mut buf = BytesMut::from(&search(filter)[..]);
+        let request = LdapControlsCodec.decode(&mut buf).unwrap().unwrap();
+        assert!(buf.is_empty());
+        assert_eq!(request.controls.len(), 1);
+        assert_eq!(
+            request.msg.op,
+            LdapOp::SearchRequest(LdapSearchRequest {
+                base: "dc=example,dc=com".to_string(),
+                scope: LdapSearchScope::Subtree,
+                aliases: LdapDerefAliases::Never,
+                sizelimit: 0,
+                timelimit: 0,
+                typesonly: false,
+                filter: LdapFilter::And(vec![
+                    LdapFilter::Equality("cn".to_string(), "Bob".to_string()),
+                    LdapFilter::Equality("cn:dn:caseExactMatch".to_string(), "Bob".to_string()),
+                    LdapFilter::Not(Box::new(LdapFilter::Equality(
+                        "uid:2.5.13.2".to_string(),
+                        "bob".to_string()
+                    ))),
+                    LdapFilter::Equality("uidNumber>=".to_string(), "1000".to_string()),
+                    LdapFilter::Equality(
+                        "createTimestamp<=".to_string(),
+                        "20240101000000Z".to_string()
+                    ),
+                    LdapFilter::Present("objectClass".to_string()),
+                ]),
+                attrs: vec!["cn".to_string()],
+            })
+        );
+        // The other filters and requests are left to the `LdapCodec`.
+        let present = search(element(0x87, b"objectClass"));
+        assert_eq!(rewrite_search_filter(&present), None);
+        assert_eq!(rewrite_search_filter(&message(None)), None);
+    }
+
+    #[test]
+    fn test_encode_sort_result() {
+        let mut codec = LdapControlsCodec;
+        let mut buf = BytesMut::new();
+        let msg = LdapMsg {
+            msgid: 3,
+            op: ldap3_proto::proto::LdapOp::DelRequest("ou=groups,dc=example,dc=com".to_string()),
+            ctrl: vec![],
+        };
+        codec
+            .encode(
+                (
+                    msg.clone(),
+                    vec![ResponseControl::SortResult {
+                        code: SortResultCode::UnwillingToPerform,
+                        attribute: Some("sn".to_string()),
+                    }],
+                ),
+                &mut buf,
+            )
+            .unwrap();
+        let sort_result = element(
+            SEQUENCE_TAG,
+            &[
+                element(ENUMERATED_TAG, &[53]),
+                element(CONTEXT_0_TAG, b"sn"),
+            ]
+            .concat(),
+        );
+        let control = element(
+            SEQUENCE_TAG,
+            &[
+                element(OCTET_STRING_TAG, SORT_RESPONSE_OID.as_bytes()),
+                element(OCTET_STRING_TAG, &sort_result),
+            ]
+            .concat(),
+        );
+        let expected = element(
+            SEQUENCE_TAG,
+            &[
+                element(0x02, &[3]),
+                element(0x4A, b"ou=groups,dc=example,dc=com"),
+                element(CONTROLS_TAG, &control),
+            ]
+            .concat(),
+        );
+        assert_eq!(buf.to_vec(), expected);
+        // The message itself is still decoded.
+        assert_eq!(
+            codec.decode(&mut buf).unwrap().map(|request| request.msg),
+            Some(msg)
+        );
+    }
+}
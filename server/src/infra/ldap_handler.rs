@@ -1,29 +1,80 @@
 use crate::{
     domain::{
-        handler::{BackendHandler, BindRequest, CreateUserRequest, LoginHandler},
+        avatar::decode_avatar,
+        error::DomainError,
+        handler::{
+            AuditActor, BackendHandler, BindRequest, CreateUserRequest, GroupRequestFilter,
+            LoginHandler, Pagination, UserRequestFilter, UserSortKey,
+        },
         ldap::{
             error::{LdapError, LdapResult},
             group::get_groups_list,
-            user::get_user_list,
+            sort::{
+                get_missing_sort_attributes, get_user_sort_keys, remove_attributes, sort_entries,
+                SortKey,
+            },
+            sudo::get_sudo_roles_list,
+            user::{get_user_list, UserListing},
             utils::{
-                get_user_id_from_distinguished_name, is_subtree, parse_distinguished_name, LdapInfo,
+                get_group_id_from_distinguished_name, get_user_id_from_distinguished_name,
+                get_user_rdn_from_distinguished_name, is_subtree, make_user_distinguished_name,
+                parse_distinguished_name, resolve_user_distinguished_name, split_comparison,
+                split_matching_rule, LdapInfo, UserRdn,
             },
         },
         opaque_handler::OpaqueHandler,
-        types::{JpegPhoto, UserId},
+        password_policy::PasswordPolicy,
+        scram::{self, ClientFirstMessage, ScramExchange, ScramVerifier},
+        types::{AuditSource, UserColumn, UserId, ADMIN_GROUP_NAME},
+    },
+    infra::{
+        access_control::{AttributeAcl, UserAttributeAccess},
+        auth_service::{Permission, ValidationResults},
+        client_certificate::ClientCertificate,
+        configuration::{
+            ClientCertificateUserMapping, LdapAnonymousBind, LdapObjectClasses, LdapUnindexedSort,
+            PosixOptions, UserRdnAttribute,
+        },
+        ldap_controls::{
+            parse_sort_keys, RequestControl, ResponseControl, SaslCredentials, SortResultCode,
+            MANAGE_DSA_IT_OID, PAGED_RESULTS_OID, SORT_REQUEST_OID, SUBTREE_DELETE_OID,
+        },
+        metrics::{self, BindResult},
+        operation_timeout::{run_with_timeout, OperationKind, OperationTimeouts},
+        rate_limiter::{retry_after_seconds, NoRateLimiter, SharedRateLimiter},
     },
-    infra::auth_service::{Permission, ValidationResults},
 };
 use anyhow::Result;
 use ldap3_proto::proto::{
-    LdapAddRequest, LdapBindCred, LdapBindRequest, LdapBindResponse, LdapExtendedRequest,
-    LdapExtendedResponse, LdapFilter, LdapOp, LdapPartialAttribute, LdapPasswordModifyRequest,
-    LdapResult as LdapResultOp, LdapResultCode, LdapSearchRequest, LdapSearchResultEntry,
-    LdapSearchScope,
+    LdapAddRequest, LdapBindCred, LdapBindRequest, LdapBindResponse, LdapCompareRequest,
+    LdapControl, LdapDerefAliases, LdapExtendedRequest, LdapExtendedResponse, LdapFilter, LdapOp,
+    LdapPartialAttribute, LdapPasswordModifyRequest, LdapResult as LdapResultOp, LdapResultCode,
+    LdapSearchRequest, LdapSearchResultEntry, LdapSearchScope,
+};
+use std::{
+    collections::{HashMap, HashSet},
+    net::IpAddr,
+    sync::Arc,
+    time::Instant,
 };
-use std::collections::HashMap;
 use tracing::{debug, instrument, warn};
 
+/// The StartTLS extended operation of RFC 4511.
+pub const START_TLS_OID: &str = "1.3.6.1.4.1.1466.20037";
+/// The password modify extended operation of RFC 3062.
+const PASSWORD_MODIFY_OID: &str = "1.3.6.1.4.1.4203.1.11.1";
+/// The "Who am I?" extended operation of RFC 4532.
+const WHOAMI_OID: &str = "1.3.6.1.4.1.4203.1.11.3";
+
+/// Whether the connection of the session is encrypted.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ConnectionSecurity {
+    Plaintext,
+    /// Plaintext, until the client upgrades it with StartTLS.
+    StartTlsAvailable,
+    Tls,
+}
+
 #[derive(Debug, PartialEq, Eq, Clone)]
 struct LdapDn(String);
 
@@ -34,40 +85,69 @@ enum SearchScope {
     Groups,
     User(LdapFilter),
     Group(LdapFilter),
+    SudoRoles,
+    SudoRole(LdapFilter),
+    /// The base exists, but none of the entries served are in the scope of the search: the base
+    /// DN and the OUs themselves are not served.
+    Empty,
     Unknown,
     Invalid,
 }
 
-fn get_search_scope(base_dn: &[(String, String)], dn_parts: &[(String, String)]) -> SearchScope {
+impl SearchScope {
+    /// The target of the search in the metrics, from a fixed set.
+    fn metric_target(&self) -> &'static str {
+        match self {
+            SearchScope::Global => "root",
+            SearchScope::Users | SearchScope::User(_) => "users",
+            SearchScope::Groups | SearchScope::Group(_) => "groups",
+            SearchScope::SudoRoles | SearchScope::SudoRole(_) => "sudoers",
+            SearchScope::Empty | SearchScope::Unknown | SearchScope::Invalid => "other",
+        }
+    }
+}
+
+fn get_search_scope(
+    ldap_info: &LdapInfo,
+    dn_parts: &[(String, String)],
+    request_scope: &LdapSearchScope,
+) -> SearchScope {
+    let base_dn = &ldap_info.base_dn;
+    let sudoers_base_dn = &ldap_info.sudoers_base_dn;
     let base_dn_len = base_dn.len();
-    if !is_subtree(dn_parts, base_dn) {
-        SearchScope::Invalid
+    let is_ou = |index: usize, ou: &String| dn_parts[index].0 == "ou" && &dn_parts[index].1 == ou;
+    let rdn_filter = || LdapFilter::Equality(dn_parts[0].0.clone(), dn_parts[0].1.clone());
+    // The entries are all directly under an OU: the searches on a container find its entries
+    // unless they only look at the base, and the searches on an entry find it unless they only
+    // look at its children.
+    let (container, entry) = match request_scope {
+        LdapSearchScope::Base => (false, true),
+        LdapSearchScope::OneLevel => (true, false),
+        _ => (true, true),
+    };
+    let scope = if !is_subtree(dn_parts, base_dn) {
+        return SearchScope::Invalid;
     } else if dn_parts.len() == base_dn_len {
-        SearchScope::Global
-    } else if dn_parts.len() == base_dn_len + 1
-        && dn_parts[0] == ("ou".to_string(), "people".to_string())
-    {
-        SearchScope::Users
-    } else if dn_parts.len() == base_dn_len + 1
-        && dn_parts[0] == ("ou".to_string(), "groups".to_string())
-    {
-        SearchScope::Groups
-    } else if dn_parts.len() == base_dn_len + 2
-        && dn_parts[1] == ("ou".to_string(), "people".to_string())
-    {
-        SearchScope::User(LdapFilter::Equality(
-            dn_parts[0].0.clone(),
-            dn_parts[0].1.clone(),
-        ))
-    } else if dn_parts.len() == base_dn_len + 2
-        && dn_parts[1] == ("ou".to_string(), "groups".to_string())
-    {
-        SearchScope::Group(LdapFilter::Equality(
-            dn_parts[0].0.clone(),
-            dn_parts[0].1.clone(),
-        ))
+        // The entries are two levels below the base DN.
+        (container && entry, SearchScope::Global)
+    } else if dn_parts == sudoers_base_dn.as_slice() {
+        (container, SearchScope::SudoRoles)
+    } else if dn_parts.len() == sudoers_base_dn.len() + 1 && is_subtree(dn_parts, sudoers_base_dn) {
+        (entry, SearchScope::SudoRole(rdn_filter()))
+    } else if dn_parts.len() == base_dn_len + 1 && is_ou(0, &ldap_info.users_ou) {
+        (container, SearchScope::Users)
+    } else if dn_parts.len() == base_dn_len + 1 && is_ou(0, &ldap_info.groups_ou) {
+        (container, SearchScope::Groups)
+    } else if dn_parts.len() == base_dn_len + 2 && is_ou(1, &ldap_info.users_ou) {
+        (entry, SearchScope::User(rdn_filter()))
+    } else if dn_parts.len() == base_dn_len + 2 && is_ou(1, &ldap_info.groups_ou) {
+        (entry, SearchScope::Group(rdn_filter()))
     } else {
-        SearchScope::Unknown
+        return SearchScope::Unknown;
+    };
+    match scope {
+        (true, scope) => scope,
+        (false, _) => SearchScope::Empty,
     }
 }
 
@@ -93,6 +173,24 @@ fn make_add_error(code: LdapResultCode, message: String) -> LdapOp {
     })
 }
 
+fn make_compare_result(code: LdapResultCode, message: String) -> LdapOp {
+    LdapOp::CompareResult(LdapResultOp {
+        code,
+        matcheddn: "".to_string(),
+        message,
+        referral: vec![],
+    })
+}
+
+fn make_del_response(code: LdapResultCode, message: String) -> LdapOp {
+    LdapOp::DelResponse(LdapResultOp {
+        code,
+        matcheddn: "".to_string(),
+        message,
+        referral: vec![],
+    })
+}
+
 fn make_extended_response(code: LdapResultCode, message: String) -> LdapOp {
     LdapOp::ExtendedResponse(LdapExtendedResponse {
         res: LdapResultOp {
@@ -106,62 +204,318 @@ fn make_extended_response(code: LdapResultCode, message: String) -> LdapOp {
     })
 }
 
-fn root_dse_response(base_dn: &str) -> LdapOp {
+/// The paged results control of the response, with the cookie of the next page if any.
+fn make_paged_control(cookie: Option<PagedSearchCookie>) -> ResponseControl {
+    ResponseControl::Ldap(LdapControl::SimplePagedResults {
+        size: 0,
+        cookie: cookie.map(|c| c.serialize()).unwrap_or_default(),
+    })
+}
+
+/// The result of a failed bind, recorded in the metrics. The wrong passwords and the unknown users
+/// get the same answer.
+fn bind_error_response(error: DomainError, start: Instant) -> (LdapResultCode, String) {
+    match error {
+        DomainError::PasswordChangeRequired(_) => {
+            metrics::record_ldap_bind(BindResult::PasswordChangeRequired, start);
+            (
+                LdapResultCode::InvalidCredentials,
+                "The password has expired and must be reset".to_string(),
+            )
+        }
+        DomainError::AccountLocked(_) => {
+            metrics::record_ldap_bind(BindResult::AccountLocked, start);
+            (
+                LdapResultCode::InvalidCredentials,
+                "Too many failed attempts, the account is temporarily locked".to_string(),
+            )
+        }
+        DomainError::ServerBusy => {
+            metrics::record_ldap_bind(BindResult::Error, start);
+            (LdapResultCode::Busy, DomainError::ServerBusy.to_string())
+        }
+        e => {
+            metrics::record_ldap_bind(
+                match e {
+                    DomainError::AuthenticationError(_) | DomainError::EntityNotFound(_) => {
+                        BindResult::InvalidCredentials
+                    }
+                    _ => BindResult::Error,
+                },
+                start,
+            );
+            (LdapResultCode::InvalidCredentials, "".to_string())
+        }
+    }
+}
+
+/// The error response to a request, or `None` for the requests without a response.
+fn make_error_response(request: &LdapOp, code: LdapResultCode, message: String) -> Option<LdapOp> {
+    Some(match request {
+        LdapOp::BindRequest(_) => LdapOp::BindResponse(LdapBindResponse {
+            res: LdapResultOp {
+                code,
+                matcheddn: "".to_string(),
+                message,
+                referral: vec![],
+            },
+            saslcreds: None,
+        }),
+        LdapOp::SearchRequest(_) => make_search_error(code, message),
+        LdapOp::AddRequest(_) => make_add_error(code, message),
+        LdapOp::DelRequest(_) => make_del_response(code, message),
+        LdapOp::CompareRequest(_) => make_compare_result(code, message),
+        LdapOp::UnbindRequest => return None,
+        _ => make_extended_response(code, message),
+    })
+}
+
+/// The controls accepted by `is_supported_control`, for at least one operation.
+const SUPPORTED_CONTROLS: &[&str] = &[
+    PAGED_RESULTS_OID,
+    SORT_REQUEST_OID,
+    SUBTREE_DELETE_OID,
+    MANAGE_DSA_IT_OID,
+];
+
+/// The controls that change the behavior of a request. ManageDsaIT is accepted everywhere: there
+/// are no referrals in this tree, so there is nothing to manage differently.
+
+fn is_supported_control(request: &LdapOp, oid: &str) -> bool {
+    match request {
+        _ if oid == MANAGE_DSA_IT_OID => true,
+        LdapOp::SearchRequest(_) => oid == PAGED_RESULTS_OID || oid == SORT_REQUEST_OID,
+        LdapOp::DelRequest(_) => oid == SUBTREE_DELETE_OID,
+        _ => false,
+    }
+}
+
+const GENERATED_PASSWORD_LENGTH: usize = 20;
+const GENERATED_PASSWORD_SYMBOLS: &[u8] = b"!#%+-.:=@_";
+
+/// A random password that satisfies the policy: the character classes are very likely to all
+/// appear at that length, and it's drawn again otherwise.
+fn generate_password(policy: &PasswordPolicy) -> String {
+    use rand::{distributions::Alphanumeric, seq::SliceRandom, Rng};
+    let options = policy.options();
+    let length = GENERATED_PASSWORD_LENGTH
+        .max(options.min_length)
+        .min(options.max_length);
+    let mut rng = rand::rngs::OsRng;
+    let mut generate = || -> String {
+        (0..length)
+            .map(|_| {
+                if options.require_symbol && rng.gen_ratio(1, 5) {
+                    *GENERATED_PASSWORD_SYMBOLS.choose(&mut rng).unwrap() as char
+                } else {
+                    rng.sample(Alphanumeric) as char
+                }
+            })
+            .collect()
+    };
+    let mut password = generate();
+    for _ in 0..10 {
+        if policy.check_rules(&password).is_empty() {
+            break;
+        }
+        password = generate();
+    }
+    password
+}
+
+fn encode_ber_length(length: usize, output: &mut Vec<u8>) {
+    if length < 0x80 {
+        output.push(length as u8);
+    } else {
+        let bytes = length.to_be_bytes();
+        let bytes = &bytes[bytes.iter().take_while(|b| **b == 0).count()..];
+        output.push(0x80 | bytes.len() as u8);
+        output.extend_from_slice(bytes);
+    }
+}
+
+/// The PasswdModifyResponseValue of RFC 3062: `SEQUENCE { genPasswd [0] OCTET STRING }`.
+fn encode_generated_password(password: &str) -> Vec<u8> {
+    let mut gen_passwd = vec![0x80];
+    encode_ber_length(password.len(), &mut gen_passwd);
+    gen_passwd.extend_from_slice(password.as_bytes());
+    let mut value = vec![0x30];
+    encode_ber_length(gen_passwd.len(), &mut value);
+    value.extend(gen_passwd);
+    value
+}
+
+/// The attributes that a filter looks at. The filters that `ldap3_proto` parses but the handler
+/// doesn't support are rejected.
+fn get_filter_attributes(filter: &LdapFilter) -> LdapResult<Vec<&str>> {
+    match filter {
+        LdapFilter::And(filters) | LdapFilter::Or(filters) => filters
+            .iter()
+            .map(get_filter_attributes)
+            .collect::<LdapResult<Vec<_>>>()
+            .map(|attributes| attributes.concat()),
+        LdapFilter::Not(filter) => get_filter_attributes(filter),
+        LdapFilter::Equality(attribute, _) => {
+            Ok(vec![split_matching_rule(split_comparison(attribute).0)?.0])
+        }
+        LdapFilter::Substring(attribute, _) | LdapFilter::Present(attribute) => {
+            Ok(vec![attribute.as_str()])
+        }
+        _ => Err(LdapError {
+            code: LdapResultCode::UnwillingToPerform,
+            message: format!("Unsupported filter: {:?}", filter),
+        }),
+    }
+}
+
+/// The user that a listing of the users is restricted to: the rules of the attribute ACL can let
+/// the users list the others, with only the attributes that they can read.
+fn get_user_list_filter<'a>(
+    user_filter: Option<&'a UserId>,
+    access: Option<&UserAttributeAccess>,
+) -> Option<&'a UserId> {
+    user_filter.filter(|_| !access.map_or(false, UserAttributeAccess::can_list_users))
+}
+
+/// The capabilities of the server, for the clients' discovery. `supportedSASLMechanisms` is only
+/// listed with the enabled mechanisms: an attribute can't be empty.
+fn root_dse_response(base_dn: &str, start_tls: bool, sasl_mechanisms: &[&str]) -> LdapOp {
+    let mut extensions = vec![
+        PASSWORD_MODIFY_OID.as_bytes().to_vec(),
+        WHOAMI_OID.as_bytes().to_vec(),
+    ];
+    if start_tls {
+        extensions.push(START_TLS_OID.as_bytes().to_vec());
+    }
+    let mut attributes = vec![
+        LdapPartialAttribute {
+            atype: "objectClass".to_string(),
+            vals: vec![b"top".to_vec()],
+        },
+        LdapPartialAttribute {
+            atype: "vendorName".to_string(),
+            vals: vec![b"LLDAP".to_vec()],
+        },
+        LdapPartialAttribute {
+            atype: "vendorVersion".to_string(),
+            vals: vec![concat!("lldap_", env!("CARGO_PKG_VERSION"))
+                .to_string()
+                .into_bytes()],
+        },
+        LdapPartialAttribute {
+            atype: "supportedLDAPVersion".to_string(),
+            vals: vec![b"3".to_vec()],
+        },
+        LdapPartialAttribute {
+            atype: "supportedExtension".to_string(),
+            vals: extensions,
+        },
+        LdapPartialAttribute {
+            atype: "supportedControl".to_string(),
+            vals: SUPPORTED_CONTROLS
+                .iter()
+                .map(|oid| oid.as_bytes().to_vec())
+                .collect(),
+        },
+        LdapPartialAttribute {
+            atype: "supportedFeatures".to_string(),
+            // Attribute "+"
+            vals: vec![b"1.3.6.1.4.1.4203.1.5.1".to_vec()],
+        },
+        LdapPartialAttribute {
+            atype: "defaultNamingContext".to_string(),
+            vals: vec![base_dn.to_string().into_bytes()],
+        },
+        LdapPartialAttribute {
+            atype: "namingContexts".to_string(),
+            vals: vec![base_dn.to_string().into_bytes()],
+        },
+        LdapPartialAttribute {
+            atype: "isGlobalCatalogReady".to_string(),
+            vals: vec![b"false".to_vec()],
+        },
+    ];
+    if !sasl_mechanisms.is_empty() {
+        attributes.push(LdapPartialAttribute {
+            atype: "supportedSASLMechanisms".to_string(),
+            vals: sasl_mechanisms
+                .iter()
+                .map(|mechanism| mechanism.as_bytes().to_vec())
+                .collect(),
+        });
+    }
     LdapOp::SearchResultEntry(LdapSearchResultEntry {
         dn: "".to_string(),
-        attributes: vec![
-            LdapPartialAttribute {
-                atype: "objectClass".to_string(),
-                vals: vec![b"top".to_vec()],
-            },
-            LdapPartialAttribute {
-                atype: "vendorName".to_string(),
-                vals: vec![b"LLDAP".to_vec()],
-            },
-            LdapPartialAttribute {
-                atype: "vendorVersion".to_string(),
-                vals: vec![concat!("lldap_", env!("CARGO_PKG_VERSION"))
-                    .to_string()
-                    .into_bytes()],
-            },
-            LdapPartialAttribute {
-                atype: "supportedLDAPVersion".to_string(),
-                vals: vec![b"3".to_vec()],
-            },
-            LdapPartialAttribute {
-                atype: "supportedExtension".to_string(),
-                // Password modification extension.
-                vals: vec![b"1.3.6.1.4.1.4203.1.11.1".to_vec()],
-            },
-            LdapPartialAttribute {
-                atype: "supportedControl".to_string(),
-                vals: vec![],
-            },
-            LdapPartialAttribute {
-                atype: "supportedFeatures".to_string(),
-                // Attribute "+"
-                vals: vec![b"1.3.6.1.4.1.4203.1.5.1".to_vec()],
-            },
-            LdapPartialAttribute {
-                atype: "defaultNamingContext".to_string(),
-                vals: vec![base_dn.to_string().into_bytes()],
-            },
-            LdapPartialAttribute {
-                atype: "namingContexts".to_string(),
-                vals: vec![base_dn.to_string().into_bytes()],
-            },
-            LdapPartialAttribute {
-                atype: "isGlobalCatalogReady".to_string(),
-                vals: vec![b"false".to_vec()],
-            },
-        ],
+        attributes,
     })
 }
 
+/// Where a paged search stopped, sent to the client as the cookie of the paged results control.
+/// A search of the whole tree goes through the users, then the groups. A sorted search continues
+/// at an offset in the sorted results.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum PagedSearchCookie {
+    Users(Option<String>),
+    Groups(Option<String>),
+    Sorted(usize),
+}
+
+impl PagedSearchCookie {
+    fn parse(cookie: &[u8]) -> LdapResult<Self> {
+        if cookie.is_empty() {
+            return Ok(PagedSearchCookie::Users(None));
+        }
+        let cursor = |cursor: &str| Some(cursor.to_owned()).filter(|c| !c.is_empty());
+        let invalid_cookie = || LdapError {
+            code: LdapResultCode::UnwillingToPerform,
+            message: "Invalid paged results cookie".to_string(),
+        };
+        match std::str::from_utf8(cookie)
+            .ok()
+            .and_then(|cookie| cookie.split_once(':'))
+        {
+            Some(("users", after)) => Ok(PagedSearchCookie::Users(cursor(after))),
+            Some(("groups", after)) => Ok(PagedSearchCookie::Groups(cursor(after))),
+            Some(("sorted", offset)) => offset
+                .parse()
+                .map(PagedSearchCookie::Sorted)
+                .map_err(|_| invalid_cookie()),
+            _ => Err(invalid_cookie()),
+        }
+    }
+
+    fn serialize(&self) -> Vec<u8> {
+        match self {
+            PagedSearchCookie::Users(after) => format!("users:{}", after.as_deref().unwrap_or("")),
+            PagedSearchCookie::Groups(after) => {
+                format!("groups:{}", after.as_deref().unwrap_or(""))
+            }
+            PagedSearchCookie::Sorted(offset) => format!("sorted:{}", offset),
+        }
+        .into_bytes()
+    }
+}
+
 pub struct LdapHandler<Backend: BackendHandler + LoginHandler + OpaqueHandler> {
     user_info: Option<ValidationResults>,
     backend_handler: Backend,
     ldap_info: LdapInfo,
+    rate_limiter: SharedRateLimiter,
+    peer_ip: Option<IpAddr>,
+    password_policy: Arc<PasswordPolicy>,
+    operation_timeouts: OperationTimeouts,
+    unindexed_sort: LdapUnindexedSort,
+    attribute_acl: AttributeAcl,
+    anonymous_bind: LdapAnonymousBind,
+    anonymous_read_attributes: Vec<String>,
+    connection_security: ConnectionSecurity,
+    require_tls: bool,
+    start_tls_requested: bool,
+    client_certificate: Option<ClientCertificate>,
+    sasl_external: Option<ClientCertificateUserMapping>,
+    sasl_scram: bool,
+    /// The SCRAM bind in progress, between the two requests of the client.
+    scram_exchange: Option<(UserId, ScramExchange)>,
 }
 
 impl<Backend: BackendHandler + LoginHandler + OpaqueHandler> LdapHandler<Backend> {
@@ -172,35 +526,245 @@ impl<Backend: BackendHandler + LoginHandler + OpaqueHandler> LdapHandler<Backend
         ignored_group_attributes: Vec<String>,
     ) -> Self {
         ldap_base_dn.make_ascii_lowercase();
+        let base_dn = parse_distinguished_name(&ldap_base_dn).unwrap_or_else(|_| {
+            panic!(
+                "Invalid value for ldap_base_dn in configuration: {}",
+                ldap_base_dn
+            )
+        });
+        let sudoers_base_dn_str = format!("ou=sudoers,{}", ldap_base_dn);
+        let sudoers_base_dn = parse_distinguished_name(&sudoers_base_dn_str).unwrap();
         Self {
             user_info: None,
             backend_handler,
             ldap_info: LdapInfo {
-                base_dn: parse_distinguished_name(&ldap_base_dn).unwrap_or_else(|_| {
-                    panic!(
-                        "Invalid value for ldap_base_dn in configuration: {}",
-                        ldap_base_dn
-                    )
-                }),
+                base_dn,
                 base_dn_str: ldap_base_dn,
+                user_rdn_attribute: UserRdnAttribute::Uid,
+                posix_options: PosixOptions::default(),
+                sudoers_base_dn,
+                sudoers_base_dn_str,
+                users_ou: "people".to_string(),
+                groups_ou: "groups".to_string(),
                 ignored_user_attributes,
                 ignored_group_attributes,
+                object_classes: LdapObjectClasses::default(),
             },
+            rate_limiter: Arc::new(NoRateLimiter),
+            peer_ip: None,
+            password_policy: Arc::new(PasswordPolicy::default()),
+            operation_timeouts: OperationTimeouts::default(),
+            unindexed_sort: LdapUnindexedSort::Allow,
+            attribute_acl: AttributeAcl::default(),
+            anonymous_bind: LdapAnonymousBind::AllowRootDseOnly,
+            anonymous_read_attributes: Vec::new(),
+            connection_security: ConnectionSecurity::Plaintext,
+            require_tls: false,
+            start_tls_requested: false,
+            client_certificate: None,
+            sasl_external: None,
+            sasl_scram: false,
+            scram_exchange: None,
+        }
+    }
+
+    /// Names the users after this attribute in their DNs.
+    pub fn with_user_rdn_attribute(mut self, user_rdn_attribute: UserRdnAttribute) -> Self {
+        self.ldap_info.user_rdn_attribute = user_rdn_attribute;
+        self
+    }
+
+    /// Fills in the POSIX attributes that the users don't have.
+    pub fn with_posix_options(mut self, posix_options: PosixOptions) -> Self {
+        self.ldap_info.posix_options = posix_options;
+        self
+    }
+
+    /// The objectClass values of the users and groups.
+    pub fn with_object_classes(mut self, object_classes: LdapObjectClasses) -> Self {
+        self.ldap_info.object_classes = object_classes;
+        self
+    }
+
+    /// Serves the users under `ou=<users_ou>,<base DN>` and the groups under
+    /// `ou=<groups_ou>,<base DN>`.
+    pub fn with_organizational_units(mut self, users_ou: &str, groups_ou: &str) -> Self {
+        self.ldap_info.users_ou = users_ou.to_ascii_lowercase();
+        self.ldap_info.groups_ou = groups_ou.to_ascii_lowercase();
+        self
+    }
+
+    /// Serves the sudo roles under this DN, lowercase and under the base DN.
+    pub fn with_sudoers_base_dn(mut self, sudoers_base_dn: String) -> Self {
+        self.ldap_info.sudoers_base_dn =
+            parse_distinguished_name(&sudoers_base_dn).unwrap_or_else(|_| {
+                panic!(
+                    "Invalid value for ldap_sudoers_base_dn in configuration: {}",
+                    sudoers_base_dn
+                )
+            });
+        self.ldap_info.sudoers_base_dn_str = sudoers_base_dn;
+        self
+    }
+
+    /// Checks the new passwords of the password modify operation.
+    pub fn with_password_policy(mut self, password_policy: Arc<PasswordPolicy>) -> Self {
+        self.password_policy = password_policy;
+        self
+    }
+
+    /// Aborts the operations that take longer, with timeLimitExceeded. The binds are not limited.
+    pub fn with_operation_timeouts(mut self, operation_timeouts: OperationTimeouts) -> Self {
+        self.operation_timeouts = operation_timeouts;
+        self
+    }
+
+    /// Whether to sort in memory the searches that the database can't sort.
+    pub fn with_unindexed_sort(mut self, unindexed_sort: LdapUnindexedSort) -> Self {
+        self.unindexed_sort = unindexed_sort;
+        self
+    }
+
+    /// Which attributes of the users the bound user can read.
+    pub fn with_attribute_acl(mut self, attribute_acl: AttributeAcl) -> Self {
+        self.attribute_acl = attribute_acl;
+        self
+    }
+
+    /// What the session can read without a bind, and the attributes it can read with
+    /// `AllowRead`.
+    pub fn with_anonymous_bind(
+        mut self,
+        anonymous_bind: LdapAnonymousBind,
+        anonymous_read_attributes: Vec<String>,
+    ) -> Self {
+        self.anonymous_bind = anonymous_bind;
+        self.anonymous_read_attributes = anonymous_read_attributes;
+        self
+    }
+
+    /// Throttles the binds of this session, counted against the address of the client.
+    pub fn with_rate_limiter(
+        mut self,
+        rate_limiter: SharedRateLimiter,
+        peer_ip: Option<IpAddr>,
+    ) -> Self {
+        self.rate_limiter = rate_limiter;
+        self.peer_ip = peer_ip;
+        self
+    }
+
+    /// Whether the connection is encrypted, and whether the binds require it to be.
+    pub fn with_connection_security(
+        mut self,
+        connection_security: ConnectionSecurity,
+        require_tls: bool,
+    ) -> Self {
+        self.connection_security = connection_security;
+        self.require_tls = require_tls;
+        self
+    }
+
+    /// The names of the certificate that the client presented in the TLS handshake.
+    pub fn with_client_certificate(
+        mut self,
+        client_certificate: Option<ClientCertificate>,
+    ) -> Self {
+        self.client_certificate = client_certificate;
+        self
+    }
+
+    /// Accepts the SASL EXTERNAL binds, as the user found with this rule in the client
+    /// certificate.
+    pub fn with_sasl_external(mut self, mapping: Option<ClientCertificateUserMapping>) -> Self {
+        self.sasl_external = mapping;
+        self
+    }
+
+    /// Accepts the SASL SCRAM-SHA-256 binds, checked against the verifiers of the passwords.
+    pub fn with_sasl_scram(mut self, sasl_scram: bool) -> Self {
+        self.sasl_scram = sasl_scram;
+        self
+    }
+
+    fn sasl_mechanisms(&self) -> Vec<&'static str> {
+        let mut mechanisms = Vec::new();
+        if self.sasl_external.is_some() {
+            mechanisms.push("EXTERNAL");
         }
+        if self.sasl_scram {
+            mechanisms.push(scram::MECHANISM);
+        }
+        mechanisms
+    }
+
+    /// Whether the last request was a successful StartTLS: the caller must then upgrade the
+    /// connection, before reading the next request.
+    pub fn take_start_tls_request(&mut self) -> bool {
+        std::mem::take(&mut self.start_tls_requested)
+    }
+
+    /// The connection was upgraded after a StartTLS, with the certificate of the client if it
+    /// presented one.
+    pub fn set_tls_established(&mut self, client_certificate: Option<ClientCertificate>) {
+        self.connection_security = ConnectionSecurity::Tls;
+        self.client_certificate = client_certificate;
+    }
+
+    /// The user of the last successful bind, if any.
+    pub fn bound_user(&self) -> Option<&UserId> {
+        self.user_info.as_ref().map(|user_info| &user_info.user)
+    }
+
+    /// Tags the changes of the next operations with the ID of the request.
+    pub fn set_correlation_id(&mut self, correlation_id: String) {
+        self.backend_handler.set_correlation_id(correlation_id);
     }
 
     #[instrument(skip_all, level = "debug")]
     pub async fn do_bind(&mut self, request: &LdapBindRequest) -> (LdapResultCode, String) {
         debug!("DN: {}", &request.dn);
-        let user_id = match get_user_id_from_distinguished_name(
+        self.scram_exchange = None;
+        if self.require_tls && self.connection_security != ConnectionSecurity::Tls {
+            return (
+                LdapResultCode::ConfidentialityRequired,
+                "The connection must be encrypted, with LDAPS or StartTLS".to_string(),
+            );
+        }
+        let LdapBindCred::Simple(password) = &request.cred;
+        if request.dn.is_empty() && password.is_empty() {
+            return self.do_anonymous_bind();
+        }
+        let start = Instant::now();
+        let user_id = match resolve_user_distinguished_name(
             &request.dn.to_ascii_lowercase(),
-            &self.ldap_info.base_dn,
-            &self.ldap_info.base_dn_str,
-        ) {
+            &self.ldap_info,
+            &self.backend_handler,
+        )
+        .await
+        {
             Ok(s) => s,
-            Err(e) => return (LdapResultCode::NamingViolation, e.to_string()),
+            Err(e) if e.code == LdapResultCode::InvalidDNSyntax => {
+                metrics::record_ldap_bind(BindResult::InvalidDn, start);
+                return (LdapResultCode::NamingViolation, e.to_string());
+            }
+            // Same as a wrong password, not to reveal which emails exist.
+            Err(_) => {
+                metrics::record_ldap_bind(BindResult::InvalidCredentials, start);
+                return (LdapResultCode::InvalidCredentials, "".to_string());
+            }
         };
-        let LdapBindCred::Simple(password) = &request.cred;
+        // Before the password check, so that a flood of binds doesn't cost a hash each.
+        if let Err(retry_after) = self.rate_limiter.check_login(self.peer_ip, &user_id).await {
+            metrics::record_ldap_bind(BindResult::Throttled, start);
+            return (
+                LdapResultCode::Busy,
+                format!(
+                    "Too many login attempts, retry in {} seconds",
+                    retry_after_seconds(retry_after)
+                ),
+            );
+        }
         match self
             .backend_handler
             .bind(BindRequest {
@@ -210,32 +774,286 @@ impl<Backend: BackendHandler + LoginHandler + OpaqueHandler> LdapHandler<Backend
             .await
         {
             Ok(()) => {
-                let user_groups = self.backend_handler.get_user_groups(&user_id).await;
-                let is_in_group = |name| {
-                    user_groups
-                        .as_ref()
-                        .map(|groups| groups.iter().any(|g| g.display_name == name))
-                        .unwrap_or(false)
-                };
-                self.user_info = Some(ValidationResults {
-                    user: user_id,
-                    permission: if is_in_group("lldap_admin") {
-                        Permission::Admin
-                    } else if is_in_group("lldap_password_manager") {
-                        Permission::PasswordManager
-                    } else if is_in_group("lldap_strict_readonly") {
-                        Permission::Readonly
-                    } else {
-                        Permission::Regular
-                    },
-                });
+                self.set_bound_user(user_id).await;
                 debug!("Success!");
+                metrics::record_ldap_bind(BindResult::Success, start);
                 (LdapResultCode::Success, "".to_string())
             }
-            Err(_) => (LdapResultCode::InvalidCredentials, "".to_string()),
+            Err(e) => bind_error_response(e, start),
+        }
+    }
+
+    /// A bind with an empty DN and password (RFC 4513, section 5.1.1) ends the authenticated
+    /// session.
+    fn do_anonymous_bind(&mut self) -> (LdapResultCode, String) {
+        if self.anonymous_bind == LdapAnonymousBind::Disallow {
+            return (
+                LdapResultCode::InappropriateAuthentication,
+                "Anonymous binds are not allowed".to_string(),
+            );
+        }
+        self.user_info = None;
+        (LdapResultCode::Success, "".to_string())
+    }
+
+    async fn set_bound_user(&mut self, user_id: UserId) {
+        let groups = self
+            .backend_handler
+            .get_user_groups(&user_id)
+            .await
+            .map(|groups| groups.into_iter().map(|g| g.display_name).collect())
+            .unwrap_or_else(|_| HashSet::new());
+        let permission = Permission::from_groups(groups.iter().map(String::as_str));
+        self.backend_handler.set_audit_actor(AuditActor {
+            user_id: user_id.clone(),
+            source: AuditSource::Ldap,
+        });
+        self.user_info = Some(ValidationResults {
+            user: user_id,
+            permission,
+            groups,
+        });
+    }
+
+    /// The user named by the client certificate, with the configured rule. An email must belong
+    /// to a single user.
+    async fn get_certificate_user(
+        &self,
+        certificate: &ClientCertificate,
+        mapping: ClientCertificateUserMapping,
+    ) -> Option<UserId> {
+        match mapping {
+            ClientCertificateUserMapping::CommonName => {
+                let user_id = UserId::new(certificate.common_name.as_deref()?);
+                self.backend_handler
+                    .get_user_details(&user_id)
+                    .await
+                    .ok()
+                    .map(|user| user.user_id)
+            }
+            ClientCertificateUserMapping::SanEmail => {
+                for email in &certificate.emails {
+                    let users = self
+                        .backend_handler
+                        .list_users(
+                            Some(UserRequestFilter::Equality(
+                                UserColumn::Email,
+                                email.clone(),
+                            )),
+                            false,
+                        )
+                        .await
+                        .ok()?;
+                    if let [user] = users.as_slice() {
+                        return Some(user.user.user_id.clone());
+                    }
+                }
+                None
+            }
+        }
+    }
+
+    /// A SASL bind, with EXTERNAL or SCRAM-SHA-256. Returns the credentials of the server, if
+    /// any.
+    #[instrument(skip_all, level = "debug")]
+    pub async fn do_sasl_bind(
+        &mut self,
+        credentials: &SaslCredentials,
+    ) -> (LdapResultCode, String, Option<Vec<u8>>) {
+        debug!("SASL mechanism: {}", &credentials.mechanism);
+        // Any other bind aborts the SCRAM exchange in progress.
+        let scram_exchange = self.scram_exchange.take();
+        match self.sasl_external {
+            Some(mapping) if credentials.mechanism.eq_ignore_ascii_case("EXTERNAL") => {
+                let (code, message) = self.do_sasl_external_bind(credentials, mapping).await;
+                (code, message, None)
+            }
+            _ if self.sasl_scram
+                && credentials.mechanism.eq_ignore_ascii_case(scram::MECHANISM) =>
+            {
+                let message = credentials.credentials.as_deref().unwrap_or_default();
+                match scram_exchange {
+                    None => self.start_scram_bind(message).await,
+                    Some((user_id, exchange)) => {
+                        self.finish_scram_bind(user_id, exchange, message).await
+                    }
+                }
+            }
+            _ => (
+                LdapResultCode::AuthMethodNotSupported,
+                format!("Unsupported SASL mechanism: {}", &credentials.mechanism),
+                None,
+            ),
+        }
+    }
+
+    /// A SASL EXTERNAL bind, with the certificate of the TLS handshake. The optional
+    /// authorization identity (`u:<user id>` or `dn:<dn>`) must be the same user.
+    async fn do_sasl_external_bind(
+        &mut self,
+        credentials: &SaslCredentials,
+        mapping: ClientCertificateUserMapping,
+    ) -> (LdapResultCode, String) {
+        let certificate = match &self.client_certificate {
+            Some(certificate) => certificate,
+            None => {
+                return (
+                    LdapResultCode::InappropriateAuthentication,
+                    "No client certificate was presented".to_string(),
+                )
+            }
+        };
+        let start = Instant::now();
+        let user_id = match self.get_certificate_user(certificate, mapping).await {
+            Some(user_id) => user_id,
+            None => {
+                metrics::record_ldap_bind(BindResult::InvalidCredentials, start);
+                return (
+                    LdapResultCode::InvalidCredentials,
+                    "No user matches the client certificate".to_string(),
+                );
+            }
+        };
+        let authz_id = credentials
+            .credentials
+            .as_deref()
+            .map(String::from_utf8_lossy)
+            .unwrap_or_default();
+        if !authz_id.is_empty() {
+            let requested_user = if let Some(user_id) = authz_id.strip_prefix("u:") {
+                Some(UserId::new(user_id))
+            } else if let Some(dn) = authz_id.strip_prefix("dn:") {
+                resolve_user_distinguished_name(
+                    &dn.to_ascii_lowercase(),
+                    &self.ldap_info,
+                    &self.backend_handler,
+                )
+                .await
+                .ok()
+            } else {
+                None
+            };
+            if requested_user.as_ref() != Some(&user_id) {
+                metrics::record_ldap_bind(BindResult::InvalidCredentials, start);
+                return (
+                    LdapResultCode::InsufficentAccessRights,
+                    format!("Cannot act as `{}`", authz_id),
+                );
+            }
+        }
+        self.set_bound_user(user_id).await;
+        debug!("Success!");
+        metrics::record_ldap_bind(BindResult::Success, start);
+        (LdapResultCode::Success, "".to_string())
+    }
+
+    /// The first request of a SCRAM bind: answers with the salt of the verifier of the user, and
+    /// waits for the proof. The users without a verifier get a fake one, that fails at the proof.
+    async fn start_scram_bind(
+        &mut self,
+        message: &[u8],
+    ) -> (LdapResultCode, String, Option<Vec<u8>>) {
+        if self.require_tls && self.connection_security != ConnectionSecurity::Tls {
+            return (
+                LdapResultCode::ConfidentialityRequired,
+                "The connection must be encrypted, with LDAPS or StartTLS".to_string(),
+                None,
+            );
+        }
+        let client_first = match ClientFirstMessage::parse(message) {
+            Ok(client_first) => client_first,
+            Err(e) => return (LdapResultCode::InvalidCredentials, e.to_string(), None),
+        };
+        let start = Instant::now();
+        let user_id = UserId::new(&client_first.username);
+        if let Some(authzid) = &client_first.authzid {
+            if UserId::new(authzid) != user_id {
+                return (
+                    LdapResultCode::InsufficentAccessRights,
+                    format!("Cannot act as `{}`", authzid),
+                    None,
+                );
+            }
+        }
+        if let Err(retry_after) = self.rate_limiter.check_login(self.peer_ip, &user_id).await {
+            metrics::record_ldap_bind(BindResult::Throttled, start);
+            return (
+                LdapResultCode::Busy,
+                format!(
+                    "Too many login attempts, retry in {} seconds",
+                    retry_after_seconds(retry_after)
+                ),
+                None,
+            );
+        }
+        let verifier = self
+            .backend_handler
+            .get_scram_verifier(&user_id)
+            .await
+            .ok()
+            .flatten()
+            .and_then(|verifier| ScramVerifier::parse(&verifier))
+            .unwrap_or_else(|| {
+                debug!(r#"No SCRAM verifier for "{}""#, &user_id);
+                ScramVerifier::unknown_user(user_id.as_str())
+            });
+        let (exchange, server_first) = ScramExchange::start(client_first, verifier);
+        self.scram_exchange = Some((user_id, exchange));
+        (
+            LdapResultCode::SaslBindInProgress,
+            "".to_string(),
+            Some(server_first.into_bytes()),
+        )
+    }
+
+    /// The second request of a SCRAM bind, with the proof of the client.
+    async fn finish_scram_bind(
+        &mut self,
+        user_id: UserId,
+        exchange: ScramExchange,
+        message: &[u8],
+    ) -> (LdapResultCode, String, Option<Vec<u8>>) {
+        let start = Instant::now();
+        let server_final = exchange.finish(message);
+        if let Err(e) = &server_final {
+            debug!(r#"SCRAM bind of "{}" failed: {}"#, &user_id, e);
+        }
+        match self
+            .backend_handler
+            .finish_scram_bind(&user_id, server_final.is_ok())
+            .await
+        {
+            Ok(()) => {
+                self.set_bound_user(user_id).await;
+                debug!("Success!");
+                metrics::record_ldap_bind(BindResult::Success, start);
+                (
+                    LdapResultCode::Success,
+                    "".to_string(),
+                    server_final.ok().map(String::into_bytes),
+                )
+            }
+            Err(e) => {
+                let (code, message) = bind_error_response(e, start);
+                (code, message, None)
+            }
         }
     }
 
+    /// Answers a SASL bind, that `ldap3_proto` can't decode.
+    pub async fn handle_sasl_bind(&mut self, credentials: &SaslCredentials) -> Vec<LdapOp> {
+        let (code, message, saslcreds) = self.do_sasl_bind(credentials).await;
+        vec![LdapOp::BindResponse(LdapBindResponse {
+            res: LdapResultOp {
+                code,
+                matcheddn: "".to_string(),
+                message,
+                referral: vec![],
+            },
+            saslcreds,
+        })]
+    }
+
     async fn change_password(&mut self, user: &UserId, password: &str) -> Result<()> {
         use lldap_auth::*;
         let mut rng = rand::rngs::OsRng;
@@ -259,69 +1077,187 @@ impl<Backend: BackendHandler + LoginHandler + OpaqueHandler> LdapHandler<Backend
         Ok(())
     }
 
-    async fn do_password_modification(
-        &mut self,
-        request: &LdapPasswordModifyRequest,
-    ) -> LdapResult<Vec<LdapOp>> {
-        let credentials = self.user_info.as_ref().ok_or_else(|| LdapError {
-            code: LdapResultCode::InsufficentAccessRights,
-            message: "No user currently bound".to_string(),
-        })?;
-        match (&request.user_identity, &request.new_password) {
-            (Some(user), Some(password)) => {
-                match get_user_id_from_distinguished_name(
-                    user,
-                    &self.ldap_info.base_dn,
-                    &self.ldap_info.base_dn_str,
-                ) {
-                    Ok(uid) => {
-                        let user_is_admin = self
-                            .backend_handler
-                            .get_user_groups(&uid)
-                            .await
-                            .map_err(|e| LdapError {
-                                code: LdapResultCode::OperationsError,
-                                message: format!(
-                                    "Internal error while requesting user's groups: {:#?}",
-                                    e
-                                ),
-                            })?
-                            .iter()
-                            .any(|g| g.display_name == "lldap_admin");
-                        if !credentials.can_change_password(&uid, user_is_admin) {
-                            Err(LdapError {
-                                code: LdapResultCode::InsufficentAccessRights,
-                                message: format!(
-                                    r#"User `{}` cannot modify the password of user `{}`"#,
-                                    &credentials.user, &uid
-                                ),
-                            })
-                        } else if let Err(e) = self.change_password(&uid, password).await {
-                            Err(LdapError {
-                                code: LdapResultCode::Other,
-                                message: format!("Error while changing the password: {:#?}", e),
-                            })
-                        } else {
-                            Ok(vec![make_extended_response(
-                                LdapResultCode::Success,
-                                "".to_string(),
-                            )])
-                        }
-                    }
-                    Err(e) => Err(LdapError {
-                        code: LdapResultCode::InvalidDNSyntax,
-                        message: format!("Invalid username: {}", e),
-                    }),
-                }
-            }
-            _ => Err(LdapError {
-                code: LdapResultCode::ConstraintViolation,
-                message: "Missing either user_id or password".to_string(),
+    /// Checks the old password given with a password modification, like a bind would.
+    async fn check_old_password(&self, user_id: &UserId, old_password: &str) -> LdapResult<()> {
+        if let Err(retry_after) = self.rate_limiter.check_login(self.peer_ip, user_id).await {
+            return Err(LdapError {
+                code: LdapResultCode::Busy,
+                message: format!(
+                    "Too many login attempts, retry in {} seconds",
+                    retry_after_seconds(retry_after)
+                ),
+            });
+        }
+        match self
+            .backend_handler
+            .bind(BindRequest {
+                name: user_id.clone(),
+                password: old_password.to_string(),
+            })
+            .await
+        {
+            // The right password, that this modification is about to replace.
+            Ok(()) | Err(DomainError::PasswordChangeRequired(_)) => Ok(()),
+            Err(DomainError::AccountLocked(_)) => Err(LdapError {
+                code: LdapResultCode::InvalidCredentials,
+                message: "Too many failed attempts, the account is temporarily locked".to_string(),
             }),
+            Err(_) => Err(LdapError {
+                code: LdapResultCode::InvalidCredentials,
+                message: "Invalid old password".to_string(),
+            }),
+        }
+    }
+
+    /// Implements the Password Modify extended operation (RFC 3062).
+    async fn do_password_modification(
+        &mut self,
+        request: &LdapPasswordModifyRequest,
+    ) -> LdapResult<Vec<LdapOp>> {
+        let credentials = self.user_info.clone().ok_or_else(|| LdapError {
+            code: LdapResultCode::InsufficentAccessRights,
+            message: "No user currently bound".to_string(),
+        })?;
+        let uid = match &request.user_identity {
+            // Without an identity, it's the password of the bound user.
+            None => credentials.user.clone(),
+            Some(user) => {
+                resolve_user_distinguished_name(user, &self.ldap_info, &self.backend_handler)
+                    .await
+                    .map_err(|e| LdapError {
+                        code: LdapResultCode::InvalidDNSyntax,
+                        message: format!("Invalid username: {}", e),
+                    })?
+            }
+        };
+        let user_is_admin = self
+            .backend_handler
+            .get_user_groups(&uid)
+            .await
+            .map_err(|e| LdapError {
+                code: LdapResultCode::OperationsError,
+                message: format!("Internal error while requesting user's groups: {:#?}", e),
+            })?
+            .iter()
+            .any(|g| g.display_name == ADMIN_GROUP_NAME);
+        if !credentials.can_change_password(&uid, user_is_admin) {
+            return Err(LdapError {
+                code: LdapResultCode::InsufficentAccessRights,
+                message: format!(
+                    r#"User `{}` cannot modify the password of user `{}`"#,
+                    &credentials.user, &uid
+                ),
+            });
+        }
+        if let Some(password) = &request.new_password {
+            self.password_policy
+                .check(password)
+                .await
+                .map_err(|e| LdapError {
+                    code: LdapResultCode::ConstraintViolation,
+                    message: e.to_string(),
+                })?;
+            // Keeping the same password wouldn't satisfy a required change.
+            if request.old_password.as_ref() == Some(password) {
+                return Err(LdapError {
+                    code: LdapResultCode::ConstraintViolation,
+                    message: "The new password must be different from the old one".to_string(),
+                });
+            }
+        }
+        if let Some(old_password) = &request.old_password {
+            self.check_old_password(&uid, old_password).await?;
+        }
+        let (password, generated) = match &request.new_password {
+            Some(password) => (password.clone(), false),
+            None => (generate_password(&self.password_policy), true),
+        };
+        self.change_password(&uid, &password)
+            .await
+            .map_err(|e| LdapError {
+                code: LdapResultCode::Other,
+                message: format!("Error while changing the password: {:#?}", e),
+            })?;
+        let mut response = make_extended_response(LdapResultCode::Success, "".to_string());
+        if generated {
+            if let LdapOp::ExtendedResponse(response) = &mut response {
+                response.value = Some(encode_generated_password(&password));
+            }
         }
+        Ok(vec![response])
+    }
+
+    fn do_start_tls(&mut self) -> LdapOp {
+        let (code, message) = match self.connection_security {
+            ConnectionSecurity::StartTlsAvailable => {
+                self.start_tls_requested = true;
+                (LdapResultCode::Success, "".to_string())
+            }
+            ConnectionSecurity::Tls => (
+                LdapResultCode::OperationsError,
+                "TLS is already established".to_string(),
+            ),
+            ConnectionSecurity::Plaintext => (
+                LdapResultCode::ProtocolError,
+                "StartTLS is not enabled".to_string(),
+            ),
+        };
+        LdapOp::ExtendedResponse(LdapExtendedResponse {
+            res: LdapResultOp {
+                code,
+                matcheddn: "".to_string(),
+                message,
+                referral: vec![],
+            },
+            name: Some(START_TLS_OID.to_string()),
+            value: None,
+        })
+    }
+
+    /// The authzId of the bound user, `dn:<DN>`, or an empty one for the anonymous sessions.
+    async fn do_whoami(&mut self) -> LdapOp {
+        let authz_id = match self
+            .user_info
+            .as_ref()
+            .map(|user_info| user_info.user.clone())
+        {
+            None => String::new(),
+            Some(user_id) => {
+                // Only the DNs named after the email need the user.
+                let email = match self.ldap_info.user_rdn_attribute {
+                    UserRdnAttribute::Mail => self
+                        .backend_handler
+                        .get_user_details(&user_id)
+                        .await
+                        .map(|user| user.email)
+                        .unwrap_or_default(),
+                    _ => String::new(),
+                };
+                format!(
+                    "dn:{}",
+                    make_user_distinguished_name(&user_id, &email, &self.ldap_info)
+                )
+            }
+        };
+        LdapOp::ExtendedResponse(LdapExtendedResponse {
+            res: LdapResultOp {
+                code: LdapResultCode::Success,
+                matcheddn: "".to_string(),
+                message: "".to_string(),
+                referral: vec![],
+            },
+            name: None,
+            value: Some(authz_id.into_bytes()),
+        })
     }
 
     async fn do_extended_request(&mut self, request: &LdapExtendedRequest) -> Vec<LdapOp> {
+        if request.name == START_TLS_OID {
+            return vec![self.do_start_tls()];
+        }
+        if request.name == WHOAMI_OID {
+            return vec![self.do_whoami().await];
+        }
         match LdapPasswordModifyRequest::try_from(request) {
             Ok(password_request) => self
                 .do_password_modification(&password_request)
@@ -334,31 +1270,460 @@ impl<Backend: BackendHandler + LoginHandler + OpaqueHandler> LdapHandler<Backend
         }
     }
 
+    /// The password is checked like a bind, only by the user or an admin: it's never read.
+    async fn do_compare_password(
+        &mut self,
+        request: &LdapCompareRequest,
+    ) -> LdapResult<LdapResultCode> {
+        let credentials = self.user_info.clone().ok_or_else(|| LdapError {
+            code: LdapResultCode::InsufficentAccessRights,
+            message: "No user currently bound".to_string(),
+        })?;
+        let uid = resolve_user_distinguished_name(
+            &request.dn.to_ascii_lowercase(),
+            &self.ldap_info,
+            &self.backend_handler,
+        )
+        .await
+        .map_err(|e| LdapError {
+            code: LdapResultCode::NoSuchObject,
+            message: format!("Invalid user DN: {}", e),
+        })?;
+        if credentials.user != uid && !credentials.is_admin() {
+            return Err(LdapError {
+                code: LdapResultCode::InsufficentAccessRights,
+                message: format!(
+                    r#"User `{}` cannot compare the password of user `{}`"#,
+                    &credentials.user, &uid
+                ),
+            });
+        }
+        let password = std::str::from_utf8(&request.val).map_err(|_| LdapError {
+            code: LdapResultCode::InvalidAttributeSyntax,
+            message: "The password is not valid UTF-8".to_string(),
+        })?;
+        match self.check_old_password(&uid, password).await {
+            Ok(()) => Ok(LdapResultCode::CompareTrue),
+            Err(LdapError {
+                code: LdapResultCode::InvalidCredentials,
+                ..
+            }) => Ok(LdapResultCode::CompareFalse),
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Implements the Compare operation with a search of the entry, so that the attributes and
+    /// the permissions are the same as for the searches.
+    pub async fn do_compare(&mut self, request: &LdapCompareRequest) -> LdapResult<Vec<LdapOp>> {
+        if request.atype.eq_ignore_ascii_case("userPassword") {
+            let code = self.do_compare_password(request).await?;
+            return Ok(vec![make_compare_result(code, "".to_string())]);
+        }
+        let search = LdapSearchRequest {
+            base: request.dn.clone(),
+            scope: LdapSearchScope::Base,
+            aliases: LdapDerefAliases::Never,
+            sizelimit: 0,
+            timelimit: 0,
+            typesonly: false,
+            filter: LdapFilter::And(vec![]),
+            attrs: vec![request.atype.clone()],
+        };
+        let entry = match self.do_search_or_dse(&search).await?.into_iter().next() {
+            Some(LdapOp::SearchResultEntry(entry)) => entry,
+            _ => {
+                return Err(LdapError {
+                    code: LdapResultCode::NoSuchObject,
+                    message: format!("No such entry: {}", request.dn),
+                })
+            }
+        };
+        let values = entry
+            .attributes
+            .iter()
+            .filter(|attribute| attribute.atype.eq_ignore_ascii_case(&request.atype))
+            .flat_map(|attribute| attribute.vals.iter())
+            .collect::<Vec<_>>();
+        if values.is_empty() {
+            return Err(LdapError {
+                code: LdapResultCode::NoSuchAttribute,
+                message: format!("No attribute {} in {}", request.atype, request.dn),
+            });
+        }
+        // The DNs and names are compared case-insensitively, like in the search filters.
+        let code = if values
+            .iter()
+            .any(|value| value.eq_ignore_ascii_case(&request.val))
+        {
+            LdapResultCode::CompareTrue
+        } else {
+            LdapResultCode::CompareFalse
+        };
+        Ok(vec![make_compare_result(code, "".to_string())])
+    }
+
     pub async fn do_search_or_dse(
         &mut self,
         request: &LdapSearchRequest,
     ) -> LdapResult<Vec<LdapOp>> {
+        self.do_paged_search_or_dse(request, None)
+            .await
+            .map(|(results, _)| results)
+    }
+
+    async fn do_paged_search_or_dse(
+        &mut self,
+        request: &LdapSearchRequest,
+        paging: Option<(usize, PagedSearchCookie)>,
+    ) -> LdapResult<(Vec<LdapOp>, Option<PagedSearchCookie>)> {
         if request.base.is_empty() && request.scope == LdapSearchScope::Base {
             if let LdapFilter::Present(attribute) = &request.filter {
                 if attribute.to_ascii_lowercase() == "objectclass" {
                     debug!("rootDSE request");
-                    return Ok(vec![
-                        root_dse_response(&self.ldap_info.base_dn_str),
-                        make_search_success(),
-                    ]);
+                    if self.user_info.is_none()
+                        && self.anonymous_bind == LdapAnonymousBind::Disallow
+                    {
+                        return Err(LdapError {
+                            code: LdapResultCode::InsufficentAccessRights,
+                            message: "No user currently bound".to_string(),
+                        });
+                    }
+                    return Ok((
+                        vec![
+                            root_dse_response(
+                                &self.ldap_info.base_dn_str,
+                                self.connection_security == ConnectionSecurity::StartTlsAvailable,
+                                &self.sasl_mechanisms(),
+                            ),
+                            make_search_success(),
+                        ],
+                        None,
+                    ));
                 }
             }
         }
-        let user_info = self.user_info.as_ref().ok_or_else(|| LdapError {
-            code: LdapResultCode::InsufficentAccessRights,
-            message: "No user currently bound".to_string(),
-        })?;
-        let user_filter = if user_info.is_admin_or_readonly() {
+        let user_filter = self.get_search_user_filter()?;
+        if self.user_info.is_none() {
+            let request = LdapSearchRequest {
+                attrs: self.get_anonymous_search_attributes(request)?,
+                ..request.clone()
+            };
+            return self.do_search(&request, user_filter, paging).await;
+        }
+        self.do_search(request, user_filter, paging).await
+    }
+
+    /// The user that a search is restricted to, unless they can see everything. The anonymous
+    /// searches, if allowed, see everything but only some attributes.
+    fn get_search_user_filter(&self) -> LdapResult<Option<UserId>> {
+        let user_info = match &self.user_info {
+            Some(user_info) => user_info,
+            None if self.anonymous_bind == LdapAnonymousBind::AllowRead => return Ok(None),
+            None => {
+                return Err(LdapError {
+                    code: LdapResultCode::InsufficentAccessRights,
+                    message: "No user currently bound".to_string(),
+                })
+            }
+        };
+        Ok(if user_info.is_admin_or_readonly() {
             None
         } else {
             Some(user_info.user.clone())
+        })
+    }
+
+    /// The attributes that an anonymous search reads: the requested ones among the readable
+    /// ones. Fails if the filter looks at the other attributes, not to reveal their values.
+    fn get_anonymous_search_attributes(
+        &self,
+        request: &LdapSearchRequest,
+    ) -> LdapResult<Vec<String>> {
+        let is_readable = |attribute: &str| {
+            attribute.eq_ignore_ascii_case("objectClass")
+                || self
+                    .anonymous_read_attributes
+                    .iter()
+                    .any(|readable| readable.eq_ignore_ascii_case(attribute))
+        };
+        if let Some(attribute) = get_filter_attributes(&request.filter)?
+            .into_iter()
+            .find(|attribute| !is_readable(attribute))
+        {
+            return Err(LdapError {
+                code: LdapResultCode::InsufficentAccessRights,
+                message: format!("Anonymous searches cannot filter on {}", attribute),
+            });
+        }
+        let attributes = if request.attrs.is_empty() || request.attrs.iter().any(|a| a == "*") {
+            self.anonymous_read_attributes.clone()
+        } else {
+            request
+                .attrs
+                .iter()
+                .filter(|attribute| is_readable(attribute))
+                .cloned()
+                .collect()
+        };
+        // An empty list would be all the attributes.
+        Ok(if attributes.is_empty() {
+            vec!["1.1".to_string()]
+        } else {
+            attributes
+        })
+    }
+
+    /// Handles the simple paged results control: the search returns at most `page_size` entries,
+    /// and the control in the response carries the cookie to get the next page, empty on the
+    /// last one.
+    async fn do_paged_search(
+        &mut self,
+        request: &LdapSearchRequest,
+        page_size: i32,
+        cookie: &[u8],
+    ) -> (Vec<LdapOp>, Vec<ResponseControl>) {
+        let make_control = |cookie: Option<PagedSearchCookie>| vec![make_paged_control(cookie)];
+        if page_size <= 0 {
+            // The client abandons the search.
+            return (vec![make_search_success()], make_control(None));
+        }
+        let result = match PagedSearchCookie::parse(cookie) {
+            Ok(cookie) => {
+                self.do_paged_search_or_dse(request, Some((page_size as usize, cookie)))
+                    .await
+            }
+            Err(e) => Err(e),
+        };
+        match result {
+            Ok((results, next_cookie)) => (results, make_control(next_cookie)),
+            Err(e) => (
+                vec![make_search_error(e.code, e.message)],
+                make_control(None),
+            ),
+        }
+    }
+
+    /// Handles the server-side sort control (RFC 2891), with or without the paged results
+    /// control. The database sorts the users by the indexed columns; the other searches are
+    /// sorted in memory, if allowed.
+    async fn do_sorted_search(
+        &mut self,
+        request: &LdapSearchRequest,
+        sort_control: &RequestControl,
+        paging: Option<(i32, &[u8])>,
+    ) -> (Vec<LdapOp>, Vec<ResponseControl>) {
+        let sort_keys = match sort_control.value.as_deref().and_then(parse_sort_keys) {
+            Some(sort_keys) => sort_keys,
+            None => {
+                return (
+                    vec![make_search_error(
+                        LdapResultCode::ProtocolError,
+                        "Invalid server-side sort control".to_string(),
+                    )],
+                    Vec::new(),
+                )
+            }
+        };
+        let user_sort = self.get_user_sort(request, &sort_keys);
+        if user_sort.is_none() && self.unindexed_sort == LdapUnindexedSort::Reject {
+            let attribute = sort_keys
+                .iter()
+                .find(|key| get_user_sort_keys(std::slice::from_ref(key)).is_none())
+                .unwrap_or(&sort_keys[0])
+                .attribute
+                .clone();
+            let sort_result = ResponseControl::SortResult {
+                code: SortResultCode::UnwillingToPerform,
+                attribute: Some(attribute.clone()),
+            };
+            if sort_control.criticality {
+                return (
+                    vec![make_search_error(
+                        LdapResultCode::UnwillingToPerform,
+                        format!("Unsupported sort by {}", attribute),
+                    )],
+                    vec![sort_result],
+                );
+            }
+            // Not critical: the results are returned unsorted.
+            let (results, mut controls) = match paging {
+                Some((size, cookie)) => self.do_paged_search(request, size, cookie).await,
+                None => (
+                    self.do_search_or_dse(request)
+                        .await
+                        .unwrap_or_else(|e: LdapError| vec![make_search_error(e.code, e.message)]),
+                    Vec::new(),
+                ),
+            };
+            controls.push(sort_result);
+            return (results, controls);
+        }
+        let page = match paging {
+            None => None,
+            // The client abandons the search.
+            Some((size, _)) if size <= 0 => {
+                return (vec![make_search_success()], vec![make_paged_control(None)])
+            }
+            Some((size, cookie)) => match cookie {
+                [] => Some((size as usize, 0)),
+                _ => match PagedSearchCookie::parse(cookie) {
+                    Ok(PagedSearchCookie::Sorted(offset)) => Some((size as usize, offset)),
+                    _ => {
+                        return (
+                            vec![make_search_error(
+                                LdapResultCode::UnwillingToPerform,
+                                "Invalid paged results cookie".to_string(),
+                            )],
+                            vec![make_paged_control(None)],
+                        )
+                    }
+                },
+            },
+        };
+        let result = match user_sort {
+            Some((filter, user_sort_keys)) => {
+                self.do_backend_sorted_search(request, &filter, user_sort_keys, page)
+                    .await
+            }
+            None => {
+                warn!(?sort_keys, base = ?request.base, "Sorting the search results in memory");
+                self.do_memory_sorted_search(request, &sort_keys, page)
+                    .await
+            }
+        };
+        let make_controls = |next_offset: Option<usize>,
+                             sort_result: Option<ResponseControl>|
+         -> Vec<ResponseControl> {
+            paging
+                .map(|_| make_paged_control(next_offset.map(PagedSearchCookie::Sorted)))
+                .into_iter()
+                .chain(sort_result)
+                .collect()
+        };
+        match result {
+            Ok((results, next_offset)) => (
+                results,
+                make_controls(
+                    next_offset,
+                    Some(ResponseControl::SortResult {
+                        code: SortResultCode::Success,
+                        attribute: None,
+                    }),
+                ),
+            ),
+            Err(e) => (
+                vec![make_search_error(e.code, e.message)],
+                make_controls(None, None),
+            ),
+        }
+    }
+
+    /// The filter and the sort keys for the backend, if the search is on the users and the
+    /// database can sort them.
+    fn get_user_sort(
+        &self,
+        request: &LdapSearchRequest,
+        sort_keys: &[SortKey],
+    ) -> Option<(LdapFilter, Vec<UserSortKey>)> {
+        let user_sort_keys = get_user_sort_keys(sort_keys)?;
+        let dn_parts = parse_distinguished_name(&request.base.to_ascii_lowercase()).ok()?;
+        let filter = match get_search_scope(&self.ldap_info, &dn_parts, &request.scope) {
+            SearchScope::Users => request.filter.clone(),
+            SearchScope::User(filter) => LdapFilter::And(vec![request.filter.clone(), filter]),
+            _ => return None,
+        };
+        Some((filter, user_sort_keys))
+    }
+
+    /// The page is the page size and the offset of the page. Also returns the offset of the next
+    /// page, if any.
+    async fn do_backend_sorted_search(
+        &mut self,
+        request: &LdapSearchRequest,
+        filter: &LdapFilter,
+        sort_keys: Vec<UserSortKey>,
+        page: Option<(usize, usize)>,
+    ) -> LdapResult<(Vec<LdapOp>, Option<usize>)> {
+        let user_filter = self.get_search_user_filter()?;
+        let attributes = match &self.user_info {
+            Some(_) => request.attrs.clone(),
+            None => self.get_anonymous_search_attributes(request)?,
+        };
+        let access = self
+            .user_info
+            .as_ref()
+            .map(|user_info| self.attribute_acl.for_user(user_info));
+        let user_filter = get_user_list_filter(user_filter.as_ref(), access.as_ref());
+        metrics::record_ldap_search(SearchScope::Users.metric_target());
+        let (offset, limit) = match page {
+            None => (0, None),
+            // One more, to know if there is a next page.
+            Some((page_size, offset)) => (offset, Some(page_size + 1)),
+        };
+        let (mut results, _) = get_user_list(
+            &self.ldap_info,
+            filter,
+            &attributes,
+            &request.base,
+            &user_filter,
+            access.as_ref(),
+            UserListing::Sorted {
+                keys: sort_keys,
+                offset,
+                limit,
+            },
+            &mut self.backend_handler,
+        )
+        .await?;
+        let next_offset = page.and_then(|(page_size, offset)| {
+            (results.len() > page_size).then(|| {
+                results.truncate(page_size);
+                offset + page_size
+            })
+        });
+        results.push(make_search_success());
+        Ok((results, next_offset))
+    }
+
+    /// Same as `do_backend_sorted_search`, sorting all the results in memory.
+    async fn do_memory_sorted_search(
+        &mut self,
+        request: &LdapSearchRequest,
+        sort_keys: &[SortKey],
+        page: Option<(usize, usize)>,
+    ) -> LdapResult<(Vec<LdapOp>, Option<usize>)> {
+        // The attributes to sort by are requested too, then removed from the results.
+        let missing_attributes = get_missing_sort_attributes(&request.attrs, sort_keys);
+        let mut request = request.clone();
+        request.attrs.extend(missing_attributes.iter().cloned());
+        let (results, _) = self.do_paged_search_or_dse(&request, None).await?;
+        let mut entries = results
+            .into_iter()
+            .filter_map(|result| match result {
+                LdapOp::SearchResultEntry(entry) => Some(entry),
+                _ => None,
+            })
+            .collect::<Vec<_>>();
+        sort_entries(&mut entries, sort_keys);
+        let (entries, next_offset) = match page {
+            None => (entries, None),
+            Some((page_size, offset)) => {
+                let next_offset = (entries.len() > offset + page_size).then(|| offset + page_size);
+                (
+                    entries.into_iter().skip(offset).take(page_size).collect(),
+                    next_offset,
+                )
+            }
         };
-        self.do_search(request, user_filter).await
+        let mut results = entries
+            .into_iter()
+            .map(|mut entry| {
+                remove_attributes(&mut entry, &missing_attributes);
+                LdapOp::SearchResultEntry(entry)
+            })
+            .collect::<Vec<_>>();
+        results.push(make_search_success());
+        Ok((results, next_offset))
     }
 
     #[instrument(skip_all, level = "debug")]
@@ -366,66 +1731,170 @@ impl<Backend: BackendHandler + LoginHandler + OpaqueHandler> LdapHandler<Backend
         &mut self,
         request: &LdapSearchRequest,
         user_filter: Option<UserId>,
-    ) -> LdapResult<Vec<LdapOp>> {
+        paging: Option<(usize, PagedSearchCookie)>,
+    ) -> LdapResult<(Vec<LdapOp>, Option<PagedSearchCookie>)> {
         let user_filter = user_filter.as_ref();
+        let access = self
+            .user_info
+            .as_ref()
+            .map(|user_info| self.attribute_acl.for_user(user_info));
+        let user_list_filter = get_user_list_filter(user_filter, access.as_ref());
         let dn_parts = parse_distinguished_name(&request.base.to_ascii_lowercase())?;
-        let scope = get_search_scope(&self.ldap_info.base_dn, &dn_parts);
-        debug!(?request.base, ?scope);
+        let scope = get_search_scope(&self.ldap_info, &dn_parts, &request.scope);
+        debug!(?request.base, ?scope, ?paging);
+        metrics::record_ldap_search(scope.metric_target());
         // Disambiguate the lifetimes.
         fn cast<T, R, B: 'a, 'a>(x: T) -> T
         where
-            T: Fn(&'a mut B, &'a LdapFilter) -> R + 'a,
+            T: Fn(&'a mut B, &'a LdapFilter, Option<Pagination>) -> R + 'a,
         {
             x
         }
 
-        let get_user_list = cast(|backend_handler: &mut Backend, filter: &LdapFilter| async {
-            get_user_list(
-                &self.ldap_info,
-                filter,
-                &request.attrs,
-                &request.base,
-                &user_filter,
-                backend_handler,
-            )
-            .await
-        });
-        let get_group_list = cast(|backend_handler: &mut Backend, filter: &LdapFilter| async {
-            get_groups_list(
-                &self.ldap_info,
-                filter,
-                &request.attrs,
-                &request.base,
-                &user_filter,
-                backend_handler,
-            )
-            .await
-        });
-        let mut results: Vec<_> = match scope {
-            SearchScope::Global => {
-                let mut results = Vec::new();
-                results.extend(get_user_list(&mut self.backend_handler, &request.filter).await?);
-                results.extend(get_group_list(&mut self.backend_handler, &request.filter).await?);
-                results
+        let get_user_list = cast(
+            |backend_handler: &mut Backend, filter: &LdapFilter, page: Option<Pagination>| async {
+                get_user_list(
+                    &self.ldap_info,
+                    filter,
+                    &request.attrs,
+                    &request.base,
+                    &user_list_filter,
+                    access.as_ref(),
+                    page.map_or(UserListing::All, UserListing::Page),
+                    backend_handler,
+                )
+                .await
+            },
+        );
+        let get_group_list = cast(
+            |backend_handler: &mut Backend, filter: &LdapFilter, page: Option<Pagination>| async {
+                get_groups_list(
+                    &self.ldap_info,
+                    filter,
+                    &request.attrs,
+                    &request.base,
+                    &user_filter,
+                    page,
+                    backend_handler,
+                )
+                .await
+            },
+        );
+        let invalid_cookie = || LdapError {
+            code: LdapResultCode::UnwillingToPerform,
+            message: "Invalid paged results cookie".to_string(),
+        };
+        let user_page = || match &paging {
+            None => Ok(None),
+            Some((page_size, PagedSearchCookie::Users(after))) => Ok(Some(Pagination {
+                after: after.clone(),
+                page_size: *page_size,
+            })),
+            Some(_) => Err(invalid_cookie()),
+        };
+        let group_page = || match &paging {
+            None => Ok(None),
+            Some((page_size, PagedSearchCookie::Groups(after))) => Ok(Some(Pagination {
+                after: after.clone(),
+                page_size: *page_size,
+            })),
+            Some(_) => Err(invalid_cookie()),
+        };
+        let next_user_page =
+            |cursor: Option<String>| cursor.map(|c| PagedSearchCookie::Users(Some(c)));
+        let next_group_page =
+            |cursor: Option<String>| cursor.map(|c| PagedSearchCookie::Groups(Some(c)));
+        let (mut results, next_cookie) = match scope {
+            SearchScope::Global => match &paging {
+                None => {
+                    let mut results = Vec::new();
+                    results.extend(
+                        get_user_list(&mut self.backend_handler, &request.filter, None)
+                            .await?
+                            .0,
+                    );
+                    results.extend(
+                        get_group_list(&mut self.backend_handler, &request.filter, None)
+                            .await?
+                            .0,
+                    );
+                    (results, None)
+                }
+                // The users come first, then the groups.
+                Some((_, PagedSearchCookie::Users(_))) => {
+                    let (results, cursor) =
+                        get_user_list(&mut self.backend_handler, &request.filter, user_page()?)
+                            .await?;
+                    (
+                        results,
+                        Some(next_user_page(cursor).unwrap_or(PagedSearchCookie::Groups(None))),
+                    )
+                }
+                Some((_, PagedSearchCookie::Groups(_))) => {
+                    let (results, cursor) =
+                        get_group_list(&mut self.backend_handler, &request.filter, group_page()?)
+                            .await?;
+                    (results, next_group_page(cursor))
+                }
+                Some((_, PagedSearchCookie::Sorted(_))) => return Err(invalid_cookie()),
+            },
+            SearchScope::Users => {
+                let (results, cursor) =
+                    get_user_list(&mut self.backend_handler, &request.filter, user_page()?).await?;
+                (results, next_user_page(cursor))
             }
-            SearchScope::Users => get_user_list(&mut self.backend_handler, &request.filter).await?,
             SearchScope::Groups => {
-                get_group_list(&mut self.backend_handler, &request.filter).await?
+                let (results, cursor) =
+                    get_group_list(&mut self.backend_handler, &request.filter, group_page()?)
+                        .await?;
+                (results, next_group_page(cursor))
             }
             SearchScope::User(filter) => {
                 let filter = LdapFilter::And(vec![request.filter.clone(), filter]);
-                get_user_list(&mut self.backend_handler, &filter).await?
+                let (results, cursor) =
+                    get_user_list(&mut self.backend_handler, &filter, user_page()?).await?;
+                (results, next_user_page(cursor))
             }
             SearchScope::Group(filter) => {
                 let filter = LdapFilter::And(vec![request.filter.clone(), filter]);
-                get_group_list(&mut self.backend_handler, &filter).await?
+                let (results, cursor) =
+                    get_group_list(&mut self.backend_handler, &filter, group_page()?).await?;
+                (results, next_group_page(cursor))
             }
+            // Few enough to be returned in one page.
+            SearchScope::SudoRoles => (
+                get_sudo_roles_list(
+                    &self.ldap_info,
+                    &request.filter,
+                    &request.attrs,
+                    &request.base,
+                    &user_filter,
+                    &mut self.backend_handler,
+                )
+                .await?,
+                None,
+            ),
+            SearchScope::SudoRole(filter) => (
+                get_sudo_roles_list(
+                    &self.ldap_info,
+                    &LdapFilter::And(vec![request.filter.clone(), filter]),
+                    &request.attrs,
+                    &request.base,
+                    &user_filter,
+                    &mut self.backend_handler,
+                )
+                .await?,
+                None,
+            ),
+            SearchScope::Empty => (Vec::new(), None),
             SearchScope::Unknown => {
                 warn!(
-                    r#"The requested search tree "{}" matches neither the user subtree "ou=people,{}" nor the group subtree "ou=groups,{}""#,
-                    &request.base, &self.ldap_info.base_dn_str, &self.ldap_info.base_dn_str
+                    r#"The requested search tree "{}" matches neither the user subtree "{}" nor the group subtree "{}""#,
+                    &request.base,
+                    self.ldap_info.users_dn_str(),
+                    self.ldap_info.groups_dn_str()
                 );
-                Vec::new()
+                (Vec::new(), None)
             }
             SearchScope::Invalid => {
                 // Search path is not in our tree, just return an empty success.
@@ -433,14 +1902,14 @@ impl<Backend: BackendHandler + LoginHandler + OpaqueHandler> LdapHandler<Backend
                     "The specified search tree {:?} is not under the common subtree {:?}",
                     &dn_parts, &self.ldap_info.base_dn
                 );
-                Vec::new()
+                (Vec::new(), None)
             }
         };
         if results.is_empty() || matches!(results[results.len() - 1], LdapOp::SearchResultEntry(_))
         {
             results.push(make_search_success());
         }
-        Ok(results)
+        Ok((results, next_cookie))
     }
 
     async fn do_create_user(&self, request: LdapAddRequest) -> LdapResult<Vec<LdapOp>> {
@@ -455,11 +1924,7 @@ impl<Backend: BackendHandler + LoginHandler + OpaqueHandler> LdapHandler<Backend
                 message: "Unauthorized write".to_string(),
             });
         }
-        let user_id = get_user_id_from_distinguished_name(
-            &request.dn,
-            &self.ldap_info.base_dn,
-            &self.ldap_info.base_dn_str,
-        )?;
+        let user_id = get_user_id_from_distinguished_name(&request.dn, &self.ldap_info)?;
         fn parse_attribute(mut attr: LdapPartialAttribute) -> LdapResult<(String, Vec<u8>)> {
             if attr.vals.len() > 1 {
                 Err(LdapError {
@@ -512,12 +1977,13 @@ impl<Backend: BackendHandler + LoginHandler + OpaqueHandler> LdapHandler<Backend
                 avatar: attributes
                     .get("avatar")
                     .map(Vec::as_slice)
-                    .map(JpegPhoto::try_from)
+                    .map(decode_avatar)
                     .transpose()
                     .map_err(|e| LdapError {
                         code: LdapResultCode::ConstraintViolation,
-                        message: format!("Invalid JPEG photo: {:#?}", e),
+                        message: format!("Invalid avatar: {}", e),
                     })?,
+                ..Default::default()
             })
             .await
             .map_err(|e| LdapError {
@@ -527,34 +1993,233 @@ impl<Backend: BackendHandler + LoginHandler + OpaqueHandler> LdapHandler<Backend
         Ok(vec![make_add_error(LdapResultCode::Success, String::new())])
     }
 
-    pub async fn handle_ldap_message(&mut self, ldap_op: LdapOp) -> Option<Vec<LdapOp>> {
-        Some(match ldap_op {
-            LdapOp::BindRequest(request) => {
-                let (code, message) = self.do_bind(&request).await;
-                vec![LdapOp::BindResponse(LdapBindResponse {
-                    res: LdapResultOp {
-                        code,
-                        matcheddn: "".to_string(),
-                        message,
-                        referral: vec![],
-                    },
-                    saslcreds: None,
-                })]
-            }
-            LdapOp::SearchRequest(request) => self
-                .do_search_or_dse(&request)
-                .await
-                .unwrap_or_else(|e: LdapError| vec![make_search_error(e.code, e.message)]),
-            LdapOp::UnbindRequest => {
-                self.user_info = None;
-                // No need to notify on unbind (per rfc4511)
-                return None;
-            }
-            LdapOp::ExtendedRequest(request) => self.do_extended_request(&request).await,
-            LdapOp::AddRequest(request) => self
-                .do_create_user(request)
-                .await
-                .unwrap_or_else(|e: LdapError| vec![make_add_error(e.code, e.message)]),
+    /// Deletes a user or a group. With the subtree delete control, the groups OU can be deleted
+    /// too: all the groups are deleted, and their memberships with them. The users OU can't,
+    /// that would delete the admins.
+    async fn do_delete(&self, dn: &str, subtree: bool) -> LdapResult<Vec<LdapOp>> {
+        if !self
+            .user_info
+            .as_ref()
+            .map(|u| u.is_admin())
+            .unwrap_or(false)
+        {
+            return Err(LdapError {
+                code: LdapResultCode::InsufficentAccessRights,
+                message: "Unauthorized write".to_string(),
+            });
+        }
+        let dn = dn.to_ascii_lowercase();
+        let dn_parts = parse_distinguished_name(&dn)?;
+        let ou_of = |ou: &String| {
+            let mut parts = vec![("ou".to_string(), ou.clone())];
+            parts.extend(self.ldap_info.base_dn.iter().cloned());
+            parts
+        };
+        let backend_error = |e: DomainError| LdapError {
+            code: LdapResultCode::OperationsError,
+            message: format!("Could not delete {}: {:#?}", dn, e),
+        };
+        if dn_parts == ou_of(&self.ldap_info.groups_ou) {
+            if !subtree {
+                return Err(LdapError {
+                    code: LdapResultCode::NotAllowedOnNonLeaf,
+                    message: format!(
+                        "{} has children, delete it with the subtree delete control",
+                        dn
+                    ),
+                });
+            }
+            let groups = self
+                .backend_handler
+                .list_groups(None)
+                .await
+                .map_err(backend_error)?;
+            for group in groups {
+                self.backend_handler
+                    .delete_group(group.id)
+                    .await
+                    .map_err(backend_error)?;
+            }
+        } else if dn_parts == ou_of(&self.ldap_info.users_ou) {
+            return Err(LdapError {
+                code: LdapResultCode::UnwillingToPerform,
+                message: format!("{} cannot be deleted", dn),
+            });
+        } else if let Ok(user_rdn) = get_user_rdn_from_distinguished_name(&dn, &self.ldap_info) {
+            let user_id = match user_rdn {
+                UserRdn::UserId(user_id) => user_id,
+                UserRdn::Email(_) => {
+                    resolve_user_distinguished_name(&dn, &self.ldap_info, &self.backend_handler)
+                        .await?
+                }
+            };
+            self.backend_handler
+                .delete_user(&user_id)
+                .await
+                .map_err(|e| match e {
+                    DomainError::EntityNotFound(_) => LdapError {
+                        code: LdapResultCode::NoSuchObject,
+                        message: format!("No such user: {}", dn),
+                    },
+                    e => backend_error(e),
+                })?;
+        } else if let Ok(group_name) = get_group_id_from_distinguished_name(&dn, &self.ldap_info) {
+            let group = self
+                .backend_handler
+                .list_groups(Some(GroupRequestFilter::DisplayName(group_name)))
+                .await
+                .map_err(backend_error)?
+                .into_iter()
+                .next()
+                .ok_or_else(|| LdapError {
+                    code: LdapResultCode::NoSuchObject,
+                    message: format!("No such group: {}", dn),
+                })?;
+            self.backend_handler
+                .delete_group(group.id)
+                .await
+                .map_err(backend_error)?;
+        } else {
+            return Err(LdapError {
+                code: LdapResultCode::NoSuchObject,
+                message: format!("No such entry: {}", dn),
+            });
+        }
+        Ok(vec![make_del_response(
+            LdapResultCode::Success,
+            String::new(),
+        )])
+    }
+
+    /// Same as `handle_ldap_message`, with the request controls: the ones decoded by
+    /// `ldap3_proto`, and all of them as sent by the client. The unknown controls are ignored,
+    /// unless they are critical. Returns the controls to attach to the last response.
+    pub async fn handle_ldap_message_with_controls(
+        &mut self,
+        ldap_op: LdapOp,
+        controls: &[LdapControl],
+        request_controls: &[RequestControl],
+    ) -> Option<(Vec<LdapOp>, Vec<ResponseControl>)> {
+        let timeout = match &ldap_op {
+            LdapOp::SearchRequest(_) | LdapOp::CompareRequest(_) => {
+                self.operation_timeouts.get(OperationKind::Search)
+            }
+            LdapOp::AddRequest(_) | LdapOp::DelRequest(_) | LdapOp::ExtendedRequest(_) => {
+                self.operation_timeouts.get(OperationKind::Modification)
+            }
+            _ => None,
+        };
+        if timeout.is_none() {
+            return self
+                .handle_ldap_message_without_timeout(ldap_op, controls, request_controls)
+                .await;
+        }
+        let request = ldap_op.clone();
+        match run_with_timeout(
+            timeout,
+            self.handle_ldap_message_without_timeout(ldap_op, controls, request_controls),
+        )
+        .await
+        {
+            Ok(response) => response,
+            Err(_) => {
+                warn!(?timeout, "LDAP operation timed out");
+                make_error_response(
+                    &request,
+                    LdapResultCode::TimeLimitExceeded,
+                    "The operation took too long".to_string(),
+                )
+                .map(|response| (vec![response], Vec::new()))
+            }
+        }
+    }
+
+    async fn handle_ldap_message_without_timeout(
+        &mut self,
+        ldap_op: LdapOp,
+        controls: &[LdapControl],
+        request_controls: &[RequestControl],
+    ) -> Option<(Vec<LdapOp>, Vec<ResponseControl>)> {
+        if let Some(control) = request_controls
+            .iter()
+            .find(|control| control.criticality && !is_supported_control(&ldap_op, &control.oid))
+        {
+            return make_error_response(
+                &ldap_op,
+                LdapResultCode::UnavailableCriticalExtension,
+                format!("Unsupported critical control: {}", control.oid),
+            )
+            .map(|response| (vec![response], Vec::new()));
+        }
+        if let LdapOp::DelRequest(dn) = &ldap_op {
+            let subtree = request_controls
+                .iter()
+                .any(|control| control.oid == SUBTREE_DELETE_OID);
+            return Some((
+                self.do_delete(dn, subtree)
+                    .await
+                    .unwrap_or_else(|e: LdapError| vec![make_del_response(e.code, e.message)]),
+                Vec::new(),
+            ));
+        }
+        if let LdapOp::SearchRequest(request) = &ldap_op {
+            let paging = controls.iter().find_map(|control| match control {
+                LdapControl::SimplePagedResults { size, cookie } => {
+                    Some((*size, cookie.as_slice()))
+                }
+                _ => None,
+            });
+            if let Some(sort_control) = request_controls
+                .iter()
+                .find(|control| control.oid == SORT_REQUEST_OID)
+            {
+                return Some(self.do_sorted_search(request, sort_control, paging).await);
+            }
+            if let Some((size, cookie)) = paging {
+                return Some(self.do_paged_search(request, size, cookie).await);
+            }
+        }
+        self.handle_ldap_message(ldap_op)
+            .await
+            .map(|results| (results, Vec::new()))
+    }
+
+    pub async fn handle_ldap_message(&mut self, ldap_op: LdapOp) -> Option<Vec<LdapOp>> {
+        Some(match ldap_op {
+            LdapOp::BindRequest(request) => {
+                let (code, message) = self.do_bind(&request).await;
+                vec![LdapOp::BindResponse(LdapBindResponse {
+                    res: LdapResultOp {
+                        code,
+                        matcheddn: "".to_string(),
+                        message,
+                        referral: vec![],
+                    },
+                    saslcreds: None,
+                })]
+            }
+            LdapOp::SearchRequest(request) => self
+                .do_search_or_dse(&request)
+                .await
+                .unwrap_or_else(|e: LdapError| vec![make_search_error(e.code, e.message)]),
+            LdapOp::UnbindRequest => {
+                self.user_info = None;
+                // No need to notify on unbind (per rfc4511)
+                return None;
+            }
+            LdapOp::ExtendedRequest(request) => self.do_extended_request(&request).await,
+            LdapOp::AddRequest(request) => self
+                .do_create_user(request)
+                .await
+                .unwrap_or_else(|e: LdapError| vec![make_add_error(e.code, e.message)]),
+            LdapOp::CompareRequest(request) => self
+                .do_compare(&request)
+                .await
+                .unwrap_or_else(|e: LdapError| vec![make_compare_result(e.code, e.message)]),
+            LdapOp::DelRequest(dn) => self
+                .do_delete(&dn, false)
+                .await
+                .unwrap_or_else(|e: LdapError| vec![make_del_response(e.code, e.message)]),
             op => vec![make_extended_response(
                 LdapResultCode::UnwillingToPerform,
                 format!("Unsupported operation: {:#?}", op),
@@ -564,10 +2229,13 @@ impl<Backend: BackendHandler + LoginHandler + OpaqueHandler> LdapHandler<Backend
 }
 
 #[cfg(test)]
-mod tests {
+pub(crate) mod tests {
     use super::*;
     use crate::{
-        domain::{error::Result, handler::*, opaque_handler::*, types::*},
+        domain::{
+            error::Result, group_rule_handler::*, handler::*, maintenance_handler::*,
+            opaque_handler::*, session_handler::*, sudo_role_handler::*, types::*,
+        },
         uuid,
     };
     use async_trait::async_trait;
@@ -585,28 +2253,70 @@ mod tests {
         #[async_trait]
         impl LoginHandler for TestBackendHandler {
             async fn bind(&self, request: BindRequest) -> Result<()>;
+            async fn get_scram_verifier(&self, user_id: &UserId) -> Result<Option<String>>;
+            async fn finish_scram_bind(&self, user_id: &UserId, valid_proof: bool) -> Result<()>;
         }
         #[async_trait]
         impl GroupBackendHandler for TestBackendHandler {
             async fn list_groups(&self, filters: Option<GroupRequestFilter>) -> Result<Vec<Group>>;
+            async fn list_groups_page(&self, filters: Option<GroupRequestFilter>, page: Pagination) -> Result<Page<Group>>;
+            async fn count_groups(&self, filters: Option<GroupRequestFilter>) -> Result<usize>;
+            async fn list_groups_with_member_counts(&self) -> Result<Vec<(GroupDetails, usize)>>;
             async fn get_group_details(&self, group_id: GroupId) -> Result<GroupDetails>;
             async fn update_group(&self, request: UpdateGroupRequest) -> Result<()>;
             async fn create_group(&self, group_name: &str) -> Result<GroupId>;
+            async fn create_group_at(&self, group_name: &str, creation_date: DateTime) -> Result<GroupId>;
             async fn delete_group(&self, group_id: GroupId) -> Result<()>;
         }
         #[async_trait]
         impl UserBackendHandler for TestBackendHandler {
             async fn list_users(&self, filters: Option<UserRequestFilter>, get_groups: bool) -> Result<Vec<UserAndGroups>>;
+            async fn list_users_page(&self, filters: Option<UserRequestFilter>, get_groups: bool, page: Pagination) -> Result<Page<UserAndGroups>>;
+        async fn list_users_sorted(&self, filters: Option<UserRequestFilter>, get_groups: bool, sort: Vec<UserSortKey>, offset: usize, limit: Option<usize>) -> Result<Vec<UserAndGroups>>;
+            async fn count_users(&self, filters: Option<UserRequestFilter>) -> Result<usize>;
             async fn get_user_details(&self, user_id: &UserId) -> Result<User>;
             async fn create_user(&self, request: CreateUserRequest) -> Result<()>;
             async fn update_user(&self, request: UpdateUserRequest) -> Result<()>;
             async fn delete_user(&self, user_id: &UserId) -> Result<()>;
+            async fn rename_user(&self, user_id: &UserId, new_user_id: &UserId) -> Result<()>;
             async fn get_user_groups(&self, user_id: &UserId) -> Result<HashSet<GroupDetails>>;
+            async fn get_users_groups(&self, user_ids: &[UserId]) -> Result<HashMap<UserId, HashSet<GroupDetails>>>;
             async fn add_user_to_group(&self, user_id: &UserId, group_id: GroupId) -> Result<()>;
             async fn remove_user_from_group(&self, user_id: &UserId, group_id: GroupId) -> Result<()>;
+            async fn add_users_to_group(&self, group_id: GroupId, user_ids: &[UserId], all_or_nothing: bool) -> Result<Vec<MembershipChange>>;
+            async fn remove_users_from_group(&self, group_id: GroupId, user_ids: &[UserId], all_or_nothing: bool) -> Result<Vec<MembershipChange>>;
+            async fn unlock_user(&self, user_id: &UserId) -> Result<()>;
+        async fn search_users(&self, query: &str, fields: &[UserColumn], limit: usize) -> Result<Vec<User>>;
+        }
+        #[async_trait]
+        impl SudoRoleBackendHandler for TestBackendHandler {
+            async fn list_sudo_roles(&self, filters: Option<SudoRoleRequestFilter>) -> Result<Vec<SudoRole>>;
+            async fn create_sudo_role(&self, request: SudoRoleRequest) -> Result<()>;
+            async fn update_sudo_role(&self, request: SudoRoleRequest) -> Result<()>;
+            async fn delete_sudo_role(&self, name: &str) -> Result<()>;
+        }
+        #[async_trait]
+        impl GroupRuleBackendHandler for TestBackendHandler {
+            async fn list_group_rules(&self) -> Result<Vec<GroupRule>>;
+            async fn create_group_rule(&self, request: CreateGroupRuleRequest) -> Result<i32>;
+            async fn delete_group_rule(&self, rule_id: i32) -> Result<()>;
+            async fn reevaluate_group_rules(&self) -> Result<usize>;
+        }
+        #[async_trait]
+        impl SessionBackendHandler for TestBackendHandler {
+            async fn list_sessions(&self, user_id: &UserId) -> Result<Vec<Session>>;
+            async fn get_session(&self, session_id: &str) -> Result<Session>;
+            async fn revoke_session(&self, session_id: &str) -> Result<()>;
+        }
+        #[async_trait]
+        impl MaintenanceBackendHandler for TestBackendHandler {
+            async fn start_maintenance(&self, dry_run: bool) -> Result<()>;
+            async fn get_maintenance_status(&self) -> Result<Option<MaintenanceStatus>>;
         }
         #[async_trait]
-        impl BackendHandler for TestBackendHandler {}
+        impl BackendHandler for TestBackendHandler {
+            async fn changed_since(&self, since: Option<DateTime>) -> Result<ChangeSet>;
+        }
         #[async_trait]
         impl OpaqueHandler for TestBackendHandler {
             async fn login_start(
@@ -632,7 +2342,7 @@ mod tests {
     ) -> LdapSearchRequest {
         LdapSearchRequest {
             base: base.to_string(),
-            scope: LdapSearchScope::Base,
+            scope: LdapSearchScope::Subtree,
             aliases: LdapDerefAliases::Never,
             sizelimit: 0,
             timelimit: 0,
@@ -737,387 +2447,1808 @@ mod tests {
         );
     }
 
+    fn make_start_tls_response(code: LdapResultCode, message: &str) -> LdapOp {
+        LdapOp::ExtendedResponse(LdapExtendedResponse {
+            res: LdapResultOp {
+                code,
+                matcheddn: "".to_string(),
+                message: message.to_string(),
+                referral: vec![],
+            },
+            name: Some(START_TLS_OID.to_string()),
+            value: None,
+        })
+    }
+
     #[tokio::test]
-    async fn test_admin_bind() {
+    async fn test_start_tls_then_bind() {
         let mut mock = MockTestBackendHandler::new();
-        mock.expect_bind()
-            .with(eq(crate::domain::handler::BindRequest {
-                name: UserId::new("test"),
-                password: "pass".to_string(),
-            }))
-            .times(1)
-            .return_once(|_| Ok(()));
+        // Only the bind after the upgrade reaches the backend.
+        mock.expect_bind().times(1).return_once(|_| Ok(()));
         mock.expect_get_user_groups()
-            .with(eq(UserId::new("test")))
-            .return_once(|_| {
-                let mut set = HashSet::new();
-                set.insert(GroupDetails {
-                    group_id: GroupId(42),
-                    display_name: "lldap_admin".to_string(),
-                    creation_date: chrono::Utc.timestamp_opt(42, 42).unwrap(),
-                    uuid: uuid!("a1a2a3a4b1b2c1c2d1d2d3d4d5d6d7d8"),
-                });
-                Ok(set)
-            });
+            .with(eq(UserId::new("bob")))
+            .return_once(|_| Ok(HashSet::new()));
         let mut ldap_handler =
-            LdapHandler::new(mock, "dc=example,dc=com".to_string(), vec![], vec![]);
-
-        let request = LdapBindRequest {
-            dn: "uid=test,ou=people,dc=example,dc=com".to_string(),
+            LdapHandler::new(mock, "dc=example,dc=com".to_string(), vec![], vec![])
+                .with_connection_security(ConnectionSecurity::StartTlsAvailable, true);
+        let bind = LdapOp::BindRequest(LdapBindRequest {
+            dn: "uid=bob,ou=people,dc=example,dc=com".to_string(),
             cred: LdapBindCred::Simple("pass".to_string()),
+        });
+        let bind_result_code = |response: Option<Vec<LdapOp>>| match response.as_deref() {
+            Some([LdapOp::BindResponse(response)]) => response.res.code,
+            _ => panic!("Unexpected response: {:?}", response),
         };
         assert_eq!(
-            ldap_handler.do_bind(&request).await.0,
+            bind_result_code(ldap_handler.handle_ldap_message(bind.clone()).await),
+            LdapResultCode::ConfidentialityRequired
+        );
+        let start_tls = LdapOp::ExtendedRequest(LdapExtendedRequest {
+            name: START_TLS_OID.to_string(),
+            value: None,
+        });
+        assert_eq!(
+            ldap_handler.handle_ldap_message(start_tls.clone()).await,
+            Some(vec![make_start_tls_response(LdapResultCode::Success, "")])
+        );
+        assert!(ldap_handler.take_start_tls_request());
+        assert!(!ldap_handler.take_start_tls_request());
+        ldap_handler.set_tls_established(None);
+        assert_eq!(
+            ldap_handler.handle_ldap_message(start_tls).await,
+            Some(vec![make_start_tls_response(
+                LdapResultCode::OperationsError,
+                "TLS is already established"
+            )])
+        );
+        assert!(!ldap_handler.take_start_tls_request());
+        assert_eq!(
+            bind_result_code(ldap_handler.handle_ldap_message(bind).await),
             LdapResultCode::Success
         );
     }
 
     #[tokio::test]
-    async fn test_search_regular_user() {
-        let mut mock = MockTestBackendHandler::new();
-        mock.expect_list_users()
-            .with(
-                eq(Some(UserRequestFilter::And(vec![
-                    UserRequestFilter::And(vec![]),
-                    UserRequestFilter::UserId(UserId::new("test")),
-                ]))),
-                eq(false),
-            )
-            .times(1)
-            .return_once(|_, _| {
-                Ok(vec![UserAndGroups {
-                    user: User {
-                        user_id: UserId::new("test"),
-                        ..Default::default()
-                    },
-                    groups: None,
-                }])
-            });
-        let mut ldap_handler = setup_bound_handler_with_group(mock, "regular").await;
-
-        let request =
-            make_user_search_request::<String>(LdapFilter::And(vec![]), vec!["1.1".to_string()]);
+    async fn test_start_tls_not_enabled() {
+        let mut ldap_handler = LdapHandler::new(
+            MockTestBackendHandler::new(),
+            "dc=example,dc=com".to_string(),
+            vec![],
+            vec![],
+        );
+        let request = LdapOp::ExtendedRequest(LdapExtendedRequest {
+            name: START_TLS_OID.to_string(),
+            value: None,
+        });
         assert_eq!(
-            ldap_handler.do_search_or_dse(&request).await,
-            Ok(vec![
-                LdapOp::SearchResultEntry(LdapSearchResultEntry {
-                    dn: "uid=test,ou=people,dc=example,dc=com".to_string(),
-                    attributes: vec![],
-                }),
-                make_search_success()
-            ]),
+            ldap_handler.handle_ldap_message(request).await,
+            Some(vec![make_start_tls_response(
+                LdapResultCode::ProtocolError,
+                "StartTLS is not enabled"
+            )])
         );
+        assert!(!ldap_handler.take_start_tls_request());
+    }
+
+    fn sasl_external(authz_id: &str) -> SaslCredentials {
+        SaslCredentials {
+            mechanism: "EXTERNAL".to_string(),
+            credentials: Some(authz_id.as_bytes().to_vec()),
+        }
+    }
+
+    fn bind_response_code(response: &[LdapOp]) -> LdapResultCode {
+        match response {
+            [LdapOp::BindResponse(response)] => response.res.code,
+            _ => panic!("Unexpected response: {:?}", response),
+        }
     }
 
     #[tokio::test]
-    async fn test_search_readonly_user() {
+    async fn test_sasl_external_bind_with_common_name() {
         let mut mock = MockTestBackendHandler::new();
-        mock.expect_list_users()
-            .with(eq(Some(UserRequestFilter::And(vec![]))), eq(false))
-            .times(1)
-            .return_once(|_, _| Ok(vec![]));
-        let mut ldap_handler = setup_bound_readonly_handler(mock).await;
-
-        let request =
-            make_user_search_request::<String>(LdapFilter::And(vec![]), vec!["1.1".to_string()]);
+        mock.expect_get_user_details()
+            .with(eq(UserId::new("bob")))
+            .returning(|_| {
+                Ok(User {
+                    user_id: UserId::new("bob"),
+                    ..Default::default()
+                })
+            });
+        mock.expect_get_user_groups()
+            .with(eq(UserId::new("bob")))
+            .return_once(|_| Ok(HashSet::new()));
+        let mut ldap_handler =
+            LdapHandler::new(mock, "dc=example,dc=com".to_string(), vec![], vec![])
+                .with_connection_security(ConnectionSecurity::Tls, true)
+                .with_sasl_external(Some(ClientCertificateUserMapping::CommonName))
+                .with_client_certificate(Some(ClientCertificate {
+                    common_name: Some("Bob".to_string()),
+                    emails: vec![],
+                }));
+        // Only the certificate's own user can be requested.
         assert_eq!(
-            ldap_handler.do_search_or_dse(&request).await,
-            Ok(vec![make_search_success()]),
+            bind_response_code(
+                &ldap_handler
+                    .handle_sasl_bind(&sasl_external("u:alice"))
+                    .await
+            ),
+            LdapResultCode::InsufficentAccessRights
+        );
+        assert_eq!(ldap_handler.bound_user(), None);
+        assert_eq!(
+            bind_response_code(&ldap_handler.handle_sasl_bind(&sasl_external("u:bob")).await),
+            LdapResultCode::Success
         );
+        assert_eq!(ldap_handler.bound_user(), Some(&UserId::new("bob")));
     }
 
     #[tokio::test]
-    async fn test_search_member_of() {
+    async fn test_sasl_external_bind_with_email() {
         let mut mock = MockTestBackendHandler::new();
         mock.expect_list_users()
-            .with(eq(Some(UserRequestFilter::And(vec![]))), eq(true))
-            .times(1)
+            .with(
+                eq(Some(UserRequestFilter::Equality(
+                    UserColumn::Email,
+                    "unknown@example.com".to_string(),
+                ))),
+                eq(false),
+            )
+            .return_once(|_, _| Ok(vec![]));
+        mock.expect_list_users()
+            .with(
+                eq(Some(UserRequestFilter::Equality(
+                    UserColumn::Email,
+                    "bob@example.com".to_string(),
+                ))),
+                eq(false),
+            )
             .return_once(|_, _| {
                 Ok(vec![UserAndGroups {
                     user: User {
                         user_id: UserId::new("bob"),
                         ..Default::default()
                     },
-                    groups: Some(vec![GroupDetails {
-                        group_id: GroupId(42),
-                        display_name: "rockstars".to_string(),
-                        creation_date: chrono::Utc.timestamp_opt(42, 42).unwrap(),
-                        uuid: uuid!("a1a2a3a4b1b2c1c2d1d2d3d4d5d6d7d8"),
-                    }]),
+                    groups: None,
                 }])
             });
-        let mut ldap_handler = setup_bound_readonly_handler(mock).await;
-
-        let request = make_user_search_request::<String>(
-            LdapFilter::And(vec![]),
-            vec!["memberOf".to_string()],
-        );
+        mock.expect_get_user_groups()
+            .with(eq(UserId::new("bob")))
+            .return_once(|_| Ok(HashSet::new()));
+        let mut ldap_handler =
+            LdapHandler::new(mock, "dc=example,dc=com".to_string(), vec![], vec![])
+                .with_connection_security(ConnectionSecurity::Tls, false)
+                .with_sasl_external(Some(ClientCertificateUserMapping::SanEmail))
+                .with_client_certificate(Some(ClientCertificate {
+                    common_name: Some("alice".to_string()),
+                    emails: vec![
+                        "unknown@example.com".to_string(),
+                        "bob@example.com".to_string(),
+                    ],
+                }));
         assert_eq!(
-            ldap_handler.do_search_or_dse(&request).await,
-            Ok(vec![
-                LdapOp::SearchResultEntry(LdapSearchResultEntry {
-                    dn: "uid=bob,ou=people,dc=example,dc=com".to_string(),
-                    attributes: vec![LdapPartialAttribute {
-                        atype: "memberOf".to_string(),
-                        vals: vec![b"uid=rockstars,ou=groups,dc=example,dc=com".to_vec()]
-                    }],
-                }),
-                make_search_success(),
-            ]),
+            bind_response_code(&ldap_handler.handle_sasl_bind(&sasl_external("")).await),
+            LdapResultCode::Success
         );
+        assert_eq!(ldap_handler.bound_user(), Some(&UserId::new("bob")));
     }
 
     #[tokio::test]
-    async fn test_search_user_as_scope() {
-        let mut mock = MockTestBackendHandler::new();
-        mock.expect_list_users()
-            .with(
-                eq(Some(UserRequestFilter::And(vec![
-                    UserRequestFilter::And(vec![]),
-                    UserRequestFilter::UserId(UserId::new("bob")),
-                ]))),
-                eq(false),
-            )
-            .times(1)
-            .return_once(|_, _| Ok(vec![]));
-        let mut ldap_handler = setup_bound_readonly_handler(mock).await;
-
-        let request = LdapSearchRequest {
-            base: "uid=bob,ou=people,Dc=example,dc=com".to_string(),
-            scope: LdapSearchScope::Base,
-            aliases: LdapDerefAliases::Never,
-            sizelimit: 0,
-            timelimit: 0,
-            typesonly: false,
-            filter: LdapFilter::And(vec![]),
-            attrs: vec!["1.1".to_string()],
+    async fn test_sasl_external_bind_rejected() {
+        let mut ldap_handler = LdapHandler::new(
+            MockTestBackendHandler::new(),
+            "dc=example,dc=com".to_string(),
+            vec![],
+            vec![],
+        )
+        .with_connection_security(ConnectionSecurity::Tls, false);
+        // Not enabled.
+        assert_eq!(
+            bind_response_code(&ldap_handler.handle_sasl_bind(&sasl_external("")).await),
+            LdapResultCode::AuthMethodNotSupported
+        );
+        let mut ldap_handler =
+            ldap_handler.with_sasl_external(Some(ClientCertificateUserMapping::CommonName));
+        // No certificate.
+        assert_eq!(
+            bind_response_code(&ldap_handler.handle_sasl_bind(&sasl_external("")).await),
+            LdapResultCode::InappropriateAuthentication
+        );
+        // Another mechanism.
+        let digest = SaslCredentials {
+            mechanism: "DIGEST-MD5".to_string(),
+            credentials: None,
         };
         assert_eq!(
-            ldap_handler.do_search_or_dse(&request).await,
-            Ok(vec![make_search_success()]),
+            bind_response_code(&ldap_handler.handle_sasl_bind(&digest).await),
+            LdapResultCode::AuthMethodNotSupported
+        );
+        assert_eq!(ldap_handler.bound_user(), None);
+    }
+
+    fn sasl_scram(message: &str) -> SaslCredentials {
+        SaslCredentials {
+            mechanism: scram::MECHANISM.to_string(),
+            credentials: Some(message.as_bytes().to_vec()),
+        }
+    }
+
+    fn server_sasl_credentials(response: &[LdapOp]) -> String {
+        match response {
+            [LdapOp::BindResponse(response)] => {
+                String::from_utf8(response.saslcreds.clone().unwrap()).unwrap()
+            }
+            _ => panic!("Unexpected response: {:?}", response),
+        }
+    }
+
+    /// Both requests of a SCRAM bind, as bob.
+    async fn scram_bind(
+        ldap_handler: &mut LdapHandler<MockTestBackendHandler>,
+        password: &str,
+    ) -> Vec<LdapOp> {
+        let client_first_bare = "n=bob,r=fyko+d2lbbFgONRv9qkxdawL";
+        let response = ldap_handler
+            .handle_sasl_bind(&sasl_scram(&format!("n,,{}", client_first_bare)))
+            .await;
+        assert_eq!(
+            bind_response_code(&response),
+            LdapResultCode::SaslBindInProgress
+        );
+        let client_final = crate::domain::scram::tests::client_final(
+            password,
+            client_first_bare,
+            &server_sasl_credentials(&response),
         );
+        ldap_handler
+            .handle_sasl_bind(&sasl_scram(&client_final))
+            .await
     }
 
     #[tokio::test]
-    async fn test_bind_invalid_dn() {
-        let mock = MockTestBackendHandler::new();
+    async fn test_sasl_scram_bind() {
+        let verifier = ScramVerifier::new("bob00").to_string();
+        let mut mock = MockTestBackendHandler::new();
+        mock.expect_get_scram_verifier()
+            .with(eq(UserId::new("bob")))
+            .returning(move |_| Ok(Some(verifier.clone())));
+        mock.expect_finish_scram_bind()
+            .with(eq(UserId::new("bob")), eq(false))
+            .return_once(|_, _| {
+                Err(DomainError::AuthenticationError(
+                    " for user 'bob'".to_string(),
+                ))
+            });
+        mock.expect_finish_scram_bind()
+            .with(eq(UserId::new("bob")), eq(true))
+            .return_once(|_, _| Ok(()));
+        mock.expect_get_user_groups()
+            .with(eq(UserId::new("bob")))
+            .return_once(|_| Ok(HashSet::new()));
         let mut ldap_handler =
-            LdapHandler::new(mock, "dc=example,dc=com".to_string(), vec![], vec![]);
+            LdapHandler::new(mock, "dc=example,dc=com".to_string(), vec![], vec![])
+                .with_sasl_scram(true);
+        assert_eq!(
+            bind_response_code(&scram_bind(&mut ldap_handler, "wrong").await),
+            LdapResultCode::InvalidCredentials
+        );
+        let response = scram_bind(&mut ldap_handler, "bob00").await;
+        assert_eq!(bind_response_code(&response), LdapResultCode::Success);
+        assert!(server_sasl_credentials(&response).starts_with("v="));
+        assert_eq!(ldap_handler.bound_user(), Some(&UserId::new("bob")));
+    }
 
-        let request = LdapBindRequest {
-            dn: "cn=bob,dc=example,dc=com".to_string(),
-            cred: LdapBindCred::Simple("pass".to_string()),
-        };
+    #[tokio::test]
+    async fn test_sasl_scram_bind_rejected() {
+        let mut ldap_handler = LdapHandler::new(
+            MockTestBackendHandler::new(),
+            "dc=example,dc=com".to_string(),
+            vec![],
+            vec![],
+        );
+        // Not enabled.
         assert_eq!(
-            ldap_handler.do_bind(&request).await.0,
-            LdapResultCode::NamingViolation,
+            bind_response_code(
+                &ldap_handler
+                    .handle_sasl_bind(&sasl_scram("n,,n=bob,r=abc"))
+                    .await
+            ),
+            LdapResultCode::AuthMethodNotSupported
         );
-        let request = LdapBindRequest {
-            dn: "uid=bob,dc=example,dc=com".to_string(),
-            cred: LdapBindCred::Simple("pass".to_string()),
-        };
+        let mut ldap_handler = ldap_handler.with_sasl_scram(true);
         assert_eq!(
-            ldap_handler.do_bind(&request).await.0,
-            LdapResultCode::NamingViolation,
+            bind_response_code(
+                &ldap_handler
+                    .handle_sasl_bind(&sasl_scram("p=tls-unique,,n=bob,r=abc"))
+                    .await
+            ),
+            LdapResultCode::InvalidCredentials
         );
-        let request = LdapBindRequest {
-            dn: "uid=bob,ou=groups,dc=example,dc=com".to_string(),
-            cred: LdapBindCred::Simple("pass".to_string()),
-        };
         assert_eq!(
-            ldap_handler.do_bind(&request).await.0,
-            LdapResultCode::NamingViolation,
+            bind_response_code(
+                &ldap_handler
+                    .handle_sasl_bind(&sasl_scram("n,a=alice,n=bob,r=abc"))
+                    .await
+            ),
+            LdapResultCode::InsufficentAccessRights
         );
+        assert_eq!(ldap_handler.bound_user(), None);
+    }
+
+    #[tokio::test]
+    async fn test_bind_rate_limited() {
+        use crate::infra::rate_limiter::{BucketConfig, InMemoryRateLimiter};
+        let mut mock = MockTestBackendHandler::new();
+        // The password is not checked once the attempts are exhausted.
+        mock.expect_bind().times(2).returning(|_| {
+            Err(DomainError::AuthenticationError(
+                " for user 'bob'".to_string(),
+            ))
+        });
+        let config = BucketConfig {
+            burst: 2,
+            refill_per_minute: 1,
+        };
+        let mut ldap_handler =
+            LdapHandler::new(mock, "dc=example,dc=com".to_string(), vec![], vec![])
+                .with_rate_limiter(
+                    Arc::new(InMemoryRateLimiter::new(config, config)),
+                    Some("10.0.0.1".parse().unwrap()),
+                );
         let request = LdapBindRequest {
-            dn: "uid=bob,ou=people,dc=example,dc=fr".to_string(),
-            cred: LdapBindCred::Simple("pass".to_string()),
+            dn: "uid=bob,ou=people,dc=example,dc=com".to_string(),
+            cred: LdapBindCred::Simple("wrong".to_string()),
         };
-        assert_eq!(
-            ldap_handler.do_bind(&request).await.0,
-            LdapResultCode::NamingViolation,
+        for _ in 0..2 {
+            assert_eq!(
+                ldap_handler.do_bind(&request).await.0,
+                LdapResultCode::InvalidCredentials
+            );
+        }
+        let (code, message) = ldap_handler.do_bind(&request).await;
+        assert_eq!(code, LdapResultCode::Busy);
+        assert!(
+            message.starts_with("Too many login attempts"),
+            "{}",
+            message
         );
+    }
+
+    #[tokio::test]
+    async fn test_admin_bind() {
+        let mut mock = MockTestBackendHandler::new();
+        mock.expect_bind()
+            .with(eq(crate::domain::handler::BindRequest {
+                name: UserId::new("test"),
+                password: "pass".to_string(),
+            }))
+            .times(1)
+            .return_once(|_| Ok(()));
+        mock.expect_get_user_groups()
+            .with(eq(UserId::new("test")))
+            .return_once(|_| {
+                let mut set = HashSet::new();
+                set.insert(GroupDetails {
+                    group_id: GroupId(42),
+                    display_name: "lldap_admin".to_string(),
+                    creation_date: chrono::Utc.timestamp_opt(42, 42).unwrap(),
+                    uuid: uuid!("a1a2a3a4b1b2c1c2d1d2d3d4d5d6d7d8"),
+                });
+                Ok(set)
+            });
+        let mut ldap_handler =
+            LdapHandler::new(mock, "dc=example,dc=com".to_string(), vec![], vec![]);
+
         let request = LdapBindRequest {
-            dn: "uid=bob=test,ou=people,dc=example,dc=com".to_string(),
+            dn: "uid=test,ou=people,dc=example,dc=com".to_string(),
             cred: LdapBindCred::Simple("pass".to_string()),
         };
         assert_eq!(
             ldap_handler.do_bind(&request).await.0,
-            LdapResultCode::NamingViolation,
+            LdapResultCode::Success
         );
     }
 
-    #[test]
-    fn test_is_subtree() {
-        let subtree1 = &[
-            ("ou".to_string(), "people".to_string()),
-            ("dc".to_string(), "example".to_string()),
-            ("dc".to_string(), "com".to_string()),
-        ];
-        let root = &[
-            ("dc".to_string(), "example".to_string()),
-            ("dc".to_string(), "com".to_string()),
-        ];
-        assert!(is_subtree(subtree1, root));
-        assert!(!is_subtree(&[], root));
-    }
+    #[tokio::test]
+    async fn test_search_regular_user() {
+        let mut mock = MockTestBackendHandler::new();
+        mock.expect_list_users()
+            .with(
+                eq(Some(UserRequestFilter::And(vec![
+                    UserRequestFilter::And(vec![]),
+                    UserRequestFilter::UserId(UserId::new("test")),
+                ]))),
+                eq(false),
+            )
+            .times(1)
+            .return_once(|_, _| {
+                Ok(vec![UserAndGroups {
+                    user: User {
+                        user_id: UserId::new("test"),
+                        ..Default::default()
+                    },
+                    groups: None,
+                }])
+            });
+        let mut ldap_handler = setup_bound_handler_with_group(mock, "regular").await;
 
-    #[test]
-    fn test_parse_distinguished_name() {
-        let parsed_dn = &[
-            ("ou".to_string(), "people".to_string()),
-            ("dc".to_string(), "example".to_string()),
-            ("dc".to_string(), "com".to_string()),
-        ];
+        let request =
+            make_user_search_request::<String>(LdapFilter::And(vec![]), vec!["1.1".to_string()]);
         assert_eq!(
-            parse_distinguished_name("ou=people,dc=example,dc=com").expect("parsing failed"),
-            parsed_dn
+            ldap_handler.do_search_or_dse(&request).await,
+            Ok(vec![
+                LdapOp::SearchResultEntry(LdapSearchResultEntry {
+                    dn: "uid=test,ou=people,dc=example,dc=com".to_string(),
+                    attributes: vec![],
+                }),
+                make_search_success()
+            ]),
         );
+    }
+
+    #[tokio::test]
+    async fn test_search_readonly_user() {
+        let mut mock = MockTestBackendHandler::new();
+        mock.expect_list_users()
+            .with(eq(Some(UserRequestFilter::And(vec![]))), eq(false))
+            .times(1)
+            .return_once(|_, _| Ok(vec![]));
+        let mut ldap_handler = setup_bound_readonly_handler(mock).await;
+
+        let request =
+            make_user_search_request::<String>(LdapFilter::And(vec![]), vec!["1.1".to_string()]);
         assert_eq!(
-            parse_distinguished_name(" ou  = people , dc = example , dc =  com ")
-                .expect("parsing failed"),
-            parsed_dn
+            ldap_handler.do_search_or_dse(&request).await,
+            Ok(vec![make_search_success()]),
         );
     }
 
     #[tokio::test]
-    async fn test_search_users() {
-        use chrono::prelude::*;
+    async fn test_paged_search() {
         let mut mock = MockTestBackendHandler::new();
-        mock.expect_list_users().times(1).return_once(|_, _| {
-            Ok(vec![
-                UserAndGroups {
-                    user: User {
-                        user_id: UserId::new("bob_1"),
-                        email: "bob@bobmail.bob".to_string(),
-                        display_name: Some("Bôb Böbberson".to_string()),
-                        first_name: Some("Bôb".to_string()),
-                        last_name: Some("Böbberson".to_string()),
-                        uuid: uuid!("698e1d5f-7a40-3151-8745-b9b8a37839da"),
-                        ..Default::default()
-                    },
-                    groups: None,
-                },
-                UserAndGroups {
-                    user: User {
-                        user_id: UserId::new("jim"),
-                        email: "jim@cricket.jim".to_string(),
-                        display_name: Some("Jimminy Cricket".to_string()),
-                        first_name: Some("Jim".to_string()),
-                        last_name: Some("Cricket".to_string()),
-                        avatar: Some(JpegPhoto::for_tests()),
-                        uuid: uuid!("04ac75e0-2900-3e21-926c-2f732c26b3fc"),
-                        creation_date: Utc.with_ymd_and_hms(2014, 7, 8, 9, 10, 11).unwrap(),
-                    },
-                    groups: None,
-                },
-            ])
-        });
+        let make_user = |name: &str| UserAndGroups {
+            user: User {
+                user_id: UserId::new(name),
+                ..Default::default()
+            },
+            groups: None,
+        };
+        mock.expect_list_users_page()
+            .with(
+                eq(Some(UserRequestFilter::And(vec![]))),
+                eq(false),
+                eq(Pagination {
+                    after: None,
+                    page_size: 2,
+                }),
+            )
+            .times(1)
+            .return_once(move |_, _, _| {
+                Ok(Page {
+                    items: vec![make_user("alice"), make_user("bob")],
+                    next_cursor: Some("Ym9i".to_owned()),
+                })
+            });
+        mock.expect_list_users_page()
+            .with(
+                eq(Some(UserRequestFilter::And(vec![]))),
+                eq(false),
+                eq(Pagination {
+                    after: Some("Ym9i".to_owned()),
+                    page_size: 2,
+                }),
+            )
+            .times(1)
+            .return_once(move |_, _, _| {
+                Ok(Page {
+                    items: vec![make_user("carol")],
+                    next_cursor: None,
+                })
+            });
         let mut ldap_handler = setup_bound_admin_handler(mock).await;
-        let request = make_user_search_request(
+        let request = LdapOp::SearchRequest(make_user_search_request::<String>(
             LdapFilter::And(vec![]),
-            vec![
-                "objectClass",
-                "dn",
-                "uid",
-                "mail",
-                "givenName",
-                "sn",
-                "cn",
-                "createTimestamp",
-                "entryUuid",
-                "jpegPhoto",
+            vec!["1.1".to_string()],
+        ));
+        let make_entry = |name: &str| {
+            LdapOp::SearchResultEntry(LdapSearchResultEntry {
+                dn: format!("uid={},ou=people,dc=example,dc=com", name),
+                attributes: vec![],
+            })
+        };
+        assert_eq!(
+            ldap_handler
+                .handle_ldap_message_with_controls(
+                    request.clone(),
+                    &[LdapControl::SimplePagedResults {
+                        size: 2,
+                        cookie: vec![],
+                    }],
+                    &[],
+                )
+                .await,
+            Some((
+                vec![
+                    make_entry("alice"),
+                    make_entry("bob"),
+                    make_search_success()
+                ],
+                vec![ResponseControl::Ldap(LdapControl::SimplePagedResults {
+                    size: 0,
+                    cookie: b"users:Ym9i".to_vec(),
+                })]
+            ))
+        );
+        assert_eq!(
+            ldap_handler
+                .handle_ldap_message_with_controls(
+                    request.clone(),
+                    &[LdapControl::SimplePagedResults {
+                        size: 2,
+                        cookie: b"users:Ym9i".to_vec(),
+                    }],
+                    &[],
+                )
+                .await,
+            Some((
+                vec![make_entry("carol"), make_search_success()],
+                vec![ResponseControl::Ldap(LdapControl::SimplePagedResults {
+                    size: 0,
+                    cookie: vec![],
+                })]
+            ))
+        );
+        // A cookie from another search is rejected.
+        assert_eq!(
+            ldap_handler
+                .handle_ldap_message_with_controls(
+                    request,
+                    &[LdapControl::SimplePagedResults {
+                        size: 2,
+                        cookie: b"groups:".to_vec(),
+                    }],
+                    &[],
+                )
+                .await,
+            Some((
+                vec![make_search_error(
+                    LdapResultCode::UnwillingToPerform,
+                    "Invalid paged results cookie".to_string()
+                )],
+                vec![ResponseControl::Ldap(LdapControl::SimplePagedResults {
+                    size: 0,
+                    cookie: vec![],
+                })]
+            ))
+        );
+    }
+
+    fn sort_control(keys: &[(&str, bool)], criticality: bool) -> RequestControl {
+        let element = |tag: u8, contents: &[u8]| {
+            let mut element = vec![tag, contents.len() as u8];
+            element.extend(contents);
+            element
+        };
+        let keys = keys
+            .iter()
+            .map(|(attribute, reverse)| {
+                let mut key = element(0x04, attribute.as_bytes());
+                if *reverse {
+                    key.extend(element(0x81, &[0xFF]));
+                }
+                element(0x30, &key)
+            })
+            .collect::<Vec<_>>()
+            .concat();
+        RequestControl {
+            oid: SORT_REQUEST_OID.to_string(),
+            criticality,
+            value: Some(element(0x30, &keys)),
+        }
+    }
+
+    fn sort_success() -> ResponseControl {
+        ResponseControl::SortResult {
+            code: SortResultCode::Success,
+            attribute: None,
+        }
+    }
+
+    #[tokio::test]
+    async fn test_sorted_search_by_backend() {
+        let mut mock = MockTestBackendHandler::new();
+        let make_user = |name: &str| UserAndGroups {
+            user: User {
+                user_id: UserId::new(name),
+                ..Default::default()
+            },
+            groups: None,
+        };
+        let sort_keys = vec![UserSortKey {
+            column: UserColumn::Email,
+            descending: true,
+        }];
+        mock.expect_list_users_sorted()
+            .with(
+                eq(Some(UserRequestFilter::And(vec![]))),
+                eq(false),
+                eq(sort_keys.clone()),
+                eq(0),
+                eq(None),
+            )
+            .times(1)
+            .return_once(move |_, _, _, _, _| Ok(vec![make_user("bob"), make_user("alice")]));
+        mock.expect_list_users_sorted()
+            .with(
+                eq(Some(UserRequestFilter::And(vec![]))),
+                eq(false),
+                eq(sort_keys),
+                eq(1),
+                eq(Some(2)),
+            )
+            .times(1)
+            .return_once(move |_, _, _, _, _| Ok(vec![make_user("alice")]));
+        let mut ldap_handler = setup_bound_admin_handler(mock).await;
+        let request = LdapOp::SearchRequest(make_user_search_request::<String>(
+            LdapFilter::And(vec![]),
+            vec!["1.1".to_string()],
+        ));
+        let make_entry = |name: &str| {
+            LdapOp::SearchResultEntry(LdapSearchResultEntry {
+                dn: format!("uid={},ou=people,dc=example,dc=com", name),
+                attributes: vec![],
+            })
+        };
+        assert_eq!(
+            ldap_handler
+                .handle_ldap_message_with_controls(
+                    request.clone(),
+                    &[],
+                    &[sort_control(&[("mail", true)], true)],
+                )
+                .await,
+            Some((
+                vec![
+                    make_entry("bob"),
+                    make_entry("alice"),
+                    make_search_success()
+                ],
+                vec![sort_success()]
+            ))
+        );
+        // With the paged results, the cookie is the offset of the next page.
+        assert_eq!(
+            ldap_handler
+                .handle_ldap_message_with_controls(
+                    request,
+                    &[LdapControl::SimplePagedResults {
+                        size: 1,
+                        cookie: b"sorted:1".to_vec(),
+                    }],
+                    &[sort_control(&[("mail", true)], true)],
+                )
+                .await,
+            Some((
+                vec![make_entry("alice"), make_search_success()],
+                vec![
+                    ResponseControl::Ldap(LdapControl::SimplePagedResults {
+                        size: 0,
+                        cookie: vec![],
+                    }),
+                    sort_success()
+                ]
+            ))
+        );
+    }
+
+    #[tokio::test]
+    async fn test_sorted_search_in_memory() {
+        let mut mock = MockTestBackendHandler::new();
+        let make_user = |name: &str, last_name: Option<&str>| UserAndGroups {
+            user: User {
+                user_id: UserId::new(name),
+                last_name: last_name.map(str::to_owned),
+                ..Default::default()
+            },
+            groups: None,
+        };
+        mock.expect_list_users()
+            .with(eq(Some(UserRequestFilter::And(vec![]))), eq(false))
+            .times(1)
+            .return_once(move |_, _| {
+                Ok(vec![
+                    make_user("alice", Some("Zed")),
+                    make_user("bob", None),
+                    make_user("carol", Some("Adams")),
+                ])
+            });
+        let mut ldap_handler = setup_bound_admin_handler(mock).await;
+        let make_entry = |name: &str| {
+            LdapOp::SearchResultEntry(LdapSearchResultEntry {
+                dn: format!("uid={},ou=people,dc=example,dc=com", name),
+                attributes: vec![LdapPartialAttribute {
+                    atype: "uid".to_string(),
+                    vals: vec![name.as_bytes().to_vec()],
+                }],
+            })
+        };
+        // The second page, without the sn that wasn't requested.
+        assert_eq!(
+            ldap_handler
+                .handle_ldap_message_with_controls(
+                    LdapOp::SearchRequest(make_user_search_request(
+                        LdapFilter::And(vec![]),
+                        vec!["uid"],
+                    )),
+                    &[LdapControl::SimplePagedResults {
+                        size: 2,
+                        cookie: b"sorted:1".to_vec(),
+                    }],
+                    &[sort_control(&[("sn", false)], false)],
+                )
+                .await,
+            Some((
+                vec![
+                    make_entry("alice"),
+                    make_entry("bob"),
+                    make_search_success()
+                ],
+                vec![
+                    ResponseControl::Ldap(LdapControl::SimplePagedResults {
+                        size: 0,
+                        cookie: vec![],
+                    }),
+                    sort_success()
+                ]
+            ))
+        );
+    }
+
+    #[tokio::test]
+    async fn test_sorted_search_rejected() {
+        let mut mock = MockTestBackendHandler::new();
+        mock.expect_list_groups()
+            .with(eq(Some(GroupRequestFilter::And(vec![]))))
+            .times(1)
+            .return_once(|_| Ok(vec![]));
+        let mut ldap_handler = setup_bound_admin_handler(mock)
+            .await
+            .with_unindexed_sort(LdapUnindexedSort::Reject);
+        let request = LdapOp::SearchRequest(make_search_request::<String>(
+            "ou=groups,dc=example,dc=com",
+            LdapFilter::And(vec![]),
+            vec![],
+        ));
+        let sort_error = ResponseControl::SortResult {
+            code: SortResultCode::UnwillingToPerform,
+            attribute: Some("cn".to_string()),
+        };
+        assert_eq!(
+            ldap_handler
+                .handle_ldap_message_with_controls(
+                    request.clone(),
+                    &[],
+                    &[sort_control(&[("cn", false)], true)],
+                )
+                .await,
+            Some((
+                vec![make_search_error(
+                    LdapResultCode::UnwillingToPerform,
+                    "Unsupported sort by cn".to_string()
+                )],
+                vec![sort_error.clone()]
+            ))
+        );
+        // Not critical: the results are returned unsorted.
+        assert_eq!(
+            ldap_handler
+                .handle_ldap_message_with_controls(
+                    request,
+                    &[],
+                    &[sort_control(&[("cn", false)], false)],
+                )
+                .await,
+            Some((vec![make_search_success()], vec![sort_error]))
+        );
+    }
+
+    #[tokio::test]
+    async fn test_search_member_of() {
+        let mut mock = MockTestBackendHandler::new();
+        mock.expect_list_users()
+            .with(eq(Some(UserRequestFilter::And(vec![]))), eq(true))
+            .times(1)
+            .return_once(|_, _| {
+                Ok(vec![UserAndGroups {
+                    user: User {
+                        user_id: UserId::new("bob"),
+                        ..Default::default()
+                    },
+                    groups: Some(vec![GroupDetails {
+                        group_id: GroupId(42),
+                        display_name: "rockstars".to_string(),
+                        creation_date: chrono::Utc.timestamp_opt(42, 42).unwrap(),
+                        uuid: uuid!("a1a2a3a4b1b2c1c2d1d2d3d4d5d6d7d8"),
+                    }]),
+                }])
+            });
+        let mut ldap_handler = setup_bound_readonly_handler(mock).await;
+
+        let request = make_user_search_request::<String>(
+            LdapFilter::And(vec![]),
+            vec!["memberOf".to_string()],
+        );
+        assert_eq!(
+            ldap_handler.do_search_or_dse(&request).await,
+            Ok(vec![
+                LdapOp::SearchResultEntry(LdapSearchResultEntry {
+                    dn: "uid=bob,ou=people,dc=example,dc=com".to_string(),
+                    attributes: vec![LdapPartialAttribute {
+                        atype: "memberOf".to_string(),
+                        vals: vec![b"uid=rockstars,ou=groups,dc=example,dc=com".to_vec()]
+                    }],
+                }),
+                make_search_success(),
+            ]),
+        );
+    }
+
+    #[tokio::test]
+    async fn test_compare_member_of() {
+        let mut mock = MockTestBackendHandler::new();
+        mock.expect_list_users()
+            .with(
+                eq(Some(UserRequestFilter::And(vec![
+                    UserRequestFilter::And(vec![]),
+                    UserRequestFilter::UserId(UserId::new("bob")),
+                ]))),
+                eq(true),
+            )
+            .times(2)
+            .returning(|_, _| {
+                Ok(vec![UserAndGroups {
+                    user: User {
+                        user_id: UserId::new("bob"),
+                        ..Default::default()
+                    },
+                    groups: Some(vec![GroupDetails {
+                        group_id: GroupId(42),
+                        display_name: "rockstars".to_string(),
+                        creation_date: chrono::Utc.timestamp_opt(42, 42).unwrap(),
+                        uuid: uuid!("a1a2a3a4b1b2c1c2d1d2d3d4d5d6d7d8"),
+                    }]),
+                }])
+            });
+        mock.expect_list_users()
+            .with(
+                eq(Some(UserRequestFilter::And(vec![
+                    UserRequestFilter::And(vec![]),
+                    UserRequestFilter::UserId(UserId::new("bob")),
+                ]))),
+                eq(false),
+            )
+            .times(1)
+            .return_once(|_, _| {
+                Ok(vec![UserAndGroups {
+                    user: User {
+                        user_id: UserId::new("bob"),
+                        ..Default::default()
+                    },
+                    groups: None,
+                }])
+            });
+        let mut ldap_handler = setup_bound_readonly_handler(mock).await;
+        let compare = |value: &str| {
+            LdapOp::CompareRequest(LdapCompareRequest {
+                dn: "uid=bob,ou=people,dc=example,dc=com".to_string(),
+                atype: "memberOf".to_string(),
+                val: value.as_bytes().to_vec(),
+            })
+        };
+        assert_eq!(
+            ldap_handler
+                .handle_ldap_message(compare("uid=RockStars,ou=groups,dc=example,dc=com"))
+                .await,
+            Some(vec![make_compare_result(
+                LdapResultCode::CompareTrue,
+                "".to_string()
+            )])
+        );
+        assert_eq!(
+            ldap_handler
+                .handle_ldap_message(compare("uid=admins,ou=groups,dc=example,dc=com"))
+                .await,
+            Some(vec![make_compare_result(
+                LdapResultCode::CompareFalse,
+                "".to_string()
+            )])
+        );
+        // Bob has no first name.
+        let request = LdapOp::CompareRequest(LdapCompareRequest {
+            dn: "uid=bob,ou=people,dc=example,dc=com".to_string(),
+            atype: "givenName".to_string(),
+            val: b"Bob".to_vec(),
+        });
+        assert!(matches!(
+            ldap_handler.handle_ldap_message(request).await.as_deref(),
+            Some([LdapOp::CompareResult(LdapResultOp {
+                code: LdapResultCode::NoSuchAttribute,
+                ..
+            })])
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_compare_password() {
+        let mut mock = MockTestBackendHandler::new();
+        mock.expect_bind()
+            .with(eq(BindRequest {
+                name: UserId::new("bob"),
+                password: "bob_password".to_string(),
+            }))
+            .times(1)
+            .return_once(|_| Ok(()));
+        mock.expect_bind()
+            .with(eq(BindRequest {
+                name: UserId::new("bob"),
+                password: "wrong".to_string(),
+            }))
+            .times(1)
+            .return_once(|_| {
+                Err(DomainError::AuthenticationError(
+                    " for user 'bob'".to_string(),
+                ))
+            });
+        let mut ldap_handler = setup_bound_admin_handler(mock).await;
+        let compare = |password: &str| {
+            LdapOp::CompareRequest(LdapCompareRequest {
+                dn: "uid=bob,ou=people,dc=example,dc=com".to_string(),
+                atype: "userPassword".to_string(),
+                val: password.as_bytes().to_vec(),
+            })
+        };
+        assert_eq!(
+            ldap_handler
+                .handle_ldap_message(compare("bob_password"))
+                .await,
+            Some(vec![make_compare_result(
+                LdapResultCode::CompareTrue,
+                "".to_string()
+            )])
+        );
+        assert_eq!(
+            ldap_handler.handle_ldap_message(compare("wrong")).await,
+            Some(vec![make_compare_result(
+                LdapResultCode::CompareFalse,
+                "".to_string()
+            )])
+        );
+    }
+
+    #[tokio::test]
+    async fn test_compare_password_of_another_user() {
+        let mut ldap_handler = setup_bound_readonly_handler(MockTestBackendHandler::new()).await;
+        let request = LdapOp::CompareRequest(LdapCompareRequest {
+            dn: "uid=bob,ou=people,dc=example,dc=com".to_string(),
+            atype: "userPassword".to_string(),
+            val: b"bob_password".to_vec(),
+        });
+        assert!(matches!(
+            ldap_handler.handle_ldap_message(request).await.as_deref(),
+            Some([LdapOp::CompareResult(LdapResultOp {
+                code: LdapResultCode::InsufficentAccessRights,
+                ..
+            })])
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_search_user_as_scope() {
+        let mut mock = MockTestBackendHandler::new();
+        mock.expect_list_users()
+            .with(
+                eq(Some(UserRequestFilter::And(vec![
+                    UserRequestFilter::And(vec![]),
+                    UserRequestFilter::UserId(UserId::new("bob")),
+                ]))),
+                eq(false),
+            )
+            .times(1)
+            .return_once(|_, _| Ok(vec![]));
+        let mut ldap_handler = setup_bound_readonly_handler(mock).await;
+
+        let request = LdapSearchRequest {
+            base: "uid=bob,ou=people,Dc=example,dc=com".to_string(),
+            scope: LdapSearchScope::Base,
+            aliases: LdapDerefAliases::Never,
+            sizelimit: 0,
+            timelimit: 0,
+            typesonly: false,
+            filter: LdapFilter::And(vec![]),
+            attrs: vec!["1.1".to_string()],
+        };
+        assert_eq!(
+            ldap_handler.do_search_or_dse(&request).await,
+            Ok(vec![make_search_success()]),
+        );
+    }
+
+    #[tokio::test]
+    async fn test_bind_invalid_dn() {
+        let mock = MockTestBackendHandler::new();
+        let mut ldap_handler =
+            LdapHandler::new(mock, "dc=example,dc=com".to_string(), vec![], vec![]);
+
+        let request = LdapBindRequest {
+            dn: "cn=bob,dc=example,dc=com".to_string(),
+            cred: LdapBindCred::Simple("pass".to_string()),
+        };
+        assert_eq!(
+            ldap_handler.do_bind(&request).await.0,
+            LdapResultCode::NamingViolation,
+        );
+        let request = LdapBindRequest {
+            dn: "uid=bob,dc=example,dc=com".to_string(),
+            cred: LdapBindCred::Simple("pass".to_string()),
+        };
+        assert_eq!(
+            ldap_handler.do_bind(&request).await.0,
+            LdapResultCode::NamingViolation,
+        );
+        let request = LdapBindRequest {
+            dn: "uid=bob,ou=groups,dc=example,dc=com".to_string(),
+            cred: LdapBindCred::Simple("pass".to_string()),
+        };
+        assert_eq!(
+            ldap_handler.do_bind(&request).await.0,
+            LdapResultCode::NamingViolation,
+        );
+        let request = LdapBindRequest {
+            dn: "uid=bob,ou=people,dc=example,dc=fr".to_string(),
+            cred: LdapBindCred::Simple("pass".to_string()),
+        };
+        assert_eq!(
+            ldap_handler.do_bind(&request).await.0,
+            LdapResultCode::NamingViolation,
+        );
+        let request = LdapBindRequest {
+            dn: "uid=bob=test,ou=people,dc=example,dc=com".to_string(),
+            cred: LdapBindCred::Simple("pass".to_string()),
+        };
+        assert_eq!(
+            ldap_handler.do_bind(&request).await.0,
+            LdapResultCode::NamingViolation,
+        );
+    }
+
+    #[test]
+    fn test_is_subtree() {
+        let subtree1 = &[
+            ("ou".to_string(), "people".to_string()),
+            ("dc".to_string(), "example".to_string()),
+            ("dc".to_string(), "com".to_string()),
+        ];
+        let root = &[
+            ("dc".to_string(), "example".to_string()),
+            ("dc".to_string(), "com".to_string()),
+        ];
+        assert!(is_subtree(subtree1, root));
+        assert!(!is_subtree(&[], root));
+    }
+
+    #[test]
+    fn test_parse_distinguished_name() {
+        let parsed_dn = &[
+            ("ou".to_string(), "people".to_string()),
+            ("dc".to_string(), "example".to_string()),
+            ("dc".to_string(), "com".to_string()),
+        ];
+        assert_eq!(
+            parse_distinguished_name("ou=people,dc=example,dc=com").expect("parsing failed"),
+            parsed_dn
+        );
+        assert_eq!(
+            parse_distinguished_name(" ou  = people , dc = example , dc =  com ")
+                .expect("parsing failed"),
+            parsed_dn
+        );
+    }
+
+    #[tokio::test]
+    async fn test_search_users() {
+        use chrono::prelude::*;
+        let mut mock = MockTestBackendHandler::new();
+        mock.expect_list_users().times(1).return_once(|_, _| {
+            Ok(vec![
+                UserAndGroups {
+                    user: User {
+                        user_id: UserId::new("bob_1"),
+                        email: "bob@bobmail.bob".to_string(),
+                        display_name: Some("Bôb Böbberson".to_string()),
+                        first_name: Some("Bôb".to_string()),
+                        last_name: Some("Böbberson".to_string()),
+                        uuid: uuid!("698e1d5f-7a40-3151-8745-b9b8a37839da"),
+                        ..Default::default()
+                    },
+                    groups: None,
+                },
+                UserAndGroups {
+                    user: User {
+                        user_id: UserId::new("jim"),
+                        email: "jim@cricket.jim".to_string(),
+                        display_name: Some("Jimminy Cricket".to_string()),
+                        first_name: Some("Jim".to_string()),
+                        last_name: Some("Cricket".to_string()),
+                        avatar: Some(JpegPhoto::for_tests()),
+                        uuid: uuid!("04ac75e0-2900-3e21-926c-2f732c26b3fc"),
+                        creation_date: Utc.with_ymd_and_hms(2014, 7, 8, 9, 10, 11).unwrap(),
+                        modified_at: Utc.with_ymd_and_hms(2023, 1, 2, 3, 4, 5).unwrap(),
+                        uid_number: None,
+                        gid_number: None,
+                        home_directory: None,
+                        login_shell: None,
+                        email_verified: false,
+                        user_id_display: None,
+                    },
+                    groups: None,
+                },
+            ])
+        });
+        let mut ldap_handler = setup_bound_admin_handler(mock).await;
+        let request = make_user_search_request(
+            LdapFilter::And(vec![]),
+            vec![
+                "objectClass",
+                "dn",
+                "uid",
+                "mail",
+                "givenName",
+                "sn",
+                "cn",
+                "createTimestamp",
+                "modifyTimestamp",
+                "entryUuid",
+                "jpegPhoto",
+            ],
+        );
+        assert_eq!(
+            ldap_handler.do_search_or_dse(&request).await,
+            Ok(vec![
+                LdapOp::SearchResultEntry(LdapSearchResultEntry {
+                    dn: "uid=bob_1,ou=people,dc=example,dc=com".to_string(),
+                    attributes: vec![
+                        LdapPartialAttribute {
+                            atype: "objectClass".to_string(),
+                            vals: vec![
+                                b"inetOrgPerson".to_vec(),
+                                b"posixAccount".to_vec(),
+                                b"mailAccount".to_vec(),
+                                b"person".to_vec()
+                            ]
+                        },
+                        LdapPartialAttribute {
+                            atype: "uid".to_string(),
+                            vals: vec![b"bob_1".to_vec()]
+                        },
+                        LdapPartialAttribute {
+                            atype: "mail".to_string(),
+                            vals: vec![b"bob@bobmail.bob".to_vec()]
+                        },
+                        LdapPartialAttribute {
+                            atype: "givenName".to_string(),
+                            vals: vec!["Bôb".to_string().into_bytes()]
+                        },
+                        LdapPartialAttribute {
+                            atype: "sn".to_string(),
+                            vals: vec!["Böbberson".to_string().into_bytes()]
+                        },
+                        LdapPartialAttribute {
+                            atype: "cn".to_string(),
+                            vals: vec!["Bôb Böbberson".to_string().into_bytes()]
+                        },
+                        LdapPartialAttribute {
+                            atype: "createTimestamp".to_string(),
+                            vals: vec![b"1970-01-01T00:00:00+00:00".to_vec()]
+                        },
+                        LdapPartialAttribute {
+                            atype: "modifyTimestamp".to_string(),
+                            vals: vec![b"1970-01-01T00:00:00+00:00".to_vec()]
+                        },
+                        LdapPartialAttribute {
+                            atype: "entryUuid".to_string(),
+                            vals: vec![b"698e1d5f-7a40-3151-8745-b9b8a37839da".to_vec()]
+                        },
+                    ],
+                }),
+                LdapOp::SearchResultEntry(LdapSearchResultEntry {
+                    dn: "uid=jim,ou=people,dc=example,dc=com".to_string(),
+                    attributes: vec![
+                        LdapPartialAttribute {
+                            atype: "objectClass".to_string(),
+                            vals: vec![
+                                b"inetOrgPerson".to_vec(),
+                                b"posixAccount".to_vec(),
+                                b"mailAccount".to_vec(),
+                                b"person".to_vec()
+                            ]
+                        },
+                        LdapPartialAttribute {
+                            atype: "uid".to_string(),
+                            vals: vec![b"jim".to_vec()]
+                        },
+                        LdapPartialAttribute {
+                            atype: "mail".to_string(),
+                            vals: vec![b"jim@cricket.jim".to_vec()]
+                        },
+                        LdapPartialAttribute {
+                            atype: "givenName".to_string(),
+                            vals: vec![b"Jim".to_vec()]
+                        },
+                        LdapPartialAttribute {
+                            atype: "sn".to_string(),
+                            vals: vec![b"Cricket".to_vec()]
+                        },
+                        LdapPartialAttribute {
+                            atype: "cn".to_string(),
+                            vals: vec![b"Jimminy Cricket".to_vec()]
+                        },
+                        LdapPartialAttribute {
+                            atype: "createTimestamp".to_string(),
+                            vals: vec![b"2014-07-08T09:10:11+00:00".to_vec()]
+                        },
+                        LdapPartialAttribute {
+                            atype: "modifyTimestamp".to_string(),
+                            vals: vec![b"2023-01-02T03:04:05+00:00".to_vec()]
+                        },
+                        LdapPartialAttribute {
+                            atype: "entryUuid".to_string(),
+                            vals: vec![b"04ac75e0-2900-3e21-926c-2f732c26b3fc".to_vec()]
+                        },
+                        LdapPartialAttribute {
+                            atype: "jpegPhoto".to_string(),
+                            vals: vec![JpegPhoto::for_tests().into_bytes()]
+                        },
+                    ],
+                }),
+                make_search_success(),
+            ])
+        );
+    }
+
+    #[tokio::test]
+    async fn test_search_groups() {
+        let mut mock = MockTestBackendHandler::new();
+        mock.expect_list_groups()
+            .with(eq(Some(GroupRequestFilter::And(vec![]))))
+            .times(1)
+            .return_once(|_| {
+                Ok(vec![
+                    Group {
+                        id: GroupId(1),
+                        display_name: "group_1".to_string(),
+                        creation_date: chrono::Utc.timestamp_opt(42, 42).unwrap(),
+                        modified_at: chrono::Utc.timestamp_opt(42, 42).unwrap(),
+                        users: vec![UserId::new("bob"), UserId::new("john")],
+                        uuid: uuid!("04ac75e0-2900-3e21-926c-2f732c26b3fc"),
+                        gid_number: None,
+                    },
+                    Group {
+                        id: GroupId(3),
+                        display_name: "BestGroup".to_string(),
+                        creation_date: chrono::Utc.timestamp_opt(42, 42).unwrap(),
+                        modified_at: chrono::Utc.timestamp_opt(42, 42).unwrap(),
+                        users: vec![UserId::new("john")],
+                        uuid: uuid!("04ac75e0-2900-3e21-926c-2f732c26b3fc"),
+                        gid_number: None,
+                    },
+                ])
+            });
+        let mut ldap_handler = setup_bound_admin_handler(mock).await;
+        let request = make_search_request(
+            "ou=groups,dc=example,dc=cOm",
+            LdapFilter::And(vec![]),
+            vec!["objectClass", "dn", "cn", "uniqueMember", "entryUuid"],
+        );
+        assert_eq!(
+            ldap_handler.do_search_or_dse(&request).await,
+            Ok(vec![
+                LdapOp::SearchResultEntry(LdapSearchResultEntry {
+                    dn: "cn=group_1,ou=groups,dc=example,dc=com".to_string(),
+                    attributes: vec![
+                        LdapPartialAttribute {
+                            atype: "objectClass".to_string(),
+                            vals: vec![b"groupOfUniqueNames".to_vec(),]
+                        },
+                        LdapPartialAttribute {
+                            atype: "cn".to_string(),
+                            vals: vec![b"group_1".to_vec()]
+                        },
+                        LdapPartialAttribute {
+                            atype: "uniqueMember".to_string(),
+                            vals: vec![
+                                b"uid=bob,ou=people,dc=example,dc=com".to_vec(),
+                                b"uid=john,ou=people,dc=example,dc=com".to_vec(),
+                            ]
+                        },
+                        LdapPartialAttribute {
+                            atype: "entryUuid".to_string(),
+                            vals: vec![b"04ac75e0-2900-3e21-926c-2f732c26b3fc".to_vec()],
+                        },
+                    ],
+                }),
+                LdapOp::SearchResultEntry(LdapSearchResultEntry {
+                    dn: "cn=BestGroup,ou=groups,dc=example,dc=com".to_string(),
+                    attributes: vec![
+                        LdapPartialAttribute {
+                            atype: "objectClass".to_string(),
+                            vals: vec![b"groupOfUniqueNames".to_vec(),]
+                        },
+                        LdapPartialAttribute {
+                            atype: "cn".to_string(),
+                            vals: vec![b"BestGroup".to_vec()]
+                        },
+                        LdapPartialAttribute {
+                            atype: "uniqueMember".to_string(),
+                            vals: vec![b"uid=john,ou=people,dc=example,dc=com".to_vec()]
+                        },
+                        LdapPartialAttribute {
+                            atype: "entryUuid".to_string(),
+                            vals: vec![b"04ac75e0-2900-3e21-926c-2f732c26b3fc".to_vec()],
+                        },
+                    ],
+                }),
+                make_search_success(),
+            ])
+        );
+    }
+
+    fn make_user_with_email(user_id: &str, email: &str) -> UserAndGroups {
+        UserAndGroups {
+            user: User {
+                user_id: UserId::new(user_id),
+                email: email.to_string(),
+                ..Default::default()
+            },
+            groups: None,
+        }
+    }
+
+    #[tokio::test]
+    async fn test_bind_mail_rdn() {
+        let mut mock = MockTestBackendHandler::new();
+        mock.expect_list_users()
+            .with(
+                eq(Some(UserRequestFilter::Equality(
+                    UserColumn::Email,
+                    "bob@bobmail.bob".to_string(),
+                ))),
+                eq(false),
+            )
+            .times(1)
+            .return_once(|_, _| Ok(vec![make_user_with_email("bob", "bob@bobmail.bob")]));
+        mock.expect_list_users()
+            .times(1)
+            .return_once(|_, _| Ok(vec![]));
+        mock.expect_bind()
+            .with(eq(BindRequest {
+                name: UserId::new("bob"),
+                password: "pass".to_string(),
+            }))
+            .times(1)
+            .return_once(|_| Ok(()));
+        mock.expect_get_user_groups()
+            .with(eq(UserId::new("bob")))
+            .return_once(|_| Ok(HashSet::new()));
+        let mut ldap_handler =
+            LdapHandler::new(mock, "dc=example,dc=com".to_string(), vec![], vec![])
+                .with_user_rdn_attribute(UserRdnAttribute::Mail);
+        let request = LdapBindRequest {
+            dn: "mail=Bob@bobmail.bob,ou=people,dc=example,dc=com".to_string(),
+            cred: LdapBindCred::Simple("pass".to_string()),
+        };
+        assert_eq!(
+            ldap_handler.do_bind(&request).await.0,
+            LdapResultCode::Success
+        );
+        assert_eq!(ldap_handler.bound_user(), Some(&UserId::new("bob")));
+        // An unknown email looks like a wrong password.
+        let request = LdapBindRequest {
+            dn: "mail=nobody@bobmail.bob,ou=people,dc=example,dc=com".to_string(),
+            cred: LdapBindCred::Simple("pass".to_string()),
+        };
+        assert_eq!(
+            ldap_handler.do_bind(&request).await.0,
+            LdapResultCode::InvalidCredentials
+        );
+    }
+
+    #[tokio::test]
+    async fn test_bind_mail_rdn_not_configured() {
+        let mut ldap_handler = LdapHandler::new(
+            MockTestBackendHandler::new(),
+            "dc=example,dc=com".to_string(),
+            vec![],
+            vec![],
+        );
+        let request = LdapBindRequest {
+            dn: "mail=bob@bobmail.bob,ou=people,dc=example,dc=com".to_string(),
+            cred: LdapBindCred::Simple("pass".to_string()),
+        };
+        assert_eq!(
+            ldap_handler.do_bind(&request).await.0,
+            LdapResultCode::NamingViolation
+        );
+    }
+
+    #[tokio::test]
+    async fn test_search_users_mail_rdn() {
+        let mut mock = MockTestBackendHandler::new();
+        mock.expect_list_users().times(1).return_once(|_, _| {
+            Ok(vec![
+                make_user_with_email("bob", "bob@bobmail.bob"),
+                make_user_with_email("john", ""),
+            ])
+        });
+        let mut ldap_handler = setup_bound_admin_handler(mock)
+            .await
+            .with_user_rdn_attribute(UserRdnAttribute::Mail);
+        let request = make_user_search_request(LdapFilter::And(vec![]), vec!["1.1".to_string()]);
+        assert_eq!(
+            ldap_handler.do_search_or_dse(&request).await,
+            Ok(vec![
+                LdapOp::SearchResultEntry(LdapSearchResultEntry {
+                    dn: "mail=bob@bobmail.bob,ou=people,dc=example,dc=com".to_string(),
+                    attributes: vec![],
+                }),
+                // Without an email, the user keeps its uid DN.
+                LdapOp::SearchResultEntry(LdapSearchResultEntry {
+                    dn: "uid=john,ou=people,dc=example,dc=com".to_string(),
+                    attributes: vec![],
+                }),
+                make_search_success(),
+            ])
+        );
+    }
+
+    #[tokio::test]
+    async fn test_search_users_posix_account() {
+        let mut mock = MockTestBackendHandler::new();
+        mock.expect_list_users()
+            .with(
+                eq(Some(UserRequestFilter::And(vec![
+                    UserRequestFilter::And(vec![]),
+                    UserRequestFilter::Equality(UserColumn::UidNumber, "10001".to_string()),
+                ]))),
+                eq(false),
+            )
+            .times(1)
+            .return_once(|_, _| {
+                Ok(vec![UserAndGroups {
+                    user: User {
+                        user_id: UserId::new("bob"),
+                        display_name: Some("Bob Bobberson".to_string()),
+                        uid_number: Some(10001),
+                        login_shell: Some("/bin/zsh".to_string()),
+                        ..Default::default()
+                    },
+                    groups: None,
+                }])
+            });
+        let mut ldap_handler = setup_bound_admin_handler(mock).await;
+        // What nss-ldap or SSSD asks for a `getpwuid(10001)`.
+        let request = make_user_search_request(
+            LdapFilter::And(vec![
+                LdapFilter::Equality("objectClass".to_string(), "posixAccount".to_string()),
+                LdapFilter::Equality("uidNumber".to_string(), "10001".to_string()),
+            ]),
+            vec![
+                "uid".to_string(),
+                "userPassword".to_string(),
+                "uidNumber".to_string(),
+                "gidNumber".to_string(),
+                "gecos".to_string(),
+                "homeDirectory".to_string(),
+                "loginShell".to_string(),
+            ],
+        );
+        assert_eq!(
+            ldap_handler.do_search_or_dse(&request).await,
+            Ok(vec![
+                LdapOp::SearchResultEntry(LdapSearchResultEntry {
+                    dn: "uid=bob,ou=people,dc=example,dc=com".to_string(),
+                    attributes: vec![
+                        LdapPartialAttribute {
+                            atype: "uid".to_string(),
+                            vals: vec![b"bob".to_vec()],
+                        },
+                        LdapPartialAttribute {
+                            atype: "uidNumber".to_string(),
+                            vals: vec![b"10001".to_vec()],
+                        },
+                        // The private group of the user, by default.
+                        LdapPartialAttribute {
+                            atype: "gidNumber".to_string(),
+                            vals: vec![b"10001".to_vec()],
+                        },
+                        LdapPartialAttribute {
+                            atype: "gecos".to_string(),
+                            vals: vec![b"Bob Bobberson".to_vec()],
+                        },
+                        LdapPartialAttribute {
+                            atype: "homeDirectory".to_string(),
+                            vals: vec![b"/home/bob".to_vec()],
+                        },
+                        LdapPartialAttribute {
+                            atype: "loginShell".to_string(),
+                            vals: vec![b"/bin/zsh".to_vec()],
+                        },
+                    ],
+                }),
+                make_search_success(),
+            ])
+        );
+    }
+
+    #[tokio::test]
+    async fn test_search_groups_posix_group() {
+        let mut mock = MockTestBackendHandler::new();
+        mock.expect_list_groups()
+            .with(eq(Some(GroupRequestFilter::And(vec![
+                GroupRequestFilter::And(vec![]),
+                GroupRequestFilter::Member(UserId::new("bob")),
+            ]))))
+            .times(1)
+            .return_once(|_| {
+                Ok(vec![Group {
+                    gid_number: Some(10042),
+                    users: vec![UserId::new("bob"), UserId::new("john")],
+                    ..make_group(1, "group_1")
+                }])
+            });
+        let mut ldap_handler =
+            setup_bound_admin_handler(mock)
+                .await
+                .with_posix_options(PosixOptions {
+                    default_gid_number: Some(10000),
+                    ..Default::default()
+                });
+        // The groups of a user, for `initgroups`.
+        let request = make_search_request(
+            "ou=groups,dc=example,dc=com",
+            LdapFilter::And(vec![
+                LdapFilter::Equality("objectClass".to_string(), "posixGroup".to_string()),
+                LdapFilter::Equality("memberUid".to_string(), "bob".to_string()),
+            ]),
+            vec!["objectClass", "cn", "gidNumber", "memberUid"],
+        );
+        assert_eq!(
+            ldap_handler.do_search_or_dse(&request).await,
+            Ok(vec![
+                LdapOp::SearchResultEntry(LdapSearchResultEntry {
+                    dn: "cn=group_1,ou=groups,dc=example,dc=com".to_string(),
+                    attributes: vec![
+                        LdapPartialAttribute {
+                            atype: "objectClass".to_string(),
+                            vals: vec![b"groupOfUniqueNames".to_vec(), b"posixGroup".to_vec()],
+                        },
+                        LdapPartialAttribute {
+                            atype: "cn".to_string(),
+                            vals: vec![b"group_1".to_vec()],
+                        },
+                        LdapPartialAttribute {
+                            atype: "gidNumber".to_string(),
+                            vals: vec![b"10042".to_vec()],
+                        },
+                        LdapPartialAttribute {
+                            atype: "memberUid".to_string(),
+                            vals: vec![b"bob".to_vec(), b"john".to_vec()],
+                        },
+                    ],
+                }),
+                make_search_success(),
+            ])
+        );
+    }
+
+    fn make_sudo_role(name: &str, users: &[&str]) -> SudoRole {
+        SudoRole {
+            name: name.to_owned(),
+            users: users.iter().map(|u| u.to_string()).collect(),
+            hosts: vec!["ALL".to_owned()],
+            commands: vec!["/usr/bin/apt".to_owned()],
+            options: vec![],
+            creation_date: chrono::Utc.timestamp_opt(42, 42).unwrap(),
+            modified_at: chrono::Utc.timestamp_opt(42, 42).unwrap(),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_search_sudo_roles() {
+        let mut mock = MockTestBackendHandler::new();
+        mock.expect_list_sudo_roles()
+            .with(eq(Some(SudoRoleRequestFilter::And(vec![
+                SudoRoleRequestFilter::And(vec![]),
+                SudoRoleRequestFilter::Or(vec![
+                    SudoRoleRequestFilter::Equality(SudoAttribute::User, "ALL".to_owned()),
+                    SudoRoleRequestFilter::Equality(SudoAttribute::User, "%admins".to_owned()),
+                    SudoRoleRequestFilter::Not(Box::new(SudoRoleRequestFilter::Present(
+                        SudoAttribute::Host,
+                    ))),
+                ]),
+            ]))))
+            .times(1)
+            .return_once(|_| Ok(vec![make_sudo_role("admins", &["%admins"])]));
+        mock.expect_list_sudo_roles()
+            .with(eq(Some(SudoRoleRequestFilter::And(vec![
+                SudoRoleRequestFilter::And(vec![]),
+                SudoRoleRequestFilter::Name("admins".to_owned()),
+            ]))))
+            .times(1)
+            .return_once(|_| Ok(vec![make_sudo_role("admins", &["%admins"])]));
+        let mut ldap_handler = setup_bound_admin_handler(mock).await;
+        // The query of sudo for the rules of a user.
+        let request = make_search_request(
+            "ou=SUDOers,dc=example,dc=com",
+            LdapFilter::And(vec![
+                LdapFilter::Equality("objectClass".to_string(), "sudoRole".to_string()),
+                LdapFilter::Or(vec![
+                    LdapFilter::Equality("sudoUser".to_string(), "ALL".to_string()),
+                    LdapFilter::Equality("sudoUser".to_string(), "%admins".to_string()),
+                    LdapFilter::Not(Box::new(LdapFilter::Present("sudoHost".to_string()))),
+                ]),
+            ]),
+            vec!["cn", "sudoUser", "sudoCommand", "sudoOption"],
+        );
+        let expected_entry = LdapOp::SearchResultEntry(LdapSearchResultEntry {
+            dn: "cn=admins,ou=sudoers,dc=example,dc=com".to_string(),
+            attributes: vec![
+                LdapPartialAttribute {
+                    atype: "cn".to_string(),
+                    vals: vec![b"admins".to_vec()],
+                },
+                LdapPartialAttribute {
+                    atype: "sudoUser".to_string(),
+                    vals: vec![b"%admins".to_vec()],
+                },
+                LdapPartialAttribute {
+                    atype: "sudoCommand".to_string(),
+                    vals: vec![b"/usr/bin/apt".to_vec()],
+                },
             ],
+        });
+        assert_eq!(
+            ldap_handler.do_search_or_dse(&request).await,
+            Ok(vec![expected_entry.clone(), make_search_success()])
+        );
+        let request = make_search_request(
+            "cn=admins,ou=sudoers,dc=example,dc=com",
+            LdapFilter::And(vec![]),
+            vec!["cn", "sudoUser", "sudoCommand", "sudoOption"],
+        );
+        assert_eq!(
+            ldap_handler.do_search_or_dse(&request).await,
+            Ok(vec![expected_entry, make_search_success()])
+        );
+    }
+
+    #[tokio::test]
+    async fn test_search_sudo_roles_regular_user() {
+        let mut mock = MockTestBackendHandler::new();
+        mock.expect_bind().return_once(|_| Ok(()));
+        mock.expect_get_user_groups()
+            .with(eq(UserId::new("test")))
+            .times(2)
+            .returning(|_| {
+                let mut set = HashSet::new();
+                set.insert(GroupDetails {
+                    group_id: GroupId(42),
+                    display_name: "devs".to_string(),
+                    creation_date: chrono::Utc.timestamp_opt(42, 42).unwrap(),
+                    uuid: uuid!("a1a2a3a4b1b2c1c2d1d2d3d4d5d6d7d8"),
+                });
+                Ok(set)
+            });
+        mock.expect_get_user_details()
+            .with(eq(UserId::new("test")))
+            .times(1)
+            .return_once(|_| {
+                Ok(User {
+                    user_id: UserId::new("test"),
+                    uid_number: Some(10042),
+                    ..Default::default()
+                })
+            });
+        let user_values = ["test", "ALL", "%devs", "#10042"];
+        mock.expect_list_sudo_roles()
+            .with(eq(Some(SudoRoleRequestFilter::And(vec![
+                SudoRoleRequestFilter::And(vec![]),
+                SudoRoleRequestFilter::Or(
+                    user_values
+                        .iter()
+                        .map(|v| {
+                            SudoRoleRequestFilter::Equality(SudoAttribute::User, v.to_string())
+                        })
+                        .chain(std::iter::once(SudoRoleRequestFilter::Not(Box::new(
+                            SudoRoleRequestFilter::Present(SudoAttribute::User),
+                        ))))
+                        .collect(),
+                ),
+            ]))))
+            .times(1)
+            .return_once(|_| Ok(vec![]));
+        let mut ldap_handler =
+            LdapHandler::new(mock, "dc=example,dc=com".to_string(), vec![], vec![])
+                .with_sudoers_base_dn("ou=sudo,ou=services,dc=example,dc=com".to_owned());
+        let request = LdapBindRequest {
+            dn: "uid=test,ou=people,dc=example,dc=com".to_string(),
+            cred: LdapBindCred::Simple("pass".to_string()),
+        };
+        assert_eq!(
+            ldap_handler.do_bind(&request).await.0,
+            LdapResultCode::Success
+        );
+        let request = make_search_request(
+            "ou=sudo,ou=services,dc=example,dc=com",
+            LdapFilter::Equality("objectClass".to_string(), "sudoRole".to_string()),
+            vec!["cn"],
+        );
+        assert_eq!(
+            ldap_handler.do_search_or_dse(&request).await,
+            Ok(vec![make_search_success()])
+        );
+    }
+
+    #[tokio::test]
+    async fn test_search_groups_mail_rdn() {
+        let mut mock = MockTestBackendHandler::new();
+        mock.expect_list_users()
+            .with(
+                eq(Some(UserRequestFilter::Or(vec![
+                    UserRequestFilter::Equality(UserColumn::Email, "bob@bobmail.bob".to_string()),
+                ]))),
+                eq(false),
+            )
+            .times(1)
+            .return_once(|_, _| Ok(vec![make_user_with_email("bob", "bob@bobmail.bob")]));
+        mock.expect_list_groups()
+            .with(eq(Some(GroupRequestFilter::Member(UserId::new("bob")))))
+            .times(1)
+            .return_once(|_| {
+                Ok(vec![Group {
+                    users: vec![UserId::new("bob"), UserId::new("john")],
+                    ..make_group(1, "group_1")
+                }])
+            });
+        // The members, in any order.
+        mock.expect_list_users().times(1).return_once(|_, _| {
+            Ok(vec![
+                make_user_with_email("bob", "bob@bobmail.bob"),
+                make_user_with_email("john", ""),
+            ])
+        });
+        let mut ldap_handler = setup_bound_admin_handler(mock)
+            .await
+            .with_user_rdn_attribute(UserRdnAttribute::Mail);
+        let request = make_search_request(
+            "ou=groups,dc=example,dc=com",
+            LdapFilter::Equality(
+                "member".to_string(),
+                "mail=bob@bobmail.bob,ou=people,dc=example,dc=com".to_string(),
+            ),
+            vec!["member"],
         );
         assert_eq!(
             ldap_handler.do_search_or_dse(&request).await,
             Ok(vec![
                 LdapOp::SearchResultEntry(LdapSearchResultEntry {
-                    dn: "uid=bob_1,ou=people,dc=example,dc=com".to_string(),
-                    attributes: vec![
-                        LdapPartialAttribute {
-                            atype: "objectClass".to_string(),
-                            vals: vec![
-                                b"inetOrgPerson".to_vec(),
-                                b"posixAccount".to_vec(),
-                                b"mailAccount".to_vec(),
-                                b"person".to_vec()
-                            ]
-                        },
-                        LdapPartialAttribute {
-                            atype: "uid".to_string(),
-                            vals: vec![b"bob_1".to_vec()]
-                        },
-                        LdapPartialAttribute {
-                            atype: "mail".to_string(),
-                            vals: vec![b"bob@bobmail.bob".to_vec()]
-                        },
-                        LdapPartialAttribute {
-                            atype: "givenName".to_string(),
-                            vals: vec!["Bôb".to_string().into_bytes()]
-                        },
-                        LdapPartialAttribute {
-                            atype: "sn".to_string(),
-                            vals: vec!["Böbberson".to_string().into_bytes()]
-                        },
-                        LdapPartialAttribute {
-                            atype: "cn".to_string(),
-                            vals: vec!["Bôb Böbberson".to_string().into_bytes()]
-                        },
-                        LdapPartialAttribute {
-                            atype: "createTimestamp".to_string(),
-                            vals: vec![b"1970-01-01T00:00:00+00:00".to_vec()]
-                        },
-                        LdapPartialAttribute {
-                            atype: "entryUuid".to_string(),
-                            vals: vec![b"698e1d5f-7a40-3151-8745-b9b8a37839da".to_vec()]
-                        },
-                    ],
-                }),
-                LdapOp::SearchResultEntry(LdapSearchResultEntry {
-                    dn: "uid=jim,ou=people,dc=example,dc=com".to_string(),
-                    attributes: vec![
-                        LdapPartialAttribute {
-                            atype: "objectClass".to_string(),
-                            vals: vec![
-                                b"inetOrgPerson".to_vec(),
-                                b"posixAccount".to_vec(),
-                                b"mailAccount".to_vec(),
-                                b"person".to_vec()
-                            ]
-                        },
-                        LdapPartialAttribute {
-                            atype: "uid".to_string(),
-                            vals: vec![b"jim".to_vec()]
-                        },
-                        LdapPartialAttribute {
-                            atype: "mail".to_string(),
-                            vals: vec![b"jim@cricket.jim".to_vec()]
-                        },
-                        LdapPartialAttribute {
-                            atype: "givenName".to_string(),
-                            vals: vec![b"Jim".to_vec()]
-                        },
-                        LdapPartialAttribute {
-                            atype: "sn".to_string(),
-                            vals: vec![b"Cricket".to_vec()]
-                        },
-                        LdapPartialAttribute {
-                            atype: "cn".to_string(),
-                            vals: vec![b"Jimminy Cricket".to_vec()]
-                        },
-                        LdapPartialAttribute {
-                            atype: "createTimestamp".to_string(),
-                            vals: vec![b"2014-07-08T09:10:11+00:00".to_vec()]
-                        },
-                        LdapPartialAttribute {
-                            atype: "entryUuid".to_string(),
-                            vals: vec![b"04ac75e0-2900-3e21-926c-2f732c26b3fc".to_vec()]
-                        },
-                        LdapPartialAttribute {
-                            atype: "jpegPhoto".to_string(),
-                            vals: vec![JpegPhoto::for_tests().into_bytes()]
-                        },
-                    ],
+                    dn: "cn=group_1,ou=groups,dc=example,dc=com".to_string(),
+                    attributes: vec![LdapPartialAttribute {
+                        atype: "member".to_string(),
+                        vals: vec![
+                            b"mail=bob@bobmail.bob,ou=people,dc=example,dc=com".to_vec(),
+                            b"uid=john,ou=people,dc=example,dc=com".to_vec(),
+                        ]
+                    }],
                 }),
                 make_search_success(),
             ])
@@ -1125,34 +4256,20 @@ mod tests {
     }
 
     #[tokio::test]
-    async fn test_search_groups() {
+    async fn test_search_group_timestamps() {
         let mut mock = MockTestBackendHandler::new();
-        mock.expect_list_groups()
-            .with(eq(Some(GroupRequestFilter::And(vec![]))))
-            .times(1)
-            .return_once(|_| {
-                Ok(vec![
-                    Group {
-                        id: GroupId(1),
-                        display_name: "group_1".to_string(),
-                        creation_date: chrono::Utc.timestamp_opt(42, 42).unwrap(),
-                        users: vec![UserId::new("bob"), UserId::new("john")],
-                        uuid: uuid!("04ac75e0-2900-3e21-926c-2f732c26b3fc"),
-                    },
-                    Group {
-                        id: GroupId(3),
-                        display_name: "BestGroup".to_string(),
-                        creation_date: chrono::Utc.timestamp_opt(42, 42).unwrap(),
-                        users: vec![UserId::new("john")],
-                        uuid: uuid!("04ac75e0-2900-3e21-926c-2f732c26b3fc"),
-                    },
-                ])
-            });
+        mock.expect_list_groups().times(1).return_once(|_| {
+            Ok(vec![Group {
+                creation_date: chrono::Utc.with_ymd_and_hms(2014, 7, 8, 9, 10, 11).unwrap(),
+                modified_at: chrono::Utc.with_ymd_and_hms(2023, 1, 2, 3, 4, 5).unwrap(),
+                ..make_group(1, "group_1")
+            }])
+        });
         let mut ldap_handler = setup_bound_admin_handler(mock).await;
         let request = make_search_request(
-            "ou=groups,dc=example,dc=cOm",
+            "ou=groups,dc=example,dc=com",
             LdapFilter::And(vec![]),
-            vec!["objectClass", "dn", "cn", "uniqueMember", "entryUuid"],
+            vec!["createTimestamp", "modifyTimestamp"],
         );
         assert_eq!(
             ldap_handler.do_search_or_dse(&request).await,
@@ -1161,44 +4278,12 @@ mod tests {
                     dn: "cn=group_1,ou=groups,dc=example,dc=com".to_string(),
                     attributes: vec![
                         LdapPartialAttribute {
-                            atype: "objectClass".to_string(),
-                            vals: vec![b"groupOfUniqueNames".to_vec(),]
-                        },
-                        LdapPartialAttribute {
-                            atype: "cn".to_string(),
-                            vals: vec![b"group_1".to_vec()]
-                        },
-                        LdapPartialAttribute {
-                            atype: "uniqueMember".to_string(),
-                            vals: vec![
-                                b"uid=bob,ou=people,dc=example,dc=com".to_vec(),
-                                b"uid=john,ou=people,dc=example,dc=com".to_vec(),
-                            ]
-                        },
-                        LdapPartialAttribute {
-                            atype: "entryUuid".to_string(),
-                            vals: vec![b"04ac75e0-2900-3e21-926c-2f732c26b3fc".to_vec()],
-                        },
-                    ],
-                }),
-                LdapOp::SearchResultEntry(LdapSearchResultEntry {
-                    dn: "cn=BestGroup,ou=groups,dc=example,dc=com".to_string(),
-                    attributes: vec![
-                        LdapPartialAttribute {
-                            atype: "objectClass".to_string(),
-                            vals: vec![b"groupOfUniqueNames".to_vec(),]
-                        },
-                        LdapPartialAttribute {
-                            atype: "cn".to_string(),
-                            vals: vec![b"BestGroup".to_vec()]
-                        },
-                        LdapPartialAttribute {
-                            atype: "uniqueMember".to_string(),
-                            vals: vec![b"uid=john,ou=people,dc=example,dc=com".to_vec()]
+                            atype: "createTimestamp".to_string(),
+                            vals: vec![b"2014-07-08T09:10:11+00:00".to_vec()],
                         },
                         LdapPartialAttribute {
-                            atype: "entryUuid".to_string(),
-                            vals: vec![b"04ac75e0-2900-3e21-926c-2f732c26b3fc".to_vec()],
+                            atype: "modifyTimestamp".to_string(),
+                            vals: vec![b"2023-01-02T03:04:05+00:00".to_vec()],
                         },
                     ],
                 }),
@@ -1229,8 +4314,10 @@ mod tests {
                     display_name: "group_1".to_string(),
                     id: GroupId(1),
                     creation_date: chrono::Utc.timestamp_opt(42, 42).unwrap(),
+                    modified_at: chrono::Utc.timestamp_opt(42, 42).unwrap(),
                     users: vec![],
                     uuid: uuid!("04ac75e0-2900-3e21-926c-2f732c26b3fc"),
+                    gid_number: None,
                 }])
             });
         let mut ldap_handler = setup_bound_admin_handler(mock).await;
@@ -1257,12 +4344,185 @@ mod tests {
             ldap_handler.do_search_or_dse(&request).await,
             Ok(vec![
                 LdapOp::SearchResultEntry(LdapSearchResultEntry {
-                    dn: "cn=group_1,ou=groups,dc=example,dc=com".to_string(),
-                    attributes: vec![],
+                    dn: "cn=group_1,ou=groups,dc=example,dc=com".to_string(),
+                    attributes: vec![],
+                }),
+                make_search_success(),
+            ])
+        );
+    }
+
+    #[tokio::test]
+    async fn test_search_custom_object_classes() {
+        let mut mock = MockTestBackendHandler::new();
+        mock.expect_list_users()
+            .with(
+                eq(Some(UserRequestFilter::And(vec![
+                    UserRequestFilter::And(vec![]),
+                    UserRequestFilter::Not(Box::new(UserRequestFilter::And(vec![]))),
+                ]))),
+                eq(false),
+            )
+            .times(1)
+            .return_once(|_, _| {
+                Ok(vec![UserAndGroups {
+                    user: User {
+                        user_id: UserId::new("bob"),
+                        ..Default::default()
+                    },
+                    groups: None,
+                }])
+            });
+        mock.expect_list_groups()
+            .with(eq(Some(GroupRequestFilter::And(vec![
+                GroupRequestFilter::And(vec![]),
+                GroupRequestFilter::Not(Box::new(GroupRequestFilter::And(vec![]))),
+            ]))))
+            .times(1)
+            .return_once(|_| {
+                Ok(vec![Group {
+                    display_name: "group_1".to_string(),
+                    id: GroupId(1),
+                    creation_date: chrono::Utc.timestamp_opt(42, 42).unwrap(),
+                    modified_at: chrono::Utc.timestamp_opt(42, 42).unwrap(),
+                    users: vec![],
+                    uuid: uuid!("04ac75e0-2900-3e21-926c-2f732c26b3fc"),
+                    gid_number: None,
+                }])
+            });
+        let mut ldap_handler =
+            setup_bound_admin_handler(mock)
+                .await
+                .with_object_classes(LdapObjectClasses {
+                    user: vec!["customPerson".to_string(), "top".to_string()],
+                    group: vec!["groupOfNames".to_string(), "posixGroup".to_string()],
+                });
+        let request = make_user_search_request(
+            LdapFilter::And(vec![
+                LdapFilter::Equality("objectClass".to_string(), "custompErson".to_string()),
+                LdapFilter::Equality("objectClass".to_string(), "inetOrgPerson".to_string()),
+            ]),
+            vec!["objectClass"],
+        );
+        assert_eq!(
+            ldap_handler.do_search_or_dse(&request).await,
+            Ok(vec![
+                LdapOp::SearchResultEntry(LdapSearchResultEntry {
+                    dn: "uid=bob,ou=people,dc=example,dc=com".to_string(),
+                    attributes: vec![LdapPartialAttribute {
+                        atype: "objectClass".to_string(),
+                        vals: vec![b"customPerson".to_vec(), b"top".to_vec()]
+                    }],
+                }),
+                make_search_success(),
+            ])
+        );
+        // Without groupOfUniqueNames, groupOfNames doesn't stand for it, and the posixGroup is
+        // only listed for the groups with a gidNumber.
+        let request = make_search_request(
+            "ou=groups,dc=example,dc=com",
+            LdapFilter::And(vec![
+                LdapFilter::Equality("objectClass".to_string(), "groupOfNames".to_string()),
+                LdapFilter::Equality("objectClass".to_string(), "groupOfUniqueNames".to_string()),
+            ]),
+            vec!["objectClass"],
+        );
+        assert_eq!(
+            ldap_handler.do_search_or_dse(&request).await,
+            Ok(vec![
+                LdapOp::SearchResultEntry(LdapSearchResultEntry {
+                    dn: "cn=group_1,ou=groups,dc=example,dc=com".to_string(),
+                    attributes: vec![LdapPartialAttribute {
+                        atype: "objectClass".to_string(),
+                        vals: vec![b"groupOfNames".to_vec()]
+                    }],
+                }),
+                make_search_success(),
+            ])
+        );
+    }
+
+    #[tokio::test]
+    async fn test_search_users_attribute_acl() {
+        use crate::infra::access_control::{
+            AclAccess, AclAttribute, AclOperation, AclTarget, AttributeAclRule,
+        };
+        let mut mock = MockTestBackendHandler::new();
+        mock.expect_list_users()
+            .with(eq(Some(UserRequestFilter::And(vec![]))), eq(false))
+            .times(1)
+            .return_once(|_, _| {
+                Ok(vec![
+                    UserAndGroups {
+                        user: User {
+                            user_id: UserId::new("test"),
+                            email: "test@example.com".to_string(),
+                            display_name: Some("Test".to_string()),
+                            ..Default::default()
+                        },
+                        groups: None,
+                    },
+                    UserAndGroups {
+                        user: User {
+                            user_id: UserId::new("bob"),
+                            email: "bob@example.com".to_string(),
+                            display_name: Some("Bob".to_string()),
+                            ..Default::default()
+                        },
+                        groups: None,
+                    },
+                ])
+            });
+        // Everyone can read the display names, and the emails of their own account only.
+        let mut ldap_handler = setup_bound_handler_with_group(mock, "users")
+            .await
+            .with_attribute_acl(AttributeAcl::new(vec![AttributeAclRule {
+                group: "*".to_string(),
+                attributes: vec![AclAttribute::DisplayName],
+                operation: AclOperation::Read,
+                target: AclTarget::All,
+                access: AclAccess::Allow,
+            }]));
+        let request = make_user_search_request(LdapFilter::And(vec![]), vec!["cn", "mail"]);
+        assert_eq!(
+            ldap_handler.do_search_or_dse(&request).await,
+            Ok(vec![
+                LdapOp::SearchResultEntry(LdapSearchResultEntry {
+                    dn: "uid=test,ou=people,dc=example,dc=com".to_string(),
+                    attributes: vec![
+                        LdapPartialAttribute {
+                            atype: "cn".to_string(),
+                            vals: vec![b"Test".to_vec()]
+                        },
+                        LdapPartialAttribute {
+                            atype: "mail".to_string(),
+                            vals: vec![b"test@example.com".to_vec()]
+                        },
+                    ],
+                }),
+                LdapOp::SearchResultEntry(LdapSearchResultEntry {
+                    dn: "uid=bob,ou=people,dc=example,dc=com".to_string(),
+                    attributes: vec![LdapPartialAttribute {
+                        atype: "cn".to_string(),
+                        vals: vec![b"Bob".to_vec()]
+                    }],
                 }),
                 make_search_success(),
             ])
         );
+        // The others can't be found by their emails.
+        let request = make_user_search_request(
+            LdapFilter::Equality("mail".to_string(), "bob@example.com".to_string()),
+            vec!["cn"],
+        );
+        assert_eq!(
+            ldap_handler
+                .do_search_or_dse(&request)
+                .await
+                .unwrap_err()
+                .code,
+            LdapResultCode::InsufficentAccessRights
+        );
     }
 
     #[tokio::test]
@@ -1280,8 +4540,10 @@ mod tests {
                     display_name: "group_1".to_string(),
                     id: GroupId(1),
                     creation_date: chrono::Utc.timestamp_opt(42, 42).unwrap(),
+                    modified_at: chrono::Utc.timestamp_opt(42, 42).unwrap(),
                     users: vec![],
                     uuid: uuid!("04ac75e0-2900-3e21-926c-2f732c26b3fc"),
+                    gid_number: None,
                 }])
             });
         let mut ldap_handler = setup_bound_admin_handler(mock).await;
@@ -1347,7 +4609,7 @@ mod tests {
             ]))))
             .times(1)
             .return_once(|_| {
-                Err(crate::domain::error::DomainError::InternalError(
+                Err(DomainError::InternalError(
                     "Error getting groups".to_string(),
                 ))
             });
@@ -1405,7 +4667,10 @@ mod tests {
                         UserRequestFilter::And(vec![]),
                         UserRequestFilter::And(vec![]),
                         UserRequestFilter::Not(Box::new(UserRequestFilter::And(vec![]))),
-                        UserRequestFilter::Not(Box::new(UserRequestFilter::And(vec![]))),
+                        UserRequestFilter::AttributeEquality(
+                            AttributeName::new("unknown_attribute"),
+                            "randomValue".to_owned(),
+                        ),
                     ],
                 )]))),
                 eq(false),
@@ -1556,8 +4821,10 @@ mod tests {
                     id: GroupId(1),
                     display_name: "group_1".to_string(),
                     creation_date: chrono::Utc.timestamp_opt(42, 42).unwrap(),
+                    modified_at: chrono::Utc.timestamp_opt(42, 42).unwrap(),
                     users: vec![UserId::new("bob"), UserId::new("john")],
                     uuid: uuid!("04ac75e0-2900-3e21-926c-2f732c26b3fc"),
+                    gid_number: None,
                 }])
             });
         let mut ldap_handler = setup_bound_admin_handler(mock).await;
@@ -1630,8 +4897,10 @@ mod tests {
                     id: GroupId(1),
                     display_name: "group_1".to_string(),
                     creation_date: chrono::Utc.timestamp_opt(42, 42).unwrap(),
+                    modified_at: chrono::Utc.timestamp_opt(42, 42).unwrap(),
                     users: vec![UserId::new("bob"), UserId::new("john")],
                     uuid: uuid!("04ac75e0-2900-3e21-926c-2f732c26b3fc"),
+                    gid_number: None,
                 }])
             });
         let mut ldap_handler = setup_bound_admin_handler(mock).await;
@@ -1803,48 +5072,358 @@ mod tests {
             vec!["objectClass"],
         );
         assert_eq!(
-            ldap_handler.do_search_or_dse(&request).await,
-            Err(LdapError{
-                code: LdapResultCode::UnwillingToPerform,
-                message: r#"Unsupported user filter: Substring("uid", LdapSubstringFilter { initial: None, any: [], final_: None })"#.to_string()
-            })
+            ldap_handler.do_search_or_dse(&request).await,
+            Err(LdapError{
+                code: LdapResultCode::UnwillingToPerform,
+                message: r#"Unsupported user filter: Substring("uid", LdapSubstringFilter { initial: None, any: [], final_: None })"#.to_string()
+            })
+        );
+    }
+
+    #[tokio::test]
+    async fn test_password_change() {
+        let mut mock = MockTestBackendHandler::new();
+        mock.expect_get_user_groups()
+            .with(eq(UserId::new("bob")))
+            .returning(|_| Ok(HashSet::new()));
+        use lldap_auth::*;
+        let mut rng = rand::rngs::OsRng;
+        let registration_start_request =
+            opaque::client::registration::start_registration("password", &mut rng).unwrap();
+        let request = registration::ClientRegistrationStartRequest {
+            username: "bob".to_string(),
+            registration_start_request: registration_start_request.message,
+        };
+        let start_response = opaque::server::registration::start_registration(
+            &opaque::server::ServerSetup::new(&mut rng),
+            request.registration_start_request,
+            &request.username,
+        )
+        .unwrap();
+        mock.expect_registration_start().times(1).return_once(|_| {
+            Ok(registration::ServerRegistrationStartResponse {
+                server_data: "".to_string(),
+                registration_response: start_response.message,
+            })
+        });
+        mock.expect_registration_finish()
+            .times(1)
+            .return_once(|_| Ok(()));
+        let mut ldap_handler = setup_bound_admin_handler(mock).await;
+        let request = LdapOp::ExtendedRequest(
+            LdapPasswordModifyRequest {
+                user_identity: Some("uid=bob,ou=people,dc=example,dc=com".to_string()),
+                old_password: None,
+                new_password: Some("password".to_string()),
+            }
+            .into(),
+        );
+        assert_eq!(
+            ldap_handler.handle_ldap_message(request).await,
+            Some(vec![make_extended_response(
+                LdapResultCode::Success,
+                "".to_string(),
+            )])
+        );
+    }
+
+    #[tokio::test]
+    async fn test_password_change_password_manager() {
+        let mut mock = MockTestBackendHandler::new();
+        mock.expect_get_user_groups()
+            .with(eq(UserId::new("bob")))
+            .returning(|_| Ok(HashSet::new()));
+        use lldap_auth::*;
+        let mut rng = rand::rngs::OsRng;
+        let registration_start_request =
+            opaque::client::registration::start_registration("password", &mut rng).unwrap();
+        let request = registration::ClientRegistrationStartRequest {
+            username: "bob".to_string(),
+            registration_start_request: registration_start_request.message,
+        };
+        let start_response = opaque::server::registration::start_registration(
+            &opaque::server::ServerSetup::new(&mut rng),
+            request.registration_start_request,
+            &request.username,
+        )
+        .unwrap();
+        mock.expect_registration_start().times(1).return_once(|_| {
+            Ok(registration::ServerRegistrationStartResponse {
+                server_data: "".to_string(),
+                registration_response: start_response.message,
+            })
+        });
+        mock.expect_registration_finish()
+            .times(1)
+            .return_once(|_| Ok(()));
+        let mut ldap_handler = setup_bound_password_manager_handler(mock).await;
+        let request = LdapOp::ExtendedRequest(
+            LdapPasswordModifyRequest {
+                user_identity: Some("uid=bob,ou=people,dc=example,dc=com".to_string()),
+                old_password: None,
+                new_password: Some("password".to_string()),
+            }
+            .into(),
+        );
+        assert_eq!(
+            ldap_handler.handle_ldap_message(request).await,
+            Some(vec![make_extended_response(
+                LdapResultCode::Success,
+                "".to_string(),
+            )])
+        );
+    }
+
+    #[tokio::test]
+    async fn test_password_change_self_service_generated() {
+        let mut mock = MockTestBackendHandler::new();
+        mock.expect_bind()
+            .with(eq(BindRequest {
+                name: UserId::new("bob"),
+                password: "pass".to_string(),
+            }))
+            .times(2)
+            .returning(|_| Ok(()));
+        mock.expect_get_user_groups()
+            .with(eq(UserId::new("bob")))
+            .returning(|_| Ok(HashSet::new()));
+        use lldap_auth::*;
+        let mut rng = rand::rngs::OsRng;
+        let registration_start_request =
+            opaque::client::registration::start_registration("password", &mut rng).unwrap();
+        let start_response = opaque::server::registration::start_registration(
+            &opaque::server::ServerSetup::new(&mut rng),
+            registration_start_request.message,
+            "bob",
+        )
+        .unwrap();
+        mock.expect_registration_start()
+            .withf(|request| request.username == "bob")
+            .times(1)
+            .return_once(|_| {
+                Ok(registration::ServerRegistrationStartResponse {
+                    server_data: "".to_string(),
+                    registration_response: start_response.message,
+                })
+            });
+        mock.expect_registration_finish()
+            .times(1)
+            .return_once(|_| Ok(()));
+        let mut ldap_handler =
+            LdapHandler::new(mock, "dc=example,dc=com".to_string(), vec![], vec![]);
+        let request = LdapBindRequest {
+            dn: "uid=bob,ou=people,dc=example,dc=com".to_string(),
+            cred: LdapBindCred::Simple("pass".to_string()),
+        };
+        assert_eq!(
+            ldap_handler.do_bind(&request).await.0,
+            LdapResultCode::Success
+        );
+        // No identity: the bound user. No new password: one is generated.
+        let request = LdapOp::ExtendedRequest(
+            LdapPasswordModifyRequest {
+                user_identity: None,
+                old_password: Some("pass".to_string()),
+                new_password: None,
+            }
+            .into(),
+        );
+        let response = ldap_handler.handle_ldap_message(request).await.unwrap();
+        match &response[..] {
+            [LdapOp::ExtendedResponse(LdapExtendedResponse {
+                res,
+                value: Some(value),
+                ..
+            })] => {
+                assert_eq!(res.code, LdapResultCode::Success);
+                assert_eq!(value[..4], [0x30, 22, 0x80, 20]);
+                assert_eq!(value.len(), 24);
+            }
+            _ => panic!("Unexpected response: {:?}", response),
+        }
+    }
+
+    fn make_whoami_request() -> LdapOp {
+        LdapOp::ExtendedRequest(LdapExtendedRequest {
+            name: WHOAMI_OID.to_string(),
+            value: None,
+        })
+    }
+
+    fn make_whoami_response(authz_id: &str) -> Vec<LdapOp> {
+        vec![LdapOp::ExtendedResponse(LdapExtendedResponse {
+            res: LdapResultOp {
+                code: LdapResultCode::Success,
+                matcheddn: "".to_string(),
+                message: "".to_string(),
+                referral: vec![],
+            },
+            name: None,
+            value: Some(authz_id.as_bytes().to_vec()),
+        })]
+    }
+
+    #[tokio::test]
+    async fn test_whoami() {
+        let mut ldap_handler = LdapHandler::new(
+            MockTestBackendHandler::new(),
+            "dc=example,dc=com".to_string(),
+            vec![],
+            vec![],
+        );
+        assert_eq!(
+            ldap_handler
+                .handle_ldap_message(make_whoami_request())
+                .await,
+            Some(make_whoami_response(""))
+        );
+        let mut ldap_handler = setup_bound_admin_handler(MockTestBackendHandler::new()).await;
+        assert_eq!(
+            ldap_handler
+                .handle_ldap_message(make_whoami_request())
+                .await,
+            Some(make_whoami_response(
+                "dn:uid=test,ou=people,dc=example,dc=com"
+            ))
+        );
+    }
+
+    #[tokio::test]
+    async fn test_whoami_mail_rdn() {
+        let mut mock = MockTestBackendHandler::new();
+        mock.expect_get_user_details()
+            .with(eq(UserId::new("test")))
+            .times(1)
+            .return_once(|_| {
+                Ok(User {
+                    user_id: UserId::new("test"),
+                    email: "test@example.com".to_string(),
+                    ..Default::default()
+                })
+            });
+        let mut ldap_handler = setup_bound_admin_handler(mock)
+            .await
+            .with_user_rdn_attribute(UserRdnAttribute::Mail);
+        assert_eq!(
+            ldap_handler
+                .handle_ldap_message(make_whoami_request())
+                .await,
+            Some(make_whoami_response(
+                "dn:mail=test@example.com,ou=people,dc=example,dc=com"
+            ))
+        );
+    }
+
+    #[tokio::test]
+    async fn test_password_change_errors() {
+        let mut mock = MockTestBackendHandler::new();
+        mock.expect_get_user_groups()
+            .with(eq(UserId::new("bob")))
+            .returning(|_| Ok(HashSet::new()));
+        mock.expect_bind()
+            .with(eq(BindRequest {
+                name: UserId::new("bob"),
+                password: "wrong".to_string(),
+            }))
+            .times(1)
+            .return_once(|_| {
+                Err(DomainError::AuthenticationError(
+                    " for user 'bob'".to_string(),
+                ))
+            });
+        let mut ldap_handler = setup_bound_admin_handler(mock).await;
+        let request = LdapOp::ExtendedRequest(
+            LdapPasswordModifyRequest {
+                user_identity: Some("uid=bob,ou=people,dc=example,dc=com".to_string()),
+                old_password: None,
+                new_password: Some("short".to_string()),
+            }
+            .into(),
+        );
+        assert_eq!(
+            ldap_handler.handle_ldap_message(request).await,
+            Some(vec![make_extended_response(
+                LdapResultCode::ConstraintViolation,
+                "The password must be at least 8 characters long".to_string(),
+            )])
+        );
+        let request = LdapOp::ExtendedRequest(
+            LdapPasswordModifyRequest {
+                user_identity: Some("uid=bob,ou=people,dc=example,dc=com".to_string()),
+                old_password: Some("password".to_string()),
+                new_password: Some("password".to_string()),
+            }
+            .into(),
+        );
+        assert_eq!(
+            ldap_handler.handle_ldap_message(request).await,
+            Some(vec![make_extended_response(
+                LdapResultCode::ConstraintViolation,
+                "The new password must be different from the old one".to_string(),
+            )])
+        );
+        let request = LdapOp::ExtendedRequest(
+            LdapPasswordModifyRequest {
+                user_identity: Some("uid=bob,ou=people,dc=example,dc=com".to_string()),
+                old_password: Some("wrong".to_string()),
+                new_password: Some("password".to_string()),
+            }
+            .into(),
+        );
+        assert_eq!(
+            ldap_handler.handle_ldap_message(request).await,
+            Some(vec![make_extended_response(
+                LdapResultCode::InvalidCredentials,
+                "Invalid old password".to_string(),
+            )])
+        );
+        let request = LdapOp::ExtendedRequest(
+            LdapPasswordModifyRequest {
+                user_identity: Some("uid=bob,ou=groups,ou=people,dc=example,dc=com".to_string()),
+                old_password: None,
+                new_password: Some("password".to_string()),
+            }
+            .into(),
+        );
+        assert_eq!(
+            ldap_handler.handle_ldap_message(request).await,
+            Some(vec![make_extended_response(
+                LdapResultCode::InvalidDNSyntax,
+                r#"Invalid username: Unexpected DN format. Got "uid=bob,ou=groups,ou=people,dc=example,dc=com", expected: "uid=id,ou=people,dc=example,dc=com""#.to_string(),
+            )])
+        );
+        let request = LdapOp::ExtendedRequest(LdapExtendedRequest {
+            name: "test".to_string(),
+            value: None,
+        });
+        assert_eq!(
+            ldap_handler.handle_ldap_message(request).await,
+            Some(vec![make_extended_response(
+                LdapResultCode::UnwillingToPerform,
+                "Unsupported extended operation: test".to_string(),
+            )])
         );
     }
 
     #[tokio::test]
-    async fn test_password_change() {
+    async fn test_password_change_unauthorized_password_manager() {
         let mut mock = MockTestBackendHandler::new();
+        let mut groups = HashSet::new();
+        groups.insert(GroupDetails {
+            group_id: GroupId(0),
+            display_name: "lldap_admin".to_string(),
+            creation_date: chrono::Utc.timestamp_opt(42, 42).unwrap(),
+            uuid: uuid!("a1a2a3a4b1b2c1c2d1d2d3d4d5d6d7d8"),
+        });
         mock.expect_get_user_groups()
             .with(eq(UserId::new("bob")))
-            .returning(|_| Ok(HashSet::new()));
-        use lldap_auth::*;
-        let mut rng = rand::rngs::OsRng;
-        let registration_start_request =
-            opaque::client::registration::start_registration("password", &mut rng).unwrap();
-        let request = registration::ClientRegistrationStartRequest {
-            username: "bob".to_string(),
-            registration_start_request: registration_start_request.message,
-        };
-        let start_response = opaque::server::registration::start_registration(
-            &opaque::server::ServerSetup::new(&mut rng),
-            request.registration_start_request,
-            &request.username,
-        )
-        .unwrap();
-        mock.expect_registration_start().times(1).return_once(|_| {
-            Ok(registration::ServerRegistrationStartResponse {
-                server_data: "".to_string(),
-                registration_response: start_response.message,
-            })
-        });
-        mock.expect_registration_finish()
             .times(1)
-            .return_once(|_| Ok(()));
-        let mut ldap_handler = setup_bound_admin_handler(mock).await;
+            .return_once(|_| Ok(groups));
+        let mut ldap_handler = setup_bound_password_manager_handler(mock).await;
         let request = LdapOp::ExtendedRequest(
             LdapPasswordModifyRequest {
                 user_identity: Some("uid=bob,ou=people,dc=example,dc=com".to_string()),
-                old_password: None,
+                old_password: Some("pass".to_string()),
                 new_password: Some("password".to_string()),
             }
             .into(),
@@ -1852,46 +5431,24 @@ mod tests {
         assert_eq!(
             ldap_handler.handle_ldap_message(request).await,
             Some(vec![make_extended_response(
-                LdapResultCode::Success,
-                "".to_string(),
+                LdapResultCode::InsufficentAccessRights,
+                "User `test` cannot modify the password of user `bob`".to_string(),
             )])
         );
     }
 
     #[tokio::test]
-    async fn test_password_change_password_manager() {
+    async fn test_password_change_unauthorized_readonly() {
         let mut mock = MockTestBackendHandler::new();
         mock.expect_get_user_groups()
             .with(eq(UserId::new("bob")))
-            .returning(|_| Ok(HashSet::new()));
-        use lldap_auth::*;
-        let mut rng = rand::rngs::OsRng;
-        let registration_start_request =
-            opaque::client::registration::start_registration("password", &mut rng).unwrap();
-        let request = registration::ClientRegistrationStartRequest {
-            username: "bob".to_string(),
-            registration_start_request: registration_start_request.message,
-        };
-        let start_response = opaque::server::registration::start_registration(
-            &opaque::server::ServerSetup::new(&mut rng),
-            request.registration_start_request,
-            &request.username,
-        )
-        .unwrap();
-        mock.expect_registration_start().times(1).return_once(|_| {
-            Ok(registration::ServerRegistrationStartResponse {
-                server_data: "".to_string(),
-                registration_response: start_response.message,
-            })
-        });
-        mock.expect_registration_finish()
             .times(1)
-            .return_once(|_| Ok(()));
-        let mut ldap_handler = setup_bound_password_manager_handler(mock).await;
+            .return_once(|_| Ok(HashSet::new()));
+        let mut ldap_handler = setup_bound_readonly_handler(mock).await;
         let request = LdapOp::ExtendedRequest(
             LdapPasswordModifyRequest {
                 user_identity: Some("uid=bob,ou=people,dc=example,dc=com".to_string()),
-                old_password: None,
+                old_password: Some("pass".to_string()),
                 new_password: Some("password".to_string()),
             }
             .into(),
@@ -1899,138 +5456,456 @@ mod tests {
         assert_eq!(
             ldap_handler.handle_ldap_message(request).await,
             Some(vec![make_extended_response(
-                LdapResultCode::Success,
-                "".to_string(),
+                LdapResultCode::InsufficentAccessRights,
+                "User `test` cannot modify the password of user `bob`".to_string(),
             )])
         );
     }
 
+    #[test]
+    fn test_root_dse_advertises_the_implemented_features() {
+        let attributes = match root_dse_response("dc=example,dc=com", true, &[]) {
+            LdapOp::SearchResultEntry(entry) => entry.attributes,
+            op => panic!("Unexpected op: {:?}", op),
+        };
+        assert!(attributes
+            .iter()
+            .all(|attribute| !attribute.vals.is_empty()));
+        let values = |name: &str| -> Vec<String> {
+            attributes
+                .iter()
+                .find(|attribute| attribute.atype == name)
+                .unwrap()
+                .vals
+                .iter()
+                .map(|val| String::from_utf8(val.clone()).unwrap())
+                .collect()
+        };
+        assert_eq!(
+            values("supportedExtension"),
+            vec![PASSWORD_MODIFY_OID, WHOAMI_OID, START_TLS_OID]
+        );
+        assert_eq!(values("namingContexts"), vec!["dc=example,dc=com"]);
+        assert!(!attributes
+            .iter()
+            .any(|attribute| attribute.atype == "supportedSASLMechanisms"));
+        let search = LdapOp::SearchRequest(make_user_search_request(
+            LdapFilter::And(vec![]),
+            vec!["objectClass"],
+        ));
+        let delete = LdapOp::DelRequest("uid=bob,ou=people,dc=example,dc=com".to_string());
+        let controls = values("supportedControl");
+        assert!(controls.contains(&PAGED_RESULTS_OID.to_string()));
+        for control in controls {
+            assert!(
+                is_supported_control(&search, &control) || is_supported_control(&delete, &control),
+                "{}",
+                control
+            );
+        }
+        match root_dse_response("dc=example,dc=com", false, &["EXTERNAL", scram::MECHANISM]) {
+            LdapOp::SearchResultEntry(entry) => {
+                assert!(entry.attributes.contains(&LdapPartialAttribute {
+                    atype: "supportedSASLMechanisms".to_string(),
+                    vals: vec![b"EXTERNAL".to_vec(), b"SCRAM-SHA-256".to_vec()],
+                }))
+            }
+            op => panic!("Unexpected op: {:?}", op),
+        }
+    }
+
+    fn make_anonymous_bind_request() -> LdapBindRequest {
+        LdapBindRequest {
+            dn: "".to_string(),
+            cred: LdapBindCred::Simple("".to_string()),
+        }
+    }
+
+    fn make_root_dse_request() -> LdapSearchRequest {
+        make_search_request(
+            "",
+            LdapFilter::Present("objectClass".to_string()),
+            vec!["*"],
+        )
+    }
+
     #[tokio::test]
-    async fn test_password_change_errors() {
+    async fn test_anonymous_bind_disallow() {
+        let mut ldap_handler = LdapHandler::new(
+            MockTestBackendHandler::new(),
+            "dc=example,dc=com".to_string(),
+            vec![],
+            vec![],
+        )
+        .with_anonymous_bind(LdapAnonymousBind::Disallow, vec![]);
+        assert_eq!(
+            ldap_handler.do_bind(&make_anonymous_bind_request()).await.0,
+            LdapResultCode::InappropriateAuthentication
+        );
+        assert_eq!(
+            ldap_handler
+                .do_search_or_dse(&make_root_dse_request())
+                .await
+                .unwrap_err()
+                .code,
+            LdapResultCode::InsufficentAccessRights
+        );
+    }
+
+    #[tokio::test]
+    async fn test_anonymous_bind_allow_root_dse_only() {
+        let mut ldap_handler = setup_bound_admin_handler(MockTestBackendHandler::new()).await;
+        // The anonymous bind ends the session of the admin.
+        assert_eq!(
+            ldap_handler.do_bind(&make_anonymous_bind_request()).await,
+            (LdapResultCode::Success, "".to_string())
+        );
+        assert_eq!(ldap_handler.bound_user(), None);
+        assert_eq!(
+            ldap_handler
+                .do_search_or_dse(&make_root_dse_request())
+                .await,
+            Ok(vec![
+                root_dse_response("dc=example,dc=com", false, &[]),
+                make_search_success()
+            ])
+        );
+        let request = make_user_search_request::<String>(LdapFilter::And(vec![]), vec![]);
+        assert_eq!(
+            ldap_handler
+                .do_search_or_dse(&request)
+                .await
+                .unwrap_err()
+                .code,
+            LdapResultCode::InsufficentAccessRights
+        );
+    }
+
+    #[tokio::test]
+    async fn test_anonymous_search_allow_read() {
         let mut mock = MockTestBackendHandler::new();
-        mock.expect_get_user_groups()
-            .with(eq(UserId::new("bob")))
-            .returning(|_| Ok(HashSet::new()));
-        let mut ldap_handler = setup_bound_admin_handler(mock).await;
-        let request = LdapOp::ExtendedRequest(
-            LdapPasswordModifyRequest {
-                user_identity: None,
-                old_password: None,
-                new_password: None,
-            }
-            .into(),
+        mock.expect_list_users()
+            .with(
+                eq(Some(UserRequestFilter::Equality(
+                    UserColumn::Email,
+                    "bob@example.com".to_string(),
+                ))),
+                eq(false),
+            )
+            .times(1)
+            .return_once(|_, _| {
+                Ok(vec![UserAndGroups {
+                    user: User {
+                        user_id: UserId::new("bob"),
+                        email: "bob@example.com".to_string(),
+                        display_name: Some("Bob".to_string()),
+                        ..Default::default()
+                    },
+                    groups: None,
+                }])
+            });
+        let mut ldap_handler =
+            LdapHandler::new(mock, "dc=example,dc=com".to_string(), vec![], vec![])
+                .with_anonymous_bind(
+                    LdapAnonymousBind::AllowRead,
+                    vec!["cn".to_string(), "mail".to_string()],
+                );
+        assert_eq!(
+            ldap_handler.do_bind(&make_anonymous_bind_request()).await.0,
+            LdapResultCode::Success
+        );
+        let request = make_user_search_request(
+            LdapFilter::Equality("mail".to_string(), "bob@example.com".to_string()),
+            vec!["cn", "uid"],
+        );
+        assert_eq!(
+            ldap_handler.do_search_or_dse(&request).await,
+            Ok(vec![
+                LdapOp::SearchResultEntry(LdapSearchResultEntry {
+                    dn: "uid=bob,ou=people,dc=example,dc=com".to_string(),
+                    attributes: vec![LdapPartialAttribute {
+                        atype: "cn".to_string(),
+                        vals: vec![b"Bob".to_vec()]
+                    }],
+                }),
+                make_search_success(),
+            ])
+        );
+        // The other attributes can't be probed with the filter.
+        let request = make_user_search_request(
+            LdapFilter::Or(vec![
+                LdapFilter::Present("objectClass".to_string()),
+                LdapFilter::Equality("uid".to_string(), "bob".to_string()),
+            ]),
+            vec!["cn"],
+        );
+        assert_eq!(
+            ldap_handler.do_search_or_dse(&request).await,
+            Err(LdapError {
+                code: LdapResultCode::InsufficentAccessRights,
+                message: "Anonymous searches cannot filter on uid".to_string(),
+            })
+        );
+    }
+
+    #[tokio::test]
+    async fn test_search_root_dse() {
+        let mut ldap_handler = setup_bound_admin_handler(MockTestBackendHandler::new()).await;
+        let request = LdapSearchRequest {
+            base: "".to_string(),
+            scope: LdapSearchScope::Base,
+            aliases: LdapDerefAliases::Never,
+            sizelimit: 0,
+            timelimit: 0,
+            typesonly: false,
+            filter: LdapFilter::Present("objectClass".to_string()),
+            attrs: vec!["supportedExtension".to_string()],
+        };
+        assert_eq!(
+            ldap_handler.do_search_or_dse(&request).await,
+            Ok(vec![
+                root_dse_response("dc=example,dc=com", false, &[]),
+                make_search_success()
+            ])
+        );
+        // StartTLS is advertised until the connection is upgraded.
+        let mut ldap_handler =
+            ldap_handler.with_connection_security(ConnectionSecurity::StartTlsAvailable, false);
+        assert_eq!(
+            ldap_handler.do_search_or_dse(&request).await,
+            Ok(vec![
+                root_dse_response("dc=example,dc=com", true, &[]),
+                make_search_success()
+            ])
         );
+        ldap_handler.set_tls_established(None);
+        assert_eq!(
+            ldap_handler.do_search_or_dse(&request).await,
+            Ok(vec![
+                root_dse_response("dc=example,dc=com", false, &[]),
+                make_search_success()
+            ])
+        );
+    }
+
+    fn make_group(id: i32, name: &str) -> Group {
+        Group {
+            id: GroupId(id),
+            display_name: name.to_string(),
+            creation_date: chrono::Utc.timestamp_opt(42, 42).unwrap(),
+            modified_at: chrono::Utc.timestamp_opt(42, 42).unwrap(),
+            users: vec![],
+            uuid: uuid!("04ac75e0-2900-3e21-926c-2f732c26b3fc"),
+            gid_number: None,
+        }
+    }
+
+    fn subtree_delete_control() -> RequestControl {
+        RequestControl {
+            oid: SUBTREE_DELETE_OID.to_string(),
+            criticality: true,
+            value: None,
+        }
+    }
+
+    #[tokio::test]
+    async fn test_delete_user_and_group() {
+        let mut mock = MockTestBackendHandler::new();
+        mock.expect_delete_user()
+            .with(eq(UserId::new("bob")))
+            .times(1)
+            .return_once(|_| Ok(()));
+        mock.expect_list_groups()
+            .with(eq(Some(GroupRequestFilter::DisplayName(
+                "rockstars".to_string(),
+            ))))
+            .times(1)
+            .return_once(|_| Ok(vec![make_group(2, "rockstars")]));
+        mock.expect_delete_group()
+            .with(eq(GroupId(2)))
+            .times(1)
+            .return_once(|_| Ok(()));
+        let mut ldap_handler = setup_bound_admin_handler(mock).await;
         assert_eq!(
-            ldap_handler.handle_ldap_message(request).await,
-            Some(vec![make_extended_response(
-                LdapResultCode::ConstraintViolation,
-                "Missing either user_id or password".to_string(),
+            ldap_handler
+                .handle_ldap_message(LdapOp::DelRequest(
+                    "uid=bob,ou=people,dc=example,dc=com".to_string()
+                ))
+                .await,
+            Some(vec![make_del_response(
+                LdapResultCode::Success,
+                "".to_string()
             )])
         );
-        let request = LdapOp::ExtendedRequest(
-            LdapPasswordModifyRequest {
-                user_identity: Some("uid=bob,ou=groups,ou=people,dc=example,dc=com".to_string()),
-                old_password: None,
-                new_password: Some("password".to_string()),
-            }
-            .into(),
-        );
         assert_eq!(
-            ldap_handler.handle_ldap_message(request).await,
-            Some(vec![make_extended_response(
-                LdapResultCode::InvalidDNSyntax,
-                r#"Invalid username: Unexpected DN format. Got "uid=bob,ou=groups,ou=people,dc=example,dc=com", expected: "uid=id,ou=people,dc=example,dc=com""#.to_string(),
+            ldap_handler
+                .handle_ldap_message(LdapOp::DelRequest(
+                    "cn=RockStars,ou=groups,dc=example,dc=com".to_string()
+                ))
+                .await,
+            Some(vec![make_del_response(
+                LdapResultCode::Success,
+                "".to_string()
             )])
         );
-        let request = LdapOp::ExtendedRequest(LdapExtendedRequest {
-            name: "test".to_string(),
-            value: None,
-        });
+    }
+
+    #[tokio::test]
+    async fn test_delete_not_admin() {
+        let mut ldap_handler = setup_bound_readonly_handler(MockTestBackendHandler::new()).await;
         assert_eq!(
-            ldap_handler.handle_ldap_message(request).await,
-            Some(vec![make_extended_response(
-                LdapResultCode::UnwillingToPerform,
-                "Unsupported extended operation: test".to_string(),
-            )])
+            ldap_handler
+                .handle_ldap_message_with_controls(
+                    LdapOp::DelRequest("ou=groups,dc=example,dc=com".to_string()),
+                    &[],
+                    &[subtree_delete_control()],
+                )
+                .await,
+            Some((
+                vec![make_del_response(
+                    LdapResultCode::InsufficentAccessRights,
+                    "Unauthorized write".to_string()
+                )],
+                vec![]
+            ))
         );
     }
 
     #[tokio::test]
-    async fn test_password_change_unauthorized_password_manager() {
+    async fn test_subtree_delete_groups() {
         let mut mock = MockTestBackendHandler::new();
-        let mut groups = HashSet::new();
-        groups.insert(GroupDetails {
-            group_id: GroupId(0),
-            display_name: "lldap_admin".to_string(),
-            creation_date: chrono::Utc.timestamp_opt(42, 42).unwrap(),
-            uuid: uuid!("a1a2a3a4b1b2c1c2d1d2d3d4d5d6d7d8"),
-        });
-        mock.expect_get_user_groups()
-            .with(eq(UserId::new("bob")))
+        mock.expect_list_groups()
+            .with(eq(None))
             .times(1)
-            .return_once(|_| Ok(groups));
-        let mut ldap_handler = setup_bound_password_manager_handler(mock).await;
-        let request = LdapOp::ExtendedRequest(
-            LdapPasswordModifyRequest {
-                user_identity: Some("uid=bob,ou=people,dc=example,dc=com".to_string()),
-                old_password: Some("pass".to_string()),
-                new_password: Some("password".to_string()),
-            }
-            .into(),
-        );
+            .return_once(|_| {
+                Ok(vec![
+                    make_group(1, "lldap_admin"),
+                    make_group(2, "rockstars"),
+                ])
+            });
+        mock.expect_delete_group()
+            .with(eq(GroupId(1)))
+            .times(1)
+            .return_once(|_| Ok(()));
+        mock.expect_delete_group()
+            .with(eq(GroupId(2)))
+            .times(1)
+            .return_once(|_| Ok(()));
+        let mut ldap_handler = setup_bound_admin_handler(mock).await;
+        let request = LdapOp::DelRequest("ou=groups,dc=example,dc=com".to_string());
+        // Without the control, the OU is not a leaf.
+        assert!(matches!(
+            ldap_handler
+                .handle_ldap_message_with_controls(request.clone(), &[], &[])
+                .await
+                .as_ref()
+                .map(|(responses, _)| responses.as_slice()),
+            Some([LdapOp::DelResponse(LdapResultOp {
+                code: LdapResultCode::NotAllowedOnNonLeaf,
+                ..
+            })])
+        ));
         assert_eq!(
-            ldap_handler.handle_ldap_message(request).await,
-            Some(vec![make_extended_response(
-                LdapResultCode::InsufficentAccessRights,
-                "User `test` cannot modify the password of user `bob`".to_string(),
-            )])
+            ldap_handler
+                .handle_ldap_message_with_controls(request, &[], &[subtree_delete_control()])
+                .await,
+            Some((
+                vec![make_del_response(LdapResultCode::Success, "".to_string())],
+                vec![]
+            ))
         );
+        // The people OU is never deleted.
+        assert!(matches!(
+            ldap_handler
+                .handle_ldap_message_with_controls(
+                    LdapOp::DelRequest("ou=people,dc=example,dc=com".to_string()),
+                    &[],
+                    &[subtree_delete_control()],
+                )
+                .await
+                .as_ref()
+                .map(|(responses, _)| responses.as_slice()),
+            Some([LdapOp::DelResponse(LdapResultOp {
+                code: LdapResultCode::UnwillingToPerform,
+                ..
+            })])
+        ));
     }
 
     #[tokio::test]
-    async fn test_password_change_unauthorized_readonly() {
+    async fn test_unsupported_controls() {
         let mut mock = MockTestBackendHandler::new();
-        mock.expect_get_user_groups()
+        mock.expect_delete_user()
             .with(eq(UserId::new("bob")))
             .times(1)
-            .return_once(|_| Ok(HashSet::new()));
-        let mut ldap_handler = setup_bound_readonly_handler(mock).await;
-        let request = LdapOp::ExtendedRequest(
-            LdapPasswordModifyRequest {
-                user_identity: Some("uid=bob,ou=people,dc=example,dc=com".to_string()),
-                old_password: Some("pass".to_string()),
-                new_password: Some("password".to_string()),
-            }
-            .into(),
+            .return_once(|_| Ok(()));
+        let mut ldap_handler = setup_bound_admin_handler(mock).await;
+        let unknown_control = |criticality| RequestControl {
+            oid: "1.2.3.4".to_string(),
+            criticality,
+            value: None,
+        };
+        // A critical control that isn't supported fails the request.
+        assert_eq!(
+            ldap_handler
+                .handle_ldap_message_with_controls(
+                    LdapOp::SearchRequest(make_user_search_request::<String>(
+                        LdapFilter::And(vec![]),
+                        vec![],
+                    )),
+                    &[],
+                    &[unknown_control(true)],
+                )
+                .await,
+            Some((
+                vec![make_search_error(
+                    LdapResultCode::UnavailableCriticalExtension,
+                    "Unsupported critical control: 1.2.3.4".to_string()
+                )],
+                vec![]
+            ))
         );
+        // The subtree delete control only applies to the deletions.
         assert_eq!(
-            ldap_handler.handle_ldap_message(request).await,
-            Some(vec![make_extended_response(
-                LdapResultCode::InsufficentAccessRights,
-                "User `test` cannot modify the password of user `bob`".to_string(),
-            )])
+            ldap_handler
+                .handle_ldap_message_with_controls(
+                    LdapOp::CompareRequest(LdapCompareRequest {
+                        dn: "uid=bob,ou=people,dc=example,dc=com".to_string(),
+                        atype: "uid".to_string(),
+                        val: b"bob".to_vec(),
+                    }),
+                    &[],
+                    &[subtree_delete_control()],
+                )
+                .await,
+            Some((
+                vec![make_compare_result(
+                    LdapResultCode::UnavailableCriticalExtension,
+                    format!("Unsupported critical control: {}", SUBTREE_DELETE_OID)
+                )],
+                vec![]
+            ))
         );
-    }
-
-    #[tokio::test]
-    async fn test_search_root_dse() {
-        let mut ldap_handler = setup_bound_admin_handler(MockTestBackendHandler::new()).await;
-        let request = LdapSearchRequest {
-            base: "".to_string(),
-            scope: LdapSearchScope::Base,
-            aliases: LdapDerefAliases::Never,
-            sizelimit: 0,
-            timelimit: 0,
-            typesonly: false,
-            filter: LdapFilter::Present("objectClass".to_string()),
-            attrs: vec!["supportedExtension".to_string()],
-        };
+        // The non-critical ones are ignored, and ManageDsaIT is always supported.
         assert_eq!(
-            ldap_handler.do_search_or_dse(&request).await,
-            Ok(vec![
-                root_dse_response("dc=example,dc=com"),
-                make_search_success()
-            ])
+            ldap_handler
+                .handle_ldap_message_with_controls(
+                    LdapOp::DelRequest("uid=bob,ou=people,dc=example,dc=com".to_string()),
+                    &[],
+                    &[
+                        unknown_control(false),
+                        RequestControl {
+                            oid: MANAGE_DSA_IT_OID.to_string(),
+                            criticality: true,
+                            value: None,
+                        }
+                    ],
+                )
+                .await,
+            Some((
+                vec![make_del_response(LdapResultCode::Success, "".to_string())],
+                vec![]
+            ))
         );
     }
 
@@ -2080,7 +5955,10 @@ mod tests {
     async fn test_search_filter_non_attribute() {
         let mut mock = MockTestBackendHandler::new();
         mock.expect_list_users()
-            .with(eq(Some(UserRequestFilter::And(vec![]))), eq(false))
+            .with(
+                eq(Some(UserRequestFilter::Present(UserColumn::DisplayName))),
+                eq(false),
+            )
             .times(1)
             .return_once(|_, _| Ok(vec![]));
         let mut ldap_handler = setup_bound_admin_handler(mock).await;
@@ -2093,4 +5971,234 @@ mod tests {
             Ok(vec![make_search_success()])
         );
     }
+
+    #[tokio::test]
+    async fn test_search_filter_matching_rules() {
+        let mut mock = MockTestBackendHandler::new();
+        mock.expect_list_users()
+            .with(
+                eq(Some(UserRequestFilter::And(vec![
+                    UserRequestFilter::EqualityIgnoreCase(
+                        UserColumn::DisplayName,
+                        "BOB".to_owned(),
+                    ),
+                    UserRequestFilter::Equality(UserColumn::FirstName, "Bob".to_owned()),
+                    UserRequestFilter::UserId(UserId::new("bob")),
+                    UserRequestFilter::And(vec![]),
+                ]))),
+                eq(false),
+            )
+            .times(1)
+            .return_once(|_, _| Ok(vec![]));
+        let mut ldap_handler = setup_bound_admin_handler(mock).await;
+        let request = make_user_search_request(
+            LdapFilter::And(vec![
+                LdapFilter::Equality("cn:caseIgnoreMatch".to_owned(), "BOB".to_owned()),
+                LdapFilter::Equality("givenName:2.5.13.5".to_owned(), "Bob".to_owned()),
+                LdapFilter::Equality("uid:caseExactMatch".to_owned(), "Bob".to_owned()),
+                LdapFilter::Present("mail".to_owned()),
+            ]),
+            vec!["objectClass"],
+        );
+        assert_eq!(
+            ldap_handler.do_search_or_dse(&request).await,
+            Ok(vec![make_search_success()])
+        );
+        for attribute in ["cn:2.5.13.15", "cn:dn:caseExactMatch", ":caseExactMatch"] {
+            let request = make_user_search_request(
+                LdapFilter::Equality(attribute.to_owned(), "Bob".to_owned()),
+                vec!["objectClass"],
+            );
+            assert_eq!(
+                ldap_handler.do_search_or_dse(&request).await,
+                Err(LdapError {
+                    code: LdapResultCode::UnwillingToPerform,
+                    message: format!(
+                        r#"Unsupported matching rule in the filter on "{}""#,
+                        attribute
+                    ),
+                })
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn test_search_filter_ordering() {
+        let mut mock = MockTestBackendHandler::new();
+        mock.expect_list_users()
+            .with(
+                eq(Some(UserRequestFilter::And(vec![
+                    UserRequestFilter::Ordering(
+                        UserColumn::UidNumber,
+                        Comparison::GreaterOrEqual,
+                        OrderedValue::Integer(1000),
+                    ),
+                    UserRequestFilter::Ordering(
+                        UserColumn::CreationDate,
+                        Comparison::LessOrEqual,
+                        OrderedValue::DateTime(
+                            chrono::Utc.with_ymd_and_hms(2024, 1, 1, 0, 0, 0).unwrap(),
+                        ),
+                    ),
+                    UserRequestFilter::AttributeOrdering(
+                        AttributeName::new("employeenumber"),
+                        Comparison::GreaterOrEqual,
+                        "12".to_owned(),
+                    ),
+                ]))),
+                eq(false),
+            )
+            .times(1)
+            .return_once(|_, _| Ok(vec![]));
+        let mut ldap_handler = setup_bound_admin_handler(mock).await;
+        let request = make_user_search_request(
+            LdapFilter::And(vec![
+                LdapFilter::Equality("uidNumber>=".to_owned(), "1000".to_owned()),
+                LdapFilter::Equality("createTimestamp<=".to_owned(), "20240101000000Z".to_owned()),
+                LdapFilter::Equality("employeeNumber>=".to_owned(), "12".to_owned()),
+            ]),
+            vec!["objectClass"],
+        );
+        assert_eq!(
+            ldap_handler.do_search_or_dse(&request).await,
+            Ok(vec![make_search_success()])
+        );
+        let request = make_user_search_request(
+            LdapFilter::Equality("cn>=".to_owned(), "Bob".to_owned()),
+            vec!["objectClass"],
+        );
+        assert_eq!(
+            ldap_handler.do_search_or_dse(&request).await,
+            Err(LdapError {
+                code: LdapResultCode::InappropriateMatching,
+                message: r#"The attribute "cn" has no ordering"#.to_owned(),
+            })
+        );
+        let request = make_user_search_request(
+            LdapFilter::Equality("uidNumber<=".to_owned(), "many".to_owned()),
+            vec!["objectClass"],
+        );
+        assert_eq!(
+            ldap_handler.do_search_or_dse(&request).await,
+            Err(LdapError {
+                code: LdapResultCode::InvalidAttributeSyntax,
+                message: r#"Invalid filter on "uidnumber": Invalid integer: "many""#.to_owned(),
+            })
+        );
+        let request = make_search_request(
+            "ou=groups,dc=example,dc=com",
+            LdapFilter::Equality("gidNumber>=".to_owned(), "1000".to_owned()),
+            vec!["cn"],
+        );
+        assert_eq!(
+            ldap_handler.do_search_or_dse(&request).await,
+            Err(LdapError {
+                code: LdapResultCode::UnwillingToPerform,
+                message: r#"Unsupported group filter: Equality("gidNumber>=", "1000")"#.to_owned(),
+            })
+        );
+    }
+
+    #[tokio::test]
+    async fn test_search_scopes() {
+        // None of these reach the backend.
+        let mut ldap_handler = setup_bound_admin_handler(MockTestBackendHandler::new()).await;
+        for (base, scope) in [
+            ("dc=example,dc=com", LdapSearchScope::Base),
+            ("dc=example,dc=com", LdapSearchScope::OneLevel),
+            ("ou=people,dc=example,dc=com", LdapSearchScope::Base),
+            ("ou=groups,dc=example,dc=com", LdapSearchScope::Base),
+            (
+                "uid=bob,ou=people,dc=example,dc=com",
+                LdapSearchScope::OneLevel,
+            ),
+            (
+                "cn=rockstars,ou=groups,dc=example,dc=com",
+                LdapSearchScope::OneLevel,
+            ),
+            ("ou=sudoers,dc=example,dc=com", LdapSearchScope::Base),
+        ] {
+            let request = LdapSearchRequest {
+                scope: scope.clone(),
+                ..make_search_request(base, LdapFilter::And(vec![]), vec!["cn"])
+            };
+            assert_eq!(
+                ldap_handler.do_search_or_dse(&request).await,
+                Ok(vec![make_search_success()]),
+                "{} {:?}",
+                base,
+                scope
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn test_search_organizational_units() {
+        let mut mock = MockTestBackendHandler::new();
+        mock.expect_list_users()
+            .with(eq(Some(UserRequestFilter::And(vec![]))), eq(true))
+            .times(1)
+            .return_once(|_, _| {
+                Ok(vec![UserAndGroups {
+                    user: User {
+                        user_id: UserId::new("bob"),
+                        ..Default::default()
+                    },
+                    groups: Some(vec![GroupDetails {
+                        group_id: GroupId(42),
+                        display_name: "rockstars".to_string(),
+                        creation_date: chrono::Utc.timestamp_opt(42, 42).unwrap(),
+                        uuid: uuid!("a1a2a3a4b1b2c1c2d1d2d3d4d5d6d7d8"),
+                    }]),
+                }])
+            });
+        let mut ldap_handler = setup_bound_admin_handler(mock)
+            .await
+            .with_organizational_units("Staff", "teams");
+        let request = LdapSearchRequest {
+            scope: LdapSearchScope::OneLevel,
+            ..make_search_request(
+                "ou=STAFF,dc=example,dc=com",
+                LdapFilter::And(vec![]),
+                vec!["memberOf"],
+            )
+        };
+        assert_eq!(
+            ldap_handler.do_search_or_dse(&request).await,
+            Ok(vec![
+                LdapOp::SearchResultEntry(LdapSearchResultEntry {
+                    dn: "uid=bob,ou=staff,dc=example,dc=com".to_string(),
+                    attributes: vec![LdapPartialAttribute {
+                        atype: "memberOf".to_string(),
+                        vals: vec![b"uid=rockstars,ou=teams,dc=example,dc=com".to_vec()]
+                    }],
+                }),
+                make_search_success(),
+            ]),
+        );
+        // The default OUs are not served anymore.
+        let request = make_user_search_request(LdapFilter::And(vec![]), vec!["memberOf"]);
+        assert_eq!(
+            ldap_handler.do_search_or_dse(&request).await,
+            Ok(vec![make_search_success()]),
+        );
+        assert_eq!(
+            get_user_id_from_distinguished_name(
+                "uid=bob,ou=staff,dc=example,dc=com",
+                &ldap_handler.ldap_info
+            ),
+            Ok(UserId::new("bob"))
+        );
+        assert_eq!(
+            get_group_id_from_distinguished_name(
+                "cn=rockstars,ou=groups,dc=example,dc=com",
+                &ldap_handler.ldap_info
+            ),
+            Err(LdapError {
+                code: LdapResultCode::InvalidDNSyntax,
+                message: r#"Unexpected DN format. Got "cn=rockstars,ou=groups,dc=example,dc=com", expected: "uid=id,ou=teams,dc=example,dc=com""#
+                    .to_string(),
+            })
+        );
+    }
 }
@@ -2,46 +2,165 @@ use crate::{
     domain::{
         handler::{BackendHandler, LoginHandler},
         opaque_handler::OpaqueHandler,
+        password_policy::PasswordPolicy,
+    },
+    infra::{
+        access_control::AttributeAcl,
+        client_certificate::ClientCertificate,
+        client_ip::{read_proxy_header, TrustedProxies},
+        configuration::{
+            ClientCertificateUserMapping, Configuration, LdapAnonymousBind, LdapObjectClasses,
+            LdapUnindexedSort, ListenerOptions, ListenerProtocol, PosixOptions, UserRdnAttribute,
+        },
+        connection_limiter::{ConnectionLimiter, ConnectionRejected},
+        correlation_id::new_correlation_id,
+        ldap_controls::{LdapControlsCodec, LdapRequest, ResponseControl},
+        ldap_handler::{ConnectionSecurity, LdapHandler},
+        operation_timeout::OperationTimeouts,
+        rate_limiter::SharedRateLimiter,
+        shutdown::{ConnectionGuard, ShutdownCoordinator},
+        tls_certificate::{watch_certificate, ReloadableCertificate},
+        tls_policy::TlsPolicy,
     },
-    infra::{configuration::Configuration, ldap_handler::LdapHandler},
 };
 use actix_rt::net::TcpStream;
 use actix_server::ServerBuilder;
 use actix_service::{fn_service, ServiceFactoryExt};
 use anyhow::{anyhow, Context, Result};
-use ldap3_proto::{proto::LdapMsg, LdapCodec};
-use rustls::PrivateKey;
-use tokio_rustls::TlsAcceptor as RustlsTlsAcceptor;
+use bytes::BytesMut;
+use ldap3_proto::proto::{
+    LdapExtendedResponse, LdapMsg, LdapOp, LdapResult as LdapResultOp, LdapResultCode,
+};
+use std::{collections::HashMap, net::IpAddr, pin::Pin, sync::Arc, task::Poll, time::Duration};
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio_rustls::{server::TlsStream, TlsAcceptor as RustlsTlsAcceptor};
 use tokio_util::codec::{FramedRead, FramedWrite};
-use tracing::{debug, error, info, instrument};
+use tracing::{debug, error, info, instrument, warn, Span};
+
+/// Logs the operations without the secrets they carry: the bind credentials, the passwords of
+/// the extended operations (and the generated ones in their responses), the compared passwords
+/// and the attributes of the new entries.
+struct Redacted<'a>(&'a LdapOp);
 
-#[instrument(skip_all, level = "info", name = "LDAP request")]
+impl std::fmt::Debug for Redacted<'_> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self.0 {
+            LdapOp::BindRequest(request) => f
+                .debug_struct("BindRequest")
+                .field("dn", &request.dn)
+                .finish_non_exhaustive(),
+            LdapOp::ExtendedRequest(request) => f
+                .debug_struct("ExtendedRequest")
+                .field("name", &request.name)
+                .finish_non_exhaustive(),
+            LdapOp::ExtendedResponse(response) => f
+                .debug_struct("ExtendedResponse")
+                .field("code", &response.res.code)
+                .field("name", &response.name)
+                .finish_non_exhaustive(),
+            LdapOp::CompareRequest(request)
+                if request.atype.eq_ignore_ascii_case("userPassword") =>
+            {
+                f.debug_struct("CompareRequest")
+                    .field("dn", &request.dn)
+                    .field("atype", &request.atype)
+                    .finish_non_exhaustive()
+            }
+            LdapOp::AddRequest(request) => f
+                .debug_struct("AddRequest")
+                .field("dn", &request.dn)
+                .finish_non_exhaustive(),
+            op => op.fmt(f),
+        }
+    }
+}
+
+/// The OID of the unsolicited notification sent before the server closes a connection.
+const NOTICE_OF_DISCONNECTION_OID: &str = "1.3.6.1.4.1.1466.20036";
+/// How long a trusted proxy has to send the PROXY protocol header.
+const PROXY_HEADER_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Tells the client that the server is closing the connection (RFC 4511, section 4.4.1).
+fn notice_of_disconnection(code: LdapResultCode, message: &str) -> LdapMsg {
+    LdapMsg {
+        msgid: 0,
+        op: LdapOp::ExtendedResponse(LdapExtendedResponse {
+            res: LdapResultOp {
+                code,
+                matcheddn: "".to_string(),
+                message: message.to_string(),
+                referral: vec![],
+            },
+            name: Some(NOTICE_OF_DISCONNECTION_OID.to_string()),
+            value: None,
+        }),
+        ctrl: vec![],
+    }
+}
+
+#[instrument(
+    skip_all,
+    level = "info",
+    name = "LDAP request",
+    fields(user = tracing::field::Empty, correlation_id = tracing::field::Empty)
+)]
 async fn handle_ldap_message<Backend, Writer>(
-    msg: Result<LdapMsg, std::io::Error>,
+    request: Result<LdapRequest, std::io::Error>,
     resp: &mut Writer,
     session: &mut LdapHandler<Backend>,
 ) -> Result<bool>
 where
     Backend: BackendHandler + LoginHandler + OpaqueHandler,
-    Writer: futures_util::Sink<LdapMsg> + Unpin,
-    <Writer as futures_util::Sink<LdapMsg>>::Error: std::error::Error + Send + Sync + 'static,
+    Writer: futures_util::Sink<(LdapMsg, Vec<ResponseControl>)> + Unpin,
+    <Writer as futures_util::Sink<(LdapMsg, Vec<ResponseControl>)>>::Error:
+        std::error::Error + Send + Sync + 'static,
 {
     use futures_util::SinkExt;
-    let msg = msg.context("while receiving LDAP op")?;
-    debug!(?msg);
-    match session.handle_ldap_message(msg.op).await {
+    let LdapRequest {
+        msg,
+        controls: request_controls,
+        sasl_credentials,
+    } = request.context("while receiving LDAP op")?;
+    let correlation_id = new_correlation_id();
+    Span::current().record("correlation_id", &correlation_id.as_str());
+    session.set_correlation_id(correlation_id);
+    debug!(msgid = msg.msgid, op = ?Redacted(&msg.op), ?request_controls);
+    let result = match &sasl_credentials {
+        Some(credentials) => Some((session.handle_sasl_bind(credentials).await, Vec::new())),
+        None => {
+            session
+                .handle_ldap_message_with_controls(msg.op, &msg.ctrl, &request_controls)
+                .await
+        }
+    };
+    // After the handling, to include the user of a bind.
+    if let Some(user) = session.bound_user() {
+        Span::current().record("user", &user.as_str());
+    }
+    match result {
         None => return Ok(false),
-        Some(result) => {
+        Some((result, controls)) => {
             if result.is_empty() {
                 debug!("No response");
             }
-            for response in result.into_iter() {
-                debug!(?response);
-                resp.send(LdapMsg {
-                    msgid: msg.msgid,
-                    op: response,
-                    ctrl: vec![],
-                })
+            let num_responses = result.len();
+            let mut controls = Some(controls);
+            for (i, response) in result.into_iter().enumerate() {
+                debug!(response = ?Redacted(&response));
+                // The response controls go with the final message (e.g. SearchResultDone).
+                let ctrl = if i + 1 == num_responses {
+                    controls.take().unwrap_or_default()
+                } else {
+                    vec![]
+                };
+                resp.send((
+                    LdapMsg {
+                        msgid: msg.msgid,
+                        op: response,
+                        ctrl: vec![],
+                    },
+                    ctrl,
+                ))
                 .await
                 .context("while sending a response: {:#}")?
             }
@@ -54,172 +173,754 @@ where
     Ok(true)
 }
 
-#[instrument(skip_all, level = "info", name = "LDAP session")]
-async fn handle_ldap_stream<Stream, Backend>(
-    stream: Stream,
+/// The settings shared by all the sessions of the LDAP listeners.
+#[derive(Clone)]
+struct LdapSessionContext<Backend> {
     backend_handler: Backend,
     ldap_base_dn: String,
     ignored_user_attributes: Vec<String>,
     ignored_group_attributes: Vec<String>,
-) -> Result<Stream>
+    user_rdn_attribute: UserRdnAttribute,
+    posix_options: PosixOptions,
+    object_classes: LdapObjectClasses,
+    sudoers_base_dn: String,
+    users_ou: String,
+    groups_ou: String,
+    rate_limiter: SharedRateLimiter,
+    password_policy: Arc<PasswordPolicy>,
+    operation_timeouts: OperationTimeouts,
+    unindexed_sort: LdapUnindexedSort,
+    attribute_acl: AttributeAcl,
+    anonymous_bind: LdapAnonymousBind,
+    anonymous_read_attributes: Vec<String>,
+    shutdown: ShutdownCoordinator,
+    require_tls: bool,
+    /// Set if the plaintext connections can be upgraded with StartTLS.
+    start_tls_acceptor: Option<RustlsTlsAcceptor>,
+    /// Set if the TLS connections accept client certificates.
+    sasl_external: Option<ClientCertificateUserMapping>,
+    sasl_scram: bool,
+    connection_limiter: ConnectionLimiter,
+    /// The sessions are closed after this long without a request.
+    idle_timeout: Option<Duration>,
+    trusted_proxies: TrustedProxies,
+    /// The trusted proxies start the connections with a PROXY protocol header.
+    proxy_protocol: bool,
+}
+
+/// A stream that first returns the bytes already read from it, e.g. the start of a TLS handshake
+/// that the client sent right after its StartTLS request.
+struct PrefixedStream<Stream> {
+    prefix: BytesMut,
+    stream: Stream,
+}
+
+impl<Stream: AsyncRead + Unpin> AsyncRead for PrefixedStream<Stream> {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut std::task::Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<std::io::Result<()>> {
+        let this = self.get_mut();
+        if !this.prefix.is_empty() {
+            let len = this.prefix.len().min(buf.remaining());
+            buf.put_slice(&this.prefix.split_to(len));
+            return Poll::Ready(Ok(()));
+        }
+        Pin::new(&mut this.stream).poll_read(cx, buf)
+    }
+}
+
+impl<Stream: AsyncWrite + Unpin> AsyncWrite for PrefixedStream<Stream> {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut std::task::Context<'_>,
+        buf: &[u8],
+    ) -> Poll<std::io::Result<usize>> {
+        Pin::new(&mut self.get_mut().stream).poll_write(cx, buf)
+    }
+
+    fn poll_flush(
+        self: Pin<&mut Self>,
+        cx: &mut std::task::Context<'_>,
+    ) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut self.get_mut().stream).poll_flush(cx)
+    }
+
+    fn poll_shutdown(
+        self: Pin<&mut Self>,
+        cx: &mut std::task::Context<'_>,
+    ) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut self.get_mut().stream).poll_shutdown(cx)
+    }
+}
+
+/// Why the session stopped reading from a stream.
+enum StreamEnd<Stream> {
+    Closed,
+    /// After a successful StartTLS: the stream to upgrade, with what the client sent after the
+    /// request.
+    StartTls(PrefixedStream<Stream>),
+}
+
+/// Resolves after the timeout, if any.
+async fn idle(timeout: Option<Duration>) {
+    match timeout {
+        Some(timeout) => tokio::time::sleep(timeout).await,
+        None => std::future::pending().await,
+    }
+}
+
+async fn serve_ldap_stream<Stream, Backend>(
+    stream: Stream,
+    session: &mut LdapHandler<Backend>,
+    connection: &mut ConnectionGuard,
+    idle_timeout: Option<Duration>,
+) -> Result<StreamEnd<Stream>>
 where
-    Backend: BackendHandler + LoginHandler + OpaqueHandler + 'static,
-    Stream: tokio::io::AsyncRead + tokio::io::AsyncWrite,
+    Backend: BackendHandler + LoginHandler + OpaqueHandler,
+    Stream: AsyncRead + AsyncWrite + Unpin,
 {
+    use futures_util::SinkExt;
     use tokio_stream::StreamExt;
     let (r, w) = tokio::io::split(stream);
     // Configure the codec etc.
-    let mut requests = FramedRead::new(r, LdapCodec);
-    let mut resp = FramedWrite::new(w, LdapCodec);
+    let mut requests = FramedRead::new(r, LdapControlsCodec);
+    let mut resp = FramedWrite::new(w, LdapControlsCodec);
+    loop {
+        // The request being handled is always answered: the shutdown only stops the session
+        // between two requests.
+        let msg = tokio::select! {
+            msg = requests.next() => msg,
+            _ = connection.shutdown_requested() => {
+                debug!("Closing the LDAP session for the shutdown");
+                return Ok(StreamEnd::Closed);
+            }
+            _ = idle(idle_timeout) => {
+                debug!("Closing the idle LDAP session");
+                // The connection is closed anyway.
+                let _ = resp
+                    .send((
+                        notice_of_disconnection(LdapResultCode::Unavailable, "Idle timeout"),
+                        vec![],
+                    ))
+                    .await;
+                return Ok(StreamEnd::Closed);
+            }
+        };
+        let msg = match msg {
+            Some(msg) => msg,
+            None => return Ok(StreamEnd::Closed),
+        };
+        if !handle_ldap_message(msg, &mut resp, session)
+            .await
+            .context("while handling incoming messages")?
+        {
+            return Ok(StreamEnd::Closed);
+        }
+        if session.take_start_tls_request() {
+            // The response is flushed: the next bytes are the TLS handshake, and the codec may
+            // have read some of them already.
+            let prefix = requests.read_buffer().clone();
+            let stream = requests.into_inner().unsplit(resp.into_inner());
+            return Ok(StreamEnd::StartTls(PrefixedStream { prefix, stream }));
+        }
+    }
+}
+
+#[instrument(
+    skip_all,
+    level = "info",
+    name = "LDAP session",
+    fields(session_id = %uuid::Uuid::new_v4(), peer_ip = ?peer_ip)
+)]
+async fn handle_ldap_stream<Stream, Backend>(
+    stream: Stream,
+    context: LdapSessionContext<Backend>,
+    is_tls: bool,
+    client_certificate: Option<ClientCertificate>,
+    peer_ip: Option<IpAddr>,
+) -> Result<()>
+where
+    Backend: BackendHandler + LoginHandler + OpaqueHandler + 'static,
+    Stream: AsyncRead + AsyncWrite + Unpin,
+{
+    let LdapSessionContext {
+        backend_handler,
+        ldap_base_dn,
+        ignored_user_attributes,
+        ignored_group_attributes,
+        user_rdn_attribute,
+        posix_options,
+        object_classes,
+        sudoers_base_dn,
+        users_ou,
+        groups_ou,
+        rate_limiter,
+        password_policy,
+        operation_timeouts,
+        unindexed_sort,
+        attribute_acl,
+        anonymous_bind,
+        anonymous_read_attributes,
+        shutdown,
+        require_tls,
+        start_tls_acceptor,
+        sasl_external,
+        sasl_scram,
+        connection_limiter: _,
+        idle_timeout,
+        trusted_proxies: _,
+        proxy_protocol: _,
+    } = context;
+    let connection_security = if is_tls {
+        ConnectionSecurity::Tls
+    } else if start_tls_acceptor.is_some() {
+        ConnectionSecurity::StartTlsAvailable
+    } else {
+        ConnectionSecurity::Plaintext
+    };
 
     let mut session = LdapHandler::new(
         backend_handler,
         ldap_base_dn,
         ignored_user_attributes,
         ignored_group_attributes,
-    );
+    )
+    .with_user_rdn_attribute(user_rdn_attribute)
+    .with_posix_options(posix_options)
+    .with_object_classes(object_classes)
+    .with_sudoers_base_dn(sudoers_base_dn)
+    .with_organizational_units(&users_ou, &groups_ou)
+    .with_rate_limiter(rate_limiter, peer_ip)
+    .with_password_policy(password_policy)
+    .with_operation_timeouts(operation_timeouts)
+    .with_unindexed_sort(unindexed_sort)
+    .with_attribute_acl(attribute_acl)
+    .with_anonymous_bind(anonymous_bind, anonymous_read_attributes)
+    .with_connection_security(connection_security, require_tls)
+    .with_client_certificate(client_certificate)
+    .with_sasl_external(sasl_external)
+    .with_sasl_scram(sasl_scram);
 
-    while let Some(msg) = requests.next().await {
-        if !handle_ldap_message(msg, &mut resp, &mut session)
+    let mut connection = shutdown.connection();
+    let result: Result<()> = async {
+        let stream =
+            match serve_ldap_stream(stream, &mut session, &mut connection, idle_timeout).await? {
+                StreamEnd::Closed => return Ok(()),
+                StreamEnd::StartTls(stream) => stream,
+            };
+        let tls_acceptor = start_tls_acceptor
+            .as_ref()
+            .context("StartTLS is not enabled")?;
+        let tls_stream = tls_acceptor
+            .accept(stream)
             .await
-            .context("while handling incoming messages")?
-        {
-            break;
+            .context("during the StartTLS handshake")?;
+        debug!("Connection upgraded with StartTLS");
+        session.set_tls_established(get_client_certificate(&tls_stream));
+        // The session refuses StartTLS once encrypted: this is the last stream.
+        serve_ldap_stream(tls_stream, &mut session, &mut connection, idle_timeout)
+            .await
+            .map(|_| ())
+    }
+    .await;
+    connection.finish();
+    result
+}
+
+/// The address of the client. With `proxy_options.ldap_proxy_protocol`, the connections of the
+/// trusted proxies start with a PROXY protocol header giving it; the others are direct, and their
+/// data is left unread.
+async fn read_client_ip<Stream: AsyncRead + Unpin>(
+    stream: &mut Stream,
+    peer_ip: Option<IpAddr>,
+    proxy_protocol: bool,
+    trusted_proxies: &TrustedProxies,
+) -> Result<Option<IpAddr>> {
+    match peer_ip {
+        Some(ip) if proxy_protocol && trusted_proxies.is_trusted(ip) => {
+            let client_ip = tokio::time::timeout(PROXY_HEADER_TIMEOUT, read_proxy_header(stream))
+                .await
+                .map_err(|_| anyhow!("Timed out reading the PROXY protocol header of {}", ip))?
+                .with_context(|| format!("while reading the PROXY protocol header of {}", ip))?;
+            // The proxy's own connections, e.g. its health checks, don't have a client.
+            Ok(client_ip.or(peer_ip))
         }
+        _ => Ok(peer_ip),
     }
-    Ok(requests.into_inner().unsplit(resp.into_inner()))
 }
 
-fn read_private_key(key_file: &str) -> Result<PrivateKey> {
-    use rustls_pemfile::{pkcs8_private_keys, rsa_private_keys};
-    use std::{fs::File, io::BufReader};
-    pkcs8_private_keys(&mut BufReader::new(File::open(key_file)?))
-        .map_err(anyhow::Error::from)
-        .and_then(|keys| {
-            keys.into_iter()
-                .next()
-                .ok_or_else(|| anyhow!("No PKCS8 key"))
-        })
-        .or_else(|_| {
-            rsa_private_keys(&mut BufReader::new(File::open(key_file)?))
-                .map_err(anyhow::Error::from)
-                .and_then(|keys| {
-                    keys.into_iter()
-                        .next()
-                        .ok_or_else(|| anyhow!("No PKCS1 key"))
-                })
-        })
-        .with_context(|| {
-            format!(
-                "Cannot read either PKCS1 or PKCS8 private key from {}",
-                key_file
-            )
-        })
-        .map(rustls::PrivateKey)
+/// The address of the client of a new connection, or `None` if its PROXY protocol header was
+/// missing or malformed: the connection is then simply closed.
+async fn accept_client_ip<Backend>(
+    stream: &mut TcpStream,
+    context: &LdapSessionContext<Backend>,
+) -> Option<Option<IpAddr>> {
+    let peer_ip = stream.peer_addr().ok().map(|addr| addr.ip());
+    match read_client_ip(
+        stream,
+        peer_ip,
+        context.proxy_protocol,
+        &context.trusted_proxies,
+    )
+    .await
+    {
+        Ok(client_ip) => Some(client_ip),
+        Err(e) => {
+            warn!(?peer_ip, "Closing an LDAP connection: {:#}", e);
+            None
+        }
+    }
+}
+
+/// Answers a connection over the limits with a notice of disconnection, then closes it.
+async fn reject_connection(mut stream: TcpStream, reason: ConnectionRejected) -> Result<()> {
+    use tokio::io::AsyncWriteExt;
+    use tokio_util::codec::Encoder;
+    warn!(
+        peer = ?stream.peer_addr().ok(),
+        "Refusing an LDAP connection: {}",
+        reason.as_str()
+    );
+    let mut buffer = BytesMut::new();
+    ldap3_proto::LdapCodec.encode(
+        notice_of_disconnection(LdapResultCode::Busy, "Too many connections"),
+        &mut buffer,
+    )?;
+    // The client may already be gone.
+    let _ = stream.write_all(&buffer).await;
+    Ok(())
 }
 
-fn get_tls_acceptor(config: &Configuration) -> Result<RustlsTlsAcceptor> {
-    use rustls::{Certificate, ServerConfig};
+/// The names in the certificate that the client presented, already verified by rustls.
+fn get_client_certificate<Stream>(tls_stream: &TlsStream<Stream>) -> Option<ClientCertificate> {
+    let certificate = tls_stream.get_ref().1.peer_certificates()?.first()?;
+    let client_certificate = ClientCertificate::parse(&certificate.0);
+    if client_certificate.is_none() {
+        warn!("Could not read the names of the client certificate");
+    }
+    client_certificate
+}
+
+/// The CAs that sign the client certificates.
+fn read_client_ca_file(client_ca_file: &str) -> Result<rustls::RootCertStore> {
     use rustls_pemfile::certs;
     use std::{fs::File, io::BufReader};
-    // Load TLS key and cert files
-    let certs = certs(&mut BufReader::new(File::open(
-        &config.ldaps_options.cert_file,
-    )?))?
-    .into_iter()
-    .map(Certificate)
-    .collect::<Vec<_>>();
-    let private_key = read_private_key(&config.ldaps_options.key_file)?;
-    let server_config = std::sync::Arc::new(
-        ServerConfig::builder()
-            .with_safe_defaults()
-            .with_no_client_auth()
-            .with_single_cert(certs, private_key)?,
-    );
+    let mut roots = rustls::RootCertStore::empty();
+    for certificate in certs(&mut BufReader::new(File::open(client_ca_file)?))? {
+        roots
+            .add(&rustls::Certificate(certificate))
+            .map_err(|e| anyhow!("Invalid CA certificate: {:?}", e))?;
+    }
+    if roots.is_empty() {
+        return Err(anyhow!("No CA certificate"));
+    }
+    Ok(roots)
+}
+
+fn make_tls_acceptor(
+    config: &Configuration,
+    certificate: Arc<ReloadableCertificate>,
+) -> Result<RustlsTlsAcceptor> {
+    use rustls::server::AllowAnyAnonymousOrAuthenticatedClient;
+    let builder = TlsPolicy::new(&config.ldaps_options)?.server_config_builder()?;
+    // The client certificates are optional, and checked against the CAs and their validity dates.
+    let builder = match &config.ldaps_options.client_ca_file {
+        Some(client_ca_file) => {
+            let roots = read_client_ca_file(client_ca_file)
+                .with_context(|| format!("while reading the client CA file {}", client_ca_file))?;
+            builder.with_client_cert_verifier(AllowAnyAnonymousOrAuthenticatedClient::new(roots))
+        }
+        None => builder.with_no_client_auth(),
+    };
+    let server_config = std::sync::Arc::new(builder.with_cert_resolver(certificate));
     Ok(server_config.into())
 }
 
+/// The TLS acceptors by certificate files: the listeners with the same certificate share it, and
+/// its reloads.
+#[derive(Default)]
+struct TlsAcceptors(HashMap<(String, String), RustlsTlsAcceptor>);
+
+impl TlsAcceptors {
+    fn get(
+        &mut self,
+        config: &Configuration,
+        cert_file: &str,
+        key_file: &str,
+    ) -> Result<RustlsTlsAcceptor> {
+        let key = (cert_file.to_owned(), key_file.to_owned());
+        if let Some(tls_acceptor) = self.0.get(&key) {
+            return Ok(tls_acceptor.clone());
+        }
+        let certificate = Arc::new(
+            ReloadableCertificate::new(cert_file, key_file)
+                .with_context(|| format!("while setting up the SSL certificate {}", cert_file))?,
+        );
+        let poll_interval = Some(config.ldaps_options.cert_reload_interval_seconds)
+            .filter(|seconds| *seconds != 0)
+            .map(Duration::from_secs);
+        actix_rt::spawn(watch_certificate(certificate.clone(), poll_interval));
+        let tls_acceptor = make_tls_acceptor(config, certificate)?;
+        self.0.insert(key, tls_acceptor.clone());
+        Ok(tls_acceptor)
+    }
+}
+
 pub fn build_ldap_server<Backend>(
     config: &Configuration,
     backend_handler: Backend,
+    rate_limiter: SharedRateLimiter,
+    shutdown: ShutdownCoordinator,
     server_builder: ServerBuilder,
 ) -> Result<ServerBuilder>
 where
     Backend: BackendHandler + LoginHandler + OpaqueHandler + 'static,
 {
-    let context = (
+    let context = LdapSessionContext {
         backend_handler,
-        config.ldap_base_dn.clone(),
-        config.ignored_user_attributes.clone(),
-        config.ignored_group_attributes.clone(),
-    );
+        ldap_base_dn: config.ldap_base_dn.clone(),
+        ignored_user_attributes: config.ignored_user_attributes.clone(),
+        ignored_group_attributes: config.ignored_group_attributes.clone(),
+        user_rdn_attribute: config.ldap_user_rdn_attribute,
+        posix_options: config.posix_options.clone(),
+        object_classes: config.ldap_object_classes.clone(),
+        sudoers_base_dn: config.get_sudoers_base_dn(),
+        users_ou: config.ldap_users_ou.clone(),
+        groups_ou: config.ldap_groups_ou.clone(),
+        rate_limiter,
+        password_policy: Arc::new(
+            PasswordPolicy::new(&config.password_policy)
+                .context("while setting up the password policy")?,
+        ),
+        operation_timeouts: OperationTimeouts::new(&config.database_pool_options),
+        unindexed_sort: config.ldap_unindexed_sort,
+        attribute_acl: AttributeAcl::new(config.attribute_acl.clone())
+            .with_self_service_attributes(config.self_service_attributes.clone()),
+        anonymous_bind: config.ldap_anonymous_bind,
+        anonymous_read_attributes: config.ldap_anonymous_read_attributes.clone(),
+        shutdown,
+        require_tls: config.ldaps_options.require_tls,
+        start_tls_acceptor: None,
+        sasl_external: config
+            .ldaps_options
+            .client_ca_file
+            .as_ref()
+            .map(|_| config.ldaps_options.client_certificate_user_mapping),
+        sasl_scram: config.ldap_sasl_scram_sha256,
+        connection_limiter: ConnectionLimiter::new(&config.ldap_connection_limits),
+        idle_timeout: Some(config.ldap_connection_limits.idle_timeout_seconds)
+            .filter(|seconds| *seconds != 0)
+            .map(Duration::from_secs),
+        trusted_proxies: TrustedProxies::new(&config.proxy_options)?,
+        proxy_protocol: config.proxy_options.ldap_proxy_protocol,
+    };
 
-    let context_for_tls = context.clone();
+    let mut tls_acceptors = TlsAcceptors::default();
+    let mut server_builder = server_builder;
+    for listener in config.get_listeners() {
+        let tls_files = match &listener.tls {
+            Some(tls) => Some((tls.cert_file.as_str(), tls.key_file.as_str())),
+            None if listener.protocol == ListenerProtocol::Ldaps
+                || config.ldaps_options.start_tls =>
+            {
+                Some((
+                    config.ldaps_options.cert_file.as_str(),
+                    config.ldaps_options.key_file.as_str(),
+                ))
+            }
+            None => None,
+        };
+        let tls_acceptor = match (listener.protocol, tls_files) {
+            (ListenerProtocol::Http | ListenerProtocol::Metrics, _) => continue,
+            (_, Some((cert_file, key_file))) => {
+                Some(tls_acceptors.get(config, cert_file, key_file)?)
+            }
+            (_, None) => None,
+        };
+        server_builder = match (listener.protocol, tls_acceptor) {
+            (ListenerProtocol::Ldaps, Some(tls_acceptor)) => {
+                bind_ldaps(server_builder, &listener, context.clone(), tls_acceptor)?
+            }
+            (_, start_tls_acceptor) => bind_ldap(
+                server_builder,
+                &listener,
+                LdapSessionContext {
+                    start_tls_acceptor,
+                    ..context.clone()
+                },
+            )?,
+        };
+    }
+    Ok(server_builder)
+}
 
+fn bind_ldap<Backend>(
+    server_builder: ServerBuilder,
+    listener: &ListenerOptions,
+    context: LdapSessionContext<Backend>,
+) -> Result<ServerBuilder>
+where
+    Backend: BackendHandler + LoginHandler + OpaqueHandler + 'static,
+{
+    info!(
+        "Starting the LDAP server on {}{}",
+        listener.address(),
+        if context.start_tls_acceptor.is_some() {
+            ", with StartTLS"
+        } else {
+            ""
+        }
+    );
     let binder = move || {
         let context = context.clone();
         fn_service(move |stream: TcpStream| {
             let context = context.clone();
             async move {
-                let (handler, base_dn, ignored_user_attributes, ignored_group_attributes) = context;
-                handle_ldap_stream(
-                    stream,
-                    handler,
-                    base_dn,
-                    ignored_user_attributes,
-                    ignored_group_attributes,
-                )
-                .await
+                let mut stream = stream;
+                let peer_ip = match accept_client_ip(&mut stream, &context).await {
+                    Some(peer_ip) => peer_ip,
+                    None => return Ok(()),
+                };
+                let _slot = match context.connection_limiter.acquire(peer_ip) {
+                    Ok(slot) => slot,
+                    Err(reason) => return reject_connection(stream, reason).await,
+                };
+                handle_ldap_stream(stream, context, false, None, peer_ip).await
             }
         })
         .map_err(|err: anyhow::Error| error!("[LDAP] Service Error: {:#}", err))
     };
+    server_builder
+        .bind("ldap", (listener.host.clone(), listener.port), binder)
+        .with_context(|| format!("while binding LDAP to {}", listener.address()))
+}
 
-    info!("Starting the LDAP server on port {}", config.ldap_port);
-    let server_builder = server_builder
-        .bind("ldap", (config.ldap_host.clone(), config.ldap_port), binder)
-        .with_context(|| format!("while binding to the port {}", config.ldap_port));
-    if config.ldaps_options.enabled {
-        let tls_context = (
-            context_for_tls,
-            get_tls_acceptor(config).context("while setting up the SSL certificate")?,
-        );
-        let tls_binder = move || {
-            let tls_context = tls_context.clone();
-            fn_service(move |stream: TcpStream| {
-                let tls_context = tls_context.clone();
-                async move {
-                    let (
-                        (handler, base_dn, ignored_user_attributes, ignored_group_attributes),
-                        tls_acceptor,
-                    ) = tls_context;
-                    let tls_stream = tls_acceptor.accept(stream).await?;
-                    handle_ldap_stream(
-                        tls_stream,
-                        handler,
-                        base_dn,
-                        ignored_user_attributes,
-                        ignored_group_attributes,
-                    )
-                    .await
-                }
-            })
-            .map_err(|err: anyhow::Error| error!("[LDAPS] Service Error: {:#}", err))
+fn bind_ldaps<Backend>(
+    server_builder: ServerBuilder,
+    listener: &ListenerOptions,
+    context: LdapSessionContext<Backend>,
+    tls_acceptor: RustlsTlsAcceptor,
+) -> Result<ServerBuilder>
+where
+    Backend: BackendHandler + LoginHandler + OpaqueHandler + 'static,
+{
+    info!("Starting the LDAPS server on {}", listener.address());
+    let tls_context = (context, tls_acceptor);
+    let tls_binder = move || {
+        let tls_context = tls_context.clone();
+        fn_service(move |stream: TcpStream| {
+            let (context, tls_acceptor) = tls_context.clone();
+            async move {
+                let mut stream = stream;
+                let peer_ip = match accept_client_ip(&mut stream, &context).await {
+                    Some(peer_ip) => peer_ip,
+                    None => return Ok(()),
+                };
+                // Checked before the handshake, so the connection is simply closed.
+                let _slot = match context.connection_limiter.acquire(peer_ip) {
+                    Ok(slot) => slot,
+                    Err(reason) => {
+                        warn!(
+                            ?peer_ip,
+                            "Refusing an LDAPS connection: {}",
+                            reason.as_str()
+                        );
+                        return Ok(());
+                    }
+                };
+                let tls_stream = tls_acceptor.accept(stream).await?;
+                let client_certificate = get_client_certificate(&tls_stream);
+                handle_ldap_stream(tls_stream, context, true, client_certificate, peer_ip).await
+            }
+        })
+        .map_err(|err: anyhow::Error| error!("[LDAPS] Service Error: {:#}", err))
+    };
+    server_builder
+        .bind("ldaps", (listener.host.clone(), listener.port), tls_binder)
+        .with_context(|| format!("while binding LDAPS to {}", listener.address()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::infra::ldap_handler::{tests::MockTestBackendHandler, START_TLS_OID};
+    use ldap3_proto::proto::{
+        LdapBindCred, LdapBindRequest, LdapCompareRequest, LdapExtendedRequest,
+    };
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio_util::codec::Encoder;
+
+    #[tokio::test]
+    async fn test_start_tls_keeps_the_pipelined_data() {
+        let (mut client, server) = tokio::io::duplex(4096);
+        let mut session = LdapHandler::new(
+            MockTestBackendHandler::new(),
+            "dc=example,dc=com".to_string(),
+            vec![],
+            vec![],
+        )
+        .with_connection_security(ConnectionSecurity::StartTlsAvailable, false);
+        // The StartTLS request and the start of the handshake, in the same packet.
+        let mut data = BytesMut::new();
+        ldap3_proto::LdapCodec
+            .encode(
+                LdapMsg {
+                    msgid: 1,
+                    op: LdapOp::ExtendedRequest(LdapExtendedRequest {
+                        name: START_TLS_OID.to_string(),
+                        value: None,
+                    }),
+                    ctrl: vec![],
+                },
+                &mut data,
+            )
+            .unwrap();
+        data.extend_from_slice(b"client hello");
+        client.write_all(&data).await.unwrap();
+        let shutdown = ShutdownCoordinator::new();
+        let mut connection = shutdown.connection();
+        let mut stream = match serve_ldap_stream(server, &mut session, &mut connection, None)
+            .await
+            .unwrap()
+        {
+            StreamEnd::StartTls(stream) => stream,
+            StreamEnd::Closed => panic!("The session didn't ask for the upgrade"),
         };
+        connection.finish();
+        client.write_all(b", continued").await.unwrap();
+        let mut received = vec![0; "client hello, continued".len()];
+        stream.read_exact(&mut received).await.unwrap();
+        assert_eq!(received, b"client hello, continued");
+        // The success response was sent before the upgrade.
+        let mut response = vec![0; 256];
+        let len = client.read(&mut response).await.unwrap();
+        assert!(len > 0);
+    }
 
-        info!(
-            "Starting the LDAPS server on port {}",
-            config.ldaps_options.port
+    #[tokio::test]
+    async fn test_idle_timeout() {
+        use tokio_stream::StreamExt;
+        let (client, server) = tokio::io::duplex(4096);
+        let mut session = LdapHandler::new(
+            MockTestBackendHandler::new(),
+            "dc=example,dc=com".to_string(),
+            vec![],
+            vec![],
         );
-        server_builder.and_then(|s| {
-            s.bind(
-                "ldaps",
-                (config.ldap_host.clone(), config.ldaps_options.port),
-                tls_binder,
+        let shutdown = ShutdownCoordinator::new();
+        let mut connection = shutdown.connection();
+        assert!(matches!(
+            serve_ldap_stream(
+                server,
+                &mut session,
+                &mut connection,
+                Some(Duration::from_millis(10))
             )
-            .with_context(|| format!("while binding to the port {}", config.ldaps_options.port))
-        })
-    } else {
-        server_builder
+            .await
+            .unwrap(),
+            StreamEnd::Closed
+        ));
+        connection.finish();
+        let notice = FramedRead::new(client, ldap3_proto::LdapCodec)
+            .next()
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(
+            notice,
+            notice_of_disconnection(LdapResultCode::Unavailable, "Idle timeout")
+        );
+    }
+
+    #[test]
+    fn test_redacted() {
+        let bind = LdapOp::BindRequest(LdapBindRequest {
+            dn: "uid=bob,ou=people,dc=example,dc=com".to_string(),
+            cred: LdapBindCred::Simple("bob_password".to_string()),
+        });
+        assert_eq!(
+            format!("{:?}", Redacted(&bind)),
+            r#"BindRequest { dn: "uid=bob,ou=people,dc=example,dc=com", .. }"#
+        );
+        let compare = LdapOp::CompareRequest(LdapCompareRequest {
+            dn: "uid=bob,ou=people,dc=example,dc=com".to_string(),
+            atype: "userPassword".to_string(),
+            val: b"bob_password".to_vec(),
+        });
+        assert_eq!(
+            format!("{:?}", Redacted(&compare)),
+            r#"CompareRequest { dn: "uid=bob,ou=people,dc=example,dc=com", atype: "userPassword", .. }"#
+        );
+        let response = LdapOp::ExtendedResponse(LdapExtendedResponse {
+            res: LdapResultOp {
+                code: LdapResultCode::Success,
+                matcheddn: "".to_string(),
+                message: "".to_string(),
+                referral: vec![],
+            },
+            name: None,
+            value: Some(b"generated_password".to_vec()),
+        });
+        assert!(!format!("{:?}", Redacted(&response)).contains("value"));
+        // The other operations are logged as they are.
+        let compare = LdapOp::CompareRequest(LdapCompareRequest {
+            dn: "uid=bob,ou=people,dc=example,dc=com".to_string(),
+            atype: "givenName".to_string(),
+            val: b"Bob".to_vec(),
+        });
+        assert_eq!(
+            format!("{:?}", Redacted(&compare)),
+            format!("{:?}", compare)
+        );
+    }
+
+    #[tokio::test]
+    async fn test_read_client_ip() {
+        let trusted_proxies = TrustedProxies::new(
+            &crate::infra::configuration::ProxyOptionsBuilder::default()
+                .trusted_proxies(vec!["10.0.0.0/8".to_string()])
+                .build()
+                .unwrap(),
+        )
+        .unwrap();
+        let proxy: IpAddr = "10.0.0.1".parse().unwrap();
+        let client: IpAddr = "203.0.113.7".parse().unwrap();
+        let header = b"PROXY TCP4 198.51.100.1 10.0.0.1 51234 389\r\n0\x0c";
+        // The PROXY header of a trusted proxy gives the client.
+        let mut stream = &header[..];
+        assert_eq!(
+            read_client_ip(&mut stream, Some(proxy), true, &trusted_proxies)
+                .await
+                .unwrap(),
+            Some("198.51.100.1".parse().unwrap())
+        );
+        assert_eq!(stream, b"0\x0c");
+        // The one of an untrusted peer isn't parsed, and is left for the LDAP session to reject.
+        let mut stream = &header[..];
+        assert_eq!(
+            read_client_ip(&mut stream, Some(client), true, &trusted_proxies)
+                .await
+                .unwrap(),
+            Some(client)
+        );
+        assert_eq!(stream, &header[..]);
+        // Nor without `ldap_proxy_protocol`.
+        let mut stream = &header[..];
+        assert_eq!(
+            read_client_ip(&mut stream, Some(proxy), false, &trusted_proxies)
+                .await
+                .unwrap(),
+            Some(proxy)
+        );
+        assert_eq!(stream, &header[..]);
+        // A malformed header is an error, for the connection to be closed.
+        let mut stream = &b"PROXY TCP4 nonsense 10.0.0.1 1 2\r\n"[..];
+        assert!(
+            read_client_ip(&mut stream, Some(proxy), true, &trusted_proxies)
+                .await
+                .is_err()
+        );
     }
 }
@@ -1,7 +1,10 @@
-use crate::infra::configuration::Configuration;
+use crate::infra::{
+    client_ip::TrustedProxies,
+    configuration::{Configuration, LogFormat},
+};
 use actix_web::{
     dev::{ServiceRequest, ServiceResponse},
-    Error,
+    web, Error,
 };
 use tracing::{error, info, Span};
 use tracing_actix_web::{root_span, RootSpanBuilder};
@@ -13,7 +16,23 @@ pub struct CustomRootSpanBuilder;
 
 impl RootSpanBuilder for CustomRootSpanBuilder {
     fn on_request_start(request: &ServiceRequest) -> Span {
-        let span = root_span!(request);
+        // The user is recorded once the token is checked, the correlation ID by the handlers
+        // that use one. Unlike `http.client_ip`, `client_ip` only trusts the forwarding headers
+        // set by the trusted proxies.
+        let span = root_span!(
+            request,
+            user = tracing::field::Empty,
+            correlation_id = tracing::field::Empty,
+            client_ip = tracing::field::Empty
+        );
+        if let Some(ip) = request
+            .app_data::<web::Data<TrustedProxies>>()
+            .and_then(|proxies| {
+                proxies.http_client_ip(request.peer_addr().map(|addr| addr.ip()), request.headers())
+            })
+        {
+            span.record("client_ip", &tracing::field::display(ip));
+        }
         span.in_scope(|| {
             info!(uri = %request.uri());
         });
@@ -42,10 +61,19 @@ pub fn init(config: &Configuration) -> anyhow::Result<()> {
             "sqlx=warn,reqwest=warn,info"
         })
     });
-    tracing_subscriber::registry()
-        .with(env_filter)
-        .with(tracing_forest::ForestLayer::default())
-        .init();
+    let registry = tracing_subscriber::registry().with(env_filter);
+    match config.log_format {
+        LogFormat::Text => registry.with(tracing_forest::ForestLayer::default()).init(),
+        // The spans carry the request ID (HTTP) or session ID (LDAP), the user and the IP.
+        LogFormat::Json => registry
+            .with(
+                tracing_subscriber::fmt::layer()
+                    .json()
+                    .with_current_span(true)
+                    .with_span_list(true),
+            )
+            .init(),
+    }
     Ok(())
 }
 
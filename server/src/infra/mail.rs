@@ -1,68 +1,435 @@
-use crate::infra::{cli::SmtpEncryption, configuration::MailOptions};
-use anyhow::{Ok, Result};
+use crate::infra::{
+    cli::SmtpEncryption,
+    configuration::MailOptions,
+    i18n::{candidate_locales, normalize_locale, substitute},
+};
+use anyhow::{Context, Result};
+use async_trait::async_trait;
 use lettre::{
     message::Mailbox, transport::smtp::authentication::Credentials, AsyncSmtpTransport,
     AsyncTransport, Message, Tokio1Executor,
 };
-use tracing::debug;
-
-async fn send_email(to: Mailbox, subject: &str, body: String, options: &MailOptions) -> Result<()> {
-    let from = options
-        .from
-        .clone()
-        .unwrap_or_else(|| "LLDAP <nobody@lldap>".parse().unwrap());
-    let reply_to = options.reply_to.clone().unwrap_or_else(|| from.clone());
-    debug!(
-        "Sending email to '{}' as '{}' via '{}'@'{}':'{}'",
-        &to, &from, &options.user, &options.server, options.port
-    );
-    let email = Message::builder()
-        .from(from)
-        .reply_to(reply_to)
-        .to(to)
-        .subject(subject)
-        .body(body)?;
-    let creds = Credentials::new(
-        options.user.clone(),
-        options.password.unsecure().to_string(),
-    );
-    let relay_factory = match options.smtp_encryption {
-        SmtpEncryption::TLS => AsyncSmtpTransport::<Tokio1Executor>::relay,
-        SmtpEncryption::STARTTLS => AsyncSmtpTransport::<Tokio1Executor>::starttls_relay,
-    };
-    let mailer = relay_factory(&options.server)?.credentials(creds).build();
-    mailer.send(email).await?;
-    Ok(())
-}
-
-pub async fn send_password_reset_email(
-    username: &str,
-    to: &str,
-    token: &str,
-    domain: &str,
-    options: &MailOptions,
-) -> Result<()> {
-    let to = to.parse()?;
-    let body = format!(
-        "Hello {},
+use std::{collections::HashMap, path::Path, sync::Arc};
+use tracing::{debug, info};
+
+/// An email, before the sender is added.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Email {
+    pub to: Mailbox,
+    pub subject: String,
+    pub body: String,
+}
+
+#[async_trait]
+pub trait Mailer: Send + Sync {
+    async fn send(&self, email: Email) -> Result<()>;
+}
+
+/// Sends the emails through the SMTP server of the configuration.
+pub struct SmtpMailer {
+    options: MailOptions,
+}
+
+impl SmtpMailer {
+    pub fn new(options: MailOptions) -> Self {
+        Self { options }
+    }
+}
+
+#[async_trait]
+impl Mailer for SmtpMailer {
+    async fn send(&self, email: Email) -> Result<()> {
+        let options = &self.options;
+        let from = options
+            .from
+            .clone()
+            .unwrap_or_else(|| "LLDAP <nobody@lldap>".parse().unwrap());
+        let reply_to = options.reply_to.clone().unwrap_or_else(|| from.clone());
+        debug!(
+            "Sending email to '{}' as '{}' via '{}'@'{}':'{}'",
+            &email.to, &from, &options.user, &options.server, options.port
+        );
+        let message = Message::builder()
+            .from(from)
+            .reply_to(reply_to)
+            .to(email.to)
+            .subject(email.subject)
+            .body(email.body)?;
+        let builder = match options.smtp_encryption {
+            SmtpEncryption::TLS => AsyncSmtpTransport::<Tokio1Executor>::relay(&options.server)?,
+            SmtpEncryption::STARTTLS => {
+                AsyncSmtpTransport::<Tokio1Executor>::starttls_relay(&options.server)?
+            }
+            SmtpEncryption::NONE => {
+                AsyncSmtpTransport::<Tokio1Executor>::builder_dangerous(&options.server)
+            }
+        }
+        .port(options.port);
+        // Local relays often don't need a login.
+        let builder = if options.user.is_empty() {
+            builder
+        } else {
+            builder.credentials(Credentials::new(
+                options.user.clone(),
+                options.password.unsecure().to_string(),
+            ))
+        };
+        builder.build().send(message).await?;
+        Ok(())
+    }
+}
+
+/// Logs the emails instead of sending them, for testing.
+pub struct LogMailer;
+
+#[async_trait]
+impl Mailer for LogMailer {
+    async fn send(&self, email: Email) -> Result<()> {
+        info!(
+            "Not sending email to '{}': {}\n{}",
+            email.to, email.subject, email.body
+        );
+        Ok(())
+    }
+}
+
+/// The subject and body of an email, with `{{variable}}` placeholders.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct EmailTemplate {
+    pub subject: String,
+    pub body: String,
+}
+
+impl EmailTemplate {
+    /// The first line of the file is the subject, the rest is the body.
+    fn parse(content: &str) -> Result<Self> {
+        let (subject, body) = content
+            .split_once('\n')
+            .context("missing the body after the subject line")?;
+        Ok(Self {
+            subject: subject.trim().to_owned(),
+            body: body.trim_start_matches(&['\r', '\n'][..]).to_owned(),
+        })
+    }
+
+    fn render(&self, to: Mailbox, variables: &[(&str, &str)]) -> Email {
+        Email {
+            to,
+            subject: substitute(&self.subject, variables),
+            body: substitute(&self.body, variables),
+        }
+    }
+}
+
+/// A template and its translations.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LocalizedEmailTemplate {
+    pub default: EmailTemplate,
+    /// By lowercase locale, e.g. `fr` or `pt-br`.
+    pub translations: HashMap<String, EmailTemplate>,
+}
+
+impl LocalizedEmailTemplate {
+    /// The translation for the first of the locales that has one, or the default template.
+    pub fn get(&self, locales: &[String]) -> &EmailTemplate {
+        candidate_locales(locales)
+            .find_map(|locale| self.translations.get(locale))
+            .unwrap_or(&self.default)
+    }
+}
+
+const PASSWORD_RESET_TEMPLATE: &str = "[LLDAP] Password reset requested
+Hello {{display_name}},
 This email has been sent to you in order to validate your identity.
 If you did not initiate the process your credentials might have been
 compromised. You should reset your password and contact an administrator.
 
-To reset your password please visit the following URL: {}/reset-password/step2/{}
+To reset your password please visit the following URL: {{link}}
+
+Please contact an administrator if you did not initiate the process.";
+
+const EMAIL_VERIFICATION_TEMPLATE: &str = "[LLDAP] Email verification
+Hello {{display_name}},
+This email has been sent to you in order to verify your email address.
+
+To confirm that this address is yours, please visit the following URL: {{link}}
+
+If you did not request it, you can ignore this email.";
+
+fn load_template_file(path: &Path) -> Result<EmailTemplate> {
+    std::fs::read_to_string(path)
+        .map_err(anyhow::Error::from)
+        .and_then(|content| EmailTemplate::parse(&content))
+        .with_context(|| format!("while loading the email template {:?}", path))
+}
+
+/// The templates of the emails, the built-in ones unless overridden by a file in
+/// `templates_dir`, e.g. `password_reset.txt`. The translations are in the files with the
+/// locale before the extension, e.g. `password_reset.fr.txt`. The variables are
+/// `display_name`, `user_id` and `link`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct EmailTemplates {
+    pub password_reset: LocalizedEmailTemplate,
+    pub email_verification: LocalizedEmailTemplate,
+}
+
+impl EmailTemplates {
+    pub fn load(templates_dir: Option<&Path>) -> Result<Self> {
+        let files = match templates_dir.filter(|dir| dir.exists()) {
+            Some(dir) => std::fs::read_dir(dir)
+                .and_then(|entries| {
+                    entries
+                        .map(|entry| entry.map(|entry| entry.path()))
+                        .collect::<std::io::Result<Vec<_>>>()
+                })
+                .with_context(|| format!("Could not read the templates directory {:?}", dir))?,
+            None => Vec::new(),
+        };
+        let load = |name: &str, default: &str| -> Result<LocalizedEmailTemplate> {
+            let mut template = LocalizedEmailTemplate {
+                default: EmailTemplate::parse(default)?,
+                translations: HashMap::new(),
+            };
+            for path in &files {
+                let stem = match path
+                    .file_name()
+                    .and_then(|file_name| file_name.to_str())
+                    .and_then(|file_name| file_name.strip_suffix(".txt"))
+                {
+                    Some(stem) => stem,
+                    None => continue,
+                };
+                if stem == name {
+                    template.default = load_template_file(path)?;
+                } else if let Some(locale) = stem
+                    .strip_prefix(name)
+                    .and_then(|rest| rest.strip_prefix('.'))
+                {
+                    template
+                        .translations
+                        .insert(normalize_locale(locale), load_template_file(path)?);
+                }
+            }
+            Ok(template)
+        };
+        Ok(Self {
+            password_reset: load("password_reset", PASSWORD_RESET_TEMPLATE)?,
+            email_verification: load("email_verification", EMAIL_VERIFICATION_TEMPLATE)?,
+        })
+    }
+}
 
-Please contact an administrator if you did not initiate the process.",
-        username, domain, token
-    );
-    send_email(to, "[LLDAP] Password reset requested", body, options).await
+/// Renders the emails of the different flows, and sends them through the mailer.
+#[derive(Clone)]
+pub struct MailSender {
+    mailer: Arc<dyn Mailer>,
+    templates: Arc<EmailTemplates>,
 }
 
-pub async fn send_test_email(to: Mailbox, options: &MailOptions) -> Result<()> {
-    send_email(
-        to,
-        "LLDAP test email",
-        "The test is successful! You can send emails from LLDAP".to_string(),
-        options,
-    )
-    .await
+impl MailSender {
+    pub fn new(options: &MailOptions) -> Result<Self> {
+        let mailer: Arc<dyn Mailer> = if options.log_only {
+            Arc::new(LogMailer)
+        } else {
+            Arc::new(SmtpMailer::new(options.clone()))
+        };
+        Ok(Self::with_mailer(
+            mailer,
+            EmailTemplates::load(options.templates_dir.as_deref().map(Path::new))?,
+        ))
+    }
+
+    pub fn with_mailer(mailer: Arc<dyn Mailer>, templates: EmailTemplates) -> Self {
+        Self {
+            mailer,
+            templates: Arc::new(templates),
+        }
+    }
+
+    pub async fn send_password_reset_email(
+        &self,
+        user_id: &str,
+        display_name: &str,
+        to: &str,
+        token: &str,
+        domain: &str,
+        locales: &[String],
+    ) -> Result<()> {
+        let link = format!("{}/reset-password/step2/{}", domain, token);
+        self.mailer
+            .send(self.templates.password_reset.get(locales).render(
+                to.parse()?,
+                &[
+                    ("display_name", display_name),
+                    ("user_id", user_id),
+                    ("link", &link),
+                ],
+            ))
+            .await
+    }
+
+    pub async fn send_email_verification_email(
+        &self,
+        user_id: &str,
+        display_name: &str,
+        to: &str,
+        token: &str,
+        domain: &str,
+        locales: &[String],
+    ) -> Result<()> {
+        let link = format!("{}/auth/email_verification/confirm/{}", domain, token);
+        self.mailer
+            .send(self.templates.email_verification.get(locales).render(
+                to.parse()?,
+                &[
+                    ("display_name", display_name),
+                    ("user_id", user_id),
+                    ("link", &link),
+                ],
+            ))
+            .await
+    }
+
+    pub async fn send_test_email(&self, to: Mailbox) -> Result<()> {
+        self.mailer
+            .send(Email {
+                to,
+                subject: "LLDAP test email".to_owned(),
+                body: "The test is successful! You can send emails from LLDAP".to_owned(),
+            })
+            .await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::infra::configuration::MailOptionsBuilder;
+    use tokio::{
+        io::{AsyncBufReadExt, AsyncWriteExt, BufReader},
+        net::TcpListener,
+    };
+
+    /// Accepts one connection, and returns the data of the emails sent through it.
+    async fn run_mock_smtp_server(listener: TcpListener) -> String {
+        let (stream, _) = listener.accept().await.unwrap();
+        let (reader, mut writer) = stream.into_split();
+        let mut lines = BufReader::new(reader).lines();
+        writer.write_all(b"220 localhost ESMTP\r\n").await.unwrap();
+        let mut data = String::new();
+        let mut in_data = false;
+        while let Some(line) = lines.next_line().await.unwrap() {
+            if in_data {
+                if line == "." {
+                    in_data = false;
+                    writer.write_all(b"250 OK\r\n").await.unwrap();
+                } else {
+                    data.push_str(&line);
+                    data.push('\n');
+                }
+                continue;
+            }
+            let command = line
+                .split_whitespace()
+                .next()
+                .unwrap_or_default()
+                .to_ascii_uppercase();
+            let reply: &[u8] = match command.as_str() {
+                "EHLO" | "HELO" => b"250 localhost\r\n",
+                "MAIL" | "RCPT" | "RSET" | "NOOP" => b"250 OK\r\n",
+                "DATA" => {
+                    in_data = true;
+                    b"354 End data with <CR><LF>.<CR><LF>\r\n"
+                }
+                "QUIT" => {
+                    writer.write_all(b"221 Bye\r\n").await.unwrap();
+                    break;
+                }
+                _ => b"500 Unknown command\r\n",
+            };
+            writer.write_all(reply).await.unwrap();
+        }
+        data
+    }
+
+    #[tokio::test]
+    async fn test_send_through_smtp() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let server = tokio::spawn(run_mock_smtp_server(listener));
+        let options = MailOptionsBuilder::default()
+            .server("127.0.0.1".to_owned())
+            .port(port)
+            .user(String::new())
+            .smtp_encryption(SmtpEncryption::NONE)
+            .from(Some("LLDAP <lldap@example.com>".parse().unwrap()))
+            .build()
+            .unwrap();
+        let sender = MailSender::new(&options).unwrap();
+        sender
+            .send_password_reset_email("bob", "Bob", "bob@example.com", "abc", "http://lldap", &[])
+            .await
+            .unwrap();
+        drop(sender);
+        let data = server.await.unwrap();
+        assert!(data.contains("To: bob@example.com"), "{}", data);
+        assert!(data.contains("From: LLDAP <lldap@example.com>"), "{}", data);
+        assert!(
+            data.contains("Subject: [LLDAP] Password reset requested"),
+            "{}",
+            data
+        );
+        assert!(data.contains("Hello Bob,"), "{}", data);
+        assert!(data.contains("/reset-password/step2/abc"), "{}", data);
+    }
+
+    #[test]
+    fn test_custom_templates() {
+        let dir = std::env::temp_dir().join(format!("lldap-templates-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(
+            dir.join("email_verification.txt"),
+            "Welcome {{display_name}}\n\nVerify {{user_id}} at {{link}}.\n",
+        )
+        .unwrap();
+        std::fs::write(
+            dir.join("password_reset.fr.txt"),
+            "Réinitialisation du mot de passe\nBonjour {{display_name}}",
+        )
+        .unwrap();
+        let templates = EmailTemplates::load(Some(&dir)).unwrap();
+        std::fs::remove_dir_all(&dir).unwrap();
+        // The other templates keep the default.
+        assert_eq!(
+            templates.password_reset.get(&[]).subject,
+            "[LLDAP] Password reset requested"
+        );
+        assert_eq!(
+            templates
+                .password_reset
+                .get(&["fr-ca".to_owned(), "en".to_owned()])
+                .subject,
+            "Réinitialisation du mot de passe"
+        );
+        // Without a translation, the default template.
+        assert_eq!(
+            templates.email_verification.get(&["fr".to_owned()]).subject,
+            "Welcome {{display_name}}"
+        );
+        assert_eq!(
+            templates.email_verification.get(&[]).render(
+                "bob@example.com".parse().unwrap(),
+                &[
+                    ("display_name", "Bob"),
+                    ("user_id", "bob"),
+                    ("link", "http://lldap/x")
+                ],
+            ),
+            Email {
+                to: "bob@example.com".parse().unwrap(),
+                subject: "Welcome Bob".to_owned(),
+                body: "Verify bob at http://lldap/x.\n".to_owned(),
+            }
+        );
+    }
 }
@@ -0,0 +1,253 @@
+use crate::infra::connection_limiter::ConnectionRejected;
+use actix_web::HttpResponse;
+use once_cell::sync::Lazy;
+use prometheus::{
+    register_histogram_vec_with_registry, register_int_counter_vec_with_registry,
+    register_int_counter_with_registry, register_int_gauge_with_registry, Encoder, HistogramVec,
+    IntCounter, IntCounterVec, IntGauge, Registry, TextEncoder,
+};
+use std::time::Instant;
+
+/// Authentication takes a few milliseconds (OPAQUE), the rest should be well under a second.
+const LATENCY_BUCKETS: &[f64] = &[
+    0.001, 0.0025, 0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5,
+];
+
+static REGISTRY: Lazy<Registry> =
+    Lazy::new(|| Registry::new_custom(Some("lldap".into()), None).unwrap());
+
+static LDAP_BINDS: Lazy<IntCounterVec> = Lazy::new(|| {
+    register_int_counter_vec_with_registry!(
+        "ldap_binds_total",
+        "LDAP bind attempts, by result. Failures are labeled with the reason.",
+        &["result"],
+        REGISTRY
+    )
+    .unwrap()
+});
+
+static LDAP_BIND_DURATION: Lazy<HistogramVec> = Lazy::new(|| {
+    register_histogram_vec_with_registry!(
+        "ldap_bind_duration_seconds",
+        "Duration of the LDAP binds.",
+        &["result"],
+        LATENCY_BUCKETS.to_vec(),
+        REGISTRY
+    )
+    .unwrap()
+});
+
+static LDAP_SEARCHES: Lazy<IntCounterVec> = Lazy::new(|| {
+    register_int_counter_vec_with_registry!(
+        "ldap_searches_total",
+        "LDAP searches, by target: users, groups, sudoers, root (the base DN) or other.",
+        &["target"],
+        REGISTRY
+    )
+    .unwrap()
+});
+
+static LDAP_CONNECTIONS: Lazy<IntGauge> = Lazy::new(|| {
+    register_int_gauge_with_registry!("ldap_connections", "Open LDAP connections.", REGISTRY)
+        .unwrap()
+});
+
+static LDAP_CONNECTIONS_REJECTED: Lazy<IntCounterVec> = Lazy::new(|| {
+    register_int_counter_vec_with_registry!(
+        "ldap_connections_rejected_total",
+        "LDAP connections refused at accept time, by the limit they exceeded.",
+        &["limit"],
+        REGISTRY
+    )
+    .unwrap()
+});
+
+static GRAPHQL_OPERATIONS: Lazy<HistogramVec> = Lazy::new(|| {
+    register_histogram_vec_with_registry!(
+        "graphql_operation_duration_seconds",
+        "Duration of the GraphQL operations, by type (query/mutation/subscription).",
+        &["type"],
+        LATENCY_BUCKETS.to_vec(),
+        REGISTRY
+    )
+    .unwrap()
+});
+
+static DB_QUERIES: Lazy<HistogramVec> = Lazy::new(|| {
+    register_histogram_vec_with_registry!(
+        "db_query_duration_seconds",
+        "Duration of the database queries, by statement type.",
+        &["statement", "result"],
+        LATENCY_BUCKETS.to_vec(),
+        REGISTRY
+    )
+    .unwrap()
+});
+
+static DB_POOL_MAX_CONNECTIONS: Lazy<IntGauge> = Lazy::new(|| {
+    register_int_gauge_with_registry!(
+        "db_pool_max_connections",
+        "The size of the database connection pool. The sum of db_query_duration_seconds over \
+         time is the average number of busy connections, to compare with it.",
+        REGISTRY
+    )
+    .unwrap()
+});
+
+static DB_POOL_TIMEOUTS: Lazy<IntCounter> = Lazy::new(|| {
+    register_int_counter_with_registry!(
+        "db_pool_acquire_timeouts_total",
+        "Requests that failed because no database connection was free in time.",
+        REGISTRY
+    )
+    .unwrap()
+});
+
+/// Why a bind failed, to tell brute-force attempts (invalid_credentials) from misconfigured
+/// clients (invalid_dn).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BindResult {
+    Success,
+    InvalidDn,
+    InvalidCredentials,
+    PasswordChangeRequired,
+    AccountLocked,
+    Throttled,
+    Error,
+}
+
+impl BindResult {
+    fn as_str(&self) -> &'static str {
+        match self {
+            BindResult::Success => "success",
+            BindResult::InvalidDn => "invalid_dn",
+            BindResult::InvalidCredentials => "invalid_credentials",
+            BindResult::PasswordChangeRequired => "password_change_required",
+            BindResult::AccountLocked => "account_locked",
+            BindResult::Throttled => "throttled",
+            BindResult::Error => "error",
+        }
+    }
+}
+
+pub fn record_ldap_bind(result: BindResult, start: Instant) {
+    LDAP_BINDS.with_label_values(&[result.as_str()]).inc();
+    LDAP_BIND_DURATION
+        .with_label_values(&[result.as_str()])
+        .observe(start.elapsed().as_secs_f64());
+}
+
+/// The target is one of a fixed set, not the base DN sent by the client: the labels would
+/// otherwise be unbounded.
+pub fn record_ldap_search(target: &'static str) {
+    LDAP_SEARCHES.with_label_values(&[target]).inc();
+}
+
+pub fn record_ldap_connections(count: usize) {
+    LDAP_CONNECTIONS.set(count as i64);
+}
+
+pub fn record_ldap_connection_rejected(reason: ConnectionRejected) {
+    LDAP_CONNECTIONS_REJECTED
+        .with_label_values(&[reason.as_str()])
+        .inc();
+}
+
+/// The type of a GraphQL operation, from the start of its document. Defaults to "query", like
+/// the shorthand syntax.
+pub fn graphql_operation_type(query: &str) -> &'static str {
+    let query = query.trim_start();
+    if query.starts_with("mutation") {
+        "mutation"
+    } else if query.starts_with("subscription") {
+        "subscription"
+    } else {
+        "query"
+    }
+}
+
+/// The operation names are chosen by the clients, so they are not labels.
+pub fn record_graphql_operation(operation_type: &'static str, start: Instant) {
+    GRAPHQL_OPERATIONS
+        .with_label_values(&[operation_type])
+        .observe(start.elapsed().as_secs_f64());
+}
+
+/// To be registered as the metric callback of the database connection.
+pub fn record_db_query(info: &sea_orm::metric::Info<'_>) {
+    let statement = info
+        .statement
+        .sql
+        .split_whitespace()
+        .next()
+        .unwrap_or("")
+        .to_ascii_lowercase();
+    DB_QUERIES
+        .with_label_values(&[&statement, if info.failed { "failure" } else { "success" }])
+        .observe(info.elapsed.as_secs_f64());
+}
+
+pub fn record_db_pool_size(max_connections: u32) {
+    DB_POOL_MAX_CONNECTIONS.set(max_connections.into());
+}
+
+pub fn record_db_pool_timeout() {
+    DB_POOL_TIMEOUTS.inc();
+}
+
+/// Renders all the metrics in the Prometheus text format.
+pub fn render() -> Result<String, prometheus::Error> {
+    let mut buffer = Vec::new();
+    TextEncoder::new().encode(&REGISTRY.gather(), &mut buffer)?;
+    Ok(String::from_utf8(buffer).expect("Prometheus metrics should be valid UTF-8"))
+}
+
+pub async fn metrics_handler() -> HttpResponse {
+    match render() {
+        Ok(metrics) => HttpResponse::Ok()
+            .content_type(TextEncoder::new().format_type())
+            .body(metrics),
+        Err(e) => HttpResponse::InternalServerError().body(e.to_string()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_graphql_operation_type() {
+        assert_eq!(
+            graphql_operation_type("{ user(userId: \"bob\") { id } }"),
+            "query"
+        );
+        assert_eq!(
+            graphql_operation_type("query GetUser { user { id } }"),
+            "query"
+        );
+        assert_eq!(
+            graphql_operation_type("\n  mutation CreateUser($user: CreateUserInput!) { }"),
+            "mutation"
+        );
+    }
+
+    #[test]
+    fn test_render() {
+        record_ldap_bind(BindResult::InvalidCredentials, Instant::now());
+        record_ldap_search("users");
+        record_db_pool_size(5);
+        record_ldap_connections(2);
+        record_ldap_connection_rejected(ConnectionRejected::TooManyFromIp);
+        record_graphql_operation("query", Instant::now());
+        let metrics = render().unwrap();
+        assert!(metrics.contains("lldap_ldap_connections 2"));
+        assert!(metrics.contains(r#"lldap_ldap_connections_rejected_total{limit="ip_limit"}"#));
+        assert!(metrics.contains("lldap_db_pool_max_connections 5"));
+        assert!(metrics.contains(r#"lldap_ldap_binds_total{result="invalid_credentials"}"#));
+        assert!(metrics.contains(r#"lldap_ldap_searches_total{target="users"}"#));
+        assert!(metrics.contains(r#"lldap_graphql_operation_duration_seconds_count{type="query"}"#));
+        assert!(metrics.contains(
+            r#"lldap_ldap_bind_duration_seconds_bucket{result="invalid_credentials",le="0.001"}"#
+        ));
+    }
+}
@@ -0,0 +1,124 @@
+use crate::{
+    domain::{
+        sql_migrations::{
+            create_migration_history_table, migrate_from_version, upgrade_to_v1, MigrationLock,
+        },
+        sql_tables::{check_schema_version, DbConnection, SchemaVersion, LAST_SCHEMA_VERSION},
+    },
+    infra::configuration::UuidBackfill,
+};
+use anyhow::{bail, Context, Result};
+use std::time::Duration;
+
+/// Upgrades the DB schema to the version of this binary, printing each step. With `dry_run`, the
+/// steps are only printed.
+pub async fn migrate(
+    pool: &DbConnection,
+    uuid_backfill: UuidBackfill,
+    dry_run: bool,
+) -> Result<()> {
+    let lock = match MigrationLock::acquire(pool, Duration::ZERO)
+        .await
+        .context("while taking the migration lock")?
+    {
+        Some(lock) => lock,
+        None => bail!("Another migration is already running"),
+    };
+    let result = migrate_locked(pool, uuid_backfill, dry_run).await;
+    lock.release(pool)
+        .await
+        .context("while releasing the migration lock")?;
+    result
+}
+
+async fn migrate_locked(
+    pool: &DbConnection,
+    uuid_backfill: UuidBackfill,
+    dry_run: bool,
+) -> Result<()> {
+    let version = check_schema_version(pool).await?;
+    match version {
+        Some(version) => println!("The DB schema is at version {}", version.0),
+        None => println!("The DB schema doesn't exist yet"),
+    }
+    let steps = (version.map(|v| v.0 + 1).unwrap_or(1)..=LAST_SCHEMA_VERSION.0)
+        .map(SchemaVersion)
+        .collect::<Vec<_>>();
+    if dry_run {
+        for step in &steps {
+            println!("Would upgrade the DB schema to version {}", step.0);
+        }
+        return Ok(());
+    }
+    create_migration_history_table(pool).await?;
+    let mut current = version;
+    for step in steps {
+        println!("Upgrading the DB schema to version {}", step.0);
+        match current {
+            None => upgrade_to_v1(pool, uuid_backfill)
+                .await
+                .map_err(anyhow::Error::from),
+            Some(current) => migrate_from_version(pool, current, step).await,
+        }
+        .with_context(|| format!("while upgrading the DB schema to version {}", step.0))?;
+        current = Some(step);
+    }
+    println!("The DB schema is up to date");
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::domain::sql_migrations::get_schema_version;
+    use sea_orm::Database;
+
+    async fn get_in_memory_db() -> DbConnection {
+        let mut sql_opt = sea_orm::ConnectOptions::new("sqlite::memory:".to_owned());
+        sql_opt.max_connections(1).sqlx_logging(false);
+        Database::connect(sql_opt).await.unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_migrate() {
+        let sql_pool = get_in_memory_db().await;
+        migrate(&sql_pool, UuidBackfill::default(), true)
+            .await
+            .unwrap();
+        assert_eq!(get_schema_version(&sql_pool).await, None);
+        migrate(&sql_pool, UuidBackfill::default(), false)
+            .await
+            .unwrap();
+        assert_eq!(
+            get_schema_version(&sql_pool).await,
+            Some(LAST_SCHEMA_VERSION)
+        );
+        // Nothing left to do.
+        migrate(&sql_pool, UuidBackfill::default(), false)
+            .await
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_migrate_from_older_version() {
+        let sql_pool = get_in_memory_db().await;
+        create_migration_history_table(&sql_pool).await.unwrap();
+        upgrade_to_v1(&sql_pool, UuidBackfill::default())
+            .await
+            .unwrap();
+        migrate_from_version(&sql_pool, SchemaVersion(1), SchemaVersion(3))
+            .await
+            .unwrap();
+        migrate(&sql_pool, UuidBackfill::default(), true)
+            .await
+            .unwrap();
+        assert_eq!(get_schema_version(&sql_pool).await, Some(SchemaVersion(3)));
+        migrate(&sql_pool, UuidBackfill::default(), false)
+            .await
+            .unwrap();
+        assert_eq!(
+            get_schema_version(&sql_pool).await,
+            Some(LAST_SCHEMA_VERSION)
+        );
+    }
+}
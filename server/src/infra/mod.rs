@@ -1,14 +1,34 @@
+pub mod access_control;
 pub mod auth_service;
+pub mod check_config;
 pub mod cli;
+pub mod client_certificate;
+pub mod client_ip;
 pub mod configuration;
+pub mod connection_limiter;
+pub mod correlation_id;
 pub mod db_cleaner;
 pub mod graphql;
 pub mod healthcheck;
+pub mod i18n;
 pub mod jwt_sql_tables;
+pub mod ldap_auth_backend;
+pub mod ldap_controls;
 pub mod ldap_handler;
 pub mod ldap_server;
 pub mod logging;
 pub mod mail;
+pub mod metrics;
+pub mod migrate;
+pub mod oidc;
+pub mod operation_timeout;
+pub mod rate_limiter;
+pub mod scim;
+pub mod security_headers;
+pub mod shutdown;
 pub mod sql_backend_handler;
 pub mod tcp_backend_handler;
 pub mod tcp_server;
+pub mod tls_certificate;
+pub mod tls_policy;
+pub mod webhooks;
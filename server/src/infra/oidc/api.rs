@@ -0,0 +1,1151 @@
+use super::keys::SigningKey;
+use crate::{
+    domain::{
+        error::DomainError,
+        handler::{BackendHandler, BindRequest, LoginHandler},
+        oidc_handler::{CreateOidcClientRequest, OidcAuthorization, OidcClient, OidcHandler},
+        types::{GroupDetails, User, UserId},
+    },
+    infra::{
+        auth_service::{
+            check_if_token_is_valid, check_login_rate_limit, CookieToHeaderTranslatorFactory,
+        },
+        configuration::{Configuration, OidcOptions},
+        i18n::{request_locales, MessageId},
+        tcp_server::{error_to_http_response, AppState, TcpError, TcpResult},
+    },
+};
+use actix_web::{
+    http::{header, StatusCode},
+    web, HttpRequest, HttpResponse,
+};
+use actix_web_httpauth::extractors::{basic::BasicAuth, bearer::BearerAuth};
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::collections::HashSet;
+use tracing::{debug, instrument, warn};
+
+const OPENID_SCOPE: &str = "openid";
+const PROFILE_SCOPE: &str = "profile";
+const EMAIL_SCOPE: &str = "email";
+const GROUPS_SCOPE: &str = "groups";
+const ACCESS_TOKEN_TYPE: &str = "at+jwt";
+const ID_TOKEN_TYPE: &str = "JWT";
+
+/// Shared by all the OIDC endpoints.
+pub struct OidcState {
+    pub signing_key: SigningKey,
+    pub options: OidcOptions,
+    /// The public URL of the server, without the trailing slash.
+    pub issuer: String,
+}
+
+impl OidcState {
+    pub fn new(config: &Configuration) -> Result<Self> {
+        Ok(Self {
+            signing_key: SigningKey::from_file(&config.oidc_options.private_key_file)?,
+            options: config.oidc_options.clone(),
+            issuer: config.http_url.trim_end_matches('/').to_owned(),
+        })
+    }
+
+    fn token_expiry(&self) -> chrono::Duration {
+        chrono::Duration::minutes(self.options.token_expiry_minutes.into())
+    }
+}
+
+/// The errors of RFC 6749 (sections 4.1.2.1 and 5.2) and RFC 6750 (section 3.1).
+#[derive(thiserror::Error, Debug)]
+pub enum OidcError {
+    #[error("{0}")]
+    InvalidRequest(String),
+    #[error("{0}")]
+    InvalidClient(String),
+    #[error("{0}")]
+    InvalidGrant(String),
+    #[error("{0}")]
+    UnsupportedGrantType(String),
+    #[error("{0}")]
+    UnsupportedResponseType(String),
+    #[error("{0}")]
+    InvalidScope(String),
+    #[error("{0}")]
+    InvalidToken(String),
+    #[error("{0}")]
+    ServerError(String),
+}
+
+impl From<DomainError> for OidcError {
+    fn from(error: DomainError) -> Self {
+        OidcError::ServerError(error.to_string())
+    }
+}
+
+impl OidcError {
+    fn code(&self) -> &'static str {
+        match self {
+            OidcError::InvalidRequest(_) => "invalid_request",
+            OidcError::InvalidClient(_) => "invalid_client",
+            OidcError::InvalidGrant(_) => "invalid_grant",
+            OidcError::UnsupportedGrantType(_) => "unsupported_grant_type",
+            OidcError::UnsupportedResponseType(_) => "unsupported_response_type",
+            OidcError::InvalidScope(_) => "invalid_scope",
+            OidcError::InvalidToken(_) => "invalid_token",
+            OidcError::ServerError(_) => "server_error",
+        }
+    }
+
+    fn status(&self) -> StatusCode {
+        match self {
+            OidcError::InvalidClient(_) | OidcError::InvalidToken(_) => StatusCode::UNAUTHORIZED,
+            OidcError::ServerError(_) => StatusCode::INTERNAL_SERVER_ERROR,
+            _ => StatusCode::BAD_REQUEST,
+        }
+    }
+
+    fn to_response(&self) -> HttpResponse {
+        if let OidcError::ServerError(e) = self {
+            warn!("OIDC error: {}", e);
+        }
+        let mut response = HttpResponse::build(self.status());
+        if let OidcError::InvalidToken(_) = self {
+            response.insert_header((
+                header::WWW_AUTHENTICATE,
+                format!(r#"Bearer error="{}""#, self.code()),
+            ));
+        }
+        response
+            .insert_header((header::CACHE_CONTROL, "no-store"))
+            .json(serde_json::json!({"error": self.code(), "error_description": self.to_string()}))
+    }
+}
+
+type OidcResult<T> = std::result::Result<T, OidcError>;
+
+fn html_escape(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+        .replace('\'', "&#39;")
+}
+
+fn html_response(status: StatusCode, title: &str, body: &str) -> HttpResponse {
+    HttpResponse::build(status)
+        .content_type("text/html; charset=utf-8")
+        .insert_header((header::CACHE_CONTROL, "no-store"))
+        .body(format!(
+            "<!DOCTYPE html>\n<html><head><meta charset=\"utf-8\"><title>{}</title></head>\
+             <body><h1>{}</h1>{}</body></html>",
+            html_escape(title),
+            html_escape(title),
+            body
+        ))
+}
+
+/// For the errors that can't be sent back to the client, because it can't be identified.
+fn error_page(message: &str) -> HttpResponse {
+    html_response(
+        StatusCode::BAD_REQUEST,
+        "Invalid authorization request",
+        &format!("<p>{}</p>", html_escape(message)),
+    )
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct AuthorizationRequest {
+    pub response_type: String,
+    pub client_id: String,
+    pub redirect_uri: String,
+    #[serde(default)]
+    pub scope: String,
+    #[serde(default)]
+    pub state: Option<String>,
+    #[serde(default)]
+    pub nonce: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct LoginForm {
+    pub username: String,
+    pub password: String,
+    #[serde(flatten)]
+    pub request: AuthorizationRequest,
+}
+
+fn login_page(request: &AuthorizationRequest, error: Option<&str>) -> HttpResponse {
+    let mut hidden_fields = vec![
+        ("response_type", request.response_type.as_str()),
+        ("client_id", request.client_id.as_str()),
+        ("redirect_uri", request.redirect_uri.as_str()),
+        ("scope", request.scope.as_str()),
+    ];
+    if let Some(state) = &request.state {
+        hidden_fields.push(("state", state));
+    }
+    if let Some(nonce) = &request.nonce {
+        hidden_fields.push(("nonce", nonce));
+    }
+    let hidden_fields = hidden_fields
+        .into_iter()
+        .map(|(name, value)| {
+            format!(
+                r#"<input type="hidden" name="{}" value="{}">"#,
+                name,
+                html_escape(value)
+            )
+        })
+        .collect::<String>();
+    html_response(
+        if error.is_some() {
+            StatusCode::UNAUTHORIZED
+        } else {
+            StatusCode::OK
+        },
+        "Sign in",
+        &format!(
+            "<p>Sign in to continue to {}.</p>{}<form method=\"post\">{}\
+             <p><label>Username <input name=\"username\" autocomplete=\"username\" required></label></p>\
+             <p><label>Password <input type=\"password\" name=\"password\" \
+             autocomplete=\"current-password\" required></label></p>\
+             <p><button type=\"submit\">Sign in</button></p></form>",
+            html_escape(&request.client_id),
+            error
+                .map(|e| format!("<p><strong>{}</strong></p>", html_escape(e)))
+                .unwrap_or_default(),
+            hidden_fields
+        ),
+    )
+}
+
+/// Until the client and the redirect URI are checked, the errors can't be redirected to the
+/// client: that would make the server an open redirector.
+async fn get_client<Backend>(
+    backend_handler: &Backend,
+    request: &AuthorizationRequest,
+) -> std::result::Result<(OidcClient, reqwest::Url), HttpResponse>
+where
+    Backend: OidcHandler,
+{
+    let client = match backend_handler.get_oidc_client(&request.client_id).await {
+        Ok(client) => client,
+        Err(DomainError::EntityNotFound(_)) => return Err(error_page("Unknown client")),
+        Err(e) => return Err(OidcError::from(e).to_response()),
+    };
+    if !client.redirect_uris.contains(&request.redirect_uri) {
+        return Err(error_page(
+            "The redirect URI is not registered for this client",
+        ));
+    }
+    let redirect_uri = reqwest::Url::parse(&request.redirect_uri)
+        .map_err(|_| error_page("Invalid redirect URI"))?;
+    Ok((client, redirect_uri))
+}
+
+fn redirect_to_client(
+    mut redirect_uri: reqwest::Url,
+    request: &AuthorizationRequest,
+    params: &[(&str, &str)],
+) -> HttpResponse {
+    {
+        let mut query = redirect_uri.query_pairs_mut();
+        for (name, value) in params {
+            query.append_pair(name, value);
+        }
+        if let Some(state) = &request.state {
+            query.append_pair("state", state);
+        }
+    }
+    HttpResponse::Found()
+        .insert_header((header::LOCATION, redirect_uri.to_string()))
+        .finish()
+}
+
+fn redirect_error(
+    redirect_uri: reqwest::Url,
+    request: &AuthorizationRequest,
+    error: OidcError,
+) -> HttpResponse {
+    debug!(?error);
+    let description = error.to_string();
+    redirect_to_client(
+        redirect_uri,
+        request,
+        &[("error", error.code()), ("error_description", &description)],
+    )
+}
+
+/// The requested scopes, which must include "openid" and be allowed for the client.
+fn get_scopes(client: &OidcClient, request: &AuthorizationRequest) -> OidcResult<Vec<String>> {
+    if request.response_type != "code" {
+        return Err(OidcError::UnsupportedResponseType(
+            "Only the authorization code flow is supported".to_owned(),
+        ));
+    }
+    let mut scopes = Vec::<String>::new();
+    for scope in request.scope.split_whitespace() {
+        if !client.allowed_scopes.iter().any(|s| s == scope) {
+            return Err(OidcError::InvalidScope(format!(
+                "Scope not allowed for this client: {}",
+                scope
+            )));
+        }
+        if !scopes.iter().any(|s| s == scope) {
+            scopes.push(scope.to_owned());
+        }
+    }
+    if !scopes.iter().any(|s| s == OPENID_SCOPE) {
+        return Err(OidcError::InvalidScope(
+            "The openid scope is required".to_owned(),
+        ));
+    }
+    Ok(scopes)
+}
+
+async fn issue_authorization_code<Backend>(
+    backend_handler: &Backend,
+    request: &AuthorizationRequest,
+    redirect_uri: reqwest::Url,
+    scopes: Vec<String>,
+    user_id: UserId,
+) -> HttpResponse
+where
+    Backend: OidcHandler,
+{
+    debug!(?user_id, client_id = ?request.client_id);
+    match backend_handler
+        .create_oidc_authorization_code(OidcAuthorization {
+            client_id: request.client_id.clone(),
+            user_id,
+            redirect_uri: request.redirect_uri.clone(),
+            scopes,
+            nonce: request.nonce.clone(),
+        })
+        .await
+    {
+        Ok(code) => redirect_to_client(redirect_uri, request, &[("code", &code)]),
+        Err(e) => redirect_error(redirect_uri, request, e.into()),
+    }
+}
+
+/// Users already logged in to the web UI don't need to enter their password again.
+fn get_logged_in_user<Backend>(req: &HttpRequest, data: &AppState<Backend>) -> Option<UserId> {
+    let token = req.cookie("token")?;
+    check_if_token_is_valid(data, token.value())
+        .ok()
+        .map(|validation_result| validation_result.user)
+}
+
+#[instrument(skip_all, level = "debug")]
+async fn get_authorize_handler<Backend>(
+    req: HttpRequest,
+    data: web::Data<AppState<Backend>>,
+) -> HttpResponse
+where
+    Backend: OidcHandler + 'static,
+{
+    let request = match web::Query::<AuthorizationRequest>::from_query(req.query_string()) {
+        Ok(request) => request.into_inner(),
+        Err(e) => return error_page(&e.to_string()),
+    };
+    let (client, redirect_uri) = match get_client(&data.backend_handler, &request).await {
+        Ok(client) => client,
+        Err(response) => return response,
+    };
+    let scopes = match get_scopes(&client, &request) {
+        Ok(scopes) => scopes,
+        Err(e) => return redirect_error(redirect_uri, &request, e),
+    };
+    match get_logged_in_user(&req, &data) {
+        Some(user_id) => {
+            issue_authorization_code(
+                &data.backend_handler,
+                &request,
+                redirect_uri,
+                scopes,
+                user_id,
+            )
+            .await
+        }
+        None => login_page(&request, None),
+    }
+}
+
+#[instrument(skip_all, level = "debug")]
+async fn post_authorize_handler<Backend>(
+    data: web::Data<AppState<Backend>>,
+    http_request: HttpRequest,
+    form: web::Form<LoginForm>,
+) -> HttpResponse
+where
+    Backend: OidcHandler + LoginHandler + 'static,
+{
+    let LoginForm {
+        username,
+        password,
+        request,
+    } = form.into_inner();
+    let (client, redirect_uri) = match get_client(&data.backend_handler, &request).await {
+        Ok(client) => client,
+        Err(response) => return response,
+    };
+    let scopes = match get_scopes(&client, &request) {
+        Ok(scopes) => scopes,
+        Err(e) => return redirect_error(redirect_uri, &request, e),
+    };
+    let error_message = |id| {
+        data.messages
+            .message(&request_locales(&http_request), id, &[])
+    };
+    let user_id = UserId::new(&username);
+    if check_login_rate_limit(&data, &http_request, &user_id)
+        .await
+        .is_err()
+    {
+        return login_page(
+            &request,
+            Some(&error_message(MessageId::TooManyLoginAttempts)),
+        );
+    }
+    match data
+        .backend_handler
+        .bind(BindRequest {
+            name: user_id.clone(),
+            password,
+        })
+        .await
+    {
+        Ok(()) => {
+            issue_authorization_code(
+                &data.backend_handler,
+                &request,
+                redirect_uri,
+                scopes,
+                user_id,
+            )
+            .await
+        }
+        Err(DomainError::AuthenticationError(_)) => login_page(
+            &request,
+            Some(&error_message(MessageId::InvalidCredentials)),
+        ),
+        Err(DomainError::AccountLocked(_)) => {
+            login_page(&request, Some(&error_message(MessageId::AccountLocked)))
+        }
+        Err(DomainError::PasswordChangeRequired(_)) => {
+            login_page(&request, Some(&error_message(MessageId::PasswordExpired)))
+        }
+        Err(e) => OidcError::from(e).to_response(),
+    }
+}
+
+/// The claims about the user, according to the granted scopes. The same claims are in the ID
+/// token and the userinfo response.
+fn get_user_claims(
+    user: &User,
+    groups: &HashSet<GroupDetails>,
+    scopes: &[String],
+    groups_claim: &str,
+) -> serde_json::Map<String, Value> {
+    let has_scope = |scope: &str| scopes.iter().any(|s| s == scope);
+    let mut claims = serde_json::Map::new();
+    claims.insert("sub".to_owned(), Value::from(user.user_id.as_str()));
+    let non_empty =
+        |value: &Option<String>| value.as_deref().filter(|v| !v.is_empty()).map(Value::from);
+    if has_scope(PROFILE_SCOPE) {
+        claims.insert(
+            "preferred_username".to_owned(),
+            Value::from(user.user_id.as_str()),
+        );
+        for (name, value) in [
+            ("name", &user.display_name),
+            ("given_name", &user.first_name),
+            ("family_name", &user.last_name),
+        ] {
+            if let Some(value) = non_empty(value) {
+                claims.insert(name.to_owned(), value);
+            }
+        }
+    }
+    if has_scope(EMAIL_SCOPE) && !user.email.is_empty() {
+        claims.insert("email".to_owned(), Value::from(user.email.as_str()));
+        claims.insert(
+            "email_verified".to_owned(),
+            Value::from(user.email_verified),
+        );
+    }
+    if has_scope(GROUPS_SCOPE) {
+        let mut group_names = groups
+            .iter()
+            .map(|g| g.display_name.as_str())
+            .collect::<Vec<_>>();
+        group_names.sort_unstable();
+        claims.insert(groups_claim.to_owned(), Value::from(group_names));
+    }
+    claims
+}
+
+#[derive(Debug, Deserialize)]
+pub struct TokenRequest {
+    pub grant_type: String,
+    #[serde(default)]
+    pub code: Option<String>,
+    #[serde(default)]
+    pub redirect_uri: Option<String>,
+    #[serde(default)]
+    pub client_id: Option<String>,
+    #[serde(default)]
+    pub client_secret: Option<String>,
+}
+
+#[derive(Debug, PartialEq, Eq, Serialize, Deserialize)]
+struct AccessTokenClaims {
+    iss: String,
+    sub: UserId,
+    /// The client the token was issued to.
+    aud: String,
+    exp: i64,
+    iat: i64,
+    /// Space-separated.
+    scope: String,
+}
+
+#[derive(Debug, Serialize)]
+struct TokenResponse {
+    access_token: String,
+    token_type: &'static str,
+    expires_in: i64,
+    id_token: String,
+    scope: String,
+}
+
+/// The client authenticates either with HTTP basic auth, or with the form parameters.
+async fn authenticate_client<Backend>(
+    backend_handler: &Backend,
+    basic: Option<BasicAuth>,
+    request: &TokenRequest,
+) -> OidcResult<OidcClient>
+where
+    Backend: OidcHandler,
+{
+    let (client_id, client_secret) = match (&basic, &request.client_id, &request.client_secret) {
+        (Some(basic), _, _) => (
+            basic.user_id().to_string(),
+            basic
+                .password()
+                .map(ToString::to_string)
+                .unwrap_or_default(),
+        ),
+        (None, Some(client_id), Some(client_secret)) => (client_id.clone(), client_secret.clone()),
+        _ => {
+            return Err(OidcError::InvalidClient(
+                "Missing client credentials".to_owned(),
+            ))
+        }
+    };
+    backend_handler
+        .authenticate_oidc_client(&client_id, &client_secret)
+        .await
+        .map_err(|e| match e {
+            DomainError::AuthenticationError(e) => OidcError::InvalidClient(e),
+            e => e.into(),
+        })
+}
+
+async fn exchange_code<Backend>(
+    backend_handler: &Backend,
+    oidc: &OidcState,
+    basic: Option<BasicAuth>,
+    request: TokenRequest,
+) -> OidcResult<TokenResponse>
+where
+    Backend: BackendHandler + OidcHandler,
+{
+    if request.grant_type != "authorization_code" {
+        return Err(OidcError::UnsupportedGrantType(
+            "Only the authorization_code grant is supported".to_owned(),
+        ));
+    }
+    let client = authenticate_client(backend_handler, basic, &request).await?;
+    let code = request
+        .code
+        .as_deref()
+        .ok_or_else(|| OidcError::InvalidRequest("Missing code".to_owned()))?;
+    let authorization = backend_handler
+        .consume_oidc_authorization_code(&client.client_id, code)
+        .await
+        .map_err(|e| match e {
+            DomainError::AuthenticationError(e) => OidcError::InvalidGrant(e),
+            e => e.into(),
+        })?;
+    if request.redirect_uri.as_deref() != Some(authorization.redirect_uri.as_str()) {
+        return Err(OidcError::InvalidGrant(
+            "The redirect URI doesn't match the authorization request".to_owned(),
+        ));
+    }
+    let user = backend_handler
+        .get_user_details(&authorization.user_id)
+        .await
+        .map_err(|e| match e {
+            DomainError::EntityNotFound(_) => {
+                OidcError::InvalidGrant("The user no longer exists".to_owned())
+            }
+            e => e.into(),
+        })?;
+    let groups = backend_handler.get_user_groups(&user.user_id).await?;
+    let now = chrono::Utc::now();
+    let expiry = oidc.token_expiry();
+    let scope = authorization.scopes.join(" ");
+    let mut id_token_claims = get_user_claims(
+        &user,
+        &groups,
+        &authorization.scopes,
+        &oidc.options.groups_claim,
+    );
+    id_token_claims.insert("iss".to_owned(), Value::from(oidc.issuer.as_str()));
+    id_token_claims.insert("aud".to_owned(), Value::from(client.client_id.as_str()));
+    id_token_claims.insert("iat".to_owned(), Value::from(now.timestamp()));
+    id_token_claims.insert("exp".to_owned(), Value::from((now + expiry).timestamp()));
+    if let Some(nonce) = authorization.nonce {
+        id_token_claims.insert("nonce".to_owned(), Value::from(nonce));
+    }
+    let sign_error = |e: anyhow::Error| OidcError::ServerError(format!("Cannot sign token: {}", e));
+    Ok(TokenResponse {
+        access_token: oidc
+            .signing_key
+            .sign(
+                ACCESS_TOKEN_TYPE,
+                &AccessTokenClaims {
+                    iss: oidc.issuer.clone(),
+                    sub: user.user_id,
+                    aud: client.client_id,
+                    exp: (now + expiry).timestamp(),
+                    iat: now.timestamp(),
+                    scope: scope.clone(),
+                },
+            )
+            .map_err(sign_error)?,
+        token_type: "Bearer",
+        expires_in: expiry.num_seconds(),
+        id_token: oidc
+            .signing_key
+            .sign(ID_TOKEN_TYPE, &id_token_claims)
+            .map_err(sign_error)?,
+        scope,
+    })
+}
+
+#[instrument(skip_all, level = "debug")]
+async fn token_handler<Backend>(
+    data: web::Data<AppState<Backend>>,
+    oidc: web::Data<OidcState>,
+    basic: Option<BasicAuth>,
+    form: web::Form<TokenRequest>,
+) -> HttpResponse
+where
+    Backend: BackendHandler + OidcHandler + 'static,
+{
+    match exchange_code(&data.backend_handler, &oidc, basic, form.into_inner()).await {
+        Ok(response) => HttpResponse::Ok()
+            .insert_header((header::CACHE_CONTROL, "no-store"))
+            .insert_header((header::PRAGMA, "no-cache"))
+            .json(response),
+        Err(e) => e.to_response(),
+    }
+}
+
+async fn get_userinfo<Backend>(
+    backend_handler: &Backend,
+    oidc: &OidcState,
+    access_token: Option<&str>,
+) -> OidcResult<serde_json::Map<String, Value>>
+where
+    Backend: BackendHandler,
+{
+    let access_token =
+        access_token.ok_or_else(|| OidcError::InvalidToken("Missing access token".to_owned()))?;
+    let claims: AccessTokenClaims = oidc
+        .signing_key
+        .verify(ACCESS_TOKEN_TYPE, access_token)
+        .map_err(|e| OidcError::InvalidToken(e.to_string()))?;
+    if claims.iss != oidc.issuer || claims.exp < chrono::Utc::now().timestamp() {
+        return Err(OidcError::InvalidToken(
+            "Expired or foreign access token".to_owned(),
+        ));
+    }
+    let user = backend_handler
+        .get_user_details(&claims.sub)
+        .await
+        .map_err(|e| match e {
+            DomainError::EntityNotFound(_) => {
+                OidcError::InvalidToken("The user no longer exists".to_owned())
+            }
+            e => e.into(),
+        })?;
+    let groups = backend_handler.get_user_groups(&user.user_id).await?;
+    let scopes = claims
+        .scope
+        .split_whitespace()
+        .map(str::to_owned)
+        .collect::<Vec<_>>();
+    Ok(get_user_claims(
+        &user,
+        &groups,
+        &scopes,
+        &oidc.options.groups_claim,
+    ))
+}
+
+#[instrument(skip_all, level = "debug")]
+async fn userinfo_handler<Backend>(
+    data: web::Data<AppState<Backend>>,
+    oidc: web::Data<OidcState>,
+    bearer: Option<BearerAuth>,
+) -> HttpResponse
+where
+    Backend: BackendHandler + 'static,
+{
+    match get_userinfo(
+        &data.backend_handler,
+        &oidc,
+        bearer.as_ref().map(BearerAuth::token),
+    )
+    .await
+    {
+        Ok(claims) => HttpResponse::Ok().json(claims),
+        Err(e) => e.to_response(),
+    }
+}
+
+async fn discovery_handler(oidc: web::Data<OidcState>) -> HttpResponse {
+    let issuer = &oidc.issuer;
+    HttpResponse::Ok().json(serde_json::json!({
+        "issuer": issuer,
+        "authorization_endpoint": format!("{}/oidc/authorize", issuer),
+        "token_endpoint": format!("{}/oidc/token", issuer),
+        "userinfo_endpoint": format!("{}/oidc/userinfo", issuer),
+        "jwks_uri": format!("{}/oidc/jwks", issuer),
+        "response_types_supported": ["code"],
+        "grant_types_supported": ["authorization_code"],
+        "subject_types_supported": ["public"],
+        "id_token_signing_alg_values_supported": ["RS256"],
+        "scopes_supported": [OPENID_SCOPE, PROFILE_SCOPE, EMAIL_SCOPE, GROUPS_SCOPE],
+        "token_endpoint_auth_methods_supported": ["client_secret_basic", "client_secret_post"],
+        "claims_supported": [
+            "sub",
+            "iss",
+            "aud",
+            "exp",
+            "iat",
+            "nonce",
+            "preferred_username",
+            "name",
+            "given_name",
+            "family_name",
+            "email",
+            oidc.options.groups_claim,
+        ],
+    }))
+}
+
+async fn jwks_handler(oidc: web::Data<OidcState>) -> HttpResponse {
+    HttpResponse::Ok().json(oidc.signing_key.jwks())
+}
+
+#[derive(Debug, Serialize)]
+struct CreateOidcClientResponse {
+    client_id: String,
+    /// Only shown once.
+    client_secret: String,
+}
+
+/// The clients are managed by the admins.
+fn check_admin<Backend>(data: &AppState<Backend>, bearer: &BearerAuth) -> TcpResult<()> {
+    let validation_result = check_if_token_is_valid(data, bearer.token())
+        .map_err(|e| TcpError::UnauthorizedError(e.to_string()))?;
+    if !validation_result.is_admin() {
+        return Err(TcpError::UnauthorizedError(
+            "Only the admins can manage the OIDC clients".to_owned(),
+        ));
+    }
+    Ok(())
+}
+
+async fn list_clients_handler<Backend>(
+    data: web::Data<AppState<Backend>>,
+    bearer: BearerAuth,
+) -> HttpResponse
+where
+    Backend: OidcHandler + 'static,
+{
+    async {
+        check_admin(&data, &bearer)?;
+        let clients = data.backend_handler.list_oidc_clients().await?;
+        TcpResult::Ok(HttpResponse::Ok().json(clients))
+    }
+    .await
+    .unwrap_or_else(error_to_http_response)
+}
+
+async fn create_client_handler<Backend>(
+    data: web::Data<AppState<Backend>>,
+    bearer: BearerAuth,
+    request: web::Json<CreateOidcClientRequest>,
+) -> HttpResponse
+where
+    Backend: OidcHandler + 'static,
+{
+    async {
+        check_admin(&data, &bearer)?;
+        let client_id = request.client_id.clone();
+        let client_secret = data
+            .backend_handler
+            .create_oidc_client(request.into_inner())
+            .await?;
+        TcpResult::Ok(HttpResponse::Created().json(CreateOidcClientResponse {
+            client_id,
+            client_secret,
+        }))
+    }
+    .await
+    .unwrap_or_else(error_to_http_response)
+}
+
+async fn delete_client_handler<Backend>(
+    data: web::Data<AppState<Backend>>,
+    bearer: BearerAuth,
+    client_id: web::Path<String>,
+) -> HttpResponse
+where
+    Backend: OidcHandler + 'static,
+{
+    async {
+        check_admin(&data, &bearer)?;
+        data.backend_handler.delete_oidc_client(&client_id).await?;
+        TcpResult::Ok(HttpResponse::NoContent().finish())
+    }
+    .await
+    .unwrap_or_else(error_to_http_response)
+}
+
+/// Registers the discovery document and the `/oidc` endpoints. Requires the `OidcState` in the
+/// app data.
+pub fn configure_endpoint<Backend>(cfg: &mut web::ServiceConfig)
+where
+    Backend: BackendHandler + LoginHandler + OidcHandler + Sync + 'static,
+{
+    cfg.service(
+        web::resource("/.well-known/openid-configuration").route(web::get().to(discovery_handler)),
+    )
+    .service(
+        web::scope("/oidc")
+            .service(
+                web::resource("/authorize")
+                    .route(web::get().to(get_authorize_handler::<Backend>))
+                    .route(web::post().to(post_authorize_handler::<Backend>)),
+            )
+            .service(web::resource("/token").route(web::post().to(token_handler::<Backend>)))
+            .service(
+                web::resource("/userinfo")
+                    .route(web::get().to(userinfo_handler::<Backend>))
+                    .route(web::post().to(userinfo_handler::<Backend>)),
+            )
+            .service(web::resource("/jwks").route(web::get().to(jwks_handler)))
+            .service(
+                web::scope("/clients")
+                    .wrap(CookieToHeaderTranslatorFactory)
+                    .service(
+                        web::resource("")
+                            .route(web::get().to(list_clients_handler::<Backend>))
+                            .route(web::post().to(create_client_handler::<Backend>)),
+                    )
+                    .service(
+                        web::resource("/{client_id}")
+                            .route(web::delete().to(delete_client_handler::<Backend>)),
+                    ),
+            ),
+    );
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        domain::{
+            handler::MockTestBackendHandler,
+            types::{GroupId, Uuid},
+        },
+        infra::oidc::keys::tests::get_test_key,
+    };
+    use mockall::predicate::eq;
+
+    const REDIRECT_URI: &str = "https://app.example.com/callback";
+
+    fn get_oidc_state() -> OidcState {
+        OidcState {
+            signing_key: get_test_key(),
+            options: OidcOptions {
+                enabled: true,
+                groups_claim: "roles".to_owned(),
+                ..Default::default()
+            },
+            issuer: "https://auth.example.com".to_owned(),
+        }
+    }
+
+    fn make_client() -> OidcClient {
+        OidcClient {
+            client_id: "app".to_owned(),
+            redirect_uris: vec![REDIRECT_URI.to_owned()],
+            allowed_scopes: vec![
+                OPENID_SCOPE.to_owned(),
+                PROFILE_SCOPE.to_owned(),
+                GROUPS_SCOPE.to_owned(),
+            ],
+            creation_date: chrono::Utc::now(),
+        }
+    }
+
+    fn make_request(scope: &str) -> AuthorizationRequest {
+        AuthorizationRequest {
+            response_type: "code".to_owned(),
+            client_id: "app".to_owned(),
+            redirect_uri: REDIRECT_URI.to_owned(),
+            scope: scope.to_owned(),
+            state: Some("xyz".to_owned()),
+            nonce: Some("abc".to_owned()),
+        }
+    }
+
+    fn make_user() -> User {
+        User {
+            user_id: UserId::new("bob"),
+            email: "bob@bob.bob".to_owned(),
+            display_name: Some("Bob Bobbers".to_owned()),
+            first_name: Some("Bob".to_owned()),
+            last_name: Some(String::new()),
+            ..Default::default()
+        }
+    }
+
+    fn make_groups() -> HashSet<GroupDetails> {
+        ["Worst Group", "Best Group"]
+            .into_iter()
+            .enumerate()
+            .map(|(i, name)| GroupDetails {
+                group_id: GroupId(i as i32),
+                display_name: name.to_owned(),
+                creation_date: chrono::Utc::now(),
+                uuid: Uuid::from_name_and_date(name, &chrono::Utc::now()),
+            })
+            .collect()
+    }
+
+    fn token_request(code: &str) -> TokenRequest {
+        TokenRequest {
+            grant_type: "authorization_code".to_owned(),
+            code: Some(code.to_owned()),
+            redirect_uri: Some(REDIRECT_URI.to_owned()),
+            client_id: Some("app".to_owned()),
+            client_secret: Some("secret".to_owned()),
+        }
+    }
+
+    fn expect_code_exchange(mock: &mut MockTestBackendHandler, scopes: &[&str]) {
+        mock.expect_authenticate_oidc_client()
+            .with(eq("app"), eq("secret"))
+            .return_once(|_, _| Ok(make_client()));
+        let scopes = scopes.iter().map(ToString::to_string).collect();
+        mock.expect_consume_oidc_authorization_code()
+            .with(eq("app"), eq("the-code"))
+            .return_once(|_, _| {
+                Ok(OidcAuthorization {
+                    client_id: "app".to_owned(),
+                    user_id: UserId::new("bob"),
+                    redirect_uri: REDIRECT_URI.to_owned(),
+                    scopes,
+                    nonce: Some("abc".to_owned()),
+                })
+            });
+        mock.expect_get_user_details()
+            .with(eq(UserId::new("bob")))
+            .returning(|_| Ok(make_user()));
+        mock.expect_get_user_groups()
+            .with(eq(UserId::new("bob")))
+            .returning(|_| Ok(make_groups()));
+    }
+
+    #[test]
+    fn test_get_scopes() {
+        let client = make_client();
+        assert_eq!(
+            get_scopes(&client, &make_request("openid profile openid")).unwrap(),
+            vec!["openid", "profile"]
+        );
+        assert!(matches!(
+            get_scopes(&client, &make_request("profile")),
+            Err(OidcError::InvalidScope(_))
+        ));
+        assert!(matches!(
+            get_scopes(&client, &make_request("openid email")),
+            Err(OidcError::InvalidScope(_))
+        ));
+        assert!(matches!(
+            get_scopes(
+                &client,
+                &AuthorizationRequest {
+                    response_type: "token".to_owned(),
+                    ..make_request("openid")
+                }
+            ),
+            Err(OidcError::UnsupportedResponseType(_))
+        ));
+    }
+
+    #[test]
+    fn test_user_claims() {
+        let user = make_user();
+        let groups = make_groups();
+        let get_claims = |scopes: &[&str]| {
+            Value::from(get_user_claims(
+                &user,
+                &groups,
+                &scopes.iter().map(ToString::to_string).collect::<Vec<_>>(),
+                "roles",
+            ))
+        };
+        assert_eq!(get_claims(&["openid"]), serde_json::json!({"sub": "bob"}));
+        assert_eq!(
+            get_claims(&["openid", "profile", "email", "groups"]),
+            serde_json::json!({
+                "sub": "bob",
+                "preferred_username": "bob",
+                "name": "Bob Bobbers",
+                "given_name": "Bob",
+                "email": "bob@bob.bob",
+                "email_verified": false,
+                "roles": ["Best Group", "Worst Group"],
+            })
+        );
+    }
+
+    #[tokio::test]
+    async fn test_unregistered_redirect_uri() {
+        let mut mock = MockTestBackendHandler::new();
+        mock.expect_get_oidc_client()
+            .with(eq("app"))
+            .return_once(|_| Ok(make_client()));
+        let response = get_client(
+            &mock,
+            &AuthorizationRequest {
+                redirect_uri: "https://evil.example.com/callback".to_owned(),
+                ..make_request("openid")
+            },
+        )
+        .await
+        .unwrap_err();
+        // No redirection to an unknown URI.
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+        assert!(response.headers().get(header::LOCATION).is_none());
+    }
+
+    #[tokio::test]
+    async fn test_issue_authorization_code() {
+        let mut mock = MockTestBackendHandler::new();
+        mock.expect_create_oidc_authorization_code()
+            .with(eq(OidcAuthorization {
+                client_id: "app".to_owned(),
+                user_id: UserId::new("bob"),
+                redirect_uri: REDIRECT_URI.to_owned(),
+                scopes: vec!["openid".to_owned()],
+                nonce: Some("abc".to_owned()),
+            }))
+            .return_once(|_| Ok("the code".to_owned()));
+        let response = issue_authorization_code(
+            &mock,
+            &make_request("openid"),
+            reqwest::Url::parse(REDIRECT_URI).unwrap(),
+            vec!["openid".to_owned()],
+            UserId::new("bob"),
+        )
+        .await;
+        assert_eq!(response.status(), StatusCode::FOUND);
+        assert_eq!(
+            response.headers().get(header::LOCATION).unwrap(),
+            "https://app.example.com/callback?code=the+code&state=xyz"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_exchange_code() {
+        let oidc = get_oidc_state();
+        let mut mock = MockTestBackendHandler::new();
+        expect_code_exchange(&mut mock, &["openid", "groups"]);
+        let response = exchange_code(&mock, &oidc, None, token_request("the-code"))
+            .await
+            .unwrap();
+        assert_eq!(response.scope, "openid groups");
+        let id_token: Value = oidc
+            .signing_key
+            .verify(ID_TOKEN_TYPE, &response.id_token)
+            .unwrap();
+        assert_eq!(id_token["iss"], "https://auth.example.com");
+        assert_eq!(id_token["sub"], "bob");
+        assert_eq!(id_token["aud"], "app");
+        assert_eq!(id_token["nonce"], "abc");
+        assert_eq!(
+            id_token["roles"],
+            serde_json::json!(["Best Group", "Worst Group"])
+        );
+        assert!(id_token.get("email").is_none());
+
+        // The access token gives access to the same claims.
+        let userinfo = get_userinfo(&mock, &oidc, Some(&response.access_token))
+            .await
+            .unwrap();
+        assert_eq!(userinfo["roles"], id_token["roles"]);
+        // But the ID token is not an access token.
+        assert!(matches!(
+            get_userinfo(&mock, &oidc, Some(&response.id_token)).await,
+            Err(OidcError::InvalidToken(_))
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_exchange_code_wrong_redirect_uri() {
+        let oidc = get_oidc_state();
+        let mut mock = MockTestBackendHandler::new();
+        expect_code_exchange(&mut mock, &["openid"]);
+        let result = exchange_code(
+            &mock,
+            &oidc,
+            None,
+            TokenRequest {
+                redirect_uri: Some("https://app.example.com/other".to_owned()),
+                ..token_request("the-code")
+            },
+        )
+        .await;
+        assert!(matches!(result, Err(OidcError::InvalidGrant(_))));
+    }
+
+    #[tokio::test]
+    async fn test_exchange_code_without_client_credentials() {
+        let oidc = get_oidc_state();
+        let mock = MockTestBackendHandler::new();
+        let result = exchange_code(
+            &mock,
+            &oidc,
+            None,
+            TokenRequest {
+                client_secret: None,
+                ..token_request("the-code")
+            },
+        )
+        .await;
+        let error = result.err().unwrap();
+        assert!(matches!(error, OidcError::InvalidClient(_)));
+        assert_eq!(error.status(), StatusCode::UNAUTHORIZED);
+    }
+}
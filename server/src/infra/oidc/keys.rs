@@ -0,0 +1,184 @@
+use crate::infra::configuration::write_to_readonly_file;
+use anyhow::{Context, Result};
+use rsa::{
+    pkcs8::{DecodePrivateKey, EncodePrivateKey, LineEnding},
+    PaddingScheme, PublicKey, PublicKeyParts, RsaPrivateKey, RsaPublicKey,
+};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+const KEY_SIZE: usize = 2048;
+
+fn base64_url(data: &[u8]) -> String {
+    base64::encode_config(data, base64::URL_SAFE_NO_PAD)
+}
+
+fn padding() -> PaddingScheme {
+    PaddingScheme::new_pkcs1v15_sign(Some(rsa::Hash::SHA2_256))
+}
+
+#[derive(Debug, PartialEq, Eq, Serialize, Deserialize)]
+struct Header {
+    alg: String,
+    #[serde(default)]
+    typ: Option<String>,
+    #[serde(default)]
+    kid: Option<String>,
+}
+
+/// The RSA key that signs the tokens (RS256), published in the JWKS so that the clients can
+/// verify them.
+pub struct SigningKey {
+    private_key: RsaPrivateKey,
+    public_key: RsaPublicKey,
+    key_id: String,
+}
+
+impl SigningKey {
+    pub fn new(private_key: RsaPrivateKey) -> Self {
+        let public_key = RsaPublicKey::from(&private_key);
+        // Derived from the key, so that it changes if the key file is replaced.
+        let key_id = base64_url(&Sha256::digest(&public_key.n().to_bytes_be())[..12]);
+        Self {
+            private_key,
+            public_key,
+            key_id,
+        }
+    }
+
+    /// Reads the PKCS#8 PEM key, or generates it if the file doesn't exist.
+    pub fn from_file(file_path: &str) -> Result<Self> {
+        let path = std::path::Path::new(file_path);
+        if path.exists() {
+            let pem = std::fs::read_to_string(path)
+                .context(format!("Could not read OIDC key file `{}`", file_path))?;
+            let private_key = RsaPrivateKey::from_pkcs8_pem(&pem)
+                .context(format!("Invalid OIDC key in `{}`", file_path))?;
+            Ok(Self::new(private_key))
+        } else {
+            let private_key = RsaPrivateKey::new(&mut rand::rngs::OsRng, KEY_SIZE)?;
+            let pem = private_key.to_pkcs8_pem(LineEnding::LF)?;
+            write_to_readonly_file(path, pem.as_bytes()).context(format!(
+                "Could not write the generated OIDC key to file `{}`",
+                file_path
+            ))?;
+            Ok(Self::new(private_key))
+        }
+    }
+
+    pub fn key_id(&self) -> &str {
+        &self.key_id
+    }
+
+    /// Signs the claims as a compact JWT, with the given `typ` header.
+    pub fn sign<Claims: Serialize>(&self, typ: &str, claims: &Claims) -> Result<String> {
+        let header = Header {
+            alg: "RS256".to_owned(),
+            typ: Some(typ.to_owned()),
+            kid: Some(self.key_id.clone()),
+        };
+        let signing_input = format!(
+            "{}.{}",
+            base64_url(&serde_json::to_vec(&header)?),
+            base64_url(&serde_json::to_vec(claims)?)
+        );
+        let signature = self
+            .private_key
+            .sign(padding(), &Sha256::digest(signing_input.as_bytes()))?;
+        Ok(format!("{}.{}", signing_input, base64_url(&signature)))
+    }
+
+    /// Checks the signature and the `typ` header, and returns the claims. The expiry is left to
+    /// the caller.
+    pub fn verify<Claims: DeserializeOwned>(&self, typ: &str, token: &str) -> Result<Claims> {
+        let decode = |part: &str| base64::decode_config(part, base64::URL_SAFE_NO_PAD);
+        let mut parts = token.split('.');
+        let (header, claims, signature) =
+            match (parts.next(), parts.next(), parts.next(), parts.next()) {
+                (Some(header), Some(claims), Some(signature), None) => (header, claims, signature),
+                _ => anyhow::bail!("Malformed JWT"),
+            };
+        let decoded_header: Header = serde_json::from_slice(&decode(header)?)?;
+        if decoded_header.alg != "RS256" || decoded_header.typ.as_deref() != Some(typ) {
+            anyhow::bail!("Unexpected JWT header: {:?}", decoded_header);
+        }
+        self.public_key
+            .verify(
+                padding(),
+                &Sha256::digest(format!("{}.{}", header, claims).as_bytes()),
+                &decode(signature)?,
+            )
+            .context("Invalid JWT signature")?;
+        Ok(serde_json::from_slice(&decode(claims)?)?)
+    }
+
+    /// The JSON Web Key Set (RFC 7517) with the public key.
+    pub fn jwks(&self) -> serde_json::Value {
+        serde_json::json!({
+            "keys": [{
+                "kty": "RSA",
+                "use": "sig",
+                "alg": "RS256",
+                "kid": self.key_id,
+                "n": base64_url(&self.public_key.n().to_bytes_be()),
+                "e": base64_url(&self.public_key.e().to_bytes_be()),
+            }]
+        })
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+
+    /// Smaller than the real keys, to keep the tests fast.
+    pub fn get_test_key() -> SigningKey {
+        SigningKey::new(RsaPrivateKey::new(&mut rand::rngs::OsRng, 1024).unwrap())
+    }
+
+    #[derive(Debug, PartialEq, Eq, Serialize, Deserialize)]
+    struct Claims {
+        sub: String,
+    }
+
+    #[test]
+    fn test_sign_and_verify() {
+        let key = get_test_key();
+        let claims = Claims {
+            sub: "bob".to_owned(),
+        };
+        let token = key.sign("JWT", &claims).unwrap();
+        assert_eq!(key.verify::<Claims>("JWT", &token).unwrap(), claims);
+        // Another type of token.
+        key.verify::<Claims>("at+jwt", &token).unwrap_err();
+        // Tampered claims.
+        let parts = token.split('.').collect::<Vec<_>>();
+        let forged = format!(
+            "{}.{}.{}",
+            parts[0],
+            base64_url(br#"{"sub":"admin"}"#),
+            parts[2]
+        );
+        key.verify::<Claims>("JWT", &forged).unwrap_err();
+        // Signed by another key.
+        key.verify::<Claims>("JWT", &get_test_key().sign("JWT", &claims).unwrap())
+            .unwrap_err();
+    }
+
+    #[test]
+    fn test_jwks() {
+        let key = get_test_key();
+        let jwks = key.jwks();
+        let jwk = &jwks["keys"][0];
+        assert_eq!(jwk["kid"], key.key_id());
+        assert_eq!(jwk["kty"], "RSA");
+        // 65537.
+        assert_eq!(jwk["e"], "AQAB");
+        assert_eq!(
+            base64::decode_config(jwk["n"].as_str().unwrap(), base64::URL_SAFE_NO_PAD)
+                .unwrap()
+                .len(),
+            128
+        );
+    }
+}
@@ -0,0 +1,98 @@
+use crate::infra::configuration::DatabasePoolOptions;
+use std::{future::Future, time::Duration};
+use tokio::time::error::Elapsed;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OperationKind {
+    Search,
+    Modification,
+}
+
+/// The time limits of the LDAP and GraphQL operations, `None` when disabled.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct OperationTimeouts {
+    pub search: Option<Duration>,
+    pub modification: Option<Duration>,
+}
+
+impl OperationTimeouts {
+    pub fn new(options: &DatabasePoolOptions) -> Self {
+        let seconds = |seconds: u64| (seconds > 0).then(|| Duration::from_secs(seconds));
+        Self {
+            search: seconds(options.search_timeout_seconds),
+            modification: seconds(options.modification_timeout_seconds),
+        }
+    }
+
+    pub fn get(&self, kind: OperationKind) -> Option<Duration> {
+        match kind {
+            OperationKind::Search => self.search,
+            OperationKind::Modification => self.modification,
+        }
+    }
+}
+
+/// Runs the operation, and drops it if it doesn't complete in time. Dropping the future releases
+/// its database connection and rolls back its transaction, if any. Where the driver supports it
+/// (Postgres, MySQL), the statement is cancelled with the connection; SQLite finishes it in its
+/// worker thread before the connection can be used again.
+pub async fn run_with_timeout<F: Future>(
+    timeout: Option<Duration>,
+    operation: F,
+) -> Result<F::Output, Elapsed> {
+    match timeout {
+        Some(timeout) => tokio::time::timeout(timeout, operation).await,
+        None => Ok(operation.await),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::domain::sql_backend_handler::tests::get_in_memory_db;
+    use sea_orm::{ConnectionTrait, DbBackend, Statement};
+
+    #[test]
+    fn test_zero_disables_the_timeout() {
+        let options = crate::infra::configuration::DatabasePoolOptionsBuilder::default()
+            .search_timeout_seconds(0)
+            .build()
+            .unwrap();
+        let timeouts = OperationTimeouts::new(&options);
+        assert_eq!(timeouts.get(OperationKind::Search), None);
+        assert_eq!(
+            timeouts.get(OperationKind::Modification),
+            Some(Duration::from_secs(60))
+        );
+    }
+
+    #[tokio::test]
+    async fn test_slow_query_times_out_and_returns_the_connection() {
+        // A single connection: the second query needs the first one back in the pool.
+        let sql_pool = get_in_memory_db().await;
+        let slow_query = Statement::from_string(
+            DbBackend::Sqlite,
+            "WITH RECURSIVE c(x) AS (SELECT 1 UNION ALL SELECT x + 1 FROM c WHERE x < 5000000) \
+             SELECT count(*) FROM c"
+                .to_owned(),
+        );
+        assert!(run_with_timeout(
+            Some(Duration::from_millis(10)),
+            sql_pool.query_one(slow_query)
+        )
+        .await
+        .is_err());
+        let row = run_with_timeout(
+            Some(Duration::from_secs(30)),
+            sql_pool.query_one(Statement::from_string(
+                DbBackend::Sqlite,
+                "SELECT 1 AS one".to_owned(),
+            )),
+        )
+        .await
+        .unwrap()
+        .unwrap()
+        .unwrap();
+        assert_eq!(row.try_get::<i32>("", "one").unwrap(), 1);
+    }
+}
@@ -0,0 +1,317 @@
+use crate::{domain::types::UserId, infra::configuration::RateLimitOptions};
+use async_trait::async_trait;
+use std::{
+    collections::HashMap,
+    net::IpAddr,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+/// Above this many buckets, the full ones are dropped: they are the same as new ones. If that's not
+/// enough, the least recently used ones are dropped too, down to half of it, so that the pruning
+/// only runs once in a while.
+const MAX_BUCKETS: usize = 10_000;
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum RateLimitKey {
+    Ip(IpAddr),
+    User(UserId),
+    /// The user ID or email of a password reset request, as typed: it may not exist.
+    PasswordReset(String),
+}
+
+/// Throttles the login attempts. The state is behind a trait so that it can be shared between
+/// several instances (e.g. in Redis) rather than kept in memory.
+#[async_trait]
+pub trait RateLimiter: Send + Sync {
+    /// Counts an attempt for the key. If there are too many, returns how long to wait before the
+    /// next one is allowed.
+    async fn check(&self, key: &RateLimitKey) -> Result<(), Duration>;
+
+    /// Counts a login attempt against both the source address, if known, and the target user.
+    async fn check_login(&self, ip: Option<IpAddr>, user_id: &UserId) -> Result<(), Duration> {
+        if let Some(ip) = ip {
+            self.check(&RateLimitKey::Ip(ip)).await?;
+        }
+        self.check(&RateLimitKey::User(user_id.clone())).await
+    }
+
+    /// Counts a password reset request against both the source address, if known, and the
+    /// identifier. The identifier has its own bucket, so that the resets can't lock out the
+    /// logins of a user.
+    async fn check_password_reset(
+        &self,
+        ip: Option<IpAddr>,
+        identifier: &str,
+    ) -> Result<(), Duration> {
+        if let Some(ip) = ip {
+            self.check(&RateLimitKey::Ip(ip)).await?;
+        }
+        self.check(&RateLimitKey::PasswordReset(identifier.to_lowercase()))
+            .await
+    }
+}
+
+pub type SharedRateLimiter = Arc<dyn RateLimiter>;
+
+/// Allows every attempt.
+pub struct NoRateLimiter;
+
+#[async_trait]
+impl RateLimiter for NoRateLimiter {
+    async fn check(&self, _: &RateLimitKey) -> Result<(), Duration> {
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct BucketConfig {
+    pub burst: u32,
+    pub refill_per_minute: u32,
+}
+
+impl BucketConfig {
+    fn refill_period(&self) -> Duration {
+        Duration::from_secs(60) / self.refill_per_minute.max(1)
+    }
+}
+
+#[derive(Debug)]
+struct Bucket {
+    tokens: f64,
+    /// Also the last use: the buckets are only refilled when checked.
+    last_refill: Instant,
+}
+
+impl Bucket {
+    fn tokens_at(&self, config: BucketConfig, now: Instant) -> f64 {
+        let elapsed = now.saturating_duration_since(self.last_refill);
+        (self.tokens + elapsed.as_secs_f64() / config.refill_period().as_secs_f64())
+            .min(config.burst as f64)
+    }
+}
+
+/// Token buckets, in the memory of this instance.
+pub struct InMemoryRateLimiter {
+    ip_config: BucketConfig,
+    user_config: BucketConfig,
+    buckets: Mutex<HashMap<RateLimitKey, Bucket>>,
+}
+
+impl InMemoryRateLimiter {
+    pub fn new(ip_config: BucketConfig, user_config: BucketConfig) -> Self {
+        Self {
+            ip_config,
+            user_config,
+            buckets: Mutex::new(HashMap::new()),
+        }
+    }
+
+    fn config_for(&self, key: &RateLimitKey) -> BucketConfig {
+        match key {
+            RateLimitKey::Ip(_) => self.ip_config,
+            RateLimitKey::User(_) | RateLimitKey::PasswordReset(_) => self.user_config,
+        }
+    }
+
+    fn prune(&self, buckets: &mut HashMap<RateLimitKey, Bucket>, now: Instant) {
+        buckets.retain(|key, bucket| {
+            let config = self.config_for(key);
+            bucket.tokens_at(config, now) < config.burst as f64
+        });
+        let target = MAX_BUCKETS / 2;
+        if buckets.len() <= target {
+            return;
+        }
+        let mut last_uses = buckets
+            .values()
+            .map(|bucket| bucket.last_refill)
+            .collect::<Vec<_>>();
+        let (_, &mut oldest_kept, _) = last_uses.select_nth_unstable(buckets.len() - target);
+        buckets.retain(|_, bucket| bucket.last_refill >= oldest_kept);
+    }
+
+    fn check_at(&self, key: &RateLimitKey, now: Instant) -> Result<(), Duration> {
+        let config = self.config_for(key);
+        let mut buckets = self.buckets.lock().unwrap();
+        if buckets.len() >= MAX_BUCKETS {
+            self.prune(&mut buckets, now);
+        }
+        let bucket = buckets.entry(key.clone()).or_insert(Bucket {
+            tokens: config.burst as f64,
+            last_refill: now,
+        });
+        bucket.tokens = bucket.tokens_at(config, now);
+        bucket.last_refill = now;
+        if bucket.tokens >= 1.0 {
+            bucket.tokens -= 1.0;
+            Ok(())
+        } else {
+            Err(config.refill_period().mul_f64(1.0 - bucket.tokens))
+        }
+    }
+}
+
+#[async_trait]
+impl RateLimiter for InMemoryRateLimiter {
+    async fn check(&self, key: &RateLimitKey) -> Result<(), Duration> {
+        self.check_at(key, Instant::now())
+    }
+}
+
+/// Rounded up, for the "retry after" messages.
+pub fn retry_after_seconds(retry_after: Duration) -> u64 {
+    retry_after.as_secs() + u64::from(retry_after.subsec_nanos() > 0)
+}
+
+pub fn build_rate_limiter(options: &RateLimitOptions) -> SharedRateLimiter {
+    if !options.enabled {
+        return Arc::new(NoRateLimiter);
+    }
+    Arc::new(InMemoryRateLimiter::new(
+        BucketConfig {
+            burst: options.ip_burst,
+            refill_per_minute: options.ip_attempts_per_minute,
+        },
+        BucketConfig {
+            burst: options.user_burst,
+            refill_per_minute: options.user_attempts_per_minute,
+        },
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn get_limiter() -> InMemoryRateLimiter {
+        InMemoryRateLimiter::new(
+            BucketConfig {
+                burst: 5,
+                refill_per_minute: 60,
+            },
+            BucketConfig {
+                burst: 3,
+                refill_per_minute: 6,
+            },
+        )
+    }
+
+    #[test]
+    fn test_burst_is_throttled() {
+        let limiter = get_limiter();
+        let now = Instant::now();
+        let bob = RateLimitKey::User(UserId::new("bob"));
+        for _ in 0..3 {
+            limiter.check_at(&bob, now).unwrap();
+        }
+        assert_eq!(limiter.check_at(&bob, now), Err(Duration::from_secs(10)));
+        // Other users are not affected.
+        limiter
+            .check_at(&RateLimitKey::User(UserId::new("alice")), now)
+            .unwrap();
+        // One attempt is regained every 10 seconds.
+        assert_eq!(
+            limiter.check_at(&bob, now + Duration::from_secs(4)),
+            Err(Duration::from_secs(6))
+        );
+        limiter
+            .check_at(&bob, now + Duration::from_secs(10))
+            .unwrap();
+        limiter
+            .check_at(&bob, now + Duration::from_secs(10))
+            .unwrap_err();
+    }
+
+    #[test]
+    fn test_pruning() {
+        let limiter = get_limiter();
+        let now = Instant::now();
+        let bob = RateLimitKey::User(UserId::new("bob"));
+        for _ in 0..3 {
+            limiter.check_at(&bob, now).unwrap();
+        }
+        let ip = |i: usize| RateLimitKey::Ip(IpAddr::from((i as u32).to_be_bytes()));
+        for i in 1..MAX_BUCKETS {
+            limiter.check_at(&ip(i), now).unwrap();
+        }
+        // The addresses are full again after a second, and dropped. Bob is refilled at the rate
+        // of the users, not the one of the addresses.
+        let later = now + Duration::from_secs(1);
+        limiter.check_at(&ip(0), later).unwrap();
+        assert_eq!(limiter.buckets.lock().unwrap().len(), 2);
+        limiter.check_at(&bob, later).unwrap_err();
+
+        // Without full buckets, the least recently used ones are dropped.
+        let user = |i: usize| RateLimitKey::User(UserId::new(&format!("user{}", i)));
+        for i in 0..MAX_BUCKETS {
+            limiter
+                .check_at(&user(i), later + Duration::from_micros(i as u64))
+                .unwrap();
+        }
+        let buckets = limiter.buckets.lock().unwrap();
+        // Half of them, and the two users checked after the pruning.
+        assert_eq!(buckets.len(), MAX_BUCKETS / 2 + 2);
+        assert!(!buckets.contains_key(&bob));
+        assert!(!buckets.contains_key(&user(0)));
+        assert!(buckets.contains_key(&user(MAX_BUCKETS - 1)));
+    }
+
+    #[tokio::test]
+    async fn test_check_login_counts_the_ip_and_the_user() {
+        let limiter = get_limiter();
+        let ip: IpAddr = "192.168.1.1".parse().unwrap();
+        // The address runs out first, whatever the user.
+        for i in 0..5 {
+            limiter
+                .check_login(Some(ip), &UserId::new(&format!("user{}", i)))
+                .await
+                .unwrap();
+        }
+        limiter
+            .check_login(Some(ip), &UserId::new("bob"))
+            .await
+            .unwrap_err();
+        // The user is throttled from any address.
+        for _ in 0..3 {
+            limiter
+                .check_login(None, &UserId::new("alice"))
+                .await
+                .unwrap();
+        }
+        limiter
+            .check_login(Some("10.0.0.1".parse().unwrap()), &UserId::new("alice"))
+            .await
+            .unwrap_err();
+    }
+
+    #[tokio::test]
+    async fn test_password_reset_is_separate_from_the_logins() {
+        let limiter = get_limiter();
+        for _ in 0..3 {
+            limiter.check_password_reset(None, "Bob").await.unwrap();
+        }
+        // The identifier is case-insensitive.
+        limiter.check_password_reset(None, "bob").await.unwrap_err();
+        limiter
+            .check_login(None, &UserId::new("bob"))
+            .await
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_disabled() {
+        let limiter = build_rate_limiter(
+            &crate::infra::configuration::RateLimitOptionsBuilder::default()
+                .enabled(false)
+                .build()
+                .unwrap(),
+        );
+        for _ in 0..100 {
+            limiter
+                .check_login(None, &UserId::new("bob"))
+                .await
+                .unwrap();
+        }
+    }
+}
@@ -0,0 +1,1423 @@
+use super::{
+    filter::{normalize_attribute, parse_filter, CompareOperator, ScimFilter},
+    resources::{
+        get_primary_email, Email, ErrorResponse, ListResponse, Meta, PatchRequest, Reference,
+        ScimGroup, ScimUser, ERROR_SCHEMA,
+    },
+};
+use crate::{
+    domain::{
+        error::{DomainError, Result},
+        handler::{
+            AuditActor, BackendHandler, CreateUserRequest, GroupRequestFilter, Page, Pagination,
+            UpdateGroupRequest, UpdateUserRequest, UserRequestFilter,
+        },
+        types::{AuditSource, Group, GroupId, UserColumn, UserId},
+    },
+    infra::{auth_service::check_if_token_is_valid, tcp_server::AppState},
+};
+use actix_web::{
+    http::{header, StatusCode},
+    web, FromRequest, HttpRequest, HttpResponse,
+};
+use actix_web_httpauth::extractors::bearer::BearerAuth;
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use serde_json::Value;
+use std::collections::HashSet;
+use tracing::{debug, instrument};
+
+const SCIM_CONTENT_TYPE: &str = "application/scim+json";
+const DEFAULT_PAGE_SIZE: usize = 100;
+const MAX_PAGE_SIZE: usize = 1000;
+
+/// The errors, with the `scimType` of RFC 7644 (section 3.12) where there is one.
+#[derive(thiserror::Error, Debug)]
+pub enum ScimError {
+    #[error("{0}")]
+    InvalidFilter(String),
+    #[error("{0}")]
+    InvalidPath(String),
+    #[error("{0}")]
+    InvalidSyntax(String),
+    #[error("{0}")]
+    InvalidValue(String),
+    #[error("{0}")]
+    Mutability(String),
+    #[error("{0}")]
+    Uniqueness(String),
+    #[error("Resource not found: {0}")]
+    NotFound(String),
+    #[error("{0}")]
+    Unauthorized(String),
+    #[error("{0}")]
+    Forbidden(String),
+    #[error("{0}")]
+    InternalError(String),
+}
+
+pub type ScimResult<T> = std::result::Result<T, ScimError>;
+
+impl From<DomainError> for ScimError {
+    fn from(error: DomainError) -> Self {
+        match error {
+            DomainError::EntityNotFound(e) => ScimError::NotFound(e),
+            DomainError::ValidationError(e) => ScimError::InvalidValue(e),
+            e => ScimError::InternalError(e.to_string()),
+        }
+    }
+}
+
+impl ScimError {
+    fn status(&self) -> StatusCode {
+        match self {
+            ScimError::InvalidFilter(_)
+            | ScimError::InvalidPath(_)
+            | ScimError::InvalidSyntax(_)
+            | ScimError::InvalidValue(_)
+            | ScimError::Mutability(_) => StatusCode::BAD_REQUEST,
+            ScimError::Uniqueness(_) => StatusCode::CONFLICT,
+            ScimError::NotFound(_) => StatusCode::NOT_FOUND,
+            ScimError::Unauthorized(_) => StatusCode::UNAUTHORIZED,
+            ScimError::Forbidden(_) => StatusCode::FORBIDDEN,
+            ScimError::InternalError(_) => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+
+    fn scim_type(&self) -> Option<&'static str> {
+        match self {
+            ScimError::InvalidFilter(_) => Some("invalidFilter"),
+            ScimError::InvalidPath(_) => Some("invalidPath"),
+            ScimError::InvalidSyntax(_) => Some("invalidSyntax"),
+            ScimError::InvalidValue(_) => Some("invalidValue"),
+            ScimError::Mutability(_) => Some("mutability"),
+            ScimError::Uniqueness(_) => Some("uniqueness"),
+            _ => None,
+        }
+    }
+
+    fn to_response(&self) -> ErrorResponse {
+        ErrorResponse {
+            schemas: vec![ERROR_SCHEMA],
+            status: self.status().as_u16().to_string(),
+            scim_type: self.scim_type(),
+            detail: self.to_string(),
+        }
+    }
+}
+
+fn scim_response(status: StatusCode, body: &impl Serialize) -> HttpResponse {
+    HttpResponse::build(status)
+        .content_type(SCIM_CONTENT_TYPE)
+        .json(body)
+}
+
+fn error_to_scim_response(error: ScimError) -> HttpResponse {
+    scim_response(error.status(), &error.to_response())
+}
+
+fn to_scim_response<T: Serialize>(result: ScimResult<T>) -> HttpResponse {
+    match result {
+        Ok(resource) => scim_response(StatusCode::OK, &resource),
+        Err(e) => error_to_scim_response(e),
+    }
+}
+
+fn to_created_response<T: Serialize>(
+    result: ScimResult<T>,
+    get_meta: impl FnOnce(&T) -> Option<&Meta>,
+) -> HttpResponse {
+    match result {
+        Ok(resource) => {
+            let mut response = HttpResponse::Created();
+            if let Some(meta) = get_meta(&resource) {
+                response.insert_header((header::LOCATION, meta.location.clone()));
+            }
+            response.content_type(SCIM_CONTENT_TYPE).json(&resource)
+        }
+        Err(e) => error_to_scim_response(e),
+    }
+}
+
+fn to_no_content_response(result: ScimResult<()>) -> HttpResponse {
+    match result {
+        Ok(()) => HttpResponse::NoContent().finish(),
+        Err(e) => error_to_scim_response(e),
+    }
+}
+
+fn parse_body<T: DeserializeOwned>(body: &[u8]) -> ScimResult<T> {
+    serde_json::from_slice(body).map_err(|e| ScimError::InvalidSyntax(e.to_string()))
+}
+
+/// Reading requires the read capability, writing requires admin rights. The changes are
+/// attributed to the owner of the token.
+async fn get_backend_handler<Backend>(
+    req: &HttpRequest,
+    data: &AppState<Backend>,
+    write: bool,
+) -> ScimResult<Backend>
+where
+    Backend: BackendHandler,
+{
+    let bearer = BearerAuth::extract(req)
+        .await
+        .map_err(|_| ScimError::Unauthorized("Missing bearer token".to_owned()))?;
+    let validation_result = check_if_token_is_valid(data, bearer.token())
+        .map_err(|e| ScimError::Unauthorized(e.to_string()))?;
+    let allowed = if write {
+        validation_result.is_admin()
+    } else {
+        validation_result.is_admin_or_readonly()
+    };
+    if !allowed {
+        return Err(ScimError::Forbidden(format!(
+            "User {} is not allowed to {} users and groups",
+            validation_result.user,
+            if write { "modify" } else { "read" }
+        )));
+    }
+    let mut handler = data.backend_handler.clone();
+    handler.set_audit_actor(AuditActor {
+        user_id: validation_result.user,
+        source: AuditSource::Scim,
+    });
+    Ok(handler)
+}
+
+fn get_base_url<Backend>(data: &AppState<Backend>) -> String {
+    format!("{}/scim/v2", data.server_url.trim_end_matches('/'))
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ListQuery {
+    pub filter: Option<String>,
+    /// 1-based.
+    pub start_index: Option<usize>,
+    pub count: Option<usize>,
+}
+
+/// SCIM pages start at an offset, the backend pages after a cursor: the items before
+/// `start_index` are skipped by fetching them as a first page.
+async fn get_page<T, Fetch, Fut>(start_index: usize, count: usize, fetch: Fetch) -> Result<Vec<T>>
+where
+    Fetch: Fn(Pagination) -> Fut,
+    Fut: std::future::Future<Output = Result<Page<T>>>,
+{
+    if count == 0 {
+        return Ok(Vec::new());
+    }
+    let after = if start_index > 1 {
+        let skipped = fetch(Pagination {
+            after: None,
+            page_size: start_index - 1,
+        })
+        .await?;
+        match skipped.next_cursor {
+            Some(cursor) => Some(cursor),
+            None => return Ok(Vec::new()),
+        }
+    } else {
+        None
+    };
+    Ok(fetch(Pagination {
+        after,
+        page_size: count,
+    })
+    .await?
+    .items)
+}
+
+fn get_filter_value(value: Value) -> ScimResult<String> {
+    match value {
+        Value::String(s) => Ok(s),
+        Value::Number(n) => Ok(n.to_string()),
+        value => Err(ScimError::InvalidFilter(format!(
+            "Unsupported value: {}",
+            value
+        ))),
+    }
+}
+
+fn unsupported_operator() -> ScimError {
+    ScimError::InvalidFilter("Only the eq and ne operators are supported".to_owned())
+}
+
+fn get_user_filter(filter: ScimFilter) -> ScimResult<UserRequestFilter> {
+    Ok(match filter {
+        ScimFilter::And(f1, f2) => {
+            UserRequestFilter::And(vec![get_user_filter(*f1)?, get_user_filter(*f2)?])
+        }
+        ScimFilter::Or(f1, f2) => {
+            UserRequestFilter::Or(vec![get_user_filter(*f1)?, get_user_filter(*f2)?])
+        }
+        ScimFilter::Not(f) => UserRequestFilter::Not(Box::new(get_user_filter(*f)?)),
+        ScimFilter::Compare(attribute, CompareOperator::Ne, value) => {
+            UserRequestFilter::Not(Box::new(get_user_filter(ScimFilter::Compare(
+                attribute,
+                CompareOperator::Eq,
+                value,
+            ))?))
+        }
+        ScimFilter::Compare(attribute, CompareOperator::Eq, value) => {
+            let value = get_filter_value(value)?;
+            match attribute.as_str() {
+                "id" | "username" => UserRequestFilter::UserId(UserId::new(&value)),
+                "emails" | "emails.value" => UserRequestFilter::Equality(UserColumn::Email, value),
+                "displayname" => UserRequestFilter::Equality(UserColumn::DisplayName, value),
+                "name.givenname" => UserRequestFilter::Equality(UserColumn::FirstName, value),
+                "name.familyname" => UserRequestFilter::Equality(UserColumn::LastName, value),
+                "groups" | "groups.value" => match value.parse() {
+                    Ok(group_id) => UserRequestFilter::MemberOfId(GroupId(group_id)),
+                    // Not a group ID, it cannot match any user.
+                    Err(_) => UserRequestFilter::Or(vec![]),
+                },
+                "groups.display" => UserRequestFilter::MemberOf(value),
+                _ => {
+                    return Err(ScimError::InvalidFilter(format!(
+                        "Unsupported attribute: {}",
+                        attribute
+                    )))
+                }
+            }
+        }
+        _ => return Err(unsupported_operator()),
+    })
+}
+
+fn get_group_filter(filter: ScimFilter) -> ScimResult<GroupRequestFilter> {
+    Ok(match filter {
+        ScimFilter::And(f1, f2) => {
+            GroupRequestFilter::And(vec![get_group_filter(*f1)?, get_group_filter(*f2)?])
+        }
+        ScimFilter::Or(f1, f2) => {
+            GroupRequestFilter::Or(vec![get_group_filter(*f1)?, get_group_filter(*f2)?])
+        }
+        ScimFilter::Not(f) => GroupRequestFilter::Not(Box::new(get_group_filter(*f)?)),
+        ScimFilter::Compare(attribute, CompareOperator::Ne, value) => {
+            GroupRequestFilter::Not(Box::new(get_group_filter(ScimFilter::Compare(
+                attribute,
+                CompareOperator::Eq,
+                value,
+            ))?))
+        }
+        ScimFilter::Compare(attribute, CompareOperator::Eq, value) => {
+            let value = get_filter_value(value)?;
+            match attribute.as_str() {
+                "id" => match value.parse() {
+                    Ok(group_id) => GroupRequestFilter::GroupId(GroupId(group_id)),
+                    Err(_) => GroupRequestFilter::Or(vec![]),
+                },
+                "displayname" => GroupRequestFilter::DisplayName(value),
+                "members" | "members.value" => GroupRequestFilter::Member(UserId::new(&value)),
+                _ => {
+                    return Err(ScimError::InvalidFilter(format!(
+                        "Unsupported attribute: {}",
+                        attribute
+                    )))
+                }
+            }
+        }
+        _ => return Err(unsupported_operator()),
+    })
+}
+
+fn parse_list_filter<T>(
+    query: &ListQuery,
+    convert: impl FnOnce(ScimFilter) -> ScimResult<T>,
+) -> ScimResult<Option<T>> {
+    query
+        .filter
+        .as_deref()
+        .map(|filter| convert(parse_filter(filter).map_err(ScimError::InvalidFilter)?))
+        .transpose()
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum PatchOp {
+    Add,
+    Replace,
+    Remove,
+}
+
+fn get_patch_op(op: &str) -> ScimResult<PatchOp> {
+    match op.to_ascii_lowercase().as_str() {
+        "add" => Ok(PatchOp::Add),
+        "replace" => Ok(PatchOp::Replace),
+        "remove" => Ok(PatchOp::Remove),
+        _ => Err(ScimError::InvalidSyntax(format!(
+            "Unknown operation: {}",
+            op
+        ))),
+    }
+}
+
+/// The targets of a patch without path: an object mapping the paths to their values.
+fn get_patch_attributes(value: Option<Value>) -> ScimResult<serde_json::Map<String, Value>> {
+    match value {
+        Some(Value::Object(attributes)) => Ok(attributes),
+        _ => Err(ScimError::InvalidValue(
+            "An operation without path needs an object value".to_owned(),
+        )),
+    }
+}
+
+/// A missing value clears the attribute.
+fn get_string(value: Value) -> ScimResult<String> {
+    match value {
+        Value::String(s) => Ok(s),
+        Value::Null => Ok(String::new()),
+        value => Err(ScimError::InvalidValue(format!(
+            "Expected a string, got {}",
+            value
+        ))),
+    }
+}
+
+fn check_active(value: &Value) -> ScimResult<()> {
+    match value {
+        Value::Bool(true) => Ok(()),
+        Value::String(s) if s.eq_ignore_ascii_case("true") => Ok(()),
+        _ => Err(ScimError::InvalidValue(
+            "Users cannot be deactivated, delete them instead".to_owned(),
+        )),
+    }
+}
+
+fn set_user_attribute(request: &mut UpdateUserRequest, path: &str, value: Value) -> ScimResult<()> {
+    let path = normalize_attribute(path);
+    // Users have a single email, whatever the filter, e.g. `emails[type eq "work"].value`.
+    let path = match path.split_once('[') {
+        Some(("emails", filter)) if filter.ends_with("].value") => "emails.value".to_owned(),
+        Some(("emails", filter)) if filter.ends_with(']') => "emails".to_owned(),
+        _ => path,
+    };
+    match path.as_str() {
+        "displayname" => request.display_name = Some(get_string(value)?),
+        "name.givenname" => request.first_name = Some(get_string(value)?),
+        "name.familyname" => request.last_name = Some(get_string(value)?),
+        "name" => match value {
+            Value::Object(name) => {
+                for (attribute, value) in name {
+                    set_user_attribute(request, &format!("name.{}", attribute), value)?;
+                }
+            }
+            Value::Null => {
+                request.first_name = Some(String::new());
+                request.last_name = Some(String::new());
+            }
+            value => {
+                return Err(ScimError::InvalidValue(format!(
+                    "Expected a name, got {}",
+                    value
+                )))
+            }
+        },
+        "emails" => {
+            let emails = match value {
+                Value::Null => Vec::new(),
+                value => serde_json::from_value::<Vec<Email>>(value)
+                    .map_err(|e| ScimError::InvalidValue(e.to_string()))?,
+            };
+            request.email = Some(get_primary_email(&emails).unwrap_or_default().to_owned());
+        }
+        "emails.value" => request.email = Some(get_string(value)?),
+        "active" => check_active(&value)?,
+        "username" => {
+            return Err(ScimError::Mutability(
+                "The userName cannot be changed".to_owned(),
+            ))
+        }
+        // Not stored.
+        "externalid" => {}
+        _ => {
+            return Err(ScimError::InvalidPath(format!(
+                "Unsupported attribute: {}",
+                path
+            )))
+        }
+    }
+    Ok(())
+}
+
+#[instrument(skip_all, level = "debug", err)]
+async fn list_users<Handler: BackendHandler>(
+    handler: &Handler,
+    query: ListQuery,
+    base_url: &str,
+) -> ScimResult<ListResponse<ScimUser>> {
+    debug!(?query);
+    let filter = parse_list_filter(&query, get_user_filter)?;
+    let start_index = query.start_index.unwrap_or(1).max(1);
+    let count = query.count.unwrap_or(DEFAULT_PAGE_SIZE).min(MAX_PAGE_SIZE);
+    let total_results = handler.count_users(filter.clone()).await?;
+    let users = get_page(start_index, count, |page| {
+        handler.list_users_page(filter.clone(), true, page)
+    })
+    .await?;
+    Ok(ListResponse::new(
+        users
+            .into_iter()
+            .map(|u| ScimUser::new(u.user, u.groups.unwrap_or_default(), base_url))
+            .collect(),
+        total_results,
+        start_index,
+    ))
+}
+
+async fn get_user<Handler: BackendHandler>(
+    handler: &Handler,
+    user_id: &UserId,
+    base_url: &str,
+) -> ScimResult<ScimUser> {
+    let user = handler.get_user_details(user_id).await?;
+    let mut groups: Vec<_> = handler
+        .get_user_groups(user_id)
+        .await?
+        .into_iter()
+        .collect();
+    groups.sort_by_key(|g| g.group_id);
+    Ok(ScimUser::new(user, groups, base_url))
+}
+
+#[instrument(skip_all, level = "debug", err)]
+async fn create_user<Handler: BackendHandler>(
+    handler: &Handler,
+    user: ScimUser,
+    base_url: &str,
+) -> ScimResult<ScimUser> {
+    debug!(?user);
+    if user.user_name.is_empty() {
+        return Err(ScimError::InvalidValue(
+            "The userName is required".to_owned(),
+        ));
+    }
+    check_active(&Value::Bool(user.active))?;
+    let user_id = UserId::new(&user.user_name);
+    match handler.get_user_details(&user_id).await {
+        Ok(_) => {
+            return Err(ScimError::Uniqueness(format!(
+                "User {} already exists",
+                user_id
+            )))
+        }
+        Err(DomainError::EntityNotFound(_)) => {}
+        Err(e) => return Err(e.into()),
+    }
+    handler
+        .create_user(CreateUserRequest {
+            user_id: user_id.clone(),
+            email: get_primary_email(&user.emails)
+                .unwrap_or_default()
+                .to_owned(),
+            display_name: user.display_name,
+            first_name: user.name.given_name,
+            last_name: user.name.family_name,
+            ..Default::default()
+        })
+        .await?;
+    get_user(handler, &user_id, base_url).await
+}
+
+#[instrument(skip_all, level = "debug", err)]
+async fn replace_user<Handler: BackendHandler>(
+    handler: &Handler,
+    user_id: &UserId,
+    user: ScimUser,
+    base_url: &str,
+) -> ScimResult<ScimUser> {
+    debug!(?user_id, ?user);
+    handler.get_user_details(user_id).await?;
+    if &UserId::new(&user.user_name) != user_id {
+        return Err(ScimError::Mutability(
+            "The userName cannot be changed".to_owned(),
+        ));
+    }
+    check_active(&Value::Bool(user.active))?;
+    // The attributes missing from the request are cleared.
+    handler
+        .update_user(UpdateUserRequest {
+            user_id: user_id.clone(),
+            email: Some(
+                get_primary_email(&user.emails)
+                    .unwrap_or_default()
+                    .to_owned(),
+            ),
+            display_name: Some(user.display_name.unwrap_or_default()),
+            first_name: Some(user.name.given_name.unwrap_or_default()),
+            last_name: Some(user.name.family_name.unwrap_or_default()),
+            ..Default::default()
+        })
+        .await?;
+    get_user(handler, user_id, base_url).await
+}
+
+#[instrument(skip_all, level = "debug", err)]
+async fn patch_user<Handler: BackendHandler>(
+    handler: &Handler,
+    user_id: &UserId,
+    patch: PatchRequest,
+    base_url: &str,
+) -> ScimResult<ScimUser> {
+    debug!(?user_id, ?patch);
+    handler.get_user_details(user_id).await?;
+    let mut request = UpdateUserRequest {
+        user_id: user_id.clone(),
+        ..Default::default()
+    };
+    for operation in patch.operations {
+        match (get_patch_op(&operation.op)?, operation.path) {
+            (PatchOp::Remove, Some(path)) => set_user_attribute(&mut request, &path, Value::Null)?,
+            (_, Some(path)) => {
+                set_user_attribute(&mut request, &path, operation.value.unwrap_or(Value::Null))?
+            }
+            (PatchOp::Remove, None) => {
+                return Err(ScimError::InvalidPath(
+                    "A remove operation needs a path".to_owned(),
+                ))
+            }
+            (_, None) => {
+                for (path, value) in get_patch_attributes(operation.value)? {
+                    set_user_attribute(&mut request, &path, value)?;
+                }
+            }
+        }
+    }
+    handler.update_user(request).await?;
+    get_user(handler, user_id, base_url).await
+}
+
+fn parse_group_id(group_id: &str) -> ScimResult<GroupId> {
+    group_id
+        .parse()
+        .map(GroupId)
+        .map_err(|_| ScimError::NotFound(format!("Group {}", group_id)))
+}
+
+async fn find_group<Handler: BackendHandler>(
+    handler: &Handler,
+    group_id: GroupId,
+) -> ScimResult<Group> {
+    handler
+        .list_groups(Some(GroupRequestFilter::GroupId(group_id)))
+        .await?
+        .into_iter()
+        .next()
+        .ok_or_else(|| ScimError::NotFound(format!("Group {}", group_id.0)))
+}
+
+async fn get_group<Handler: BackendHandler>(
+    handler: &Handler,
+    group_id: GroupId,
+    base_url: &str,
+) -> ScimResult<ScimGroup> {
+    Ok(ScimGroup::new(
+        find_group(handler, group_id).await?,
+        base_url,
+    ))
+}
+
+async fn check_group_name_is_free<Handler: BackendHandler>(
+    handler: &Handler,
+    display_name: &str,
+) -> ScimResult<()> {
+    if display_name.is_empty() {
+        return Err(ScimError::InvalidValue(
+            "The displayName is required".to_owned(),
+        ));
+    }
+    if !handler
+        .list_groups(Some(GroupRequestFilter::DisplayName(
+            display_name.to_owned(),
+        )))
+        .await?
+        .is_empty()
+    {
+        return Err(ScimError::Uniqueness(format!(
+            "Group {} already exists",
+            display_name
+        )));
+    }
+    Ok(())
+}
+
+/// Unlike the members returned when reading the group, these don't include the members of the
+/// subgroups.
+async fn get_direct_members<Handler: BackendHandler>(
+    handler: &Handler,
+    group_id: GroupId,
+) -> ScimResult<HashSet<UserId>> {
+    Ok(handler
+        .list_users(Some(UserRequestFilter::MemberOfId(group_id)), false)
+        .await?
+        .into_iter()
+        .map(|u| u.user.user_id)
+        .collect())
+}
+
+fn get_member_ids(members: Vec<Reference>) -> HashSet<UserId> {
+    members.into_iter().map(|m| UserId::new(&m.value)).collect()
+}
+
+/// Accepts a list of members, or a single one.
+fn parse_members(value: Value) -> ScimResult<HashSet<UserId>> {
+    let members =
+        match value {
+            Value::Null => Vec::new(),
+            Value::Array(_) => {
+                serde_json::from_value(value).map_err(|e| ScimError::InvalidValue(e.to_string()))?
+            }
+            value => vec![serde_json::from_value(value)
+                .map_err(|e| ScimError::InvalidValue(e.to_string()))?],
+        };
+    Ok(get_member_ids(members))
+}
+
+/// Adds and removes the memberships to go from the `current` direct members to `members`.
+async fn update_members<Handler: BackendHandler>(
+    handler: &Handler,
+    group_id: GroupId,
+    current: &HashSet<UserId>,
+    members: &HashSet<UserId>,
+) -> ScimResult<()> {
+    for user_id in members.difference(current) {
+        if let Err(e) = handler.get_user_details(user_id).await {
+            return Err(match e {
+                DomainError::EntityNotFound(_) => {
+                    ScimError::InvalidValue(format!("Unknown member: {}", user_id))
+                }
+                e => e.into(),
+            });
+        }
+        handler.add_user_to_group(user_id, group_id).await?;
+    }
+    for user_id in current.difference(members) {
+        handler.remove_user_from_group(user_id, group_id).await?;
+    }
+    Ok(())
+}
+
+/// Applies an operation to the display name or the direct members of a group.
+fn apply_group_change(
+    op: PatchOp,
+    path: &str,
+    value: Value,
+    display_name: &mut String,
+    members: &mut HashSet<UserId>,
+) -> ScimResult<()> {
+    // `members[value eq "bob"]` designates a single member.
+    if let Some(filter) = path
+        .get(..8)
+        .filter(|prefix| prefix.eq_ignore_ascii_case("members["))
+        .and_then(|_| path[8..].strip_suffix(']'))
+    {
+        if op != PatchOp::Remove {
+            return Err(ScimError::InvalidPath(
+                "Only members can be removed by filter".to_owned(),
+            ));
+        }
+        match parse_filter(filter).map_err(ScimError::InvalidFilter)? {
+            ScimFilter::Compare(attribute, CompareOperator::Eq, Value::String(user_id))
+                if attribute == "value" =>
+            {
+                members.remove(&UserId::new(&user_id));
+            }
+            _ => {
+                return Err(ScimError::InvalidFilter(
+                    "Members can only be filtered by value".to_owned(),
+                ))
+            }
+        }
+        return Ok(());
+    }
+    match (normalize_attribute(path).as_str(), op) {
+        ("displayname", PatchOp::Remove) => {
+            return Err(ScimError::Mutability(
+                "The displayName is required".to_owned(),
+            ))
+        }
+        ("displayname", _) => *display_name = get_string(value)?,
+        ("members", PatchOp::Add) => members.extend(parse_members(value)?),
+        ("members", PatchOp::Replace) => *members = parse_members(value)?,
+        // Without value, all the members are removed.
+        ("members", PatchOp::Remove) if value.is_null() => members.clear(),
+        ("members", PatchOp::Remove) => {
+            for user_id in parse_members(value)? {
+                members.remove(&user_id);
+            }
+        }
+        ("externalid", _) => {}
+        (path, _) => {
+            return Err(ScimError::InvalidPath(format!(
+                "Unsupported attribute: {}",
+                path
+            )))
+        }
+    }
+    Ok(())
+}
+
+/// Renames the group if needed, then updates its direct members.
+async fn update_group<Handler: BackendHandler>(
+    handler: &Handler,
+    group: Group,
+    display_name: String,
+    current_members: &HashSet<UserId>,
+    members: &HashSet<UserId>,
+) -> ScimResult<()> {
+    if display_name != group.display_name {
+        check_group_name_is_free(handler, &display_name).await?;
+        handler
+            .update_group(UpdateGroupRequest {
+                group_id: group.id,
+                display_name: Some(display_name),
+            })
+            .await?;
+    }
+    update_members(handler, group.id, current_members, members).await
+}
+
+#[instrument(skip_all, level = "debug", err)]
+async fn list_groups<Handler: BackendHandler>(
+    handler: &Handler,
+    query: ListQuery,
+    base_url: &str,
+) -> ScimResult<ListResponse<ScimGroup>> {
+    debug!(?query);
+    let filter = parse_list_filter(&query, get_group_filter)?;
+    let start_index = query.start_index.unwrap_or(1).max(1);
+    let count = query.count.unwrap_or(DEFAULT_PAGE_SIZE).min(MAX_PAGE_SIZE);
+    let total_results = handler.count_groups(filter.clone()).await?;
+    let groups = get_page(start_index, count, |page| {
+        handler.list_groups_page(filter.clone(), page)
+    })
+    .await?;
+    Ok(ListResponse::new(
+        groups
+            .into_iter()
+            .map(|g| ScimGroup::new(g, base_url))
+            .collect(),
+        total_results,
+        start_index,
+    ))
+}
+
+#[instrument(skip_all, level = "debug", err)]
+async fn create_group<Handler: BackendHandler>(
+    handler: &Handler,
+    group: ScimGroup,
+    base_url: &str,
+) -> ScimResult<ScimGroup> {
+    debug!(?group);
+    check_group_name_is_free(handler, &group.display_name).await?;
+    let group_id = handler.create_group(&group.display_name).await?;
+    update_members(
+        handler,
+        group_id,
+        &HashSet::new(),
+        &get_member_ids(group.members),
+    )
+    .await?;
+    get_group(handler, group_id, base_url).await
+}
+
+#[instrument(skip_all, level = "debug", err)]
+async fn replace_group<Handler: BackendHandler>(
+    handler: &Handler,
+    group_id: GroupId,
+    group: ScimGroup,
+    base_url: &str,
+) -> ScimResult<ScimGroup> {
+    debug!(?group_id, ?group);
+    let current = find_group(handler, group_id).await?;
+    let current_members = get_direct_members(handler, group_id).await?;
+    update_group(
+        handler,
+        current,
+        group.display_name,
+        &current_members,
+        &get_member_ids(group.members),
+    )
+    .await?;
+    get_group(handler, group_id, base_url).await
+}
+
+#[instrument(skip_all, level = "debug", err)]
+async fn patch_group<Handler: BackendHandler>(
+    handler: &Handler,
+    group_id: GroupId,
+    patch: PatchRequest,
+    base_url: &str,
+) -> ScimResult<ScimGroup> {
+    debug!(?group_id, ?patch);
+    let current = find_group(handler, group_id).await?;
+    let current_members = get_direct_members(handler, group_id).await?;
+    let mut display_name = current.display_name.clone();
+    let mut members = current_members.clone();
+    for operation in patch.operations {
+        let op = get_patch_op(&operation.op)?;
+        match operation.path {
+            Some(path) => apply_group_change(
+                op,
+                &path,
+                operation.value.unwrap_or(Value::Null),
+                &mut display_name,
+                &mut members,
+            )?,
+            None if op == PatchOp::Remove => {
+                return Err(ScimError::InvalidPath(
+                    "A remove operation needs a path".to_owned(),
+                ))
+            }
+            None => {
+                for (path, value) in get_patch_attributes(operation.value)? {
+                    apply_group_change(op, &path, value, &mut display_name, &mut members)?;
+                }
+            }
+        }
+    }
+    update_group(handler, current, display_name, &current_members, &members).await?;
+    get_group(handler, group_id, base_url).await
+}
+
+async fn list_users_handler<Backend>(
+    req: HttpRequest,
+    data: web::Data<AppState<Backend>>,
+) -> HttpResponse
+where
+    Backend: BackendHandler + Sync + 'static,
+{
+    to_scim_response(
+        async {
+            let handler = get_backend_handler(&req, &data, false).await?;
+            let query = web::Query::<ListQuery>::from_query(req.query_string())
+                .map_err(|e| ScimError::InvalidValue(e.to_string()))?;
+            list_users(&handler, query.into_inner(), &get_base_url(&data)).await
+        }
+        .await,
+    )
+}
+
+async fn get_user_handler<Backend>(
+    req: HttpRequest,
+    data: web::Data<AppState<Backend>>,
+    user_id: web::Path<String>,
+) -> HttpResponse
+where
+    Backend: BackendHandler + Sync + 'static,
+{
+    to_scim_response(
+        async {
+            let handler = get_backend_handler(&req, &data, false).await?;
+            get_user(&handler, &UserId::new(&user_id), &get_base_url(&data)).await
+        }
+        .await,
+    )
+}
+
+async fn create_user_handler<Backend>(
+    req: HttpRequest,
+    data: web::Data<AppState<Backend>>,
+    body: web::Bytes,
+) -> HttpResponse
+where
+    Backend: BackendHandler + Sync + 'static,
+{
+    to_created_response(
+        async {
+            let handler = get_backend_handler(&req, &data, true).await?;
+            create_user(&handler, parse_body(&body)?, &get_base_url(&data)).await
+        }
+        .await,
+        |user| user.meta.as_ref(),
+    )
+}
+
+async fn replace_user_handler<Backend>(
+    req: HttpRequest,
+    data: web::Data<AppState<Backend>>,
+    user_id: web::Path<String>,
+    body: web::Bytes,
+) -> HttpResponse
+where
+    Backend: BackendHandler + Sync + 'static,
+{
+    to_scim_response(
+        async {
+            let handler = get_backend_handler(&req, &data, true).await?;
+            replace_user(
+                &handler,
+                &UserId::new(&user_id),
+                parse_body(&body)?,
+                &get_base_url(&data),
+            )
+            .await
+        }
+        .await,
+    )
+}
+
+async fn patch_user_handler<Backend>(
+    req: HttpRequest,
+    data: web::Data<AppState<Backend>>,
+    user_id: web::Path<String>,
+    body: web::Bytes,
+) -> HttpResponse
+where
+    Backend: BackendHandler + Sync + 'static,
+{
+    to_scim_response(
+        async {
+            let handler = get_backend_handler(&req, &data, true).await?;
+            patch_user(
+                &handler,
+                &UserId::new(&user_id),
+                parse_body(&body)?,
+                &get_base_url(&data),
+            )
+            .await
+        }
+        .await,
+    )
+}
+
+async fn delete_user_handler<Backend>(
+    req: HttpRequest,
+    data: web::Data<AppState<Backend>>,
+    user_id: web::Path<String>,
+) -> HttpResponse
+where
+    Backend: BackendHandler + Sync + 'static,
+{
+    to_no_content_response(
+        async {
+            let handler = get_backend_handler(&req, &data, true).await?;
+            Ok(handler.delete_user(&UserId::new(&user_id)).await?)
+        }
+        .await,
+    )
+}
+
+async fn list_groups_handler<Backend>(
+    req: HttpRequest,
+    data: web::Data<AppState<Backend>>,
+) -> HttpResponse
+where
+    Backend: BackendHandler + Sync + 'static,
+{
+    to_scim_response(
+        async {
+            let handler = get_backend_handler(&req, &data, false).await?;
+            let query = web::Query::<ListQuery>::from_query(req.query_string())
+                .map_err(|e| ScimError::InvalidValue(e.to_string()))?;
+            list_groups(&handler, query.into_inner(), &get_base_url(&data)).await
+        }
+        .await,
+    )
+}
+
+async fn get_group_handler<Backend>(
+    req: HttpRequest,
+    data: web::Data<AppState<Backend>>,
+    group_id: web::Path<String>,
+) -> HttpResponse
+where
+    Backend: BackendHandler + Sync + 'static,
+{
+    to_scim_response(
+        async {
+            let handler = get_backend_handler(&req, &data, false).await?;
+            get_group(&handler, parse_group_id(&group_id)?, &get_base_url(&data)).await
+        }
+        .await,
+    )
+}
+
+async fn create_group_handler<Backend>(
+    req: HttpRequest,
+    data: web::Data<AppState<Backend>>,
+    body: web::Bytes,
+) -> HttpResponse
+where
+    Backend: BackendHandler + Sync + 'static,
+{
+    to_created_response(
+        async {
+            let handler = get_backend_handler(&req, &data, true).await?;
+            create_group(&handler, parse_body(&body)?, &get_base_url(&data)).await
+        }
+        .await,
+        |group| group.meta.as_ref(),
+    )
+}
+
+async fn replace_group_handler<Backend>(
+    req: HttpRequest,
+    data: web::Data<AppState<Backend>>,
+    group_id: web::Path<String>,
+    body: web::Bytes,
+) -> HttpResponse
+where
+    Backend: BackendHandler + Sync + 'static,
+{
+    to_scim_response(
+        async {
+            let handler = get_backend_handler(&req, &data, true).await?;
+            replace_group(
+                &handler,
+                parse_group_id(&group_id)?,
+                parse_body(&body)?,
+                &get_base_url(&data),
+            )
+            .await
+        }
+        .await,
+    )
+}
+
+async fn patch_group_handler<Backend>(
+    req: HttpRequest,
+    data: web::Data<AppState<Backend>>,
+    group_id: web::Path<String>,
+    body: web::Bytes,
+) -> HttpResponse
+where
+    Backend: BackendHandler + Sync + 'static,
+{
+    to_scim_response(
+        async {
+            let handler = get_backend_handler(&req, &data, true).await?;
+            patch_group(
+                &handler,
+                parse_group_id(&group_id)?,
+                parse_body(&body)?,
+                &get_base_url(&data),
+            )
+            .await
+        }
+        .await,
+    )
+}
+
+async fn delete_group_handler<Backend>(
+    req: HttpRequest,
+    data: web::Data<AppState<Backend>>,
+    group_id: web::Path<String>,
+) -> HttpResponse
+where
+    Backend: BackendHandler + Sync + 'static,
+{
+    to_no_content_response(
+        async {
+            let handler = get_backend_handler(&req, &data, true).await?;
+            Ok(handler.delete_group(parse_group_id(&group_id)?).await?)
+        }
+        .await,
+    )
+}
+
+pub fn configure_endpoint<Backend>(cfg: &mut web::ServiceConfig)
+where
+    Backend: BackendHandler + Sync + 'static,
+{
+    cfg.service(
+        web::resource("/Users")
+            .route(web::get().to(list_users_handler::<Backend>))
+            .route(web::post().to(create_user_handler::<Backend>)),
+    )
+    .service(
+        web::resource("/Users/{user_id}")
+            .route(web::get().to(get_user_handler::<Backend>))
+            .route(web::put().to(replace_user_handler::<Backend>))
+            .route(web::patch().to(patch_user_handler::<Backend>))
+            .route(web::delete().to(delete_user_handler::<Backend>)),
+    )
+    .service(
+        web::resource("/Groups")
+            .route(web::get().to(list_groups_handler::<Backend>))
+            .route(web::post().to(create_group_handler::<Backend>)),
+    )
+    .service(
+        web::resource("/Groups/{group_id}")
+            .route(web::get().to(get_group_handler::<Backend>))
+            .route(web::put().to(replace_group_handler::<Backend>))
+            .route(web::patch().to(patch_group_handler::<Backend>))
+            .route(web::delete().to(delete_group_handler::<Backend>)),
+    );
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::domain::{
+        handler::MockTestBackendHandler,
+        types::{User, UserAndGroups, Uuid},
+    };
+    use mockall::predicate::eq;
+
+    const BASE_URL: &str = "http://localhost/scim/v2";
+
+    fn make_user(user_id: &str) -> User {
+        User {
+            user_id: UserId::new(user_id),
+            email: format!("{}@bob.bob", user_id),
+            ..Default::default()
+        }
+    }
+
+    fn make_user_and_groups(user_id: &str) -> UserAndGroups {
+        UserAndGroups {
+            user: make_user(user_id),
+            groups: None,
+        }
+    }
+
+    fn make_patch(operations: Value) -> PatchRequest {
+        serde_json::from_value(serde_json::json!({
+            "schemas": ["urn:ietf:params:scim:api:messages:2.0:PatchOp"],
+            "Operations": operations,
+        }))
+        .unwrap()
+    }
+
+    #[test]
+    fn test_user_filter() {
+        let get_filter = |filter: &str| {
+            parse_filter(filter)
+                .map_err(ScimError::InvalidFilter)
+                .and_then(get_user_filter)
+        };
+        assert_eq!(
+            get_filter(r#"userName eq "Bob" and emails.value ne "b@b.b""#).unwrap(),
+            UserRequestFilter::And(vec![
+                UserRequestFilter::UserId(UserId::new("bob")),
+                UserRequestFilter::Not(Box::new(UserRequestFilter::Equality(
+                    UserColumn::Email,
+                    "b@b.b".to_owned()
+                ))),
+            ])
+        );
+        assert_eq!(
+            get_filter(r#"groups.value eq "3""#).unwrap(),
+            UserRequestFilter::MemberOfId(GroupId(3))
+        );
+        assert!(matches!(
+            get_filter(r#"userName co "b""#),
+            Err(ScimError::InvalidFilter(_))
+        ));
+        assert!(matches!(
+            get_filter(r#"title eq "b""#),
+            Err(ScimError::InvalidFilter(_))
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_list_users_pagination() {
+        let mut mock = MockTestBackendHandler::new();
+        mock.expect_count_users()
+            .with(eq(None))
+            .times(1)
+            .return_once(|_| Ok(5));
+        // The first two users are skipped.
+        mock.expect_list_users_page()
+            .with(
+                eq(None),
+                eq(true),
+                eq(Pagination {
+                    after: None,
+                    page_size: 2,
+                }),
+            )
+            .times(1)
+            .return_once(|_, _, _| {
+                Ok(Page {
+                    items: vec![make_user_and_groups("a"), make_user_and_groups("b")],
+                    next_cursor: Some("cursor".to_owned()),
+                })
+            });
+        mock.expect_list_users_page()
+            .with(
+                eq(None),
+                eq(true),
+                eq(Pagination {
+                    after: Some("cursor".to_owned()),
+                    page_size: 2,
+                }),
+            )
+            .times(1)
+            .return_once(|_, _, _| {
+                Ok(Page {
+                    items: vec![make_user_and_groups("c"), make_user_and_groups("d")],
+                    next_cursor: Some("cursor2".to_owned()),
+                })
+            });
+        let response = list_users(
+            &mock,
+            ListQuery {
+                filter: None,
+                start_index: Some(3),
+                count: Some(2),
+            },
+            BASE_URL,
+        )
+        .await
+        .unwrap();
+        assert_eq!(response.total_results, 5);
+        assert_eq!(response.start_index, 3);
+        assert_eq!(response.items_per_page, 2);
+        assert_eq!(
+            response
+                .resources
+                .iter()
+                .map(|u| u.user_name.as_str())
+                .collect::<Vec<_>>(),
+            vec!["c", "d"]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_create_existing_user() {
+        let mut mock = MockTestBackendHandler::new();
+        mock.expect_get_user_details()
+            .with(eq(UserId::new("bob")))
+            .times(1)
+            .return_once(|_| Ok(make_user("bob")));
+        let user = serde_json::from_value(serde_json::json!({"userName": "Bob"})).unwrap();
+        let error = create_user(&mock, user, BASE_URL).await.unwrap_err();
+        assert_eq!(error.status(), StatusCode::CONFLICT);
+        assert_eq!(
+            serde_json::to_value(error.to_response()).unwrap(),
+            serde_json::json!({
+                "schemas": [ERROR_SCHEMA],
+                "status": "409",
+                "scimType": "uniqueness",
+                "detail": "User bob already exists",
+            })
+        );
+    }
+
+    #[tokio::test]
+    async fn test_patch_user() {
+        let mut mock = MockTestBackendHandler::new();
+        mock.expect_get_user_details()
+            .with(eq(UserId::new("bob")))
+            .times(2)
+            .returning(|_| Ok(make_user("bob")));
+        mock.expect_update_user()
+            .with(eq(UpdateUserRequest {
+                user_id: UserId::new("bob"),
+                email: Some("new@bob.bob".to_owned()),
+                display_name: Some(String::new()),
+                first_name: Some("Robert".to_owned()),
+                ..Default::default()
+            }))
+            .times(1)
+            .return_once(|_| Ok(()));
+        mock.expect_get_user_groups()
+            .times(1)
+            .return_once(|_| Ok(HashSet::new()));
+        let patch = make_patch(serde_json::json!([
+            {
+                "op": "Replace",
+                "path": "emails[type eq \"work\"].value",
+                "value": "new@bob.bob",
+            },
+            {"op": "replace", "value": {"name.givenName": "Robert", "active": "True"}},
+            {"op": "remove", "path": "displayName"},
+        ]));
+        patch_user(&mock, &UserId::new("bob"), patch, BASE_URL)
+            .await
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_patch_user_invalid() {
+        let mut mock = MockTestBackendHandler::new();
+        mock.expect_get_user_details()
+            .returning(|_| Ok(make_user("bob")));
+        for (operations, expected_status) in [
+            (
+                serde_json::json!([{"op": "replace", "path": "userName", "value": "alice"}]),
+                StatusCode::BAD_REQUEST,
+            ),
+            (
+                serde_json::json!([{"op": "replace", "value": {"active": false}}]),
+                StatusCode::BAD_REQUEST,
+            ),
+            (
+                serde_json::json!([{"op": "move", "path": "displayName"}]),
+                StatusCode::BAD_REQUEST,
+            ),
+        ] {
+            let error = patch_user(&mock, &UserId::new("bob"), make_patch(operations), BASE_URL)
+                .await
+                .unwrap_err();
+            assert_eq!(error.status(), expected_status);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_patch_group_members() {
+        let mut mock = MockTestBackendHandler::new();
+        let now = chrono::Utc::now();
+        mock.expect_list_groups()
+            .with(eq(Some(GroupRequestFilter::GroupId(GroupId(3)))))
+            .times(2)
+            .returning(move |_| {
+                Ok(vec![Group {
+                    id: GroupId(3),
+                    display_name: "Best".to_owned(),
+                    creation_date: now,
+                    modified_at: now,
+                    uuid: Uuid::from_name_and_date("Best", &now),
+                    users: vec![UserId::new("bob"), UserId::new("patrick")],
+                    gid_number: None,
+                }])
+            });
+        mock.expect_list_users()
+            .with(
+                eq(Some(UserRequestFilter::MemberOfId(GroupId(3)))),
+                eq(false),
+            )
+            .times(1)
+            .return_once(|_, _| {
+                Ok(vec![
+                    make_user_and_groups("bob"),
+                    make_user_and_groups("patrick"),
+                ])
+            });
+        mock.expect_list_groups()
+            .with(eq(Some(GroupRequestFilter::DisplayName(
+                "Better".to_owned(),
+            ))))
+            .times(1)
+            .return_once(|_| Ok(vec![]));
+        mock.expect_update_group()
+            .with(eq(UpdateGroupRequest {
+                group_id: GroupId(3),
+                display_name: Some("Better".to_owned()),
+            }))
+            .times(1)
+            .return_once(|_| Ok(()));
+        mock.expect_get_user_details()
+            .with(eq(UserId::new("alice")))
+            .times(1)
+            .return_once(|_| Ok(make_user("alice")));
+        mock.expect_add_user_to_group()
+            .with(eq(UserId::new("alice")), eq(GroupId(3)))
+            .times(1)
+            .return_once(|_, _| Ok(()));
+        mock.expect_remove_user_from_group()
+            .with(eq(UserId::new("patrick")), eq(GroupId(3)))
+            .times(1)
+            .return_once(|_, _| Ok(()));
+        let patch = make_patch(serde_json::json!([
+            {"op": "add", "path": "members", "value": [{"value": "alice"}]},
+            {"op": "remove", "path": "members[value eq \"Patrick\"]"},
+            {"op": "replace", "value": {"displayName": "Better"}},
+        ]));
+        patch_group(&mock, GroupId(3), patch, BASE_URL)
+            .await
+            .unwrap();
+    }
+}
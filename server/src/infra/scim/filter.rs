@@ -0,0 +1,270 @@
+//! Parser for the SCIM filter expressions (RFC 7644, section 3.4.2.2), e.g.
+//! `userName eq "bob" and not (emails co "@example.com")`.
+
+use serde_json::Value;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CompareOperator {
+    Eq,
+    Ne,
+    Co,
+    Sw,
+    Ew,
+    Gt,
+    Ge,
+    Lt,
+    Le,
+}
+
+impl std::str::FromStr for CompareOperator {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, String> {
+        Ok(match s.to_ascii_lowercase().as_str() {
+            "eq" => CompareOperator::Eq,
+            "ne" => CompareOperator::Ne,
+            "co" => CompareOperator::Co,
+            "sw" => CompareOperator::Sw,
+            "ew" => CompareOperator::Ew,
+            "gt" => CompareOperator::Gt,
+            "ge" => CompareOperator::Ge,
+            "lt" => CompareOperator::Lt,
+            "le" => CompareOperator::Le,
+            _ => return Err(format!("Unknown operator: {}", s)),
+        })
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ScimFilter {
+    And(Box<ScimFilter>, Box<ScimFilter>),
+    Or(Box<ScimFilter>, Box<ScimFilter>),
+    Not(Box<ScimFilter>),
+    /// The attribute has a non-empty value.
+    Present(String),
+    Compare(String, CompareOperator, Value),
+}
+
+/// Attribute names are case-insensitive, and can be prefixed with the URN of their schema.
+pub fn normalize_attribute(attribute: &str) -> String {
+    attribute
+        .rsplit(':')
+        .next()
+        .unwrap_or(attribute)
+        .to_ascii_lowercase()
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Token {
+    LeftParen,
+    RightParen,
+    /// A keyword, an attribute, an operator or a literal other than a string.
+    Word(String),
+    String(String),
+}
+
+fn tokenize(filter: &str) -> Result<Vec<Token>, String> {
+    let mut tokens = Vec::new();
+    let mut chars = filter.char_indices().peekable();
+    while let Some((start, c)) = chars.next() {
+        match c {
+            c if c.is_whitespace() => {}
+            '(' => tokens.push(Token::LeftParen),
+            ')' => tokens.push(Token::RightParen),
+            '"' => {
+                // The strings are JSON strings, escapes included.
+                let mut escaped = false;
+                let end = loop {
+                    match chars.next() {
+                        None => return Err("Unterminated string".to_owned()),
+                        Some((i, '"')) if !escaped => break i,
+                        Some((_, '\\')) if !escaped => escaped = true,
+                        Some(_) => escaped = false,
+                    }
+                };
+                tokens.push(Token::String(
+                    serde_json::from_str(&filter[start..=end])
+                        .map_err(|e| format!("Invalid string: {}", e))?,
+                ));
+            }
+            '[' | ']' => return Err("Complex attribute filters are not supported".to_owned()),
+            _ => {
+                let mut end = start + c.len_utf8();
+                while let Some(&(i, c)) = chars.peek() {
+                    if c.is_whitespace() || "()\"[]".contains(c) {
+                        break;
+                    }
+                    end = i + c.len_utf8();
+                    chars.next();
+                }
+                tokens.push(Token::Word(filter[start..end].to_owned()));
+            }
+        }
+    }
+    Ok(tokens)
+}
+
+struct Parser {
+    tokens: std::iter::Peekable<std::vec::IntoIter<Token>>,
+}
+
+impl Parser {
+    fn next_is_keyword(&mut self, keyword: &str) -> bool {
+        matches!(self.tokens.peek(), Some(Token::Word(w)) if w.eq_ignore_ascii_case(keyword))
+    }
+
+    fn expect(&mut self, expected: Token) -> Result<(), String> {
+        match self.tokens.next() {
+            Some(token) if token == expected => Ok(()),
+            token => Err(format!("Expected {:?}, got {:?}", expected, token)),
+        }
+    }
+
+    fn parse_or(&mut self) -> Result<ScimFilter, String> {
+        let mut filter = self.parse_and()?;
+        while self.next_is_keyword("or") {
+            self.tokens.next();
+            filter = ScimFilter::Or(Box::new(filter), Box::new(self.parse_and()?));
+        }
+        Ok(filter)
+    }
+
+    fn parse_and(&mut self) -> Result<ScimFilter, String> {
+        let mut filter = self.parse_unary()?;
+        while self.next_is_keyword("and") {
+            self.tokens.next();
+            filter = ScimFilter::And(Box::new(filter), Box::new(self.parse_unary()?));
+        }
+        Ok(filter)
+    }
+
+    fn parse_unary(&mut self) -> Result<ScimFilter, String> {
+        match self.tokens.next() {
+            Some(Token::LeftParen) => {
+                let filter = self.parse_or()?;
+                self.expect(Token::RightParen)?;
+                Ok(filter)
+            }
+            Some(Token::Word(word)) if word.eq_ignore_ascii_case("not") => {
+                self.expect(Token::LeftParen)?;
+                let filter = self.parse_or()?;
+                self.expect(Token::RightParen)?;
+                Ok(ScimFilter::Not(Box::new(filter)))
+            }
+            Some(Token::Word(attribute)) => {
+                let attribute = normalize_attribute(&attribute);
+                let operator = match self.tokens.next() {
+                    Some(Token::Word(operator)) => operator,
+                    token => return Err(format!("Expected an operator, got {:?}", token)),
+                };
+                if operator.eq_ignore_ascii_case("pr") {
+                    return Ok(ScimFilter::Present(attribute));
+                }
+                let operator = operator.parse()?;
+                let value = match self.tokens.next() {
+                    Some(Token::String(value)) => Value::String(value),
+                    // true, false, null or a number.
+                    Some(Token::Word(value)) => serde_json::from_str(&value)
+                        .map_err(|_| format!("Invalid value: {}", value))?,
+                    token => return Err(format!("Expected a value, got {:?}", token)),
+                };
+                Ok(ScimFilter::Compare(attribute, operator, value))
+            }
+            token => Err(format!("Expected a filter, got {:?}", token)),
+        }
+    }
+}
+
+pub fn parse_filter(filter: &str) -> Result<ScimFilter, String> {
+    let mut parser = Parser {
+        tokens: tokenize(filter)?.into_iter().peekable(),
+    };
+    let result = parser.parse_or()?;
+    match parser.tokens.next() {
+        None => Ok(result),
+        Some(token) => Err(format!("Unexpected {:?} after the filter", token)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn compare(attribute: &str, operator: CompareOperator, value: &str) -> ScimFilter {
+        ScimFilter::Compare(
+            attribute.to_owned(),
+            operator,
+            Value::String(value.to_owned()),
+        )
+    }
+
+    #[test]
+    fn test_parse_simple_filter() {
+        assert_eq!(
+            parse_filter(r#"userName Eq "bob""#).unwrap(),
+            compare("username", CompareOperator::Eq, "bob")
+        );
+        assert_eq!(
+            parse_filter(r#"urn:ietf:params:scim:schemas:core:2.0:User:name.givenName sw "B\"o""#)
+                .unwrap(),
+            compare("name.givenname", CompareOperator::Sw, "B\"o")
+        );
+        assert_eq!(
+            parse_filter("title pr").unwrap(),
+            ScimFilter::Present("title".to_owned())
+        );
+        assert_eq!(
+            parse_filter("members.value eq 3").unwrap(),
+            ScimFilter::Compare(
+                "members.value".to_owned(),
+                CompareOperator::Eq,
+                Value::from(3)
+            )
+        );
+    }
+
+    #[test]
+    fn test_parse_precedence() {
+        // "and" binds tighter than "or".
+        assert_eq!(
+            parse_filter(r#"a eq "1" or b eq "2" and not (c eq "3")"#).unwrap(),
+            ScimFilter::Or(
+                Box::new(compare("a", CompareOperator::Eq, "1")),
+                Box::new(ScimFilter::And(
+                    Box::new(compare("b", CompareOperator::Eq, "2")),
+                    Box::new(ScimFilter::Not(Box::new(compare(
+                        "c",
+                        CompareOperator::Eq,
+                        "3"
+                    ))))
+                ))
+            )
+        );
+        assert_eq!(
+            parse_filter(r#"(a eq "1" or b eq "2") and c eq "3""#).unwrap(),
+            ScimFilter::And(
+                Box::new(ScimFilter::Or(
+                    Box::new(compare("a", CompareOperator::Eq, "1")),
+                    Box::new(compare("b", CompareOperator::Eq, "2"))
+                )),
+                Box::new(compare("c", CompareOperator::Eq, "3"))
+            )
+        );
+    }
+
+    #[test]
+    fn test_parse_invalid_filter() {
+        for filter in [
+            "",
+            "userName",
+            r#"userName is "bob""#,
+            r#"userName eq "bob"#,
+            r#"userName eq "bob" or"#,
+            r#"(userName eq "bob""#,
+            r#"userName eq "bob" extra"#,
+            r#"emails[type eq "work"] pr"#,
+            "userName eq bob",
+        ] {
+            assert!(parse_filter(filter).is_err(), "{}", filter);
+        }
+    }
+}
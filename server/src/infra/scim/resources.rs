@@ -0,0 +1,296 @@
+use crate::domain::types::{DateTime, Group, GroupDetails, User};
+use serde::{Deserialize, Serialize};
+
+pub const USER_SCHEMA: &str = "urn:ietf:params:scim:schemas:core:2.0:User";
+pub const GROUP_SCHEMA: &str = "urn:ietf:params:scim:schemas:core:2.0:Group";
+pub const LIST_RESPONSE_SCHEMA: &str = "urn:ietf:params:scim:api:messages:2.0:ListResponse";
+pub const ERROR_SCHEMA: &str = "urn:ietf:params:scim:api:messages:2.0:Error";
+
+#[derive(Debug, Clone, PartialEq, Eq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Name {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub given_name: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub family_name: Option<String>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Email {
+    pub value: String,
+    #[serde(default)]
+    pub primary: bool,
+}
+
+/// Users only have one email: the primary one, or else the first one.
+pub fn get_primary_email(emails: &[Email]) -> Option<&str> {
+    emails
+        .iter()
+        .find(|e| e.primary)
+        .or_else(|| emails.first())
+        .map(|e| e.value.as_str())
+}
+
+/// A member of a group, or a group of a user.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Reference {
+    pub value: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub display: Option<String>,
+    #[serde(rename = "$ref", default, skip_serializing_if = "Option::is_none")]
+    pub reference: Option<String>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Meta {
+    pub resource_type: &'static str,
+    pub created: DateTime,
+    pub location: String,
+}
+
+/// The attributes that are not listed here (externalId, phoneNumbers, ...) are ignored.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ScimUser {
+    #[serde(default)]
+    pub schemas: Vec<String>,
+    /// Same as the user name.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub id: Option<String>,
+    pub user_name: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub display_name: Option<String>,
+    #[serde(default)]
+    pub name: Name,
+    #[serde(default)]
+    pub emails: Vec<Email>,
+    /// Users can't be deactivated, only deleted.
+    #[serde(default = "get_true")]
+    pub active: bool,
+    /// Read-only, the memberships are managed through the groups.
+    #[serde(default, skip_deserializing)]
+    pub groups: Vec<Reference>,
+    #[serde(default, skip_deserializing, skip_serializing_if = "Option::is_none")]
+    pub meta: Option<Meta>,
+}
+
+fn get_true() -> bool {
+    true
+}
+
+fn non_empty(value: Option<String>) -> Option<String> {
+    value.filter(|v| !v.is_empty())
+}
+
+impl ScimUser {
+    pub fn new(user: User, groups: Vec<GroupDetails>, base_url: &str) -> Self {
+        let user_id = user.user_id.to_string();
+        let user_name = user.display_user_id().to_owned();
+        ScimUser {
+            schemas: vec![USER_SCHEMA.to_owned()],
+            id: Some(user_id.clone()),
+            display_name: non_empty(user.display_name),
+            name: Name {
+                given_name: non_empty(user.first_name),
+                family_name: non_empty(user.last_name),
+            },
+            emails: if user.email.is_empty() {
+                Vec::new()
+            } else {
+                vec![Email {
+                    value: user.email,
+                    primary: true,
+                }]
+            },
+            active: true,
+            groups: groups
+                .into_iter()
+                .map(|g| Reference {
+                    value: g.group_id.0.to_string(),
+                    display: Some(g.display_name),
+                    reference: Some(format!("{}/Groups/{}", base_url, g.group_id.0)),
+                })
+                .collect(),
+            meta: Some(Meta {
+                resource_type: "User",
+                created: user.creation_date,
+                location: format!("{}/Users/{}", base_url, user_id),
+            }),
+            user_name,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ScimGroup {
+    #[serde(default)]
+    pub schemas: Vec<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub id: Option<String>,
+    pub display_name: String,
+    /// When reading a group, includes the members of its subgroups.
+    #[serde(default)]
+    pub members: Vec<Reference>,
+    #[serde(default, skip_deserializing, skip_serializing_if = "Option::is_none")]
+    pub meta: Option<Meta>,
+}
+
+impl ScimGroup {
+    pub fn new(group: Group, base_url: &str) -> Self {
+        let group_id = group.id.0.to_string();
+        ScimGroup {
+            schemas: vec![GROUP_SCHEMA.to_owned()],
+            id: Some(group_id.clone()),
+            display_name: group.display_name,
+            members: group
+                .users
+                .into_iter()
+                .map(|u| Reference {
+                    reference: Some(format!("{}/Users/{}", base_url, u)),
+                    value: u.to_string(),
+                    display: None,
+                })
+                .collect(),
+            meta: Some(Meta {
+                resource_type: "Group",
+                created: group.creation_date,
+                location: format!("{}/Groups/{}", base_url, group_id),
+            }),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ListResponse<T> {
+    pub schemas: Vec<&'static str>,
+    pub total_results: usize,
+    /// 1-based.
+    pub start_index: usize,
+    pub items_per_page: usize,
+    #[serde(rename = "Resources")]
+    pub resources: Vec<T>,
+}
+
+impl<T> ListResponse<T> {
+    pub fn new(resources: Vec<T>, total_results: usize, start_index: usize) -> Self {
+        ListResponse {
+            schemas: vec![LIST_RESPONSE_SCHEMA],
+            total_results,
+            start_index,
+            items_per_page: resources.len(),
+            resources,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+pub struct PatchRequest {
+    #[serde(rename = "Operations")]
+    pub operations: Vec<PatchOperation>,
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+pub struct PatchOperation {
+    /// add, replace or remove, case-insensitive.
+    pub op: String,
+    #[serde(default)]
+    pub path: Option<String>,
+    #[serde(default)]
+    pub value: Option<serde_json::Value>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ErrorResponse {
+    pub schemas: Vec<&'static str>,
+    /// The HTTP status code, as a string.
+    pub status: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub scim_type: Option<&'static str>,
+    pub detail: String,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::domain::types::{GroupId, UserId, Uuid};
+
+    #[test]
+    fn test_user_format() {
+        let creation_date = chrono::DateTime::parse_from_rfc3339("2023-01-02T03:04:05Z")
+            .unwrap()
+            .into();
+        let user = ScimUser::new(
+            User {
+                user_id: UserId::new("bob"),
+                email: "bob@bob.bob".to_owned(),
+                display_name: Some("Bob".to_owned()),
+                first_name: Some("Robert".to_owned()),
+                last_name: Some(String::new()),
+                avatar: None,
+                creation_date,
+                modified_at: creation_date,
+                uuid: Uuid::from_name_and_date("bob", &creation_date),
+                uid_number: None,
+                gid_number: None,
+                home_directory: None,
+                login_shell: None,
+                email_verified: false,
+                user_id_display: None,
+            },
+            vec![GroupDetails {
+                group_id: GroupId(3),
+                display_name: "Best".to_owned(),
+                creation_date,
+                uuid: Uuid::from_name_and_date("Best", &creation_date),
+            }],
+            "http://localhost/scim/v2",
+        );
+        assert_eq!(
+            serde_json::to_value(&user).unwrap(),
+            serde_json::json!({
+                "schemas": [USER_SCHEMA],
+                "id": "bob",
+                "userName": "bob",
+                "displayName": "Bob",
+                "name": {"givenName": "Robert"},
+                "emails": [{"value": "bob@bob.bob", "primary": true}],
+                "active": true,
+                "groups": [{
+                    "value": "3",
+                    "display": "Best",
+                    "$ref": "http://localhost/scim/v2/Groups/3",
+                }],
+                "meta": {
+                    "resourceType": "User",
+                    "created": "2023-01-02T03:04:05Z",
+                    "location": "http://localhost/scim/v2/Users/bob",
+                },
+            })
+        );
+    }
+
+    #[test]
+    fn test_parse_user() {
+        let user: ScimUser = serde_json::from_value(serde_json::json!({
+            "schemas": [USER_SCHEMA],
+            "externalId": "00u1",
+            "userName": "bob",
+            "name": {"givenName": "Robert", "familyName": "Smith"},
+            "emails": [
+                {"value": "work@bob.bob", "type": "work"},
+                {"value": "home@bob.bob", "type": "home", "primary": true},
+            ],
+            "groups": [{"value": "3"}],
+        }))
+        .unwrap();
+        assert_eq!(user.user_name, "bob");
+        assert_eq!(user.name.family_name.as_deref(), Some("Smith"));
+        assert_eq!(get_primary_email(&user.emails), Some("home@bob.bob"));
+        assert!(user.active);
+        assert!(user.groups.is_empty());
+    }
+}
@@ -0,0 +1,157 @@
+//! The security headers of all the HTTP responses: the web app, the API, the health checks and
+//! the errors. A handler can set its own value of one of them, e.g. a more permissive CSP.
+use crate::infra::configuration::SecurityHeadersOptions;
+use actix_web::{http::header::HeaderValue, middleware::DefaultHeaders};
+use anyhow::{Context, Result};
+
+/// The headers to add, in order. Fails on a value that can't be sent in a header.
+pub fn security_headers(
+    options: &SecurityHeadersOptions,
+) -> Result<Vec<(&'static str, HeaderValue)>> {
+    let mut headers = vec![(
+        "x-content-type-options",
+        HeaderValue::from_static("nosniff"),
+    )];
+    if options.hsts {
+        let mut hsts = format!("max-age={}", options.hsts_max_age_seconds);
+        if options.hsts_include_subdomains {
+            hsts.push_str("; includeSubDomains");
+        }
+        headers.push(("strict-transport-security", HeaderValue::from_str(&hsts)?));
+    }
+    if !options.frame_options.is_empty() {
+        headers.push((
+            "x-frame-options",
+            HeaderValue::from_str(&options.frame_options)
+                .context("Invalid `security_headers.frame_options`")?,
+        ));
+    }
+    if !options.content_security_policy.is_empty() {
+        headers.push((
+            "content-security-policy",
+            HeaderValue::from_str(&options.content_security_policy)
+                .context("Invalid `security_headers.content_security_policy`")?,
+        ));
+    }
+    Ok(headers)
+}
+
+/// The middleware adding the headers. The options are checked on startup.
+pub fn security_headers_middleware(options: &SecurityHeadersOptions) -> DefaultHeaders {
+    security_headers(options)
+        .expect("invalid security headers")
+        .into_iter()
+        .fold(DefaultHeaders::new(), |middleware, (name, value)| {
+            middleware.header(name, value)
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::infra::configuration::SecurityHeadersOptionsBuilder;
+    use actix_web::{
+        http::StatusCode,
+        test::{call_service, init_service, TestRequest},
+        web, App, HttpResponse,
+    };
+
+    async fn get_headers(
+        options: &SecurityHeadersOptions,
+        path: &str,
+    ) -> (StatusCode, Vec<(String, String)>) {
+        let app = init_service(
+            App::new()
+                .wrap(security_headers_middleware(options))
+                .route("/health", web::get().to(|| HttpResponse::Ok().finish()))
+                .route("/api/graphql", web::to(|| HttpResponse::Ok().finish()))
+                .route(
+                    "/pkg/bundle.js",
+                    web::get().to(|| {
+                        HttpResponse::Ok()
+                            .insert_header(("content-security-policy", "default-src *"))
+                            .body("")
+                    }),
+                ),
+        )
+        .await;
+        let response = call_service(&app, TestRequest::get().uri(path).to_request()).await;
+        let mut headers = response
+            .headers()
+            .iter()
+            .map(|(name, value)| (name.to_string(), value.to_str().unwrap().to_owned()))
+            .filter(|(name, _)| name != "content-length")
+            .collect::<Vec<_>>();
+        headers.sort();
+        (response.status(), headers)
+    }
+
+    fn header(name: &str, value: &str) -> (String, String) {
+        (name.to_owned(), value.to_owned())
+    }
+
+    #[actix_rt::test]
+    async fn test_default_headers() {
+        let options = SecurityHeadersOptions::default();
+        let expected = vec![
+            header(
+                "content-security-policy",
+                &SecurityHeadersOptions::default().content_security_policy,
+            ),
+            header("strict-transport-security", "max-age=31536000"),
+            header("x-content-type-options", "nosniff"),
+            header("x-frame-options", "DENY"),
+        ];
+        for path in ["/health", "/api/graphql"] {
+            assert_eq!(
+                get_headers(&options, path).await,
+                (StatusCode::OK, expected.clone()),
+                "{}",
+                path
+            );
+        }
+        // Also on the errors.
+        assert_eq!(
+            get_headers(&options, "/unknown").await,
+            (StatusCode::NOT_FOUND, expected)
+        );
+        // The handler's own value is kept.
+        let (_, headers) = get_headers(&options, "/pkg/bundle.js").await;
+        assert!(headers.contains(&header("content-security-policy", "default-src *")));
+    }
+
+    #[actix_rt::test]
+    async fn test_configured_headers() {
+        let options = SecurityHeadersOptionsBuilder::default()
+            .hsts(false)
+            .frame_options("SAMEORIGIN".to_owned())
+            .content_security_policy(String::new())
+            .build()
+            .unwrap();
+        assert_eq!(
+            get_headers(&options, "/health").await,
+            (
+                StatusCode::OK,
+                vec![
+                    header("x-content-type-options", "nosniff"),
+                    header("x-frame-options", "SAMEORIGIN"),
+                ]
+            )
+        );
+        let options = SecurityHeadersOptionsBuilder::default()
+            .hsts_max_age_seconds(600)
+            .hsts_include_subdomains(true)
+            .build()
+            .unwrap();
+        let (_, headers) = get_headers(&options, "/health").await;
+        assert!(headers.contains(&header(
+            "strict-transport-security",
+            "max-age=600; includeSubDomains"
+        )));
+        let options = SecurityHeadersOptionsBuilder::default()
+            .content_security_policy("default-src 'self'\n".to_owned())
+            .build()
+            .unwrap();
+        assert!(security_headers(&options).is_err());
+    }
+}
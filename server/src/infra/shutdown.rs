@@ -0,0 +1,175 @@
+//! Graceful shutdown. On SIGTERM or Ctrl-C, the servers stop accepting connections, and the open
+//! LDAP sessions close once their current request is answered. The HTTP requests in flight are
+//! drained by actix. Whatever still runs at the end of the grace period is dropped.
+use std::sync::{
+    atomic::{AtomicUsize, Ordering},
+    Arc,
+};
+use tokio::sync::watch;
+use tracing::{error, info};
+
+struct Inner {
+    sender: watch::Sender<bool>,
+    // Keeps the channel open, so that starting the shutdown never fails.
+    receiver: watch::Receiver<bool>,
+    active: AtomicUsize,
+    drained: AtomicUsize,
+    force_closed: AtomicUsize,
+}
+
+/// Tells the sessions when to stop, and counts how they ended.
+#[derive(Clone)]
+pub struct ShutdownCoordinator {
+    inner: Arc<Inner>,
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub struct ShutdownReport {
+    pub drained: usize,
+    pub force_closed: usize,
+}
+
+impl ShutdownCoordinator {
+    pub fn new() -> Self {
+        let (sender, receiver) = watch::channel(false);
+        Self {
+            inner: Arc::new(Inner {
+                sender,
+                receiver,
+                active: AtomicUsize::new(0),
+                drained: AtomicUsize::new(0),
+                force_closed: AtomicUsize::new(0),
+            }),
+        }
+    }
+
+    /// Registers a session, until the guard is dropped.
+    pub fn connection(&self) -> ConnectionGuard {
+        self.inner.active.fetch_add(1, Ordering::SeqCst);
+        ConnectionGuard {
+            coordinator: self.clone(),
+            receiver: self.inner.receiver.clone(),
+            finished: false,
+        }
+    }
+
+    pub fn is_shutting_down(&self) -> bool {
+        *self.inner.receiver.borrow()
+    }
+
+    /// Returns the number of sessions still open.
+    pub fn start(&self) -> usize {
+        if self.inner.sender.send(true).is_err() {
+            error!("Could not notify the sessions of the shutdown");
+        }
+        self.inner.active.load(Ordering::SeqCst)
+    }
+
+    /// How the sessions open at the start of the shutdown ended.
+    pub fn report(&self) -> ShutdownReport {
+        ShutdownReport {
+            drained: self.inner.drained.load(Ordering::SeqCst),
+            force_closed: self.inner.force_closed.load(Ordering::SeqCst),
+        }
+    }
+
+    /// Waits for SIGTERM or Ctrl-C, then stops the server gracefully: the server waits for the
+    /// connections up to its shutdown timeout.
+    pub async fn stop_on_signal(self, server: actix_server::Server) {
+        if let Err(e) = wait_for_signal().await {
+            error!("Could not listen to the shutdown signals: {:#}", e);
+            return;
+        }
+        info!(
+            "Shutting down, waiting for {} open connections",
+            self.start()
+        );
+        server.stop(true).await;
+    }
+}
+
+impl Default for ShutdownCoordinator {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+async fn wait_for_signal() -> std::io::Result<()> {
+    #[cfg(unix)]
+    {
+        use tokio::signal::unix::{signal, SignalKind};
+        let mut terminate = signal(SignalKind::terminate())?;
+        tokio::select! {
+            result = tokio::signal::ctrl_c() => result,
+            _ = terminate.recv() => Ok(()),
+        }
+    }
+    #[cfg(not(unix))]
+    tokio::signal::ctrl_c().await
+}
+
+/// A session. If it's dropped without `finish` during the shutdown, it was force-closed.
+pub struct ConnectionGuard {
+    coordinator: ShutdownCoordinator,
+    receiver: watch::Receiver<bool>,
+    finished: bool,
+}
+
+impl ConnectionGuard {
+    /// Resolves once the shutdown starts.
+    pub async fn shutdown_requested(&mut self) {
+        while !*self.receiver.borrow() {
+            if self.receiver.changed().await.is_err() {
+                return std::future::pending().await;
+            }
+        }
+    }
+
+    pub fn finish(mut self) {
+        self.finished = true;
+    }
+}
+
+impl Drop for ConnectionGuard {
+    fn drop(&mut self) {
+        let inner = &self.coordinator.inner;
+        inner.active.fetch_sub(1, Ordering::SeqCst);
+        if self.coordinator.is_shutting_down() {
+            if self.finished {
+                inner.drained.fetch_add(1, Ordering::SeqCst);
+            } else {
+                inner.force_closed.fetch_add(1, Ordering::SeqCst);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_shutdown() {
+        let coordinator = ShutdownCoordinator::new();
+        // Closed before the shutdown: not counted.
+        coordinator.connection().finish();
+        let mut drained = coordinator.connection();
+        let forced = coordinator.connection();
+        let waiting = tokio::spawn(async move {
+            drained.shutdown_requested().await;
+            drained.finish();
+        });
+        assert_eq!(coordinator.start(), 2);
+        waiting.await.unwrap();
+        drop(forced);
+        assert_eq!(
+            coordinator.report(),
+            ShutdownReport {
+                drained: 1,
+                force_closed: 1,
+            }
+        );
+        // Already shutting down.
+        coordinator.connection().shutdown_requested().await;
+    }
+}
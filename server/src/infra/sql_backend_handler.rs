@@ -1,27 +1,89 @@
-use super::tcp_backend_handler::TcpBackendHandler;
+use super::{
+    auth_service::JWT_VALIDITY_DAYS,
+    tcp_backend_handler::{HealthStatus, RefreshToken, TcpBackendHandler},
+};
 use crate::domain::{
     error::*,
-    model::{self, JwtRefreshStorageColumn, JwtStorageColumn, PasswordResetTokensColumn},
+    model::{
+        self, EmailVerificationTokensColumn, JwtStorageColumn, PasswordResetTokensColumn,
+        RefreshTokensColumn, SessionsColumn, UserColumn,
+    },
+    session_handler::SessionClient,
     sql_backend_handler::SqlBackendHandler,
+    sql_migrations::get_schema_version,
+    sql_oidc_handler::{gen_random_token, hash_token},
+    sql_session_backend_handler::revoke_sessions,
+    sql_tables::LAST_SCHEMA_VERSION,
     types::UserId,
 };
 use async_trait::async_trait;
 use sea_orm::{
-    sea_query::Cond, ActiveModelTrait, ColumnTrait, EntityTrait, FromQueryResult, IntoActiveModel,
-    QueryFilter, QuerySelect,
+    sea_query::Cond, ActiveModelTrait, ActiveValue, ColumnTrait, ConnectionTrait, EntityTrait,
+    FromQueryResult, QueryFilter, QuerySelect, Statement, TransactionTrait,
 };
 use sea_query::Expr;
 use std::collections::HashSet;
-use tracing::{debug, instrument};
-
-fn gen_random_string(len: usize) -> String {
-    use rand::{distributions::Alphanumeric, rngs::SmallRng, Rng, SeedableRng};
-    let mut rng = SmallRng::from_entropy();
-    std::iter::repeat(())
-        .map(|()| rng.sample(Alphanumeric))
-        .map(char::from)
-        .take(len)
-        .collect()
+use tracing::{debug, instrument, warn};
+
+const EMAIL_VERIFICATION_TOKEN_VALIDITY_HOURS: i64 = 24;
+const PASSWORD_RESET_TOKEN_VALIDITY_MINUTES: i64 = 10;
+const REFRESH_TOKEN_VALIDITY_DAYS: i64 = 30;
+
+/// Stores a new refresh token of the session, or of a new session if `session_id` is `None`, and
+/// records the client in the session.
+async fn insert_refresh_token(
+    connection: &impl ConnectionTrait,
+    user: &UserId,
+    session_id: Option<String>,
+    client: &SessionClient,
+) -> Result<RefreshToken> {
+    let token = gen_random_token(100);
+    let token_hash = hash_token(&token);
+    let duration = chrono::Duration::days(REFRESH_TOKEN_VALIDITY_DAYS);
+    let now = chrono::Utc::now();
+    let session = model::sessions::ActiveModel {
+        session_id: ActiveValue::Set(session_id.clone().unwrap_or_else(|| token_hash.clone())),
+        user_id: ActiveValue::Set(user.clone()),
+        creation_date: ActiveValue::Set(now),
+        last_seen: ActiveValue::Set(now),
+        expiry_date: ActiveValue::Set(now + duration),
+        ip_address: ActiveValue::Set(client.ip_address.clone()),
+        user_agent: ActiveValue::Set(client.user_agent.clone()),
+        revoked: ActiveValue::Set(false),
+    };
+    let session = match session_id {
+        None => session.insert(connection).await?,
+        Some(_) => {
+            model::sessions::ActiveModel {
+                creation_date: ActiveValue::NotSet,
+                user_id: ActiveValue::NotSet,
+                revoked: ActiveValue::NotSet,
+                ..session
+            }
+            .update(connection)
+            .await?
+        }
+    };
+    model::refresh_tokens::ActiveModel {
+        token_hash: ActiveValue::Set(token_hash),
+        family_id: ActiveValue::Set(session.session_id.clone()),
+        user_id: ActiveValue::Set(user.clone()),
+        issued_at: ActiveValue::Set(now),
+        expiry_date: ActiveValue::Set(now + duration),
+        revoked: ActiveValue::Set(false),
+    }
+    .insert(connection)
+    .await?;
+    Ok(RefreshToken {
+        token,
+        session_id: session.session_id,
+        duration,
+    })
+}
+
+#[derive(FromQueryResult)]
+struct OnlySessionId {
+    session_id: String,
 }
 
 #[derive(FromQueryResult)]
@@ -46,38 +108,75 @@ impl TcpBackendHandler for SqlBackendHandler {
     }
 
     #[instrument(skip_all, level = "debug")]
-    async fn create_refresh_token(&self, user: &UserId) -> Result<(String, chrono::Duration)> {
-        debug!(?user);
-        // TODO: Initialize the rng only once. Maybe Arc<Cell>?
-        let refresh_token = gen_random_string(100);
-        let refresh_token_hash = {
-            use std::collections::hash_map::DefaultHasher;
-            use std::hash::{Hash, Hasher};
-            let mut s = DefaultHasher::new();
-            refresh_token.hash(&mut s);
-            s.finish()
-        };
-        let duration = chrono::Duration::days(30);
-        let new_token = model::jwt_refresh_storage::Model {
-            refresh_token_hash: refresh_token_hash as i64,
-            user_id: user.clone(),
-            expiry_date: chrono::Utc::now() + duration,
-        }
-        .into_active_model();
-        new_token.insert(&self.sql_pool).await?;
-        Ok((refresh_token, duration))
+    async fn get_revoked_sessions(&self) -> anyhow::Result<HashSet<String>> {
+        // The last JWT of a session was issued at its last refresh.
+        let oldest_valid_jwt = chrono::Utc::now() - chrono::Duration::days(JWT_VALIDITY_DAYS);
+        Ok(model::Sessions::find()
+            .select_only()
+            .column(SessionsColumn::SessionId)
+            .filter(SessionsColumn::Revoked.eq(true))
+            .filter(SessionsColumn::LastSeen.gt(oldest_valid_jwt))
+            .into_model::<OnlySessionId>()
+            .all(&self.sql_pool)
+            .await?
+            .into_iter()
+            .map(|m| m.session_id)
+            .collect::<HashSet<String>>())
     }
 
     #[instrument(skip_all, level = "debug")]
-    async fn check_token(&self, refresh_token_hash: u64, user: &UserId) -> Result<bool> {
-        debug!(?user);
-        Ok(
-            model::JwtRefreshStorage::find_by_id(refresh_token_hash as i64)
-                .filter(JwtRefreshStorageColumn::UserId.eq(user))
-                .one(&self.sql_pool)
-                .await?
-                .is_some(),
-        )
+    async fn create_refresh_token(
+        &self,
+        user: &UserId,
+        client: &SessionClient,
+    ) -> Result<RefreshToken> {
+        debug!(?user, ?client);
+        let transaction = self.sql_pool.begin().await?;
+        let refresh_token = insert_refresh_token(&transaction, user, None, client).await?;
+        transaction.commit().await?;
+        Ok(refresh_token)
+    }
+
+    #[instrument(skip_all, level = "debug")]
+    async fn rotate_refresh_token(
+        &self,
+        token: &str,
+        user: &UserId,
+        client: &SessionClient,
+    ) -> Result<RefreshToken> {
+        debug!(?user, ?client);
+        let invalid_token = || DomainError::AuthenticationError("Invalid refresh token".into());
+        let token_hash = hash_token(token);
+        let stored = model::RefreshTokens::find_by_id(token_hash.clone())
+            .filter(RefreshTokensColumn::UserId.eq(user))
+            .one(&self.sql_pool)
+            .await?
+            .ok_or_else(invalid_token)?;
+        if stored.expiry_date < chrono::Utc::now() {
+            return Err(invalid_token());
+        }
+        let transaction = self.sql_pool.begin().await?;
+        let res = model::RefreshTokens::update_many()
+            .col_expr(RefreshTokensColumn::Revoked, Expr::value(true))
+            .filter(RefreshTokensColumn::TokenHash.eq(token_hash))
+            .filter(RefreshTokensColumn::Revoked.eq(false))
+            .exec(&transaction)
+            .await?;
+        // Nothing was revoked if the token was already used, or used concurrently: whoever holds
+        // the next token can't be trusted either.
+        if res.rows_affected == 0 {
+            warn!(
+                "Reuse of a revoked refresh token of {}, revoking the session",
+                user
+            );
+            revoke_sessions(&transaction, vec![stored.family_id]).await?;
+            transaction.commit().await?;
+            return Err(invalid_token());
+        }
+        let refresh_token =
+            insert_refresh_token(&transaction, user, Some(stored.family_id), client).await?;
+        transaction.commit().await?;
+        Ok(refresh_token)
     }
 
     #[instrument(skip_all, level = "debug")]
@@ -106,59 +205,497 @@ impl TcpBackendHandler for SqlBackendHandler {
     }
 
     #[instrument(skip_all, level = "debug")]
-    async fn delete_refresh_token(&self, refresh_token_hash: u64) -> Result<()> {
-        model::JwtRefreshStorage::delete_by_id(refresh_token_hash as i64)
-            .exec(&self.sql_pool)
-            .await?;
-        Ok(())
+    async fn revoke_refresh_token(&self, token: &str) -> Result<Option<String>> {
+        let session_id = model::RefreshTokens::find_by_id(hash_token(token))
+            .one(&self.sql_pool)
+            .await?
+            .map(|stored| stored.family_id);
+        if let Some(session_id) = &session_id {
+            revoke_sessions(&self.sql_pool, vec![session_id.clone()]).await?;
+        }
+        Ok(session_id)
+    }
+
+    #[instrument(skip_all, level = "debug")]
+    async fn revoke_all_refresh_tokens(&self, user: &UserId) -> Result<HashSet<String>> {
+        debug!(?user);
+        let session_ids = model::Sessions::find()
+            .select_only()
+            .column(SessionsColumn::SessionId)
+            .filter(SessionsColumn::UserId.eq(user))
+            .filter(SessionsColumn::Revoked.eq(false))
+            .into_model::<OnlySessionId>()
+            .all(&self.sql_pool)
+            .await?
+            .into_iter()
+            .map(|m| m.session_id)
+            .collect::<HashSet<String>>();
+        revoke_sessions(&self.sql_pool, session_ids.iter().cloned().collect()).await?;
+        Ok(session_ids)
     }
 
     #[instrument(skip_all, level = "debug")]
     async fn start_password_reset(&self, user: &UserId) -> Result<Option<String>> {
         debug!(?user);
-        if model::User::find_by_id(user.clone())
+        match model::User::find_by_id(user.clone())
+            .filter(UserColumn::DeletedAt.is_null())
             .one(&self.sql_pool)
             .await?
-            .is_none()
         {
-            debug!("User not found");
-            return Ok(None);
+            None => {
+                debug!("User not found");
+                return Ok(None);
+            }
+            // Whoever controls an unverified email could take over the account.
+            Some(user) if self.config.require_verified_email && !user.email_verified => {
+                debug!("Email not verified");
+                return Ok(None);
+            }
+            Some(_) => (),
         }
 
-        let token = gen_random_string(100);
-        let duration = chrono::Duration::minutes(10);
-
-        let new_token = model::password_reset_tokens::Model {
-            token: token.clone(),
-            user_id: user.clone(),
-            expiry_date: chrono::Utc::now() + duration,
+        let transaction = self.sql_pool.begin().await?;
+        // Only the last requested token is valid.
+        model::PasswordResetTokens::delete_many()
+            .filter(PasswordResetTokensColumn::UserId.eq(user))
+            .exec(&transaction)
+            .await?;
+        let token = gen_random_token(48);
+        model::password_reset_tokens::ActiveModel {
+            token: ActiveValue::Set(hash_token(&token)),
+            user_id: ActiveValue::Set(user.clone()),
+            expiry_date: ActiveValue::Set(
+                chrono::Utc::now()
+                    + chrono::Duration::minutes(PASSWORD_RESET_TOKEN_VALIDITY_MINUTES),
+            ),
         }
-        .into_active_model();
-        new_token.insert(&self.sql_pool).await?;
+        .insert(&transaction)
+        .await?;
+        transaction.commit().await?;
         Ok(Some(token))
     }
 
-    #[instrument(skip_all, level = "debug", ret)]
-    async fn get_user_id_for_password_reset_token(&self, token: &str) -> Result<UserId> {
-        Ok(model::PasswordResetTokens::find_by_id(token.to_owned())
-            .filter(PasswordResetTokensColumn::ExpiryDate.gt(chrono::Utc::now().naive_utc()))
+    #[instrument(skip_all, level = "debug", ret, err)]
+    async fn consume_password_reset_token(&self, token: &str) -> Result<UserId> {
+        let invalid_token = || DomainError::AuthenticationError("Invalid reset token".into());
+        let token_hash = hash_token(token);
+        let stored = model::PasswordResetTokens::find_by_id(token_hash.clone())
             .one(&self.sql_pool)
             .await?
-            .ok_or_else(|| DomainError::EntityNotFound("Invalid reset token".to_owned()))?
-            .user_id)
+            .ok_or_else(invalid_token)?;
+        let res = model::PasswordResetTokens::delete_by_id(token_hash)
+            .exec(&self.sql_pool)
+            .await?;
+        // Nothing was deleted if the token was used concurrently.
+        if res.rows_affected == 0 || stored.expiry_date < chrono::Utc::now() {
+            return Err(invalid_token());
+        }
+        Ok(stored.user_id)
     }
 
     #[instrument(skip_all, level = "debug")]
-    async fn delete_password_reset_token(&self, token: &str) -> Result<()> {
-        let result = model::PasswordResetTokens::delete_by_id(token.to_owned())
+    async fn start_email_verification(&self, user: &UserId, email: &str) -> Result<String> {
+        debug!(?user, ?email);
+        let transaction = self.sql_pool.begin().await?;
+        model::EmailVerificationTokens::delete_many()
+            .filter(EmailVerificationTokensColumn::UserId.eq(user))
+            .filter(EmailVerificationTokensColumn::Email.eq(email))
+            .exec(&transaction)
+            .await?;
+        let token = gen_random_token(48);
+        model::email_verification_tokens::ActiveModel {
+            token_hash: ActiveValue::Set(hash_token(&token)),
+            user_id: ActiveValue::Set(user.clone()),
+            email: ActiveValue::Set(email.to_owned()),
+            expiry_date: ActiveValue::Set(
+                chrono::Utc::now()
+                    + chrono::Duration::hours(EMAIL_VERIFICATION_TOKEN_VALIDITY_HOURS),
+            ),
+        }
+        .insert(&transaction)
+        .await?;
+        transaction.commit().await?;
+        Ok(token)
+    }
+
+    #[instrument(skip_all, level = "debug", ret, err)]
+    async fn confirm_email_verification(&self, token: &str) -> Result<UserId> {
+        let invalid_token =
+            || DomainError::AuthenticationError("Invalid email verification token".into());
+        let token_hash = hash_token(token);
+        let stored = model::EmailVerificationTokens::find_by_id(token_hash.clone())
+            .one(&self.sql_pool)
+            .await?
+            .ok_or_else(invalid_token)?;
+        let res = model::EmailVerificationTokens::delete_by_id(token_hash)
+            .exec(&self.sql_pool)
+            .await?;
+        // Nothing was deleted if the token was used concurrently.
+        if res.rows_affected == 0 || stored.expiry_date < chrono::Utc::now() {
+            return Err(invalid_token());
+        }
+        let res = model::User::update_many()
+            .col_expr(UserColumn::EmailVerified, Expr::value(true))
+            .filter(ColumnTrait::eq(&UserColumn::UserId, &stored.user_id))
+            .filter(ColumnTrait::eq(&UserColumn::Email, stored.email.as_str()))
+            .filter(UserColumn::DeletedAt.is_null())
             .exec(&self.sql_pool)
             .await?;
-        if result.rows_affected == 0 {
-            return Err(DomainError::EntityNotFound(format!(
-                "No such password reset token: '{}'",
-                token
-            )));
+        if res.rows_affected == 0 {
+            debug!("The email changed since the token was issued");
+            return Err(invalid_token());
+        }
+        self.last_write.mark();
+        Ok(stored.user_id)
+    }
+
+    #[instrument(skip_all, level = "debug", ret)]
+    async fn check_health(&self) -> HealthStatus {
+        let database_reachable = self
+            .sql_pool
+            .execute(Statement::from_string(
+                self.sql_pool.get_database_backend(),
+                "SELECT 1".to_owned(),
+            ))
+            .await
+            .is_ok();
+        HealthStatus {
+            database_reachable,
+            schema_version: get_schema_version(&self.sql_pool).await.map(|v| v.0),
+            expected_schema_version: LAST_SCHEMA_VERSION.0,
         }
-        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::domain::{
+        handler::{UpdateUserRequest, UserBackendHandler},
+        sql_backend_handler::tests::*,
+    };
+
+    #[tokio::test]
+    async fn test_check_health() {
+        let fixture = TestFixture::new().await;
+        let status = fixture.handler.check_health().await;
+        assert!(status.is_ready(), "{:?}", status);
+
+        // A database that hasn't been migrated yet.
+        let handler = SqlBackendHandler::new(get_default_config(), get_in_memory_db().await);
+        let status = handler.check_health().await;
+        assert_eq!(
+            status,
+            HealthStatus {
+                database_reachable: true,
+                schema_version: None,
+                expected_schema_version: LAST_SCHEMA_VERSION.0,
+            }
+        );
+        assert!(!status.is_ready());
+    }
+
+    #[tokio::test]
+    async fn test_password_reset_token() {
+        let fixture = TestFixture::new().await;
+        let bob = UserId::new("bob");
+        let old_token = fixture
+            .handler
+            .start_password_reset(&bob)
+            .await
+            .unwrap()
+            .unwrap();
+        let token = fixture
+            .handler
+            .start_password_reset(&bob)
+            .await
+            .unwrap()
+            .unwrap();
+        // Only the hash is stored.
+        assert!(model::PasswordResetTokens::find_by_id(token.clone())
+            .one(&fixture.handler.sql_pool)
+            .await
+            .unwrap()
+            .is_none());
+        // The new token replaced the old one.
+        assert!(fixture
+            .handler
+            .consume_password_reset_token(&old_token)
+            .await
+            .is_err());
+        assert_eq!(
+            fixture
+                .handler
+                .consume_password_reset_token(&token)
+                .await
+                .unwrap(),
+            bob
+        );
+        // Single use.
+        assert!(fixture
+            .handler
+            .consume_password_reset_token(&token)
+            .await
+            .is_err());
+        // Unknown users don't get a token.
+        assert_eq!(
+            fixture
+                .handler
+                .start_password_reset(&UserId::new("nobody"))
+                .await
+                .unwrap(),
+            None
+        );
+    }
+
+    #[tokio::test]
+    async fn test_password_reset_token_expired() {
+        let fixture = TestFixture::new().await;
+        let bob = UserId::new("bob");
+        let token = fixture
+            .handler
+            .start_password_reset(&bob)
+            .await
+            .unwrap()
+            .unwrap();
+        model::PasswordResetTokens::update_many()
+            .col_expr(
+                PasswordResetTokensColumn::ExpiryDate,
+                Expr::value(chrono::Utc::now() - chrono::Duration::minutes(1)),
+            )
+            .exec(&fixture.handler.sql_pool)
+            .await
+            .unwrap();
+        assert!(fixture
+            .handler
+            .consume_password_reset_token(&token)
+            .await
+            .is_err());
+    }
+
+    async fn setup_refresh_tokens() -> TestFixture {
+        let fixture = TestFixture::new().await;
+        crate::infra::jwt_sql_tables::init_table(&fixture.handler.sql_pool)
+            .await
+            .unwrap();
+        fixture
+    }
+
+    #[tokio::test]
+    async fn test_refresh_token_rotation() {
+        let fixture = setup_refresh_tokens().await;
+        let bob = UserId::new("bob");
+        let RefreshToken {
+            token, session_id, ..
+        } = fixture
+            .handler
+            .create_refresh_token(&bob, &SessionClient::default())
+            .await
+            .unwrap();
+        // Only the hash is stored.
+        assert!(model::RefreshTokens::find_by_id(token.clone())
+            .one(&fixture.handler.sql_pool)
+            .await
+            .unwrap()
+            .is_none());
+        // Not for another user.
+        assert!(fixture
+            .handler
+            .rotate_refresh_token(&token, &UserId::new("patrick"), &SessionClient::default())
+            .await
+            .is_err());
+        let new_token = fixture
+            .handler
+            .rotate_refresh_token(&token, &bob, &SessionClient::default())
+            .await
+            .unwrap()
+            .token;
+        let newest_token = fixture
+            .handler
+            .rotate_refresh_token(&new_token, &bob, &SessionClient::default())
+            .await
+            .unwrap();
+        assert_eq!(newest_token.session_id, session_id);
+        let newest_token = newest_token.token;
+        // Logout.
+        assert_eq!(
+            fixture
+                .handler
+                .revoke_refresh_token(&newest_token)
+                .await
+                .unwrap(),
+            Some(session_id.clone())
+        );
+        assert!(fixture
+            .handler
+            .get_revoked_sessions()
+            .await
+            .unwrap()
+            .contains(&session_id));
+        assert!(fixture
+            .handler
+            .rotate_refresh_token(&newest_token, &bob, &SessionClient::default())
+            .await
+            .is_err());
+    }
+
+    #[tokio::test]
+    async fn test_refresh_token_reuse_revokes_the_session() {
+        let fixture = setup_refresh_tokens().await;
+        let bob = UserId::new("bob");
+        let stolen_token = fixture
+            .handler
+            .create_refresh_token(&bob, &SessionClient::default())
+            .await
+            .unwrap()
+            .token;
+        let other_session_token = fixture
+            .handler
+            .create_refresh_token(&bob, &SessionClient::default())
+            .await
+            .unwrap()
+            .token;
+        let new_token = fixture
+            .handler
+            .rotate_refresh_token(&stolen_token, &bob, &SessionClient::default())
+            .await
+            .unwrap()
+            .token;
+        // The token was already rotated.
+        assert!(fixture
+            .handler
+            .rotate_refresh_token(&stolen_token, &bob, &SessionClient::default())
+            .await
+            .is_err());
+        // The whole session is revoked.
+        assert!(fixture
+            .handler
+            .rotate_refresh_token(&new_token, &bob, &SessionClient::default())
+            .await
+            .is_err());
+        // The other sessions are not.
+        let other_session_token = fixture
+            .handler
+            .rotate_refresh_token(&other_session_token, &bob, &SessionClient::default())
+            .await
+            .unwrap()
+            .token;
+        // Until all of them are revoked.
+        fixture
+            .handler
+            .revoke_all_refresh_tokens(&bob)
+            .await
+            .unwrap();
+        assert!(fixture
+            .handler
+            .rotate_refresh_token(&other_session_token, &bob, &SessionClient::default())
+            .await
+            .is_err());
+    }
+
+    #[tokio::test]
+    async fn test_refresh_token_expired() {
+        let fixture = setup_refresh_tokens().await;
+        let bob = UserId::new("bob");
+        let token = fixture
+            .handler
+            .create_refresh_token(&bob, &SessionClient::default())
+            .await
+            .unwrap()
+            .token;
+        model::RefreshTokens::update_many()
+            .col_expr(
+                RefreshTokensColumn::ExpiryDate,
+                Expr::value(chrono::Utc::now() - chrono::Duration::minutes(1)),
+            )
+            .exec(&fixture.handler.sql_pool)
+            .await
+            .unwrap();
+        assert!(fixture
+            .handler
+            .rotate_refresh_token(&token, &bob, &SessionClient::default())
+            .await
+            .is_err());
+    }
+
+    async fn is_email_verified(handler: &SqlBackendHandler, user: &str) -> bool {
+        model::User::find_by_id(UserId::new(user))
+            .one(&handler.sql_pool)
+            .await
+            .unwrap()
+            .unwrap()
+            .email_verified
+    }
+
+    #[tokio::test]
+    async fn test_email_verification() {
+        let fixture = TestFixture::new().await;
+        let bob = UserId::new("bob");
+        let email = fixture.handler.get_user_details(&bob).await.unwrap().email;
+        let old_token = fixture
+            .handler
+            .start_email_verification(&bob, &email)
+            .await
+            .unwrap();
+        let token = fixture
+            .handler
+            .start_email_verification(&bob, &email)
+            .await
+            .unwrap();
+        // Only the hash is stored.
+        assert!(model::EmailVerificationTokens::find_by_id(token.clone())
+            .one(&fixture.handler.sql_pool)
+            .await
+            .unwrap()
+            .is_none());
+        // The new token replaced the old one.
+        assert!(fixture
+            .handler
+            .confirm_email_verification(&old_token)
+            .await
+            .is_err());
+        assert!(!is_email_verified(&fixture.handler, "bob").await);
+        assert_eq!(
+            fixture
+                .handler
+                .confirm_email_verification(&token)
+                .await
+                .unwrap(),
+            bob
+        );
+        assert!(is_email_verified(&fixture.handler, "bob").await);
+        // Single use.
+        assert!(fixture
+            .handler
+            .confirm_email_verification(&token)
+            .await
+            .is_err());
+    }
+
+    #[tokio::test]
+    async fn test_email_verification_after_email_change() {
+        let fixture = TestFixture::new().await;
+        let bob = UserId::new("bob");
+        let email = fixture.handler.get_user_details(&bob).await.unwrap().email;
+        let token = fixture
+            .handler
+            .start_email_verification(&bob, &email)
+            .await
+            .unwrap();
+        fixture
+            .handler
+            .update_user(UpdateUserRequest {
+                user_id: bob.clone(),
+                email: Some("new@example.com".to_owned()),
+                ..Default::default()
+            })
+            .await
+            .unwrap();
+        assert!(fixture
+            .handler
+            .confirm_email_verification(&token)
+            .await
+            .is_err());
+        assert!(!is_email_verified(&fixture.handler, "bob").await);
     }
 }
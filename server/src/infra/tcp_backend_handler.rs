@@ -1,28 +1,92 @@
 use async_trait::async_trait;
+use serde::Serialize;
 use std::collections::HashSet;
 
-use crate::domain::{error::Result, types::UserId};
+use crate::domain::{error::Result, session_handler::SessionClient, types::UserId};
+
+/// A refresh token, in clear.
+#[derive(Debug, Clone)]
+pub struct RefreshToken {
+    pub token: String,
+    pub session_id: String,
+    pub duration: chrono::Duration,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct HealthStatus {
+    pub database_reachable: bool,
+    /// `None` if the database is unreachable or not initialized.
+    pub schema_version: Option<u8>,
+    pub expected_schema_version: u8,
+}
+
+impl HealthStatus {
+    /// Ready to serve traffic: the database answers, and the schema is the one this binary was
+    /// compiled against (e.g. not in the middle of a migration by another instance).
+    pub fn is_ready(&self) -> bool {
+        self.database_reachable && self.schema_version == Some(self.expected_schema_version)
+    }
+}
 
 #[async_trait]
 pub trait TcpBackendHandler {
     async fn get_jwt_blacklist(&self) -> anyhow::Result<HashSet<u64>>;
-    async fn create_refresh_token(&self, user: &UserId) -> Result<(String, chrono::Duration)>;
-    async fn check_token(&self, refresh_token_hash: u64, user: &UserId) -> Result<bool>;
+    /// The revoked sessions whose JWTs may not have expired yet.
+    async fn get_revoked_sessions(&self) -> anyhow::Result<HashSet<String>>;
+
+    /// Issues a refresh token for a new session, at login. Only the hash of the token is stored.
+    async fn create_refresh_token(
+        &self,
+        user: &UserId,
+        client: &SessionClient,
+    ) -> Result<RefreshToken>;
+
+    /// Revokes the refresh token and issues the next one of the session. A token that was already
+    /// revoked is a sign of theft: all the tokens of the session are revoked, and this fails.
+    async fn rotate_refresh_token(
+        &self,
+        token: &str,
+        user: &UserId,
+        client: &SessionClient,
+    ) -> Result<RefreshToken>;
+
     async fn blacklist_jwts(&self, user: &UserId) -> Result<HashSet<u64>>;
-    async fn delete_refresh_token(&self, refresh_token_hash: u64) -> Result<()>;
 
-    /// Request a token to reset a user's password.
+    /// Revokes the session of the refresh token, at logout. Returns the ID of the session, if the
+    /// token exists.
+    async fn revoke_refresh_token(&self, token: &str) -> Result<Option<String>>;
+
+    /// Revokes all the sessions of the user, and returns their IDs.
+    async fn revoke_all_refresh_tokens(&self, user: &UserId) -> Result<HashSet<String>>;
+
+    /// Request a short-lived token to reset a user's password, replacing the previous ones. Only
+    /// the hash of the token is stored.
     /// If the user doesn't exist, returns `Ok(None)`, otherwise `Ok(Some(token))`.
     async fn start_password_reset(&self, user: &UserId) -> Result<Option<String>>;
 
-    /// Get the user ID associated with a password reset token.
-    async fn get_user_id_for_password_reset_token(&self, token: &str) -> Result<UserId>;
+    /// Consumes the token and returns the user whose password can be reset. Fails if the token
+    /// expired or was already used.
+    async fn consume_password_reset_token(&self, token: &str) -> Result<UserId>;
 
-    async fn delete_password_reset_token(&self, token: &str) -> Result<()>;
+    /// Request a token to verify that `email` belongs to the user, replacing the previous tokens
+    /// for this email. Only the hash of the token is stored.
+    async fn start_email_verification(&self, user: &UserId, email: &str) -> Result<String>;
+
+    /// Consumes the token and marks the email of the user as verified. Fails if the token expired
+    /// or if the user changed the email since.
+    async fn confirm_email_verification(&self, token: &str) -> Result<UserId>;
+
+    /// Pings the database and checks its schema version.
+    async fn check_health(&self) -> HealthStatus;
 }
 
 #[cfg(test)]
-use crate::domain::{handler::*, types::*};
+use crate::domain::{
+    group_rule_handler::*, handler::*, maintenance_handler::*, session_handler::*,
+    sudo_role_handler::*, types::*,
+};
+#[cfg(test)]
+use std::collections::HashMap;
 #[cfg(test)]
 mockall::mock! {
     pub TestTcpBackendHandler{}
@@ -32,26 +96,68 @@ mockall::mock! {
     #[async_trait]
     impl LoginHandler for TestTcpBackendHandler {
         async fn bind(&self, request: BindRequest) -> Result<()>;
+        async fn get_scram_verifier(&self, user_id: &UserId) -> Result<Option<String>>;
+        async fn finish_scram_bind(&self, user_id: &UserId, valid_proof: bool) -> Result<()>;
     }
     #[async_trait]
     impl GroupBackendHandler for TestTcpBackendHandler {
         async fn list_groups(&self, filters: Option<GroupRequestFilter>) -> Result<Vec<Group>>;
+        async fn list_groups_page(&self, filters: Option<GroupRequestFilter>, page: Pagination) -> Result<Page<Group>>;
+        async fn count_groups(&self, filters: Option<GroupRequestFilter>) -> Result<usize>;
+        async fn list_groups_with_member_counts(&self) -> Result<Vec<(GroupDetails, usize)>>;
         async fn get_group_details(&self, group_id: GroupId) -> Result<GroupDetails>;
         async fn update_group(&self, request: UpdateGroupRequest) -> Result<()>;
         async fn create_group(&self, group_name: &str) -> Result<GroupId>;
+        async fn create_group_at(&self, group_name: &str, creation_date: DateTime) -> Result<GroupId>;
         async fn delete_group(&self, group_id: GroupId) -> Result<()>;
     }
     #[async_trait]
     impl UserBackendHandler for TestBackendHandler {
         async fn list_users(&self, filters: Option<UserRequestFilter>, get_groups: bool) -> Result<Vec<UserAndGroups>>;
+        async fn list_users_page(&self, filters: Option<UserRequestFilter>, get_groups: bool, page: Pagination) -> Result<Page<UserAndGroups>>;
+        async fn list_users_sorted(&self, filters: Option<UserRequestFilter>, get_groups: bool, sort: Vec<UserSortKey>, offset: usize, limit: Option<usize>) -> Result<Vec<UserAndGroups>>;
+        async fn count_users(&self, filters: Option<UserRequestFilter>) -> Result<usize>;
         async fn get_user_details(&self, user_id: &UserId) -> Result<User>;
         async fn create_user(&self, request: CreateUserRequest) -> Result<()>;
         async fn update_user(&self, request: UpdateUserRequest) -> Result<()>;
         async fn delete_user(&self, user_id: &UserId) -> Result<()>;
+        async fn rename_user(&self, user_id: &UserId, new_user_id: &UserId) -> Result<()>;
         async fn get_user_groups(&self, user_id: &UserId) -> Result<HashSet<GroupDetails>>;
+        async fn get_users_groups(&self, user_ids: &[UserId]) -> Result<HashMap<UserId, HashSet<GroupDetails>>>;
         async fn add_user_to_group(&self, user_id: &UserId, group_id: GroupId) -> Result<()>;
         async fn remove_user_from_group(&self, user_id: &UserId, group_id: GroupId) -> Result<()>;
+        async fn add_users_to_group(&self, group_id: GroupId, user_ids: &[UserId], all_or_nothing: bool) -> Result<Vec<MembershipChange>>;
+        async fn remove_users_from_group(&self, group_id: GroupId, user_ids: &[UserId], all_or_nothing: bool) -> Result<Vec<MembershipChange>>;
+        async fn unlock_user(&self, user_id: &UserId) -> Result<()>;
+        async fn search_users(&self, query: &str, fields: &[UserColumn], limit: usize) -> Result<Vec<User>>;
+    }
+    #[async_trait]
+    impl SudoRoleBackendHandler for TestTcpBackendHandler {
+        async fn list_sudo_roles(&self, filters: Option<SudoRoleRequestFilter>) -> Result<Vec<SudoRole>>;
+        async fn create_sudo_role(&self, request: SudoRoleRequest) -> Result<()>;
+        async fn update_sudo_role(&self, request: SudoRoleRequest) -> Result<()>;
+        async fn delete_sudo_role(&self, name: &str) -> Result<()>;
+    }
+    #[async_trait]
+    impl GroupRuleBackendHandler for TestTcpBackendHandler {
+        async fn list_group_rules(&self) -> Result<Vec<GroupRule>>;
+        async fn create_group_rule(&self, request: CreateGroupRuleRequest) -> Result<i32>;
+        async fn delete_group_rule(&self, rule_id: i32) -> Result<()>;
+        async fn reevaluate_group_rules(&self) -> Result<usize>;
+    }
+    #[async_trait]
+    impl SessionBackendHandler for TestTcpBackendHandler {
+        async fn list_sessions(&self, user_id: &UserId) -> Result<Vec<Session>>;
+        async fn get_session(&self, session_id: &str) -> Result<Session>;
+        async fn revoke_session(&self, session_id: &str) -> Result<()>;
     }
     #[async_trait]
-    impl BackendHandler for TestTcpBackendHandler {}
+    impl MaintenanceBackendHandler for TestTcpBackendHandler {
+        async fn start_maintenance(&self, dry_run: bool) -> Result<()>;
+        async fn get_maintenance_status(&self) -> Result<Option<MaintenanceStatus>>;
+    }
+    #[async_trait]
+    impl BackendHandler for TestTcpBackendHandler {
+        async fn changed_since(&self, since: Option<DateTime>) -> Result<ChangeSet>;
+    }
 }
@@ -1,13 +1,25 @@
 use crate::{
     domain::{
+        change_events::ChangeEventSender,
         error::DomainError,
         handler::{BackendHandler, LoginHandler},
+        oidc_handler::OidcHandler,
         opaque_handler::OpaqueHandler,
+        password_policy::PasswordPolicy,
     },
     infra::{
+        access_control::AttributeAcl,
         auth_service,
-        configuration::{Configuration, MailOptions},
+        client_ip::TrustedProxies,
+        configuration::{Configuration, ListenerProtocol},
+        i18n::MessageCatalog,
         logging::CustomRootSpanBuilder,
+        mail::MailSender,
+        metrics,
+        oidc::api::OidcState,
+        operation_timeout::OperationTimeouts,
+        rate_limiter::{retry_after_seconds, SharedRateLimiter},
+        security_headers::security_headers_middleware,
         tcp_backend_handler::*,
     },
 };
@@ -15,13 +27,14 @@ use actix_files::{Files, NamedFile};
 use actix_http::HttpServiceBuilder;
 use actix_server::ServerBuilder;
 use actix_service::map_config;
-use actix_web::{dev::AppConfig, web, App, HttpResponse};
+use actix_web::{dev::AppConfig, web, App, HttpRequest, HttpResponse};
 use anyhow::{Context, Result};
 use hmac::{Hmac, NewMac};
 use sha2::Sha512;
 use std::collections::HashSet;
-use std::path::PathBuf;
-use std::sync::RwLock;
+use std::net::IpAddr;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, RwLock};
 use tracing::info;
 
 async fn index() -> actix_web::Result<NamedFile> {
@@ -39,48 +52,107 @@ pub enum TcpError {
     InternalServerError(String),
     #[error("Unauthorized: `{0}`")]
     UnauthorizedError(String),
+    #[error("Too many requests, retry later")]
+    TooManyRequests(std::time::Duration),
 }
 
 pub type TcpResult<T> = std::result::Result<T, TcpError>;
 
+/// Readiness probe: fails while the database is unreachable or at an unexpected schema version.
+/// Liveness is served by `/health`, which doesn't touch the database.
+async fn readiness_handler<Backend>(data: web::Data<AppState<Backend>>) -> HttpResponse
+where
+    Backend: TcpBackendHandler + 'static,
+{
+    let status = data.backend_handler.check_health().await;
+    if status.is_ready() {
+        HttpResponse::Ok().json(status)
+    } else {
+        HttpResponse::ServiceUnavailable().json(status)
+    }
+}
+
 pub(crate) fn error_to_http_response(error: TcpError) -> HttpResponse {
     match error {
         TcpError::DomainError(ref de) => match de {
-            DomainError::AuthenticationError(_) | DomainError::AuthenticationProtocolError(_) => {
-                HttpResponse::Unauthorized()
+            DomainError::AuthenticationError(_)
+            | DomainError::AuthenticationProtocolError(_)
+            | DomainError::WebauthnError(_) => HttpResponse::Unauthorized(),
+            DomainError::PasswordChangeRequired(_) | DomainError::AccountLocked(_) => {
+                HttpResponse::Forbidden()
             }
+            DomainError::ServerBusy => HttpResponse::ServiceUnavailable(),
             DomainError::DatabaseError(_)
             | DomainError::InternalError(_)
             | DomainError::UnknownCryptoError(_) => HttpResponse::InternalServerError(),
             DomainError::Base64DecodeError(_)
             | DomainError::BinarySerializationError(_)
-            | DomainError::EntityNotFound(_) => HttpResponse::BadRequest(),
+            | DomainError::EntityNotFound(_)
+            | DomainError::InvalidAvatar(_)
+            | DomainError::InvalidEmail(_)
+            | DomainError::ValidationError(_) => HttpResponse::BadRequest(),
         },
         TcpError::BadRequest(_) => HttpResponse::BadRequest(),
         TcpError::InternalServerError(_) => HttpResponse::InternalServerError(),
         TcpError::UnauthorizedError(_) => HttpResponse::Unauthorized(),
+        TcpError::TooManyRequests(retry_after) => {
+            let mut response = HttpResponse::TooManyRequests();
+            response.insert_header(("Retry-After", retry_after_seconds(retry_after).to_string()));
+            response
+        }
     }
     .body(error.to_string())
 }
 
+#[allow(clippy::too_many_arguments)]
 fn http_config<Backend>(
     cfg: &mut web::ServiceConfig,
     backend_handler: Backend,
     jwt_secret: secstr::SecUtf8,
     jwt_blacklist: HashSet<u64>,
+    revoked_sessions: RevokedSessions,
     server_url: String,
-    mail_options: MailOptions,
+    mail: MailSender,
+    oidc_state: Option<web::Data<OidcState>>,
+    rate_limiter: SharedRateLimiter,
+    password_policy: Arc<PasswordPolicy>,
+    messages: Arc<MessageCatalog>,
+    operation_timeouts: OperationTimeouts,
+    change_events: ChangeEventSender,
+    attribute_acl: AttributeAcl,
+    trusted_proxies: TrustedProxies,
 ) where
-    Backend: TcpBackendHandler + BackendHandler + LoginHandler + OpaqueHandler + Sync + 'static,
+    Backend: TcpBackendHandler
+        + BackendHandler
+        + LoginHandler
+        + OpaqueHandler
+        + OidcHandler
+        + Sync
+        + 'static,
 {
     cfg.app_data(web::Data::new(AppState::<Backend> {
         backend_handler,
         jwt_key: Hmac::new_varkey(jwt_secret.unsecure().as_bytes()).unwrap(),
         jwt_blacklist: RwLock::new(jwt_blacklist),
+        revoked_sessions,
         server_url,
-        mail_options,
+        mail,
+        rate_limiter,
+        password_policy,
+        messages,
+        operation_timeouts,
+        change_events,
+        attribute_acl,
+        trusted_proxies: trusted_proxies.clone(),
     }))
+    // Also for the logs, before the routing.
+    .app_data(web::Data::new(trusted_proxies))
     .route("/health", web::get().to(|| HttpResponse::Ok().finish()))
+    .route(
+        "/health/live",
+        web::get().to(|| HttpResponse::Ok().finish()),
+    )
+    .route("/health/ready", web::get().to(readiness_handler::<Backend>))
     .service(web::scope("/auth").configure(auth_service::configure_server::<Backend>))
     // API endpoint.
     .service(
@@ -88,77 +160,181 @@ fn http_config<Backend>(
             .wrap(auth_service::CookieToHeaderTranslatorFactory)
             .configure(super::graphql::api::configure_endpoint::<Backend>),
     )
+    // SCIM 2.0 provisioning endpoints.
+    .service(web::scope("/scim/v2").configure(super::scim::api::configure_endpoint::<Backend>));
+    // OpenID Connect provider, before the catch-all routes.
+    if let Some(oidc_state) = oidc_state {
+        cfg.app_data(oidc_state);
+        super::oidc::api::configure_endpoint::<Backend>(cfg);
+    }
     // Serve the /pkg path with the compiled WASM app.
-    .service(Files::new("/pkg", "./app/pkg"))
-    // Serve static files
-    .service(Files::new("/static", "./app/static"))
-    // Serve static fonts
-    .service(Files::new("/static/fonts", "./app/static/fonts"))
-    // Default to serve index.html for unknown routes, to support routing.
-    .service(
-        web::scope("/")
-            .route("", web::get().to(index)) // this is necessary because the below doesn't match a request for "/"
-            .route(".*", web::get().to(index)),
-    );
+    cfg.service(Files::new("/pkg", "./app/pkg"))
+        // Serve static files
+        .service(Files::new("/static", "./app/static"))
+        // Serve static fonts
+        .service(Files::new("/static/fonts", "./app/static/fonts"))
+        // Default to serve index.html for unknown routes, to support routing.
+        .service(
+            web::scope("/")
+                .route("", web::get().to(index)) // this is necessary because the below doesn't match a request for "/"
+                .route(".*", web::get().to(index)),
+        );
 }
 
+/// The IDs of the revoked sessions, whose JWTs are refused. Shared by all the workers.
+pub type RevokedSessions = Arc<RwLock<HashSet<String>>>;
+
 pub(crate) struct AppState<Backend> {
     pub backend_handler: Backend,
     pub jwt_key: Hmac<Sha512>,
     pub jwt_blacklist: RwLock<HashSet<u64>>,
+    pub revoked_sessions: RevokedSessions,
     pub server_url: String,
-    pub mail_options: MailOptions,
+    pub mail: MailSender,
+    pub rate_limiter: SharedRateLimiter,
+    pub password_policy: Arc<PasswordPolicy>,
+    pub messages: Arc<MessageCatalog>,
+    pub operation_timeouts: OperationTimeouts,
+    pub change_events: ChangeEventSender,
+    pub attribute_acl: AttributeAcl,
+    pub trusted_proxies: TrustedProxies,
+}
+
+impl<Backend> AppState<Backend> {
+    /// The address of the client, behind the trusted proxies.
+    pub fn client_ip(&self, request: &HttpRequest) -> Option<IpAddr> {
+        self.trusted_proxies
+            .http_client_ip(request.peer_addr().map(|addr| addr.ip()), request.headers())
+    }
 }
 
 pub async fn build_tcp_server<Backend>(
     config: &Configuration,
     backend_handler: Backend,
+    rate_limiter: SharedRateLimiter,
+    change_events: ChangeEventSender,
     server_builder: ServerBuilder,
 ) -> Result<ServerBuilder>
 where
-    Backend: TcpBackendHandler + BackendHandler + LoginHandler + OpaqueHandler + Sync + 'static,
+    Backend: TcpBackendHandler
+        + BackendHandler
+        + LoginHandler
+        + OpaqueHandler
+        + OidcHandler
+        + Sync
+        + 'static,
 {
     let jwt_secret = config.jwt_secret.clone();
     let jwt_blacklist = backend_handler
         .get_jwt_blacklist()
         .await
         .context("while getting the jwt blacklist")?;
+    let revoked_sessions = Arc::new(RwLock::new(
+        backend_handler
+            .get_revoked_sessions()
+            .await
+            .context("while getting the revoked sessions")?,
+    ));
     let server_url = config.http_url.clone();
-    let mail_options = config.smtp_options.clone();
-    info!("Starting the API/web server on port {}", config.http_port);
-    server_builder
-        .bind(
-            "http",
-            (config.http_host.clone(), config.http_port),
-            move || {
-                let backend_handler = backend_handler.clone();
-                let jwt_secret = jwt_secret.clone();
-                let jwt_blacklist = jwt_blacklist.clone();
-                let server_url = server_url.clone();
-                let mail_options = mail_options.clone();
-                HttpServiceBuilder::new()
-                    .finish(map_config(
-                        App::new()
-                            .wrap(tracing_actix_web::TracingLogger::<CustomRootSpanBuilder>::new())
-                            .configure(move |cfg| {
-                                http_config(
-                                    cfg,
-                                    backend_handler,
-                                    jwt_secret,
-                                    jwt_blacklist,
-                                    server_url,
-                                    mail_options,
-                                )
-                            }),
-                        |_| AppConfig::default(),
-                    ))
-                    .tcp()
-            },
-        )
-        .with_context(|| {
-            format!(
-                "While bringing up the TCP server with port {}",
-                config.http_port
-            )
-        })
+    let mail =
+        MailSender::new(&config.smtp_options).context("while loading the email templates")?;
+    let password_policy = Arc::new(
+        PasswordPolicy::new(&config.password_policy)
+            .context("while setting up the password policy")?,
+    );
+    let messages = Arc::new(
+        MessageCatalog::load(config.translations_dir.as_deref().map(Path::new))
+            .context("while loading the translations")?,
+    );
+    let operation_timeouts = OperationTimeouts::new(&config.database_pool_options);
+    let attribute_acl = AttributeAcl::new(config.attribute_acl.clone())
+        .with_self_service_attributes(config.self_service_attributes.clone());
+    let oidc_state = if config.oidc_options.enabled {
+        info!("OpenID Connect provider enabled");
+        Some(web::Data::new(
+            OidcState::new(config).context("while loading the OIDC signing key")?,
+        ))
+    } else {
+        None
+    };
+    let security_headers = config.security_headers.clone();
+    let trusted_proxies = TrustedProxies::new(&config.proxy_options)?;
+    let factory = move || {
+        let backend_handler = backend_handler.clone();
+        let jwt_secret = jwt_secret.clone();
+        let jwt_blacklist = jwt_blacklist.clone();
+        let revoked_sessions = revoked_sessions.clone();
+        let server_url = server_url.clone();
+        let mail = mail.clone();
+        let oidc_state = oidc_state.clone();
+        let rate_limiter = rate_limiter.clone();
+        let password_policy = password_policy.clone();
+        let messages = messages.clone();
+        let change_events = change_events.clone();
+        let attribute_acl = attribute_acl.clone();
+        let trusted_proxies = trusted_proxies.clone();
+        HttpServiceBuilder::new()
+            .finish(map_config(
+                App::new()
+                    .wrap(security_headers_middleware(&security_headers))
+                    .wrap(tracing_actix_web::TracingLogger::<CustomRootSpanBuilder>::new())
+                    .configure(move |cfg| {
+                        http_config(
+                            cfg,
+                            backend_handler,
+                            jwt_secret,
+                            jwt_blacklist,
+                            revoked_sessions,
+                            server_url,
+                            mail,
+                            oidc_state,
+                            rate_limiter,
+                            password_policy,
+                            messages,
+                            operation_timeouts,
+                            change_events,
+                            attribute_acl,
+                            trusted_proxies,
+                        )
+                    }),
+                |_| AppConfig::default(),
+            ))
+            .tcp()
+    };
+    // The metrics are served apart from the API, to keep them private without authentication.
+    let metrics_factory = || {
+        HttpServiceBuilder::new()
+            .finish(map_config(
+                App::new().route("/metrics", web::get().to(metrics::metrics_handler)),
+                |_| AppConfig::default(),
+            ))
+            .tcp()
+    };
+    let mut server_builder = server_builder;
+    for listener in config.get_listeners() {
+        server_builder = match listener.protocol {
+            ListenerProtocol::Http => {
+                info!("Starting the API/web server on {}", listener.address());
+                server_builder
+                    .bind(
+                        "http",
+                        (listener.host.clone(), listener.port),
+                        factory.clone(),
+                    )
+                    .with_context(|| format!("while binding HTTP to {}", listener.address()))?
+            }
+            ListenerProtocol::Metrics => {
+                info!("Starting the metrics server on {}", listener.address());
+                server_builder
+                    .bind(
+                        "metrics",
+                        (listener.host.clone(), listener.port),
+                        metrics_factory,
+                    )
+                    .with_context(|| format!("while binding metrics to {}", listener.address()))?
+            }
+            ListenerProtocol::Ldap | ListenerProtocol::Ldaps => continue,
+        };
+    }
+    Ok(server_builder)
 }
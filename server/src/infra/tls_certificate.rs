@@ -0,0 +1,328 @@
+//! The certificate of LDAPS and StartTLS. It's reloaded when its files change or on SIGHUP, so
+//! that a renewed certificate is used without a restart: the new handshakes get the new
+//! certificate, and the open connections keep theirs.
+use super::ldap_controls::{elements, split_element, SEQUENCE_TAG};
+use anyhow::{anyhow, bail, ensure, Context, Result};
+use rsa::{
+    pkcs1::{DecodeRsaPrivateKey, DecodeRsaPublicKey},
+    pkcs8::DecodePrivateKey,
+    PublicKeyParts, RsaPrivateKey, RsaPublicKey,
+};
+use rustls::{
+    server::{ClientHello, ResolvesServerCert},
+    sign::CertifiedKey,
+    Certificate, PrivateKey,
+};
+use std::{
+    sync::{Arc, Mutex, RwLock},
+    time::{Duration, SystemTime},
+};
+use tracing::{error, info};
+
+const VERSION_TAG: u8 = 0xA0;
+const BIT_STRING_TAG: u8 = 0x03;
+
+pub(crate) fn read_private_key(key_file: &str) -> Result<PrivateKey> {
+    use rustls_pemfile::{pkcs8_private_keys, rsa_private_keys};
+    use std::{fs::File, io::BufReader};
+    pkcs8_private_keys(&mut BufReader::new(File::open(key_file)?))
+        .map_err(anyhow::Error::from)
+        .and_then(|keys| {
+            keys.into_iter()
+                .next()
+                .ok_or_else(|| anyhow!("No PKCS8 key"))
+        })
+        .or_else(|_| {
+            rsa_private_keys(&mut BufReader::new(File::open(key_file)?))
+                .map_err(anyhow::Error::from)
+                .and_then(|keys| {
+                    keys.into_iter()
+                        .next()
+                        .ok_or_else(|| anyhow!("No PKCS1 key"))
+                })
+        })
+        .with_context(|| {
+            format!(
+                "Cannot read either PKCS1 or PKCS8 private key from {}",
+                key_file
+            )
+        })
+        .map(rustls::PrivateKey)
+}
+
+fn read_certificates(cert_file: &str) -> Result<Vec<Certificate>> {
+    use rustls_pemfile::certs;
+    use std::{fs::File, io::BufReader};
+    let certificates = certs(&mut BufReader::new(
+        File::open(cert_file).with_context(|| format!("Cannot open {}", cert_file))?,
+    ))?;
+    ensure!(!certificates.is_empty(), "No certificate in {}", cert_file);
+    Ok(certificates.into_iter().map(Certificate).collect())
+}
+
+/// The RSA public key of a DER certificate, from its `subjectPublicKeyInfo ::= SEQUENCE {
+/// algorithm, subjectPublicKey BIT STRING }`. `None` for the other types of keys.
+fn rsa_public_key(certificate: &[u8]) -> Option<RsaPublicKey> {
+    let certificate = match split_element(certificate)? {
+        (SEQUENCE_TAG, certificate, _) => certificate,
+        _ => return None,
+    };
+    let tbs_certificate = match split_element(certificate)? {
+        (SEQUENCE_TAG, tbs_certificate, _) => tbs_certificate,
+        _ => return None,
+    };
+    let public_key_info = match elements(tbs_certificate)
+        .filter(|(tag, _)| *tag != VERSION_TAG)
+        .nth(5)?
+    {
+        (SEQUENCE_TAG, public_key_info) => public_key_info,
+        _ => return None,
+    };
+    match elements(public_key_info).nth(1)? {
+        // The first byte is the number of unused bits.
+        (BIT_STRING_TAG, [0, public_key @ ..]) => RsaPublicKey::from_pkcs1_der(public_key).ok(),
+        _ => None,
+    }
+}
+
+/// Only the RSA keys are compared: for the other types, a mismatch fails the handshakes.
+fn check_key_matches(certificate: &Certificate, key: &PrivateKey) -> Result<()> {
+    let private_key =
+        RsaPrivateKey::from_pkcs8_der(&key.0).or_else(|_| RsaPrivateKey::from_pkcs1_der(&key.0));
+    match (rsa_public_key(&certificate.0), private_key) {
+        (None, Err(_)) => Ok(()),
+        (Some(public_key), Ok(private_key))
+            if public_key.n() == private_key.n() && public_key.e() == private_key.e() =>
+        {
+            Ok(())
+        }
+        _ => bail!("The private key doesn't match the certificate"),
+    }
+}
+
+/// Reads the certificate chain and its key, and checks that they match.
+pub fn load_certified_key(cert_file: &str, key_file: &str) -> Result<CertifiedKey> {
+    let certificates = read_certificates(cert_file)?;
+    let key = read_private_key(key_file)?;
+    check_key_matches(&certificates[0], &key)
+        .with_context(|| format!("with {} and {}", cert_file, key_file))?;
+    let signing_key = rustls::sign::any_supported_type(&key)
+        .map_err(|_| anyhow!("Unsupported private key type in {}", key_file))?;
+    Ok(CertifiedKey::new(certificates, signing_key))
+}
+
+fn modification_times(files: [&str; 2]) -> Option<[SystemTime; 2]> {
+    let modified = |file| std::fs::metadata(file).and_then(|m| m.modified()).ok();
+    Some([modified(files[0])?, modified(files[1])?])
+}
+
+/// The certificate served to the new handshakes.
+pub struct ReloadableCertificate {
+    cert_file: String,
+    key_file: String,
+    current: RwLock<Arc<CertifiedKey>>,
+    /// Of the files, at the last reload attempt.
+    modified: Mutex<Option<[SystemTime; 2]>>,
+}
+
+impl ReloadableCertificate {
+    pub fn new(cert_file: &str, key_file: &str) -> Result<Self> {
+        let modified = modification_times([cert_file, key_file]);
+        Ok(Self {
+            cert_file: cert_file.to_owned(),
+            key_file: key_file.to_owned(),
+            current: RwLock::new(Arc::new(load_certified_key(cert_file, key_file)?)),
+            modified: Mutex::new(modified),
+        })
+    }
+
+    /// On error, the current certificate is kept.
+    pub fn reload(&self) -> Result<()> {
+        *self.modified.lock().unwrap() = modification_times([&self.cert_file, &self.key_file]);
+        let certified_key = load_certified_key(&self.cert_file, &self.key_file)?;
+        *self.current.write().unwrap() = Arc::new(certified_key);
+        Ok(())
+    }
+
+    fn is_modified(&self) -> bool {
+        *self.modified.lock().unwrap() != modification_times([&self.cert_file, &self.key_file])
+    }
+
+    fn reload_and_log(&self) {
+        match self.reload() {
+            Ok(()) => info!("Reloaded the certificate {}", &self.cert_file),
+            Err(e) => error!(
+                "Could not reload the certificate, keeping the current one: {:#}",
+                e
+            ),
+        }
+    }
+}
+
+impl ResolvesServerCert for ReloadableCertificate {
+    fn resolve(&self, _: ClientHello) -> Option<Arc<CertifiedKey>> {
+        Some(self.current.read().unwrap().clone())
+    }
+}
+
+#[cfg(unix)]
+type HangupSignal = tokio::signal::unix::Signal;
+#[cfg(not(unix))]
+type HangupSignal = std::convert::Infallible;
+
+#[cfg(unix)]
+fn hangup_signal() -> Option<HangupSignal> {
+    use tokio::signal::unix::{signal, SignalKind};
+    signal(SignalKind::hangup())
+        .map_err(|e| error!("Could not listen to SIGHUP: {:#}", e))
+        .ok()
+}
+
+#[cfg(not(unix))]
+fn hangup_signal() -> Option<HangupSignal> {
+    None
+}
+
+async fn next_hangup(signal: &mut Option<HangupSignal>) {
+    #[cfg(unix)]
+    if let Some(signal) = signal {
+        if signal.recv().await.is_some() {
+            return;
+        }
+    }
+    #[cfg(not(unix))]
+    let _ = signal;
+    std::future::pending().await
+}
+
+async fn next_tick(interval: &mut Option<tokio::time::Interval>) {
+    match interval {
+        Some(interval) => {
+            interval.tick().await;
+        }
+        None => std::future::pending().await,
+    }
+}
+
+/// Reloads the certificate on SIGHUP, and when its files change, checked every `poll_interval`.
+pub async fn watch_certificate(
+    certificate: Arc<ReloadableCertificate>,
+    poll_interval: Option<Duration>,
+) {
+    let mut hangup = hangup_signal();
+    let mut interval = poll_interval.map(tokio::time::interval);
+    loop {
+        tokio::select! {
+            _ = next_hangup(&mut hangup) => certificate.reload_and_log(),
+            _ = next_tick(&mut interval) => {
+                if certificate.is_modified() {
+                    certificate.reload_and_log();
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio_rustls::{TlsAcceptor, TlsConnector};
+
+    const FIRST_CERT: &str = include_str!("testdata/first_cert.pem");
+    const FIRST_KEY: &str = include_str!("testdata/first_key.pem");
+    const SECOND_CERT: &str = include_str!("testdata/second_cert.pem");
+    const SECOND_KEY: &str = include_str!("testdata/second_key.pem");
+
+    fn write_files(dir: &std::path::Path, cert: &str, key: &str) {
+        std::fs::write(dir.join("cert.pem"), cert).unwrap();
+        std::fs::write(dir.join("key.pem"), key).unwrap();
+    }
+
+    fn der(pem: &str) -> Certificate {
+        Certificate(
+            rustls_pemfile::certs(&mut pem.as_bytes())
+                .unwrap()
+                .remove(0),
+        )
+    }
+
+    /// Whether a client that only trusts `trusted` completes a handshake with the acceptor.
+    async fn handshake(acceptor: &TlsAcceptor, trusted: &str) -> bool {
+        let mut roots = rustls::RootCertStore::empty();
+        roots.add(&der(trusted)).unwrap();
+        let connector = TlsConnector::from(Arc::new(
+            rustls::ClientConfig::builder()
+                .with_safe_defaults()
+                .with_root_certificates(roots)
+                .with_no_client_auth(),
+        ));
+        let (client, server) = tokio::io::duplex(16384);
+        let acceptor = acceptor.clone();
+        let server = tokio::spawn(async move {
+            let mut stream = acceptor.accept(server).await.ok()?;
+            let mut buffer = [0; 4];
+            stream.read_exact(&mut buffer).await.ok()?;
+            Some(buffer)
+        });
+        let client = async {
+            let mut stream = connector
+                .connect(rustls::ServerName::try_from("localhost").unwrap(), client)
+                .await
+                .ok()?;
+            stream.write_all(b"ping").await.ok()?;
+            stream.flush().await.ok()
+        };
+        let client_result = client.await;
+        let server_result = server.await.unwrap();
+        client_result.is_some() && server_result == Some(*b"ping")
+    }
+
+    #[tokio::test]
+    async fn test_reload_certificate() {
+        let dir = std::env::temp_dir().join(format!("lldap-tls-{}", uuid::Uuid::new_v4()));
+        std::fs::create_dir(&dir).unwrap();
+        let cert_file = dir.join("cert.pem").to_str().unwrap().to_owned();
+        let key_file = dir.join("key.pem").to_str().unwrap().to_owned();
+        write_files(&dir, FIRST_CERT, FIRST_KEY);
+        let certificate = Arc::new(ReloadableCertificate::new(&cert_file, &key_file).unwrap());
+        assert!(!certificate.is_modified());
+        let acceptor = TlsAcceptor::from(Arc::new(
+            rustls::ServerConfig::builder()
+                .with_safe_defaults()
+                .with_no_client_auth()
+                .with_cert_resolver(certificate.clone()),
+        ));
+        assert!(handshake(&acceptor, FIRST_CERT).await);
+        assert!(!handshake(&acceptor, SECOND_CERT).await);
+
+        // A mismatched key: the current certificate is kept.
+        write_files(&dir, SECOND_CERT, FIRST_KEY);
+        certificate.reload().unwrap_err();
+        assert!(handshake(&acceptor, FIRST_CERT).await);
+
+        // The same acceptor serves the new certificate.
+        write_files(&dir, SECOND_CERT, SECOND_KEY);
+        certificate.reload().unwrap();
+        assert!(!certificate.is_modified());
+        assert!(handshake(&acceptor, SECOND_CERT).await);
+        assert!(!handshake(&acceptor, FIRST_CERT).await);
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_check_key_matches() {
+        let key = |pem: &str| {
+            PrivateKey(
+                rustls_pemfile::pkcs8_private_keys(&mut pem.as_bytes())
+                    .unwrap()
+                    .remove(0),
+            )
+        };
+        assert!(rsa_public_key(&der(FIRST_CERT).0).is_some());
+        check_key_matches(&der(FIRST_CERT), &key(FIRST_KEY)).unwrap();
+        check_key_matches(&der(SECOND_CERT), &key(SECOND_KEY)).unwrap();
+        check_key_matches(&der(FIRST_CERT), &key(SECOND_KEY)).unwrap_err();
+    }
+}
@@ -0,0 +1,179 @@
+//! The TLS versions and cipher suites accepted by LDAPS and StartTLS, from `ldaps_options`.
+use crate::infra::configuration::{LdapsOptions, TlsVersion};
+use anyhow::{bail, Result};
+use rustls::{
+    version, ConfigBuilder, ProtocolVersion, ServerConfig, SupportedCipherSuite,
+    SupportedProtocolVersion, WantsVerifier,
+};
+
+const VERSIONS: [TlsVersion; 2] = [TlsVersion::Tls13, TlsVersion::Tls12];
+
+impl TlsVersion {
+    fn rustls_version(self) -> &'static SupportedProtocolVersion {
+        match self {
+            TlsVersion::Tls12 => &version::TLS12,
+            TlsVersion::Tls13 => &version::TLS13,
+        }
+    }
+}
+
+fn version_name(version: ProtocolVersion) -> String {
+    VERSIONS
+        .into_iter()
+        .find(|v| v.rustls_version().version == version)
+        .map_or_else(|| format!("{:?}", version), |v| v.as_str().to_owned())
+}
+
+/// The IANA name of the cipher suite, e.g. `TLS13_AES_256_GCM_SHA384`.
+fn cipher_suite_name(suite: &SupportedCipherSuite) -> String {
+    format!("{:?}", suite.suite())
+}
+
+fn find_cipher_suite(name: &str) -> Result<SupportedCipherSuite> {
+    match rustls::ALL_CIPHER_SUITES
+        .iter()
+        .find(|suite| cipher_suite_name(suite).eq_ignore_ascii_case(name))
+    {
+        Some(suite) => Ok(*suite),
+        None => bail!(
+            "Unknown cipher suite `{}` in `ldaps_options.cipher_suites`, expected one of {}",
+            name,
+            rustls::ALL_CIPHER_SUITES
+                .iter()
+                .map(cipher_suite_name)
+                .collect::<Vec<_>>()
+                .join(", ")
+        ),
+    }
+}
+
+/// The accepted versions, from the most recent, and cipher suites.
+#[derive(Debug)]
+pub struct TlsPolicy {
+    pub versions: Vec<&'static SupportedProtocolVersion>,
+    pub cipher_suites: Vec<SupportedCipherSuite>,
+}
+
+impl TlsPolicy {
+    /// Fails if a cipher suite is unknown or excluded by the minimum version, rather than
+    /// silently accepting less than configured.
+    pub fn new(options: &LdapsOptions) -> Result<Self> {
+        let versions = VERSIONS
+            .into_iter()
+            .filter(|v| *v >= options.min_tls_version)
+            .map(TlsVersion::rustls_version)
+            .collect::<Vec<_>>();
+        let is_accepted = |suite: &SupportedCipherSuite| {
+            versions
+                .iter()
+                .any(|v| v.version == suite.version().version)
+        };
+        if options.cipher_suites.is_empty() {
+            return Ok(Self {
+                cipher_suites: rustls::DEFAULT_CIPHER_SUITES
+                    .iter()
+                    .filter(|suite| is_accepted(suite))
+                    .copied()
+                    .collect(),
+                versions,
+            });
+        }
+        let cipher_suites = options
+            .cipher_suites
+            .iter()
+            .map(|name| find_cipher_suite(name))
+            .collect::<Result<Vec<_>>>()?;
+        if let Some(suite) = cipher_suites.iter().find(|suite| !is_accepted(suite)) {
+            bail!(
+                "The cipher suite `{}` of `ldaps_options.cipher_suites` is for {}, which \
+                 `ldaps_options.min_tls_version = \"{}\"` excludes",
+                cipher_suite_name(suite),
+                version_name(suite.version().version),
+                options.min_tls_version.as_str()
+            );
+        }
+        Ok(Self {
+            versions,
+            cipher_suites,
+        })
+    }
+
+    pub fn server_config_builder(&self) -> Result<ConfigBuilder<ServerConfig, WantsVerifier>> {
+        Ok(ServerConfig::builder()
+            .with_cipher_suites(&self.cipher_suites)
+            .with_safe_default_kx_groups()
+            .with_protocol_versions(&self.versions)?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::infra::configuration::LdapsOptionsBuilder;
+
+    fn policy(min_tls_version: TlsVersion, cipher_suites: &[&str]) -> Result<TlsPolicy> {
+        TlsPolicy::new(
+            &LdapsOptionsBuilder::default()
+                .min_tls_version(min_tls_version)
+                .cipher_suites(cipher_suites.iter().map(|s| s.to_string()).collect())
+                .build()
+                .unwrap(),
+        )
+    }
+
+    fn names(policy: &TlsPolicy) -> Vec<String> {
+        policy.cipher_suites.iter().map(cipher_suite_name).collect()
+    }
+
+    #[test]
+    fn test_default_policy() {
+        let default = policy(TlsVersion::Tls12, &[]).unwrap();
+        assert_eq!(default.versions.len(), 2);
+        assert_eq!(
+            default.cipher_suites.len(),
+            rustls::DEFAULT_CIPHER_SUITES.len()
+        );
+        default.server_config_builder().unwrap();
+        let modern = policy(TlsVersion::Tls13, &[]).unwrap();
+        assert_eq!(modern.versions.len(), 1);
+        assert!(names(&modern).iter().all(|name| name.starts_with("TLS13_")));
+        modern.server_config_builder().unwrap();
+    }
+
+    #[test]
+    fn test_cipher_suites() {
+        let restricted = policy(
+            TlsVersion::Tls12,
+            &[
+                "TLS13_AES_256_GCM_SHA384",
+                "tls_ecdhe_rsa_with_aes_256_gcm_sha384",
+            ],
+        )
+        .unwrap();
+        assert_eq!(
+            names(&restricted),
+            vec![
+                "TLS13_AES_256_GCM_SHA384",
+                "TLS_ECDHE_RSA_WITH_AES_256_GCM_SHA384"
+            ]
+        );
+        restricted.server_config_builder().unwrap();
+        let error = policy(TlsVersion::Tls12, &["TLS_RSA_WITH_RC4_128_MD5"]).unwrap_err();
+        assert!(error.to_string().contains("Unknown cipher suite"));
+        // TLS 1.3 with a TLS 1.2 suite.
+        let error = policy(
+            TlsVersion::Tls13,
+            &[
+                "TLS13_AES_256_GCM_SHA384",
+                "TLS_ECDHE_RSA_WITH_AES_256_GCM_SHA384",
+            ],
+        )
+        .unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "The cipher suite `TLS_ECDHE_RSA_WITH_AES_256_GCM_SHA384` of \
+             `ldaps_options.cipher_suites` is for TLSv1.2, which \
+             `ldaps_options.min_tls_version = \"TLSv1.3\"` excludes"
+        );
+    }
+}
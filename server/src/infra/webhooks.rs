@@ -0,0 +1,185 @@
+use crate::{
+    domain::{
+        model::webhook_deliveries,
+        sql_tables::DbConnection,
+        sql_webhook_queue::{
+            delete_webhook_delivery, get_due_webhook_deliveries, reschedule_webhook_delivery,
+        },
+    },
+    infra::configuration::WebhookOptions,
+};
+use actix::{
+    fut::ActorFutureExt,
+    prelude::{Actor, AsyncContext, Context},
+};
+use hmac::{Hmac, Mac, NewMac};
+use sha2::Sha256;
+use std::time::Duration;
+use tracing::{error, info, instrument, warn};
+
+const POLL_INTERVAL: Duration = Duration::from_secs(5);
+const BATCH_SIZE: u64 = 50;
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+const FIRST_RETRY_DELAY_SECONDS: i64 = 10;
+const MAX_RETRY_DELAY_SECONDS: i64 = 3600;
+
+/// Sends the queued webhook deliveries, retrying the failed ones with an exponential backoff.
+pub struct WebhookDispatcher {
+    sql_pool: DbConnection,
+    options: WebhookOptions,
+    client: reqwest::Client,
+}
+
+impl Actor for WebhookDispatcher {
+    type Context = Context<Self>;
+
+    fn started(&mut self, context: &mut Context<Self>) {
+        info!("Webhook dispatcher started");
+        self.schedule_task(context);
+    }
+
+    fn stopped(&mut self, _ctx: &mut Context<Self>) {
+        info!("Webhook dispatcher stopped");
+    }
+}
+
+/// The value of the `X-Lldap-Signature` header: the HMAC-SHA256 of the body, keyed with the
+/// shared secret.
+pub fn sign_payload(secret: &str, payload: &str) -> String {
+    let mut mac =
+        Hmac::<Sha256>::new_varkey(secret.as_bytes()).expect("HMAC accepts keys of any size");
+    mac.update(payload.as_bytes());
+    let signature: String = mac
+        .finalize()
+        .into_bytes()
+        .iter()
+        .map(|b| format!("{:02x}", b))
+        .collect();
+    format!("sha256={}", signature)
+}
+
+/// How long to wait after the given number of failed attempts.
+fn retry_delay(failed_attempts: i32) -> chrono::Duration {
+    let exponent = (failed_attempts.max(1) - 1).min(20) as u32;
+    chrono::Duration::seconds(
+        FIRST_RETRY_DELAY_SECONDS
+            .saturating_mul(1 << exponent)
+            .min(MAX_RETRY_DELAY_SECONDS),
+    )
+}
+
+impl WebhookDispatcher {
+    pub fn new(sql_pool: DbConnection, options: WebhookOptions) -> Self {
+        Self {
+            sql_pool,
+            options,
+            client: reqwest::Client::builder()
+                .timeout(REQUEST_TIMEOUT)
+                .build()
+                .expect("Could not build the webhook HTTP client"),
+        }
+    }
+
+    /// The next poll is only scheduled once the current one is done, so that a slow receiver
+    /// doesn't get the same delivery twice.
+    fn schedule_task(&self, ctx: &mut Context<Self>) {
+        let future = actix::fut::wrap_future::<_, Self>(Self::send_due_deliveries(
+            self.sql_pool.clone(),
+            self.options.clone(),
+            self.client.clone(),
+        ))
+        .map(|_, _this, ctx: &mut Context<Self>| {
+            ctx.run_later(POLL_INTERVAL, move |this, ctx| this.schedule_task(ctx));
+        });
+        ctx.spawn(future);
+    }
+
+    #[instrument(skip_all, level = "debug")]
+    async fn send_due_deliveries(
+        sql_pool: DbConnection,
+        options: WebhookOptions,
+        client: reqwest::Client,
+    ) {
+        let deliveries =
+            match get_due_webhook_deliveries(&sql_pool, chrono::Utc::now(), BATCH_SIZE).await {
+                Ok(deliveries) => deliveries,
+                Err(e) => {
+                    error!("DB error while fetching the webhook deliveries: {}", e);
+                    return;
+                }
+            };
+        for delivery in deliveries {
+            let id = delivery.id;
+            let attempts = delivery.attempts + 1;
+            let result = match send_delivery(&client, options.secret.unsecure(), &delivery).await {
+                Ok(()) => delete_webhook_delivery(&sql_pool, id).await,
+                Err(e) if attempts >= options.max_attempts as i32 => {
+                    error!(
+                        "Giving up on the {} webhook to {} after {} attempts: {}",
+                        delivery.event_type, delivery.url, attempts, e
+                    );
+                    delete_webhook_delivery(&sql_pool, id).await
+                }
+                Err(e) => {
+                    warn!(
+                        "Could not send the {} webhook to {} (attempt {}): {}",
+                        delivery.event_type, delivery.url, attempts, e
+                    );
+                    reschedule_webhook_delivery(
+                        &sql_pool,
+                        id,
+                        chrono::Utc::now() + retry_delay(attempts),
+                        e,
+                    )
+                    .await
+                }
+            };
+            if let Err(e) = result {
+                error!("DB error while updating the webhook delivery {}: {}", id, e);
+            }
+        }
+    }
+}
+
+async fn send_delivery(
+    client: &reqwest::Client,
+    secret: &str,
+    delivery: &webhook_deliveries::Model,
+) -> Result<(), String> {
+    client
+        .post(&delivery.url)
+        .header(reqwest::header::CONTENT_TYPE, "application/json")
+        .header("X-Lldap-Event", &delivery.event_type)
+        .header("X-Lldap-Delivery", delivery.id.to_string())
+        .header("X-Lldap-Signature", sign_payload(secret, &delivery.payload))
+        .body(delivery.payload.clone())
+        .send()
+        .await
+        .and_then(reqwest::Response::error_for_status)
+        .map(|_| ())
+        .map_err(|e| e.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_sign_payload() {
+        // Reference value from `echo -n '{}' | openssl dgst -sha256 -hmac secret`.
+        assert_eq!(
+            sign_payload("secret", "{}"),
+            "sha256=77325902caca812dc259733aacd046b73817372c777b8d95b402647474516e13"
+        );
+        assert_ne!(sign_payload("secret", "{}"), sign_payload("other", "{}"));
+    }
+
+    #[test]
+    fn test_retry_delay() {
+        assert_eq!(retry_delay(1), chrono::Duration::seconds(10));
+        assert_eq!(retry_delay(2), chrono::Duration::seconds(20));
+        assert_eq!(retry_delay(4), chrono::Duration::seconds(80));
+        assert_eq!(retry_delay(10), chrono::Duration::hours(1));
+        assert_eq!(retry_delay(1000), chrono::Duration::hours(1));
+    }
+}
@@ -5,16 +5,19 @@
 use std::time::Duration;
 
 use crate::{
-    domain::{
-        handler::{CreateUserRequest, GroupBackendHandler, GroupRequestFilter, UserBackendHandler},
-        sql_backend_handler::SqlBackendHandler,
-        sql_opaque_handler::register_password,
+    domain::{sql_backend_handler::SqlBackendHandler, sql_tables::DbConnection},
+    infra::{
+        cli::*,
+        configuration::{Configuration, DatabasePoolOptions},
+        db_cleaner::Scheduler,
+        healthcheck, mail,
+        shutdown::ShutdownCoordinator,
+        webhooks::WebhookDispatcher,
     },
-    infra::{cli::*, configuration::Configuration, db_cleaner::Scheduler, healthcheck, mail},
 };
 use actix::Actor;
 use actix_server::ServerBuilder;
-use anyhow::{anyhow, Context, Result};
+use anyhow::{anyhow, bail, Context, Result};
 use futures_util::TryFutureExt;
 use sea_orm::Database;
 use tracing::*;
@@ -22,105 +25,138 @@ use tracing::*;
 mod domain;
 mod infra;
 
-async fn create_admin_user(handler: &SqlBackendHandler, config: &Configuration) -> Result<()> {
-    let pass_length = config.ldap_user_pass.unsecure().len();
-    assert!(
-        pass_length >= 8,
-        "Minimum password length is 8 characters, got {} characters",
-        pass_length
-    );
-    handler
-        .create_user(CreateUserRequest {
-            user_id: config.ldap_user_dn.clone(),
-            email: config.ldap_user_email.clone(),
-            display_name: Some("Administrator".to_string()),
-            ..Default::default()
-        })
-        .and_then(|_| register_password(handler, &config.ldap_user_dn, &config.ldap_user_pass))
-        .await
-        .context("Error creating admin user")?;
-    let groups = handler
-        .list_groups(Some(GroupRequestFilter::DisplayName(
-            "lldap_admin".to_owned(),
-        )))
-        .await?;
-    assert_eq!(groups.len(), 1);
-    handler
-        .add_user_to_group(&config.ldap_user_dn, groups[0].id)
-        .await
-        .context("Error adding admin user to group")
-}
-
-async fn ensure_group_exists(handler: &SqlBackendHandler, group_name: &str) -> Result<()> {
-    if handler
-        .list_groups(Some(GroupRequestFilter::DisplayName(group_name.to_owned())))
-        .await?
-        .is_empty()
-    {
-        warn!("Could not find {} group, trying to create it", group_name);
-        handler
-            .create_group(group_name)
-            .await
-            .context(format!("while creating {} group", group_name))?;
+async fn connect_to_database(
+    database_url: &str,
+    options: &DatabasePoolOptions,
+) -> Result<DbConnection> {
+    let mut sql_opt = sea_orm::ConnectOptions::new(database_url.to_owned());
+    let seconds = |seconds: u64| (seconds > 0).then(|| Duration::from_secs(seconds));
+    sql_opt
+        .max_connections(options.max_connections)
+        .min_connections(options.min_idle_connections)
+        .acquire_timeout(Duration::from_secs(options.acquire_timeout_seconds));
+    if let Some(idle_timeout) = seconds(options.idle_timeout_seconds) {
+        sql_opt.idle_timeout(idle_timeout);
     }
-    Ok(())
+    if let Some(max_lifetime) = seconds(options.max_lifetime_seconds) {
+        sql_opt.max_lifetime(max_lifetime);
+    }
+    sql_opt
+        .sqlx_logging(true)
+        .sqlx_logging_level(log::LevelFilter::Debug);
+    Ok(Database::connect(sql_opt).await?)
 }
 
+/// Also returns the DB pool, to close it once the server is stopped.
 #[instrument(skip_all)]
-async fn set_up_server(config: Configuration) -> Result<ServerBuilder> {
+async fn set_up_server(
+    config: Configuration,
+    shutdown: ShutdownCoordinator,
+) -> Result<(ServerBuilder, DbConnection)> {
     info!("Starting LLDAP version {}", env!("CARGO_PKG_VERSION"));
 
-    let sql_pool = {
-        let mut sql_opt = sea_orm::ConnectOptions::new(config.database_url.clone());
-        sql_opt
-            .max_connections(5)
-            .sqlx_logging(true)
-            .sqlx_logging_level(log::LevelFilter::Debug);
-        Database::connect(sql_opt).await?
-    };
-    domain::sql_tables::init_table(&sql_pool)
+    let mut sql_pool =
+        connect_to_database(&config.database_url, &config.database_pool_options).await?;
+    infra::metrics::record_db_pool_size(config.database_pool_options.max_connections);
+    sql_pool.set_metric_callback(infra::metrics::record_db_query);
+    domain::sql_tables::init_table(&sql_pool, config.uuid_backfill)
         .await
         .context("while creating the tables")?;
-    let backend_handler = SqlBackendHandler::new(config.clone(), sql_pool.clone());
-    ensure_group_exists(&backend_handler, "lldap_admin").await?;
-    ensure_group_exists(&backend_handler, "lldap_password_manager").await?;
-    ensure_group_exists(&backend_handler, "lldap_strict_readonly").await?;
-    if let Err(e) = backend_handler.get_user_details(&config.ldap_user_dn).await {
-        warn!("Could not get admin user, trying to create it: {:#}", e);
-        create_admin_user(&backend_handler, &config)
+    if config.case_insensitive_emails || config.email_policy.normalize {
+        domain::sql_migrations::normalize_emails(&sql_pool, |email| {
+            domain::email_policy::normalize_email(&config, email)
+        })
+        .await
+        .context("while normalizing the emails")?;
+    }
+    domain::sql_posix_numbers::assign_missing_posix_numbers(&sql_pool, &config.posix_options)
+        .await
+        .context("while allocating the POSIX numbers")?;
+    let mut backend_handler = SqlBackendHandler::new(config.clone(), sql_pool.clone());
+    if let Some(key_file) = &config.totp_encryption_key_file {
+        let keys = domain::totp_secret::TotpSecretKeys::from_file(key_file)?;
+        domain::totp_secret::encrypt_totp_secrets(&sql_pool, &keys)
             .await
-            .map_err(|e| anyhow!("Error setting up admin login/account: {:#}", e))
-            .context("while creating the admin user")?;
+            .context("while encrypting the TOTP secrets")?;
+        backend_handler = backend_handler.with_totp_keys(keys);
     }
+    if let Some(database_replica_url) = &config.database_replica_url {
+        info!("Reading the user and group listings from the replica");
+        let mut read_sql_pool =
+            connect_to_database(database_replica_url, &config.database_pool_options).await?;
+        read_sql_pool.set_metric_callback(infra::metrics::record_db_query);
+        backend_handler = backend_handler.with_read_replica(read_sql_pool);
+    }
+    backend_handler = backend_handler.with_auth_backends(
+        infra::ldap_auth_backend::build_auth_backends(&config.auth_backends)?,
+    );
+    domain::bootstrap::bootstrap(&backend_handler, &config)
+        .await
+        .map_err(|e| anyhow!("Error setting up admin login/account: {:#}", e))?;
+    // Shared by LDAP and HTTP, so that switching protocols doesn't give more attempts.
+    let rate_limiter = infra::rate_limiter::build_rate_limiter(&config.rate_limit_options);
     let server_builder = infra::ldap_server::build_ldap_server(
         &config,
         backend_handler.clone(),
+        rate_limiter.clone(),
+        shutdown,
         actix_server::Server::build(),
     )
     .context("while binding the LDAP server")?;
     infra::jwt_sql_tables::init_table(&sql_pool).await?;
-    let server_builder =
-        infra::tcp_server::build_tcp_server(&config, backend_handler, server_builder)
-            .await
-            .context("while binding the TCP server")?;
+    let change_events = backend_handler.change_events();
+    let server_builder = infra::tcp_server::build_tcp_server(
+        &config,
+        backend_handler,
+        rate_limiter,
+        change_events,
+        server_builder,
+    )
+    .await
+    .context("while binding the TCP server")?;
     // Run every hour.
-    let scheduler = Scheduler::new("0 0 * * * * *", sql_pool);
+    let deleted_users_retention = config
+        .soft_delete_users
+        .then(|| chrono::Duration::days(config.deleted_users_retention_days.into()));
+    let scheduler = Scheduler::new(
+        "0 0 * * * * *",
+        sql_pool.clone(),
+        deleted_users_retention,
+        chrono::Duration::days(config.deletion_tombstones_retention_days.into()),
+    );
     scheduler.start();
-    Ok(server_builder)
+    if !config.webhook_options.urls.is_empty() {
+        WebhookDispatcher::new(sql_pool.clone(), config.webhook_options.clone()).start();
+    }
+    Ok((server_builder, sql_pool))
 }
 
 async fn run_server(config: Configuration) -> Result<()> {
-    set_up_server(config)
-        .await?
+    let grace_period = config.shutdown_grace_period_seconds;
+    let shutdown = ShutdownCoordinator::new();
+    let (server_builder, sql_pool) = set_up_server(config, shutdown.clone()).await?;
+    let server = server_builder
         .workers(1)
-        .run()
-        .await
-        .context("while starting the server")?;
+        .disable_signals()
+        .shutdown_timeout(grace_period)
+        .run();
+    actix_rt::spawn(shutdown.clone().stop_on_signal(server.clone()));
+    server.await.context("while starting the server")?;
+    let report = shutdown.report();
+    info!(
+        "Server stopped: {} connections drained, {} force-closed",
+        report.drained, report.force_closed
+    );
+    // Only now that no request can still be using it.
+    drop(sql_pool);
     Ok(())
 }
 
 fn run_server_command(opts: RunOpts) -> Result<()> {
     debug!("CLI: {:#?}", &opts);
+    if opts.check_only {
+        return run_check_config_command(opts);
+    }
 
     let config = infra::configuration::init(opts)?;
     infra::logging::init(&config)?;
@@ -143,12 +179,232 @@ fn send_test_email_command(opts: TestEmailOpts) -> Result<()> {
         .build()?;
 
     runtime.block_on(
-        mail::send_test_email(to, &config.smtp_options)
+        mail::MailSender::new(&config.smtp_options)?
+            .send_test_email(to)
             .unwrap_or_else(|e| error!("Could not send email: {:#}", e)),
     );
     Ok(())
 }
 
+fn run_migrate_command(opts: MigrateOpts) -> Result<()> {
+    debug!("CLI: {:#?}", &opts);
+    let dry_run = opts.dry_run;
+    let config = infra::configuration::init(opts)?;
+    infra::logging::init(&config)?;
+
+    let runtime = tokio::runtime::Builder::new_current_thread()
+        .enable_all()
+        .build()?;
+
+    runtime.block_on(async {
+        // One connection holds the migration lock, the other runs the migrations.
+        let sql_pool = connect_to_database(
+            &config.database_url,
+            &DatabasePoolOptions {
+                max_connections: 2,
+                min_idle_connections: 0,
+                ..config.database_pool_options.clone()
+            },
+        )
+        .await?;
+        infra::migrate::migrate(&sql_pool, config.uuid_backfill, dry_run).await
+    })
+}
+
+fn run_reevaluate_group_rules_command(opts: RunOpts) -> Result<()> {
+    use domain::group_rule_handler::GroupRuleBackendHandler;
+    debug!("CLI: {:#?}", &opts);
+    let config = infra::configuration::init(opts)?;
+    infra::logging::init(&config)?;
+
+    let runtime = tokio::runtime::Builder::new_current_thread()
+        .enable_all()
+        .build()?;
+
+    runtime.block_on(async {
+        let sql_pool =
+            connect_to_database(&config.database_url, &config.database_pool_options).await?;
+        domain::sql_tables::init_table(&sql_pool, config.uuid_backfill)
+            .await
+            .context("while creating the tables")?;
+        let num_changes = SqlBackendHandler::new(config, sql_pool)
+            .reevaluate_group_rules()
+            .await
+            .context("while reevaluating the group rules")?;
+        println!("{} memberships added or removed", num_changes);
+        Ok(())
+    })
+}
+
+fn run_rebuild_derived_data_command(opts: MaintenanceOpts) -> Result<()> {
+    debug!("CLI: {:#?}", &opts);
+    let dry_run = opts.dry_run;
+    let config = infra::configuration::init(opts)?;
+    infra::logging::init(&config)?;
+
+    let runtime = tokio::runtime::Builder::new_current_thread()
+        .enable_all()
+        .build()?;
+
+    runtime.block_on(async {
+        let sql_pool =
+            connect_to_database(&config.database_url, &config.database_pool_options).await?;
+        domain::sql_tables::init_table(&sql_pool, config.uuid_backfill)
+            .await
+            .context("while creating the tables")?;
+        let report = SqlBackendHandler::new(config, sql_pool)
+            .rebuild_derived_data(dry_run, |step, _| println!("Done: {}", step.as_str()))
+            .await
+            .context("while rebuilding the derived data")?;
+        if dry_run {
+            println!("Dry run: nothing was changed.");
+        }
+        println!("{:#?}", report);
+        Ok(())
+    })
+}
+
+fn run_reset_password_command(opts: ResetPasswordOpts) -> Result<()> {
+    use domain::{
+        password_policy::{PasswordPolicy, PasswordPolicyError},
+        sql_opaque_handler::reset_password,
+        types::UserId,
+    };
+    let user_id = UserId::new(&opts.user_id);
+    let password = match opts.password.clone() {
+        Some(password) => password,
+        None => {
+            eprint!("New password for {}: ", user_id);
+            let mut password = String::new();
+            std::io::stdin().read_line(&mut password)?;
+            password.trim_end_matches(&['\r', '\n'][..]).to_owned()
+        }
+    };
+    if password.is_empty() {
+        bail!("The password is empty");
+    }
+    // A missing key file would be generated, and the password registered with the wrong key.
+    let key_file = infra::configuration::load(opts.clone())?.key_file;
+    if !std::path::Path::new(&key_file).exists() {
+        bail!("The server key file `{}` doesn't exist", key_file);
+    }
+    let config = infra::configuration::init(opts)?;
+    infra::logging::init(&config)?;
+    // The breach check is skipped: this shouldn't depend on a third-party service.
+    let violations = PasswordPolicy::new(&config.password_policy)?.check_rules(&password);
+    if !violations.is_empty() {
+        bail!(PasswordPolicyError(violations));
+    }
+
+    let runtime = tokio::runtime::Builder::new_current_thread()
+        .enable_all()
+        .build()?;
+
+    runtime.block_on(async {
+        let sql_pool =
+            connect_to_database(&config.database_url, &config.database_pool_options).await?;
+        domain::sql_tables::init_table(&sql_pool, config.uuid_backfill)
+            .await
+            .context("while creating the tables")?;
+        reset_password(
+            &SqlBackendHandler::new(config, sql_pool),
+            &user_id,
+            &password.into(),
+        )
+        .await
+        .with_context(|| format!("while resetting the password of `{}`", user_id))?;
+        println!("The password of `{}` was reset", user_id);
+        Ok(())
+    })
+}
+
+fn run_backup_command(opts: BackupOpts) -> Result<()> {
+    use std::io::Write;
+    debug!("CLI: {:#?}", &opts);
+    let output = opts.output.clone();
+    let config = infra::configuration::init(opts)?;
+    infra::logging::init(&config)?;
+
+    let runtime = tokio::runtime::Builder::new_current_thread()
+        .enable_all()
+        .build()?;
+
+    let backup = runtime.block_on(async {
+        let sql_pool =
+            connect_to_database(&config.database_url, &config.database_pool_options).await?;
+        domain::sql_backup::create_backup(&sql_pool).await
+    })?;
+    // Only readable by the owner, because of the password hashes. An existing file is kept.
+    let mut options = std::fs::OpenOptions::new();
+    options.write(true).create_new(true);
+    #[cfg(unix)]
+    std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+    let file = options
+        .open(&output)
+        .with_context(|| format!("while creating {}", output))?;
+    let mut writer = std::io::BufWriter::new(file);
+    serde_json::to_writer(&mut writer, &backup)?;
+    writer.flush()?;
+    println!(
+        "Backed up {} users and {} groups to {}",
+        backup.users.len(),
+        backup.groups.len(),
+        output
+    );
+    Ok(())
+}
+
+fn run_restore_command(opts: RestoreOpts) -> Result<()> {
+    debug!("CLI: {:#?}", &opts);
+    let (input, force) = (opts.input.clone(), opts.force);
+    let config = infra::configuration::init(opts)?;
+    infra::logging::init(&config)?;
+    // Before touching the DB, to refuse the backups of a newer version.
+    let backup = std::fs::File::open(&input)
+        .map_err(anyhow::Error::from)
+        .and_then(|file| Ok(serde_json::from_reader(std::io::BufReader::new(file))?))
+        .and_then(domain::sql_backup::parse_backup)
+        .with_context(|| format!("while reading the backup {}", input))?;
+
+    let runtime = tokio::runtime::Builder::new_current_thread()
+        .enable_all()
+        .build()?;
+
+    runtime.block_on(async {
+        let sql_pool =
+            connect_to_database(&config.database_url, &config.database_pool_options).await?;
+        domain::sql_tables::init_table(&sql_pool, config.uuid_backfill)
+            .await
+            .context("while creating the tables")?;
+        domain::sql_backup::restore_backup(&sql_pool, backup, force).await?;
+        println!("Restored the backup {}", input);
+        Ok(())
+    })
+}
+
+fn run_check_config_command(opts: RunOpts) -> Result<()> {
+    debug!("CLI: {:#?}", &opts);
+    // Unlike `configuration::init`, this doesn't generate the missing key files.
+    let config = infra::configuration::load(opts)?;
+
+    let runtime = tokio::runtime::Builder::new_current_thread()
+        .enable_all()
+        .build()?;
+
+    let check = runtime.block_on(infra::check_config::check_config(&config));
+    for warning in &check.warnings {
+        println!("WARNING: {}", warning);
+    }
+    for error in &check.errors {
+        println!("ERROR: {}", error);
+    }
+    if !check.errors.is_empty() {
+        bail!("Invalid configuration: {} error(s)", check.errors.len());
+    }
+    println!("The configuration is valid");
+    Ok(())
+}
+
 fn run_healthcheck(opts: RunOpts) -> Result<()> {
     debug!("CLI: {:#?}", &opts);
     let config = infra::configuration::init(opts)?;
@@ -160,22 +416,18 @@ fn run_healthcheck(opts: RunOpts) -> Result<()> {
 
     use tokio::time::timeout;
     let delay = Duration::from_millis(3000);
-    let (ldap, ldaps, api) = runtime.block_on(async {
-        tokio::join!(
-            timeout(delay, healthcheck::check_ldap(config.ldap_port)),
-            timeout(delay, healthcheck::check_ldaps(&config.ldaps_options)),
-            timeout(delay, healthcheck::check_api(config.http_port)),
-        )
-    });
+    let listeners = config.get_listeners();
+    let results = runtime.block_on(futures::future::join_all(
+        listeners
+            .iter()
+            .map(|listener| timeout(delay, healthcheck::check_listener(listener))),
+    ));
 
     let mut failure = false;
-    [ldap, ldaps, api]
-        .into_iter()
-        .filter_map(Result::err)
-        .for_each(|e| {
-            failure = true;
-            error!("{:#}", e)
-        });
+    results.into_iter().filter_map(Result::err).for_each(|e| {
+        failure = true;
+        error!("{:#}", e)
+    });
     std::process::exit(i32::from(failure))
 }
 
@@ -186,5 +438,12 @@ fn main() -> Result<()> {
         Command::Run(opts) => run_server_command(opts),
         Command::HealthCheck(opts) => run_healthcheck(opts),
         Command::SendTestEmail(opts) => send_test_email_command(opts),
+        Command::Migrate(opts) => run_migrate_command(opts),
+        Command::CheckConfig(opts) => run_check_config_command(opts),
+        Command::ReevaluateGroupRules(opts) => run_reevaluate_group_rules_command(opts),
+        Command::RebuildDerivedData(opts) => run_rebuild_derived_data_command(opts),
+        Command::ResetPassword(opts) => run_reset_password_command(opts),
+        Command::Backup(opts) => run_backup_command(opts),
+        Command::Restore(opts) => run_restore_command(opts),
     }
 }